@@ -1,26 +1,319 @@
 use anyhow::{Context, Result};
-use clap::{CommandFactory, Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashMap;
-use std::io::{self, Write};
-use std::path::PathBuf;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::io::{self, BufRead, BufReader, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The server API this CLI speaks; servers report theirs at `/version`.
+const API_VERSION: u32 = 2;
+
+/// Bodies piped in on stdin are cut to this many bytes, leaving room in
+/// the 4KB APNs payload for the rest of the notification.
+const STDIN_BODY_LIMIT: usize = 2048;
 
 #[derive(Parser)]
 #[command(name = "psh")]
 #[command(about = "Push notification server client")]
 struct Cli {
-    /// Server URL (required via flag, PSH_SERVER env, or config file)
+    /// Server URL, or unix:///path/to/psh.sock for a server on a Unix
+    /// socket (required via flag, PSH_SERVER env, or config file)
     #[arg(short, long, env = "PSH_SERVER")]
     server: Option<String>,
 
+    #[command(flatten)]
+    http: HttpArgs,
+
+    /// Refuse to run against a server that speaks an older API than psh
+    #[arg(long, global = true, env = "PSH_STRICT")]
+    strict: bool,
+
+    /// Print nothing but errors, for crontabs; listings still print
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Describe each request, its payload, status and timing on stderr
+    #[arg(short, long, global = true)]
+    verbose: bool,
+
+    /// Print whole device tokens instead of their first and last 8 characters
+    #[arg(long, global = true, env = "PSH_SHOW_FULL_TOKENS")]
+    show_full_tokens: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+/// Set by `--quiet`.
+static QUIET: AtomicBool = AtomicBool::new(false);
+/// Set by `--verbose`.
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+/// Set by `--show-full-tokens`.
+static SHOW_FULL_TOKENS: AtomicBool = AtomicBool::new(false);
+
+/// `println!` for reports of what a command did, which `--quiet` silences.
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if !QUIET.load(Ordering::Relaxed) {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// `eprintln!` for warnings, which `--quiet` silences too.
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        if !QUIET.load(Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// `RequestBuilder::send`, describing the request and response on stderr
+/// under `--verbose`.
+trait SendLogged {
+    async fn send_logged(self) -> reqwest::Result<reqwest::Response>;
+}
+
+impl SendLogged for reqwest::RequestBuilder {
+    async fn send_logged(self) -> reqwest::Result<reqwest::Response> {
+        if !VERBOSE.load(Ordering::Relaxed) {
+            return self.send().await;
+        }
+        let (client, request) = self.build_split();
+        let request = request?;
+        eprintln!("> {} {}", request.method(), request.url());
+        if let Some(body) = request.body().and_then(|body| body.as_bytes()) {
+            match std::str::from_utf8(body) {
+                Ok(text) => eprintln!("> {}", text),
+                Err(_) => eprintln!("> ({} bytes)", body.len()),
+            }
+        }
+        let started = Instant::now();
+        let result = client.execute(request).await;
+        let elapsed = started.elapsed().as_millis();
+        match &result {
+            Ok(response) => eprintln!("< {} ({} ms)", response.status(), elapsed),
+            Err(e) => eprintln!("< {} ({} ms)", e, elapsed),
+        }
+        result
+    }
+}
+
+/// Connection settings for the HTTP client every command shares.
+#[derive(clap::Args, Debug)]
+struct HttpArgs {
+    /// Seconds to wait for the server to connect or send data
+    #[arg(long, global = true, env = "PSH_TIMEOUT", default_value_t = 30)]
+    timeout: u64,
+
+    /// PEM file of additional CA certificates to trust
+    #[arg(long, global = true, env = "PSH_CA_CERT")]
+    ca_cert: Option<PathBuf>,
+
+    /// Accept invalid TLS certificates (dangerous: for testing only)
+    #[arg(long, global = true)]
+    insecure: bool,
+
+    /// API key, sent as a bearer token, for servers that require one
+    #[arg(long, global = true, env = "PSH_API_KEY", hide_env_values = true)]
+    api_key: Option<String>,
+}
+
+impl HttpArgs {
+    /// Fills in settings not given as flags or environment variables from
+    /// the config file.
+    fn with_config(mut self, config: &Config) -> Self {
+        if self.ca_cert.is_none() {
+            self.ca_cert = config.ca_cert.clone();
+        }
+        self.insecure |= config.insecure.unwrap_or(false);
+        if self.api_key.is_none() {
+            self.api_key = config.api_key.clone();
+        }
+        self
+    }
+
+    /// Builds the client, connecting over `socket` when the server is on a
+    /// Unix socket. Proxies come from HTTPS_PROXY, HTTP_PROXY and NO_PROXY;
+    /// idle connections are kept alive and reused.
+    fn client(&self, socket: Option<&Path>) -> Result<reqwest::Client> {
+        let timeout = Duration::from_secs(self.timeout);
+        let mut builder = reqwest::Client::builder()
+            .user_agent(concat!("psh/", env!("CARGO_PKG_VERSION")))
+            .connect_timeout(timeout)
+            .read_timeout(timeout)
+            .tcp_keepalive(Duration::from_secs(60))
+            .pool_idle_timeout(Duration::from_secs(90));
+
+        if let Some(path) = &self.ca_cert {
+            let pem = std::fs::read(path)
+                .with_context(|| format!("Failed to read CA certificate {}", path.display()))?;
+            let certs = reqwest::Certificate::from_pem_bundle(&pem)
+                .with_context(|| format!("Invalid CA certificate {}", path.display()))?;
+            if certs.is_empty() {
+                anyhow::bail!("No certificates found in {}", path.display());
+            }
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        if self.insecure {
+            warn!("Warning: TLS certificate verification is disabled");
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        if let Some(key) = &self.api_key {
+            let mut value = reqwest::header::HeaderValue::from_str(&format!("Bearer {}", key))
+                .context("API key contains characters not allowed in a header")?;
+            value.set_sensitive(true);
+            let mut headers = reqwest::header::HeaderMap::new();
+            headers.insert(reqwest::header::AUTHORIZATION, value);
+            builder = builder.default_headers(headers);
+        }
+
+        if let Some(path) = socket {
+            #[cfg(unix)]
+            {
+                builder = builder.unix_socket(path);
+            }
+            #[cfg(not(unix))]
+            anyhow::bail!(
+                "Unix socket servers ({}) aren't supported on this platform",
+                path.display()
+            );
+        }
+
+        builder.build().context("Failed to build HTTP client")
+    }
+}
+
+/// The socket of a server given as `unix:///run/psh.sock` or
+/// `unix:/run/psh.sock`.
+fn unix_socket(server: &str) -> Option<&Path> {
+    let path = server.strip_prefix("unix:")?;
+    Some(Path::new(path.strip_prefix("//").unwrap_or(path)))
+}
+
+/// The URL requests are built on: the server's own, or localhost for one on
+/// a Unix socket, whose connections go to the socket whatever the host.
+fn base_url(server: String) -> String {
+    match unix_socket(&server) {
+        Some(_) => "http://localhost".to_string(),
+        None => server,
+    }
+}
+
 #[derive(Debug, Default, Deserialize, Serialize)]
 struct Config {
     server: Option<String>,
+    /// Default for `--ca-cert`.
+    ca_cert: Option<PathBuf>,
+    /// Default for `--insecure`.
+    insecure: Option<bool>,
+    /// Default for `--api-key`.
+    api_key: Option<String>,
+    /// `psh send --preset <name>` option bundles, edited in the file.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    presets: BTreeMap<String, Preset>,
+}
+
+/// Send options saved under `[presets.<name>]`. Options given on the
+/// command line win, and `-d` flags override the preset's data keys.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+struct Preset {
+    title: Option<String>,
+    subtitle: Option<String>,
+    sound: Option<String>,
+    sound_critical: Option<bool>,
+    priority: Option<u8>,
+    category: Option<String>,
+    thread_id: Option<String>,
+    interruption_level: Option<String>,
+    collapse_id: Option<String>,
+    push_type: Option<String>,
+    topic: Option<String>,
+    /// As `--expires-in`, e.g. "1h".
+    expires_in: Option<String>,
+    content_available: Option<bool>,
+    mutable_content: Option<bool>,
+    segment: Option<String>,
+    /// Clauses as for `--filter`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    filter: Vec<String>,
+    /// Custom data; nested tables become dotted keys.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    data: BTreeMap<String, Value>,
+}
+
+impl Preset {
+    /// Fills in what `args` leaves unset.
+    fn apply(&self, args: &mut SendArgs) -> Result<(), String> {
+        if let Some(priority) = self.priority.filter(|p| !(1..=10).contains(p)) {
+            return Err(format!("priority must be 1-10, got {}", priority));
+        }
+        let or = |arg: &mut Option<String>, preset: &Option<String>| {
+            if arg.is_none() {
+                *arg = preset.clone();
+            }
+        };
+        or(&mut args.title, &self.title);
+        or(&mut args.subtitle, &self.subtitle);
+        or(&mut args.sound, &self.sound);
+        or(&mut args.category, &self.category);
+        or(&mut args.thread_id, &self.thread_id);
+        or(&mut args.interruption_level, &self.interruption_level);
+        or(&mut args.collapse_id, &self.collapse_id);
+        or(&mut args.push_type, &self.push_type);
+        or(&mut args.topic, &self.topic);
+        or(&mut args.segment, &self.segment);
+        args.priority = args.priority.or(self.priority);
+        args.sound_critical |= self.sound_critical.unwrap_or(false);
+        args.content_available |= self.content_available.unwrap_or(false);
+        args.mutable_content |= self.mutable_content.unwrap_or(false);
+        if args.expires_in.is_none() && args.expiration.is_none() {
+            args.expires_in = self
+                .expires_in
+                .as_deref()
+                .map(parse_duration_secs)
+                .transpose()?;
+        }
+
+        let mut filters = self
+            .filter
+            .iter()
+            .map(|clause| parse_filter_clause(clause))
+            .collect::<Result<Vec<_>, _>>()?;
+        filters.append(&mut args.filters);
+        args.filters = filters;
+
+        let mut data = Vec::new();
+        for (key, value) in &self.data {
+            flatten_preset_data(vec![key.clone()], value, &mut data);
+        }
+        data.append(&mut args.data);
+        args.data = data;
+        Ok(())
+    }
+}
+
+/// Turns a preset's data table into `-d` pairs, so flags can override a
+/// single nested key.
+fn flatten_preset_data(path: Vec<String>, value: &Value, pairs: &mut Vec<(Vec<String>, Value)>) {
+    match value {
+        Value::Object(object) if !object.is_empty() => {
+            for (key, value) in object {
+                let mut path = path.clone();
+                path.push(key.clone());
+                flatten_preset_data(path, value, pairs);
+            }
+        }
+        value => pairs.push((path, value.clone())),
+    }
 }
 
 impl Config {
@@ -44,6 +337,24 @@ impl Config {
     fn config_path() -> Option<PathBuf> {
         dirs::home_dir().map(|p| p.join(".config").join("psh").join("config.toml"))
     }
+
+    fn get(&self, key: ConfigKey) -> Option<&str> {
+        match key {
+            ConfigKey::Server => self.server.as_deref(),
+            ConfigKey::CaCert => self.ca_cert.as_deref().and_then(|p| p.to_str()),
+            ConfigKey::Insecure => self.insecure.map(|v| if v { "true" } else { "false" }),
+            ConfigKey::ApiKey => self.api_key.as_deref(),
+        }
+    }
+
+    fn set(&mut self, key: ConfigKey, value: Option<String>) {
+        match key {
+            ConfigKey::Server => self.server = value,
+            ConfigKey::CaCert => self.ca_cert = value.map(PathBuf::from),
+            ConfigKey::Insecure => self.insecure = value.map(|v| v == "true"),
+            ConfigKey::ApiKey => self.api_key = value,
+        }
+    }
 }
 
 fn prompt_for_server() -> Result<String> {
@@ -69,7 +380,7 @@ fn resolve_server(cli_server: Option<String>, config: &Config) -> Result<String>
     let mut config = Config::load();
     config.server = Some(server.clone());
     config.save()?;
-    println!("Saved to {:?}", Config::config_path().unwrap());
+    say!("Saved to {:?}", Config::config_path().unwrap());
 
     Ok(server)
 }
@@ -77,14 +388,400 @@ fn resolve_server(cli_server: Option<String>, config: &Config) -> Result<String>
 #[derive(Subcommand)]
 enum Commands {
     /// Send a push notification
-    Send(SendArgs),
+    Send(Box<SendArgs>),
     /// Get server statistics
-    Stats,
+    Stats(StatsArgs),
+    /// Show pushes sent per API key today and this month, against their
+    /// quotas
+    Usage,
     /// Health check
     Ping,
+    /// Check the config file, the server and its APNs credentials, and
+    /// suggest fixes for whatever is wrong
+    Doctor,
+    /// Manage the config file
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Manage saved device segments
+    #[command(subcommand)]
+    Segments(SegmentsCommand),
+    /// Inspect registered devices
+    #[command(subcommand)]
+    Devices(DevicesCommand),
+    /// Manage APNs broadcast channels for Live Activities
+    #[command(subcommand)]
+    Channels(ChannelsCommand),
+    /// Run a command and send a push when it finishes, with its exit
+    /// status, duration and last lines of output
+    Watch(WatchArgs),
+    /// Send a push about a command that just finished, given its exit
+    /// status: `make; psh notify-done --status $?`
+    NotifyDone(NotifyDoneArgs),
+    /// Send a labeled test push to a device token through both APNs
+    /// environments and report which accepted it
+    TestDevice {
+        /// Device token, registered or not
+        token: String,
+    },
+    /// List app installations with their device counts, pushes and last
+    /// activity, most recently active first
+    Installations,
+    /// Handle users' data protection requests
+    #[command(subcommand)]
+    Gdpr(GdprCommand),
+    /// Act on pushes already sent
+    #[command(subcommand)]
+    Pushes(PushesCommand),
+    /// Hold sends to a topic or segment, queuing them (or with --drop,
+    /// dropping them) until `psh resume`
+    Pause {
+        #[command(flatten)]
+        target: PauseTarget,
+        /// Drop sends while paused instead of queuing them
+        #[arg(long)]
+        drop: bool,
+        /// Why, shown by `psh paused`
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// Lift a pause and send what it queued, in order
+    Resume {
+        #[command(flatten)]
+        target: PauseTarget,
+    },
+    /// List paused topics and segments
+    Paused,
+    /// Issue and revoke API keys for teammates and scripts
+    #[command(subcommand)]
+    Token(TokenCommand),
+}
+
+/// The topic or segment a pause applies to.
+#[derive(clap::Args, Debug)]
+#[group(required = true, multiple = false)]
+struct PauseTarget {
+    #[arg(long)]
+    topic: Option<String>,
+    #[arg(long)]
+    segment: Option<String>,
+}
+
+impl PauseTarget {
+    fn url(&self, server: &str) -> String {
+        match (&self.topic, &self.segment) {
+            (Some(topic), _) => format!("{}/topics/{}", server, topic),
+            (None, Some(segment)) => format!("{}/segments/{}", server, segment),
+            (None, None) => unreachable!("clap requires a topic or segment"),
+        }
+    }
+}
+
+/// Payload presets picked by whether a command succeeded.
+#[derive(clap::Args, Clone, Debug, Default)]
+struct OutcomeArgs {
+    /// Sound to play when the command succeeded
+    #[arg(long)]
+    ok_sound: Option<String>,
+
+    /// Sound to play when the command failed
+    #[arg(long)]
+    fail_sound: Option<String>,
+
+    /// Send failures as critical alerts, which sound even when muted
+    #[arg(long)]
+    fail_critical: bool,
+}
+
+impl OutcomeArgs {
+    fn apply(&self, args: &mut SendArgs, succeeded: bool) {
+        if succeeded {
+            args.sound = self.ok_sound.clone();
+        } else if self.fail_critical {
+            args.sound_critical = true;
+            args.sound_name = self.fail_sound.clone();
+        } else {
+            args.sound = self.fail_sound.clone();
+        }
+    }
 }
 
 #[derive(Parser)]
+struct NotifyDoneArgs {
+    /// Exit status of the command that finished, usually $?
+    #[arg(long, allow_negative_numbers = true)]
+    status: i32,
+
+    /// Notification body, such as what finished
+    message: Option<String>,
+
+    /// Notification title; defaults to whether the command succeeded
+    #[arg(short, long)]
+    title: Option<String>,
+
+    #[command(flatten)]
+    outcome: OutcomeArgs,
+
+    /// Device filter (repeatable), as for send
+    #[arg(long = "filter", value_parser = parse_filter_clause)]
+    filters: Vec<FilterClause>,
+}
+
+#[derive(Parser)]
+struct WatchArgs {
+    /// Notification title; defaults to the command and whether it succeeded
+    #[arg(short, long)]
+    title: Option<String>,
+
+    /// How many of the command's last output lines to include
+    #[arg(long, default_value_t = 5)]
+    lines: usize,
+
+    #[command(flatten)]
+    outcome: OutcomeArgs,
+
+    /// Device filter (repeatable), as for send
+    #[arg(long = "filter", value_parser = parse_filter_clause)]
+    filters: Vec<FilterClause>,
+
+    /// The command to run, after --
+    #[arg(required = true, last = true)]
+    command: Vec<String>,
+}
+
+#[derive(Subcommand)]
+enum ChannelsCommand {
+    /// List the app's channels in one environment
+    List {
+        #[arg(long, default_value = "production", value_parser = ["sandbox", "production"])]
+        environment: String,
+    },
+    /// Create a channel
+    Create {
+        #[arg(long, default_value = "production", value_parser = ["sandbox", "production"])]
+        environment: String,
+        /// Keep the latest message for devices that were offline
+        #[arg(long)]
+        store_latest: bool,
+    },
+    /// Show a channel's settings
+    Show {
+        /// Channel ID
+        id: String,
+        #[arg(long, default_value = "production", value_parser = ["sandbox", "production"])]
+        environment: String,
+    },
+    /// Delete a channel
+    Delete {
+        /// Channel ID
+        id: String,
+        #[arg(long, default_value = "production", value_parser = ["sandbox", "production"])]
+        environment: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum DevicesCommand {
+    /// List devices that currently receive sends
+    List {
+        /// Device filter (repeatable), as for send: locale=fr, timezone='America/*'
+        #[arg(long = "filter", value_parser = parse_filter_clause)]
+        filters: Vec<FilterClause>,
+        /// Group devices under their installations, with each one's pushes
+        /// and last activity
+        #[arg(long)]
+        by_installation: bool,
+    },
+    /// Show recent pushes sent to a device, including failures
+    History {
+        /// Device token
+        token: String,
+        /// Maximum number of pushes to show
+        #[arg(long, default_value_t = 50)]
+        limit: u32,
+    },
+    /// Write every registered device to stdout
+    Export {
+        /// Output format
+        #[arg(long, default_value = "json", value_parser = ["csv", "json", "ndjson"])]
+        format: String,
+    },
+    /// Stop sending to a device, keeping its registration and history
+    Disable {
+        /// Device token
+        token: String,
+    },
+    /// Resume sending to a disabled device
+    Enable {
+        /// Device token
+        token: String,
+    },
+    /// Hold all but critical pushes to a device for a while
+    Snooze {
+        /// Device token
+        token: String,
+        /// How long, e.g. 30m, 2h or 1d
+        #[arg(long = "for", value_parser = parse_duration_secs)]
+        duration: u64,
+    },
+    /// End a device's snooze early
+    Unsnooze {
+        /// Device token
+        token: String,
+    },
+    /// Set or remove tags on a device
+    Tag {
+        /// Device token
+        token: String,
+        /// key=value to set, or key= to remove (repeatable)
+        #[arg(required = true, value_parser = parse_tag)]
+        tags: Vec<(String, Option<String>)>,
+    },
+}
+
+#[derive(Parser, Default)]
+struct StatsArgs {
+    /// Show a time series for this window (e.g. 24h, 7d)
+    #[arg(long)]
+    since: Option<String>,
+
+    /// Bucket size for the series
+    #[arg(long, value_parser = ["hour", "day"], requires = "since")]
+    bucket: Option<String>,
+
+    /// Add a sparkline of sent pushes to the series
+    #[arg(long, requires = "since")]
+    graph: bool,
+
+    /// Break pushes down by app, topic or API key, over --since if given
+    #[arg(long, value_parser = ["app", "topic", "api_key"])]
+    by: Option<String>,
+}
+
+#[derive(Subcommand)]
+enum SegmentsCommand {
+    /// List saved segments
+    List,
+    /// Create or replace a segment from filter clauses
+    Set {
+        name: String,
+        /// Device filter (repeatable), same syntax as `send --filter`
+        #[arg(long = "filter", value_parser = parse_filter_clause, required = true)]
+        filters: Vec<FilterClause>,
+    },
+    /// Delete a segment
+    Delete { name: String },
+}
+
+#[derive(Subcommand)]
+enum GdprCommand {
+    /// Delete an installation's devices and push history
+    Delete {
+        installation_id: String,
+        /// Only count what would be deleted
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum PushesCommand {
+    /// Take back a delivered push: replace it under its collapse id when
+    /// given a title or body, else tell the app to remove it
+    Withdraw {
+        /// psh push id or APNs id
+        id: String,
+        /// Title of the replacement
+        #[arg(long)]
+        title: Option<String>,
+        /// Body of the replacement
+        #[arg(long)]
+        body: Option<String>,
+    },
+    /// Compare two pushes field by field, including their custom data
+    Diff {
+        /// psh push id
+        first: i64,
+        /// psh push id
+        second: i64,
+    },
+}
+
+#[derive(Subcommand)]
+enum TokenCommand {
+    /// Issue a send or read API key, shown only this once
+    Create {
+        #[command(flatten)]
+        role: TokenRole,
+        /// Limit the key to publishing to this topic
+        #[arg(long, conflicts_with = "read_only")]
+        topic: Option<String>,
+        /// Key name; defaults to the topic or role with a random suffix
+        #[arg(long)]
+        name: Option<String>,
+        /// Also print a curl command that uses the key, for teammates
+        /// without psh
+        #[arg(long)]
+        print_curl: bool,
+    },
+    /// List keys issued with `psh token create`
+    List,
+    /// Revoke an issued key at once
+    Revoke { name: String },
+}
+
+/// The role of an issued key; admin keys only come from server.toml.
+#[derive(clap::Args, Debug)]
+#[group(required = true, multiple = false)]
+struct TokenRole {
+    /// Can send pushes, but not read devices or history
+    #[arg(long)]
+    send_only: bool,
+    /// Can read devices, history and stats, but not send
+    #[arg(long)]
+    read_only: bool,
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Set a config value
+    Set { key: ConfigKey, value: String },
+    /// Print a config value, or all values when no key is given
+    Get { key: Option<ConfigKey> },
+    /// Print the config file path
+    Path,
+    /// Remove a config value
+    Unset { key: ConfigKey },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ConfigKey {
+    Server,
+    CaCert,
+    Insecure,
+    ApiKey,
+}
+
+impl ConfigKey {
+    fn name(self) -> &'static str {
+        match self {
+            ConfigKey::Server => "server",
+            ConfigKey::CaCert => "ca-cert",
+            ConfigKey::Insecure => "insecure",
+            ConfigKey::ApiKey => "api-key",
+        }
+    }
+
+    fn validate(self, value: &str) -> Result<()> {
+        match self {
+            ConfigKey::Insecure if value != "true" && value != "false" => {
+                anyhow::bail!("insecure must be true or false, got '{}'", value)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Parser, Default)]
 struct SendArgs {
     /// Notification body (positional)
     body_positional: Option<String>,
@@ -106,6 +803,11 @@ struct SendArgs {
     #[arg(long)]
     launch_image: Option<String>,
 
+    /// Send the text as written; by default the server converts markdown
+    /// such as **bold** and `code` to plain text
+    #[arg(long)]
+    raw: bool,
+
     // Localization
     /// Localization key for title
     #[arg(long)]
@@ -123,6 +825,10 @@ struct SendArgs {
     #[arg(long)]
     loc_args: Option<String>,
 
+    /// Server message catalog key; each device gets its locale's title and body
+    #[arg(long = "message")]
+    message_key: Option<String>,
+
     // Badge & Sound
     /// Badge count
     #[arg(long)]
@@ -157,9 +863,62 @@ struct SendArgs {
     #[arg(long)]
     category: Option<String>,
 
+    /// Action button (repeatable): id=Title, or a JSON object such as
+    /// '{"id":"delete","title":"Delete","destructive":true}'; needs --category
+    #[arg(long = "action", value_parser = parse_action, requires = "category")]
+    actions: Vec<Value>,
+
+    /// Thread identifier for grouping in Notification Center
+    #[arg(long)]
+    thread_id: Option<String>,
+
+    /// Interruption level (passive, active, time-sensitive, critical)
+    #[arg(long)]
+    interruption_level: Option<String>,
+
+    /// Relevance score (0.0-1.0) for notification summary ordering
+    #[arg(long)]
+    relevance_score: Option<f64>,
+
+    /// Identifier of the window or scene to bring forward when opened
+    #[arg(long)]
+    target_content_id: Option<String>,
+
+    /// Unix timestamp after which a Live Activity update is stale
+    #[arg(long)]
+    stale_date: Option<u64>,
+
+    /// Focus filter criteria that decide whether the notification shows
+    #[arg(long)]
+    filter_criteria: Option<String>,
+
+    /// Live Activity event
+    #[arg(long, value_parser = ["start", "update", "end"])]
+    event: Option<String>,
+
+    /// Live Activity content state, as a JSON object
+    #[arg(long, value_parser = parse_json_object)]
+    content_state: Option<Value>,
+
+    /// Unix timestamp an ended Live Activity leaves the Lock Screen
+    #[arg(long)]
+    dismissal_date: Option<u64>,
+
     // Delivery options
-    /// Priority (1-10, 10 = highest)
+    /// APNs push type; picks the topic suffix APNs expects (e.g. liveactivity
+    /// sends to <bundle>.push-type.liveactivity)
+    #[arg(long, value_parser = [
+        "alert", "background", "liveactivity", "voip", "complication", "location",
+        "fileprovider", "mdm",
+    ])]
+    push_type: Option<String>,
+
+    /// Send under this apns-topic instead of the server's
     #[arg(long)]
+    topic: Option<String>,
+
+    /// Priority (1-10): 10 sends immediately, 5-9 power-considerate, 1-4 low power
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=10))]
     priority: Option<u8>,
 
     /// Collapse identifier
@@ -170,56 +929,429 @@ struct SendArgs {
     #[arg(long)]
     expiration: Option<u64>,
 
+    /// Expire after a duration such as 30m, 2h or 1d (0 = deliver now or never)
+    #[arg(long, visible_alias = "ttl", value_parser = parse_duration_secs, conflicts_with = "expiration")]
+    expires_in: Option<u64>,
+
+    /// Hold background pushes to devices over APNs' hourly budget until the
+    /// next hour instead of sending them to be throttled
+    #[arg(long, requires = "content_available")]
+    defer_throttled: bool,
+
     // Custom data
-    /// Custom key=value pairs (repeatable)
-    #[arg(short = 'd', long = "data")]
-    data: Vec<String>,
-}
+    /// Custom data (repeatable): key=value for a string, key:=json for numbers,
+    /// booleans, arrays or objects (e.g. count:=5, flag:=true). Dotted keys
+    /// such as user.id=42 nest; later flags win, and \. is a literal dot
+    #[arg(short = 'd', long = "data", value_parser = parse_data_pair)]
+    data: Vec<(Vec<String>, Value)>,
 
-#[derive(Serialize)]
-struct SendRequest {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    title: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    subtitle: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    body: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    launch_image: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    title_loc_key: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    title_loc_args: Option<Vec<String>>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    loc_key: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    loc_args: Option<Vec<String>>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    badge: Option<u32>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    sound: Option<SoundConfig>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    content_available: Option<bool>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    mutable_content: Option<bool>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    category: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    priority: Option<u8>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    collapse_id: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    expiration: Option<u64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    data: Option<HashMap<String, Value>>,
-}
+    /// File (image, log snippet) to upload to the server, which links it
+    /// in the custom data for the app's notification service extension
+    #[arg(long, conflicts_with_all = ["channel", "preview"])]
+    attach: Option<PathBuf>,
 
-#[derive(Serialize)]
-#[serde(untagged)]
-enum SoundConfig {
-    Simple(String),
-    Critical {
-        name: String,
+    // Targeting
+    /// Device filter (repeatable): device_type=iPad, name='*Test*',
+    /// app_version=1.2, app_version>=1.2, os_version>=17.0, locale=fr,
+    /// timezone='Europe/*', topic=backups, tag.tier=beta
+    #[arg(long = "filter", value_parser = parse_filter_clause)]
+    filters: Vec<FilterClause>,
+
+    /// Saved segment to target; --filter clauses override its fields
+    #[arg(long)]
+    segment: Option<String>,
+
+    /// Publish to this broadcast channel instead of sending to devices
+    #[arg(long, conflicts_with_all = ["filters", "segment", "percent", "preview"])]
+    channel: Option<String>,
+
+    /// The channel's APNs environment
+    #[arg(long, requires = "channel", value_parser = ["sandbox", "production"])]
+    environment: Option<String>,
+
+    /// Send to only this percentage of the targeted devices; the same
+    /// --collapse-id picks the same devices, so a canary can be widened
+    #[arg(long, value_parser = parse_percent)]
+    percent: Option<f64>,
+
+    /// Show the rendered APNs payload, its size and any warnings instead
+    /// of sending
+    #[arg(long)]
+    preview: bool,
+
+    /// Exit non-zero unless every targeted device got the push now,
+    /// counting deferred, skipped and snoozed devices as failed
+    #[arg(long)]
+    fail_on_any: bool,
+
+    /// Print one `key=value` summary line for CI logs instead of a line per
+    /// device
+    #[arg(long, conflicts_with = "preview")]
+    summary: bool,
+
+    /// Start from the options saved as [presets.<name>] in the config file;
+    /// options given here win
+    #[arg(long)]
+    preset: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum FilterClause {
+    DeviceType(String),
+    DeviceName(String),
+    AppVersion(String),
+    MinAppVersion(String),
+    MinOsVersion(String),
+    Locale(String),
+    Timezone(String),
+    Topic(String),
+    Tag(String, String),
+}
+
+fn parse_filter_clause(s: &str) -> Result<FilterClause, String> {
+    let (key, op, value) = if let Some((key, value)) = s.split_once(">=") {
+        (key, ">=", value)
+    } else if let Some((key, value)) = s.split_once('=') {
+        (key, "=", value)
+    } else {
+        return Err(format!("expected key=value or key>=value, got '{}'", s));
+    };
+
+    let value = value.trim().to_string();
+    match (key.trim(), op) {
+        ("device_type", "=") => Ok(FilterClause::DeviceType(value)),
+        ("name" | "device_name", "=") => Ok(FilterClause::DeviceName(value)),
+        ("app_version", "=") => Ok(FilterClause::AppVersion(value)),
+        ("app_version", ">=") => Ok(FilterClause::MinAppVersion(value)),
+        ("os_version", ">=") => Ok(FilterClause::MinOsVersion(value)),
+        ("locale", "=") => Ok(FilterClause::Locale(value)),
+        ("timezone", "=") => Ok(FilterClause::Timezone(value)),
+        ("topic", "=") => Ok(FilterClause::Topic(value)),
+        (key, "=") if key.starts_with("tag.") => {
+            let key = &key["tag.".len()..];
+            check_tag(key, Some(&value))?;
+            Ok(FilterClause::Tag(key.to_string(), value))
+        }
+        (key, op) => Err(format!("unsupported filter '{}{}'", key, op)),
+    }
+}
+
+/// Tags travel as `key=value,key=value` in filters, so keys are limited to
+/// what the server accepts and values can't hold a comma.
+fn check_tag(key: &str, value: Option<&str>) -> Result<(), String> {
+    let valid_key = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid_key {
+        return Err(format!(
+            "tag keys are letters, digits, '-', '_' or '.', got '{}'",
+            key
+        ));
+    }
+    if let Some(value) = value.filter(|value| value.contains(',')) {
+        return Err(format!("tag values can't contain ',', got '{}'", value));
+    }
+    Ok(())
+}
+
+/// Parses `key=value` into a tag to set and `key=` into one to remove.
+fn parse_tag(s: &str) -> Result<(String, Option<String>), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected key=value or key=, got '{}'", s))?;
+    if key.trim().is_empty() {
+        return Err(format!("missing key in '{}'", s));
+    }
+    let value = value.trim();
+    check_tag(key.trim(), Some(value))?;
+    Ok((
+        key.trim().to_string(),
+        (!value.is_empty()).then(|| value.to_string()),
+    ))
+}
+
+/// Parses `-d key=value` as a string and `-d key:=json` as a JSON value,
+/// splitting dotted keys into a path.
+fn parse_data_pair(s: &str) -> Result<(Vec<String>, Value), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected key=value or key:=json, got '{}'", s))?;
+    let (key, value) = match key.strip_suffix(':') {
+        Some(key) => {
+            let json = serde_json::from_str(value)
+                .map_err(|e| format!("invalid JSON for '{}': {}", key, e))?;
+            (key, json)
+        }
+        None => (key, Value::String(value.to_string())),
+    };
+    if key.is_empty() {
+        return Err(format!("missing key in '{}'", s));
+    }
+    Ok((split_data_key(key)?, value))
+}
+
+/// Parses `--action id=Title` into an action object, or takes a JSON object
+/// as is for the other fields.
+fn parse_json_object(s: &str) -> Result<Value, String> {
+    match serde_json::from_str(s) {
+        Ok(value @ Value::Object(_)) => Ok(value),
+        Ok(_) => Err("expected a JSON object".to_string()),
+        Err(e) => Err(format!("invalid JSON: {}", e)),
+    }
+}
+
+fn parse_action(s: &str) -> Result<Value, String> {
+    if s.trim_start().starts_with('{') {
+        let action: Value =
+            serde_json::from_str(s).map_err(|e| format!("invalid action JSON: {}", e))?;
+        return match action.get("id").zip(action.get("title")) {
+            Some(_) => Ok(action),
+            None => Err("action JSON needs an id and a title".to_string()),
+        };
+    }
+    match s.split_once('=') {
+        Some((id, title)) if !id.is_empty() && !title.is_empty() => {
+            Ok(serde_json::json!({ "id": id, "title": title }))
+        }
+        _ => Err(format!("expected id=Title or a JSON object, got '{}'", s)),
+    }
+}
+
+/// Splits "user.id" into ["user", "id"]; `\.` keeps a dot in the key.
+fn split_data_key(key: &str) -> Result<Vec<String>, String> {
+    let mut path = vec![String::new()];
+    let mut chars = key.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'.') => {
+                chars.next();
+                path.last_mut().unwrap().push('.');
+            }
+            '.' => path.push(String::new()),
+            c => path.last_mut().unwrap().push(c),
+        }
+    }
+    if path.iter().any(String::is_empty) {
+        return Err(format!("empty segment in key '{}'", key));
+    }
+    Ok(path)
+}
+
+/// Builds the data map from `-d` pairs in order, nesting dotted paths.
+/// A later pair replaces whatever an earlier one put at the same path.
+fn nested_data(pairs: Vec<(Vec<String>, Value)>) -> HashMap<String, Value> {
+    let mut root = serde_json::Map::new();
+    for (path, value) in pairs {
+        insert_at_path(&mut root, &path, value);
+    }
+    root.into_iter().collect()
+}
+
+fn insert_at_path(map: &mut serde_json::Map<String, Value>, path: &[String], value: Value) {
+    let Some((key, rest)) = path.split_first() else {
+        return;
+    };
+    if rest.is_empty() {
+        map.insert(key.clone(), value);
+        return;
+    }
+    let child = map
+        .entry(key.clone())
+        .or_insert_with(|| Value::Object(serde_json::Map::new()));
+    if !child.is_object() {
+        *child = Value::Object(serde_json::Map::new());
+    }
+    if let Value::Object(child) = child {
+        insert_at_path(child, rest, value);
+    }
+}
+
+/// Parses a rollout percentage, more than 0 and at most 100.
+fn parse_percent(s: &str) -> Result<f64, String> {
+    match s.trim().trim_end_matches('%').parse::<f64>() {
+        Ok(percent) if percent > 0.0 && percent <= 100.0 => Ok(percent),
+        _ => Err(format!("expected a percentage in (0, 100], got '{}'", s)),
+    }
+}
+
+/// Parses durations such as "90", "30m", "2h" or "1h30m" into seconds.
+/// A bare number is seconds; units are s, m, h, d and w.
+fn parse_duration_secs(s: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid duration '{}', expected e.g. 30m, 2h or 1d", s);
+    let value = s.trim();
+    if value.is_empty() {
+        return Err(invalid());
+    }
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Ok(seconds);
+    }
+
+    let mut total: u64 = 0;
+    let mut digits = String::new();
+    for c in value.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let amount: u64 = digits.parse().map_err(|_| invalid())?;
+        digits.clear();
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        total = amount
+            .checked_mul(unit)
+            .and_then(|secs| total.checked_add(secs))
+            .ok_or_else(invalid)?;
+    }
+    if !digits.is_empty() {
+        return Err(invalid());
+    }
+    Ok(total)
+}
+
+#[derive(Serialize, Default)]
+struct SendRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subtitle: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    launch_image: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    raw: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title_loc_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title_loc_args: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    loc_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    loc_args: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    badge: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sound: Option<SoundConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_available: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mutable_content: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    category: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thread_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interruption_level: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    relevance_score: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target_content_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stale_date: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filter_criteria: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_state: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dismissal_date: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    actions: Option<Vec<Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    push_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    priority: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    collapse_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expiration: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_in_seconds: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    defer_throttled: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<HashMap<String, Value>>,
+    /// Set by `cmd_send` once `--attach` is uploaded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attachment: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filter: Option<DeviceFilter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    segment: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sample_percent: Option<f64>,
+}
+
+#[derive(Serialize, Default, Debug, PartialEq)]
+struct DeviceFilter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    app_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_app_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_os_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    locale: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timezone: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    topic: Option<String>,
+    /// `key=value,key=value`, which the server reads in JSON and query strings alike.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tags: Option<String>,
+}
+
+impl DeviceFilter {
+    fn from_clauses(clauses: Vec<FilterClause>) -> Option<Self> {
+        if clauses.is_empty() {
+            return None;
+        }
+        let mut filter = DeviceFilter::default();
+        for clause in clauses {
+            match clause {
+                FilterClause::DeviceType(v) => filter.device_type = Some(v),
+                FilterClause::DeviceName(v) => filter.device_name = Some(v),
+                FilterClause::AppVersion(v) => filter.app_version = Some(v),
+                FilterClause::MinAppVersion(v) => filter.min_app_version = Some(v),
+                FilterClause::MinOsVersion(v) => filter.min_os_version = Some(v),
+                FilterClause::Locale(v) => filter.locale = Some(v),
+                FilterClause::Timezone(v) => filter.timezone = Some(v),
+                FilterClause::Topic(v) => filter.topic = Some(v),
+                FilterClause::Tag(key, value) => {
+                    let tag = format!("{}={}", key, value);
+                    filter.tags = Some(match filter.tags.take() {
+                        Some(tags) => format!("{},{}", tags, tag),
+                        None => tag,
+                    });
+                }
+            }
+        }
+        Some(filter)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum SoundConfig {
+    Simple(String),
+    Critical {
+        name: String,
         critical: bool,
         #[serde(skip_serializing_if = "Option::is_none")]
         volume: Option<f64>,
@@ -232,15 +1364,143 @@ struct SendResponse {
     success: bool,
     sent: usize,
     failed: usize,
+    #[serde(default)]
+    deferred: usize,
+    #[serde(default)]
+    skipped: usize,
+    #[serde(default)]
+    snoozed: usize,
+    #[serde(default)]
+    request_id: Option<String>,
+    /// Set when a paused topic or segment held the send.
+    #[serde(default)]
+    paused: Option<PausedSend>,
     results: Vec<DeviceSendResult>,
 }
 
+#[derive(Deserialize)]
+struct PausedSend {
+    kind: String,
+    name: String,
+    action: String,
+}
+
+#[derive(Deserialize)]
+struct PausesResponse {
+    pauses: Vec<PauseRecord>,
+}
+
+#[derive(Deserialize)]
+struct PauseRecord {
+    kind: String,
+    name: String,
+    policy: String,
+    #[serde(default)]
+    reason: Option<String>,
+    paused_at: String,
+    queued: i64,
+    dropped: i64,
+}
+
+#[derive(Deserialize)]
+struct ResumeResponse {
+    kind: String,
+    name: String,
+    replayed: usize,
+    sent: usize,
+    failed: usize,
+    rejected: usize,
+    dropped: i64,
+}
+
 #[derive(Deserialize)]
 struct DeviceSendResult {
     device_token: String,
     success: bool,
     apns_id: Option<String>,
     error: Option<String>,
+    #[serde(default)]
+    error_code: Option<String>,
+    #[serde(default)]
+    warning: Option<String>,
+    #[serde(default)]
+    deferred_until: Option<u64>,
+    #[serde(default)]
+    skipped_duplicate: bool,
+    #[serde(default)]
+    skipped_snoozed: bool,
+    #[serde(default)]
+    fallback_environment: Option<String>,
+    #[serde(default)]
+    environment_corrected: bool,
+    #[serde(default)]
+    truncated: bool,
+}
+
+#[derive(Deserialize)]
+struct PreviewResponse {
+    summary: String,
+    headers: BTreeMap<String, String>,
+    payload: Value,
+    size_bytes: usize,
+    max_size_bytes: usize,
+    #[serde(default)]
+    warnings: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct HealthResponse {
+    status: String,
+    version: String,
+    uptime_seconds: u64,
+    queue_depth: usize,
+    checks: HealthChecks,
+}
+
+/// What `/health` tells callers without a read key once the server requires
+/// keys: whether it's healthy, without the checks.
+#[derive(Deserialize)]
+struct HealthStatus {
+    status: String,
+}
+
+#[derive(Deserialize)]
+struct VersionResponse {
+    version: String,
+    git_hash: String,
+    api_version: u32,
+}
+
+#[derive(Deserialize)]
+struct HealthChecks {
+    database: HealthCheck,
+    apns: HealthCheck,
+}
+
+#[derive(Deserialize)]
+struct HealthCheck {
+    ok: bool,
+    error: Option<String>,
+    /// Something that doesn't fail the check, like APNs rejecting the
+    /// provider token over and over.
+    #[serde(default)]
+    warning: Option<String>,
+    #[serde(default)]
+    token_age_seconds: Option<u64>,
+    #[serde(default)]
+    consecutive_failures: Option<u32>,
+    #[serde(default)]
+    credentials: Option<ProviderCredentials>,
+}
+
+/// Who the server's APNs provider sends as.
+#[derive(Deserialize)]
+struct ProviderCredentials {
+    #[serde(default)]
+    mock: bool,
+    key_id: Option<String>,
+    team_id: Option<String>,
+    topic: String,
 }
 
 #[derive(Deserialize)]
@@ -249,71 +1509,306 @@ struct StatsResponse {
     sandbox_devices: i64,
     production_devices: i64,
     total_pushes: i64,
+    #[serde(default)]
+    failed_pushes: i64,
+    #[serde(default)]
+    opened_pushes: Option<i64>,
+    #[serde(default)]
+    open_rate: Option<f64>,
+    #[serde(default)]
+    device_types: Vec<PlatformCount>,
+    #[serde(default)]
+    os_versions: Vec<PlatformCount>,
+    series: Option<StatsSeries>,
+    #[serde(default)]
+    groups: Option<Vec<StatsGroup>>,
+}
+
+#[derive(Deserialize)]
+struct PlatformCount {
+    value: String,
+    devices: i64,
+    share: f64,
+}
+
+#[derive(Deserialize)]
+struct StatsGroup {
+    key: Option<String>,
+    #[serde(default)]
+    devices: Option<i64>,
+    sent: i64,
+    failed: i64,
+    failure_rate: f64,
+    opened: i64,
+}
+
+#[derive(Deserialize)]
+struct StatsSeries {
+    points: Vec<StatsPoint>,
+}
+
+#[derive(Deserialize)]
+struct StatsPoint {
+    bucket: String,
+    sent: i64,
+    failed: i64,
+    failure_rate: f64,
+    sandbox: i64,
+    production: i64,
 }
 
 #[derive(Deserialize)]
 struct ErrorResponse {
     error: String,
+    #[serde(default)]
+    request_id: Option<String>,
 }
 
-impl SendArgs {
-    fn is_empty(&self) -> bool {
-        self.body_positional.is_none()
-            && self.title.is_none()
-            && self.subtitle.is_none()
-            && self.body.is_none()
-            && self.launch_image.is_none()
-            && self.title_loc_key.is_none()
-            && self.loc_key.is_none()
-            && self.badge.is_none()
-            && self.sound.is_none()
-            && !self.sound_critical
-            && !self.content_available
-            && !self.mutable_content
-            && self.category.is_none()
-            && self.data.is_empty()
+impl ErrorResponse {
+    fn message(&self) -> String {
+        match &self.request_id {
+            Some(request_id) => format!("{} (request {})", self.error, request_id),
+            None => self.error.clone(),
+        }
     }
+}
 
-    fn into_request(self) -> SendRequest {
-        let body = self.body.or(self.body_positional);
-
-        let title_loc_args = self
-            .title_loc_args
-            .map(|s| s.split(',').map(|s| s.trim().to_string()).collect());
+#[derive(Deserialize)]
+struct DevicePushesResponse {
+    pushes: Vec<DevicePushRecord>,
+}
 
-        let loc_args = self
-            .loc_args
-            .map(|s| s.split(',').map(|s| s.trim().to_string()).collect());
+#[derive(Deserialize)]
+struct UsageResponse {
+    keys: Vec<KeyUsage>,
+}
 
-        let sound = if self.sound_critical {
-            Some(SoundConfig::Critical {
-                name: self.sound_name.unwrap_or_else(|| "default".to_string()),
-                critical: true,
-                volume: self.sound_volume,
-            })
-        } else {
-            self.sound.map(SoundConfig::Simple)
-        };
+#[derive(Deserialize)]
+struct KeyUsage {
+    name: String,
+    role: String,
+    today: u64,
+    this_month: u64,
+    daily_quota: Option<u64>,
+    monthly_quota: Option<u64>,
+}
 
-        let data = if self.data.is_empty() {
-            None
-        } else {
-            let mut map = HashMap::new();
-            for pair in self.data {
-                if let Some((key, value)) = pair.split_once('=') {
-                    map.insert(key.to_string(), Value::String(value.to_string()));
-                }
-            }
-            Some(map)
-        };
+#[derive(Deserialize)]
+struct IssuedKeysResponse {
+    keys: Vec<IssuedKey>,
+}
 
-        let content_available = if self.content_available {
-            Some(true)
-        } else {
-            None
-        };
+#[derive(Deserialize)]
+struct IssuedKey {
+    name: String,
+    role: String,
+    #[serde(default)]
+    topic: Option<String>,
+    #[serde(default)]
+    created_by: Option<String>,
+    created_at: String,
+    /// Only in the response that issues it.
+    #[serde(default)]
+    key: Option<String>,
+}
 
-        let mutable_content = if self.mutable_content {
+#[derive(Deserialize)]
+struct TestDeviceResponse {
+    device_token: String,
+    environment: Option<String>,
+    results: Vec<EnvironmentResult>,
+}
+
+#[derive(Deserialize)]
+struct EnvironmentResult {
+    environment: String,
+    success: bool,
+    apns_id: Option<String>,
+    error: Option<String>,
+    error_code: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct DeleteInstallationResponse {
+    installation_id: String,
+    dry_run: bool,
+    devices: u64,
+    pushes: u64,
+}
+
+#[derive(Deserialize)]
+struct WithdrawResponse {
+    push_id: i64,
+    action: String,
+    #[serde(default)]
+    collapse_id: Option<String>,
+    result: DeviceSendResult,
+}
+
+#[derive(Deserialize)]
+struct DevicesResponse {
+    devices: Vec<DeviceRecord>,
+}
+
+#[derive(Deserialize)]
+struct InstallationActivity {
+    pushes: u64,
+    #[serde(default)]
+    last_push_at: Option<String>,
+    last_active_at: String,
+}
+
+#[derive(Deserialize)]
+struct InstallationsResponse {
+    installations: Vec<InstallationSummary>,
+}
+
+#[derive(Deserialize)]
+struct InstallationSummary {
+    installation_id: String,
+    devices: u64,
+    #[serde(flatten)]
+    activity: InstallationActivity,
+}
+
+#[derive(Deserialize)]
+struct InstallationDevicesResponse {
+    installations: Vec<InstallationDevices>,
+}
+
+#[derive(Deserialize)]
+struct InstallationDevices {
+    installation_id: Option<String>,
+    #[serde(flatten)]
+    activity: InstallationActivity,
+    devices: Vec<DeviceRecord>,
+}
+
+#[derive(Deserialize)]
+struct DeviceRecord {
+    device_token: String,
+    environment: String,
+    device_name: Option<String>,
+    device_type: Option<String>,
+    #[serde(default)]
+    locale: Option<String>,
+    #[serde(default)]
+    timezone: Option<String>,
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+    #[serde(default)]
+    snoozed_until: Option<String>,
+    #[serde(default)]
+    expires_at: Option<String>,
+    #[serde(default)]
+    tags: BTreeMap<String, String>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+struct DeviceTagsResponse {
+    tags: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct ChannelsResponse {
+    channels: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ChannelRecord {
+    channel_id: String,
+    environment: String,
+    message_storage_policy: String,
+}
+
+#[derive(Deserialize)]
+struct ChannelSendResponse {
+    channel_id: String,
+    apns_request_id: String,
+}
+
+#[derive(Deserialize)]
+struct DevicePushRecord {
+    id: i64,
+    apns_id: Option<String>,
+    title: Option<String>,
+    body: Option<String>,
+    status: String,
+    error: Option<String>,
+    #[serde(default)]
+    error_code: Option<String>,
+    sent_at: String,
+}
+
+#[derive(Deserialize)]
+struct SegmentsResponse {
+    segments: Vec<Segment>,
+}
+
+#[derive(Deserialize)]
+struct Segment {
+    name: String,
+    filter: Value,
+}
+
+impl SendArgs {
+    fn is_empty(&self) -> bool {
+        self.body_positional.is_none()
+            && self.title.is_none()
+            && self.subtitle.is_none()
+            && self.body.is_none()
+            && self.launch_image.is_none()
+            && self.title_loc_key.is_none()
+            && self.loc_key.is_none()
+            && self.message_key.is_none()
+            && self.badge.is_none()
+            && self.sound.is_none()
+            && !self.sound_critical
+            && !self.content_available
+            && !self.mutable_content
+            && self.category.is_none()
+            && self.event.is_none()
+            && self.data.is_empty()
+    }
+
+    fn into_request(self) -> SendRequest {
+        let body = self.body.or(self.body_positional);
+
+        let title_loc_args = self
+            .title_loc_args
+            .map(|s| s.split(',').map(|s| s.trim().to_string()).collect());
+
+        let loc_args = self
+            .loc_args
+            .map(|s| s.split(',').map(|s| s.trim().to_string()).collect());
+
+        let sound = if self.sound_critical {
+            Some(SoundConfig::Critical {
+                name: self.sound_name.unwrap_or_else(|| "default".to_string()),
+                critical: true,
+                volume: self.sound_volume,
+            })
+        } else {
+            self.sound.map(SoundConfig::Simple)
+        };
+
+        let data = if self.data.is_empty() {
+            None
+        } else {
+            Some(nested_data(self.data))
+        };
+
+        let content_available = if self.content_available {
+            Some(true)
+        } else {
+            None
+        };
+
+        let mutable_content = if self.mutable_content {
             Some(true)
         } else {
             None
@@ -324,528 +1819,3464 @@ impl SendArgs {
             subtitle: self.subtitle,
             body,
             launch_image: self.launch_image,
+            raw: self.raw.then_some(true),
             title_loc_key: self.title_loc_key,
             title_loc_args,
             loc_key: self.loc_key,
             loc_args,
+            message_key: self.message_key,
             badge: self.badge,
             sound,
             content_available,
             mutable_content,
             category: self.category,
+            thread_id: self.thread_id,
+            interruption_level: self.interruption_level,
+            relevance_score: self.relevance_score,
+            target_content_id: self.target_content_id,
+            stale_date: self.stale_date,
+            filter_criteria: self.filter_criteria,
+            event: self.event,
+            content_state: self.content_state,
+            dismissal_date: self.dismissal_date,
+            actions: if self.actions.is_empty() {
+                None
+            } else {
+                Some(self.actions)
+            },
+            push_type: self.push_type,
+            topic: self.topic,
             priority: self.priority,
             collapse_id: self.collapse_id,
             expiration: self.expiration,
+            expires_in_seconds: self.expires_in,
+            defer_throttled: self.defer_throttled.then_some(true),
             data,
+            attachment: None,
+            filter: DeviceFilter::from_clauses(self.filters),
+            segment: self.segment,
+            sample_percent: self.percent,
+        }
+    }
+}
+
+/// `psh send` exits with this when the send couldn't be made: the server
+/// was unreachable or refused the request.
+const EXIT_TRANSPORT_ERROR: i32 = 3;
+
+/// What a send did with the devices it targeted.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct SendCounts {
+    sent: usize,
+    failed: usize,
+    /// Deferred, skipped as duplicates or held for snoozed devices.
+    held: usize,
+}
+
+impl SendCounts {
+    /// 0 when every device got the push, 1 when only some did and 2 when
+    /// none did. Held devices only count against the send with
+    /// `fail_on_any`, as does a send that targeted no devices at all.
+    fn exit_code(self, fail_on_any: bool) -> i32 {
+        let failed = if fail_on_any {
+            self.failed + self.held
+        } else {
+            self.failed
+        };
+        if failed == 0 && (self.sent > 0 || !fail_on_any) {
+            0
+        } else if self.sent > 0 {
+            1
+        } else {
+            2
+        }
+    }
+
+    fn status(self) -> &'static str {
+        match self.exit_code(false) {
+            0 => "ok",
+            1 => "partial",
+            _ => "failed",
         }
     }
 }
 
-async fn cmd_send(server: &str, args: SendArgs) -> Result<()> {
-    let client = reqwest::Client::new();
+/// `status=partial sent=2 failed=1 ...`: the whole send on one line, with
+/// how many devices failed with each error code.
+fn format_send_summary(result: &SendResponse, counts: SendCounts) -> String {
+    let mut line = format!(
+        "status={} sent={} failed={} deferred={} skipped={} snoozed={}",
+        counts.status(),
+        result.sent,
+        result.failed,
+        result.deferred,
+        result.skipped,
+        result.snoozed
+    );
+    let mut errors: BTreeMap<&str, usize> = BTreeMap::new();
+    for r in result.results.iter().filter(|r| !r.success) {
+        *errors
+            .entry(r.error_code.as_deref().unwrap_or("Unknown"))
+            .or_default() += 1;
+    }
+    if !errors.is_empty() {
+        let errors: Vec<String> = errors
+            .iter()
+            .map(|(code, count)| format!("{}:{}", code, count))
+            .collect();
+        line.push_str(&format!(" errors={}", errors.join(",")));
+    }
+    if let Some(request_id) = &result.request_id {
+        line.push_str(&format!(" request_id={}", request_id));
+    }
+    line
+}
+
+async fn cmd_send(
+    client: &reqwest::Client,
+    server: &str,
+    mut args: SendArgs,
+) -> Result<SendCounts> {
+    if let Some(channel) = args.channel.take() {
+        let environment = args.environment.take();
+        cmd_channel_send(client, server, &channel, environment, args).await?;
+        return Ok(SendCounts {
+            sent: 1,
+            ..Default::default()
+        });
+    }
     let url = format!("{}/send", server);
-    let request = args.into_request();
+    let attach = args.attach.take();
+    let summary_only = args.summary;
+    let mut request = args.into_request();
+    if let Some(path) = attach {
+        request.attachment = Some(upload_attachment(client, server, &path).await?);
+    }
 
     let response = client
         .post(&url)
         .json(&request)
-        .send()
+        .send_logged()
         .await
         .context("Failed to connect to server")?;
 
     let status = response.status();
     if status.is_success() {
         let result: SendResponse = response.json().await.context("Invalid response")?;
-        println!(
-            "Sent: {}, Failed: {}",
-            result.sent, result.failed
-        );
-        for r in result.results {
-            if r.success {
-                println!(
-                    "  {} -> {}",
-                    truncate_token(&r.device_token),
-                    r.apns_id.unwrap_or_default()
-                );
-            } else {
-                println!(
-                    "  {} -> ERROR: {}",
-                    truncate_token(&r.device_token),
-                    r.error.unwrap_or_else(|| "Unknown error".to_string())
-                );
+        let counts = SendCounts {
+            sent: result.sent,
+            failed: result.failed,
+            held: result.deferred + result.skipped + result.snoozed,
+        };
+        if summary_only {
+            say!("{}", format_send_summary(&result, counts));
+            return Ok(counts);
+        }
+        let mut summary = format!("Sent: {}, Failed: {}", result.sent, result.failed);
+        if result.deferred > 0 {
+            summary.push_str(&format!(", Deferred: {}", result.deferred));
+        }
+        if result.skipped > 0 {
+            summary.push_str(&format!(", Skipped: {}", result.skipped));
+        }
+        if result.snoozed > 0 {
+            summary.push_str(&format!(", Snoozed: {}", result.snoozed));
+        }
+        say!("{}", summary);
+        if let Some(paused) = &result.paused {
+            say!(
+                "{} while {} {} is paused",
+                if paused.action == "queued" {
+                    "Queued"
+                } else {
+                    "Dropped"
+                },
+                paused.kind,
+                paused.name
+            );
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        for r in &result.results {
+            say!("{}", format_send_result(r, now));
+        }
+        if let Some(request_id) = result.request_id.filter(|_| result.failed > 0) {
+            say!("Request ID: {}", request_id);
+        }
+        Ok(counts)
+    } else {
+        let error: ErrorResponse = response
+            .json()
+            .await
+            .unwrap_or(ErrorResponse {
+                error: format!("HTTP {}", status),
+                request_id: None,
+            });
+        anyhow::bail!("Error: {}", error.message());
+    }
+}
+
+async fn cmd_channel_send(
+    client: &reqwest::Client,
+    server: &str,
+    channel: &str,
+    environment: Option<String>,
+    args: SendArgs,
+) -> Result<()> {
+    let environment = environment.unwrap_or_else(|| "production".to_string());
+    let response = client
+        .post(format!("{}/channels/{}/send", server, channel))
+        .query(&[("environment", &environment)])
+        .json(&args.into_request())
+        .send_logged()
+        .await
+        .context("Failed to connect to server")?;
+    let result: ChannelSendResponse = check_response(response)
+        .await?
+        .json()
+        .await
+        .context("Invalid response")?;
+    say!(
+        "Published to channel {} ({}), apns-request-id {}",
+        result.channel_id,
+        environment,
+        result.apns_request_id
+    );
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct AttachmentResponse {
+    id: String,
+}
+
+/// The content type the server stores a file of this extension as.
+fn attachment_content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("heic") => "image/heic",
+        Some("webp") => "image/webp",
+        Some("mp3") => "audio/mpeg",
+        Some("m4a") => "audio/mp4",
+        Some("wav") => "audio/wav",
+        Some("mp4") => "video/mp4",
+        Some("mov") => "video/quicktime",
+        Some("txt" | "log") => "text/plain",
+        Some("json") => "application/json",
+        Some("pdf") => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Uploads `path` to `/attachments`, returning the id a send refers to it by.
+async fn upload_attachment(client: &reqwest::Client, server: &str, path: &Path) -> Result<String> {
+    let bytes =
+        std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let response = client
+        .post(format!("{}/attachments", server))
+        .header(reqwest::header::CONTENT_TYPE, attachment_content_type(path))
+        .body(bytes)
+        .send_logged()
+        .await
+        .context("Failed to connect to server")?;
+    let attachment: AttachmentResponse = check_response(response)
+        .await?
+        .json()
+        .await
+        .context("Invalid response")?;
+    Ok(attachment.id)
+}
+
+/// Reads a piped body, cutting it to `limit` bytes with a trailing `…`.
+/// Empty input is no body.
+fn read_body(input: impl Read, limit: usize) -> io::Result<Option<String>> {
+    let mut bytes = Vec::new();
+    input.take(limit as u64 + 1).read_to_end(&mut bytes)?;
+    let truncated = bytes.len() > limit;
+    bytes.truncate(limit);
+    let mut body = String::from_utf8_lossy(&bytes).into_owned();
+    if truncated {
+        // Drop a character the cut split in two.
+        body.truncate(body.trim_end_matches('\u{FFFD}').len());
+        body.push('…');
+    }
+    let body = body.trim_end();
+    Ok((!body.is_empty()).then(|| body.to_string()))
+}
+
+/// Output lines longer than this are cut short in the push.
+const WATCH_LINE_CHARS: usize = 120;
+
+/// How a watched command ended, with the last lines it printed.
+struct WatchOutcome {
+    /// `None` when a signal ended the command.
+    code: Option<i32>,
+    elapsed: Duration,
+    tail: Vec<String>,
+}
+
+async fn cmd_watch(client: &reqwest::Client, server: &str, args: WatchArgs) -> Result<()> {
+    let outcome = run_watched(&args.command, args.lines)?;
+    // The command's status matters more to a pipeline than the push.
+    if let Err(e) = cmd_send(client, server, watch_send_args(&args, &outcome)).await {
+        eprintln!("Warning: failed to send notification: {:#}", e);
+    }
+    match outcome.code {
+        Some(0) => Ok(()),
+        code => std::process::exit(code.unwrap_or(1)),
+    }
+}
+
+/// Runs `command`, passing its output through while keeping the last
+/// `lines` lines of stdout and stderr together.
+fn run_watched(command: &[String], lines: usize) -> Result<WatchOutcome> {
+    let started = Instant::now();
+    let mut child = Command::new(&command[0])
+        .args(&command[1..])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run {}", command[0]))?;
+    let tail = Arc::new(Mutex::new(VecDeque::new()));
+    let stdout = child.stdout.take().context("No stdout")?;
+    let stderr = child.stderr.take().context("No stderr")?;
+    let readers = [
+        tee(stdout, io::stdout(), &tail, lines),
+        tee(stderr, io::stderr(), &tail, lines),
+    ];
+    let status = child.wait().context("Failed to wait for the command")?;
+    for reader in readers {
+        let _ = reader.join();
+    }
+    let tail = std::mem::take(&mut *tail.lock().unwrap_or_else(|e| e.into_inner()));
+    Ok(WatchOutcome {
+        code: status.code(),
+        elapsed: started.elapsed(),
+        tail: tail.into(),
+    })
+}
+
+fn tee(
+    from: impl Read + Send + 'static,
+    mut to: impl Write + Send + 'static,
+    tail: &Arc<Mutex<VecDeque<String>>>,
+    lines: usize,
+) -> std::thread::JoinHandle<()> {
+    let tail = Arc::clone(tail);
+    std::thread::spawn(move || {
+        let mut reader = BufReader::new(from);
+        let mut line = Vec::new();
+        while reader.read_until(b'\n', &mut line).unwrap_or(0) > 0 {
+            let _ = to.write_all(&line).and_then(|_| to.flush());
+            let mut tail = tail.lock().unwrap_or_else(|e| e.into_inner());
+            push_tail(&mut tail, &String::from_utf8_lossy(&line), lines);
+            line.clear();
+        }
+    })
+}
+
+/// Keeps the last `max` non-blank lines, each as it last appeared on a
+/// terminal (after any `\r`) and cut to `WATCH_LINE_CHARS`.
+fn push_tail(tail: &mut VecDeque<String>, line: &str, max: usize) {
+    let line = line.trim_end();
+    let line = line.rsplit('\r').next().unwrap_or(line).trim_end();
+    if line.is_empty() || max == 0 {
+        return;
+    }
+    let line = match line.char_indices().nth(WATCH_LINE_CHARS) {
+        Some((end, _)) => format!("{}…", &line[..end]),
+        None => line.to_string(),
+    };
+    if tail.len() == max {
+        tail.pop_front();
+    }
+    tail.push_back(line);
+}
+
+fn watch_send_args(args: &WatchArgs, outcome: &WatchOutcome) -> SendArgs {
+    let command = args.command.join(" ");
+    let status = match outcome.code {
+        Some(0) => "succeeded".to_string(),
+        Some(code) => format!("failed with exit code {}", code),
+        None => "was killed by a signal".to_string(),
+    };
+    let mut body = format!(
+        "{} {} after {}",
+        command,
+        status,
+        format_uptime(outcome.elapsed.as_secs())
+    );
+    for line in &outcome.tail {
+        body.push('\n');
+        body.push_str(line);
+    }
+    let title = args.title.clone().unwrap_or_else(|| match outcome.code {
+        Some(0) => format!("Succeeded: {}", command),
+        _ => format!("Failed: {}", command),
+    });
+    let mut data = vec![(
+        vec!["duration_seconds".to_string()],
+        Value::from(outcome.elapsed.as_secs()),
+    )];
+    if let Some(code) = outcome.code {
+        data.push((vec!["exit_code".to_string()], Value::from(code)));
+    }
+    let mut send = SendArgs {
+        title: Some(title),
+        body: Some(body),
+        data,
+        filters: args.filters.clone(),
+        ..Default::default()
+    };
+    args.outcome.apply(&mut send, outcome.code == Some(0));
+    send
+}
+
+async fn cmd_notify_done(
+    client: &reqwest::Client,
+    server: &str,
+    args: NotifyDoneArgs,
+) -> Result<()> {
+    let status = args.status;
+    cmd_send(client, server, notify_done_send_args(args)).await?;
+    // Pass the status on, so `cmd; psh notify-done --status $?` fails when cmd did.
+    if status != 0 {
+        std::process::exit(status);
+    }
+    Ok(())
+}
+
+fn notify_done_send_args(args: NotifyDoneArgs) -> SendArgs {
+    let succeeded = args.status == 0;
+    let title = args.title.unwrap_or_else(|| match args.status {
+        0 => "Succeeded".to_string(),
+        code => format!("Failed with exit code {}", code),
+    });
+    let mut send = SendArgs {
+        title: Some(title),
+        body: args.message,
+        data: vec![(vec!["exit_code".to_string()], Value::from(args.status))],
+        filters: args.filters,
+        ..Default::default()
+    };
+    args.outcome.apply(&mut send, succeeded);
+    send
+}
+
+async fn cmd_preview(client: &reqwest::Client, server: &str, args: SendArgs) -> Result<()> {
+    let response = client
+        .post(format!("{}/preview", server))
+        .json(&args.into_request())
+        .send_logged()
+        .await
+        .context("Failed to connect to server")?;
+    let preview: PreviewResponse = check_response(response)
+        .await?
+        .json()
+        .await
+        .context("Invalid response")?;
+    for line in format_preview(&preview) {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+fn format_preview(preview: &PreviewResponse) -> Vec<String> {
+    let mut lines = vec![preview.summary.clone(), "Headers:".to_string()];
+    for (name, value) in &preview.headers {
+        lines.push(format!("  {}: {}", name, value));
+    }
+    lines.push(format!(
+        "Payload ({} of {} bytes):",
+        preview.size_bytes, preview.max_size_bytes
+    ));
+    let payload = serde_json::to_string_pretty(&preview.payload).unwrap_or_default();
+    lines.extend(payload.lines().map(|line| format!("  {}", line)));
+    if !preview.warnings.is_empty() {
+        lines.push("Warnings:".to_string());
+        for warning in &preview.warnings {
+            lines.push(format!("  - {}", warning));
+        }
+    }
+    lines
+}
+
+async fn cmd_stats(client: &reqwest::Client, server: &str, args: StatsArgs) -> Result<()> {
+    let url = format!("{}/stats", server);
+
+    let mut query = Vec::new();
+    if let Some(since) = &args.since {
+        query.push(("since", since.as_str()));
+    }
+    if let Some(bucket) = &args.bucket {
+        query.push(("bucket", bucket.as_str()));
+    }
+    if let Some(by) = &args.by {
+        query.push(("group_by", by.as_str()));
+    }
+
+    let response = client
+        .get(&url)
+        .query(&query)
+        .send_logged()
+        .await
+        .context("Failed to connect to server")?;
+
+    let status = response.status();
+    if status.is_success() {
+        let stats: StatsResponse = response.json().await.context("Invalid response")?;
+        println!("Devices: {} total ({} sandbox, {} production)",
+            stats.total_devices,
+            stats.sandbox_devices,
+            stats.production_devices
+        );
+        println!("Pushes: {} ({} failed)", stats.total_pushes, stats.failed_pushes);
+        if let (Some(opened), Some(rate)) = (stats.opened_pushes, stats.open_rate) {
+            println!("Opened: {} ({:.1}% of delivered)", opened, rate * 100.0);
+        }
+        if let Some(line) = format_platforms("Device types", &stats.device_types) {
+            println!("{}", line);
+        }
+        if let Some(line) = format_platforms("OS versions", &stats.os_versions) {
+            println!("{}", line);
+        }
+        if let (Some(groups), Some(by)) = (&stats.groups, &args.by) {
+            println!();
+            for line in format_groups(by, groups) {
+                println!("{}", line);
             }
         }
+        if let Some(series) = stats.series {
+            print_series(&series, args.graph);
+        }
     } else {
         let error: ErrorResponse = response
             .json()
             .await
             .unwrap_or(ErrorResponse {
                 error: format!("HTTP {}", status),
+                request_id: None,
             });
-        anyhow::bail!("Error: {}", error.error);
+        anyhow::bail!("Error: {}", error.message());
     }
 
     Ok(())
 }
 
-async fn cmd_stats(server: &str) -> Result<()> {
-    let client = reqwest::Client::new();
-    let url = format!("{}/stats", server);
+async fn cmd_usage(client: &reqwest::Client, server: &str) -> Result<()> {
+    let response = client
+        .get(format!("{}/usage", server))
+        .send_logged()
+        .await
+        .context("Failed to connect to server")?;
+    let usage: UsageResponse = check_response(response)
+        .await?
+        .json()
+        .await
+        .context("Invalid response")?;
+
+    if usage.keys.is_empty() {
+        println!("The server doesn't require API keys");
+    }
+    for key in usage.keys {
+        println!("{}", format_usage_line(&key));
+    }
+    Ok(())
+}
+
+async fn cmd_test_device(client: &reqwest::Client, server: &str, token: &str) -> Result<()> {
+    let response = client
+        .post(format!("{}/devices/{}/test", server, token))
+        .send_logged()
+        .await
+        .context("Failed to connect to server")?;
+    let test: TestDeviceResponse = check_response(response)
+        .await?
+        .json()
+        .await
+        .context("Invalid response")?;
+    for line in format_test_device(&test) {
+        say!("{}", line);
+    }
+    if test.environment.is_none() {
+        anyhow::bail!("Neither APNs environment accepted the token");
+    }
+    Ok(())
+}
+
+async fn cmd_installations(client: &reqwest::Client, server: &str) -> Result<()> {
+    let response = client
+        .get(format!("{}/installations", server))
+        .send_logged()
+        .await
+        .context("Failed to connect to server")?;
+    let list: InstallationsResponse = check_response(response)
+        .await?
+        .json()
+        .await
+        .context("Invalid response")?;
+    if list.installations.is_empty() {
+        say!("No installations");
+    }
+    for installation in &list.installations {
+        println!(
+            "{}",
+            format_installation_line(
+                &installation.installation_id,
+                installation.devices,
+                &installation.activity
+            )
+        );
+    }
+    Ok(())
+}
+
+fn format_installation_line(
+    installation_id: &str,
+    devices: u64,
+    activity: &InstallationActivity,
+) -> String {
+    let count = |n: u64, one: &str, many: &str| format!("{n} {}", if n == 1 { one } else { many });
+    format!(
+        "{}\t{}\t{}\tlast push {}\tactive {}",
+        installation_id,
+        count(devices, "device", "devices"),
+        count(activity.pushes, "push", "pushes"),
+        activity.last_push_at.as_deref().unwrap_or("never"),
+        activity.last_active_at
+    )
+}
+
+async fn cmd_gdpr(client: &reqwest::Client, server: &str, command: GdprCommand) -> Result<()> {
+    match command {
+        GdprCommand::Delete {
+            installation_id,
+            dry_run,
+        } => {
+            let response = client
+                .delete(format!("{}/installations/{}", server, installation_id))
+                .query(&[("dry_run", dry_run)])
+                .send_logged()
+                .await
+                .context("Failed to connect to server")?;
+            let deleted: DeleteInstallationResponse = check_response(response)
+                .await?
+                .json()
+                .await
+                .context("Invalid response")?;
+            say!("{}", format_installation_deleted(&deleted));
+        }
+    }
+    Ok(())
+}
+
+fn format_installation_deleted(deleted: &DeleteInstallationResponse) -> String {
+    let count = |n: u64, one: &str, many: &str| format!("{n} {}", if n == 1 { one } else { many });
+    let verb = if deleted.dry_run {
+        "Would delete"
+    } else {
+        "Deleted"
+    };
+    format!(
+        "{} {} and {} for {}",
+        verb,
+        count(deleted.devices, "device", "devices"),
+        count(deleted.pushes, "push", "pushes"),
+        deleted.installation_id
+    )
+}
+
+async fn cmd_pushes(client: &reqwest::Client, server: &str, command: PushesCommand) -> Result<()> {
+    match command {
+        PushesCommand::Withdraw { id, title, body } => {
+            let mut request = client.post(format!("{}/pushes/{}/withdraw", server, id));
+            if title.is_some() || body.is_some() {
+                request = request.json(&serde_json::json!({ "title": title, "body": body }));
+            }
+            let response = request
+                .send_logged()
+                .await
+                .context("Failed to connect to server")?;
+            let withdrawn: WithdrawResponse = check_response(response)
+                .await?
+                .json()
+                .await
+                .context("Invalid response")?;
+            say!("{}", format_withdrawn(&withdrawn));
+            if !withdrawn.result.success {
+                anyhow::bail!("The withdrawal wasn't delivered");
+            }
+        }
+        PushesCommand::Diff { first, second } => {
+            let mut pushes = Vec::new();
+            for id in [first, second] {
+                let response = client
+                    .get(format!("{}/pushes/{}", server, id))
+                    .send_logged()
+                    .await
+                    .context("Failed to connect to server")?;
+                let push: Value = check_response(response)
+                    .await?
+                    .json()
+                    .await
+                    .context("Invalid response")?;
+                pushes.push(push);
+            }
+            for line in format_push_diff(first, &pushes[0], second, &pushes[1]) {
+                println!("{}", line);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A push record as `path -> JSON value`, with its stored custom data
+/// parsed so each key compares on its own. Nulls are left out, as the
+/// server omits most unset fields anyway.
+fn flatten_push(push: &Value) -> BTreeMap<String, String> {
+    let mut push = push.clone();
+    if let Some(object) = push.as_object_mut() {
+        object.remove("id");
+        if let Some(payload) = object.get_mut("payload") {
+            if let Some(parsed) = payload.as_str().and_then(|s| serde_json::from_str(s).ok()) {
+                *payload = parsed;
+            }
+        }
+    }
+    let mut fields = BTreeMap::new();
+    flatten_json(String::new(), &push, &mut fields);
+    fields
+}
+
+fn flatten_json(path: String, value: &Value, fields: &mut BTreeMap<String, String>) {
+    let join = |key: &str| {
+        if path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", path, key)
+        }
+    };
+    match value {
+        Value::Null => {}
+        Value::Object(object) if !object.is_empty() => {
+            for (key, value) in object {
+                flatten_json(join(key), value, fields);
+            }
+        }
+        Value::Array(items) if !items.is_empty() => {
+            for (i, item) in items.iter().enumerate() {
+                flatten_json(format!("{}[{}]", path, i), item, fields);
+            }
+        }
+        value => {
+            fields.insert(path, value.to_string());
+        }
+    }
+}
+
+/// `~ field: old -> new` for changed fields, `-` for ones only the first
+/// push has and `+` for ones only the second has, then a count.
+fn format_push_diff(first_id: i64, first: &Value, second_id: i64, second: &Value) -> Vec<String> {
+    let (first, second) = (flatten_push(first), flatten_push(second));
+    let mut lines = vec![format!("push {} -> push {}", first_id, second_id)];
+    let mut same = 0;
+    let paths: BTreeSet<&String> = first.keys().chain(second.keys()).collect();
+    for path in paths {
+        match (first.get(path), second.get(path)) {
+            (Some(a), Some(b)) if a == b => same += 1,
+            (Some(a), Some(b)) => lines.push(format!("~ {}: {} -> {}", path, a, b)),
+            (Some(a), None) => lines.push(format!("- {}: {}", path, a)),
+            (None, Some(b)) => lines.push(format!("+ {}: {}", path, b)),
+            (None, None) => {}
+        }
+    }
+    let differ = lines.len() - 1;
+    lines.push(if differ == 0 {
+        format!("Identical ({} fields)", same)
+    } else {
+        format!("{} fields differ, {} same", differ, same)
+    });
+    lines
+}
+
+async fn cmd_pause(
+    client: &reqwest::Client,
+    server: &str,
+    target: PauseTarget,
+    drop: bool,
+    reason: Option<String>,
+) -> Result<()> {
+    let policy = if drop { "drop" } else { "queue" };
+    let response = client
+        .post(format!("{}/pause", target.url(server)))
+        .json(&serde_json::json!({ "policy": policy, "reason": reason }))
+        .send_logged()
+        .await
+        .context("Failed to connect to server")?;
+    let pause: PauseRecord = check_response(response)
+        .await?
+        .json()
+        .await
+        .context("Invalid response")?;
+    say!(
+        "Paused {} {}: sends will be {}",
+        pause.kind,
+        pause.name,
+        if pause.policy == "drop" {
+            "dropped"
+        } else {
+            "queued until `psh resume`"
+        }
+    );
+    Ok(())
+}
+
+async fn cmd_resume(client: &reqwest::Client, server: &str, target: PauseTarget) -> Result<()> {
+    let response = client
+        .post(format!("{}/resume", target.url(server)))
+        .send_logged()
+        .await
+        .context("Failed to connect to server")?;
+    let resumed: ResumeResponse = check_response(response)
+        .await?
+        .json()
+        .await
+        .context("Invalid response")?;
+    say!("{}", format_resumed(&resumed));
+    Ok(())
+}
+
+async fn cmd_paused(client: &reqwest::Client, server: &str) -> Result<()> {
+    let response = client
+        .get(format!("{}/pauses", server))
+        .send_logged()
+        .await
+        .context("Failed to connect to server")?;
+    let list: PausesResponse = check_response(response)
+        .await?
+        .json()
+        .await
+        .context("Invalid response")?;
+    if list.pauses.is_empty() {
+        say!("Nothing is paused");
+    }
+    for pause in &list.pauses {
+        println!("{}", format_pause(pause));
+    }
+    Ok(())
+}
+
+async fn cmd_token(client: &reqwest::Client, server: &str, command: TokenCommand) -> Result<()> {
+    match command {
+        TokenCommand::Create {
+            role,
+            topic,
+            name,
+            print_curl,
+        } => {
+            let role = if role.send_only { "send" } else { "read" };
+            let response = client
+                .post(format!("{}/keys", server))
+                .json(&serde_json::json!({ "name": name, "role": role, "topic": topic }))
+                .send_logged()
+                .await
+                .context("Failed to connect to server")?;
+            let issued: IssuedKey = check_response(response)
+                .await?
+                .json()
+                .await
+                .context("Invalid response")?;
+            let key = issued
+                .key
+                .as_deref()
+                .context("The server didn't return the key")?;
+            match &issued.topic {
+                Some(topic) => say!(
+                    "Issued {} key {} for topic {}",
+                    issued.role,
+                    issued.name,
+                    topic
+                ),
+                None => say!("Issued {} key {}", issued.role, issued.name),
+            }
+            say!(
+                "It won't be shown again; revoke it with `psh token revoke {}`",
+                issued.name
+            );
+            println!("{}", key);
+            if print_curl {
+                println!("{}", curl_command(server, key, &issued));
+            }
+        }
+        TokenCommand::List => {
+            let response = client
+                .get(format!("{}/keys", server))
+                .send_logged()
+                .await
+                .context("Failed to connect to server")?;
+            let list: IssuedKeysResponse = check_response(response)
+                .await?
+                .json()
+                .await
+                .context("Invalid response")?;
+            if list.keys.is_empty() {
+                say!("No keys have been issued");
+            }
+            for key in &list.keys {
+                println!("{}", format_issued_key(key));
+            }
+        }
+        TokenCommand::Revoke { name } => {
+            let response = client
+                .delete(format!("{}/keys/{}", server, name))
+                .send_logged()
+                .await
+                .context("Failed to connect to server")?;
+            check_response(response).await?;
+            say!("Revoked {}", name);
+        }
+    }
+    Ok(())
+}
+
+/// A curl command that does what the key is for: publish to its topic,
+/// send, or read stats.
+fn curl_command(server: &str, key: &str, issued: &IssuedKey) -> String {
+    let auth = format!("curl -H 'Authorization: Bearer {}'", key);
+    match (&issued.topic, issued.role.as_str()) {
+        (Some(topic), _) => format!("{} -d 'Build finished' '{}/t/{}'", auth, server, topic),
+        (None, "send") => format!("{} -d 'Hello from curl' '{}/send'", auth, server),
+        _ => format!("{} '{}/stats'", auth, server),
+    }
+}
+
+fn format_issued_key(key: &IssuedKey) -> String {
+    let mut line = format!(
+        "{}\t{}\t{}\tissued {}",
+        key.name,
+        key.role,
+        key.topic.as_deref().unwrap_or("any topic"),
+        key.created_at
+    );
+    if let Some(created_by) = &key.created_by {
+        line.push_str(&format!(" by {}", created_by));
+    }
+    line
+}
+
+fn format_resumed(resumed: &ResumeResponse) -> String {
+    let mut line = format!(
+        "Resumed {} {}: {} queued sends replayed, {} sent, {} failed",
+        resumed.kind, resumed.name, resumed.replayed, resumed.sent, resumed.failed
+    );
+    if resumed.rejected > 0 {
+        line.push_str(&format!(", {} rejected", resumed.rejected));
+    }
+    if resumed.dropped > 0 {
+        line.push_str(&format!(", {} dropped while paused", resumed.dropped));
+    }
+    line
+}
+
+fn format_pause(pause: &PauseRecord) -> String {
+    let mut line = format!(
+        "{}\t{}\t{}\t{} queued\t{} dropped\tsince {}",
+        pause.kind, pause.name, pause.policy, pause.queued, pause.dropped, pause.paused_at
+    );
+    if let Some(reason) = &pause.reason {
+        line.push_str(&format!("\t{}", reason));
+    }
+    line
+}
+
+fn format_withdrawn(withdrawn: &WithdrawResponse) -> String {
+    let verb = if withdrawn.action == "update" {
+        "Replaced"
+    } else {
+        "Withdrew"
+    };
+    let collapse_id = match &withdrawn.collapse_id {
+        Some(id) => format!(" (collapse id {})", id),
+        None => String::new(),
+    };
+    // Withdrawals are never deferred, so the time doesn't matter.
+    format!(
+        "{} push {}{}\n{}",
+        verb,
+        withdrawn.push_id,
+        collapse_id,
+        format_send_result(&withdrawn.result, 0)
+    )
+}
+
+/// Each environment's answer, then which one the app should register with.
+fn format_test_device(test: &TestDeviceResponse) -> Vec<String> {
+    let mut lines = vec![format!(
+        "Test push to {}",
+        truncate_token(&test.device_token)
+    )];
+    for result in &test.results {
+        lines.push(if result.success {
+            format!(
+                "  {:<10} accepted, apns-id {}",
+                result.environment,
+                result.apns_id.as_deref().unwrap_or_default()
+            )
+        } else {
+            format!(
+                "  {:<10} rejected: {}",
+                result.environment,
+                format_error(result.error_code.as_deref(), result.error.as_deref())
+            )
+        });
+    }
+    if let Some(environment) = &test.environment {
+        lines.push(format!(
+            "The token is a {} token; the app should register it with \"environment\": \"{}\"",
+            environment, environment
+        ));
+    }
+    lines
+}
+
+/// `ci  send  today 12/1000  month 340`: pushes sent, over the quota if any.
+fn format_usage_line(key: &KeyUsage) -> String {
+    let used = |count: u64, quota: Option<u64>| match quota {
+        Some(quota) => format!("{}/{}", count, quota),
+        None => count.to_string(),
+    };
+    format!(
+        "{}\t{}\ttoday {}\tmonth {}",
+        key.name,
+        key.role,
+        used(key.today, key.daily_quota),
+        used(key.this_month, key.monthly_quota)
+    )
+}
+
+async fn cmd_ping(client: &reqwest::Client, server: &str) -> Result<()> {
+    let url = format!("{}/health", server.trim_end_matches('/'));
+
+    let response = client
+        .get(&url)
+        .send_logged()
+        .await
+        .context("Failed to connect to server")?;
+
+    let status = response.status();
+    if status == reqwest::StatusCode::NOT_FOUND {
+        // Servers older than /health only answer the root route.
+        let response = client
+            .get(server)
+            .send_logged()
+            .await
+            .context("Failed to connect to server")?;
+        if !response.status().is_success() {
+            anyhow::bail!("Server returned status: {}", response.status());
+        }
+        say!("Server is healthy");
+        return Ok(());
+    }
+
+    let body = response
+        .bytes()
+        .await
+        .with_context(|| format!("Server returned status: {}", status))?;
+    match serde_json::from_slice::<HealthResponse>(&body) {
+        Ok(health) => {
+            for line in format_health(&health) {
+                say!("{}", line);
+            }
+        }
+        Err(_) => {
+            let health: HealthStatus = serde_json::from_slice(&body)
+                .with_context(|| format!("Server returned status: {}", status))?;
+            say!("{}", format_health_status(&health));
+        }
+    }
+    if !status.is_success() {
+        anyhow::bail!("Server is unhealthy");
+    }
+
+    Ok(())
+}
+
+fn format_health_status(health: &HealthStatus) -> String {
+    format!(
+        "Server is {} (details need an API key with read access)",
+        if health.status == "ok" {
+            "healthy"
+        } else {
+            "unhealthy"
+        }
+    )
+}
+
+fn format_health(health: &HealthResponse) -> Vec<String> {
+    let mut lines = vec![format!(
+        "Server is {} (version {}, up {})",
+        if health.status == "ok" {
+            "healthy"
+        } else {
+            "unhealthy"
+        },
+        health.version,
+        format_uptime(health.uptime_seconds)
+    )];
+    for (name, check) in [
+        ("database", &health.checks.database),
+        ("apns", &health.checks.apns),
+    ] {
+        let mut line = if check.ok {
+            match check.token_age_seconds {
+                Some(age) => format!("  {:<9} ok (token {} old)", name, format_uptime(age)),
+                None => format!("  {:<9} ok", name),
+            }
+        } else {
+            format!(
+                "  {:<9} FAIL: {}",
+                name,
+                check.error.as_deref().unwrap_or("unknown error")
+            )
+        };
+        if let Some(failures) = check.consecutive_failures.filter(|&n| n > 0) {
+            line.push_str(&format!(", {} connection failures in a row", failures));
+        }
+        lines.push(line);
+        if let Some(warning) = &check.warning {
+            lines.push(format!("  {:<9} warning: {}", "", warning));
+        }
+    }
+    lines.push(format!("  {:<9} {}", "queue", health.queue_depth));
+    lines
+}
+
+#[derive(Debug, PartialEq)]
+enum DoctorStatus {
+    Ok,
+    Warn,
+    Fail,
+}
+
+/// One `psh doctor` finding, with what to do about it when it isn't ok.
+struct DoctorCheck {
+    name: &'static str,
+    status: DoctorStatus,
+    detail: String,
+    fix: Option<String>,
+}
+
+impl DoctorCheck {
+    fn ok(name: &'static str, detail: impl Into<String>) -> Self {
+        DoctorCheck {
+            name,
+            status: DoctorStatus::Ok,
+            detail: detail.into(),
+            fix: None,
+        }
+    }
+
+    fn warn(name: &'static str, detail: impl Into<String>, fix: impl Into<String>) -> Self {
+        DoctorCheck {
+            status: DoctorStatus::Warn,
+            fix: Some(fix.into()),
+            ..Self::ok(name, detail)
+        }
+    }
+
+    fn fail(name: &'static str, detail: impl Into<String>, fix: impl Into<String>) -> Self {
+        DoctorCheck {
+            status: DoctorStatus::Fail,
+            fix: Some(fix.into()),
+            ..Self::ok(name, detail)
+        }
+    }
+}
+
+async fn cmd_doctor(cli_server: Option<String>, http: HttpArgs) -> Result<()> {
+    let (config_check, config) = check_config(Config::config_path().as_deref());
+    let mut checks = vec![config_check];
+    match cli_server.or_else(|| config.server.clone()) {
+        None => checks.push(DoctorCheck::fail(
+            "server",
+            "no server configured",
+            "Run `psh config set server <url>`, set PSH_SERVER or pass --server",
+        )),
+        Some(server) => match http.with_config(&config).client(unix_socket(&server)) {
+            Ok(client) => checks.extend(check_server(&client, &base_url(server)).await),
+            Err(e) => checks.push(DoctorCheck::fail(
+                "client",
+                format!("{:#}", e),
+                "Point --ca-cert or `psh config set ca-cert` at a PEM file of CA certificates",
+            )),
+        },
+    }
+
+    for check in &checks {
+        say!("{}", format_doctor_check(check));
+    }
+    let failed = checks
+        .iter()
+        .filter(|check| check.status == DoctorStatus::Fail)
+        .count();
+    if failed > 0 {
+        anyhow::bail!("{} of {} checks failed", failed, checks.len());
+    }
+    Ok(())
+}
+
+/// Reads the config file as `psh` would, but reports what `Config::load`
+/// silently ignores: unreadable files, invalid TOML, unknown keys and a
+/// missing CA certificate.
+fn check_config(path: Option<&Path>) -> (DoctorCheck, Config) {
+    let Some(path) = path else {
+        let check = DoctorCheck::warn(
+            "config",
+            "no home directory, so no config file",
+            "Set HOME, or pass --server and the other settings as flags",
+        );
+        return (check, Config::default());
+    };
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let detail = format!("{} not found, using flags and environment", path.display());
+            return (DoctorCheck::ok("config", detail), Config::default());
+        }
+        Err(e) => {
+            let check = DoctorCheck::fail(
+                "config",
+                format!("cannot read {}: {}", path.display(), e),
+                format!("Check the permissions of {}", path.display()),
+            );
+            return (check, Config::default());
+        }
+    };
+    let config: Config = match toml::from_str(&contents) {
+        Ok(config) => config,
+        Err(e) => {
+            let check = DoctorCheck::fail(
+                "config",
+                format!("{} is not valid TOML: {}", path.display(), e.message()),
+                format!(
+                    "Fix {}, or delete it and run `psh config set server <url>`",
+                    path.display()
+                ),
+            );
+            return (check, Config::default());
+        }
+    };
+
+    let mut known: Vec<String> = [
+        ConfigKey::Server,
+        ConfigKey::CaCert,
+        ConfigKey::Insecure,
+        ConfigKey::ApiKey,
+    ]
+    .map(|key| key.name().replace('-', "_"))
+    .into();
+    known.push("presets".to_string());
+    let unknown: Vec<String> = toml::from_str::<toml::Table>(&contents)
+        .map(|table| {
+            table
+                .keys()
+                .filter(|key| !known.contains(key))
+                .cloned()
+                .collect()
+        })
+        .unwrap_or_default();
+    let check = if !unknown.is_empty() {
+        DoctorCheck::warn(
+            "config",
+            format!(
+                "{} has unknown keys: {}",
+                path.display(),
+                unknown.join(", ")
+            ),
+            format!("Remove them; psh reads only {}", known.join(", ")),
+        )
+    } else if let Some(ca_cert) = config.ca_cert.as_deref().filter(|p| !p.exists()) {
+        DoctorCheck::fail(
+            "config",
+            format!("ca_cert {} does not exist", ca_cert.display()),
+            "Run `psh config set ca-cert <pem>` or `psh config unset ca-cert`",
+        )
+    } else {
+        DoctorCheck::ok("config", path.display().to_string())
+    };
+    (check, config)
+}
+
+/// Reaches the server's `/health` and checks what it reports.
+async fn check_server(client: &reqwest::Client, server: &str) -> Vec<DoctorCheck> {
+    let url = format!("{}/health", server.trim_end_matches('/'));
+    let response = match client.get(&url).send_logged().await {
+        Ok(response) => response,
+        Err(e) => {
+            return vec![DoctorCheck::fail(
+                "server",
+                format!("cannot reach {}: {}", server, error_chain(&e)),
+                unreachable_fix(&e),
+            )]
+        }
+    };
+
+    let status = response.status();
+    if status == reqwest::StatusCode::NOT_FOUND {
+        return vec![
+            DoctorCheck::ok("server", format!("{} reachable", server)),
+            version_check(fetch_version(client, server).await),
+            DoctorCheck::warn(
+                "health",
+                "server predates /health",
+                "Upgrade the server to check its database and APNs credentials",
+            ),
+        ];
+    }
+    let body = response.bytes().await.unwrap_or_default();
+    if let Ok(health) = serde_json::from_slice::<HealthResponse>(&body) {
+        let mut checks = vec![
+            DoctorCheck::ok(
+                "server",
+                format!(
+                    "{} reachable, up {}",
+                    server,
+                    format_uptime(health.uptime_seconds)
+                ),
+            ),
+            version_check(fetch_version(client, server).await),
+        ];
+        checks.extend(health_checks(&health));
+        return checks;
+    }
+    match serde_json::from_slice::<HealthStatus>(&body) {
+        // Servers requiring keys only show the checks to read keys.
+        Ok(health) => vec![
+            if health.status == "ok" {
+                DoctorCheck::ok("server", format!("{} reachable", server))
+            } else {
+                DoctorCheck::fail(
+                    "server",
+                    format!("{} reachable but unhealthy", server),
+                    "Pass a read or admin API key to see which check fails",
+                )
+            },
+            version_check(fetch_version(client, server).await),
+            DoctorCheck::warn(
+                "health",
+                "details need an API key with read access",
+                "Pass --api-key (or set PSH_API_KEY) to a read or admin key to check the database and APNs credentials",
+            ),
+        ],
+        Err(_) => vec![DoctorCheck::fail(
+            "server",
+            format!("{} answered {} without a psh health report", server, status),
+            "Check the URL points at the psh server rather than a proxy or another service",
+        )],
+    }
+}
+
+fn version_check(version: Result<Option<VersionResponse>>) -> DoctorCheck {
+    match version {
+        Ok(Some(version)) if api_mismatch(Some(&version)).is_none() => DoctorCheck::ok(
+            "version",
+            format!(
+                "server {} ({}), API v{}",
+                version.version, version.git_hash, version.api_version
+            ),
+        ),
+        Ok(version) => DoctorCheck::warn(
+            "version",
+            api_mismatch(version.as_ref()).unwrap_or_default(),
+            "Upgrade the server",
+        ),
+        Err(e) => DoctorCheck::warn(
+            "version",
+            format!("{:#}", e),
+            "Check the URL points at the psh server rather than a proxy or another service",
+        ),
+    }
+}
+
+fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(e) = source {
+        message.push_str(&format!(": {}", e));
+        source = e.source();
+    }
+    message
+}
+
+fn unreachable_fix(error: &reqwest::Error) -> String {
+    if error.is_timeout() {
+        "The server didn't answer in time; check it's running, or raise --timeout".to_string()
+    } else if error_chain(error).to_lowercase().contains("certificate") {
+        "For a private CA, run `psh config set ca-cert <pem>` or pass --ca-cert".to_string()
+    } else {
+        "Check the URL (`psh config get server`), that the server is running, and \
+         HTTPS_PROXY/NO_PROXY"
+            .to_string()
+    }
+}
+
+/// The checks that come from the server's own health report.
+fn health_checks(health: &HealthResponse) -> Vec<DoctorCheck> {
+    let database = &health.checks.database;
+    let apns = &health.checks.apns;
+    let mut checks = Vec::new();
+
+    checks.push(if database.ok {
+        DoctorCheck::ok("database", "ok")
+    } else {
+        DoctorCheck::fail(
+            "database",
+            database.error.as_deref().unwrap_or("unknown error"),
+            "Check DATABASE_URL on the server and that its directory is writable",
+        )
+    });
+
+    checks.push(if !apns.ok {
+        DoctorCheck::fail(
+            "apns",
+            apns.error.as_deref().unwrap_or("unknown error"),
+            "Check APNS_KEY_PATH points at your .p8 key, and that APNS_KEY_ID and \
+             APNS_TEAM_ID match it in the Apple Developer portal",
+        )
+    } else if let Some(credentials) = apns.credentials.as_ref().filter(|c| c.mock) {
+        DoctorCheck::warn(
+            "apns",
+            format!(
+                "mock mode: pushes to {} are recorded, not delivered",
+                credentials.topic
+            ),
+            "Unset PSH_APNS_MODE and set APNS_KEY_PATH, APNS_KEY_ID, APNS_TEAM_ID and \
+             APNS_TOPIC",
+        )
+    } else if let Some(warning) = &apns.warning {
+        DoctorCheck::warn(
+            "apns",
+            warning.as_str(),
+            "If the key was revoked in the Apple Developer portal, create a new one and \
+             update APNS_KEY_PATH and APNS_KEY_ID",
+        )
+    } else if let Some(failures) = apns.consecutive_failures.filter(|&n| n > 0) {
+        DoctorCheck::warn(
+            "apns",
+            format!("{} connection failures in a row", failures),
+            "Check the server can reach api.push.apple.com and \
+             api.sandbox.push.apple.com on port 443",
+        )
+    } else {
+        let detail = match &apns.credentials {
+            Some(credentials) => format!(
+                "key {} (team {}) for {}",
+                credentials.key_id.as_deref().unwrap_or("?"),
+                credentials.team_id.as_deref().unwrap_or("?"),
+                credentials.topic
+            ),
+            None => "ok".to_string(),
+        };
+        DoctorCheck::ok("apns", detail)
+    });
+
+    checks
+}
+
+fn format_doctor_check(check: &DoctorCheck) -> String {
+    let status = match check.status {
+        DoctorStatus::Ok => "ok",
+        DoctorStatus::Warn => "WARN",
+        DoctorStatus::Fail => "FAIL",
+    };
+    let mut line = format!("  {:<4} {:<8} {}", status, check.name, check.detail);
+    if let Some(fix) = &check.fix {
+        line.push_str(&format!("\n       fix: {}", fix));
+    }
+    line
+}
+
+/// The server's `/version`, or `None` for a server that predates it.
+async fn fetch_version(client: &reqwest::Client, server: &str) -> Result<Option<VersionResponse>> {
+    let url = format!("{}/version", server.trim_end_matches('/'));
+    let response = client
+        .get(&url)
+        .send_logged()
+        .await
+        .context("Failed to connect to server")?;
+    let status = response.status();
+    if status == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !status.is_success() {
+        anyhow::bail!("/version returned status: {}", status);
+    }
+    let version = response
+        .json()
+        .await
+        .context("/version returned an unexpected response")?;
+    Ok(Some(version))
+}
+
+/// Why a server may silently drop fields this CLI sends, if it might.
+fn api_mismatch(server: Option<&VersionResponse>) -> Option<String> {
+    match server {
+        None => Some(format!(
+            "the server predates /version and may ignore fields psh {} sends",
+            env!("CARGO_PKG_VERSION")
+        )),
+        Some(server) if server.api_version < API_VERSION => Some(format!(
+            "server {} speaks API v{} but psh {} speaks v{}, so newer fields are ignored",
+            server.version,
+            server.api_version,
+            env!("CARGO_PKG_VERSION"),
+            API_VERSION
+        )),
+        Some(_) => None,
+    }
+}
+
+/// Warns, or with `--strict` fails, before talking to a server older than
+/// this CLI. Unreachable servers are left for the command itself to report.
+async fn check_api_version(client: &reqwest::Client, server: &str, strict: bool) -> Result<()> {
+    let Ok(version) = fetch_version(client, server).await else {
+        return Ok(());
+    };
+    if let Some(mismatch) = api_mismatch(version.as_ref()) {
+        if strict {
+            anyhow::bail!("{}; upgrade the server or drop --strict", mismatch);
+        }
+        warn!("Warning: {}; upgrade the server", mismatch);
+    }
+    Ok(())
+}
+
+/// A device's line in the send output, plus any warning beneath it.
+fn format_send_result(result: &DeviceSendResult, now: u64) -> String {
+    let token = truncate_token(&result.device_token);
+    let mut line = if result.skipped_duplicate {
+        format!("  {} -> skipped, same push sent recently", token)
+    } else if result.skipped_snoozed {
+        format!("  {} -> skipped, device is snoozed", token)
+    } else if let Some(until) = result.deferred_until {
+        format!(
+            "  {} -> deferred, sends in {}",
+            token,
+            format_uptime(until.saturating_sub(now))
+        )
+    } else if let Some(environment) = &result.fallback_environment {
+        format!(
+            "  {} -> {} (sent via {} fallback{})",
+            token,
+            result.apns_id.as_deref().unwrap_or_default(),
+            environment,
+            if result.environment_corrected { ", device updated" } else { "" }
+        )
+    } else if result.success {
+        format!("  {} -> {}", token, result.apns_id.as_deref().unwrap_or_default())
+    } else {
+        format!(
+            "  {} -> ERROR: {}",
+            token,
+            format_error(result.error_code.as_deref(), result.error.as_deref())
+        )
+    };
+    if result.truncated {
+        line.push_str(" (body truncated)");
+    }
+    if let Some(warning) = &result.warning {
+        line.push_str(&format!("\n    warning: {}", warning));
+    }
+    line
+}
+
+fn format_uptime(seconds: u64) -> String {
+    let (days, hours, minutes) = (seconds / 86_400, seconds / 3600 % 24, seconds / 60 % 60);
+    if days > 0 {
+        format!("{}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m", minutes)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+/// Turns a non-success response into the server's error message.
+async fn check_response(response: reqwest::Response) -> Result<reqwest::Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let error: ErrorResponse = response
+        .json()
+        .await
+        .unwrap_or(ErrorResponse {
+            error: format!("HTTP {}", status),
+            request_id: None,
+        });
+    anyhow::bail!("Error: {}", error.message());
+}
+
+async fn cmd_segments(
+    client: &reqwest::Client,
+    server: &str,
+    command: SegmentsCommand,
+) -> Result<()> {
+    match command {
+        SegmentsCommand::List => {
+            let response = client
+                .get(format!("{}/segments", server))
+                .send_logged()
+                .await
+                .context("Failed to connect to server")?;
+            let list: SegmentsResponse = check_response(response)
+                .await?
+                .json()
+                .await
+                .context("Invalid response")?;
+            for segment in list.segments {
+                println!("{}\t{}", segment.name, segment.filter);
+            }
+        }
+        SegmentsCommand::Set { name, filters } => {
+            let response = client
+                .put(format!("{}/segments/{}", server, name))
+                .json(&serde_json::json!({ "filter": DeviceFilter::from_clauses(filters) }))
+                .send_logged()
+                .await
+                .context("Failed to connect to server")?;
+            let segment: Segment = check_response(response)
+                .await?
+                .json()
+                .await
+                .context("Invalid response")?;
+            say!("{}\t{}", segment.name, segment.filter);
+        }
+        SegmentsCommand::Delete { name } => {
+            let response = client
+                .delete(format!("{}/segments/{}", server, name))
+                .send_logged()
+                .await
+                .context("Failed to connect to server")?;
+            check_response(response).await?;
+            say!("Deleted {}", name);
+        }
+    }
+
+    Ok(())
+}
+
+async fn cmd_channels(
+    client: &reqwest::Client,
+    server: &str,
+    command: ChannelsCommand,
+) -> Result<()> {
+    let response = match &command {
+        ChannelsCommand::List { environment } => client
+            .get(format!("{}/channels", server))
+            .query(&[("environment", environment)]),
+        ChannelsCommand::Create {
+            environment,
+            store_latest,
+        } => client
+            .post(format!("{}/channels", server))
+            .json(&serde_json::json!({
+                "environment": environment,
+                "message_storage_policy": if *store_latest { "most_recent" } else { "no_storage" },
+            })),
+        ChannelsCommand::Show { id, environment } => client
+            .get(format!("{}/channels/{}", server, id))
+            .query(&[("environment", environment)]),
+        ChannelsCommand::Delete { id, environment } => client
+            .delete(format!("{}/channels/{}", server, id))
+            .query(&[("environment", environment)]),
+    }
+    .send_logged()
+    .await
+    .context("Failed to connect to server")?;
+    let response = check_response(response).await?;
+
+    match command {
+        ChannelsCommand::List { .. } => {
+            let list: ChannelsResponse = response.json().await.context("Invalid response")?;
+            for channel in list.channels {
+                println!("{}", channel);
+            }
+        }
+        ChannelsCommand::Create { .. } | ChannelsCommand::Show { .. } => {
+            let channel: ChannelRecord = response.json().await.context("Invalid response")?;
+            println!("{}", format_channel_line(&channel));
+        }
+        ChannelsCommand::Delete { id, .. } => say!("Deleted {}", id),
+    }
+    Ok(())
+}
+
+/// Tab-separated channel ID, environment and message storage policy.
+fn format_channel_line(channel: &ChannelRecord) -> String {
+    format!(
+        "{}\t{}\t{}",
+        channel.channel_id, channel.environment, channel.message_storage_policy
+    )
+}
+
+async fn cmd_devices(
+    client: &reqwest::Client,
+    server: &str,
+    command: DevicesCommand,
+) -> Result<()> {
+    match command {
+        DevicesCommand::List {
+            filters,
+            by_installation,
+        } => {
+            let filter = DeviceFilter::from_clauses(filters).unwrap_or_default();
+            let mut request = client.get(format!("{}/devices", server)).query(&filter);
+            if by_installation {
+                request = request.query(&[("group_by", "installation")]);
+            }
+            let response = request
+                .send_logged()
+                .await
+                .context("Failed to connect to server")?;
+            if by_installation {
+                let list: InstallationDevicesResponse = check_response(response)
+                    .await?
+                    .json()
+                    .await
+                    .context("Invalid response")?;
+                if list.installations.is_empty() {
+                    println!("No devices");
+                }
+                for installation in list.installations {
+                    let id = installation.installation_id.as_deref().unwrap_or("-");
+                    let count = installation.devices.len() as u64;
+                    println!(
+                        "{}",
+                        format_installation_line(id, count, &installation.activity)
+                    );
+                    for device in &installation.devices {
+                        println!("  {}", format_device_line(device));
+                    }
+                }
+                return Ok(());
+            }
+            let list: DevicesResponse = check_response(response)
+                .await?
+                .json()
+                .await
+                .context("Invalid response")?;
+
+            if list.devices.is_empty() {
+                println!("No devices");
+            }
+            for device in list.devices {
+                println!("{}", format_device_line(&device));
+            }
+        }
+        DevicesCommand::History { token, limit } => {
+            let response = client
+                .get(format!("{}/devices/{}/pushes", server, token))
+                .query(&[("limit", limit)])
+                .send_logged()
+                .await
+                .context("Failed to connect to server")?;
+            let history: DevicePushesResponse = check_response(response)
+                .await?
+                .json()
+                .await
+                .context("Invalid response")?;
+
+            if history.pushes.is_empty() {
+                println!("No pushes for {}", truncate_token(&token));
+            }
+            for push in history.pushes {
+                println!("{}", format_history_line(&push));
+            }
+        }
+        DevicesCommand::Export { format } => {
+            let mut response = check_response(
+                client
+                    .get(format!("{}/devices/export", server))
+                    .query(&[("format", format)])
+                    .send_logged()
+                    .await
+                    .context("Failed to connect to server")?,
+            )
+            .await?;
+
+            let mut stdout = io::stdout().lock();
+            while let Some(chunk) = response.chunk().await.context("Export interrupted")? {
+                stdout.write_all(&chunk)?;
+            }
+            stdout.flush()?;
+        }
+        DevicesCommand::Disable { token } => {
+            set_device_enabled(client, server, &token, false).await?
+        }
+        DevicesCommand::Enable { token } => {
+            set_device_enabled(client, server, &token, true).await?
+        }
+        DevicesCommand::Snooze { token, duration } => {
+            snooze_device(client, server, &token, duration).await?
+        }
+        DevicesCommand::Unsnooze { token } => snooze_device(client, server, &token, 0).await?,
+        DevicesCommand::Tag { token, tags } => {
+            let changes: BTreeMap<String, Option<String>> = tags.into_iter().collect();
+            let response = client
+                .post(format!("{}/devices/{}/tags", server, token))
+                .json(&changes)
+                .send_logged()
+                .await
+                .context("Failed to connect to server")?;
+            let device: DeviceTagsResponse = check_response(response)
+                .await?
+                .json()
+                .await
+                .context("Invalid response")?;
+            if device.tags.is_empty() {
+                say!("{} has no tags", truncate_token(&token));
+            } else {
+                say!("{}\t{}", truncate_token(&token), format_tags(&device.tags));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn set_device_enabled(
+    client: &reqwest::Client,
+    server: &str,
+    token: &str,
+    enabled: bool,
+) -> Result<()> {
+    let response = client
+        .patch(format!("{}/devices/{}", server, token))
+        .json(&serde_json::json!({ "enabled": enabled }))
+        .send_logged()
+        .await
+        .context("Failed to connect to server")?;
+    let device: DeviceRecord = check_response(response)
+        .await?
+        .json()
+        .await
+        .context("Invalid response")?;
+    let state = if device.enabled {
+        "Enabled"
+    } else {
+        "Disabled"
+    };
+    say!("{} {}", state, truncate_token(&device.device_token));
+    Ok(())
+}
+
+async fn snooze_device(
+    client: &reqwest::Client,
+    server: &str,
+    token: &str,
+    seconds: u64,
+) -> Result<()> {
+    let response = client
+        .patch(format!("{}/devices/{}", server, token))
+        .json(&serde_json::json!({ "snooze_seconds": seconds }))
+        .send_logged()
+        .await
+        .context("Failed to connect to server")?;
+    let device: DeviceRecord = check_response(response)
+        .await?
+        .json()
+        .await
+        .context("Invalid response")?;
+    let token = truncate_token(&device.device_token);
+    match &device.snoozed_until {
+        Some(until) => say!("Snoozed {} until {} UTC", token, until),
+        None => say!("{} is not snoozed", token),
+    }
+    Ok(())
+}
+
+/// Formats a failure as "Code: message", falling back to whichever is present.
+fn format_error(code: Option<&str>, message: Option<&str>) -> String {
+    match (code, message) {
+        (Some(code), Some(message)) => format!("{}: {}", code, message),
+        (Some(text), None) | (None, Some(text)) => text.to_string(),
+        (None, None) => "Unknown error".to_string(),
+    }
+}
+
+/// Tab-separated token, environment, name, type, locale and time zone, then
+/// any tags as `key=value,...`, with a trailing `disabled` or
+/// `snoozed until ...` for devices that don't receive every send and
+/// `expires ...` for registrations made with a TTL.
+fn format_device_line(device: &DeviceRecord) -> String {
+    let token = truncate_token(&device.device_token);
+    let mut line = [
+        Some(token.as_str()),
+        Some(device.environment.as_str()),
+        device.device_name.as_deref(),
+        device.device_type.as_deref(),
+        device.locale.as_deref(),
+        device.timezone.as_deref(),
+    ]
+    .map(|field| field.unwrap_or("-"))
+    .join("\t");
+    if !device.tags.is_empty() {
+        line.push('\t');
+        line.push_str(&format_tags(&device.tags));
+    }
+    if !device.enabled {
+        line.push_str("\tdisabled");
+    }
+    if let Some(until) = &device.snoozed_until {
+        line.push_str(&format!("\tsnoozed until {}", until));
+    }
+    if let Some(expires_at) = &device.expires_at {
+        line.push_str(&format!("\texpires {}", expires_at));
+    }
+    line
+}
+
+fn format_tags(tags: &BTreeMap<String, String>) -> String {
+    tags.iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn format_history_line(push: &DevicePushRecord) -> String {
+    let outcome = if push.status == "sent" {
+        push.apns_id.clone().unwrap_or_default()
+    } else {
+        format!(
+            "ERROR: {}",
+            format_error(push.error_code.as_deref(), push.error.as_deref())
+        )
+    };
+    let text = match (&push.title, &push.body) {
+        (Some(title), Some(body)) => format!("{}: {}", title, body),
+        (Some(text), None) | (None, Some(text)) => text.clone(),
+        (None, None) => String::new(),
+    };
+    format!(
+        "#{} {} {:<6} {} {}",
+        push.id, push.sent_at, push.status, outcome, text
+    )
+    .trim_end()
+    .to_string()
+}
+
+fn cmd_config(command: ConfigCommand) -> Result<()> {
+    let mut config = Config::load();
+
+    match command {
+        ConfigCommand::Set { key, value } => {
+            key.validate(&value)?;
+            config.set(key, Some(value));
+            config.save()?;
+        }
+        ConfigCommand::Get { key: Some(key) } => match config.get(key) {
+            Some(value) => println!("{}", value),
+            None => anyhow::bail!("{} is not set", key.name()),
+        },
+        ConfigCommand::Get { key: None } => {
+            print!("{}", toml::to_string_pretty(&config)?);
+        }
+        ConfigCommand::Path => {
+            let path = Config::config_path().context("Could not determine config directory")?;
+            println!("{}", path.display());
+        }
+        ConfigCommand::Unset { key } => {
+            config.set(key, None);
+            config.save()?;
+        }
+    }
+
+    Ok(())
+}
+
+fn print_series(series: &StatsSeries, graph: bool) {
+    let spark = if graph {
+        sparkline(&series.points.iter().map(|p| p.sent).collect::<Vec<_>>())
+    } else {
+        String::new()
+    };
+    let mut spark = spark.chars();
+
+    println!();
+    println!(
+        "{:<19}  {:>6}  {:>6}  {:>6}  {:>8}  {:>10}",
+        "bucket", "sent", "failed", "fail%", "sandbox", "production"
+    );
+    for point in &series.points {
+        println!(
+            "{:<19}  {:>6}  {:>6}  {:>5.1}%  {:>8}  {:>10}  {}",
+            point.bucket,
+            point.sent,
+            point.failed,
+            point.failure_rate * 100.0,
+            point.sandbox,
+            point.production,
+            spark.next().map(String::from).unwrap_or_default()
+        );
+    }
+}
+
+/// `label: iPhone 12 (75%), iPad 4 (25%)`, or nothing without devices.
+fn format_platforms(label: &str, counts: &[PlatformCount]) -> Option<String> {
+    if counts.is_empty() {
+        return None;
+    }
+    let counts: Vec<String> = counts
+        .iter()
+        .map(|count| {
+            format!(
+                "{} {} ({:.0}%)",
+                count.value,
+                count.devices,
+                count.share * 100.0
+            )
+        })
+        .collect();
+    Some(format!("{}: {}", label, counts.join(", ")))
+}
+
+/// A table of push counts per group, headed by what they're grouped by.
+fn format_groups(by: &str, groups: &[StatsGroup]) -> Vec<String> {
+    let mut lines = vec![format!(
+        "{:<32}  {:>7}  {:>6}  {:>6}  {:>6}  {:>6}",
+        by, "devices", "sent", "failed", "fail%", "opened"
+    )];
+    for group in groups {
+        lines.push(format!(
+            "{:<32}  {:>7}  {:>6}  {:>6}  {:>5.1}%  {:>6}",
+            group.key.as_deref().unwrap_or("(none)"),
+            group.devices.map_or("-".to_string(), |n| n.to_string()),
+            group.sent,
+            group.failed,
+            group.failure_rate * 100.0,
+            group.opened
+        ));
+    }
+    lines
+}
+
+/// Renders values as block characters scaled to the largest value.
+fn sparkline(values: &[i64]) -> String {
+    const BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let max = values.iter().copied().max().unwrap_or(0);
+    values
+        .iter()
+        .map(|&v| {
+            if max <= 0 || v <= 0 {
+                ' '
+            } else {
+                let index = ((v as f64 / max as f64) * (BLOCKS.len() - 1) as f64).round() as usize;
+                BLOCKS[index.min(BLOCKS.len() - 1)]
+            }
+        })
+        .collect()
+}
+
+/// A device token as output shows it: cut to its ends, so tokens don't end
+/// up whole in CI logs, unless `--show-full-tokens` is given.
+fn truncate_token(token: &str) -> String {
+    if token.len() > 16 && !SHOW_FULL_TOKENS.load(Ordering::Relaxed) {
+        format!("{}...{}", &token[..8], &token[token.len() - 8..])
+    } else {
+        token.to_string()
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    QUIET.store(cli.quiet, Ordering::Relaxed);
+    VERBOSE.store(cli.verbose, Ordering::Relaxed);
+    SHOW_FULL_TOKENS.store(cli.show_full_tokens, Ordering::Relaxed);
+    match cli.command {
+        Commands::Config(command) => return cmd_config(command),
+        Commands::Doctor => return cmd_doctor(cli.server, cli.http).await,
+        _ => {}
+    }
+
+    let config = Config::load();
+    let server = resolve_server(cli.server, &config)?;
+    let client = cli.http.with_config(&config).client(unix_socket(&server))?;
+    let server = base_url(server);
+    check_api_version(&client, &server, cli.strict).await?;
+
+    match cli.command {
+        Commands::Send(mut args) => {
+            if let Some(name) = args.preset.take() {
+                let preset = config
+                    .presets
+                    .get(&name)
+                    .with_context(|| format!("No preset named '{}' in the config file", name))?;
+                preset
+                    .apply(&mut args)
+                    .map_err(|e| anyhow::anyhow!("Invalid preset '{}': {}", name, e))?;
+            }
+            let piped = !io::stdin().is_terminal();
+            if piped && args.body.is_none() && args.body_positional.is_none() {
+                args.body = read_body(io::stdin().lock(), STDIN_BODY_LIMIT)
+                    .context("Failed to read the body from stdin")?;
+            }
+            if args.is_empty() {
+                Cli::command()
+                    .find_subcommand_mut("send")
+                    .unwrap()
+                    .print_help()?;
+                println!();
+                return Ok(());
+            }
+            if args.preview {
+                return cmd_preview(&client, &server, *args).await;
+            }
+            let fail_on_any = args.fail_on_any;
+            match cmd_send(&client, &server, *args).await {
+                Ok(counts) => match counts.exit_code(fail_on_any) {
+                    0 => Ok(()),
+                    code => std::process::exit(code),
+                },
+                Err(e) => {
+                    eprintln!("Error: {:?}", e);
+                    std::process::exit(EXIT_TRANSPORT_ERROR);
+                }
+            }
+        }
+        Commands::Stats(args) => cmd_stats(&client, &server, args).await,
+        Commands::Usage => cmd_usage(&client, &server).await,
+        Commands::Ping => cmd_ping(&client, &server).await,
+        Commands::Segments(command) => cmd_segments(&client, &server, command).await,
+        Commands::Devices(command) => cmd_devices(&client, &server, command).await,
+        Commands::Channels(command) => cmd_channels(&client, &server, command).await,
+        Commands::Watch(args) => cmd_watch(&client, &server, args).await,
+        Commands::NotifyDone(args) => cmd_notify_done(&client, &server, args).await,
+        Commands::TestDevice { token } => cmd_test_device(&client, &server, &token).await,
+        Commands::Installations => cmd_installations(&client, &server).await,
+        Commands::Gdpr(command) => cmd_gdpr(&client, &server, command).await,
+        Commands::Pushes(command) => cmd_pushes(&client, &server, command).await,
+        Commands::Pause {
+            target,
+            drop,
+            reason,
+        } => cmd_pause(&client, &server, target, drop, reason).await,
+        Commands::Resume { target } => cmd_resume(&client, &server, target).await,
+        Commands::Paused => cmd_paused(&client, &server).await,
+        Commands::Token(command) => cmd_token(&client, &server, command).await,
+        Commands::Config(_) | Commands::Doctor => {
+            unreachable!("config and doctor run before server resolution")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_send_args_basic_body() {
+        let args = SendArgs {
+            body_positional: Some("Hello".to_string()),
+            ..Default::default()
+        };
+        let req = args.into_request();
+        assert_eq!(req.body, Some("Hello".to_string()));
+        assert!(req.title.is_none());
+    }
+
+    #[test]
+    fn test_send_args_body_flag_overrides_positional() {
+        let args = SendArgs {
+            body_positional: Some("Positional".to_string()),
+            body: Some("Flag".to_string()),
+            ..Default::default()
+        };
+        let req = args.into_request();
+        assert_eq!(req.body, Some("Flag".to_string()));
+    }
+
+    #[test]
+    fn test_send_args_with_title() {
+        let args = SendArgs {
+            body_positional: Some("Body".to_string()),
+            title: Some("Title".to_string()),
+            ..Default::default()
+        };
+        let req = args.into_request();
+        assert_eq!(req.title, Some("Title".to_string()));
+        assert_eq!(req.body, Some("Body".to_string()));
+    }
+
+    #[test]
+    fn test_send_args_simple_sound() {
+        let args = SendArgs {
+            sound: Some("default".to_string()),
+            ..Default::default()
+        };
+        let req = args.into_request();
+        assert!(matches!(req.sound, Some(SoundConfig::Simple(s)) if s == "default"));
+    }
+
+    #[test]
+    fn test_send_args_critical_sound() {
+        let args = SendArgs {
+            sound_critical: true,
+            sound_name: Some("alert.caf".to_string()),
+            sound_volume: Some(0.8),
+            ..Default::default()
+        };
+        let req = args.into_request();
+        match req.sound {
+            Some(SoundConfig::Critical { name, critical, volume }) => {
+                assert_eq!(name, "alert.caf");
+                assert!(critical);
+                assert_eq!(volume, Some(0.8));
+            }
+            _ => panic!("Expected critical sound config"),
+        }
+    }
+
+    #[test]
+    fn test_send_args_critical_sound_default_name() {
+        let args = SendArgs {
+            sound_critical: true,
+            ..Default::default()
+        };
+        let req = args.into_request();
+        match req.sound {
+            Some(SoundConfig::Critical { name, .. }) => {
+                assert_eq!(name, "default");
+            }
+            _ => panic!("Expected critical sound config"),
+        }
+    }
+
+    #[test]
+    fn test_send_args_data_parsing() {
+        let args = SendArgs {
+            data: vec![
+                parse_data_pair("key1=value1").unwrap(),
+                parse_data_pair("key2=value2").unwrap(),
+            ],
+            ..Default::default()
+        };
+        let req = args.into_request();
+        let data = req.data.unwrap();
+        assert_eq!(data.get("key1").unwrap(), "value1");
+        assert_eq!(data.get("key2").unwrap(), "value2");
+    }
+
+    #[test]
+    fn test_format_preview() {
+        let preview: PreviewResponse = serde_json::from_str(
+            r#"{
+                "summary": "Alert: Hi",
+                "headers": {"apns-push-type": "alert", "apns-topic": "com.example.psh"},
+                "payload": {"aps": {"alert": {"title": "Hi"}}},
+                "size_bytes": 32,
+                "max_size_bytes": 4096,
+                "warnings": ["priority 1-4 is sent as 5 by the APNs client"]
+            }"#,
+        )
+        .unwrap();
+        let lines = format_preview(&preview);
+        assert_eq!(lines[0], "Alert: Hi");
+        assert_eq!(lines[2], "  apns-push-type: alert");
+        assert_eq!(lines[4], "Payload (32 of 4096 bytes):");
+        assert_eq!(lines[5], "  {");
+        assert_eq!(
+            lines.last().unwrap(),
+            "  - priority 1-4 is sent as 5 by the APNs client"
+        );
+    }
+
+    #[test]
+    fn test_parse_percent() {
+        assert_eq!(parse_percent("10").unwrap(), 10.0);
+        assert_eq!(parse_percent("0.5%").unwrap(), 0.5);
+        assert_eq!(parse_percent("100").unwrap(), 100.0);
+        assert!(parse_percent("0").is_err());
+        assert!(parse_percent("150").is_err());
+        assert!(parse_percent("ten").is_err());
+    }
+
+    #[test]
+    fn test_parse_action() {
+        assert_eq!(
+            parse_action("reply=Reply now").unwrap(),
+            json!({"id": "reply", "title": "Reply now"})
+        );
+        assert_eq!(
+            parse_action(r#"{"id":"delete","title":"Delete","destructive":true}"#).unwrap(),
+            json!({"id": "delete", "title": "Delete", "destructive": true})
+        );
+        assert!(parse_action("reply").is_err());
+        assert!(parse_action("=Reply").is_err());
+        assert!(parse_action(r#"{"id":"delete"}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_data_pair_types() {
+        let pair = |s: &str| parse_data_pair(s).map(|(path, value)| (path.join("/"), value));
+        assert_eq!(pair("count:=5").unwrap(), ("count".to_string(), json!(5)));
+        assert_eq!(
+            pair("flag:=true").unwrap(),
+            ("flag".to_string(), json!(true))
+        );
+        assert_eq!(
+            pair(r#"obj:={"a":1}"#).unwrap(),
+            ("obj".to_string(), json!({"a": 1}))
+        );
+        assert_eq!(
+            pair("url=psh://x?a=b").unwrap(),
+            ("url".to_string(), json!("psh://x?a=b"))
+        );
+        assert!(pair("novalue").is_err());
+        assert!(pair("=x").is_err());
+        assert!(pair("n:=five").is_err());
+    }
+
+    #[test]
+    fn test_dotted_data_keys_nest() {
+        let pairs = ["user.id:=42", "user.plan=pro", r"com\.example\.flag:=true"]
+            .into_iter()
+            .map(|s| parse_data_pair(s).unwrap())
+            .collect();
+        let data = nested_data(pairs);
+        assert_eq!(data["user"], json!({"id": 42, "plan": "pro"}));
+        assert_eq!(data["com.example.flag"], json!(true));
+
+        assert!(parse_data_pair("user..id=1").is_err());
+        assert!(parse_data_pair("user.=1").is_err());
+    }
+
+    #[test]
+    fn test_later_data_pairs_win() {
+        let pairs = ["user=x", "user.id=1", "other.a=1", "other=y"]
+            .into_iter()
+            .map(|s| parse_data_pair(s).unwrap())
+            .collect();
+        let data = nested_data(pairs);
+        assert_eq!(data["user"], json!({"id": "1"}));
+        assert_eq!(data["other"], json!("y"));
+    }
+
+    #[test]
+    fn test_send_args_loc_args_parsing() {
+        let args = SendArgs {
+            title_loc_key: Some("TITLE_KEY".to_string()),
+            title_loc_args: Some("arg1, arg2, arg3".to_string()),
+            loc_key: Some("BODY_KEY".to_string()),
+            loc_args: Some("a,b".to_string()),
+            ..Default::default()
+        };
+        let req = args.into_request();
+        assert_eq!(req.title_loc_key, Some("TITLE_KEY".to_string()));
+        assert_eq!(
+            req.title_loc_args,
+            Some(vec!["arg1".to_string(), "arg2".to_string(), "arg3".to_string()])
+        );
+        assert_eq!(req.loc_key, Some("BODY_KEY".to_string()));
+        assert_eq!(req.loc_args, Some(vec!["a".to_string(), "b".to_string()]));
+    }
+
+    #[test]
+    fn test_send_args_content_available() {
+        let args = SendArgs {
+            content_available: true,
+            ..Default::default()
+        };
+        let req = args.into_request();
+        assert_eq!(req.content_available, Some(true));
+        assert!(req.mutable_content.is_none());
+    }
+
+    #[test]
+    fn test_send_args_mutable_content() {
+        let args = SendArgs {
+            mutable_content: true,
+            ..Default::default()
+        };
+        let req = args.into_request();
+        assert!(req.content_available.is_none());
+        assert_eq!(req.mutable_content, Some(true));
+    }
+
+    #[test]
+    fn test_send_request_serialization() {
+        let req = SendRequest {
+            title: Some("Test".to_string()),
+            body: Some("Body".to_string()),
+            badge: Some(5),
+            sound: Some(SoundConfig::Simple("default".to_string())),
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&req).unwrap();
+        assert!(json.contains("\"title\":\"Test\""));
+        assert!(json.contains("\"body\":\"Body\""));
+        assert!(json.contains("\"badge\":5"));
+        assert!(json.contains("\"sound\":\"default\""));
+        assert!(!json.contains("subtitle"));
+        assert!(!json.contains("content_available"));
+    }
+
+    #[test]
+    fn test_send_request_critical_sound_serialization() {
+        let req = SendRequest {
+            sound: Some(SoundConfig::Critical {
+                name: "alert.caf".to_string(),
+                critical: true,
+                volume: Some(0.5),
+            }),
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&req).unwrap();
+        assert!(json.contains("\"name\":\"alert.caf\""));
+        assert!(json.contains("\"critical\":true"));
+        assert!(json.contains("\"volume\":0.5"));
+    }
+
+    #[test]
+    fn test_parse_filter_clause() {
+        assert_eq!(
+            parse_filter_clause("os_version>=17.0"),
+            Ok(FilterClause::MinOsVersion("17.0".to_string()))
+        );
+        assert_eq!(
+            parse_filter_clause("device_type=iPad"),
+            Ok(FilterClause::DeviceType("iPad".to_string()))
+        );
+        assert_eq!(
+            parse_filter_clause("name=*Test*"),
+            Ok(FilterClause::DeviceName("*Test*".to_string()))
+        );
+        assert_eq!(
+            parse_filter_clause("timezone=America/*"),
+            Ok(FilterClause::Timezone("America/*".to_string()))
+        );
+        assert_eq!(
+            parse_filter_clause("topic=backups"),
+            Ok(FilterClause::Topic("backups".to_string()))
+        );
+        assert_eq!(
+            parse_filter_clause("tag.tier=beta"),
+            Ok(FilterClause::Tag("tier".to_string(), "beta".to_string()))
+        );
+        assert!(parse_filter_clause("tag.tier=a,b").is_err());
+        assert!(parse_filter_clause("tag.a,b=c").is_err());
+        assert!(parse_filter_clause("tag.=beta").is_err());
+        assert!(parse_filter_clause("os_version=17.0").is_err());
+        assert!(parse_filter_clause("color=blue").is_err());
+        assert!(parse_filter_clause("nonsense").is_err());
+    }
+
+    #[test]
+    fn test_send_args_filters() {
+        let args = SendArgs {
+            body_positional: Some("Hello".to_string()),
+            filters: vec![
+                FilterClause::DeviceType("iPhone".to_string()),
+                FilterClause::MinOsVersion("17.0".to_string()),
+            ],
+            ..Default::default()
+        };
+        let req = args.into_request();
+        assert_eq!(
+            req.filter,
+            Some(DeviceFilter {
+                device_type: Some("iPhone".to_string()),
+                min_os_version: Some("17.0".to_string()),
+                ..Default::default()
+            })
+        );
+        assert!(SendArgs::default().into_request().filter.is_none());
+    }
+
+    #[test]
+    fn test_format_installation_deleted() {
+        let mut deleted = DeleteInstallationResponse {
+            installation_id: "install-1".to_string(),
+            dry_run: true,
+            devices: 2,
+            pushes: 1,
+        };
+        assert_eq!(
+            format_installation_deleted(&deleted),
+            "Would delete 2 devices and 1 push for install-1"
+        );
+        deleted.dry_run = false;
+        deleted.pushes = 12;
+        assert_eq!(
+            format_installation_deleted(&deleted),
+            "Deleted 2 devices and 12 pushes for install-1"
+        );
+    }
+
+    #[test]
+    fn test_format_withdrawn() {
+        let withdrawn: WithdrawResponse = serde_json::from_str(
+            r#"{
+                "push_id": 12,
+                "action": "update",
+                "collapse_id": "alert-db-1",
+                "result": {"device_token": "abc", "success": true, "apns_id": "apns-1", "error": null}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            format_withdrawn(&withdrawn),
+            "Replaced push 12 (collapse id alert-db-1)\n  abc -> apns-1"
+        );
+        let cli = Cli::try_parse_from(["psh", "pushes", "withdraw", "12", "--body", "ok"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::Pushes(PushesCommand::Withdraw { id, title: None, body: Some(body) })
+                if id == "12" && body == "ok"
+        ));
+    }
+
+    #[test]
+    fn test_format_push_diff() {
+        let first = json!({
+            "id": 12,
+            "title": "Deploy",
+            "body": "Build passed",
+            "payload": "{\"build\":{\"id\":41,\"url\":\"https://ci/41\"}}",
+            "status": "sent",
+            "error_code": null,
+            "actions": [{"id": "open", "title": "Open"}]
+        });
+        let second = json!({
+            "id": 15,
+            "title": "Deploy",
+            "body": "Build failed",
+            "payload": "{\"build\":{\"id\":42}}",
+            "status": "failed",
+            "error_code": "PayloadTooLarge"
+        });
+        assert_eq!(
+            format_push_diff(12, &first, 15, &second),
+            [
+                "push 12 -> push 15",
+                "- actions[0].id: \"open\"",
+                "- actions[0].title: \"Open\"",
+                "~ body: \"Build passed\" -> \"Build failed\"",
+                "+ error_code: \"PayloadTooLarge\"",
+                "~ payload.build.id: 41 -> 42",
+                "- payload.build.url: \"https://ci/41\"",
+                "~ status: \"sent\" -> \"failed\"",
+                "7 fields differ, 1 same",
+            ]
+        );
+        assert_eq!(
+            format_push_diff(12, &first, 12, &first).last().unwrap(),
+            "Identical (7 fields)"
+        );
+    }
+
+    #[test]
+    fn test_token_create_prints_curl() {
+        assert!(Cli::try_parse_from(["psh", "token", "create"]).is_err());
+        let read_topic = ["psh", "token", "create", "--read-only", "--topic", "builds"];
+        assert!(Cli::try_parse_from(read_topic).is_err());
+        let cli = Cli::try_parse_from(
+            "psh token create --send-only --topic builds --print-curl".split(' '),
+        )
+        .unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::Token(TokenCommand::Create { role, topic: Some(topic), print_curl: true, .. })
+                if role.send_only && topic == "builds"
+        ));
+
+        let issued: IssuedKey = serde_json::from_str(
+            r#"{"key": "psh_abc", "name": "builds-1a2b3c", "role": "send", "topic": "builds", "created_by": "ops", "created_at": "2026-10-16 09:00:00"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            curl_command("https://psh.example.com", "psh_abc", &issued),
+            "curl -H 'Authorization: Bearer psh_abc' -d 'Build finished' 'https://psh.example.com/t/builds'"
+        );
+        assert_eq!(
+            format_issued_key(&issued),
+            "builds-1a2b3c\tsend\tbuilds\tissued 2026-10-16 09:00:00 by ops"
+        );
+    }
+
+    #[test]
+    fn test_pause_and_resume() {
+        assert!(Cli::try_parse_from(["psh", "pause"]).is_err());
+        assert!(Cli::try_parse_from(["psh", "pause", "--topic", "a", "--segment", "b"]).is_err());
+        let cli = Cli::try_parse_from(["psh", "pause", "--segment", "ipads", "--drop"]).unwrap();
+        match cli.command {
+            Commands::Pause { target, drop, .. } => {
+                assert!(drop);
+                assert_eq!(target.url("http://psh"), "http://psh/segments/ipads");
+            }
+            _ => panic!("expected pause"),
+        }
+
+        let resumed: ResumeResponse = serde_json::from_str(
+            r#"{"kind": "topic", "name": "deploys", "replayed": 2, "sent": 3, "failed": 0, "rejected": 1, "dropped": 0}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            format_resumed(&resumed),
+            "Resumed topic deploys: 2 queued sends replayed, 3 sent, 0 failed, 1 rejected"
+        );
+        let pause: PauseRecord = serde_json::from_str(
+            r#"{"kind": "topic", "name": "deploys", "policy": "queue", "reason": "postmortem", "paused_at": "2026-10-16 09:00:00", "queued": 2, "dropped": 0}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            format_pause(&pause),
+            "topic\tdeploys\tqueue\t2 queued\t0 dropped\tsince 2026-10-16 09:00:00\tpostmortem"
+        );
+    }
+
+    #[test]
+    fn test_segments_set_requires_filter() {
+        assert!(Cli::try_parse_from(["psh", "segments", "set", "ipads"]).is_err());
+        let cli = Cli::try_parse_from([
+            "psh", "segments", "set", "ipads", "--filter", "device_type=iPad",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::Segments(SegmentsCommand::Set { name, filters })
+                if name == "ipads" && filters == vec![FilterClause::DeviceType("iPad".to_string())]
+        ));
+    }
+
+    #[derive(Deserialize)]
+    struct ContractCase {
+        name: String,
+        args: Vec<String>,
+        request: Value,
+    }
+
+    #[test]
+    fn test_send_request_contract() {
+        let cases: Vec<ContractCase> =
+            serde_json::from_str(include_str!("../../contract/send_requests.json")).unwrap();
+        for case in cases {
+            let argv = std::iter::once("psh".to_string()).chain(case.args);
+            let cli = Cli::try_parse_from(argv)
+                .unwrap_or_else(|e| panic!("{}: failed to parse args: {}", case.name, e));
+            let Commands::Send(args) = cli.command else {
+                panic!("{}: expected a send command", case.name);
+            };
+            let request = serde_json::to_value(args.into_request()).unwrap();
+            assert_eq!(request, case.request, "{}", case.name);
+        }
+    }
+
+    #[test]
+    fn test_format_history_line() {
+        let sent = DevicePushRecord {
+            id: 7,
+            apns_id: Some("apns-1".to_string()),
+            title: Some("Deploy".to_string()),
+            body: Some("done".to_string()),
+            status: "sent".to_string(),
+            error: None,
+            error_code: None,
+            sent_at: "2024-01-01 14:02:00".to_string(),
+        };
+        assert_eq!(
+            format_history_line(&sent),
+            "#7 2024-01-01 14:02:00 sent   apns-1 Deploy: done"
+        );
+
+        let failed = DevicePushRecord {
+            id: 8,
+            apns_id: None,
+            title: None,
+            body: None,
+            status: "failed".to_string(),
+            error: Some("The device token is invalid".to_string()),
+            error_code: Some("BadDeviceToken".to_string()),
+            sent_at: "2024-01-01 14:03:00".to_string(),
+        };
+        assert_eq!(
+            format_history_line(&failed),
+            "#8 2024-01-01 14:03:00 failed ERROR: BadDeviceToken: The device token is invalid"
+        );
+    }
+
+    #[test]
+    fn test_send_to_channel_args() {
+        let cli = Cli::try_parse_from([
+            "psh",
+            "send",
+            "--channel",
+            "abc=",
+            "--environment",
+            "sandbox",
+            "--event",
+            "update",
+            "--content-state",
+            r#"{"score":"2-1"}"#,
+        ])
+        .unwrap();
+        let Commands::Send(args) = cli.command else {
+            panic!("expected send");
+        };
+        assert_eq!(args.channel.as_deref(), Some("abc="));
+        assert_eq!(args.environment.as_deref(), Some("sandbox"));
+        let req = args.into_request();
+        assert_eq!(req.content_state, Some(serde_json::json!({"score": "2-1"})));
+
+        assert!(Cli::try_parse_from(["psh", "send", "--environment", "sandbox", "hi"]).is_err());
+        let args = ["psh", "send", "--channel", "abc", "--filter", "n=x", "hi"];
+        assert!(Cli::try_parse_from(args).is_err());
+        assert!(Cli::try_parse_from(["psh", "send", "--content-state", "[1]", "hi"]).is_err());
+    }
+
+    #[test]
+    fn test_watch_args() {
+        let cli = Cli::try_parse_from(["psh", "watch", "--lines", "2", "--", "make", "-j", "4"]);
+        let Commands::Watch(args) = cli.unwrap().command else {
+            panic!("expected watch");
+        };
+        assert_eq!(args.lines, 2);
+        assert_eq!(args.command, ["make", "-j", "4"]);
+        assert!(Cli::try_parse_from(["psh", "watch"]).is_err());
+    }
+
+    #[test]
+    fn test_push_tail() {
+        let mut tail = VecDeque::new();
+        for line in ["one\n", "\n", "two\r\n", "10%\r50%\r100%\n", "three"] {
+            push_tail(&mut tail, line, 3);
+        }
+        assert_eq!(tail, ["two", "100%", "three"]);
+
+        push_tail(&mut tail, &"x".repeat(200), 3);
+        assert_eq!(tail[2], format!("{}…", "x".repeat(WATCH_LINE_CHARS)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_watched() {
+        let command = ["sh", "-c", "echo out; echo err >&2; exit 3"].map(String::from);
+        let outcome = run_watched(&command, 5).unwrap();
+        assert_eq!(outcome.code, Some(3));
+        let mut tail = outcome.tail.clone();
+        tail.sort();
+        assert_eq!(tail, ["err", "out"]);
+    }
+
+    #[test]
+    fn test_watch_send_args() {
+        let args = WatchArgs {
+            title: None,
+            lines: 5,
+            outcome: OutcomeArgs::default(),
+            filters: Vec::new(),
+            command: vec!["make".to_string()],
+        };
+        let outcome = WatchOutcome {
+            code: Some(2),
+            elapsed: Duration::from_secs(125),
+            tail: vec!["error: oops".to_string()],
+        };
+        let req = watch_send_args(&args, &outcome).into_request();
+        assert_eq!(req.title.as_deref(), Some("Failed: make"));
+        assert_eq!(
+            req.body.as_deref(),
+            Some("make failed with exit code 2 after 2m\nerror: oops")
+        );
+        assert_eq!(req.data.unwrap()["exit_code"], json!(2));
+    }
+
+    #[test]
+    fn test_notify_done_presets() {
+        let sounds = ["--ok-sound", "default", "--fail-sound", "alarm.caf"];
+        let parse = |status: &str| {
+            let args = ["psh", "notify-done", "--status", status, "deploy"];
+            let args = args.into_iter().chain(sounds).chain(["--fail-critical"]);
+            let cli = Cli::try_parse_from(args).unwrap();
+            let Commands::NotifyDone(args) = cli.command else {
+                panic!("expected notify-done");
+            };
+            notify_done_send_args(args).into_request()
+        };
+
+        let ok = parse("0");
+        assert_eq!(ok.title.as_deref(), Some("Succeeded"));
+        assert_eq!(ok.body.as_deref(), Some("deploy"));
+        assert!(matches!(ok.sound, Some(SoundConfig::Simple(ref name)) if name == "default"));
+
+        let failed = parse("2");
+        assert_eq!(failed.title.as_deref(), Some("Failed with exit code 2"));
+        assert_eq!(failed.data.unwrap()["exit_code"], json!(2));
+        let Some(SoundConfig::Critical { name, critical, .. }) = failed.sound else {
+            panic!("expected a critical sound");
+        };
+        assert_eq!((name.as_str(), critical), ("alarm.caf", true));
+
+        assert!(Cli::try_parse_from(["psh", "notify-done", "deploy"]).is_err());
+    }
+
+    #[test]
+    fn test_read_body() {
+        let read = |input: &str, limit| read_body(input.as_bytes(), limit).unwrap();
+        assert_eq!(read("disk at 95%\n", 64).as_deref(), Some("disk at 95%"));
+        assert_eq!(read(" \n", 64), None);
+        assert_eq!(read("abcdef", 6).as_deref(), Some("abcdef"));
+        assert_eq!(read("abcdefg", 6).as_deref(), Some("abcdef…"));
+        // "é" is two bytes; cutting through it drops it rather than mangling it.
+        assert_eq!(read("abcdeé", 6).as_deref(), Some("abcde…"));
+    }
+
+    #[test]
+    fn test_attach() {
+        let cli = Cli::try_parse_from(["psh", "send", "--attach", "shot.PNG", "done"]).unwrap();
+        let Commands::Send(args) = cli.command else {
+            panic!("expected a send command");
+        };
+        let path = args.attach.clone().unwrap();
+        assert_eq!(attachment_content_type(&path), "image/png");
+        let log = Path::new("build.log");
+        assert_eq!(attachment_content_type(log), "text/plain");
+        let unknown = Path::new("core");
+        assert_eq!(attachment_content_type(unknown), "application/octet-stream");
+        // The id is only known once the file is uploaded.
+        assert_eq!(args.into_request().attachment, None);
+
+        let preview = ["psh", "send", "--attach", "a.png", "--preview", "hi"];
+        assert!(Cli::try_parse_from(preview).is_err());
+    }
+
+    #[test]
+    fn test_format_device_line() {
+        let mut device = DeviceRecord {
+            device_token: "abc".to_string(),
+            environment: "sandbox".to_string(),
+            device_name: Some("Pat's iPhone".to_string()),
+            device_type: Some("iPhone".to_string()),
+            locale: Some("fr-ca".to_string()),
+            timezone: None,
+            enabled: true,
+            snoozed_until: None,
+            expires_at: None,
+            tags: BTreeMap::new(),
+        };
+        assert_eq!(
+            format_device_line(&device),
+            "abc\tsandbox\tPat's iPhone\tiPhone\tfr-ca\t-"
+        );
+        device.tags = BTreeMap::from([
+            ("tier".to_string(), "beta".to_string()),
+            ("team".to_string(), "ios".to_string()),
+        ]);
+        assert!(format_device_line(&device).ends_with("\t-\tteam=ios,tier=beta"));
+        device.enabled = false;
+        assert!(format_device_line(&device).ends_with("\tteam=ios,tier=beta\tdisabled"));
+        device.snoozed_until = Some("2024-01-01 16:00:00".to_string());
+        assert!(
+            format_device_line(&device).ends_with("\tdisabled\tsnoozed until 2024-01-01 16:00:00")
+        );
+        device.expires_at = Some("2024-02-01 00:00:00".to_string());
+        assert!(format_device_line(&device).ends_with("\texpires 2024-02-01 00:00:00"));
+    }
+
+    #[test]
+    fn test_format_installation_line() {
+        let mut activity = InstallationActivity {
+            pushes: 1,
+            last_push_at: Some("2026-10-16 09:30:00".to_string()),
+            last_active_at: "2026-10-16 10:00:00".to_string(),
+        };
+        assert_eq!(
+            format_installation_line("install-1", 2, &activity),
+            "install-1\t2 devices\t1 push\tlast push 2026-10-16 09:30:00\tactive 2026-10-16 10:00:00"
+        );
+        activity.pushes = 0;
+        activity.last_push_at = None;
+        assert!(format_installation_line("install-1", 1, &activity)
+            .starts_with("install-1\t1 device\t0 pushes\tlast push never\t"));
+
+        let cli = Cli::try_parse_from(["psh", "devices", "list", "--by-installation"]).unwrap();
+        match cli.command {
+            Commands::Devices(DevicesCommand::List {
+                by_installation, ..
+            }) => assert!(by_installation),
+            _ => panic!("expected devices list"),
+        }
+    }
+
+    #[test]
+    fn test_send_exit_codes() {
+        let counts = |sent, failed, held| SendCounts { sent, failed, held };
+        assert_eq!(counts(3, 0, 0).exit_code(false), 0);
+        assert_eq!(counts(2, 1, 0).exit_code(false), 1);
+        assert_eq!(counts(0, 3, 0).exit_code(false), 2);
+        // Held devices and empty sends only fail with --fail-on-any.
+        assert_eq!(counts(2, 0, 1).exit_code(false), 0);
+        assert_eq!(counts(2, 0, 1).exit_code(true), 1);
+        assert_eq!(counts(0, 0, 2).exit_code(true), 2);
+        assert_eq!(counts(0, 0, 0).exit_code(false), 0);
+        assert_eq!(counts(0, 0, 0).exit_code(true), 2);
+    }
+
+    #[test]
+    fn test_format_send_summary() {
+        let result: SendResponse = serde_json::from_value(json!({
+            "success": true,
+            "sent": 1,
+            "failed": 2,
+            "skipped": 1,
+            "request_id": "req-1",
+            "results": [
+                {"device_token": "a", "success": true, "apns_id": "apns-1", "error": null},
+                {"device_token": "b", "success": false, "apns_id": null, "error": "x", "error_code": "Unregistered"},
+                {"device_token": "c", "success": false, "apns_id": null, "error": "y", "error_code": "BadDeviceToken"}
+            ]
+        }))
+        .unwrap();
+        let counts = SendCounts {
+            sent: 1,
+            failed: 2,
+            held: 1,
+        };
+        assert_eq!(
+            format_send_summary(&result, counts),
+            "status=partial sent=1 failed=2 deferred=0 skipped=1 snoozed=0 \
+             errors=BadDeviceToken:1,Unregistered:1 request_id=req-1"
+        );
+    }
+
+    #[test]
+    fn test_format_test_device() {
+        let test: TestDeviceResponse = serde_json::from_value(json!({
+            "device_token": "a".repeat(64),
+            "environment": "production",
+            "results": [
+                {
+                    "environment": "sandbox",
+                    "success": false,
+                    "apns_id": null,
+                    "error": "BadDeviceToken",
+                    "error_code": "BadDeviceToken"
+                },
+                {
+                    "environment": "production",
+                    "success": true,
+                    "apns_id": "apns-1",
+                    "error": null,
+                    "error_code": null
+                }
+            ]
+        }))
+        .unwrap();
+        let lines = format_test_device(&test);
+        assert_eq!(lines[0], "Test push to aaaaaaaa...aaaaaaaa");
+        assert!(
+            lines[1].starts_with("  sandbox    rejected: "),
+            "{}",
+            lines[1]
+        );
+        assert_eq!(lines[2], "  production accepted, apns-id apns-1");
+        assert!(lines[3].ends_with("\"environment\": \"production\""));
+    }
+
+    #[test]
+    fn test_format_usage_line() {
+        let key = KeyUsage {
+            name: "ci".to_string(),
+            role: "send".to_string(),
+            today: 12,
+            this_month: 340,
+            daily_quota: Some(1000),
+            monthly_quota: None,
+        };
+        assert_eq!(
+            format_usage_line(&key),
+            "ci\tsend\ttoday 12/1000\tmonth 340"
+        );
+    }
+
+    #[test]
+    fn test_devices_export_format() {
+        let cli = Cli::try_parse_from(["psh", "devices", "export", "--format", "csv"]).unwrap();
+        match cli.command {
+            Commands::Devices(DevicesCommand::Export { format }) => assert_eq!(format, "csv"),
+            _ => panic!("expected devices export"),
+        }
+        assert!(Cli::try_parse_from(["psh", "devices", "export", "--format", "xml"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_tag() {
+        assert_eq!(
+            parse_tag("tier=beta"),
+            Ok(("tier".to_string(), Some("beta".to_string())))
+        );
+        assert_eq!(parse_tag("tier="), Ok(("tier".to_string(), None)));
+        assert!(parse_tag("tier").is_err());
+        assert!(parse_tag("=beta").is_err());
+        assert!(parse_tag("a,b=c").is_err());
+        assert!(parse_tag("tier=beta,ga").is_err());
+    }
+
+    #[test]
+    fn test_devices_snooze_duration() {
+        let cli = Cli::try_parse_from(["psh", "devices", "snooze", "abc", "--for", "2h"]).unwrap();
+        match cli.command {
+            Commands::Devices(DevicesCommand::Snooze { token, duration }) => {
+                assert_eq!((token.as_str(), duration), ("abc", 7200))
+            }
+            _ => panic!("expected devices snooze"),
+        }
+        assert!(Cli::try_parse_from(["psh", "devices", "snooze", "abc"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_duration_secs() {
+        assert_eq!(parse_duration_secs("90"), Ok(90));
+        assert_eq!(parse_duration_secs("30m"), Ok(1800));
+        assert_eq!(parse_duration_secs("1h30m"), Ok(5400));
+        assert_eq!(parse_duration_secs("1d"), Ok(86_400));
+        assert!(parse_duration_secs("").is_err());
+        assert!(parse_duration_secs("2x").is_err());
+        assert!(parse_duration_secs("1h30").is_err());
+    }
+
+    #[test]
+    fn test_expires_in_conflicts_with_expiration() {
+        assert!(Cli::try_parse_from(["psh", "send", "--ttl", "30m", "hi"]).is_ok());
+        assert!(Cli::try_parse_from([
+            "psh",
+            "send",
+            "--expires-in",
+            "2h",
+            "--expiration",
+            "1700000000",
+            "hi"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_priority_range() {
+        assert!(Cli::try_parse_from(["psh", "send", "--priority", "10"]).is_ok());
+        assert!(Cli::try_parse_from(["psh", "send", "--priority", "1"]).is_ok());
+        assert!(Cli::try_parse_from(["psh", "send", "--priority", "0"]).is_err());
+        assert!(Cli::try_parse_from(["psh", "send", "--priority", "255"]).is_err());
+    }
+
+    #[test]
+    fn test_format_health_status() {
+        let health: HealthStatus = serde_json::from_str(r#"{"status": "ok"}"#).unwrap();
+        assert_eq!(
+            format_health_status(&health),
+            "Server is healthy (details need an API key with read access)"
+        );
+    }
+
+    #[test]
+    fn test_format_health() {
+        let health: HealthResponse = serde_json::from_str(
+            r#"{
+                "status": "unavailable",
+                "version": "abc123",
+                "uptime_seconds": 7260,
+                "queue_depth": 4,
+                "checks": {
+                    "database": {"ok": true},
+                    "apns": {"ok": false, "error": "Cannot read APNs key"}
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            format_health(&health),
+            vec![
+                "Server is unhealthy (version abc123, up 2h 1m)",
+                "  database  ok",
+                "  apns      FAIL: Cannot read APNs key",
+                "  queue     4",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_format_health_token_age() {
+        let health: HealthResponse = serde_json::from_str(
+            r#"{
+                "status": "ok",
+                "version": "abc123",
+                "uptime_seconds": 42,
+                "queue_depth": 0,
+                "checks": {
+                    "database": {"ok": true},
+                    "apns": {"ok": true, "token_age_seconds": 1500}
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(format_health(&health)[2], "  apns      ok (token 25m old)");
+    }
 
-    let response = client
-        .get(&url)
-        .send()
-        .await
-        .context("Failed to connect to server")?;
+    #[test]
+    fn test_format_health_connection_failures() {
+        let health: HealthResponse = serde_json::from_str(
+            r#"{
+                "status": "ok",
+                "version": "abc123",
+                "uptime_seconds": 42,
+                "queue_depth": 0,
+                "checks": {
+                    "database": {"ok": true},
+                    "apns": {"ok": true, "token_age_seconds": 60, "consecutive_failures": 3}
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            format_health(&health)[2],
+            "  apns      ok (token 1m old), 3 connection failures in a row"
+        );
+    }
 
-    let status = response.status();
-    if status.is_success() {
-        let stats: StatsResponse = response.json().await.context("Invalid response")?;
-        println!("Devices: {} total ({} sandbox, {} production)",
-            stats.total_devices,
-            stats.sandbox_devices,
-            stats.production_devices
+    #[test]
+    fn test_format_health_warning() {
+        let health: HealthResponse = serde_json::from_str(
+            r#"{
+                "status": "ok",
+                "version": "abc123",
+                "uptime_seconds": 42,
+                "queue_depth": 0,
+                "checks": {
+                    "database": {"ok": true},
+                    "apns": {"ok": true, "warning": "APNs production rejected the provider token 3 times in a row"}
+                }
+            }"#,
+        )
+        .unwrap();
+        let lines = format_health(&health);
+        assert_eq!(
+            lines[3],
+            "            warning: APNs production rejected the provider token 3 times in a row"
         );
-        println!("Pushes: {}", stats.total_pushes);
-    } else {
-        let error: ErrorResponse = response
-            .json()
-            .await
-            .unwrap_or(ErrorResponse {
-                error: format!("HTTP {}", status),
-            });
-        anyhow::bail!("Error: {}", error.error);
+        assert_eq!(lines[4], "  queue     0");
     }
 
-    Ok(())
-}
+    #[test]
+    fn test_doctor_health_checks() {
+        let health: HealthResponse = serde_json::from_str(
+            r#"{
+                "status": "ok",
+                "version": "abc123",
+                "uptime_seconds": 42,
+                "queue_depth": 0,
+                "checks": {
+                    "database": {"ok": true},
+                    "apns": {"ok": true, "credentials": {"mock": true, "topic": "com.example"}}
+                }
+            }"#,
+        )
+        .unwrap();
+        let checks = health_checks(&health);
+        assert_eq!(checks[0].status, DoctorStatus::Ok);
+        assert_eq!(checks[1].status, DoctorStatus::Warn);
+        assert_eq!(
+            format_doctor_check(&checks[1]),
+            "  WARN apns     mock mode: pushes to com.example are recorded, not delivered\n       \
+             fix: Unset PSH_APNS_MODE and set APNS_KEY_PATH, APNS_KEY_ID, APNS_TEAM_ID and \
+             APNS_TOPIC"
+        );
 
-async fn cmd_ping(server: &str) -> Result<()> {
-    let client = reqwest::Client::new();
+        let health: HealthResponse = serde_json::from_str(
+            r#"{
+                "status": "unavailable",
+                "version": "abc123",
+                "uptime_seconds": 42,
+                "queue_depth": 0,
+                "checks": {
+                    "database": {"ok": true},
+                    "apns": {"ok": false, "error": "Cannot read APNs key"}
+                }
+            }"#,
+        )
+        .unwrap();
+        let checks = health_checks(&health);
+        assert_eq!(checks[0].status, DoctorStatus::Ok);
+        assert_eq!(checks[1].status, DoctorStatus::Fail);
+        assert!(checks[1].fix.as_deref().unwrap().contains("APNS_KEY_PATH"));
+    }
 
-    let response = client
-        .get(server)
-        .send()
-        .await
-        .context("Failed to connect to server")?;
+    #[test]
+    fn test_api_mismatch() {
+        let version = |api_version| VersionResponse {
+            version: "0.1.5".to_string(),
+            git_hash: "abc123".to_string(),
+            api_version,
+        };
+        assert!(api_mismatch(Some(&version(API_VERSION))).is_none());
+        assert!(api_mismatch(Some(&version(API_VERSION + 1))).is_none());
+        assert!(api_mismatch(Some(&version(0)))
+            .unwrap()
+            .contains("speaks API v0"));
+        assert!(api_mismatch(None).unwrap().contains("predates /version"));
 
-    if response.status().is_success() {
-        println!("Server is healthy");
-    } else {
-        anyhow::bail!("Server returned status: {}", response.status());
+        let cli = Cli::try_parse_from(["psh", "ping", "--strict"]).unwrap();
+        assert!(cli.strict);
     }
 
-    Ok(())
-}
+    #[test]
+    fn test_doctor_check_config() {
+        let dir = std::env::temp_dir().join(format!("psh-test-doctor-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
 
-fn truncate_token(token: &str) -> String {
-    if token.len() > 16 {
-        format!("{}...{}", &token[..8], &token[token.len() - 8..])
-    } else {
-        token.to_string()
-    }
-}
+        let (check, config) = check_config(Some(&path));
+        assert_eq!(check.status, DoctorStatus::Ok);
+        assert!(config.server.is_none());
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let cli = Cli::parse();
-    let config = Config::load();
-    let server = resolve_server(cli.server, &config)?;
+        std::fs::write(
+            &path,
+            "server = \"https://psh.example\"\nsever = \"typo\"\n",
+        )
+        .unwrap();
+        let (check, config) = check_config(Some(&path));
+        assert_eq!(check.status, DoctorStatus::Warn);
+        assert!(check.detail.ends_with("unknown keys: sever"));
+        assert_eq!(config.server.as_deref(), Some("https://psh.example"));
 
-    match cli.command {
-        Commands::Send(args) => {
-            if args.is_empty() {
-                Cli::command()
-                    .find_subcommand_mut("send")
-                    .unwrap()
-                    .print_help()?;
-                println!();
-                return Ok(());
-            }
-            cmd_send(&server, args).await
-        }
-        Commands::Stats => cmd_stats(&server).await,
-        Commands::Ping => cmd_ping(&server).await,
-    }
-}
+        std::fs::write(&path, "ca_cert = \"/nonexistent/ca.pem\"\n").unwrap();
+        assert_eq!(check_config(Some(&path)).0.status, DoctorStatus::Fail);
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        std::fs::write(&path, "server = ").unwrap();
+        let (check, _) = check_config(Some(&path));
+        assert_eq!(check.status, DoctorStatus::Fail);
+        assert!(check.detail.contains("not valid TOML"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
     #[test]
-    fn test_send_args_basic_body() {
-        let args = SendArgs {
-            body_positional: Some("Hello".to_string()),
-            title: None,
-            subtitle: None,
-            body: None,
-            launch_image: None,
-            title_loc_key: None,
-            title_loc_args: None,
-            loc_key: None,
-            loc_args: None,
-            badge: None,
-            sound: None,
-            sound_critical: false,
-            sound_name: None,
-            sound_volume: None,
-            content_available: false,
-            mutable_content: false,
-            category: None,
-            priority: None,
-            collapse_id: None,
-            expiration: None,
-            data: vec![],
-        };
-        let req = args.into_request();
-        assert_eq!(req.body, Some("Hello".to_string()));
-        assert!(req.title.is_none());
+    fn test_error_message_includes_request_id() {
+        let error: ErrorResponse =
+            serde_json::from_str(r#"{"error": "Segment not found: beta", "request_id": "ab12"}"#)
+                .unwrap();
+        assert_eq!(error.message(), "Segment not found: beta (request ab12)");
+
+        let error: ErrorResponse = serde_json::from_str(r#"{"error": "Push not found"}"#).unwrap();
+        assert_eq!(error.message(), "Push not found");
     }
 
     #[test]
-    fn test_send_args_body_flag_overrides_positional() {
-        let args = SendArgs {
-            body_positional: Some("Positional".to_string()),
-            title: None,
-            subtitle: None,
-            body: Some("Flag".to_string()),
-            launch_image: None,
-            title_loc_key: None,
-            title_loc_args: None,
-            loc_key: None,
-            loc_args: None,
-            badge: None,
-            sound: None,
-            sound_critical: false,
-            sound_name: None,
-            sound_volume: None,
-            content_available: false,
-            mutable_content: false,
-            category: None,
-            priority: None,
-            collapse_id: None,
-            expiration: None,
-            data: vec![],
-        };
-        let req = args.into_request();
-        assert_eq!(req.body, Some("Flag".to_string()));
+    fn test_format_send_result_annotations() {
+        let result: DeviceSendResult = serde_json::from_str(
+            r#"{
+                "device_token": "abcdef1234567890abcdef",
+                "success": true,
+                "apns_id": null,
+                "error": null,
+                "warning": "Device already received 3 background pushes this hour",
+                "deferred_until": 10800
+            }"#,
+        )
+        .unwrap();
+        let expected = format!(
+            "  {} -> deferred, sends in 40m\n    warning: {}",
+            truncate_token(&result.device_token),
+            "Device already received 3 background pushes this hour"
+        );
+        assert_eq!(format_send_result(&result, 8400), expected);
+
+        let result: DeviceSendResult = serde_json::from_str(
+            r#"{
+                "device_token": "abcdef1234567890abcdef",
+                "success": true,
+                "apns_id": null,
+                "error": null,
+                "skipped_duplicate": true
+            }"#,
+        )
+        .unwrap();
+        assert!(format_send_result(&result, 0).ends_with("skipped, same push sent recently"));
+
+        let result: DeviceSendResult = serde_json::from_str(
+            r#"{
+                "device_token": "abcdef1234567890abcdef",
+                "success": true,
+                "apns_id": null,
+                "error": null,
+                "skipped_snoozed": true
+            }"#,
+        )
+        .unwrap();
+        assert!(format_send_result(&result, 0).ends_with("skipped, device is snoozed"));
+
+        let result: DeviceSendResult = serde_json::from_str(
+            r#"{
+                "device_token": "abcdef1234567890abcdef",
+                "success": true,
+                "apns_id": "apns-1",
+                "error": null,
+                "fallback_environment": "production",
+                "environment_corrected": true
+            }"#,
+        )
+        .unwrap();
+        assert!(format_send_result(&result, 0)
+            .ends_with("apns-1 (sent via production fallback, device updated)"));
+
+        let result: DeviceSendResult = serde_json::from_str(
+            r#"{
+                "device_token": "abcdef1234567890abcdef",
+                "success": true,
+                "apns_id": "apns-1",
+                "error": null,
+                "truncated": true
+            }"#,
+        )
+        .unwrap();
+        assert!(format_send_result(&result, 0).ends_with("apns-1 (body truncated)"));
     }
 
     #[test]
-    fn test_send_args_with_title() {
-        let args = SendArgs {
-            body_positional: Some("Body".to_string()),
-            title: Some("Title".to_string()),
-            subtitle: None,
-            body: None,
-            launch_image: None,
-            title_loc_key: None,
-            title_loc_args: None,
-            loc_key: None,
-            loc_args: None,
-            badge: None,
-            sound: None,
-            sound_critical: false,
-            sound_name: None,
-            sound_volume: None,
-            content_available: false,
-            mutable_content: false,
-            category: None,
-            priority: None,
-            collapse_id: None,
-            expiration: None,
-            data: vec![],
-        };
-        let req = args.into_request();
-        assert_eq!(req.title, Some("Title".to_string()));
-        assert_eq!(req.body, Some("Body".to_string()));
+    fn test_http_flags_are_global() {
+        let args = "psh ping --timeout 5 --ca-cert /tmp/ca.pem --insecure";
+        let cli = Cli::try_parse_from(args.split(' ')).unwrap();
+        assert_eq!(cli.http.timeout, 5);
+        assert_eq!(cli.http.ca_cert, Some(PathBuf::from("/tmp/ca.pem")));
+        assert!(cli.http.insecure);
+        assert!(cli.http.client(None).is_err());
+
+        let cli = Cli::try_parse_from(["psh", "ping"]).unwrap();
+        assert_eq!(cli.http.timeout, 30);
+        assert!(!cli.http.insecure);
+        assert!(cli.http.client(None).is_ok());
+
+        let cli = Cli::try_parse_from(["psh", "--api-key", "ci-secret", "ping"]).unwrap();
+        assert_eq!(cli.http.api_key.as_deref(), Some("ci-secret"));
+        assert!(cli.http.client(None).is_ok());
     }
 
     #[test]
-    fn test_send_args_simple_sound() {
-        let args = SendArgs {
-            body_positional: None,
-            title: None,
-            subtitle: None,
-            body: None,
-            launch_image: None,
-            title_loc_key: None,
-            title_loc_args: None,
-            loc_key: None,
-            loc_args: None,
-            badge: None,
-            sound: Some("default".to_string()),
-            sound_critical: false,
-            sound_name: None,
-            sound_volume: None,
-            content_available: false,
-            mutable_content: false,
-            category: None,
-            priority: None,
-            collapse_id: None,
-            expiration: None,
-            data: vec![],
-        };
-        let req = args.into_request();
-        assert!(matches!(req.sound, Some(SoundConfig::Simple(s)) if s == "default"));
+    fn test_quiet_and_verbose_flags() {
+        let cli = Cli::try_parse_from(["psh", "send", "-q", "hi"]).unwrap();
+        assert!(cli.quiet && !cli.verbose);
+        let cli = Cli::try_parse_from(["psh", "-v", "ping"]).unwrap();
+        assert!(cli.verbose && !cli.quiet);
+        assert!(Cli::try_parse_from(["psh", "-q", "-v", "ping"]).is_err());
     }
 
     #[test]
-    fn test_send_args_critical_sound() {
-        let args = SendArgs {
-            body_positional: None,
-            title: None,
-            subtitle: None,
-            body: None,
-            launch_image: None,
-            title_loc_key: None,
-            title_loc_args: None,
-            loc_key: None,
-            loc_args: None,
-            badge: None,
-            sound: None,
-            sound_critical: true,
-            sound_name: Some("alert.caf".to_string()),
-            sound_volume: Some(0.8),
-            content_available: false,
-            mutable_content: false,
-            category: None,
-            priority: None,
-            collapse_id: None,
-            expiration: None,
-            data: vec![],
-        };
-        let req = args.into_request();
-        match req.sound {
-            Some(SoundConfig::Critical { name, critical, volume }) => {
-                assert_eq!(name, "alert.caf");
-                assert!(critical);
-                assert_eq!(volume, Some(0.8));
-            }
-            _ => panic!("Expected critical sound config"),
-        }
+    fn test_show_full_tokens_flag() {
+        let cli = Cli::try_parse_from(["psh", "devices", "list", "--show-full-tokens"]).unwrap();
+        assert!(cli.show_full_tokens);
+        let cli = Cli::try_parse_from(["psh", "devices", "list"]).unwrap();
+        assert!(!cli.show_full_tokens);
     }
 
     #[test]
-    fn test_send_args_critical_sound_default_name() {
-        let args = SendArgs {
-            body_positional: None,
-            title: None,
-            subtitle: None,
-            body: None,
-            launch_image: None,
-            title_loc_key: None,
-            title_loc_args: None,
-            loc_key: None,
-            loc_args: None,
-            badge: None,
-            sound: None,
-            sound_critical: true,
-            sound_name: None,
-            sound_volume: None,
-            content_available: false,
-            mutable_content: false,
-            category: None,
-            priority: None,
-            collapse_id: None,
-            expiration: None,
-            data: vec![],
+    fn test_ca_cert_without_certificates_is_an_error() {
+        let path = std::env::temp_dir().join(format!("psh-test-ca-{}.pem", std::process::id()));
+        std::fs::write(&path, "not a certificate").unwrap();
+        let http = HttpArgs {
+            timeout: 30,
+            ca_cert: Some(path.clone()),
+            insecure: false,
+            api_key: None,
         };
-        let req = args.into_request();
-        match req.sound {
-            Some(SoundConfig::Critical { name, .. }) => {
-                assert_eq!(name, "default");
-            }
-            _ => panic!("Expected critical sound config"),
-        }
+        let error = http.client(None).unwrap_err().to_string();
+        std::fs::remove_file(&path).unwrap();
+        assert!(error.starts_with("No certificates found in"), "{error}");
     }
 
     #[test]
-    fn test_send_args_data_parsing() {
-        let args = SendArgs {
-            body_positional: None,
-            title: None,
-            subtitle: None,
-            body: None,
-            launch_image: None,
-            title_loc_key: None,
-            title_loc_args: None,
-            loc_key: None,
-            loc_args: None,
-            badge: None,
-            sound: None,
-            sound_critical: false,
-            sound_name: None,
-            sound_volume: None,
-            content_available: false,
-            mutable_content: false,
-            category: None,
-            priority: None,
-            collapse_id: None,
-            expiration: None,
-            data: vec!["key1=value1".to_string(), "key2=value2".to_string()],
-        };
-        let req = args.into_request();
-        let data = req.data.unwrap();
-        assert_eq!(data.get("key1").unwrap(), "value1");
-        assert_eq!(data.get("key2").unwrap(), "value2");
+    fn test_format_uptime() {
+        assert_eq!(format_uptime(42), "42s");
+        assert_eq!(format_uptime(600), "10m");
+        assert_eq!(format_uptime(90_000), "1d 1h");
     }
 
     #[test]
-    fn test_send_args_loc_args_parsing() {
-        let args = SendArgs {
-            body_positional: None,
-            title: None,
-            subtitle: None,
-            body: None,
-            launch_image: None,
-            title_loc_key: Some("TITLE_KEY".to_string()),
-            title_loc_args: Some("arg1, arg2, arg3".to_string()),
-            loc_key: Some("BODY_KEY".to_string()),
-            loc_args: Some("a,b".to_string()),
-            badge: None,
-            sound: None,
-            sound_critical: false,
-            sound_name: None,
-            sound_volume: None,
-            content_available: false,
-            mutable_content: false,
-            category: None,
-            priority: None,
-            collapse_id: None,
-            expiration: None,
-            data: vec![],
-        };
-        let req = args.into_request();
-        assert_eq!(req.title_loc_key, Some("TITLE_KEY".to_string()));
+    fn test_format_error() {
         assert_eq!(
-            req.title_loc_args,
-            Some(vec!["arg1".to_string(), "arg2".to_string(), "arg3".to_string()])
+            format_error(Some("TooManyRequests"), Some("Slow down")),
+            "TooManyRequests: Slow down"
         );
-        assert_eq!(req.loc_key, Some("BODY_KEY".to_string()));
-        assert_eq!(req.loc_args, Some(vec!["a".to_string(), "b".to_string()]));
+        assert_eq!(format_error(None, Some("HTTP 500")), "HTTP 500");
+        assert_eq!(format_error(None, None), "Unknown error");
     }
 
     #[test]
-    fn test_send_args_content_available() {
-        let args = SendArgs {
-            body_positional: None,
-            title: None,
-            subtitle: None,
-            body: None,
-            launch_image: None,
-            title_loc_key: None,
-            title_loc_args: None,
-            loc_key: None,
-            loc_args: None,
-            badge: None,
-            sound: None,
-            sound_critical: false,
-            sound_name: None,
-            sound_volume: None,
-            content_available: true,
-            mutable_content: false,
-            category: None,
-            priority: None,
-            collapse_id: None,
-            expiration: None,
-            data: vec![],
-        };
-        let req = args.into_request();
-        assert_eq!(req.content_available, Some(true));
-        assert!(req.mutable_content.is_none());
+    fn test_sparkline() {
+        assert_eq!(sparkline(&[0, 1, 2, 4]), " ▃▅█");
+        assert_eq!(sparkline(&[0, 0]), "  ");
+        assert_eq!(sparkline(&[]), "");
     }
 
     #[test]
-    fn test_send_args_mutable_content() {
-        let args = SendArgs {
-            body_positional: None,
-            title: None,
-            subtitle: None,
-            body: None,
-            launch_image: None,
-            title_loc_key: None,
-            title_loc_args: None,
-            loc_key: None,
-            loc_args: None,
-            badge: None,
-            sound: None,
-            sound_critical: false,
-            sound_name: None,
-            sound_volume: None,
-            content_available: false,
-            mutable_content: true,
-            category: None,
-            priority: None,
-            collapse_id: None,
-            expiration: None,
-            data: vec![],
-        };
-        let req = args.into_request();
-        assert!(req.content_available.is_none());
-        assert_eq!(req.mutable_content, Some(true));
+    fn test_stats_args_require_since() {
+        assert!(Cli::try_parse_from(["psh", "stats", "--graph"]).is_err());
+        assert!(Cli::try_parse_from(["psh", "stats", "--since", "7d", "--graph"]).is_ok());
+        assert!(Cli::try_parse_from(["psh", "stats", "--since", "7d", "--bucket", "week"]).is_err());
     }
 
     #[test]
-    fn test_send_request_serialization() {
-        let req = SendRequest {
-            title: Some("Test".to_string()),
-            subtitle: None,
-            body: Some("Body".to_string()),
-            launch_image: None,
-            title_loc_key: None,
-            title_loc_args: None,
-            loc_key: None,
-            loc_args: None,
-            badge: Some(5),
-            sound: Some(SoundConfig::Simple("default".to_string())),
-            content_available: None,
-            mutable_content: None,
-            category: None,
-            priority: None,
-            collapse_id: None,
-            expiration: None,
-            data: None,
-        };
-        let json = serde_json::to_string(&req).unwrap();
-        assert!(json.contains("\"title\":\"Test\""));
-        assert!(json.contains("\"body\":\"Body\""));
-        assert!(json.contains("\"badge\":5"));
-        assert!(json.contains("\"sound\":\"default\""));
-        assert!(!json.contains("subtitle"));
-        assert!(!json.contains("content_available"));
+    fn test_stats_response_without_series() {
+        let json = r#"{"total_devices":1,"sandbox_devices":1,"production_devices":0,"total_pushes":2}"#;
+        let stats: StatsResponse = serde_json::from_str(json).unwrap();
+        assert_eq!(stats.failed_pushes, 0);
+        assert!(stats.series.is_none());
+        assert!(stats.groups.is_none());
+        assert!(format_platforms("OS versions", &stats.os_versions).is_none());
     }
 
     #[test]
-    fn test_send_request_critical_sound_serialization() {
-        let req = SendRequest {
-            title: None,
-            subtitle: None,
-            body: None,
-            launch_image: None,
-            title_loc_key: None,
-            title_loc_args: None,
-            loc_key: None,
-            loc_args: None,
-            badge: None,
-            sound: Some(SoundConfig::Critical {
-                name: "alert.caf".to_string(),
-                critical: true,
-                volume: Some(0.5),
-            }),
-            content_available: None,
-            mutable_content: None,
-            category: None,
-            priority: None,
-            collapse_id: None,
-            expiration: None,
-            data: None,
-        };
-        let json = serde_json::to_string(&req).unwrap();
-        assert!(json.contains("\"name\":\"alert.caf\""));
-        assert!(json.contains("\"critical\":true"));
-        assert!(json.contains("\"volume\":0.5"));
+    fn test_format_platforms() {
+        let counts: Vec<PlatformCount> = serde_json::from_str(
+            r#"[{"value": "18", "devices": 3, "share": 0.75}, {"value": "17", "devices": 1, "share": 0.25}]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            format_platforms("OS versions", &counts).unwrap(),
+            "OS versions: 18 3 (75%), 17 1 (25%)"
+        );
+    }
+
+    #[test]
+    fn test_format_groups() {
+        let groups: Vec<StatsGroup> = serde_json::from_str(
+            r#"[
+                {"key": "backups", "devices": 2, "sent": 3, "failed": 1, "failure_rate": 0.25, "opened": 1},
+                {"key": null, "sent": 5, "failed": 0, "failure_rate": 0.0, "opened": 0}
+            ]"#,
+        )
+        .unwrap();
+        let lines = format_groups("topic", &groups);
+        assert_eq!(
+            lines[0],
+            "topic                             devices    sent  failed   fail%  opened"
+        );
+        assert_eq!(
+            lines[1],
+            "backups                                 2       3       1   25.0%       1"
+        );
+        assert!(lines[2].starts_with("(none)  ") && lines[2].contains("      -       5"));
+        assert!(Cli::try_parse_from(["psh", "stats", "--by", "app"]).is_ok());
+        assert!(Cli::try_parse_from(["psh", "stats", "--by", "planet"]).is_err());
     }
 
     #[test]
@@ -877,6 +5308,45 @@ server = "https://push.example.com"
         assert_eq!(config.server, Some("https://push.example.com".to_string()));
     }
 
+    #[test]
+    fn test_preset_under_command_line() {
+        let toml = r#"
+[presets.deploy]
+title = "Deploy"
+sound = "ship.caf"
+priority = 10
+expires_in = "1h"
+filter = ["tag.team=ops"]
+
+[presets.deploy.data]
+kind = "deploy"
+build = { id = 1, channel = "stable" }
+"#;
+        let config: Config = toml::from_str(toml).unwrap();
+        let preset = &config.presets["deploy"];
+        let line = "psh send --preset deploy --sound alarm.caf -d build.id:=42 --filter device_type=iPad hi";
+        let cli = Cli::try_parse_from(line.split(' ')).unwrap();
+        let Commands::Send(mut args) = cli.command else {
+            panic!("expected send");
+        };
+        assert_eq!(args.preset.as_deref(), Some("deploy"));
+        preset.apply(&mut args).unwrap();
+        assert_eq!(args.title.as_deref(), Some("Deploy"));
+        assert_eq!(args.sound.as_deref(), Some("alarm.caf"));
+        assert_eq!(args.priority, Some(10));
+        assert_eq!(args.expires_in, Some(3600));
+        assert_eq!(args.filters.len(), 2);
+        let data = nested_data(std::mem::take(&mut args.data));
+        assert_eq!(data["kind"], json!("deploy"));
+        assert_eq!(data["build"], json!({"id": 42, "channel": "stable"}));
+
+        let loud = Preset {
+            priority: Some(11),
+            ..Default::default()
+        };
+        assert!(loud.apply(&mut SendArgs::default()).is_err());
+    }
+
     #[test]
     fn test_config_parse_empty() {
         let toml = "";
@@ -888,6 +5358,7 @@ server = "https://push.example.com"
     fn test_resolve_server_cli_takes_priority() {
         let config = Config {
             server: Some("https://config.example.com".to_string()),
+            ..Default::default()
         };
         let result = resolve_server(Some("https://cli.example.com".to_string()), &config).unwrap();
         assert_eq!(result, "https://cli.example.com");
@@ -897,17 +5368,84 @@ server = "https://push.example.com"
     fn test_resolve_server_config_fallback() {
         let config = Config {
             server: Some("https://config.example.com".to_string()),
+            ..Default::default()
         };
         let result = resolve_server(None, &config).unwrap();
         assert_eq!(result, "https://config.example.com");
     }
 
+    #[test]
+    fn test_unix_socket_servers() {
+        let server = "unix:///run/psh.sock";
+        assert_eq!(unix_socket(server), Some(Path::new("/run/psh.sock")));
+        assert_eq!(unix_socket("unix:psh.sock"), Some(Path::new("psh.sock")));
+        assert_eq!(base_url(server.to_string()), "http://localhost");
+        let url = "https://psh.example.com";
+        assert_eq!(unix_socket(url), None);
+        assert_eq!(base_url(url.to_string()), url);
+        let cli = Cli::try_parse_from(["psh", "ping"]).unwrap();
+        assert!(cli.http.client(unix_socket(server)).is_ok());
+    }
+
     #[test]
     fn test_config_serialize() {
         let config = Config {
             server: Some("https://example.com".to_string()),
+            ..Default::default()
         };
         let toml = toml::to_string_pretty(&config).unwrap();
         assert!(toml.contains("server = \"https://example.com\""));
     }
+
+    #[test]
+    fn test_config_set_and_unset() {
+        let mut config = Config::default();
+        config.set(ConfigKey::Server, Some("https://example.com".to_string()));
+        assert_eq!(config.get(ConfigKey::Server), Some("https://example.com"));
+
+        config.set(ConfigKey::Server, None);
+        assert!(config.get(ConfigKey::Server).is_none());
+        assert_eq!(toml::to_string_pretty(&config).unwrap(), "");
+    }
+
+    #[test]
+    fn test_config_subcommand_parsing() {
+        let cli = Cli::try_parse_from(["psh", "config", "set", "server", "https://example.com"])
+            .unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::Config(ConfigCommand::Set { key: ConfigKey::Server, value })
+                if value == "https://example.com"
+        ));
+
+        let cli = Cli::try_parse_from(["psh", "config", "get"]).unwrap();
+        assert!(matches!(cli.command, Commands::Config(ConfigCommand::Get { key: None })));
+
+        assert!(Cli::try_parse_from(["psh", "config", "set", "bogus", "x"]).is_err());
+    }
+
+    #[test]
+    fn test_config_tls_settings() {
+        let mut config = Config::default();
+        assert!(ConfigKey::Insecure.validate("yes").is_err());
+        config.set(ConfigKey::CaCert, Some("/etc/psh/ca.pem".to_string()));
+        config.set(ConfigKey::Insecure, Some("true".to_string()));
+        assert_eq!(config.get(ConfigKey::CaCert), Some("/etc/psh/ca.pem"));
+        assert_eq!(config.get(ConfigKey::Insecure), Some("true"));
+        config.set(ConfigKey::ApiKey, Some("ci-secret".to_string()));
+        assert_eq!(config.get(ConfigKey::ApiKey), Some("ci-secret"));
+
+        let toml = toml::to_string_pretty(&config).unwrap();
+        assert!(toml.contains("ca_cert = \"/etc/psh/ca.pem\""));
+        assert!(toml.contains("insecure = true"));
+
+        let cli = Cli::try_parse_from(["psh", "ping"]).unwrap();
+        let http = cli.http.with_config(&config);
+        assert_eq!(http.ca_cert, Some(PathBuf::from("/etc/psh/ca.pem")));
+        assert!(http.insecure);
+
+        let cli = Cli::try_parse_from(["psh", "ping", "--ca-cert", "/tmp/other.pem"]).unwrap();
+        let http = cli.http.with_config(&config);
+        assert_eq!(http.ca_cert, Some(PathBuf::from("/tmp/other.pem")));
+    }
 }
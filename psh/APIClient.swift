@@ -16,6 +16,8 @@ struct RegisterRequest: Encodable {
     let deviceType: String?
     let osVersion: String?
     let appVersion: String?
+    let locale: String?
+    let timezone: String?
 
     enum CodingKeys: String, CodingKey {
         case deviceToken = "device_token"
@@ -25,6 +27,8 @@ struct RegisterRequest: Encodable {
         case deviceType = "device_type"
         case osVersion = "os_version"
         case appVersion = "app_version"
+        case locale
+        case timezone
     }
 }
 
@@ -144,12 +148,14 @@ struct StatsResponse: Decodable {
     let sandboxDevices: Int
     let productionDevices: Int
     let totalPushes: Int
+    let openedPushes: Int?
 
     enum CodingKeys: String, CodingKey {
         case totalDevices = "total_devices"
         case sandboxDevices = "sandbox_devices"
         case productionDevices = "production_devices"
         case totalPushes = "total_pushes"
+        case openedPushes = "opened_pushes"
     }
 }
 
@@ -211,7 +217,9 @@ final class APIClient: Sendable {
             deviceName: deviceName,
             deviceType: deviceType,
             osVersion: osVersion,
-            appVersion: appVersion
+            appVersion: appVersion,
+            locale: Locale.current.identifier,
+            timezone: TimeZone.current.identifier
         )
 
         var urlRequest = URLRequest(url: baseURL.appendingPathComponent("register"))
@@ -255,6 +263,19 @@ final class APIClient: Sendable {
         return try JSONDecoder().decode(ServerPushDetail.self, from: data)
     }
 
+    /// Reports that the user opened a notification. For remote notifications
+    /// the request identifier is the push's APNs id.
+    func reportOpened(notificationId: String) async throws {
+        var urlRequest = URLRequest(url: baseURL.appendingPathComponent("pushes/\(notificationId)/opened"))
+        urlRequest.httpMethod = "POST"
+
+        let (_, response) = try await URLSession.shared.data(for: urlRequest)
+
+        guard let httpResponse = response as? HTTPURLResponse, httpResponse.statusCode == 200 else {
+            throw APIError.reportFailed
+        }
+    }
+
     func sendNotification(_ request: SendRequest) async throws -> SendResponse {
         var urlRequest = URLRequest(url: baseURL.appendingPathComponent("send"))
         urlRequest.httpMethod = "POST"
@@ -287,6 +308,7 @@ enum APIError: Error, LocalizedError {
     case registrationFailed
     case fetchFailed
     case sendFailed
+    case reportFailed
     case serverError(String)
 
     var errorDescription: String? {
@@ -297,6 +319,8 @@ enum APIError: Error, LocalizedError {
             return "Failed to fetch data"
         case .sendFailed:
             return "Failed to send notification"
+        case .reportFailed:
+            return "Failed to report opened notification"
         case let .serverError(message):
             return message
         }
@@ -76,6 +76,17 @@ final class AppDelegate: NSObject, UIApplicationDelegate, UNUserNotificationCent
         didReceive response: UNNotificationResponse
     ) async {
         saveNotification(from: response.notification.request.content.userInfo)
+        if response.actionIdentifier == UNNotificationDefaultActionIdentifier {
+            await reportOpened(response.notification.request.identifier)
+        }
+    }
+
+    private func reportOpened(_ notificationId: String) async {
+        do {
+            try await APIClient.shared.reportOpened(notificationId: notificationId)
+        } catch {
+            print("Failed to report opened notification: \(error)")
+        }
     }
 
     private func saveNotification(from userInfo: [AnyHashable: Any]) {
@@ -183,6 +194,17 @@ final class AppDelegate: NSObject, NSApplicationDelegate, UNUserNotificationCent
         didReceive response: UNNotificationResponse
     ) async {
         saveNotification(from: response.notification.request.content.userInfo)
+        if response.actionIdentifier == UNNotificationDefaultActionIdentifier {
+            await reportOpened(response.notification.request.identifier)
+        }
+    }
+
+    private func reportOpened(_ notificationId: String) async {
+        do {
+            try await APIClient.shared.reportOpened(notificationId: notificationId)
+        } catch {
+            print("Failed to report opened notification: \(error)")
+        }
     }
 
     private func saveNotification(from userInfo: [AnyHashable: Any]) {
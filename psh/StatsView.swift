@@ -24,6 +24,9 @@ struct StatsView: View {
                         }
                         Section("Pushes") {
                             StatRow(label: "Total Sent", value: stats.totalPushes)
+                            if let opened = stats.openedPushes {
+                                StatRow(label: "Opened", value: opened)
+                            }
                         }
                     }
                 } else if let error = errorMessage {
@@ -0,0 +1,152 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+use crate::SendRequest;
+
+/// The custom data key a send's `actions` are delivered under, for the app to
+/// register as its category's buttons.
+pub const ACTIONS_DATA_KEY: &str = "psh_actions";
+
+/// iOS shows at most this many actions on an expanded notification.
+const MAX_ACTIONS: usize = 10;
+
+/// One button a notification advertises, mirroring `UNNotificationAction`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(test, serde(deny_unknown_fields))]
+pub struct Action {
+    pub id: String,
+    pub title: String,
+    /// SF Symbol shown beside the title.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub destructive: bool,
+    /// Launch the app into the foreground when tapped.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub foreground: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub authentication_required: bool,
+    /// Makes this a text input action, like a quick reply.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text_input: Option<TextInput>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(test, serde(deny_unknown_fields))]
+pub struct TextInput {
+    pub button_title: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub placeholder: Option<String>,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+/// Checks a send's actions: a category for the app to register them under,
+/// non-empty unique ids and titles, and no more than iOS can show.
+pub(crate) fn validate(req: &SendRequest) -> Result<(), String> {
+    let Some(actions) = &req.actions else {
+        return Ok(());
+    };
+    if req.category.is_none() {
+        return Err("actions require a category".to_string());
+    }
+    if actions.len() > MAX_ACTIONS {
+        return Err(format!(
+            "at most {MAX_ACTIONS} actions are supported, got {}",
+            actions.len()
+        ));
+    }
+    let mut ids = HashSet::new();
+    for action in actions {
+        if action.id.trim().is_empty() || action.title.trim().is_empty() {
+            return Err("every action needs an id and a title".to_string());
+        }
+        if !ids.insert(action.id.as_str()) {
+            return Err(format!("duplicate action id: {}", action.id));
+        }
+    }
+    Ok(())
+}
+
+/// Adds `req.actions` to its custom data under `ACTIONS_DATA_KEY`.
+pub(crate) fn inject(req: &mut SendRequest) {
+    let Some(actions) = req.actions.as_ref().filter(|a| !a.is_empty()) else {
+        return;
+    };
+    let actions = serde_json::to_value(actions).unwrap_or(Value::Null);
+    req.data
+        .get_or_insert_with(HashMap::new)
+        .insert(ACTIONS_DATA_KEY.to_string(), actions);
+}
+
+/// `req.actions` as stored in push history.
+pub(crate) fn history_json(req: &SendRequest) -> Option<String> {
+    req.actions
+        .as_ref()
+        .and_then(|actions| serde_json::to_string(actions).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn action(id: &str) -> Action {
+        Action {
+            id: id.to_string(),
+            title: "Reply".to_string(),
+            icon: None,
+            destructive: false,
+            foreground: false,
+            authentication_required: false,
+            text_input: None,
+        }
+    }
+
+    fn request(actions: Vec<Action>) -> SendRequest {
+        SendRequest {
+            category: Some("MESSAGE".to_string()),
+            actions: Some(actions),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate(&SendRequest::default()).is_ok());
+        assert!(validate(&request(vec![action("reply"), action("archive")])).is_ok());
+
+        let mut req = request(vec![action("reply")]);
+        req.category = None;
+        assert_eq!(validate(&req).unwrap_err(), "actions require a category");
+
+        let err = validate(&request(vec![action("reply"), action("reply")])).unwrap_err();
+        assert!(err.contains("duplicate"), "{err}");
+        assert!(validate(&request(vec![action(" ")])).is_err());
+        assert!(validate(&request((0..11).map(|i| action(&i.to_string())).collect())).is_err());
+    }
+
+    #[test]
+    fn test_inject_keeps_existing_data() {
+        let mut req = request(vec![Action {
+            text_input: Some(TextInput {
+                button_title: "Send".to_string(),
+                placeholder: None,
+            }),
+            ..action("reply")
+        }]);
+        req.data = Some(HashMap::from([("thread".to_string(), json!(7))]));
+
+        inject(&mut req);
+
+        let data = req.data.unwrap();
+        assert_eq!(data["thread"], 7);
+        assert_eq!(
+            data[ACTIONS_DATA_KEY],
+            json!([{"id": "reply", "title": "Reply", "text_input": {"button_title": "Send"}}])
+        );
+    }
+}
@@ -0,0 +1,482 @@
+use axum::Json;
+use reqwest::header::CONTENT_TYPE;
+use seekwel::{connection::Connection, error::Error as SeekwelError, rusqlite::params};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+use crate::{
+    audit::AuditContext,
+    config::FailureAlertsConfig,
+    deliver, duration,
+    filter::DeviceFilter,
+    leases,
+    provider::{EndpointStatus, Platform},
+    request_id, token, topics, AppState, Database, SendRequest,
+};
+
+/// How often the failure rate and provider token are checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pushes recorded in the window.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct WindowCounts {
+    pushes: i64,
+    failed: i64,
+    /// The most common error code among the failures.
+    top_error: Option<String>,
+}
+
+impl WindowCounts {
+    fn failure_rate(&self) -> f64 {
+        if self.pushes == 0 {
+            0.0
+        } else {
+            self.failed as f64 / self.pushes as f64
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Alert {
+    Firing,
+    Recovered,
+    /// APNs keeps rejecting the provider token, so the key is likely revoked.
+    TokenRejected,
+    TokenAccepted,
+}
+
+impl Alert {
+    fn event(self) -> &'static str {
+        match self {
+            Alert::Firing => "failure_rate.alert",
+            Alert::Recovered => "failure_rate.recovered",
+            Alert::TokenRejected => "provider_token.rejected",
+            Alert::TokenAccepted => "provider_token.recovered",
+        }
+    }
+}
+
+/// Whether APNs has started or stopped rejecting the provider token, given
+/// whether an alert about it is already out.
+fn evaluate_token(endpoints: &[EndpointStatus], rejected: bool) -> Option<Alert> {
+    match (rejected, endpoints.iter().any(EndpointStatus::is_rejecting)) {
+        (false, true) => Some(Alert::TokenRejected),
+        (true, false) => Some(Alert::TokenAccepted),
+        _ => None,
+    }
+}
+
+fn token_message(alert: Alert, endpoints: &[EndpointStatus]) -> (String, String) {
+    if alert == Alert::TokenRejected {
+        let warnings: Vec<String> = endpoints
+            .iter()
+            .filter_map(EndpointStatus::warning)
+            .collect();
+        (
+            "psh: APNs is rejecting the provider token".to_string(),
+            format!("{}.", warnings.join(". ")),
+        )
+    } else {
+        (
+            "psh: APNs accepts the provider token again".to_string(),
+            "Pushes are being delivered again.".to_string(),
+        )
+    }
+}
+
+impl Database {
+    fn failure_counts(window: Duration) -> Result<WindowCounts, SeekwelError> {
+        let conn = Connection::get()?;
+        let offset = format!("-{} seconds", window.as_secs());
+        let (pushes, failed) = conn.query_row(
+            r#"
+            SELECT COUNT(*), COALESCE(SUM(status = 'failed'), 0)
+            FROM pushes
+            WHERE sent_at >= datetime('now', ?1)
+            "#,
+            params![offset],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        let top_error = conn.query_optional(
+            r#"
+            SELECT error_code
+            FROM pushes
+            WHERE sent_at >= datetime('now', ?1) AND status = 'failed' AND error_code IS NOT NULL
+            GROUP BY error_code
+            ORDER BY COUNT(*) DESC, error_code
+            LIMIT 1
+            "#,
+            params![offset],
+            |row| row.get(0),
+        )?;
+        Ok(WindowCounts {
+            pushes,
+            failed,
+            top_error,
+        })
+    }
+}
+
+/// Watches the share of pushes failing and warns admins when it climbs past
+/// `threshold`, then again once it's back under, so an expired credential
+/// is noticed before users do. Also warns when APNs keeps rejecting the
+/// provider token, however few pushes are going out.
+pub struct FailureAlerts {
+    threshold: f64,
+    window: Duration,
+    /// The window as configured, for messages.
+    window_label: String,
+    min_pushes: i64,
+    topic: Option<String>,
+    device_token: Option<String>,
+    webhook_url: Option<String>,
+    client: reqwest::Client,
+}
+
+impl FailureAlerts {
+    pub fn new(config: &FailureAlertsConfig) -> Result<Self, String> {
+        if !(config.threshold > 0.0 && config.threshold < 1.0) {
+            return Err(format!(
+                "failure_alerts threshold must be between 0 and 1, got {}",
+                config.threshold
+            ));
+        }
+        let window = duration::parse_duration(&config.window)
+            .filter(|window| !window.is_zero())
+            .ok_or_else(|| {
+                format!(
+                    "Invalid failure_alerts window '{}', expected a duration such as 10m",
+                    config.window
+                )
+            })?;
+        if let Some(topic) = &config.topic {
+            if !topics::is_valid_topic(topic) {
+                return Err(format!("Invalid failure_alerts topic '{topic}'"));
+            }
+        }
+        if let Some(url) = &config.webhook_url {
+            if !url.starts_with("https://") && !url.starts_with("http://") {
+                return Err(format!(
+                    "Invalid failure_alerts webhook_url '{url}', expected an http(s) URL"
+                ));
+            }
+        }
+        if config.topic.is_none() && config.device_token.is_none() && config.webhook_url.is_none() {
+            return Err(
+                "[failure_alerts] needs a topic, device_token or webhook_url to alert".to_string(),
+            );
+        }
+        let client = reqwest::Client::builder()
+            .timeout(WEBHOOK_TIMEOUT)
+            .build()
+            .map_err(|e| format!("Cannot build the failure alert webhook client: {e}"))?;
+        Ok(Self {
+            threshold: config.threshold,
+            window,
+            window_label: config.window.trim().to_string(),
+            min_pushes: config.min_pushes as i64,
+            topic: config.topic.clone(),
+            device_token: config.device_token.clone(),
+            webhook_url: config.webhook_url.clone(),
+            client,
+        })
+    }
+
+    pub fn threshold(&self) -> f64 {
+        self.threshold
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// What to send, if anything, given the window's counts and whether an
+    /// alert is already out. Too few pushes change nothing either way.
+    fn evaluate(&self, counts: &WindowCounts, firing: bool) -> Option<Alert> {
+        if counts.pushes < self.min_pushes.max(1) {
+            return None;
+        }
+        match (firing, counts.failure_rate() > self.threshold) {
+            (false, true) => Some(Alert::Firing),
+            (true, false) => Some(Alert::Recovered),
+            _ => None,
+        }
+    }
+
+    /// The title and body of a failure rate alert or recovery.
+    fn message(&self, alert: Alert, counts: &WindowCounts) -> (String, String) {
+        let percent = counts.failure_rate() * 100.0;
+        let window = &self.window_label;
+        if alert == Alert::Firing {
+            (
+                format!("psh: {percent:.0}% of pushes failing"),
+                format!(
+                    "{} of {} pushes failed in the last {window}{}.",
+                    counts.failed,
+                    counts.pushes,
+                    counts
+                        .top_error
+                        .as_deref()
+                        .map(|code| format!(", mostly {code}"))
+                        .unwrap_or_default()
+                ),
+            )
+        } else {
+            (
+                "psh: push failures recovered".to_string(),
+                format!(
+                    "{} of {} pushes failed in the last {window} ({percent:.0}%).",
+                    counts.failed, counts.pushes
+                ),
+            )
+        }
+    }
+
+    /// Checks every minute, on whichever instance holds the lease.
+    pub fn spawn(self, state: AppState) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(CHECK_INTERVAL);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            let mut firing = false;
+            let mut token_rejected = false;
+            loop {
+                ticker.tick().await;
+                if !leases::is_leader("failure_alerts", CHECK_INTERVAL * 2) {
+                    continue;
+                }
+                let endpoints = state
+                    .providers
+                    .get(Platform::Apns)
+                    .map(|provider| provider.endpoint_statuses())
+                    .unwrap_or_default();
+                if let Some(alert) = evaluate_token(&endpoints, token_rejected) {
+                    token_rejected = alert == Alert::TokenRejected;
+                    let (title, body) = token_message(alert, &endpoints);
+                    let details = json!({ "endpoints": endpoints });
+                    self.notify(&state, alert, title, body, details).await;
+                }
+
+                let counts = match Database::failure_counts(self.window) {
+                    Ok(counts) => counts,
+                    Err(e) => {
+                        tracing::error!(error = %e, "Failed to count failed pushes");
+                        continue;
+                    }
+                };
+                if let Some(alert) = self.evaluate(&counts, firing) {
+                    firing = alert == Alert::Firing;
+                    let (title, body) = self.message(alert, &counts);
+                    let details = json!({
+                        "failure_rate": counts.failure_rate(),
+                        "failed": counts.failed,
+                        "pushes": counts.pushes,
+                        "window_seconds": self.window.as_secs(),
+                        "top_error_code": counts.top_error,
+                    });
+                    self.notify(&state, alert, title, body, details).await;
+                }
+            }
+        });
+    }
+
+    /// Sends the alert to each destination. `details` are added to the
+    /// webhook's JSON.
+    async fn notify(
+        &self,
+        state: &AppState,
+        alert: Alert,
+        title: String,
+        body: String,
+        details: Value,
+    ) {
+        tracing::warn!(event = alert.event(), body = %body, "{title}");
+        let req = SendRequest {
+            title: Some(title.clone()),
+            body: Some(body.clone()),
+            interruption_level: Some("time-sensitive".to_string()),
+            ..Default::default()
+        };
+
+        if let Some(topic) = &self.topic {
+            let audit = AuditContext {
+                endpoint: format!("failure alert {topic}"),
+                request_id: Some(request_id::new_request_id()),
+                ..Default::default()
+            };
+            let req = SendRequest {
+                filter: Some(DeviceFilter {
+                    topic: Some(topic.clone()),
+                    ..Default::default()
+                }),
+                ..req.clone()
+            };
+            match crate::send(state.clone(), audit, req).await {
+                Ok(Json(response)) => {
+                    tracing::info!(topic = %topic, sent = response.sent, failed = response.failed, "Sent failure alert");
+                }
+                Err((status, Json(e))) => {
+                    tracing::error!(topic = %topic, status = %status, error = %e.error, "Failed to send failure alert");
+                }
+            }
+        }
+
+        if let Some(device_token) = &self.device_token {
+            match Database::delivery_target(device_token) {
+                Ok(Some(device)) => {
+                    let (result, record) = deliver(state, &device, &req, None).await;
+                    if let Err(e) = Database::record_pushes(&[record]) {
+                        tracing::error!(error = %e, "Failed to record failure alert");
+                    }
+                    if !result.success {
+                        tracing::error!(device_token = %token::logged(device_token), error = ?result.error, "Failed to send failure alert");
+                    }
+                }
+                Ok(None) => {
+                    tracing::error!(device_token = %token::logged(device_token), "Failure alert device isn't registered");
+                }
+                Err(e) => tracing::error!(error = %e, "Failed to look up failure alert device"),
+            }
+        }
+
+        if let Some(url) = &self.webhook_url {
+            let mut payload = json!({
+                "event": alert.event(),
+                "text": format!("{title}\n{body}"),
+                "title": title,
+                "body": body,
+            });
+            if let (Some(payload), Value::Object(details)) = (payload.as_object_mut(), details) {
+                payload.extend(details);
+            }
+            let request = self
+                .client
+                .post(url)
+                .header(CONTENT_TYPE, "application/json")
+                .body(payload.to_string());
+            match request.send().await {
+                Ok(response) if response.status().is_success() => {
+                    tracing::info!("Posted failure alert webhook");
+                }
+                Ok(response) => {
+                    tracing::error!(status = %response.status(), "Failure alert webhook was rejected");
+                }
+                Err(e) => tracing::error!(error = %e, "Failed to post failure alert webhook"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_db;
+
+    fn config(toml: &str) -> FailureAlertsConfig {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn counts(pushes: i64, failed: i64) -> WindowCounts {
+        WindowCounts {
+            pushes,
+            failed,
+            top_error: None,
+        }
+    }
+
+    #[test]
+    fn test_config() {
+        let alerts = FailureAlerts::new(&config("topic = \"ops\"")).unwrap();
+        assert_eq!(alerts.threshold(), 0.2);
+        assert_eq!(alerts.window(), Duration::from_secs(600));
+
+        assert!(FailureAlerts::new(&config("")).is_err());
+        assert!(FailureAlerts::new(&config("topic = \"ops\"\nthreshold = 1.5")).is_err());
+        assert!(FailureAlerts::new(&config("topic = \"ops\"\nwindow = \"soon\"")).is_err());
+        assert!(FailureAlerts::new(&config("topic = \"o p s\"")).is_err());
+        assert!(FailureAlerts::new(&config("webhook_url = \"hooks.example.com\"")).is_err());
+    }
+
+    #[test]
+    fn test_alerts_once_then_recovers() {
+        let alerts = FailureAlerts::new(&config("device_token = \"abc\"")).unwrap();
+        assert_eq!(alerts.evaluate(&counts(9, 9), false), None);
+        assert_eq!(alerts.evaluate(&counts(10, 2), false), None);
+        assert_eq!(alerts.evaluate(&counts(10, 3), false), Some(Alert::Firing));
+        assert_eq!(alerts.evaluate(&counts(40, 30), true), None);
+        assert_eq!(alerts.evaluate(&counts(0, 0), true), None);
+        assert_eq!(
+            alerts.evaluate(&counts(20, 1), true),
+            Some(Alert::Recovered)
+        );
+
+        let firing = WindowCounts {
+            top_error: Some("ExpiredProviderToken".to_string()),
+            ..counts(40, 14)
+        };
+        assert_eq!(
+            alerts.message(Alert::Firing, &firing),
+            (
+                "psh: 35% of pushes failing".to_string(),
+                "14 of 40 pushes failed in the last 10m, mostly ExpiredProviderToken.".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_alerts_when_token_keeps_being_rejected() {
+        let mut endpoints = vec![
+            EndpointStatus::new("sandbox"),
+            EndpointStatus::new("production"),
+        ];
+        assert_eq!(evaluate_token(&endpoints, false), None);
+
+        endpoints[1].consecutive_rejections = 3;
+        endpoints[1].last_rejection = Some(crate::apns_error::ApnsErrorCode::ExpiredProviderToken);
+        assert_eq!(
+            evaluate_token(&endpoints, false),
+            Some(Alert::TokenRejected)
+        );
+        assert_eq!(evaluate_token(&endpoints, true), None);
+        let (title, body) = token_message(Alert::TokenRejected, &endpoints);
+        assert_eq!(title, "psh: APNs is rejecting the provider token");
+        assert!(body.starts_with("APNs production rejected the provider token 3 times"));
+
+        endpoints[1].consecutive_rejections = 0;
+        assert_eq!(evaluate_token(&endpoints, true), Some(Alert::TokenAccepted));
+    }
+
+    #[test]
+    fn test_failure_counts() {
+        let _db = test_db();
+        let conn = Connection::get().unwrap();
+        conn.execute(
+            "INSERT INTO devices (device_token, installation_id, environment) VALUES ('a', 'i', 'sandbox')",
+            (),
+        )
+        .unwrap();
+        conn.execute(
+            r#"
+            INSERT INTO pushes (device_id, status, error_code, sent_at) VALUES
+                (1, 'sent', NULL, datetime('now')),
+                (1, 'failed', 'ExpiredProviderToken', datetime('now')),
+                (1, 'failed', 'ExpiredProviderToken', datetime('now')),
+                (1, 'failed', 'BadDeviceToken', datetime('now')),
+                (1, 'failed', 'BadDeviceToken', datetime('now', '-1 hour'))
+            "#,
+            (),
+        )
+        .unwrap();
+
+        let counts = Database::failure_counts(Duration::from_secs(600)).unwrap();
+        assert_eq!((counts.pushes, counts.failed), (4, 3));
+        assert_eq!(counts.top_error.as_deref(), Some("ExpiredProviderToken"));
+        assert_eq!(
+            Database::failure_counts(Duration::from_secs(1))
+                .unwrap()
+                .pushes,
+            4
+        );
+    }
+}
@@ -0,0 +1,290 @@
+use serde::Serialize;
+use std::fmt;
+
+/// Why a push to one device failed.
+///
+/// APNs rejections use Apple's `reason` names so scripts can match them
+/// against Apple's documentation; the remaining codes cover failures that
+/// never got an APNs response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ApnsErrorCode {
+    BadCollapseId,
+    BadDeviceToken,
+    BadExpirationDate,
+    BadMessageId,
+    BadPriority,
+    BadTopic,
+    DeviceTokenNotForTopic,
+    DuplicateHeaders,
+    IdleTimeout,
+    InvalidPushType,
+    MissingDeviceToken,
+    MissingTopic,
+    PayloadEmpty,
+    TopicDisallowed,
+    BadCertificate,
+    BadCertificateEnvironment,
+    ExpiredProviderToken,
+    Forbidden,
+    InvalidProviderToken,
+    MissingProviderToken,
+    BadPath,
+    MethodNotAllowed,
+    ExpiredToken,
+    Unregistered,
+    PayloadTooLarge,
+    TooManyProviderTokenUpdates,
+    TooManyRequests,
+    InternalServerError,
+    ServiceUnavailable,
+    Shutdown,
+    /// The request timed out before APNs answered.
+    Timeout,
+    /// The connection to APNs failed.
+    ConnectionError,
+    /// The payload or options were rejected before sending.
+    InvalidRequest,
+    /// The device row has an environment other than sandbox/production.
+    InvalidEnvironment,
+    /// No provider is configured for the target's platform.
+    ProviderUnavailable,
+    /// Requests to the endpoint kept failing, so this one wasn't sent.
+    CircuitOpen,
+    Unknown,
+}
+
+impl ApnsErrorCode {
+    /// Maps an APNs `reason` string (e.g. "BadDeviceToken") to a code.
+    pub fn from_reason(reason: &str) -> Self {
+        match reason {
+            "BadCollapseId" => Self::BadCollapseId,
+            "BadDeviceToken" => Self::BadDeviceToken,
+            "BadExpirationDate" => Self::BadExpirationDate,
+            "BadMessageId" => Self::BadMessageId,
+            "BadPriority" => Self::BadPriority,
+            "BadTopic" => Self::BadTopic,
+            "DeviceTokenNotForTopic" => Self::DeviceTokenNotForTopic,
+            "DuplicateHeaders" => Self::DuplicateHeaders,
+            "IdleTimeout" => Self::IdleTimeout,
+            "InvalidPushType" => Self::InvalidPushType,
+            "MissingDeviceToken" => Self::MissingDeviceToken,
+            "MissingTopic" => Self::MissingTopic,
+            "PayloadEmpty" => Self::PayloadEmpty,
+            "TopicDisallowed" => Self::TopicDisallowed,
+            "BadCertificate" => Self::BadCertificate,
+            "BadCertificateEnvironment" => Self::BadCertificateEnvironment,
+            "ExpiredProviderToken" => Self::ExpiredProviderToken,
+            "Forbidden" => Self::Forbidden,
+            "InvalidProviderToken" => Self::InvalidProviderToken,
+            "MissingProviderToken" => Self::MissingProviderToken,
+            "BadPath" => Self::BadPath,
+            "MethodNotAllowed" => Self::MethodNotAllowed,
+            "ExpiredToken" => Self::ExpiredToken,
+            "Unregistered" => Self::Unregistered,
+            "PayloadTooLarge" => Self::PayloadTooLarge,
+            "TooManyProviderTokenUpdates" => Self::TooManyProviderTokenUpdates,
+            "TooManyRequests" => Self::TooManyRequests,
+            "InternalServerError" => Self::InternalServerError,
+            "ServiceUnavailable" => Self::ServiceUnavailable,
+            "Shutdown" => Self::Shutdown,
+            _ => Self::Unknown,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::BadCollapseId => "BadCollapseId",
+            Self::BadDeviceToken => "BadDeviceToken",
+            Self::BadExpirationDate => "BadExpirationDate",
+            Self::BadMessageId => "BadMessageId",
+            Self::BadPriority => "BadPriority",
+            Self::BadTopic => "BadTopic",
+            Self::DeviceTokenNotForTopic => "DeviceTokenNotForTopic",
+            Self::DuplicateHeaders => "DuplicateHeaders",
+            Self::IdleTimeout => "IdleTimeout",
+            Self::InvalidPushType => "InvalidPushType",
+            Self::MissingDeviceToken => "MissingDeviceToken",
+            Self::MissingTopic => "MissingTopic",
+            Self::PayloadEmpty => "PayloadEmpty",
+            Self::TopicDisallowed => "TopicDisallowed",
+            Self::BadCertificate => "BadCertificate",
+            Self::BadCertificateEnvironment => "BadCertificateEnvironment",
+            Self::ExpiredProviderToken => "ExpiredProviderToken",
+            Self::Forbidden => "Forbidden",
+            Self::InvalidProviderToken => "InvalidProviderToken",
+            Self::MissingProviderToken => "MissingProviderToken",
+            Self::BadPath => "BadPath",
+            Self::MethodNotAllowed => "MethodNotAllowed",
+            Self::ExpiredToken => "ExpiredToken",
+            Self::Unregistered => "Unregistered",
+            Self::PayloadTooLarge => "PayloadTooLarge",
+            Self::TooManyProviderTokenUpdates => "TooManyProviderTokenUpdates",
+            Self::TooManyRequests => "TooManyRequests",
+            Self::InternalServerError => "InternalServerError",
+            Self::ServiceUnavailable => "ServiceUnavailable",
+            Self::Shutdown => "Shutdown",
+            Self::Timeout => "Timeout",
+            Self::ConnectionError => "ConnectionError",
+            Self::InvalidRequest => "InvalidRequest",
+            Self::InvalidEnvironment => "InvalidEnvironment",
+            Self::ProviderUnavailable => "ProviderUnavailable",
+            Self::CircuitOpen => "CircuitOpen",
+            Self::Unknown => "Unknown",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::BadCollapseId => "The collapse identifier exceeds the maximum allowed size",
+            Self::BadDeviceToken => {
+                "The device token is invalid or belongs to the other APNs environment"
+            }
+            Self::BadExpirationDate => "The apns-expiration value is invalid",
+            Self::BadMessageId => "The apns-id value is invalid",
+            Self::BadPriority => "The apns-priority value is invalid",
+            Self::BadTopic => "The apns-topic value is invalid",
+            Self::DeviceTokenNotForTopic => "The device token doesn't match the specified topic",
+            Self::DuplicateHeaders => "One or more headers were repeated",
+            Self::IdleTimeout => "APNs closed an idle connection",
+            Self::InvalidPushType => "The apns-push-type value is invalid",
+            Self::MissingDeviceToken => "The device token wasn't specified",
+            Self::MissingTopic => "The apns-topic header is required but missing",
+            Self::PayloadEmpty => "The notification payload is empty",
+            Self::TopicDisallowed => "Pushing to this topic is not allowed",
+            Self::BadCertificate => "The certificate is invalid",
+            Self::BadCertificateEnvironment => "The certificate is for the wrong environment",
+            Self::ExpiredProviderToken => "The provider token is stale and must be regenerated",
+            Self::Forbidden => "The action is not allowed for these credentials",
+            Self::InvalidProviderToken => {
+                "The provider token is not valid or its signature can't be verified"
+            }
+            Self::MissingProviderToken => "No provider token was sent",
+            Self::BadPath => "The request path is invalid",
+            Self::MethodNotAllowed => "The request method is not supported",
+            Self::ExpiredToken => "The device token has expired",
+            Self::Unregistered => "The device token is no longer active for the topic",
+            Self::PayloadTooLarge => "The notification payload exceeds 4KB",
+            Self::TooManyProviderTokenUpdates => "The provider token is being updated too often",
+            Self::TooManyRequests => "Too many requests were sent to the same device token",
+            Self::InternalServerError => "APNs had an internal error",
+            Self::ServiceUnavailable => "APNs is unavailable",
+            Self::Shutdown => "The APNs server is shutting down",
+            Self::Timeout => "Timed out waiting for APNs",
+            Self::ConnectionError => "Could not connect to APNs",
+            Self::InvalidRequest => "The notification could not be built",
+            Self::InvalidEnvironment => "Invalid environment in database",
+            Self::ProviderUnavailable => "No provider is configured for this platform",
+            Self::CircuitOpen => "APNs circuit open: requests to APNs keep failing",
+            Self::Unknown => "Unknown APNs error",
+        }
+    }
+}
+
+impl fmt::Display for ApnsErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A failed push with a classified reason and a human-readable message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SendError {
+    pub code: ApnsErrorCode,
+    pub message: String,
+}
+
+impl SendError {
+    pub fn new(code: ApnsErrorCode) -> Self {
+        Self {
+            code,
+            message: code.description().to_string(),
+        }
+    }
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for SendError {}
+
+impl From<a2::Error> for SendError {
+    fn from(error: a2::Error) -> Self {
+        match error {
+            a2::Error::ResponseError(response) => {
+                let code = response
+                    .error
+                    .as_ref()
+                    .map(|body| ApnsErrorCode::from_reason(&format!("{:?}", body.reason)))
+                    .unwrap_or(ApnsErrorCode::Unknown);
+                SendError::new(code)
+            }
+            a2::Error::RequestTimeout(_) => SendError::new(ApnsErrorCode::Timeout),
+            a2::Error::ConnectionError(e) => SendError {
+                code: ApnsErrorCode::ConnectionError,
+                message: format!("Could not connect to APNs: {e}"),
+            },
+            a2::Error::SerializeError(e) => SendError {
+                code: ApnsErrorCode::InvalidRequest,
+                message: format!("Could not serialize payload: {e}"),
+            },
+            a2::Error::InvalidOptions(e) => SendError {
+                code: ApnsErrorCode::InvalidRequest,
+                message: format!("Invalid notification options: {e}"),
+            },
+            other => SendError {
+                code: ApnsErrorCode::Unknown,
+                message: other.to_string(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reason_round_trip() {
+        for reason in [
+            "BadDeviceToken",
+            "TooManyRequests",
+            "PayloadTooLarge",
+            "Unregistered",
+        ] {
+            assert_eq!(ApnsErrorCode::from_reason(reason).as_str(), reason);
+        }
+        assert_eq!(
+            ApnsErrorCode::from_reason("SomethingNew"),
+            ApnsErrorCode::Unknown
+        );
+    }
+
+    #[test]
+    fn test_serializes_as_reason_name() {
+        let json = serde_json::to_string(&ApnsErrorCode::ExpiredProviderToken).unwrap();
+        assert_eq!(json, "\"ExpiredProviderToken\"");
+    }
+
+    #[test]
+    fn test_response_error_maps_to_reason() {
+        let error = a2::Error::ResponseError(a2::Response {
+            error: Some(a2::ErrorBody {
+                reason: a2::ErrorReason::BadDeviceToken,
+                timestamp: None,
+            }),
+            apns_id: None,
+            code: 400,
+        });
+        let send_error = SendError::from(error);
+        assert_eq!(send_error.code, ApnsErrorCode::BadDeviceToken);
+    }
+
+    #[test]
+    fn test_timeout_maps_to_timeout() {
+        let send_error = SendError::from(a2::Error::RequestTimeout(20));
+        assert_eq!(send_error.code, ApnsErrorCode::Timeout);
+    }
+}
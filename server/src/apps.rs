@@ -0,0 +1,583 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key,
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use seekwel::{
+    connection::Connection,
+    error::Error as SeekwelError,
+    rusqlite::{self, params},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+use crate::{
+    audit::{self, AuditContext},
+    signing, AppState, Database, ErrorResponse, MAX_EXPIRATION_SECONDS,
+};
+
+/// Custom data key that carries the sealed `data` map when encryption is on.
+pub const ENCRYPTED_DATA_KEY: &str = "psh_encrypted";
+
+const COLUMNS: &str = "bundle_id, encryption_key, signing_key, signing_key_id, \
+    previous_signing_key, created_at, updated_at, default_ttl_seconds";
+
+/// Per-app settings, keyed by bundle id (the APNs topic).
+#[derive(Debug, Clone)]
+pub struct App {
+    pub bundle_id: String,
+    pub encryption_key: Option<String>,
+    pub signing_key: Option<String>,
+    /// Counts up with each new signing key; 0 until signing is first on.
+    pub signing_key_id: i64,
+    /// The key before the current one, still signed with until retired.
+    pub previous_signing_key: Option<String>,
+    pub created_at: String,
+    pub updated_at: String,
+    /// How long sends without an expiration stay deliverable; unset leaves
+    /// it to APNs.
+    pub default_ttl_seconds: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AppResponse {
+    bundle_id: String,
+    encryption_enabled: bool,
+    /// Only returned when the server generated the key for this request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encryption_key: Option<String>,
+    signing_enabled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signing_key_id: Option<i64>,
+    /// Set while the previous key still signs alongside the current one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    previous_signing_key_id: Option<i64>,
+    /// Only returned when the server generated the key for this request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signing_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    default_ttl_seconds: Option<u64>,
+    created_at: String,
+    updated_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AppsResponse {
+    apps: Vec<AppResponse>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateAppRequest {
+    /// Base64 AES-256 key; `null` turns encryption off.
+    #[serde(default, with = "double_option")]
+    encryption_key: Option<Option<String>>,
+    /// Have the server create a key and return it once.
+    #[serde(default)]
+    generate_encryption_key: bool,
+    /// Base64 HMAC key of at least 32 bytes that becomes the current
+    /// signing key; `null` turns signing off.
+    #[serde(default, with = "double_option")]
+    signing_key: Option<Option<String>>,
+    /// Have the server create the next signing key and return it once.
+    #[serde(default)]
+    rotate_signing_key: bool,
+    /// Stop signing with the key before the current one.
+    #[serde(default)]
+    retire_previous_signing_key: bool,
+    /// Seconds a send without an expiration stays deliverable; `null`
+    /// leaves it to APNs.
+    #[serde(default, with = "double_option")]
+    default_ttl_seconds: Option<Option<u64>>,
+}
+
+/// Distinguishes an absent field from an explicit `null`.
+mod double_option {
+    use serde::{Deserialize, Deserializer};
+
+    pub fn deserialize<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: Deserialize<'de>,
+    {
+        Option::<T>::deserialize(deserializer).map(Some)
+    }
+}
+
+impl App {
+    fn response(
+        self,
+        generated_key: Option<String>,
+        generated_signing_key: Option<String>,
+    ) -> AppResponse {
+        let signing_enabled = self.signing_key.is_some();
+        AppResponse {
+            bundle_id: self.bundle_id,
+            encryption_enabled: self.encryption_key.is_some(),
+            encryption_key: generated_key,
+            signing_enabled,
+            signing_key_id: signing_enabled.then_some(self.signing_key_id),
+            previous_signing_key_id: self.previous_signing_key.map(|_| self.signing_key_id - 1),
+            signing_key: generated_signing_key,
+            default_ttl_seconds: self.default_ttl_seconds,
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+        }
+    }
+}
+
+impl Database {
+    pub(crate) fn create_apps_table(conn: &Connection) -> Result<(), SeekwelError> {
+        conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS apps (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                bundle_id TEXT NOT NULL UNIQUE,
+                encryption_key TEXT,
+                signing_key TEXT,
+                signing_key_id INTEGER NOT NULL DEFAULT 0,
+                previous_signing_key TEXT,
+                default_ttl_seconds INTEGER,
+                created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            )
+            "#,
+            (),
+        )?;
+        if !Self::column_exists(conn, "apps", "signing_key")? {
+            conn.execute("ALTER TABLE apps ADD COLUMN signing_key TEXT", ())?;
+            conn.execute(
+                "ALTER TABLE apps ADD COLUMN signing_key_id INTEGER NOT NULL DEFAULT 0",
+                (),
+            )?;
+            conn.execute("ALTER TABLE apps ADD COLUMN previous_signing_key TEXT", ())?;
+        }
+        if !Self::column_exists(conn, "apps", "default_ttl_seconds")? {
+            conn.execute(
+                "ALTER TABLE apps ADD COLUMN default_ttl_seconds INTEGER",
+                (),
+            )?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn apps() -> Result<Vec<App>, SeekwelError> {
+        Connection::get()?.query_all(
+            &format!("SELECT {COLUMNS} FROM apps ORDER BY bundle_id"),
+            (),
+            app_from_row,
+        )
+    }
+
+    pub(crate) fn app(bundle_id: &str) -> Result<Option<App>, SeekwelError> {
+        Connection::get()?.query_optional(
+            &format!("SELECT {COLUMNS} FROM apps WHERE bundle_id = ?1"),
+            params![bundle_id],
+            app_from_row,
+        )
+    }
+
+    fn set_app_encryption_key(
+        bundle_id: &str,
+        encryption_key: Option<&str>,
+    ) -> Result<(), SeekwelError> {
+        Connection::get()?.execute(
+            r#"
+            INSERT INTO apps (bundle_id, encryption_key, updated_at)
+            VALUES (?1, ?2, CURRENT_TIMESTAMP)
+            ON CONFLICT(bundle_id) DO UPDATE SET
+                encryption_key = excluded.encryption_key,
+                updated_at = CURRENT_TIMESTAMP
+            "#,
+            params![bundle_id, encryption_key],
+        )?;
+        Ok(())
+    }
+
+    fn set_app_default_ttl(
+        bundle_id: &str,
+        default_ttl_seconds: Option<u64>,
+    ) -> Result<(), SeekwelError> {
+        Connection::get()?.execute(
+            r#"
+            INSERT INTO apps (bundle_id, default_ttl_seconds, updated_at)
+            VALUES (?1, ?2, CURRENT_TIMESTAMP)
+            ON CONFLICT(bundle_id) DO UPDATE SET
+                default_ttl_seconds = excluded.default_ttl_seconds,
+                updated_at = CURRENT_TIMESTAMP
+            "#,
+            params![bundle_id, default_ttl_seconds.map(|ttl| ttl as i64)],
+        )?;
+        Ok(())
+    }
+
+    fn set_app_signing_keys(
+        bundle_id: &str,
+        signing_key: Option<&str>,
+        signing_key_id: i64,
+        previous_signing_key: Option<&str>,
+    ) -> Result<(), SeekwelError> {
+        Connection::get()?.execute(
+            r#"
+            INSERT INTO apps (bundle_id, signing_key, signing_key_id, previous_signing_key, updated_at)
+            VALUES (?1, ?2, ?3, ?4, CURRENT_TIMESTAMP)
+            ON CONFLICT(bundle_id) DO UPDATE SET
+                signing_key = excluded.signing_key,
+                signing_key_id = excluded.signing_key_id,
+                previous_signing_key = excluded.previous_signing_key,
+                updated_at = CURRENT_TIMESTAMP
+            "#,
+            params![bundle_id, signing_key, signing_key_id, previous_signing_key],
+        )?;
+        Ok(())
+    }
+}
+
+fn app_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<App> {
+    Ok(App {
+        bundle_id: row.get(0)?,
+        encryption_key: row.get(1)?,
+        signing_key: row.get(2)?,
+        signing_key_id: row.get(3)?,
+        previous_signing_key: row.get(4)?,
+        created_at: row.get(5)?,
+        updated_at: row.get(6)?,
+        default_ttl_seconds: row.get::<_, Option<i64>>(7)?.map(|ttl| ttl.max(0) as u64),
+    })
+}
+
+fn decode_key(encoded: &str) -> Result<Key<Aes256Gcm>, String> {
+    let bytes = BASE64
+        .decode(encoded.trim())
+        .map_err(|e| format!("encryption_key is not valid base64: {e}"))?;
+    if bytes.len() != 32 {
+        return Err(format!(
+            "encryption_key must be 32 bytes (AES-256), got {}",
+            bytes.len()
+        ));
+    }
+    Ok(*Key::<Aes256Gcm>::from_slice(&bytes))
+}
+
+fn generate_key() -> String {
+    BASE64.encode(Aes256Gcm::generate_key(OsRng))
+}
+
+/// Seals the JSON-encoded `data` map with AES-256-GCM.
+///
+/// The result replaces the map as `{"psh_encrypted": {"v": 1, "sealed": ...}}`,
+/// where `sealed` is base64 of nonce || ciphertext || tag, the layout
+/// CryptoKit's `AES.GCM.SealedBox(combined:)` reads.
+pub fn encrypt_data(
+    encoded_key: &str,
+    data: &HashMap<String, Value>,
+) -> Result<HashMap<String, Value>, String> {
+    let cipher = Aes256Gcm::new(&decode_key(encoded_key)?);
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let plaintext = serde_json::to_vec(data).map_err(|e| e.to_string())?;
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext.as_slice())
+        .map_err(|e| format!("Encryption failed: {e}"))?;
+
+    let mut sealed = nonce.to_vec();
+    sealed.extend_from_slice(&ciphertext);
+    Ok(HashMap::from([(
+        ENCRYPTED_DATA_KEY.to_string(),
+        json!({ "v": 1, "sealed": BASE64.encode(sealed) }),
+    )]))
+}
+
+fn database_error(e: SeekwelError) -> (StatusCode, Json<ErrorResponse>) {
+    tracing::error!(error = %e, "Database error handling app settings");
+    ErrorResponse::with_status(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Database error: {e}"),
+    )
+}
+
+pub async fn list_apps(
+    State(_state): State<AppState>,
+) -> Result<Json<AppsResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let apps = Database::apps().map_err(database_error)?;
+    Ok(Json(AppsResponse {
+        apps: apps
+            .into_iter()
+            .map(|app| app.response(None, None))
+            .collect(),
+    }))
+}
+
+pub async fn get_app(
+    State(_state): State<AppState>,
+    Path(bundle_id): Path<String>,
+) -> Result<Json<AppResponse>, (StatusCode, Json<ErrorResponse>)> {
+    match Database::app(&bundle_id).map_err(database_error)? {
+        Some(app) => Ok(Json(app.response(None, None))),
+        None => Err(ErrorResponse::with_status(
+            StatusCode::NOT_FOUND,
+            format!("App not found: {bundle_id}"),
+        )),
+    }
+}
+
+pub async fn update_app(
+    State(_state): State<AppState>,
+    audit: AuditContext,
+    Path(bundle_id): Path<String>,
+    Json(req): Json<UpdateAppRequest>,
+) -> Result<Json<AppResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let current = Database::app(&bundle_id).map_err(database_error)?;
+    let (encryption_key, generated) = match (req.encryption_key, req.generate_encryption_key) {
+        (Some(_), true) => {
+            return Err(ErrorResponse::with_status(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Pass either encryption_key or generate_encryption_key, not both",
+            ))
+        }
+        (None, true) => {
+            let key = generate_key();
+            (Some(key.clone()), Some(key))
+        }
+        (Some(Some(key)), false) => {
+            decode_key(&key)
+                .map_err(|e| ErrorResponse::with_status(StatusCode::UNPROCESSABLE_ENTITY, e))?;
+            (Some(key.trim().to_string()), None)
+        }
+        (Some(None), false) => (None, None),
+        (None, false) => (
+            current.as_ref().and_then(|app| app.encryption_key.clone()),
+            None,
+        ),
+    };
+    let signing = signing_update(
+        current.as_ref(),
+        req.signing_key,
+        req.rotate_signing_key,
+        req.retire_previous_signing_key,
+    )?;
+    if let Some(Some(ttl)) = req.default_ttl_seconds {
+        if ttl > MAX_EXPIRATION_SECONDS {
+            return Err(ErrorResponse::with_status(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("default_ttl_seconds must be at most {MAX_EXPIRATION_SECONDS} (30 days), got {ttl}"),
+            ));
+        }
+    }
+
+    tracing::info!(
+        bundle_id = %bundle_id,
+        encryption_enabled = encryption_key.is_some(),
+        "Updating app settings"
+    );
+    Database::set_app_encryption_key(&bundle_id, encryption_key.as_deref())
+        .map_err(database_error)?;
+    let mut summary = format!(
+        "bundle_id={bundle_id} encryption={}",
+        if encryption_key.is_some() {
+            "on"
+        } else {
+            "off"
+        }
+    );
+    if let Some(signing) = &signing {
+        tracing::info!(
+            bundle_id = %bundle_id,
+            signing_enabled = signing.key.is_some(),
+            signing_key_id = signing.key_id,
+            "Updating app signing keys"
+        );
+        Database::set_app_signing_keys(
+            &bundle_id,
+            signing.key.as_deref(),
+            signing.key_id,
+            signing.previous.as_deref(),
+        )
+        .map_err(database_error)?;
+        match &signing.key {
+            Some(_) => summary.push_str(&format!(" signing=on key_id={}", signing.key_id)),
+            None => summary.push_str(" signing=off"),
+        }
+    }
+    if let Some(ttl) = req.default_ttl_seconds {
+        tracing::info!(bundle_id = %bundle_id, default_ttl_seconds = ?ttl, "Updating app default TTL");
+        Database::set_app_default_ttl(&bundle_id, ttl).map_err(database_error)?;
+        match ttl {
+            Some(ttl) => summary.push_str(&format!(" default_ttl={ttl}")),
+            None => summary.push_str(" default_ttl=off"),
+        }
+    }
+    audit::record(&audit, "app.update", summary);
+
+    let app = Database::app(&bundle_id)
+        .map_err(database_error)?
+        .ok_or_else(|| {
+            ErrorResponse::with_status(StatusCode::NOT_FOUND, format!("App not found: {bundle_id}"))
+        })?;
+    Ok(Json(app.response(
+        generated,
+        signing.and_then(|signing| signing.generated),
+    )))
+}
+
+/// The signing keys an update leaves an app with.
+struct SigningUpdate {
+    key: Option<String>,
+    key_id: i64,
+    previous: Option<String>,
+    /// A key the server created, to return once.
+    generated: Option<String>,
+}
+
+/// Applies the signing fields of an update to the app's current keys, or
+/// `None` when the update doesn't touch signing. A new key gets the next id
+/// and the current one signs alongside it until retired.
+fn signing_update(
+    current: Option<&App>,
+    signing_key: Option<Option<String>>,
+    rotate: bool,
+    retire_previous: bool,
+) -> Result<Option<SigningUpdate>, (StatusCode, Json<ErrorResponse>)> {
+    let current_key = current.and_then(|app| app.signing_key.clone());
+    let current_id = current.map_or(0, |app| app.signing_key_id);
+    let mut update = match (signing_key, rotate) {
+        (Some(_), true) => {
+            return Err(ErrorResponse::with_status(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Pass either signing_key or rotate_signing_key, not both",
+            ))
+        }
+        (Some(None), false) => SigningUpdate {
+            key: None,
+            key_id: current_id,
+            previous: None,
+            generated: None,
+        },
+        (Some(Some(key)), false) => {
+            signing::decode_key(&key)
+                .map_err(|e| ErrorResponse::with_status(StatusCode::UNPROCESSABLE_ENTITY, e))?;
+            SigningUpdate {
+                key: Some(key.trim().to_string()),
+                key_id: current_id + 1,
+                previous: current_key,
+                generated: None,
+            }
+        }
+        (None, true) => {
+            let key = signing::generate_key();
+            SigningUpdate {
+                key: Some(key.clone()),
+                key_id: current_id + 1,
+                previous: current_key,
+                generated: Some(key),
+            }
+        }
+        (None, false) if retire_previous => SigningUpdate {
+            key: current_key,
+            key_id: current_id,
+            previous: None,
+            generated: None,
+        },
+        (None, false) => return Ok(None),
+    };
+    if retire_previous {
+        update.previous = None;
+    }
+    Ok(Some(update))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_db;
+
+    fn decrypt(encoded_key: &str, sealed: &HashMap<String, Value>) -> HashMap<String, Value> {
+        let sealed = BASE64
+            .decode(sealed[ENCRYPTED_DATA_KEY]["sealed"].as_str().unwrap())
+            .unwrap();
+        let (nonce, ciphertext) = sealed.split_at(12);
+        let cipher = Aes256Gcm::new(&decode_key(encoded_key).unwrap());
+        let plaintext = cipher.decrypt(nonce.into(), ciphertext).unwrap();
+        serde_json::from_slice(&plaintext).unwrap()
+    }
+
+    #[test]
+    fn test_encrypt_data_round_trip() {
+        let key = generate_key();
+        let data = HashMap::from([("otp".to_string(), json!("123456"))]);
+
+        let sealed = encrypt_data(&key, &data).unwrap();
+        assert_eq!(sealed.len(), 1);
+        assert_eq!(sealed[ENCRYPTED_DATA_KEY]["v"], 1);
+        assert!(!sealed[ENCRYPTED_DATA_KEY].to_string().contains("123456"));
+        assert_eq!(decrypt(&key, &sealed), data);
+    }
+
+    #[test]
+    fn test_decode_key_rejects_wrong_length() {
+        assert!(decode_key(&BASE64.encode([0u8; 16])).is_err());
+        assert!(decode_key("not base64!").is_err());
+        assert!(decode_key(&BASE64.encode([0u8; 32])).is_ok());
+    }
+
+    #[test]
+    fn test_update_request_distinguishes_null() {
+        let req: UpdateAppRequest = serde_json::from_str(r#"{"encryption_key": null}"#).unwrap();
+        assert_eq!(req.encryption_key, Some(None));
+        let req: UpdateAppRequest = serde_json::from_str("{}").unwrap();
+        assert_eq!(req.encryption_key, None);
+    }
+
+    #[test]
+    fn test_signing_update_rotates_keys() {
+        let _db = test_db();
+        let first = signing_update(None, None, true, false).unwrap().unwrap();
+        assert_eq!(first.key_id, 1);
+        assert_eq!(first.key, first.generated);
+        assert!(first.previous.is_none());
+        Database::set_app_signing_keys("com.example.app", first.key.as_deref(), 1, None).unwrap();
+        let app = Database::app("com.example.app").unwrap().unwrap();
+
+        let second = signing_update(Some(&app), None, true, false)
+            .unwrap()
+            .unwrap();
+        assert_eq!(second.key_id, 2);
+        assert_eq!(second.previous, first.key);
+        assert!(signing_update(Some(&app), None, false, false)
+            .unwrap()
+            .is_none());
+        let retired = signing_update(Some(&app), None, false, true)
+            .unwrap()
+            .unwrap();
+        assert_eq!((retired.key, retired.key_id), (first.key, 1));
+        let off = signing_update(Some(&app), Some(None), false, false)
+            .unwrap()
+            .unwrap();
+        assert!(off.key.is_none() && off.previous.is_none());
+        assert!(
+            signing_update(Some(&app), Some(Some("c2hvcnQ=".to_string())), false, false).is_err()
+        );
+    }
+
+    #[test]
+    fn test_app_settings_round_trip() {
+        let _db = test_db();
+        let key = generate_key();
+        Database::set_app_encryption_key("com.example.app", Some(&key)).unwrap();
+        let app = Database::app("com.example.app").unwrap().unwrap();
+        assert_eq!(app.encryption_key.as_deref(), Some(key.as_str()));
+
+        Database::set_app_encryption_key("com.example.app", None).unwrap();
+        assert_eq!(Database::apps().unwrap().len(), 1);
+        assert!(Database::app("com.example.app")
+            .unwrap()
+            .unwrap()
+            .encryption_key
+            .is_none());
+    }
+}
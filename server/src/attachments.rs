@@ -0,0 +1,533 @@
+use aes_gcm::aead::{rand_core::RngCore, OsRng};
+use async_trait::async_trait;
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{
+        header::{CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_TYPE, HOST, X_CONTENT_TYPE_OPTIONS},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use seekwel::{connection::Connection, error::Error as SeekwelError, rusqlite::params};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::{env, io, path::PathBuf, sync::Arc, time::Duration};
+
+use crate::{
+    audit::{self, AuditContext},
+    duration,
+    s3::{self, S3Store},
+    unix_now, AppState, Database, ErrorResponse, SendRequest,
+};
+
+/// The custom data key a send's attachment is delivered under, for the
+/// app's notification service extension to download.
+pub const ATTACHMENT_DATA_KEY: &str = "psh_attachment";
+
+/// Uploads larger than this are rejected.
+pub(crate) const MAX_ATTACHMENT_BYTES: usize = 10 * 1024 * 1024;
+
+/// How long an attachment's URL works unless `PSH_ATTACHMENT_URL_TTL` says
+/// otherwise.
+const DEFAULT_URL_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Where uploaded files are kept.
+#[async_trait]
+pub trait AttachmentStore: Send + Sync {
+    async fn put(&self, id: &str, content_type: &str, bytes: Bytes) -> io::Result<()>;
+    /// The file stored under `id`, or `None` if there isn't one.
+    async fn get(&self, id: &str) -> io::Result<Option<Bytes>>;
+    /// A link devices can download `id` from directly for `ttl` from `now`.
+    /// Without one they download it from the server by signed link.
+    fn presigned_url(&self, _id: &str, _now: u64, _ttl: Duration) -> Option<String> {
+        None
+    }
+}
+
+/// Keeps attachments as files in a directory, created on first upload.
+pub struct DiskStore {
+    dir: PathBuf,
+}
+
+impl DiskStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+#[async_trait]
+impl AttachmentStore for DiskStore {
+    async fn put(&self, id: &str, _content_type: &str, bytes: Bytes) -> io::Result<()> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        tokio::fs::write(self.dir.join(id), bytes).await
+    }
+
+    async fn get(&self, id: &str) -> io::Result<Option<Bytes>> {
+        match tokio::fs::read(self.dir.join(id)).await {
+            Ok(bytes) => Ok(Some(bytes.into())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Uploaded files and how links to them are signed.
+pub struct Attachments {
+    store: Arc<dyn AttachmentStore>,
+    secret: Vec<u8>,
+    /// The server's address as devices reach it; the request's `Host` when
+    /// unset.
+    public_url: Option<String>,
+    url_ttl: Duration,
+}
+
+impl Attachments {
+    pub fn new(store: Arc<dyn AttachmentStore>, secret: impl Into<Vec<u8>>) -> Self {
+        Self {
+            store,
+            secret: secret.into(),
+            public_url: None,
+            url_ttl: DEFAULT_URL_TTL,
+        }
+    }
+
+    /// Builds links on `url` instead of the address uploads arrive at.
+    pub fn with_public_url(mut self, url: impl Into<String>) -> Self {
+        self.public_url = Some(url.into().trim_end_matches('/').to_string());
+        self
+    }
+
+    /// Reads the S3 settings (see `S3Store::from_env`), else
+    /// `PSH_ATTACHMENTS_DIR` (default `attachments`), then
+    /// `PSH_ATTACHMENT_SECRET`, `PSH_PUBLIC_URL` and
+    /// `PSH_ATTACHMENT_URL_TTL` (default `7d`, at most that with S3).
+    /// Without a secret one is made up, and links served by the server stop
+    /// working when it restarts.
+    pub fn from_env() -> Result<Self, String> {
+        let mut max_ttl = None;
+        let store: Arc<dyn AttachmentStore> = match S3Store::from_env()? {
+            Some(s3) => {
+                tracing::info!("Storing attachments in S3");
+                max_ttl = Some(s3::MAX_PRESIGN_TTL);
+                Arc::new(s3)
+            }
+            None => {
+                let dir = env::var("PSH_ATTACHMENTS_DIR")
+                    .ok()
+                    .filter(|dir| !dir.trim().is_empty())
+                    .unwrap_or_else(|| "attachments".to_string());
+                tracing::info!(dir = %dir, "Storing attachments on disk");
+                Arc::new(DiskStore::new(dir))
+            }
+        };
+        let secret = match env::var("PSH_ATTACHMENT_SECRET") {
+            Ok(secret) if !secret.is_empty() => secret.into_bytes(),
+            _ => {
+                tracing::warn!(
+                    "PSH_ATTACHMENT_SECRET is not set, attachment links will stop working on restart"
+                );
+                let mut secret = vec![0u8; 32];
+                OsRng.fill_bytes(&mut secret);
+                secret
+            }
+        };
+        let mut attachments = Self::new(store, secret);
+        if let Ok(url) = env::var("PSH_PUBLIC_URL") {
+            if !url.trim().is_empty() {
+                attachments = attachments.with_public_url(url.trim());
+            }
+        }
+        if let Ok(value) = env::var("PSH_ATTACHMENT_URL_TTL") {
+            attachments.url_ttl = match duration::parse_duration(&value) {
+                Some(ttl) if max_ttl.is_some_and(|max| ttl > max) => {
+                    return Err(format!(
+                        "PSH_ATTACHMENT_URL_TTL '{value}' is longer than S3 allows, at most 7d"
+                    ))
+                }
+                Some(ttl) if !ttl.is_zero() => ttl,
+                _ => {
+                    return Err(format!(
+                        "Invalid PSH_ATTACHMENT_URL_TTL '{value}', expected a duration such as 7d"
+                    ))
+                }
+            };
+        }
+        Ok(attachments)
+    }
+
+    fn signature(&self, id: &str, expires: u64) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC takes keys of any length");
+        mac.update(format!("{id}:{expires}").as_bytes());
+        mac
+    }
+
+    fn signed_url(&self, base: &str, id: &str, expires: u64) -> String {
+        let sig = URL_SAFE_NO_PAD.encode(self.signature(id, expires).finalize().into_bytes());
+        format!("{base}/attachments/{id}?expires={expires}&sig={sig}")
+    }
+
+    fn verify(&self, id: &str, expires: u64, sig: &str) -> bool {
+        URL_SAFE_NO_PAD
+            .decode(sig)
+            .is_ok_and(|sig| self.signature(id, expires).verify_slice(&sig).is_ok())
+    }
+}
+
+/// A stored attachment and the signed link to it.
+#[derive(Debug, Serialize)]
+pub(crate) struct Attachment {
+    id: String,
+    url: String,
+    content_type: String,
+    size_bytes: u64,
+    /// Unix time the link stops working.
+    expires_at: u64,
+}
+
+impl Database {
+    pub(crate) fn create_attachments_table(conn: &Connection) -> Result<(), SeekwelError> {
+        conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS attachments (
+                id TEXT PRIMARY KEY,
+                content_type TEXT NOT NULL,
+                size_bytes INTEGER NOT NULL,
+                url TEXT NOT NULL,
+                expires_at INTEGER NOT NULL,
+                created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            )
+            "#,
+            (),
+        )?;
+        Ok(())
+    }
+
+    fn insert_attachment(attachment: &Attachment) -> Result<(), SeekwelError> {
+        Connection::get()?.execute(
+            r#"
+            INSERT INTO attachments (id, content_type, size_bytes, url, expires_at)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            "#,
+            params![
+                attachment.id,
+                attachment.content_type,
+                attachment.size_bytes as i64,
+                attachment.url,
+                attachment.expires_at as i64
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn attachment(id: &str) -> Result<Option<Attachment>, SeekwelError> {
+        Connection::get()?.query_optional(
+            "SELECT id, url, content_type, size_bytes, expires_at FROM attachments WHERE id = ?1",
+            params![id],
+            |row| {
+                Ok(Attachment {
+                    id: row.get(0)?,
+                    url: row.get(1)?,
+                    content_type: row.get(2)?,
+                    size_bytes: row.get::<_, i64>(3)? as u64,
+                    expires_at: row.get::<_, i64>(4)? as u64,
+                })
+            },
+        )
+    }
+}
+
+/// The file extension an attachment of `content_type` is stored with, which
+/// iOS uses to tell the type of a notification attachment.
+fn extension(content_type: &str) -> &'static str {
+    match content_type {
+        "image/png" => "png",
+        "image/jpeg" => "jpg",
+        "image/gif" => "gif",
+        "image/heic" => "heic",
+        "image/webp" => "webp",
+        "audio/mpeg" => "mp3",
+        "audio/mp4" | "audio/x-m4a" => "m4a",
+        "audio/wav" | "audio/x-wav" => "wav",
+        "video/mp4" => "mp4",
+        "video/quicktime" => "mov",
+        "text/plain" => "txt",
+        "application/json" => "json",
+        "application/pdf" => "pdf",
+        _ => "bin",
+    }
+}
+
+/// `content_type` if it's one `extension` knows, and otherwise a download,
+/// so an upload of HTML or SVG never runs as script on the server's origin.
+fn served_content_type(content_type: &str) -> &str {
+    match extension(content_type) {
+        "bin" => "application/octet-stream",
+        _ => content_type,
+    }
+}
+
+/// Whether browsers may show an attachment of `content_type` inline; other
+/// types are sent as downloads.
+fn is_media(content_type: &str) -> bool {
+    ["image/", "audio/", "video/"]
+        .iter()
+        .any(|prefix| content_type.starts_with(prefix))
+}
+
+fn new_attachment_id(content_type: &str) -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    format!("{hex}.{}", extension(content_type))
+}
+
+/// Ids are only ever what `new_attachment_id` makes, so nothing else
+/// reaches the store.
+fn is_attachment_id(id: &str) -> bool {
+    id.split_once('.').is_some_and(|(hex, ext)| {
+        hex.len() == 32
+            && hex.bytes().all(|b| b.is_ascii_hexdigit())
+            && !ext.is_empty()
+            && ext.bytes().all(|b| b.is_ascii_alphanumeric())
+    })
+}
+
+/// The server's address as the uploader reached it.
+fn base_url(attachments: &Attachments, headers: &HeaderMap) -> Option<String> {
+    if let Some(url) = &attachments.public_url {
+        return Some(url.clone());
+    }
+    let host = headers.get(HOST)?.to_str().ok()?;
+    let scheme = headers
+        .get("x-forwarded-proto")
+        .and_then(|proto| proto.to_str().ok())
+        .unwrap_or("http");
+    Some(format!("{scheme}://{host}"))
+}
+
+fn configured(state: &AppState) -> Result<&Attachments, (StatusCode, Json<ErrorResponse>)> {
+    state.attachments.as_deref().ok_or_else(|| {
+        ErrorResponse::with_status(StatusCode::NOT_FOUND, "Attachments are not configured")
+    })
+}
+
+fn storage_error(e: impl std::fmt::Display) -> (StatusCode, Json<ErrorResponse>) {
+    tracing::error!(error = %e, "Attachment storage error");
+    ErrorResponse::with_status(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Attachment storage error: {e}"),
+    )
+}
+
+fn database_error(e: SeekwelError) -> (StatusCode, Json<ErrorResponse>) {
+    tracing::error!(error = %e, "Database error with attachment");
+    ErrorResponse::with_status(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Database error: {e}"),
+    )
+}
+
+/// `POST /attachments`: stores the body as a file of its `Content-Type` and
+/// returns a signed link to it.
+pub(crate) async fn upload(
+    State(state): State<AppState>,
+    audit: AuditContext,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Attachment>, (StatusCode, Json<ErrorResponse>)> {
+    let attachments = configured(&state)?;
+    if body.is_empty() {
+        return Err(ErrorResponse::with_status(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Attachment is empty",
+        ));
+    }
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_ascii_lowercase())
+        .filter(|value| !value.is_empty())
+        .map(|value| served_content_type(&value).to_string())
+        .unwrap_or_else(|| "application/octet-stream".to_string());
+
+    let id = new_attachment_id(&content_type);
+    let now = unix_now();
+    let expires_at = now + attachments.url_ttl.as_secs();
+    let url = match attachments
+        .store
+        .presigned_url(&id, now, attachments.url_ttl)
+    {
+        Some(url) => url,
+        None => {
+            let base = base_url(attachments, &headers).ok_or_else(|| {
+                ErrorResponse::with_status(
+                    StatusCode::BAD_REQUEST,
+                    "Can't tell the server's address for the link, set PSH_PUBLIC_URL",
+                )
+            })?;
+            attachments.signed_url(&base, &id, expires_at)
+        }
+    };
+    let attachment = Attachment {
+        url,
+        id,
+        content_type,
+        size_bytes: body.len() as u64,
+        expires_at,
+    };
+    attachments
+        .store
+        .put(&attachment.id, &attachment.content_type, body)
+        .await
+        .map_err(storage_error)?;
+    Database::insert_attachment(&attachment).map_err(database_error)?;
+
+    tracing::info!(
+        id = %attachment.id,
+        content_type = %attachment.content_type,
+        size_bytes = attachment.size_bytes,
+        "Stored attachment"
+    );
+    audit::record(
+        &audit,
+        "attachment.upload",
+        format!(
+            "id={} content_type={} size_bytes={}",
+            attachment.id, attachment.content_type, attachment.size_bytes
+        ),
+    );
+    Ok(Json(attachment))
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct DownloadQuery {
+    expires: Option<u64>,
+    sig: Option<String>,
+}
+
+/// `GET /attachments/:id`, which needs no API key but only works through
+/// an unexpired signed link.
+pub(crate) async fn download(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<DownloadQuery>,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let attachments = configured(&state)?;
+    let forbidden = |message| ErrorResponse::with_status(StatusCode::FORBIDDEN, message);
+    let (Some(expires), Some(sig)) = (query.expires, query.sig) else {
+        return Err(forbidden("Attachment links must be signed"));
+    };
+    if !is_attachment_id(&id) || !attachments.verify(&id, expires, &sig) {
+        tracing::warn!(id = %id, "Rejecting attachment link with a bad signature");
+        return Err(forbidden("Invalid attachment link"));
+    }
+    let now = unix_now();
+    if expires < now {
+        return Err(forbidden("Attachment link has expired"));
+    }
+
+    let not_found = || ErrorResponse::with_status(StatusCode::NOT_FOUND, "Attachment not found");
+    let attachment = Database::attachment(&id)
+        .map_err(database_error)?
+        .ok_or_else(not_found)?;
+    let bytes = attachments
+        .store
+        .get(&id)
+        .await
+        .map_err(storage_error)?
+        .ok_or_else(not_found)?;
+    let cache_control = format!("private, max-age={}", expires - now);
+    // Attachments stored before types were checked may be anything.
+    let content_type = served_content_type(&attachment.content_type).to_string();
+    let mut response = (
+        [
+            (CONTENT_TYPE, content_type.clone()),
+            (CACHE_CONTROL, cache_control),
+        ],
+        bytes,
+    )
+        .into_response();
+    let headers = response.headers_mut();
+    headers.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    if !is_media(&content_type) {
+        headers.insert(CONTENT_DISPOSITION, HeaderValue::from_static("attachment"));
+    }
+    Ok(response)
+}
+
+/// Adds the link to `req.attachment` to its custom data under
+/// `ATTACHMENT_DATA_KEY`, and marks the push mutable so the notification
+/// service extension runs to fetch it.
+pub(crate) fn inject(req: &mut SendRequest) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+    let Some(id) = req.attachment.as_deref() else {
+        return Ok(());
+    };
+    let unknown = || {
+        ErrorResponse::with_status(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("Unknown attachment '{id}'"),
+        )
+    };
+    if !is_attachment_id(id) {
+        return Err(unknown());
+    }
+    let attachment = Database::attachment(id)
+        .map_err(database_error)?
+        .ok_or_else(unknown)?;
+    if attachment.expires_at <= unix_now() {
+        return Err(ErrorResponse::with_status(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("Attachment '{id}' has expired, upload it again"),
+        ));
+    }
+    let value: Value = json!({
+        "url": attachment.url,
+        "content_type": attachment.content_type,
+    });
+    req.data
+        .get_or_insert_with(Default::default)
+        .insert(ATTACHMENT_DATA_KEY.to_string(), value);
+    req.mutable_content = Some(true);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signed_urls() {
+        let attachments = Attachments::new(Arc::new(DiskStore::new("unused")), "secret");
+        let id = new_attachment_id("image/png");
+        assert!(is_attachment_id(&id));
+        assert!(id.ends_with(".png"));
+
+        let url = attachments.signed_url("https://psh.example", &id, 100);
+        let sig = url.split("sig=").nth(1).unwrap();
+        assert!(url.starts_with(&format!(
+            "https://psh.example/attachments/{id}?expires=100&"
+        )));
+        assert!(attachments.verify(&id, 100, sig));
+        assert!(!attachments.verify(&id, 101, sig));
+        assert!(!attachments.verify(&new_attachment_id("image/png"), 100, sig));
+        assert!(!attachments.verify(&id, 100, "not base64!"));
+
+        let other = Attachments::new(Arc::new(DiskStore::new("unused")), "other");
+        assert!(!other.verify(&id, 100, sig));
+
+        for bad in [
+            "../data.db",
+            "abc.png",
+            &format!("{}/x.png", "a".repeat(32)),
+        ] {
+            assert!(!is_attachment_id(bad), "{bad}");
+        }
+    }
+}
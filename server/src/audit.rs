@@ -0,0 +1,293 @@
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts, Query, State},
+    http::{header::USER_AGENT, request::Parts, StatusCode},
+    Json,
+};
+use seekwel::{
+    connection::Connection,
+    error::Error as SeekwelError,
+    rusqlite::{params, ToSql},
+};
+use serde::{Deserialize, Serialize};
+use std::{convert::Infallible, net::SocketAddr};
+
+use crate::{
+    auth::Caller, duration::parse_duration, request_id, AppState, Database, ErrorResponse,
+};
+
+const DEFAULT_AUDIT_LIMIT: i64 = 100;
+const MAX_AUDIT_LIMIT: i64 = 1000;
+
+/// Who made a request and which endpoint it hit, captured for the audit log.
+#[derive(Debug, Clone, Default)]
+pub struct AuditContext {
+    pub endpoint: String,
+    pub remote_addr: Option<String>,
+    pub user_agent: Option<String>,
+    /// The `x-request-id` the request was handled under.
+    pub request_id: Option<String>,
+    /// The API key the request authenticated with, if keys are required.
+    pub caller: Option<Caller>,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for AuditContext {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        // Behind a proxy the socket address is the proxy's, so prefer the
+        // first hop it reports.
+        let forwarded_for = parts
+            .headers
+            .get("x-forwarded-for")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(',').next())
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty());
+        let remote_addr = forwarded_for.or_else(|| {
+            parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip().to_string())
+        });
+
+        Ok(AuditContext {
+            endpoint: format!("{} {}", parts.method, parts.uri.path()),
+            remote_addr,
+            user_agent: parts
+                .headers
+                .get(USER_AGENT)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string),
+            request_id: request_id::from_extensions(&parts.extensions),
+            caller: parts.extensions.get::<Caller>().cloned(),
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditEntry {
+    id: i64,
+    action: String,
+    endpoint: String,
+    summary: Option<String>,
+    remote_addr: Option<String>,
+    user_agent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    created_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditResponse {
+    entries: Vec<AuditEntry>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditQuery {
+    /// Exact action, e.g. "send" or "segment.delete".
+    action: Option<String>,
+    /// Only entries newer than this, e.g. "24h" or "7d".
+    since: Option<String>,
+    limit: Option<i64>,
+}
+
+impl Database {
+    pub(crate) fn create_audit_table(conn: &Connection) -> Result<(), SeekwelError> {
+        conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS audit_log (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                action TEXT NOT NULL,
+                endpoint TEXT NOT NULL,
+                summary TEXT,
+                remote_addr TEXT,
+                user_agent TEXT,
+                request_id TEXT,
+                created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            )
+            "#,
+            (),
+        )?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_audit_log_created_at ON audit_log(created_at)",
+            (),
+        )?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_audit_log_action ON audit_log(action)",
+            (),
+        )?;
+        if !Self::column_exists(conn, "audit_log", "request_id")? {
+            conn.execute("ALTER TABLE audit_log ADD COLUMN request_id TEXT", ())?;
+        }
+        Ok(())
+    }
+
+    fn insert_audit(
+        context: &AuditContext,
+        action: &str,
+        summary: Option<&str>,
+    ) -> Result<(), SeekwelError> {
+        Connection::get()?.execute(
+            r#"
+            INSERT INTO audit_log (action, endpoint, summary, remote_addr, user_agent, request_id)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            "#,
+            params![
+                action,
+                context.endpoint,
+                summary,
+                context.remote_addr,
+                context.user_agent,
+                context.request_id
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn audit_entries(
+        action: Option<&str>,
+        since_seconds: Option<u64>,
+        limit: i64,
+    ) -> Result<Vec<AuditEntry>, SeekwelError> {
+        let since = since_seconds.map(|s| format!("-{s} seconds"));
+        let mut conditions = Vec::new();
+        let mut values: Vec<&dyn ToSql> = Vec::new();
+        if let Some(action) = &action {
+            conditions.push("action = ?");
+            values.push(action);
+        }
+        if let Some(since) = &since {
+            conditions.push("created_at >= datetime('now', ?)");
+            values.push(since);
+        }
+        values.push(&limit);
+
+        let where_clause = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        };
+        let sql = format!(
+            r#"
+            SELECT id, action, endpoint, summary, remote_addr, user_agent, request_id, created_at
+            FROM audit_log
+            {where_clause}
+            ORDER BY id DESC
+            LIMIT ?
+            "#
+        );
+
+        Connection::get()?.query_all(&sql, values.as_slice(), |row| {
+            Ok(AuditEntry {
+                id: row.get(0)?,
+                action: row.get(1)?,
+                endpoint: row.get(2)?,
+                summary: row.get(3)?,
+                remote_addr: row.get(4)?,
+                user_agent: row.get(5)?,
+                request_id: row.get(6)?,
+                created_at: row.get(7)?,
+            })
+        })
+    }
+}
+
+/// Records an audited operation. Failures are logged rather than failing
+/// the request that has already been carried out.
+pub fn record(context: &AuditContext, action: &str, summary: impl Into<Option<String>>) {
+    let summary = summary.into();
+    if let Err(e) = Database::insert_audit(context, action, summary.as_deref()) {
+        tracing::error!(action = action, error = %e, "Failed to write audit log entry");
+    }
+}
+
+pub async fn get_audit(
+    State(_state): State<AppState>,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<AuditResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let since_seconds = match &query.since {
+        Some(since) => Some(
+            parse_duration(since)
+                .ok_or_else(|| {
+                    ErrorResponse::with_status(
+                        StatusCode::BAD_REQUEST,
+                        format!("Invalid since '{since}', expected e.g. 24h or 7d"),
+                    )
+                })?
+                .as_secs(),
+        ),
+        None => None,
+    };
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_AUDIT_LIMIT)
+        .clamp(1, MAX_AUDIT_LIMIT);
+
+    let entries =
+        Database::audit_entries(query.action.as_deref(), since_seconds, limit).map_err(|e| {
+            tracing::error!(error = %e, "Database error fetching audit log");
+            ErrorResponse::with_status(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Database error: {e}"),
+            )
+        })?;
+
+    Ok(Json(AuditResponse { entries }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_db;
+
+    fn context() -> AuditContext {
+        AuditContext {
+            endpoint: "POST /send".to_string(),
+            remote_addr: Some("203.0.113.7".to_string()),
+            user_agent: Some("curl/8.0".to_string()),
+            request_id: Some("4bf92f3577b34da6".to_string()),
+            caller: None,
+        }
+    }
+
+    #[test]
+    fn test_audit_entries_filter_by_action() {
+        let _db = test_db();
+        record(&context(), "send", "1 sent, 0 failed".to_string());
+        record(&context(), "register", None);
+        record(&context(), "send", "0 sent, 2 failed".to_string());
+
+        let all = Database::audit_entries(None, None, 10).unwrap();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[0].summary.as_deref(), Some("0 sent, 2 failed"));
+
+        let sends = Database::audit_entries(Some("send"), Some(3600), 10).unwrap();
+        assert_eq!(sends.len(), 2);
+        assert!(sends.iter().all(|e| e.action == "send"));
+        assert_eq!(sends[0].remote_addr.as_deref(), Some("203.0.113.7"));
+        assert_eq!(sends[0].request_id.as_deref(), Some("4bf92f3577b34da6"));
+
+        assert_eq!(Database::audit_entries(None, None, 1).unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_context_prefers_forwarded_for() {
+        let request = axum::http::Request::builder()
+            .method("DELETE")
+            .uri("/segments/beta?x=1")
+            .header("x-forwarded-for", "198.51.100.2, 10.0.0.1")
+            .header(USER_AGENT, "psh")
+            .body(())
+            .unwrap();
+        let (mut parts, _) = request.into_parts();
+        let context = AuditContext::from_request_parts(&mut parts, &())
+            .await
+            .unwrap();
+
+        assert_eq!(context.endpoint, "DELETE /segments/beta");
+        assert_eq!(context.remote_addr.as_deref(), Some("198.51.100.2"));
+        assert_eq!(context.user_agent.as_deref(), Some("psh"));
+    }
+}
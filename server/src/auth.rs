@@ -0,0 +1,376 @@
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{
+        header::{AUTHORIZATION, WWW_AUTHENTICATE},
+        HeaderMap, HeaderValue, Method, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use sha2::{Digest, Sha256};
+use std::{collections::HashSet, sync::Arc};
+
+use crate::{
+    config::{ApiKeyConfig, Role},
+    quota, AppState, Database, ErrorResponse,
+};
+
+/// The API keys requests authenticate with. With none configured the API is
+/// open, as it was before keys existed.
+#[derive(Debug, Clone, Default)]
+pub struct ApiKeys {
+    keys: Arc<Vec<ApiKey>>,
+}
+
+#[derive(Debug)]
+struct ApiKey {
+    /// SHA-256 of the key, so lookups compare digests rather than secrets.
+    digest: [u8; 32],
+    caller: Caller,
+}
+
+/// The key a request authenticated with, for handlers that act per key.
+#[derive(Debug, Clone)]
+pub struct Caller {
+    pub name: String,
+    pub role: Role,
+    pub daily_quota: Option<u64>,
+    pub monthly_quota: Option<u64>,
+    /// The one topic the key may publish to, for an issued key limited to
+    /// it.
+    pub topic: Option<String>,
+}
+
+impl Caller {
+    pub(crate) fn has_quota(&self) -> bool {
+        self.daily_quota.is_some() || self.monthly_quota.is_some()
+    }
+}
+
+/// What an endpoint needs from the caller's key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    /// Health checks and what devices call for themselves.
+    Public,
+    /// Any valid key.
+    Key,
+    Send,
+    Read,
+    Admin,
+}
+
+pub(crate) fn digest(key: &str) -> [u8; 32] {
+    Sha256::digest(key.as_bytes()).into()
+}
+
+impl ApiKeys {
+    /// Keys from `[[api_keys]]`, rejecting empty keys and repeated names or
+    /// keys.
+    pub fn from_config(configs: &[ApiKeyConfig]) -> Result<Self, String> {
+        let mut names = HashSet::new();
+        let mut digests = HashSet::new();
+        let mut keys = Vec::with_capacity(configs.len());
+        for config in configs {
+            if config.key.trim().is_empty() {
+                return Err(format!("API key '{}' is empty", config.name));
+            }
+            if !names.insert(config.name.as_str()) {
+                return Err(format!("API key name '{}' is used twice", config.name));
+            }
+            let digest = digest(&config.key);
+            if !digests.insert(digest) {
+                return Err(format!(
+                    "API key '{}' has the same key as another",
+                    config.name
+                ));
+            }
+            keys.push(ApiKey {
+                digest,
+                caller: Caller {
+                    name: config.name.clone(),
+                    role: config.role,
+                    daily_quota: config.daily_quota,
+                    monthly_quota: config.monthly_quota,
+                    topic: None,
+                },
+            });
+        }
+        Ok(Self {
+            keys: Arc::new(keys),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    fn find(&self, presented: &str) -> Option<&Caller> {
+        let digest = digest(presented);
+        self.keys
+            .iter()
+            .find(|key| key.digest == digest)
+            .map(|key| &key.caller)
+    }
+
+    pub(crate) fn callers(&self) -> impl Iterator<Item = &Caller> {
+        self.keys.iter().map(|key| &key.caller)
+    }
+}
+
+impl Role {
+    /// Whether the role may read the API, to see health details.
+    pub(crate) fn can_read(self) -> bool {
+        self.allows(Access::Read)
+    }
+
+    fn allows(self, access: Access) -> bool {
+        match access {
+            Access::Public | Access::Key => true,
+            Access::Send => matches!(self, Role::Send | Role::Admin),
+            Access::Read => matches!(self, Role::Read | Role::Admin),
+            Access::Admin => self == Role::Admin,
+        }
+    }
+
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Role::Send => "send",
+            Role::Read => "read",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+/// The access `method` on the route `path` needs. Reads not listed need
+/// `read` and writes `admin`.
+fn required_access(method: &Method, path: &str) -> Access {
+    match path {
+        "/"
+        | "/health"
+        | "/health/ready"
+        | "/health/live"
+        | "/version"
+        | "/register"
+        | "/webpush/vapid-public-key"
+        | "/webpush/subscriptions"
+        | "/devices/:token/topics/:topic"
+        | "/pushes/:id/opened"
+        | "/pushes/read"
+        | "/attachments/:id" => Access::Public,
+        // Apps read and delete from their own inbox, naming their
+        // installation.
+        "/pushes" if method == Method::GET || method == Method::HEAD => Access::Public,
+        "/pushes/:id" if method == Method::DELETE => Access::Public,
+        "/usage" => Access::Key,
+        "/send"
+        | "/t/:topic"
+        | "/channels/:id/send"
+        | "/webhook/slack"
+        | "/preview"
+        | "/attachments"
+        | "/devices/:token/test"
+        | "/pushes/:id/withdraw" => Access::Send,
+        _ if method == Method::GET || method == Method::HEAD => Access::Read,
+        _ => Access::Admin,
+    }
+}
+
+/// The key in `Authorization: Bearer <key>`, or the password (else the user
+/// name) of Basic auth, for webhook senders that can only put credentials
+/// in the URL.
+fn presented_key(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, credentials) = value.split_once(' ')?;
+    let credentials = credentials.trim();
+    if scheme.eq_ignore_ascii_case("bearer") {
+        return Some(credentials.to_string());
+    }
+    if scheme.eq_ignore_ascii_case("basic") {
+        let decoded = String::from_utf8(BASE64.decode(credentials).ok()?).ok()?;
+        let (user, password) = decoded.split_once(':').unwrap_or((&decoded, ""));
+        let key = if password.is_empty() { user } else { password };
+        return Some(key.to_string());
+    }
+    None
+}
+
+fn unauthorized(message: &str) -> Response {
+    let mut response =
+        ErrorResponse::with_status(StatusCode::UNAUTHORIZED, message).into_response();
+    response
+        .headers_mut()
+        .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+    response
+}
+
+/// Checks the caller's key against the route's required access, when any
+/// keys are configured, and makes the key available as a `Caller`.
+pub(crate) async fn require(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if state.api_keys.is_empty() {
+        return next.run(request).await;
+    }
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| request.uri().path().to_string());
+    let access = required_access(request.method(), &path);
+
+    let key = presented_key(request.headers()).and_then(|key| match state.api_keys.find(&key) {
+        Some(caller) => Some(caller.clone()),
+        None => Database::issued_caller(&key).unwrap_or_else(|e| {
+            tracing::error!(error = %e, "Database error looking up an issued API key");
+            None
+        }),
+    });
+    let key = match (key, access) {
+        (Some(key), _) => key,
+        (None, Access::Public) => return next.run(request).await,
+        (None, _) => {
+            tracing::warn!(path = %path, "Rejecting request without a valid API key");
+            return unauthorized("A valid API key is required: Authorization: Bearer <key>");
+        }
+    };
+    if !key.role.allows(access) {
+        tracing::warn!(api_key = %key.name, role = key.role.as_str(), path = %path, "Rejecting request outside the API key's role");
+        return ErrorResponse::with_status(
+            StatusCode::FORBIDDEN,
+            format!(
+                "API key '{}' has the {} role, which can't {} {}",
+                key.name,
+                key.role.as_str(),
+                request.method(),
+                path
+            ),
+        )
+        .into_response();
+    }
+    if let Some(topic) = key.topic.as_deref() {
+        let publishes = path == "/t/:topic" && request.uri().path() == format!("/t/{topic}");
+        if !publishes && !matches!(access, Access::Public | Access::Key) {
+            tracing::warn!(api_key = %key.name, topic = %topic, path = %path, "Rejecting request outside the API key's topic");
+            return ErrorResponse::with_status(
+                StatusCode::FORBIDDEN,
+                format!("API key '{}' can only publish to /t/{topic}", key.name),
+            )
+            .into_response();
+        }
+    }
+    tracing::debug!(api_key = %key.name, path = %path, "Authenticated request");
+    request.extensions_mut().insert(key.clone());
+    let mut response = next.run(request).await;
+    if key.has_quota() {
+        quota::annotate(&key, &mut response);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(name: &str, key: &str, role: Role) -> ApiKeyConfig {
+        ApiKeyConfig {
+            name: name.to_string(),
+            key: key.to_string(),
+            role,
+            daily_quota: None,
+            monthly_quota: None,
+        }
+    }
+
+    #[test]
+    fn test_roles_cover_routes() {
+        assert_eq!(required_access(&Method::GET, "/health"), Access::Public);
+        assert_eq!(required_access(&Method::POST, "/register"), Access::Public);
+        assert_eq!(required_access(&Method::GET, "/send"), Access::Send);
+        assert_eq!(required_access(&Method::POST, "/t/:topic"), Access::Send);
+        assert_eq!(
+            required_access(&Method::POST, "/channels/:id/send"),
+            Access::Send
+        );
+        assert_eq!(required_access(&Method::POST, "/channels"), Access::Admin);
+        assert_eq!(required_access(&Method::GET, "/devices"), Access::Read);
+        assert_eq!(required_access(&Method::GET, "/pushes/:id"), Access::Read);
+        assert_eq!(required_access(&Method::GET, "/pushes"), Access::Public);
+        assert_eq!(
+            required_access(&Method::DELETE, "/pushes/:id"),
+            Access::Public
+        );
+        assert_eq!(
+            required_access(&Method::POST, "/pushes/:id/opened"),
+            Access::Public
+        );
+        assert_eq!(required_access(&Method::POST, "/attachments"), Access::Send);
+        assert_eq!(
+            required_access(&Method::POST, "/pushes/:id/withdraw"),
+            Access::Send
+        );
+        assert_eq!(
+            required_access(&Method::GET, "/attachments/:id"),
+            Access::Public
+        );
+        assert_eq!(
+            required_access(&Method::PUT, "/apps/:bundle_id"),
+            Access::Admin
+        );
+
+        assert!(Role::Send.allows(Access::Send));
+        assert!(!Role::Send.allows(Access::Read));
+        assert!(!Role::Read.allows(Access::Send));
+        assert!(!Role::Read.allows(Access::Admin));
+        assert!(Role::Admin.allows(Access::Send) && Role::Admin.allows(Access::Read));
+    }
+
+    #[test]
+    fn test_presented_key() {
+        let headers = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(AUTHORIZATION, HeaderValue::from_str(value).unwrap());
+            headers
+        };
+        assert_eq!(
+            presented_key(&headers("Bearer abc")).as_deref(),
+            Some("abc")
+        );
+        let basic = |credentials: &str| format!("Basic {}", BASE64.encode(credentials));
+        assert_eq!(
+            presented_key(&headers(&basic("ci:abc"))).as_deref(),
+            Some("abc")
+        );
+        assert_eq!(
+            presented_key(&headers(&basic("abc:"))).as_deref(),
+            Some("abc")
+        );
+        assert!(presented_key(&headers("Token abc")).is_none());
+        assert!(presented_key(&HeaderMap::new()).is_none());
+    }
+
+    #[test]
+    fn test_from_config_rejects_duplicates() {
+        let keys = ApiKeys::from_config(&[
+            config("ci", "ci-secret", Role::Send),
+            config("ops", "ops-secret", Role::Admin),
+        ])
+        .unwrap();
+        assert_eq!(keys.find("ops-secret").unwrap().name, "ops");
+        assert!(keys.find("guess").is_none());
+
+        assert!(ApiKeys::from_config(&[config("ci", " ", Role::Send)]).is_err());
+        assert!(ApiKeys::from_config(&[
+            config("ci", "a", Role::Send),
+            config("ci", "b", Role::Read)
+        ])
+        .is_err());
+        assert!(ApiKeys::from_config(&[
+            config("ci", "a", Role::Send),
+            config("ops", "a", Role::Admin)
+        ])
+        .is_err());
+    }
+}
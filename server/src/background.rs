@@ -0,0 +1,144 @@
+use seekwel::{connection::Connection, error::Error as SeekwelError, rusqlite::params};
+use std::time::Duration;
+
+use crate::{provider::Platform, Database, SendRequest};
+
+/// Background pushes per device per hour before APNs starts throttling, per
+/// Apple's guidance of "two or three per hour".
+pub(crate) const HOURLY_BUDGET: i64 = 3;
+
+/// Whether APNs sends `req` as a background push, which it throttles.
+pub(crate) fn is_throttled(req: &SendRequest, platform: Platform) -> bool {
+    platform == Platform::Apns && req.content_available == Some(true)
+}
+
+/// The send response warning for a device already at `count` this hour.
+pub(crate) fn budget_warning(count: i64) -> String {
+    format!(
+        "Device already received {count} background pushes this hour; APNs throttles more than {HOURLY_BUDGET}"
+    )
+}
+
+/// Unix time the current hour ends, when every device's count starts over.
+pub(crate) fn next_hour(now: u64) -> u64 {
+    now - now % 3600 + 3600
+}
+
+/// How long a deferred push waits, from `now` to the next hour.
+pub(crate) fn delay_until_next_hour(now: u64) -> Duration {
+    Duration::from_secs(next_hour(now) - now)
+}
+
+impl Database {
+    pub(crate) fn create_background_pushes_table(conn: &Connection) -> Result<(), SeekwelError> {
+        conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS background_pushes (
+                device_id INTEGER NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+                hour TEXT NOT NULL,
+                count INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (device_id, hour)
+            )
+            "#,
+            (),
+        )?;
+        Ok(())
+    }
+
+    /// Background pushes sent to `device_id` in the current UTC hour.
+    pub(crate) fn background_pushes_this_hour(device_id: i64) -> Result<i64, SeekwelError> {
+        let count: Option<i64> = Connection::get()?.query_row(
+            r#"
+            SELECT MAX(count) FROM background_pushes
+            WHERE device_id = ?1 AND hour = strftime('%Y-%m-%dT%H', 'now')
+            "#,
+            params![device_id],
+            |row| row.get(0),
+        )?;
+        Ok(count.unwrap_or(0))
+    }
+
+    /// Counts a background push to `device_id`, dropping counts older than a
+    /// day along the way.
+    pub(crate) fn record_background_push(device_id: i64) -> Result<(), SeekwelError> {
+        let conn = Connection::get()?;
+        conn.execute(
+            r#"
+            INSERT INTO background_pushes (device_id, hour, count)
+            VALUES (?1, strftime('%Y-%m-%dT%H', 'now'), 1)
+            ON CONFLICT (device_id, hour) DO UPDATE SET count = count + 1
+            "#,
+            params![device_id],
+        )?;
+        conn.execute(
+            "DELETE FROM background_pushes WHERE hour < strftime('%Y-%m-%dT%H', 'now', '-1 day')",
+            (),
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_db;
+
+    fn device() -> i64 {
+        Connection::get()
+            .unwrap()
+            .execute(
+                "INSERT INTO devices (device_token, installation_id, environment) VALUES ('abc', 'install-1', 'sandbox')",
+                (),
+            )
+            .unwrap();
+        Connection::get()
+            .unwrap()
+            .query_row("SELECT id FROM devices", (), |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_only_apns_background_pushes_are_throttled() {
+        let mut req = SendRequest::default();
+        assert!(!is_throttled(&req, Platform::Apns));
+        req.content_available = Some(true);
+        assert!(is_throttled(&req, Platform::Apns));
+        assert!(!is_throttled(&req, Platform::WebPush));
+    }
+
+    #[test]
+    fn test_next_hour() {
+        assert_eq!(next_hour(7200), 10800);
+        assert_eq!(next_hour(7201), 10800);
+        assert_eq!(delay_until_next_hour(10799), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_counts_background_pushes_per_device() {
+        let _db = test_db();
+        let device_id = device();
+        assert_eq!(Database::background_pushes_this_hour(device_id).unwrap(), 0);
+
+        Database::record_background_push(device_id).unwrap();
+        Database::record_background_push(device_id).unwrap();
+        assert_eq!(Database::background_pushes_this_hour(device_id).unwrap(), 2);
+        assert_eq!(
+            Database::background_pushes_this_hour(device_id + 1).unwrap(),
+            0
+        );
+
+        Connection::get()
+            .unwrap()
+            .execute("UPDATE background_pushes SET hour = '2000-01-01T00'", ())
+            .unwrap();
+        Database::record_background_push(device_id).unwrap();
+        assert_eq!(Database::background_pushes_this_hour(device_id).unwrap(), 1);
+        let rows: i64 = Connection::get()
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM background_pushes", (), |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(rows, 1);
+    }
+}
@@ -0,0 +1,233 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::apns_error::{ApnsErrorCode, SendError};
+
+/// The longest an open circuit waits before probing again.
+const MAX_OPEN_FOR: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    /// Requests go out; `failures` in a row so far.
+    Closed { failures: u32 },
+    /// Requests fail fast until `until`.
+    Open { until: Instant, open_for: Duration },
+    /// One request is probing whether the endpoint has recovered.
+    Probing { open_for: Duration },
+}
+
+/// Whether `code` means the endpoint, rather than the push, is failing:
+/// APNs unreachable or erroring, or refusing the credentials.
+fn is_endpoint_failure(code: ApnsErrorCode) -> bool {
+    matches!(
+        code,
+        ApnsErrorCode::ConnectionError
+            | ApnsErrorCode::IdleTimeout
+            | ApnsErrorCode::Timeout
+            | ApnsErrorCode::InternalServerError
+            | ApnsErrorCode::ServiceUnavailable
+            | ApnsErrorCode::Shutdown
+            | ApnsErrorCode::ExpiredProviderToken
+            | ApnsErrorCode::InvalidProviderToken
+            | ApnsErrorCode::MissingProviderToken
+            | ApnsErrorCode::Forbidden
+            | ApnsErrorCode::BadCertificate
+            | ApnsErrorCode::BadCertificateEnvironment
+            | ApnsErrorCode::TooManyProviderTokenUpdates
+    )
+}
+
+struct Circuit {
+    state: State,
+    /// Failures in a row that open the circuit.
+    threshold: u32,
+    open_for: Duration,
+}
+
+impl Circuit {
+    /// Lets a request through at `now`, or says how long the circuit stays
+    /// open. The first request after that is let through as a probe.
+    fn allow(&mut self, now: Instant) -> Result<(), Duration> {
+        match self.state {
+            State::Closed { .. } => Ok(()),
+            State::Open { until, open_for } if now >= until => {
+                self.state = State::Probing { open_for };
+                Ok(())
+            }
+            State::Open { until, .. } => Err(until - now),
+            State::Probing { .. } => Err(Duration::ZERO),
+        }
+    }
+
+    /// Counts a request's outcome, returning the new state if it changed
+    /// whether requests go out.
+    fn record(&mut self, failed: bool, now: Instant) -> Option<State> {
+        let next = match (self.state, failed) {
+            (_, false) => State::Closed { failures: 0 },
+            (State::Closed { failures }, true) if failures + 1 < self.threshold => State::Closed {
+                failures: failures + 1,
+            },
+            (State::Closed { .. }, true) => State::Open {
+                until: now + self.open_for,
+                open_for: self.open_for,
+            },
+            // Requests sent before the circuit opened.
+            (State::Open { .. }, true) => self.state,
+            (State::Probing { open_for }, true) => {
+                let open_for = (open_for * 2).min(MAX_OPEN_FOR);
+                State::Open {
+                    until: now + open_for,
+                    open_for,
+                }
+            }
+        };
+        let was_closed = matches!(self.state, State::Closed { .. });
+        self.state = next;
+        (was_closed != matches!(next, State::Closed { .. })).then_some(next)
+    }
+}
+
+/// A circuit breaker for one APNs endpoint. After `threshold` requests in
+/// a row fail because APNs is down or refusing the credentials, requests
+/// fail fast with `CircuitOpen` instead of each waiting on a doomed one.
+/// Once it has been open a while, the next request probes the endpoint:
+/// success closes the circuit, failure opens it for twice as long.
+pub(crate) struct Breaker {
+    environment: &'static str,
+    /// `None` when the breaker is off.
+    circuit: Option<Mutex<Circuit>>,
+}
+
+impl Breaker {
+    /// A breaker opening for `open_for` after `threshold` failures in a row.
+    /// A threshold of 0 never opens.
+    pub(crate) fn new(environment: &'static str, threshold: u32, open_for: Duration) -> Self {
+        Self {
+            environment,
+            circuit: (threshold > 0).then(|| {
+                Mutex::new(Circuit {
+                    state: State::Closed { failures: 0 },
+                    threshold,
+                    open_for,
+                })
+            }),
+        }
+    }
+
+    fn with_circuit<T>(&self, f: impl FnOnce(&mut Circuit) -> T) -> Option<T> {
+        self.circuit
+            .as_ref()
+            .map(|circuit| f(&mut circuit.lock().unwrap_or_else(|e| e.into_inner())))
+    }
+
+    /// Lets a request through, or fails it while the circuit is open.
+    pub(crate) fn allow(&self) -> Result<(), SendError> {
+        match self.with_circuit(|circuit| circuit.allow(Instant::now())) {
+            Some(Err(remaining)) => Err(SendError {
+                code: ApnsErrorCode::CircuitOpen,
+                message: format!(
+                    "APNs circuit open: requests to {} keep failing, next attempt in {}s",
+                    self.environment,
+                    remaining.as_secs().max(1)
+                ),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts a request's outcome towards opening or closing the circuit.
+    pub(crate) fn record(&self, result: &Result<String, SendError>) {
+        let failed = matches!(result, Err(error) if is_endpoint_failure(error.code));
+        let changed = self
+            .with_circuit(|circuit| circuit.record(failed, Instant::now()))
+            .flatten();
+        match changed {
+            Some(State::Open { open_for, .. }) => tracing::error!(
+                environment = self.environment,
+                open_for_seconds = open_for.as_secs(),
+                "APNs circuit open, failing sends fast"
+            ),
+            Some(_) => tracing::info!(
+                environment = self.environment,
+                "APNs circuit closed, endpoint recovered"
+            ),
+            None => {}
+        }
+    }
+
+    /// Whether sends are currently failing fast.
+    pub(crate) fn is_open(&self) -> bool {
+        self.with_circuit(|circuit| !matches!(circuit.state, State::Closed { .. }))
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn circuit(threshold: u32) -> Circuit {
+        Circuit {
+            state: State::Closed { failures: 0 },
+            threshold,
+            open_for: Duration::from_secs(30),
+        }
+    }
+
+    #[test]
+    fn test_circuit_opens_after_failures_in_a_row() {
+        let now = Instant::now();
+        let mut circuit = circuit(3);
+        assert_eq!(circuit.record(true, now), None);
+        assert_eq!(circuit.record(false, now), None);
+        assert_eq!(circuit.record(true, now), None);
+        assert_eq!(circuit.record(true, now), None);
+        assert!(matches!(
+            circuit.record(true, now),
+            Some(State::Open { .. })
+        ));
+        assert_eq!(circuit.allow(now), Err(Duration::from_secs(30)));
+        // Answers to requests sent before it opened change nothing.
+        assert_eq!(circuit.record(true, now), None);
+    }
+
+    #[test]
+    fn test_circuit_probes_and_backs_off() {
+        let now = Instant::now();
+        let mut circuit = circuit(1);
+        circuit.record(true, now);
+
+        let later = now + Duration::from_secs(30);
+        assert_eq!(circuit.allow(later), Ok(()));
+        // One probe at a time.
+        assert_eq!(circuit.allow(later), Err(Duration::ZERO));
+        circuit.record(true, later);
+        assert_eq!(circuit.allow(later), Err(Duration::from_secs(60)));
+
+        let recovered = later + Duration::from_secs(60);
+        assert_eq!(circuit.allow(recovered), Ok(()));
+        assert_eq!(
+            circuit.record(false, recovered),
+            Some(State::Closed { failures: 0 })
+        );
+        assert_eq!(circuit.allow(recovered), Ok(()));
+    }
+
+    #[test]
+    fn test_only_endpoint_failures_count() {
+        let breaker = Breaker::new("production", 1, Duration::from_secs(30));
+        breaker.record(&Err(SendError::new(ApnsErrorCode::BadDeviceToken)));
+        assert!(breaker.allow().is_ok());
+        breaker.record(&Err(SendError::new(ApnsErrorCode::InvalidProviderToken)));
+        let error = breaker.allow().unwrap_err();
+        assert_eq!(error.code, ApnsErrorCode::CircuitOpen);
+        assert!(error.message.starts_with("APNs circuit open"));
+        assert!(breaker.is_open());
+
+        let off = Breaker::new("production", 0, Duration::from_secs(30));
+        off.record(&Err(SendError::new(ApnsErrorCode::ConnectionError)));
+        assert!(off.allow().is_ok());
+    }
+}
@@ -0,0 +1,234 @@
+use axum::{
+    body::{to_bytes, Body, Bytes},
+    extract::{Request, State},
+    http::{
+        header::{
+            CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+        },
+        HeaderMap, HeaderValue, Method, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::AppState;
+
+/// Read endpoints dashboards poll, served with ETags. Not the inboxes at
+/// `/pushes`, which need no key and answer conditional reads themselves.
+const CACHED_PATHS: [&str; 2] = ["/devices", "/stats"];
+
+/// GET endpoints that write, for webhooks that can only fire GETs.
+const WRITE_PATHS: [&str; 1] = ["/send"];
+
+/// Bounds staleness for results that change with time alone, like the
+/// buckets of `/stats?since=24h`.
+const MAX_AGE: Duration = Duration::from_secs(30);
+
+/// Past this many distinct URLs the cache starts over.
+const MAX_ENTRIES: usize = 256;
+
+#[derive(Clone)]
+struct Entry {
+    generation: u64,
+    stored_at: Instant,
+    etag: HeaderValue,
+    content_type: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+    body: Bytes,
+}
+
+/// Recent `CACHED_PATHS` responses keyed by URL. Every write request bumps
+/// the generation, which invalidates all of them.
+#[derive(Clone, Default)]
+pub struct ResponseCache {
+    generation: Arc<AtomicU64>,
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+impl ResponseCache {
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn invalidate(&self) {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
+
+    fn get(&self, key: &str) -> Option<Entry> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let entry = entries.get(key)?;
+        let fresh = entry.generation == self.generation() && entry.stored_at.elapsed() < MAX_AGE;
+        fresh.then(|| entry.clone())
+    }
+
+    fn insert(&self, key: String, entry: Entry) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.len() >= MAX_ENTRIES && !entries.contains_key(&key) {
+            entries.clear();
+        }
+        entries.insert(key, entry);
+    }
+}
+
+/// A strong validator for `body`.
+fn etag_for(body: &[u8]) -> HeaderValue {
+    let digest = Sha256::digest(body);
+    let tag = format!("\"{}\"", URL_SAFE_NO_PAD.encode(&digest[..16]));
+    HeaderValue::from_str(&tag).expect("base64 is a valid header value")
+}
+
+/// Whether `If-None-Match` lists `etag`, ignoring weak prefixes.
+pub(crate) fn matches_if_none_match(headers: &HeaderMap, etag: &HeaderValue) -> bool {
+    let Ok(etag) = etag.to_str() else {
+        return false;
+    };
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|candidate| candidate.trim())
+        .any(|candidate| {
+            candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == etag
+        })
+}
+
+fn respond(headers: &HeaderMap, entry: Entry) -> Response {
+    let mut response = if matches_if_none_match(headers, &entry.etag) {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        let mut response = Response::new(Body::from(entry.body));
+        if let Some(content_type) = entry.content_type {
+            response.headers_mut().insert(CONTENT_TYPE, content_type);
+        }
+        response
+    };
+    if let Some(last_modified) = entry.last_modified {
+        response.headers_mut().insert(LAST_MODIFIED, last_modified);
+    }
+    response.headers_mut().insert(ETAG, entry.etag);
+    response
+        .headers_mut()
+        .insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    response
+}
+
+/// Answers repeat reads of `CACHED_PATHS` from memory and with 304 when the
+/// client's `If-None-Match` is current; any other method, or a GET of
+/// `WRITE_PATHS`, invalidates.
+pub(crate) async fn conditional_get(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let cache = &state.response_cache;
+    if !matches!(*req.method(), Method::GET | Method::HEAD)
+        || WRITE_PATHS.contains(&req.uri().path())
+    {
+        let response = next.run(req).await;
+        cache.invalidate();
+        return response;
+    }
+    // HEAD bodies are already stripped by the router, so only GET is stored.
+    // `If-Modified-Since` is the handler's to answer.
+    if req.method() != Method::GET
+        || !CACHED_PATHS.contains(&req.uri().path())
+        || req.headers().contains_key(IF_MODIFIED_SINCE)
+    {
+        return next.run(req).await;
+    }
+
+    let key = req.uri().to_string();
+    let headers = req.headers().clone();
+    if let Some(entry) = cache.get(&key) {
+        tracing::debug!(uri = %key, "Serving cached response");
+        return respond(&headers, entry);
+    }
+
+    let generation = cache.generation();
+    let response = next.run(req).await;
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    let body = match to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::error!(uri = %key, error = %e, "Failed to buffer response for caching");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let entry = Entry {
+        generation,
+        stored_at: Instant::now(),
+        etag: etag_for(&body),
+        content_type: parts.headers.get(CONTENT_TYPE).cloned(),
+        last_modified: parts.headers.get(LAST_MODIFIED).cloned(),
+        body,
+    };
+    cache.insert(key, entry.clone());
+    respond(&headers, entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(cache: &ResponseCache, body: &'static str) -> Entry {
+        Entry {
+            generation: cache.generation(),
+            stored_at: Instant::now(),
+            etag: etag_for(body.as_bytes()),
+            content_type: None,
+            last_modified: None,
+            body: Bytes::from_static(body.as_bytes()),
+        }
+    }
+
+    #[test]
+    fn test_etag_is_stable_and_quoted() {
+        let etag = etag_for(b"{\"pushes\":[]}");
+        assert_eq!(etag, etag_for(b"{\"pushes\":[]}"));
+        assert_ne!(etag, etag_for(b"{\"pushes\":[1]}"));
+        let value = etag.to_str().unwrap();
+        assert!(value.starts_with('"') && value.ends_with('"'));
+    }
+
+    #[test]
+    fn test_if_none_match() {
+        let etag = etag_for(b"body");
+        let mut headers = HeaderMap::new();
+        assert!(!matches_if_none_match(&headers, &etag));
+
+        let list = format!("\"other\", W/{}", etag.to_str().unwrap());
+        headers.insert(IF_NONE_MATCH, HeaderValue::from_str(&list).unwrap());
+        assert!(matches_if_none_match(&headers, &etag));
+
+        headers.insert(IF_NONE_MATCH, HeaderValue::from_static("\"other\""));
+        assert!(!matches_if_none_match(&headers, &etag));
+
+        headers.insert(IF_NONE_MATCH, HeaderValue::from_static("*"));
+        assert!(matches_if_none_match(&headers, &etag));
+    }
+
+    #[test]
+    fn test_writes_invalidate_entries() {
+        let cache = ResponseCache::default();
+        cache.insert("/stats".to_string(), entry(&cache, "{}"));
+        assert!(cache.get("/stats").is_some());
+        assert!(cache.get("/stats?since=24h").is_none());
+
+        cache.invalidate();
+        assert!(cache.get("/stats").is_none());
+    }
+}
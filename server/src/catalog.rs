@@ -0,0 +1,409 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use seekwel::{connection::Connection, error::Error as SeekwelError, rusqlite::params};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::{
+    audit::{self, AuditContext},
+    AppState, Database, ErrorResponse, SendRequest,
+};
+
+/// Title and body of a catalog message in one locale.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct Translation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+}
+
+/// A message's translations, keyed by normalized locale (`en`, `pt-br`).
+#[derive(Debug, Serialize)]
+pub struct Message {
+    pub key: String,
+    pub locales: BTreeMap<String, Translation>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MessagesResponse {
+    messages: Vec<Message>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateMessageRequest {
+    locales: BTreeMap<String, Translation>,
+}
+
+impl Message {
+    /// The catalog locale `locale` resolves to, trying the exact locale
+    /// before its language (`fr-ca` falls back to `fr`).
+    pub(crate) fn resolved_locale(&self, locale: Option<&str>) -> Option<&str> {
+        let locale = locale?;
+        let language = locale.split('-').next().unwrap_or(locale);
+        [locale, language]
+            .into_iter()
+            .find_map(|candidate| self.locales.get_key_value(candidate))
+            .map(|(resolved, _)| resolved.as_str())
+    }
+
+    /// The request a device in `locale` receives: the catalog's title and
+    /// body when it has a translation, otherwise loc-keys named after the
+    /// message for the app to resolve from its own strings.
+    pub(crate) fn localize(&self, req: &SendRequest, locale: Option<&str>) -> SendRequest {
+        let mut localized = req.clone();
+        match self
+            .resolved_locale(locale)
+            .map(|resolved| &self.locales[resolved])
+        {
+            Some(translation) => {
+                localized.title = translation.title.clone().or(localized.title);
+                localized.body = translation.body.clone().or(localized.body);
+            }
+            None => {
+                localized.loc_key = localized.loc_key.or_else(|| Some(self.key.clone()));
+                if self.locales.values().any(|t| t.title.is_some()) {
+                    localized.title_loc_key = localized
+                        .title_loc_key
+                        .or_else(|| Some(format!("{}.title", self.key)));
+                }
+            }
+        }
+        localized
+    }
+}
+
+/// Lowercases a locale identifier and uses `-` as the separator, dropping
+/// any `@` keywords (`en_US@calendar=gregorian` becomes `en-us`).
+pub(crate) fn normalize_locale(locale: &str) -> Option<String> {
+    let locale = locale.split('@').next().unwrap_or_default().trim();
+    let valid = !locale.is_empty()
+        && locale.len() <= 35
+        && locale
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    valid.then(|| locale.replace('_', "-").to_ascii_lowercase())
+}
+
+impl Database {
+    pub(crate) fn create_messages_table(conn: &Connection) -> Result<(), SeekwelError> {
+        conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS messages (
+                message_key TEXT NOT NULL,
+                locale TEXT NOT NULL,
+                title TEXT,
+                body TEXT,
+                updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                PRIMARY KEY (message_key, locale)
+            )
+            "#,
+            (),
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn messages() -> Result<Vec<Message>, SeekwelError> {
+        let rows = Connection::get()?.query_all(
+            "SELECT message_key, locale, title, body FROM messages ORDER BY message_key, locale",
+            (),
+            |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    Translation {
+                        title: row.get(2)?,
+                        body: row.get(3)?,
+                    },
+                ))
+            },
+        )?;
+
+        let mut messages: Vec<Message> = Vec::new();
+        for (key, locale, translation) in rows {
+            match messages.last_mut() {
+                Some(message) if message.key == key => {
+                    message.locales.insert(locale, translation);
+                }
+                _ => messages.push(Message {
+                    key,
+                    locales: BTreeMap::from([(locale, translation)]),
+                }),
+            }
+        }
+        Ok(messages)
+    }
+
+    pub(crate) fn message(key: &str) -> Result<Option<Message>, SeekwelError> {
+        let locales: Vec<(String, Translation)> = Connection::get()?.query_all(
+            "SELECT locale, title, body FROM messages WHERE message_key = ?1 ORDER BY locale",
+            params![key],
+            |row| {
+                Ok((
+                    row.get(0)?,
+                    Translation {
+                        title: row.get(1)?,
+                        body: row.get(2)?,
+                    },
+                ))
+            },
+        )?;
+        if locales.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Message {
+            key: key.to_string(),
+            locales: locales.into_iter().collect(),
+        }))
+    }
+
+    /// Replaces every translation of `key` with `locales`.
+    fn save_message(
+        key: &str,
+        locales: &BTreeMap<String, Translation>,
+    ) -> Result<(), SeekwelError> {
+        let conn = Connection::get()?;
+        Connection::transaction(|| {
+            conn.execute("DELETE FROM messages WHERE message_key = ?1", params![key])?;
+            for (locale, translation) in locales {
+                conn.execute(
+                    "INSERT INTO messages (message_key, locale, title, body) VALUES (?1, ?2, ?3, ?4)",
+                    params![key, locale, translation.title, translation.body],
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    fn delete_message(key: &str) -> Result<(), SeekwelError> {
+        Connection::get()?.execute("DELETE FROM messages WHERE message_key = ?1", params![key])?;
+        Ok(())
+    }
+}
+
+fn is_valid_message_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= 128
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+fn database_error(e: SeekwelError) -> (StatusCode, Json<ErrorResponse>) {
+    tracing::error!(error = %e, "Database error handling message catalog");
+    ErrorResponse::with_status(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Database error: {e}"),
+    )
+}
+
+pub(crate) fn message_not_found(key: &str) -> (StatusCode, Json<ErrorResponse>) {
+    ErrorResponse::with_status(StatusCode::NOT_FOUND, format!("Message not found: {key}"))
+}
+
+/// Normalizes the request's locales, rejecting invalid or duplicate ones
+/// and translations with neither title nor body.
+fn normalize_locales(
+    locales: BTreeMap<String, Translation>,
+) -> Result<BTreeMap<String, Translation>, String> {
+    if locales.is_empty() {
+        return Err("A message needs at least one locale".to_string());
+    }
+    let mut normalized = BTreeMap::new();
+    for (locale, translation) in locales {
+        let Some(key) = normalize_locale(&locale) else {
+            return Err(format!("Invalid locale: {locale}"));
+        };
+        if translation.title.is_none() && translation.body.is_none() {
+            return Err(format!("Locale {locale} needs a title or body"));
+        }
+        if normalized.insert(key, translation).is_some() {
+            return Err(format!("Locale {locale} is listed more than once"));
+        }
+    }
+    Ok(normalized)
+}
+
+pub async fn list_messages(
+    State(_state): State<AppState>,
+) -> Result<Json<MessagesResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let messages = Database::messages().map_err(database_error)?;
+    Ok(Json(MessagesResponse { messages }))
+}
+
+pub async fn get_message(
+    State(_state): State<AppState>,
+    Path(key): Path<String>,
+) -> Result<Json<Message>, (StatusCode, Json<ErrorResponse>)> {
+    match Database::message(&key).map_err(database_error)? {
+        Some(message) => Ok(Json(message)),
+        None => Err(message_not_found(&key)),
+    }
+}
+
+pub async fn update_message(
+    State(_state): State<AppState>,
+    audit: AuditContext,
+    Path(key): Path<String>,
+    Json(req): Json<UpdateMessageRequest>,
+) -> Result<Json<Message>, (StatusCode, Json<ErrorResponse>)> {
+    if !is_valid_message_key(&key) {
+        return Err(ErrorResponse::with_status(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Message keys must be 1-128 characters of letters, digits, '-', '_' or '.'",
+        ));
+    }
+    let locales = normalize_locales(req.locales)
+        .map_err(|e| ErrorResponse::with_status(StatusCode::UNPROCESSABLE_ENTITY, e))?;
+
+    tracing::info!(message_key = %key, locales = locales.len(), "Saving message");
+    Database::save_message(&key, &locales).map_err(database_error)?;
+    audit::record(
+        &audit,
+        "message.update",
+        format!(
+            "message_key={key} locales={}",
+            locales.keys().cloned().collect::<Vec<_>>().join(",")
+        ),
+    );
+    let message = Database::message(&key)
+        .map_err(database_error)?
+        .ok_or_else(|| message_not_found(&key))?;
+    Ok(Json(message))
+}
+
+pub async fn delete_message(
+    State(_state): State<AppState>,
+    audit: AuditContext,
+    Path(key): Path<String>,
+) -> Result<StatusCode, (StatusCode, Json<ErrorResponse>)> {
+    if Database::message(&key).map_err(database_error)?.is_none() {
+        return Err(message_not_found(&key));
+    }
+
+    tracing::info!(message_key = %key, "Deleting message");
+    Database::delete_message(&key).map_err(database_error)?;
+    audit::record(&audit, "message.delete", format!("message_key={key}"));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_db;
+
+    fn translation(title: Option<&str>, body: &str) -> Translation {
+        Translation {
+            title: title.map(str::to_string),
+            body: Some(body.to_string()),
+        }
+    }
+
+    fn welcome() -> Message {
+        Message {
+            key: "welcome".to_string(),
+            locales: BTreeMap::from([
+                ("en".to_string(), translation(Some("Welcome"), "Thanks!")),
+                (
+                    "pt-br".to_string(),
+                    translation(Some("Bem-vindo"), "Obrigado!"),
+                ),
+            ]),
+        }
+    }
+
+    #[test]
+    fn test_normalize_locale() {
+        assert_eq!(normalize_locale("en_US").as_deref(), Some("en-us"));
+        assert_eq!(normalize_locale("pt-BR").as_deref(), Some("pt-br"));
+        assert_eq!(
+            normalize_locale("en_US@calendar=gregorian").as_deref(),
+            Some("en-us")
+        );
+        assert_eq!(normalize_locale(""), None);
+        assert_eq!(normalize_locale("en US"), None);
+    }
+
+    #[test]
+    fn test_localize_uses_exact_then_language_translation() {
+        let message = welcome();
+        let req = SendRequest::default();
+
+        let localized = message.localize(&req, Some("pt-br"));
+        assert_eq!(localized.title.as_deref(), Some("Bem-vindo"));
+        assert_eq!(localized.body.as_deref(), Some("Obrigado!"));
+        assert_eq!(localized.loc_key, None);
+
+        let localized = message.localize(&req, Some("en-gb"));
+        assert_eq!(localized.title.as_deref(), Some("Welcome"));
+        assert_eq!(message.resolved_locale(Some("en-gb")), Some("en"));
+        assert_eq!(message.resolved_locale(Some("pt")), None);
+    }
+
+    #[test]
+    fn test_localize_falls_back_to_loc_keys() {
+        let message = welcome();
+        let req = SendRequest {
+            body: Some("fallback".to_string()),
+            ..Default::default()
+        };
+
+        for locale in [None, Some("de")] {
+            let localized = message.localize(&req, locale);
+            assert_eq!(localized.loc_key.as_deref(), Some("welcome"));
+            assert_eq!(localized.title_loc_key.as_deref(), Some("welcome.title"));
+            assert_eq!(localized.body.as_deref(), Some("fallback"));
+        }
+    }
+
+    #[test]
+    fn test_normalize_locales_rejects_bad_input() {
+        let valid = BTreeMap::from([("en_US".to_string(), translation(None, "Hi"))]);
+        assert_eq!(
+            normalize_locales(valid).unwrap().keys().collect::<Vec<_>>(),
+            ["en-us"]
+        );
+
+        assert!(normalize_locales(BTreeMap::new()).is_err());
+        let empty = BTreeMap::from([("en".to_string(), Translation::default())]);
+        assert!(normalize_locales(empty).is_err());
+        let duplicate = BTreeMap::from([
+            ("en-US".to_string(), translation(None, "Hi")),
+            ("en_us".to_string(), translation(None, "Hi")),
+        ]);
+        assert!(normalize_locales(duplicate).is_err());
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let _db = test_db();
+        Database::save_message("welcome", &welcome().locales).unwrap();
+        Database::save_message(
+            "goodbye",
+            &BTreeMap::from([("en".to_string(), translation(None, "Bye"))]),
+        )
+        .unwrap();
+
+        let message = Database::message("welcome").unwrap().unwrap();
+        assert_eq!(message.locales, welcome().locales);
+
+        let messages = Database::messages().unwrap();
+        let keys: Vec<_> = messages.iter().map(|m| m.key.as_str()).collect();
+        assert_eq!(keys, ["goodbye", "welcome"]);
+        assert_eq!(messages[1].locales.len(), 2);
+
+        Database::save_message(
+            "welcome",
+            &BTreeMap::from([("fr".to_string(), translation(None, "Merci"))]),
+        )
+        .unwrap();
+        let message = Database::message("welcome").unwrap().unwrap();
+        assert_eq!(message.locales.keys().collect::<Vec<_>>(), ["fr"]);
+
+        Database::delete_message("welcome").unwrap();
+        assert!(Database::message("welcome").unwrap().is_none());
+    }
+}
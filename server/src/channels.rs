@@ -0,0 +1,578 @@
+use async_trait::async_trait;
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, RawQuery, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use p256::{
+    ecdsa::{signature::Signer, Signature, SigningKey},
+    pkcs8::DecodePrivateKey,
+};
+use reqwest::header::{HeaderName, AUTHORIZATION, CONTENT_TYPE};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    env, fs,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    apns::{self, ApnsPriority, TOKEN_REFRESH_AFTER},
+    audit::{self, AuditContext},
+    parse_send_request, unix_now, validate_send_request, AppState, Environment, ErrorResponse,
+    SendRequest,
+};
+
+const CHANNEL_ID: HeaderName = HeaderName::from_static("apns-channel-id");
+const REQUEST_ID: HeaderName = HeaderName::from_static("apns-request-id");
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Whether APNs keeps a channel's latest message for devices that were
+/// offline when it was published.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageStoragePolicy {
+    #[default]
+    NoStorage,
+    MostRecent,
+}
+
+impl MessageStoragePolicy {
+    fn as_u8(&self) -> u8 {
+        match self {
+            Self::NoStorage => 0,
+            Self::MostRecent => 1,
+        }
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::MostRecent,
+            _ => Self::NoStorage,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChannelRecord {
+    channel_id: String,
+    environment: &'static str,
+    message_storage_policy: MessageStoragePolicy,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChannelsResponse {
+    environment: &'static str,
+    channels: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChannelDeletedResponse {
+    success: bool,
+    channel_id: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChannelSendResponse {
+    success: bool,
+    channel_id: String,
+    environment: &'static str,
+    apns_request_id: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ChannelQuery {
+    environment: Option<Environment>,
+}
+
+impl ChannelQuery {
+    /// Channels belong to one APNs environment; production unless asked.
+    fn environment(&self) -> Environment {
+        self.environment.unwrap_or(Environment::Production)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateChannelRequest {
+    environment: Option<Environment>,
+    #[serde(default)]
+    message_storage_policy: MessageStoragePolicy,
+}
+
+/// Why APNs refused a channel request, with the status to answer with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ChannelError {
+    pub(crate) status: StatusCode,
+    pub(crate) reason: String,
+}
+
+impl ChannelError {
+    pub(crate) fn not_found() -> Self {
+        Self {
+            status: StatusCode::NOT_FOUND,
+            reason: "ChannelNotRegistered".to_string(),
+        }
+    }
+
+    /// APNs' own client errors pass through; anything else is a bad gateway.
+    fn from_apns(status: reqwest::StatusCode, body: &str) -> Self {
+        let reason = serde_json::from_str::<Value>(body)
+            .ok()
+            .and_then(|body| body["reason"].as_str().map(str::to_string))
+            .unwrap_or_else(|| status.to_string());
+        let status = match status.as_u16() {
+            400 | 404 | 410 | 413 | 429 => {
+                StatusCode::from_u16(status.as_u16()).unwrap_or(StatusCode::BAD_GATEWAY)
+            }
+            _ => StatusCode::BAD_GATEWAY,
+        };
+        Self { status, reason }
+    }
+
+    fn unreachable(e: reqwest::Error) -> Self {
+        Self {
+            status: StatusCode::BAD_GATEWAY,
+            reason: format!("Cannot reach APNs: {e}"),
+        }
+    }
+
+    fn into_response(self) -> (StatusCode, Json<ErrorResponse>) {
+        ErrorResponse::with_status(self.status, self.reason)
+    }
+}
+
+/// Apple's broadcast channels, which deliver one published Live Activity
+/// update to every device subscribed to the channel.
+#[async_trait]
+pub(crate) trait Broadcaster: Send + Sync {
+    async fn create_channel(
+        &self,
+        environment: Environment,
+        policy: MessageStoragePolicy,
+    ) -> Result<String, ChannelError>;
+
+    async fn list_channels(&self, environment: Environment) -> Result<Vec<String>, ChannelError>;
+
+    async fn channel_policy(
+        &self,
+        environment: Environment,
+        channel_id: &str,
+    ) -> Result<MessageStoragePolicy, ChannelError>;
+
+    async fn delete_channel(
+        &self,
+        environment: Environment,
+        channel_id: &str,
+    ) -> Result<(), ChannelError>;
+
+    /// Publishes `req` to the channel, returning APNs' request id.
+    async fn publish(
+        &self,
+        environment: Environment,
+        channel_id: &str,
+        req: &SendRequest,
+    ) -> Result<String, ChannelError>;
+}
+
+/// Talks to APNs' channel management and broadcast endpoints with the same
+/// `.p8` key as the per-device clients.
+pub struct ApnsBroadcaster {
+    client: reqwest::Client,
+    signing_key: SigningKey,
+    key_id: String,
+    team_id: String,
+    bundle_id: String,
+    /// The provider token and when it was signed.
+    token: Mutex<Option<(String, Instant)>>,
+}
+
+impl ApnsBroadcaster {
+    pub fn from_env() -> Result<Self, String> {
+        let var = |name: &str| env::var(name).map_err(|_| format!("{name} must be set"));
+        let key_path = var("APNS_KEY_PATH")?;
+        let key_pem = fs::read_to_string(&key_path)
+            .map_err(|e| format!("Cannot read APNs key {key_path}: {e}"))?;
+        let signing_key = SigningKey::from_pkcs8_pem(&key_pem)
+            .map_err(|e| format!("APNs key {key_path} is not a P-256 private key: {e}"))?;
+        let client = reqwest::Client::builder()
+            .http2_prior_knowledge()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .map_err(|e| format!("Cannot build APNs broadcast client: {e}"))?;
+        Ok(Self {
+            client,
+            signing_key,
+            key_id: var("APNS_KEY_ID")?,
+            team_id: var("APNS_TEAM_ID")?,
+            bundle_id: var("APNS_TOPIC")?,
+            token: Mutex::new(None),
+        })
+    }
+
+    /// The signed provider token, minting a new one when it's near expiry.
+    fn bearer(&self) -> String {
+        let mut token = self.token.lock().unwrap_or_else(|e| e.into_inner());
+        match token.as_ref() {
+            Some((jwt, issued_at)) if issued_at.elapsed() < TOKEN_REFRESH_AFTER => {
+                format!("bearer {jwt}")
+            }
+            _ => {
+                let jwt = self.sign(unix_now());
+                *token = Some((jwt.clone(), Instant::now()));
+                format!("bearer {jwt}")
+            }
+        }
+    }
+
+    fn sign(&self, now: u64) -> String {
+        let header =
+            URL_SAFE_NO_PAD.encode(json!({"alg": "ES256", "kid": self.key_id}).to_string());
+        let claims = URL_SAFE_NO_PAD.encode(json!({"iss": self.team_id, "iat": now}).to_string());
+        let signing_input = format!("{header}.{claims}");
+        let signature: Signature = self.signing_key.sign(signing_input.as_bytes());
+        format!(
+            "{signing_input}.{}",
+            URL_SAFE_NO_PAD.encode(signature.to_bytes())
+        )
+    }
+
+    fn manage_url(&self, environment: Environment, path: &str) -> String {
+        let host = match environment {
+            Environment::Sandbox => "api-manage-broadcast.sandbox.push.apple.com:2195",
+            Environment::Production => "api-manage-broadcast.push.apple.com:2196",
+        };
+        format!("https://{host}/1/apps/{}/{path}", self.bundle_id)
+    }
+
+    async fn request(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, ChannelError> {
+        let response = request
+            .header(AUTHORIZATION, self.bearer())
+            .send()
+            .await
+            .map_err(ChannelError::unreachable)?;
+        if response.status().is_success() {
+            return Ok(response);
+        }
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        Err(ChannelError::from_apns(status, &body))
+    }
+}
+
+async fn json_body(response: reqwest::Response) -> Result<Value, ChannelError> {
+    let text = response.text().await.map_err(ChannelError::unreachable)?;
+    serde_json::from_str(&text).map_err(|e| ChannelError {
+        status: StatusCode::BAD_GATEWAY,
+        reason: format!("Invalid response from APNs: {e}"),
+    })
+}
+
+fn header(response: &reqwest::Response, name: &HeaderName) -> Option<String> {
+    response
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+#[async_trait]
+impl Broadcaster for ApnsBroadcaster {
+    async fn create_channel(
+        &self,
+        environment: Environment,
+        policy: MessageStoragePolicy,
+    ) -> Result<String, ChannelError> {
+        let body = json!({
+            "message-storage-policy": policy.as_u8(),
+            "push-type": "LiveActivity",
+        });
+        let response = self
+            .request(
+                self.client
+                    .post(self.manage_url(environment, "channels"))
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(body.to_string()),
+            )
+            .await?;
+        header(&response, &CHANNEL_ID).ok_or_else(|| ChannelError {
+            status: StatusCode::BAD_GATEWAY,
+            reason: "APNs created a channel without returning its id".to_string(),
+        })
+    }
+
+    async fn list_channels(&self, environment: Environment) -> Result<Vec<String>, ChannelError> {
+        let response = self
+            .request(
+                self.client
+                    .get(self.manage_url(environment, "all-channels")),
+            )
+            .await?;
+        let body = json_body(response).await?;
+        Ok(body["channels"]
+            .as_array()
+            .map(|ids| {
+                ids.iter()
+                    .filter_map(|id| id.as_str().map(str::to_string))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn channel_policy(
+        &self,
+        environment: Environment,
+        channel_id: &str,
+    ) -> Result<MessageStoragePolicy, ChannelError> {
+        let response = self
+            .request(
+                self.client
+                    .get(self.manage_url(environment, "channels"))
+                    .header(CHANNEL_ID, channel_id),
+            )
+            .await?;
+        let body = json_body(response).await?;
+        Ok(MessageStoragePolicy::from_u8(
+            body["message-storage-policy"].as_u64().unwrap_or(0) as u8,
+        ))
+    }
+
+    async fn delete_channel(
+        &self,
+        environment: Environment,
+        channel_id: &str,
+    ) -> Result<(), ChannelError> {
+        self.request(
+            self.client
+                .delete(self.manage_url(environment, "channels"))
+                .header(CHANNEL_ID, channel_id),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn publish(
+        &self,
+        environment: Environment,
+        channel_id: &str,
+        req: &SendRequest,
+    ) -> Result<String, ChannelError> {
+        let host = match environment {
+            Environment::Sandbox => "api.sandbox.push.apple.com",
+            Environment::Production => "api.push.apple.com",
+        };
+        let mut request = self
+            .client
+            .post(format!(
+                "https://{host}/4/broadcasts/apps/{}",
+                self.bundle_id
+            ))
+            .header(CHANNEL_ID, channel_id)
+            .header("apns-push-type", "liveactivity")
+            .header(CONTENT_TYPE, "application/json")
+            .body(apns::payload_json(req).to_string());
+        if let Some(priority) = req.priority.and_then(|p| ApnsPriority::from_u8(p).ok()) {
+            request = request.header("apns-priority", priority.as_u8().to_string());
+        }
+        if let Some(expiration) = req.expiration {
+            request = request.header("apns-expiration", expiration.to_string());
+        }
+        let response = self.request(request).await?;
+        Ok(header(&response, &REQUEST_ID).unwrap_or_default())
+    }
+}
+
+fn broadcaster(state: &AppState) -> Result<&dyn Broadcaster, (StatusCode, Json<ErrorResponse>)> {
+    state.broadcaster.as_deref().ok_or_else(|| {
+        ErrorResponse::with_status(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Broadcast channels need APNs credentials",
+        )
+    })
+}
+
+/// Channel ids are base64 from APNs; anything else would only be rejected
+/// there, or break the header it goes in.
+fn check_channel_id(channel_id: &str) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+    let valid = !channel_id.is_empty()
+        && channel_id.len() <= 128
+        && channel_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '/' | '=' | '-' | '_'));
+    if valid {
+        Ok(())
+    } else {
+        Err(ErrorResponse::with_status(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("Invalid channel id '{channel_id}'"),
+        ))
+    }
+}
+
+/// `POST /channels`: creates a Live Activity broadcast channel.
+pub async fn create_channel(
+    State(state): State<AppState>,
+    audit: AuditContext,
+    Json(req): Json<CreateChannelRequest>,
+) -> Result<Json<ChannelRecord>, (StatusCode, Json<ErrorResponse>)> {
+    let environment = req.environment.unwrap_or(Environment::Production);
+    let channel_id = broadcaster(&state)?
+        .create_channel(environment, req.message_storage_policy)
+        .await
+        .map_err(|e| {
+            tracing::error!(environment = environment.as_str(), reason = %e.reason, "Failed to create channel");
+            e.into_response()
+        })?;
+    tracing::info!(channel_id = %channel_id, environment = environment.as_str(), "Channel created");
+    audit::record(
+        &audit,
+        "channel.create",
+        format!(
+            "channel_id={channel_id} environment={}",
+            environment.as_str()
+        ),
+    );
+    Ok(Json(ChannelRecord {
+        channel_id,
+        environment: environment.as_str(),
+        message_storage_policy: req.message_storage_policy,
+    }))
+}
+
+/// `GET /channels`: the app's channels in one environment.
+pub async fn list_channels(
+    State(state): State<AppState>,
+    Query(query): Query<ChannelQuery>,
+) -> Result<Json<ChannelsResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let environment = query.environment();
+    let channels = broadcaster(&state)?
+        .list_channels(environment)
+        .await
+        .map_err(ChannelError::into_response)?;
+    Ok(Json(ChannelsResponse {
+        environment: environment.as_str(),
+        channels,
+    }))
+}
+
+pub async fn get_channel(
+    State(state): State<AppState>,
+    Path(channel_id): Path<String>,
+    Query(query): Query<ChannelQuery>,
+) -> Result<Json<ChannelRecord>, (StatusCode, Json<ErrorResponse>)> {
+    check_channel_id(&channel_id)?;
+    let environment = query.environment();
+    let message_storage_policy = broadcaster(&state)?
+        .channel_policy(environment, &channel_id)
+        .await
+        .map_err(ChannelError::into_response)?;
+    Ok(Json(ChannelRecord {
+        channel_id,
+        environment: environment.as_str(),
+        message_storage_policy,
+    }))
+}
+
+pub async fn delete_channel(
+    State(state): State<AppState>,
+    audit: AuditContext,
+    Path(channel_id): Path<String>,
+    Query(query): Query<ChannelQuery>,
+) -> Result<Json<ChannelDeletedResponse>, (StatusCode, Json<ErrorResponse>)> {
+    check_channel_id(&channel_id)?;
+    let environment = query.environment();
+    broadcaster(&state)?
+        .delete_channel(environment, &channel_id)
+        .await
+        .map_err(ChannelError::into_response)?;
+    tracing::info!(channel_id = %channel_id, environment = environment.as_str(), "Channel deleted");
+    audit::record(
+        &audit,
+        "channel.delete",
+        format!(
+            "channel_id={channel_id} environment={}",
+            environment.as_str()
+        ),
+    );
+    Ok(Json(ChannelDeletedResponse {
+        success: true,
+        channel_id,
+    }))
+}
+
+/// `POST /channels/:id/send`: publishes one update to every device
+/// subscribed to the channel. Takes the same body as `/send`, less the
+/// targeting, and records no per-device history since APNs fans it out.
+pub async fn send_to_channel(
+    State(state): State<AppState>,
+    audit: AuditContext,
+    Path(channel_id): Path<String>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<ChannelSendResponse>, (StatusCode, Json<ErrorResponse>)> {
+    check_channel_id(&channel_id)?;
+    let environment = serde_urlencoded::from_str::<ChannelQuery>(query.as_deref().unwrap_or(""))
+        .map(|query| query.environment())
+        .unwrap_or(Environment::Production);
+    let mut req = parse_send_request(&headers, None, &body)?;
+    validate_send_request(&mut req)?;
+    if req.filter.is_some() || req.segment.is_some() || req.sample_percent.is_some() {
+        return Err(ErrorResponse::with_status(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "A channel send reaches every subscriber; filter, segment and sample_percent don't apply",
+        ));
+    }
+
+    let apns_request_id = broadcaster(&state)?
+        .publish(environment, &channel_id, &req)
+        .await
+        .map_err(|e| {
+            tracing::warn!(channel_id = %channel_id, reason = %e.reason, "Channel send rejected");
+            e.into_response()
+        })?;
+    tracing::info!(channel_id = %channel_id, apns_request_id = %apns_request_id, "Published to channel");
+    audit::record(
+        &audit,
+        "channel.send",
+        format!(
+            "channel_id={channel_id} environment={} apns_request_id={apns_request_id}",
+            environment.as_str()
+        ),
+    );
+    Ok(Json(ChannelSendResponse {
+        success: true,
+        channel_id,
+        environment: environment.as_str(),
+        apns_request_id,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel_error_from_apns() {
+        let error = ChannelError::from_apns(
+            reqwest::StatusCode::NOT_FOUND,
+            r#"{"reason": "ChannelNotRegistered"}"#,
+        );
+        assert_eq!(error, ChannelError::not_found());
+        let error = ChannelError::from_apns(reqwest::StatusCode::FORBIDDEN, "");
+        assert_eq!(error.status, StatusCode::BAD_GATEWAY);
+        assert_eq!(error.reason, "403 Forbidden");
+    }
+
+    #[test]
+    fn test_check_channel_id() {
+        assert!(check_channel_id("dHN0LXNyY2gtY2hubA==").is_ok());
+        assert!(check_channel_id("").is_err());
+        assert!(check_channel_id("a\r\nb").is_err());
+    }
+}
@@ -0,0 +1,457 @@
+use serde::{Deserialize, Serialize};
+use std::{env, fs, path::PathBuf};
+
+/// Settings read from `server.toml`, for what doesn't fit an environment
+/// variable. Everything is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    pub log: Option<LogConfig>,
+    /// `[[api_keys]]`: when any are set, requests need one of them.
+    #[serde(default)]
+    pub api_keys: Vec<ApiKeyConfig>,
+    #[serde(default)]
+    pub lanes: LanesConfig,
+    #[serde(default)]
+    pub apns_pacing: ApnsPacingConfig,
+    #[serde(default)]
+    pub apns_circuit: ApnsCircuitConfig,
+    #[serde(default)]
+    pub truncation: TruncationConfig,
+    pub mqtt: Option<MqttConfig>,
+    pub token_encryption: Option<TokenEncryptionConfig>,
+    pub failure_alerts: Option<FailureAlertsConfig>,
+    pub cors: Option<CorsConfig>,
+}
+
+/// `[lanes]`: how many deliveries each dispatch lane runs at once, and how
+/// many devices make a send bulk.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct LanesConfig {
+    pub critical: usize,
+    pub normal: usize,
+    pub bulk: usize,
+    pub bulk_threshold: usize,
+}
+
+impl Default for LanesConfig {
+    fn default() -> Self {
+        Self {
+            critical: 32,
+            normal: 8,
+            bulk: 2,
+            bulk_threshold: 1000,
+        }
+    }
+}
+
+/// `[apns_pacing]`: the most requests a second psh makes to each APNs
+/// endpoint, with 0 for no limit. When APNs answers `TooManyRequests`, the
+/// endpoint's rate halves and then climbs back over the following seconds.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct ApnsPacingConfig {
+    pub sandbox: u32,
+    pub production: u32,
+    /// Requests that may go at once after a quiet spell.
+    pub burst: u32,
+}
+
+impl Default for ApnsPacingConfig {
+    fn default() -> Self {
+        Self {
+            sandbox: 1000,
+            production: 1000,
+            burst: 50,
+        }
+    }
+}
+
+/// `[apns_circuit]`: how many requests to an APNs endpoint fail in a row,
+/// because APNs is down or refusing the credentials, before sends to it
+/// fail fast, and for how long before it's tried again. `failures = 0`
+/// turns the breaker off.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct ApnsCircuitConfig {
+    pub failures: u32,
+    /// e.g. `30s`; doubles, up to five minutes, each time a probe fails.
+    pub open_for: String,
+}
+
+impl Default for ApnsCircuitConfig {
+    fn default() -> Self {
+        Self {
+            failures: 10,
+            open_for: "30s".to_string(),
+        }
+    }
+}
+
+/// `[truncation]`: how a body too long for APNs is cut rather than the
+/// push failing with `PayloadTooLarge`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct TruncationConfig {
+    /// Cuts bodies to at most this many bytes even when the payload fits.
+    pub max_body_bytes: Option<usize>,
+    /// Ends a cut body.
+    pub ellipsis: String,
+    /// Keeps the uncut body in push history, for `GET /pushes/:id`.
+    pub store_full_body: bool,
+}
+
+impl Default for TruncationConfig {
+    fn default() -> Self {
+        Self {
+            max_body_bytes: None,
+            ellipsis: "…".to_string(),
+            store_full_body: false,
+        }
+    }
+}
+
+/// `[mqtt]`: subscribe to a broker and send what's published on `topics`
+/// as pushes.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    /// `mqtt://[user[:password]@]host[:port]`.
+    pub url: String,
+    /// Defaults to `psh-` and the instance id.
+    pub client_id: Option<String>,
+    pub topics: Vec<MqttTopicConfig>,
+}
+
+/// `[[mqtt.topics]]`: where a message published on `subscribe` goes.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttTopicConfig {
+    /// An MQTT topic filter, with `+` and `#` wildcards.
+    pub subscribe: String,
+    /// The psh topic whose subscribers get the push; unset sends to every
+    /// device.
+    pub topic: Option<String>,
+}
+
+/// `[token_encryption]`: seal device tokens in the database with a key
+/// given as `key`, or printed by `key_command`, base64 of 32 bytes.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TokenEncryptionConfig {
+    pub key: Option<String>,
+    /// Run with `sh -c` at startup, to fetch the key from a KMS or secret
+    /// manager instead of keeping it in this file.
+    pub key_command: Option<String>,
+}
+
+/// `[failure_alerts]`: warn when too many pushes fail, by a push to
+/// `topic` or `device_token`, or a POST to `webhook_url`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FailureAlertsConfig {
+    /// The share of pushes, 0 to 1, that failing raises an alert.
+    #[serde(default = "default_failure_threshold")]
+    pub threshold: f64,
+    /// How far back the failure rate looks, e.g. `10m`.
+    #[serde(default = "default_failure_window")]
+    pub window: String,
+    /// Fewer pushes than this in the window never alert.
+    #[serde(default = "default_min_pushes")]
+    pub min_pushes: u64,
+    pub topic: Option<String>,
+    pub device_token: Option<String>,
+    /// Gets a JSON `POST`, with a `text` field for Slack-style webhooks.
+    /// Reaches you when pushes themselves don't.
+    pub webhook_url: Option<String>,
+}
+
+/// `[cors]`: let pages on `allowed_origins` call the API from the browser,
+/// for a dashboard or internal tool hosted elsewhere.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CorsConfig {
+    /// e.g. `https://dashboard.example.com`, or `*` for any origin.
+    pub allowed_origins: Vec<String>,
+    /// Defaults to `GET` and `HEAD`, the read endpoints.
+    #[serde(default = "default_cors_methods")]
+    pub allowed_methods: Vec<String>,
+    /// Request headers pages may set. Defaults to `authorization`, for API
+    /// keys, and `content-type`.
+    #[serde(default = "default_cors_headers")]
+    pub allowed_headers: Vec<String>,
+    /// How long browsers may reuse a preflight answer, e.g. `1h`.
+    #[serde(default = "default_cors_max_age")]
+    pub max_age: String,
+}
+
+fn default_cors_methods() -> Vec<String> {
+    vec!["GET".to_string(), "HEAD".to_string()]
+}
+
+fn default_cors_headers() -> Vec<String> {
+    vec!["authorization".to_string(), "content-type".to_string()]
+}
+
+fn default_cors_max_age() -> String {
+    "1h".to_string()
+}
+
+fn default_failure_threshold() -> f64 {
+    0.2
+}
+
+fn default_failure_window() -> String {
+    "10m".to_string()
+}
+
+fn default_min_pushes() -> u64 {
+    10
+}
+
+/// `[log]`: also write logs to rotating files under `directory`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogConfig {
+    pub directory: PathBuf,
+    /// File name before the rotation date, as in `psh.log.2024-06-01`.
+    #[serde(default = "default_file_name")]
+    pub file_name: String,
+    #[serde(default)]
+    pub rotation: LogRotation,
+    #[serde(default)]
+    pub format: LogFormat,
+    /// Rotated files to keep; older ones are deleted. Unset keeps all.
+    pub max_files: Option<usize>,
+    /// Filter for the file, in `RUST_LOG` syntax. Defaults to the console's.
+    pub level: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Minutely,
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One JSON object per line, with the request's spans.
+    #[default]
+    Json,
+    Text,
+}
+
+/// An API key and what it may do.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeyConfig {
+    /// Shown in logs and errors instead of the key.
+    pub name: String,
+    pub key: String,
+    pub role: Role,
+    /// Pushes the key may send per UTC day.
+    pub daily_quota: Option<u64>,
+    /// Pushes the key may send per UTC calendar month.
+    pub monthly_quota: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Send and preview pushes, but not list devices or read history.
+    Send,
+    /// Read devices, history, stats and settings, but not send or change
+    /// anything.
+    Read,
+    Admin,
+}
+
+fn default_file_name() -> String {
+    "psh.log".to_string()
+}
+
+impl ServerConfig {
+    /// Reads the file named by `PSH_CONFIG`, or `server.toml` in the working
+    /// directory when it exists.
+    pub fn load() -> Result<Self, String> {
+        let (path, required) = match env::var("PSH_CONFIG") {
+            Ok(path) => (PathBuf::from(path), true),
+            Err(_) => (PathBuf::from("server.toml"), false),
+        };
+        match fs::read_to_string(&path) {
+            Ok(text) => Self::parse(&text).map_err(|e| format!("{}: {e}", path.display())),
+            Err(e) if !required && e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(format!("Cannot read {}: {e}", path.display())),
+        }
+    }
+
+    fn parse(text: &str) -> Result<Self, String> {
+        toml::from_str(text).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_log_config() {
+        let config = ServerConfig::parse(
+            r#"
+            [log]
+            directory = "/var/log/psh"
+            rotation = "hourly"
+            max_files = 48
+            "#,
+        )
+        .unwrap();
+        let log = config.log.unwrap();
+        assert_eq!(log.directory, PathBuf::from("/var/log/psh"));
+        assert_eq!(log.file_name, "psh.log");
+        assert_eq!(log.rotation, LogRotation::Hourly);
+        assert_eq!(log.format, LogFormat::Json);
+        assert_eq!(log.max_files, Some(48));
+
+        assert!(ServerConfig::parse("").unwrap().log.is_none());
+        assert!(ServerConfig::parse("[log]\ndirectory = \"logs\"\nformat = \"xml\"").is_err());
+        assert!(ServerConfig::parse("[logs]\ndirectory = \"logs\"").is_err());
+    }
+
+    #[test]
+    fn test_parse_api_keys() {
+        let config = ServerConfig::parse(
+            r#"
+            [[api_keys]]
+            name = "ci"
+            key = "ci-secret"
+            role = "send"
+            daily_quota = 1000
+
+            [[api_keys]]
+            name = "grafana"
+            key = "grafana-secret"
+            role = "read"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.api_keys.len(), 2);
+        assert_eq!(config.api_keys[0].name, "ci");
+        assert_eq!(config.api_keys[0].daily_quota, Some(1000));
+        assert_eq!(config.api_keys[1].role, Role::Read);
+        assert!(config.api_keys[1].monthly_quota.is_none());
+
+        assert!(ServerConfig::parse("").unwrap().api_keys.is_empty());
+        assert!(
+            ServerConfig::parse("[[api_keys]]\nname = \"x\"\nkey = \"y\"\nrole = \"root\"")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_lanes() {
+        let config = ServerConfig::parse(
+            "[lanes]
+bulk = 1
+bulk_threshold = 500",
+        )
+        .unwrap();
+        assert_eq!(config.lanes.bulk, 1);
+        assert_eq!(config.lanes.bulk_threshold, 500);
+        assert_eq!(config.lanes.critical, LanesConfig::default().critical);
+        assert!(ServerConfig::parse(
+            "[lanes]
+urgent = 4"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_apns_pacing() {
+        let config = ServerConfig::parse(
+            "[apns_pacing]
+production = 200
+sandbox = 0",
+        )
+        .unwrap();
+        assert_eq!(config.apns_pacing.production, 200);
+        assert_eq!(config.apns_pacing.sandbox, 0);
+        assert_eq!(config.apns_pacing.burst, ApnsPacingConfig::default().burst);
+        assert!(ServerConfig::parse(
+            "[apns_pacing]
+production = -1"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_apns_circuit() {
+        let config = ServerConfig::parse("[apns_circuit]\nfailures = 3").unwrap();
+        assert_eq!(config.apns_circuit.failures, 3);
+        assert_eq!(config.apns_circuit.open_for, "30s");
+        assert!(ServerConfig::parse("[apns_circuit]\nthreshold = 3").is_err());
+    }
+
+    #[test]
+    fn test_parse_truncation() {
+        let config = ServerConfig::parse(
+            "[truncation]
+max_body_bytes = 512
+store_full_body = true",
+        )
+        .unwrap();
+        assert_eq!(config.truncation.max_body_bytes, Some(512));
+        assert_eq!(config.truncation.ellipsis, "…");
+        assert!(config.truncation.store_full_body);
+        assert_eq!(ServerConfig::default().truncation.max_body_bytes, None);
+    }
+
+    #[test]
+    fn test_parse_mqtt() {
+        let config = ServerConfig::parse(
+            r#"
+            [mqtt]
+            url = "mqtt://broker:1883"
+
+            [[mqtt.topics]]
+            subscribe = "home/+/doorbell"
+            topic = "doorbell"
+
+            [[mqtt.topics]]
+            subscribe = "alerts/#"
+            "#,
+        )
+        .unwrap();
+        let mqtt = config.mqtt.unwrap();
+        assert_eq!(mqtt.url, "mqtt://broker:1883");
+        assert!(mqtt.client_id.is_none());
+        assert_eq!(mqtt.topics[0].topic.as_deref(), Some("doorbell"));
+        assert!(mqtt.topics[1].topic.is_none());
+        assert!(ServerConfig::default().mqtt.is_none());
+        assert!(ServerConfig::parse("[mqtt]\nurl = \"mqtt://broker\"").is_err());
+    }
+
+    #[test]
+    fn test_parse_cors() {
+        let config = ServerConfig::parse(
+            r#"
+            [cors]
+            allowed_origins = ["https://dashboard.example.com"]
+            allowed_methods = ["GET", "POST"]
+            "#,
+        )
+        .unwrap();
+        let cors = config.cors.unwrap();
+        assert_eq!(cors.allowed_origins, ["https://dashboard.example.com"]);
+        assert_eq!(cors.allowed_methods, ["GET", "POST"]);
+        assert_eq!(cors.allowed_headers, ["authorization", "content-type"]);
+        assert_eq!(cors.max_age, "1h");
+        assert!(ServerConfig::default().cors.is_none());
+        assert!(ServerConfig::parse("[cors]\nmax_age = \"1h\"").is_err());
+    }
+}
@@ -0,0 +1,109 @@
+use axum::http::{HeaderName, HeaderValue, Method};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::{config::CorsConfig, duration};
+
+/// Response headers pages may read, besides the ones browsers always let
+/// through.
+const EXPOSED_HEADERS: [&str; 5] = [
+    "x-request-id",
+    "etag",
+    "retry-after",
+    "x-quota-daily-remaining",
+    "x-quota-monthly-remaining",
+];
+
+/// The CORS layer `[cors]` describes. Preflight requests are answered
+/// before authentication, since browsers send them without the API key.
+pub fn layer(config: &CorsConfig) -> Result<CorsLayer, String> {
+    let methods = config
+        .allowed_methods
+        .iter()
+        .map(|method| {
+            Method::from_bytes(method.trim().to_ascii_uppercase().as_bytes())
+                .map_err(|_| format!("Invalid [cors] method '{method}'"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let headers = config
+        .allowed_headers
+        .iter()
+        .map(|header| {
+            HeaderName::from_bytes(header.trim().as_bytes())
+                .map_err(|_| format!("Invalid [cors] header '{header}'"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let max_age = duration::parse_duration(&config.max_age).ok_or_else(|| {
+        format!(
+            "Invalid [cors] max_age '{}', expected a duration such as 1h",
+            config.max_age
+        )
+    })?;
+    Ok(CorsLayer::new()
+        .allow_origin(allow_origin(&config.allowed_origins)?)
+        .allow_methods(methods)
+        .allow_headers(headers)
+        .expose_headers(EXPOSED_HEADERS.map(HeaderName::from_static))
+        .max_age(max_age))
+}
+
+fn allow_origin(origins: &[String]) -> Result<AllowOrigin, String> {
+    match origins {
+        [] => Err("[cors] allowed_origins is empty".to_string()),
+        [any] if any.trim() == "*" => Ok(AllowOrigin::any()),
+        origins => origins
+            .iter()
+            .map(|origin| parse_origin(origin.trim()))
+            .collect::<Result<Vec<_>, _>>()
+            .map(AllowOrigin::list),
+    }
+}
+
+/// An origin as browsers send it: a scheme and host, maybe a port, and
+/// nothing after.
+fn parse_origin(origin: &str) -> Result<HeaderValue, String> {
+    let host = origin
+        .strip_prefix("https://")
+        .or_else(|| origin.strip_prefix("http://"));
+    match host {
+        Some(host) if !host.is_empty() && !host.contains('/') => HeaderValue::from_str(origin)
+            .map_err(|_| format!("Invalid [cors] origin '{origin}'")),
+        _ => Err(format!(
+            "Invalid [cors] origin '{origin}', expected a scheme and host such as https://dashboard.example.com, or *"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(origins: &[&str]) -> CorsConfig {
+        CorsConfig {
+            allowed_origins: origins.iter().map(|o| o.to_string()).collect(),
+            allowed_methods: vec!["get".to_string()],
+            allowed_headers: vec!["authorization".to_string()],
+            max_age: "1h".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_layer_checks_the_config() {
+        assert!(layer(&config(&["https://dashboard.example.com"])).is_ok());
+        assert!(layer(&config(&["http://localhost:5173", "https://a.example"])).is_ok());
+        assert!(layer(&config(&["*"])).is_ok());
+        assert!(layer(&config(&[])).is_err());
+
+        let error = layer(&config(&["https://dashboard.example.com/"])).unwrap_err();
+        assert!(error.contains("expected a scheme and host"), "{error}");
+        assert!(layer(&config(&["dashboard.example.com"])).is_err());
+        // `*` only means any origin on its own.
+        assert!(layer(&config(&["*", "https://a.example"])).is_err());
+
+        let mut bad = config(&["*"]);
+        bad.allowed_headers = vec!["x header".to_string()];
+        assert!(layer(&bad).is_err());
+        let mut bad = config(&["*"]);
+        bad.max_age = "soon".to_string();
+        assert!(layer(&bad).is_err());
+    }
+}
@@ -0,0 +1,103 @@
+use std::{env, fmt::Display, fs};
+
+use crate::{
+    apns::{self, ApnsClients},
+    Environment,
+};
+
+/// One line of the report, in the CLI's `psh doctor` layout.
+fn report(ok: bool, name: &str, detail: impl Display, fix: Option<&str>) -> String {
+    let mut line = format!(
+        "  {:<4} {:<10} {}",
+        if ok { "ok" } else { "FAIL" },
+        name,
+        detail
+    );
+    if let Some(fix) = fix {
+        line.push_str(&format!("\n       fix: {fix}"));
+    }
+    line
+}
+
+/// `server check-credentials`: checks the APNs credentials in the
+/// environment, stopping at the first problem with what to do about it. The
+/// key and IDs are checked offline, then both APNs endpoints are asked to
+/// accept a push signed with them.
+pub async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let fail = |name: &str, detail: &dyn Display, fix: Option<&str>| {
+        println!("{}", report(false, name, detail, fix));
+        format!("APNs credentials failed the {name} check")
+    };
+
+    let vars = ["APNS_KEY_PATH", "APNS_KEY_ID", "APNS_TEAM_ID", "APNS_TOPIC"]
+        .map(|name| (name, env::var(name).unwrap_or_default()));
+    let missing: Vec<&str> = vars
+        .iter()
+        .filter(|(_, value)| value.trim().is_empty())
+        .map(|(name, _)| *name)
+        .collect();
+    if !missing.is_empty() {
+        return Err(fail(
+            "env",
+            &format!("{} not set", missing.join(", ")),
+            Some("Export them, or run `server init` to write them to .env"),
+        )
+        .into());
+    }
+    let [key_path, key_id, team_id, topic] = vars.map(|(_, value)| value);
+
+    apns::check_ids(&key_id, &team_id).map_err(|e| fail("ids", &e, None))?;
+    println!(
+        "{}",
+        report(true, "ids", format!("key {key_id}, team {team_id}"), None)
+    );
+
+    let key_pem = fs::read(&key_path).map_err(|e| {
+        fail(
+            "key",
+            &format!("Cannot read {key_path}: {e}"),
+            Some("Point APNS_KEY_PATH at the .p8 file, readable by this user"),
+        )
+    })?;
+    apns::check_key(&key_pem).map_err(|e| fail("key", &format!("{key_path}: {e}"), None))?;
+    println!("{}", report(true, "key", &key_path, None));
+
+    let clients = ApnsClients::with_key(key_path, key_id, team_id, topic.clone())
+        .map_err(|e| fail("key", &e, None))?;
+    for environment in [Environment::Sandbox, Environment::Production] {
+        let name = environment.as_str();
+        match clients.check_endpoint(environment).await {
+            Ok(()) => println!(
+                "{}",
+                report(true, name, format!("accepted pushes for {topic}"), None)
+            ),
+            Err(e) => {
+                let fix = apns::remediation(e.code);
+                return Err(fail(name, &format!("{}: {}", e.code, e.message), Some(fix)).into());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        assert_eq!(
+            report(true, "sandbox", "accepted pushes for com.example", None),
+            "  ok   sandbox    accepted pushes for com.example"
+        );
+        assert_eq!(
+            report(
+                false,
+                "production",
+                "InvalidProviderToken",
+                Some("Check the key")
+            ),
+            "  FAIL production InvalidProviderToken\n       fix: Check the key"
+        );
+    }
+}
@@ -0,0 +1,112 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use seekwel::{connection::Connection, error::Error as SeekwelError, rusqlite::params};
+use sha2::{Digest, Sha256};
+use std::{env, time::Duration};
+
+use crate::{duration, Database, SendRequest};
+
+/// Reads `PSH_DEDUP_WINDOW` (e.g. `10m`), the span in which a device isn't
+/// sent the same title, body and data twice. Unset or `0` turns it off.
+pub fn window_from_env() -> Result<Option<Duration>, String> {
+    match env::var("PSH_DEDUP_WINDOW") {
+        Ok(value) => parse_window(&value),
+        Err(_) => Ok(None),
+    }
+}
+
+fn parse_window(value: &str) -> Result<Option<Duration>, String> {
+    if value.trim().is_empty() {
+        return Ok(None);
+    }
+    match duration::parse_duration(value) {
+        Some(window) if window.is_zero() => Ok(None),
+        Some(window) => Ok(Some(window)),
+        None => Err(format!(
+            "Invalid PSH_DEDUP_WINDOW '{value}', expected a duration such as 10m"
+        )),
+    }
+}
+
+/// Identifies a push's visible content: its title, body and the custom data
+/// as recorded in `payload_json`.
+pub(crate) fn content_hash(req: &SendRequest, payload_json: Option<&str>) -> String {
+    let mut hasher = Sha256::new();
+    for part in [req.title.as_deref(), req.body.as_deref(), payload_json] {
+        match part {
+            Some(part) => {
+                hasher.update([1]);
+                hasher.update((part.len() as u64).to_be_bytes());
+                hasher.update(part);
+            }
+            None => hasher.update([0]),
+        }
+    }
+    URL_SAFE_NO_PAD.encode(hasher.finalize())
+}
+
+impl Database {
+    /// Whether `device_id` was sent a push with `content_hash` within `window`.
+    #[tracing::instrument(name = "db.sent_recently", skip_all)]
+    pub(crate) fn sent_recently(
+        device_id: i64,
+        content_hash: &str,
+        window: Duration,
+    ) -> Result<bool, SeekwelError> {
+        let exists: i64 = Connection::get()?.query_row(
+            r#"
+            SELECT EXISTS(
+                SELECT 1 FROM pushes
+                WHERE device_id = ?1
+                  AND content_hash = ?2
+                  AND status = 'sent'
+                  AND sent_at >= datetime('now', ?3)
+            )
+            "#,
+            params![
+                device_id,
+                content_hash,
+                format!("-{} seconds", window.as_secs())
+            ],
+            |row| row.get(0),
+        )?;
+        Ok(exists != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(title: &str, body: &str) -> SendRequest {
+        SendRequest {
+            title: Some(title.to_string()),
+            body: Some(body.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_window() {
+        assert_eq!(parse_window("10m").unwrap(), Some(Duration::from_secs(600)));
+        assert_eq!(parse_window("0").unwrap(), None);
+        assert_eq!(parse_window("").unwrap(), None);
+        assert!(parse_window("soon").is_err());
+    }
+
+    #[test]
+    fn test_content_hash() {
+        let hash = content_hash(&request("Deploy", "done"), Some(r#"{"a":1}"#));
+        assert_eq!(
+            hash,
+            content_hash(&request("Deploy", "done"), Some(r#"{"a":1}"#))
+        );
+        assert_ne!(
+            hash,
+            content_hash(&request("Deploy", "done"), Some(r#"{"a":2}"#))
+        );
+        assert_ne!(
+            content_hash(&request("ab", "c"), None),
+            content_hash(&request("a", "bc"), None)
+        );
+    }
+}
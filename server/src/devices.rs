@@ -0,0 +1,593 @@
+use axum::{
+    extract::{Path, Query, RawQuery, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use seekwel::{connection::Connection, error::Error as SeekwelError, rusqlite::params};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::{
+    audit::{self, AuditContext},
+    catalog,
+    filter::DeviceFilter,
+    installations::InstallationActivity,
+    token, token_cipher, AppState, Database, ErrorResponse,
+};
+
+const DEFAULT_HISTORY_LIMIT: i64 = 50;
+const MAX_HISTORY_LIMIT: i64 = 500;
+
+/// A device that currently receives sends.
+#[derive(Debug, Serialize)]
+pub struct DeviceRecord {
+    device_token: String,
+    installation_id: Option<String>,
+    environment: String,
+    platform: String,
+    device_name: Option<String>,
+    device_type: Option<String>,
+    os_version: Option<String>,
+    app_version: Option<String>,
+    locale: Option<String>,
+    timezone: Option<String>,
+    enabled: bool,
+    /// When a snooze in effect ends; until then only critical pushes go out.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    snoozed_until: Option<String>,
+    /// When a registration made with `expires_in` lapses.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    tags: BTreeMap<String, String>,
+    updated_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DevicesResponse {
+    devices: Vec<DeviceRecord>,
+}
+
+/// An installation's current devices, for `GET /devices?group_by=installation`.
+#[derive(Debug, Serialize)]
+pub struct InstallationDevices {
+    installation_id: Option<String>,
+    #[serde(flatten)]
+    activity: InstallationActivity,
+    devices: Vec<DeviceRecord>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InstallationDevicesResponse {
+    installations: Vec<InstallationDevices>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DevicePushRecord {
+    id: i64,
+    apns_id: Option<String>,
+    title: Option<String>,
+    body: Option<String>,
+    interruption_level: Option<String>,
+    status: String,
+    error: Option<String>,
+    error_code: Option<String>,
+    sent_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DevicePushesResponse {
+    device_token: String,
+    pushes: Vec<DevicePushRecord>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct HistoryQuery {
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateDeviceRequest {
+    enabled: Option<bool>,
+    /// Snooze for this many seconds from now; 0 ends a snooze.
+    snooze_seconds: Option<u64>,
+}
+
+/// Longest snooze accepted, a year in seconds.
+const MAX_SNOOZE_SECONDS: u64 = 365 * 24 * 60 * 60;
+
+const DEVICE_COLUMNS: &str = "device_token, installation_id, environment, platform, device_name, \
+     device_type, os_version, app_version, locale, timezone, enabled, \
+     CASE WHEN snoozed_until > CURRENT_TIMESTAMP THEN snoozed_until END, expires_at, updated_at";
+
+fn device_record(row: &seekwel::rusqlite::Row) -> seekwel::rusqlite::Result<DeviceRecord> {
+    Ok(DeviceRecord {
+        device_token: token_cipher::open(row, 0)?,
+        installation_id: row.get(1)?,
+        environment: row.get(2)?,
+        platform: row.get(3)?,
+        device_name: row.get(4)?,
+        device_type: row.get(5)?,
+        os_version: row.get(6)?,
+        app_version: row.get(7)?,
+        locale: row.get(8)?,
+        timezone: row.get(9)?,
+        enabled: row.get(10)?,
+        snoozed_until: row.get(11)?,
+        expires_at: row.get(12)?,
+        tags: BTreeMap::new(),
+        updated_at: row.get(13)?,
+    })
+}
+
+impl Database {
+    fn devices(filter: &DeviceFilter) -> Result<Vec<DeviceRecord>, SeekwelError> {
+        let (mut conditions, values) = filter.sql_conditions();
+        conditions.insert(0, "superseded_at IS NULL");
+        let sql = format!(
+            "SELECT {DEVICE_COLUMNS} FROM devices WHERE {} ORDER BY id",
+            conditions.join(" AND ")
+        );
+
+        let devices = Connection::get()?.query_all(&sql, values.as_slice(), device_record)?;
+        Ok(devices
+            .into_iter()
+            .filter(|device| {
+                filter.matches_versions(device.os_version.as_deref(), device.app_version.as_deref())
+            })
+            .collect())
+    }
+
+    fn device(device_token: &str) -> Result<Option<DeviceRecord>, SeekwelError> {
+        Connection::get()?.query_optional(
+            &format!("SELECT {DEVICE_COLUMNS} FROM devices WHERE device_token = ?1"),
+            params![token_cipher::seal(device_token)],
+            device_record,
+        )
+    }
+
+    /// Turns sends to a device on or off, keeping its registration and
+    /// history.
+    fn set_device_enabled(device_id: i64, enabled: bool) -> Result<(), SeekwelError> {
+        Connection::get()?.execute(
+            "UPDATE devices SET enabled = ?1, updated_at = CURRENT_TIMESTAMP WHERE id = ?2",
+            params![enabled, device_id],
+        )?;
+        Ok(())
+    }
+
+    /// Holds non-critical pushes to a device for `seconds`, or ends its
+    /// snooze when 0.
+    fn snooze_device(device_id: i64, seconds: u64) -> Result<(), SeekwelError> {
+        Connection::get()?.execute(
+            r#"
+            UPDATE devices SET
+                snoozed_until = CASE WHEN ?1 > 0 THEN datetime('now', '+' || ?1 || ' seconds') END,
+                updated_at = CURRENT_TIMESTAMP
+            WHERE id = ?2
+            "#,
+            params![seconds as i64, device_id],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn device_id(device_token: &str) -> Result<Option<i64>, SeekwelError> {
+        Connection::get()?.query_optional(
+            "SELECT id FROM devices WHERE device_token = ?1",
+            params![token_cipher::seal(device_token)],
+            |row| row.get(0),
+        )
+    }
+
+    fn pushes_for_device(
+        device_id: i64,
+        limit: i64,
+    ) -> Result<Vec<DevicePushRecord>, SeekwelError> {
+        Connection::get()?.query_all(
+            r#"
+            SELECT id, apns_id, title, body, interruption_level, status, error, error_code, sent_at
+            FROM pushes
+            WHERE device_id = ?1
+            ORDER BY sent_at DESC, id DESC
+            LIMIT ?2
+            "#,
+            params![device_id, limit],
+            |row| {
+                Ok(DevicePushRecord {
+                    id: row.get(0)?,
+                    apns_id: row.get(1)?,
+                    title: row.get(2)?,
+                    body: row.get(3)?,
+                    interruption_level: row.get(4)?,
+                    status: row.get(5)?,
+                    error: row.get(6)?,
+                    error_code: row.get(7)?,
+                    sent_at: row.get(8)?,
+                })
+            },
+        )
+    }
+}
+
+fn database_error(e: SeekwelError) -> (StatusCode, Json<ErrorResponse>) {
+    tracing::error!(error = %e, "Database error handling device request");
+    ErrorResponse::with_status(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Database error: {e}"),
+    )
+}
+
+/// Normalizes the locale and time zone a device registers with, dropping
+/// unusable values with a warning rather than rejecting the registration.
+pub(crate) fn normalize_locale_and_timezone(
+    device_token: &str,
+    locale: &mut Option<String>,
+    timezone: &mut Option<String>,
+) {
+    if let Some(value) = locale.take() {
+        *locale = catalog::normalize_locale(&value);
+        if locale.is_none() {
+            tracing::warn!(device_token = %token::logged(device_token), locale = %value, "Ignoring invalid locale");
+        }
+    }
+    if let Some(value) = timezone.take() {
+        *timezone = normalize_timezone(&value);
+        if timezone.is_none() {
+            tracing::warn!(device_token = %token::logged(device_token), timezone = %value, "Ignoring invalid time zone");
+        }
+    }
+}
+
+/// Accepts IANA names like `America/Los_Angeles`, `Etc/GMT+5` or `UTC`.
+fn normalize_timezone(timezone: &str) -> Option<String> {
+    let timezone = timezone.trim();
+    let valid = !timezone.is_empty()
+        && timezone.len() <= 64
+        && timezone.starts_with(|c: char| c.is_ascii_alphabetic())
+        && timezone
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '/' | '_' | '-' | '+'));
+    valid.then(|| timezone.to_string())
+}
+
+pub(crate) fn device_not_found() -> (StatusCode, Json<ErrorResponse>) {
+    ErrorResponse::with_status(StatusCode::NOT_FOUND, "Device not found")
+}
+
+/// Splits `group_by` off a `/devices` query, returning whether to group by
+/// installation and the rest as a filter.
+fn parse_list_query(query: &str) -> Result<(bool, DeviceFilter), String> {
+    let mut pairs: Vec<(String, String)> =
+        serde_urlencoded::from_str(query).map_err(|e| e.to_string())?;
+    let group_by = pairs
+        .iter()
+        .position(|(key, _)| key == "group_by")
+        .map(|i| pairs.remove(i).1);
+    let by_installation = match group_by.as_deref() {
+        None => false,
+        Some("installation") => true,
+        Some(other) => return Err(format!("can't group devices by '{other}'")),
+    };
+    let filter = serde_urlencoded::to_string(&pairs)
+        .map_err(|e| e.to_string())
+        .and_then(|rest| serde_urlencoded::from_str(&rest).map_err(|e| e.to_string()))?;
+    Ok((by_installation, filter))
+}
+
+/// `GET /devices`: current devices matching the filter in the query.
+/// `group_by=installation` groups them under their installations, most
+/// recently active first, with each installation's pushes and activity.
+pub async fn list_devices(
+    State(_state): State<AppState>,
+    RawQuery(query): RawQuery,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let (by_installation, filter) =
+        parse_list_query(query.as_deref().unwrap_or("")).map_err(|e| {
+            ErrorResponse::with_status(StatusCode::BAD_REQUEST, format!("Invalid query: {e}"))
+        })?;
+    let mut devices = Database::devices(&filter).map_err(database_error)?;
+    let mut tags = Database::current_device_tags().map_err(database_error)?;
+    for device in &mut devices {
+        device.tags = tags.remove(&device.device_token).unwrap_or_default();
+    }
+    if !by_installation {
+        return Ok(Json(DevicesResponse { devices }).into_response());
+    }
+
+    let mut by_id: BTreeMap<Option<String>, Vec<DeviceRecord>> = BTreeMap::new();
+    for device in devices {
+        by_id
+            .entry(device.installation_id.clone())
+            .or_default()
+            .push(device);
+    }
+    let installations = Database::installations()
+        .map_err(database_error)?
+        .into_iter()
+        .filter_map(|installation| {
+            let devices = by_id.remove(&installation.installation_id)?;
+            Some(InstallationDevices {
+                installation_id: installation.installation_id,
+                activity: installation.activity,
+                devices,
+            })
+        })
+        .collect();
+    Ok(Json(InstallationDevicesResponse { installations }).into_response())
+}
+
+pub async fn get_device_pushes(
+    State(state): State<AppState>,
+    Path(device_token): Path<String>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<DevicePushesResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_HISTORY_LIMIT)
+        .clamp(1, MAX_HISTORY_LIMIT);
+    tracing::debug!(device_token = %token::logged(&device_token), limit = limit, "Fetching device push history");
+
+    let device_id = Database::device_id(&device_token)
+        .map_err(database_error)?
+        .ok_or_else(device_not_found)?;
+    let pushes = Database::pushes_for_device(device_id, limit).map_err(database_error)?;
+
+    Ok(Json(DevicePushesResponse {
+        device_token: if state.redact_tokens {
+            token::redact(&device_token)
+        } else {
+            device_token
+        },
+        pushes,
+    }))
+}
+
+/// `PATCH /devices/:token`: `{"enabled": false}` mutes a device without
+/// unregistering it; `{"enabled": true}` turns it back on.
+/// `{"snooze_seconds": 7200}` holds all but critical pushes for two hours.
+pub async fn update_device(
+    State(_state): State<AppState>,
+    audit: AuditContext,
+    Path(device_token): Path<String>,
+    Json(req): Json<UpdateDeviceRequest>,
+) -> Result<Json<DeviceRecord>, (StatusCode, Json<ErrorResponse>)> {
+    if req
+        .snooze_seconds
+        .is_some_and(|seconds| seconds > MAX_SNOOZE_SECONDS)
+    {
+        return Err(ErrorResponse::with_status(
+            StatusCode::BAD_REQUEST,
+            format!("snooze_seconds can be at most {MAX_SNOOZE_SECONDS} (a year)"),
+        ));
+    }
+    let device_id = Database::device_id(&device_token)
+        .map_err(database_error)?
+        .ok_or_else(device_not_found)?;
+    if let Some(enabled) = req.enabled {
+        Database::set_device_enabled(device_id, enabled).map_err(database_error)?;
+        tracing::info!(device_token = %token::logged(&device_token), enabled = enabled, "Updated device");
+        let action = if enabled {
+            "device.enable"
+        } else {
+            "device.disable"
+        };
+        audit::record(
+            &audit,
+            action,
+            format!("device_token={}", token::logged(&device_token)),
+        );
+    }
+    if let Some(seconds) = req.snooze_seconds {
+        Database::snooze_device(device_id, seconds).map_err(database_error)?;
+        tracing::info!(device_token = %token::logged(&device_token), seconds = seconds, "Snoozed device");
+        let (action, summary) = if seconds > 0 {
+            (
+                "device.snooze",
+                format!(
+                    "device_token={} seconds={seconds}",
+                    token::logged(&device_token)
+                ),
+            )
+        } else {
+            (
+                "device.unsnooze",
+                format!("device_token={}", token::logged(&device_token)),
+            )
+        };
+        audit::record(&audit, action, summary);
+    }
+    let mut device = Database::device(&device_token)
+        .map_err(database_error)?
+        .ok_or_else(device_not_found)?;
+    device.tags = Database::device_tags(device_id).map_err(database_error)?;
+    Ok(Json(device))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_db;
+
+    #[test]
+    fn test_normalize_locale_and_timezone() {
+        let mut locale = Some("en_GB".to_string());
+        let mut timezone = Some(" Europe/London ".to_string());
+        normalize_locale_and_timezone("a", &mut locale, &mut timezone);
+        assert_eq!(locale.as_deref(), Some("en-gb"));
+        assert_eq!(timezone.as_deref(), Some("Europe/London"));
+
+        let mut locale = Some("not a locale".to_string());
+        let mut timezone = Some("../etc/passwd".to_string());
+        normalize_locale_and_timezone("a", &mut locale, &mut timezone);
+        assert_eq!(locale, None);
+        assert_eq!(timezone, None);
+
+        assert_eq!(
+            normalize_timezone("Etc/GMT+5").as_deref(),
+            Some("Etc/GMT+5")
+        );
+        assert_eq!(normalize_timezone("UTC").as_deref(), Some("UTC"));
+    }
+
+    #[test]
+    fn test_devices_filters_by_locale_and_timezone() {
+        let _db = test_db();
+        Connection::get()
+            .unwrap()
+            .execute(
+                r#"
+                INSERT INTO devices (device_token, installation_id, environment, locale, timezone) VALUES
+                    ('a', 'i1', 'sandbox', 'fr-ca', 'America/Toronto'),
+                    ('b', 'i2', 'sandbox', 'fr', 'Europe/Paris'),
+                    ('c', 'i3', 'sandbox', 'fro', NULL),
+                    ('d', 'i4', 'sandbox', NULL, NULL)
+                "#,
+                (),
+            )
+            .unwrap();
+        let tokens = |filter: DeviceFilter| -> Vec<String> {
+            Database::devices(&filter)
+                .unwrap()
+                .into_iter()
+                .map(|device| device.device_token)
+                .collect()
+        };
+
+        assert_eq!(tokens(DeviceFilter::default()), ["a", "b", "c", "d"]);
+        let french = DeviceFilter {
+            locale: Some("fr".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(tokens(french), ["a", "b"]);
+        let canadian = DeviceFilter {
+            locale: Some("fr_CA".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(tokens(canadian), ["a"]);
+        let americas = DeviceFilter {
+            timezone: Some("America/*".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(tokens(americas), ["a"]);
+    }
+
+    #[test]
+    fn test_disabled_devices_are_skipped_until_enabled() {
+        let _db = test_db();
+        let register = |token: &str, installation_id: &str| {
+            Database::upsert_device(&crate::RegisterRequest {
+                device_token: token.to_string(),
+                installation_id: installation_id.to_string(),
+                environment: crate::Environment::Sandbox,
+                device_name: None,
+                device_type: None,
+                os_version: None,
+                app_version: None,
+                locale: None,
+                timezone: None,
+                expires_in: None,
+            })
+            .unwrap();
+        };
+        let targets = || -> Vec<String> {
+            Database::delivery_targets(None)
+                .unwrap()
+                .into_iter()
+                .map(|target| target.device_token)
+                .collect()
+        };
+        register("a", "i1");
+        register("b", "i2");
+
+        let id = |token: &str| Database::device_id(token).unwrap().unwrap();
+        Database::set_device_enabled(id("a"), false).unwrap();
+        assert_eq!(targets(), ["b"]);
+        assert!(!Database::device("a").unwrap().unwrap().enabled);
+
+        // Re-registering or rotating the token doesn't turn it back on.
+        register("a", "i1");
+        register("a2", "i1");
+        assert_eq!(targets(), ["b"]);
+        assert_eq!(
+            Database::devices(&DeviceFilter::default())
+                .unwrap()
+                .into_iter()
+                .map(|device| (device.device_token, device.enabled))
+                .collect::<Vec<_>>(),
+            [("b".to_string(), true), ("a2".to_string(), false)]
+        );
+
+        Database::set_device_enabled(id("a2"), true).unwrap();
+        assert_eq!(targets(), ["b", "a2"]);
+    }
+
+    #[test]
+    fn test_snooze_expires() {
+        let _db = test_db();
+        let conn = Connection::get().unwrap();
+        conn.execute(
+            "INSERT INTO devices (device_token, installation_id, environment) VALUES ('a', 'i', 'sandbox')",
+            (),
+        )
+        .unwrap();
+        let snoozed = || Database::delivery_targets(None).unwrap()[0].snoozed;
+        let id = Database::device_id("a").unwrap().unwrap();
+
+        Database::snooze_device(id, 3600).unwrap();
+        assert!(snoozed());
+        assert!(Database::device("a")
+            .unwrap()
+            .unwrap()
+            .snoozed_until
+            .is_some());
+
+        conn.execute(
+            "UPDATE devices SET snoozed_until = datetime('now', '-1 second')",
+            (),
+        )
+        .unwrap();
+        assert!(!snoozed());
+        assert!(Database::device("a")
+            .unwrap()
+            .unwrap()
+            .snoozed_until
+            .is_none());
+
+        Database::snooze_device(id, 60).unwrap();
+        Database::snooze_device(id, 0).unwrap();
+        assert!(!snoozed());
+    }
+
+    #[test]
+    fn test_pushes_for_device_includes_failures_newest_first() {
+        let _db = test_db();
+        let conn = Connection::get().unwrap();
+        conn.execute(
+            "INSERT INTO devices (device_token, installation_id, environment) VALUES ('a', 'i', 'sandbox'), ('b', 'i', 'sandbox')",
+            (),
+        )
+        .unwrap();
+        conn.execute(
+            r#"
+            INSERT INTO pushes (device_id, apns_id, title, status, error, error_code, sent_at) VALUES
+                (1, 'id-1', 'first', 'sent', NULL, NULL, '2024-01-01 14:00:00'),
+                (1, NULL, 'second', 'failed', 'The device token is invalid', 'BadDeviceToken', '2024-01-01 14:02:00'),
+                (2, 'id-3', 'other device', 'sent', NULL, NULL, '2024-01-01 14:03:00')
+            "#,
+            (),
+        )
+        .unwrap();
+
+        let device_id = Database::device_id("a").unwrap().unwrap();
+        let pushes = Database::pushes_for_device(device_id, 10).unwrap();
+        assert_eq!(pushes.len(), 2);
+        assert_eq!(pushes[0].title.as_deref(), Some("second"));
+        assert_eq!(pushes[0].status, "failed");
+        assert_eq!(pushes[0].error_code.as_deref(), Some("BadDeviceToken"));
+        assert_eq!(pushes[1].apns_id.as_deref(), Some("id-1"));
+
+        assert_eq!(Database::pushes_for_device(device_id, 1).unwrap().len(), 1);
+        assert!(Database::device_id("missing").unwrap().is_none());
+    }
+}
@@ -0,0 +1,486 @@
+use axum::{
+    body::{Body, Bytes},
+    extract::{Query, State},
+    http::{
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+        HeaderValue, StatusCode,
+    },
+    response::Response,
+};
+use futures_util::stream::{self, Stream};
+use seekwel::{connection::Connection, error::Error as SeekwelError, rusqlite::params};
+use serde::{Deserialize, Serialize};
+use std::io;
+
+use crate::{token, token_cipher, AppState, Database};
+
+/// Rows fetched per query while streaming an export.
+const PAGE_SIZE: i64 = 500;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Csv,
+    #[default]
+    Json,
+    Ndjson,
+}
+
+impl ExportFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Json => "application/json",
+            ExportFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Json => "json",
+            ExportFormat::Ndjson => "ndjson",
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+/// A row that can be written as CSV as well as JSON.
+trait ExportRow: Serialize {
+    const COLUMNS: &'static [&'static str];
+
+    fn id(&self) -> i64;
+    fn csv_fields(&self) -> Vec<Option<String>>;
+}
+
+#[derive(Debug, Serialize)]
+struct DeviceExportRow {
+    id: i64,
+    device_token: String,
+    installation_id: Option<String>,
+    environment: String,
+    platform: String,
+    device_name: Option<String>,
+    device_type: Option<String>,
+    os_version: Option<String>,
+    app_version: Option<String>,
+    locale: Option<String>,
+    timezone: Option<String>,
+    created_at: String,
+    updated_at: String,
+    superseded_at: Option<String>,
+}
+
+impl ExportRow for DeviceExportRow {
+    const COLUMNS: &'static [&'static str] = &[
+        "id",
+        "device_token",
+        "installation_id",
+        "environment",
+        "platform",
+        "device_name",
+        "device_type",
+        "os_version",
+        "app_version",
+        "locale",
+        "timezone",
+        "created_at",
+        "updated_at",
+        "superseded_at",
+    ];
+
+    fn id(&self) -> i64 {
+        self.id
+    }
+
+    fn csv_fields(&self) -> Vec<Option<String>> {
+        vec![
+            Some(self.id.to_string()),
+            Some(self.device_token.clone()),
+            self.installation_id.clone(),
+            Some(self.environment.clone()),
+            Some(self.platform.clone()),
+            self.device_name.clone(),
+            self.device_type.clone(),
+            self.os_version.clone(),
+            self.app_version.clone(),
+            self.locale.clone(),
+            self.timezone.clone(),
+            Some(self.created_at.clone()),
+            Some(self.updated_at.clone()),
+            self.superseded_at.clone(),
+        ]
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct PushExportRow {
+    id: i64,
+    device_token: String,
+    environment: String,
+    apns_id: Option<String>,
+    title: Option<String>,
+    body: Option<String>,
+    interruption_level: Option<String>,
+    status: String,
+    error: Option<String>,
+    error_code: Option<String>,
+    sent_at: String,
+}
+
+impl ExportRow for PushExportRow {
+    const COLUMNS: &'static [&'static str] = &[
+        "id",
+        "device_token",
+        "environment",
+        "apns_id",
+        "title",
+        "body",
+        "interruption_level",
+        "status",
+        "error",
+        "error_code",
+        "sent_at",
+    ];
+
+    fn id(&self) -> i64 {
+        self.id
+    }
+
+    fn csv_fields(&self) -> Vec<Option<String>> {
+        vec![
+            Some(self.id.to_string()),
+            Some(self.device_token.clone()),
+            Some(self.environment.clone()),
+            self.apns_id.clone(),
+            self.title.clone(),
+            self.body.clone(),
+            self.interruption_level.clone(),
+            Some(self.status.clone()),
+            self.error.clone(),
+            self.error_code.clone(),
+            Some(self.sent_at.clone()),
+        ]
+    }
+}
+
+impl Database {
+    fn device_export_page(after_id: i64) -> Result<Vec<DeviceExportRow>, SeekwelError> {
+        Connection::get()?.query_all(
+            r#"
+            SELECT id, device_token, installation_id, environment, platform, device_name,
+                   device_type, os_version, app_version, locale, timezone, created_at,
+                   updated_at, superseded_at
+            FROM devices
+            WHERE id > ?1
+            ORDER BY id
+            LIMIT ?2
+            "#,
+            params![after_id, PAGE_SIZE],
+            |row| {
+                Ok(DeviceExportRow {
+                    id: row.get(0)?,
+                    device_token: token_cipher::open(row, 1)?,
+                    installation_id: row.get(2)?,
+                    environment: row.get(3)?,
+                    platform: row.get(4)?,
+                    device_name: row.get(5)?,
+                    device_type: row.get(6)?,
+                    os_version: row.get(7)?,
+                    app_version: row.get(8)?,
+                    locale: row.get(9)?,
+                    timezone: row.get(10)?,
+                    created_at: row.get(11)?,
+                    updated_at: row.get(12)?,
+                    superseded_at: row.get(13)?,
+                })
+            },
+        )
+    }
+
+    fn push_export_page(after_id: i64) -> Result<Vec<PushExportRow>, SeekwelError> {
+        Connection::get()?.query_all(
+            r#"
+            SELECT p.id, d.device_token, d.environment, p.apns_id, p.title, p.body,
+                   p.interruption_level, p.status, p.error, p.error_code, p.sent_at
+            FROM pushes p
+            JOIN devices d ON p.device_id = d.id
+            WHERE p.id > ?1
+            ORDER BY p.id
+            LIMIT ?2
+            "#,
+            params![after_id, PAGE_SIZE],
+            |row| {
+                Ok(PushExportRow {
+                    id: row.get(0)?,
+                    device_token: token_cipher::open(row, 1)?,
+                    environment: row.get(2)?,
+                    apns_id: row.get(3)?,
+                    title: row.get(4)?,
+                    body: row.get(5)?,
+                    interruption_level: row.get(6)?,
+                    status: row.get(7)?,
+                    error: row.get(8)?,
+                    error_code: row.get(9)?,
+                    sent_at: row.get(10)?,
+                })
+            },
+        )
+    }
+}
+
+fn csv_line<S: AsRef<str>>(fields: impl IntoIterator<Item = Option<S>>) -> String {
+    let mut line = fields
+        .into_iter()
+        .map(|field| match field {
+            Some(value) => csv_escape(value.as_ref()),
+            None => String::new(),
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    line.push_str("\r\n");
+    line
+}
+
+fn csv_escape(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn header<T: ExportRow>(format: ExportFormat) -> String {
+    match format {
+        ExportFormat::Csv => csv_line(T::COLUMNS.iter().map(Some)),
+        ExportFormat::Json => "[".to_string(),
+        ExportFormat::Ndjson => String::new(),
+    }
+}
+
+fn encode_rows<T: ExportRow>(rows: &[T], format: ExportFormat, first: bool) -> String {
+    let mut out = String::new();
+    for (index, row) in rows.iter().enumerate() {
+        match format {
+            ExportFormat::Csv => out.push_str(&csv_line(row.csv_fields())),
+            ExportFormat::Json => {
+                if !(first && index == 0) {
+                    out.push(',');
+                }
+                out.push_str(&serde_json::to_string(row).unwrap_or_default());
+            }
+            ExportFormat::Ndjson => {
+                out.push_str(&serde_json::to_string(row).unwrap_or_default());
+                out.push('\n');
+            }
+        }
+    }
+    out
+}
+
+fn footer(format: ExportFormat) -> &'static str {
+    match format {
+        ExportFormat::Json => "]\n",
+        _ => "",
+    }
+}
+
+enum Cursor {
+    Start,
+    After { id: i64, first: bool },
+    Done,
+}
+
+/// Streams every row page by page so large tables never sit in memory.
+fn export_stream<T, F>(
+    format: ExportFormat,
+    fetch_page: F,
+) -> impl Stream<Item = Result<Bytes, io::Error>>
+where
+    T: ExportRow,
+    F: Fn(i64) -> Result<Vec<T>, SeekwelError>,
+{
+    stream::unfold(Cursor::Start, move |cursor| {
+        let next = match cursor {
+            Cursor::Start => Some((
+                Ok(Bytes::from(header::<T>(format))),
+                Cursor::After { id: 0, first: true },
+            )),
+            Cursor::After { id, first } => match fetch_page(id) {
+                Ok(rows) if rows.is_empty() => Some((
+                    Ok(Bytes::from_static(footer(format).as_bytes())),
+                    Cursor::Done,
+                )),
+                Ok(rows) => {
+                    let last_id = rows.last().map(ExportRow::id).unwrap_or(id);
+                    Some((
+                        Ok(Bytes::from(encode_rows(&rows, format, first))),
+                        Cursor::After {
+                            id: last_id,
+                            first: false,
+                        },
+                    ))
+                }
+                Err(e) => {
+                    tracing::error!(error = %e, "Database error during export");
+                    Some((Err(io::Error::other(e.to_string())), Cursor::Done))
+                }
+            },
+            Cursor::Done => None,
+        };
+        async move { next }
+    })
+}
+
+fn export_response(name: &str, format: ExportFormat, body: Body) -> Response {
+    let mut response = Response::new(body);
+    *response.status_mut() = StatusCode::OK;
+    let headers = response.headers_mut();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(format.content_type()),
+    );
+    if let Ok(value) = HeaderValue::from_str(&format!(
+        "attachment; filename=\"{name}.{}\"",
+        format.extension()
+    )) {
+        headers.insert(CONTENT_DISPOSITION, value);
+    }
+    response
+}
+
+pub async fn export_devices(
+    State(_state): State<AppState>,
+    Query(query): Query<ExportQuery>,
+) -> Response {
+    tracing::info!(format = ?query.format, "Exporting devices");
+    let body = Body::from_stream(export_stream(query.format, Database::device_export_page));
+    export_response("devices", query.format, body)
+}
+
+pub async fn export_pushes(
+    State(state): State<AppState>,
+    Query(query): Query<ExportQuery>,
+) -> Response {
+    tracing::info!(format = ?query.format, "Exporting pushes");
+    let redact = state.redact_tokens;
+    let body = Body::from_stream(export_stream(query.format, move |after_id| {
+        let mut rows = Database::push_export_page(after_id)?;
+        if redact {
+            for row in &mut rows {
+                row.device_token = token::redact(&row.device_token);
+            }
+        }
+        Ok(rows)
+    }));
+    export_response("pushes", query.format, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_db;
+    use futures_util::StreamExt;
+
+    fn collect<T: ExportRow>(
+        format: ExportFormat,
+        fetch_page: impl Fn(i64) -> Result<Vec<T>, SeekwelError>,
+    ) -> String {
+        let chunks: Vec<_> = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(export_stream(format, fetch_page).collect());
+        chunks
+            .into_iter()
+            .map(|chunk| String::from_utf8(chunk.unwrap().to_vec()).unwrap())
+            .collect()
+    }
+
+    fn seed() {
+        let conn = Connection::get().unwrap();
+        conn.execute(
+            r#"
+            INSERT INTO devices (device_token, installation_id, environment, device_name, created_at, updated_at)
+            VALUES ('aa', 'i1', 'sandbox', 'Pat''s "phone", v2', '2024-01-01 00:00:00', '2024-01-01 00:00:00'),
+                   ('bb', 'i2', 'production', NULL, '2024-01-02 00:00:00', '2024-01-02 00:00:00')
+            "#,
+            (),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_csv_escape() {
+        assert_eq!(csv_escape("plain"), "plain");
+        assert_eq!(csv_escape("a,b"), "\"a,b\"");
+        assert_eq!(csv_escape("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+
+    #[test]
+    fn test_export_devices_csv() {
+        let _db = test_db();
+        seed();
+        let csv = collect(ExportFormat::Csv, Database::device_export_page);
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("id,device_token,installation_id,environment"));
+        assert!(lines[1].starts_with("1,aa,i1,sandbox,apns,\"Pat's \"\"phone\"\", v2\","));
+        assert!(lines[2].starts_with("2,bb,i2,production,apns,,"));
+    }
+
+    #[test]
+    fn test_export_devices_json_and_ndjson() {
+        let _db = test_db();
+        seed();
+        let json = collect(ExportFormat::Json, Database::device_export_page);
+        let rows: Vec<serde_json::Value> = serde_json::from_str(&json).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1]["environment"], "production");
+
+        let ndjson = collect(ExportFormat::Ndjson, Database::device_export_page);
+        let rows: Vec<serde_json::Value> = ndjson
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["device_token"], "aa");
+    }
+
+    #[test]
+    fn test_export_empty_json_is_valid() {
+        let _db = test_db();
+        let json = collect(ExportFormat::Json, Database::push_export_page);
+        let rows: Vec<serde_json::Value> = serde_json::from_str(&json).unwrap();
+        assert!(rows.is_empty());
+    }
+
+    #[test]
+    fn test_export_pages_through_rows() {
+        let rows = |after: i64| -> Result<Vec<PushExportRow>, SeekwelError> {
+            Ok((after + 1..=(after + 2).min(5))
+                .map(|id| PushExportRow {
+                    id,
+                    device_token: "aa".to_string(),
+                    environment: "sandbox".to_string(),
+                    apns_id: None,
+                    title: None,
+                    body: None,
+                    interruption_level: None,
+                    status: "sent".to_string(),
+                    error: None,
+                    error_code: None,
+                    sent_at: "2024-01-01 00:00:00".to_string(),
+                })
+                .collect())
+        };
+        let json = collect(ExportFormat::Json, rows);
+        let rows: Vec<serde_json::Value> = serde_json::from_str(&json).unwrap();
+        let ids: Vec<_> = rows.iter().map(|r| r["id"].as_i64().unwrap()).collect();
+        assert_eq!(ids, vec![1, 2, 3, 4, 5]);
+    }
+}
@@ -0,0 +1,325 @@
+use seekwel::rusqlite::ToSql;
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
+use std::{cmp::Ordering, collections::BTreeMap};
+
+/// Narrows a send to devices matching every given field.
+///
+/// Exact and glob matches are translated to SQL. Version minimums are
+/// checked in Rust because devices report free-form strings such as
+/// "Version 17.4.1 (Build 21E236)" that SQLite can't compare numerically.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceFilter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_app_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_os_version: Option<String>,
+    /// A language (`fr`) also matches its regional locales (`fr-ca`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+    /// Glob over IANA names, e.g. `America/*`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    /// Devices subscribed to this topic.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    /// Devices carrying every one of these tags.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_tags",
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub tags: BTreeMap<String, String>,
+}
+
+/// Reads `tags` as a map, or as `key=value,key=value` for query strings,
+/// which can't nest.
+fn deserialize_tags<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<String, String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Tags {
+        Map(BTreeMap<String, String>),
+        List(String),
+    }
+    match Tags::deserialize(deserializer)? {
+        Tags::Map(tags) => Ok(tags),
+        Tags::List(list) => parse_tag_list(&list).map_err(D::Error::custom),
+    }
+}
+
+fn parse_tag_list(list: &str) -> Result<BTreeMap<String, String>, String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected tags as key=value, got '{pair}'"))?;
+            Ok((key.trim().to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+impl DeviceFilter {
+    /// Returns this filter with every field set in `overrides` replaced.
+    pub fn overlay(&self, overrides: &DeviceFilter) -> DeviceFilter {
+        DeviceFilter {
+            device_type: overrides
+                .device_type
+                .clone()
+                .or_else(|| self.device_type.clone()),
+            device_name: overrides
+                .device_name
+                .clone()
+                .or_else(|| self.device_name.clone()),
+            app_version: overrides
+                .app_version
+                .clone()
+                .or_else(|| self.app_version.clone()),
+            min_app_version: overrides
+                .min_app_version
+                .clone()
+                .or_else(|| self.min_app_version.clone()),
+            min_os_version: overrides
+                .min_os_version
+                .clone()
+                .or_else(|| self.min_os_version.clone()),
+            locale: overrides.locale.clone().or_else(|| self.locale.clone()),
+            timezone: overrides.timezone.clone().or_else(|| self.timezone.clone()),
+            topic: overrides.topic.clone().or_else(|| self.topic.clone()),
+            tags: self
+                .tags
+                .iter()
+                .chain(&overrides.tags)
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+        }
+    }
+
+    /// Returns SQL conditions (joined with AND) and their positional values.
+    pub fn sql_conditions(&self) -> (Vec<&'static str>, Vec<&dyn ToSql>) {
+        let mut conditions = Vec::new();
+        let mut values: Vec<&dyn ToSql> = Vec::new();
+
+        if let Some(device_type) = &self.device_type {
+            conditions.push("device_type = ? COLLATE NOCASE");
+            values.push(device_type);
+        }
+        if let Some(device_name) = &self.device_name {
+            conditions.push("device_name GLOB ?");
+            values.push(device_name);
+        }
+        if let Some(app_version) = &self.app_version {
+            conditions.push("app_version = ?");
+            values.push(app_version);
+        }
+        if let Some(locale) = &self.locale {
+            conditions.push(
+                "(locale = lower(replace(?, '_', '-')) OR locale GLOB lower(replace(?, '_', '-')) || '-*')",
+            );
+            values.push(locale);
+            values.push(locale);
+        }
+        if let Some(timezone) = &self.timezone {
+            conditions.push("timezone GLOB ?");
+            values.push(timezone);
+        }
+        if let Some(topic) = &self.topic {
+            conditions.push("id IN (SELECT device_id FROM topic_subscriptions WHERE topic = ?)");
+            values.push(topic);
+        }
+        for (key, value) in &self.tags {
+            conditions
+                .push("id IN (SELECT device_id FROM device_tags WHERE key = ? AND value = ?)");
+            values.push(key);
+            values.push(value);
+        }
+
+        (conditions, values)
+    }
+
+    /// Checks the version minimums that can't be expressed in SQL.
+    pub fn matches_versions(&self, os_version: Option<&str>, app_version: Option<&str>) -> bool {
+        version_at_least(os_version, self.min_os_version.as_deref())
+            && version_at_least(app_version, self.min_app_version.as_deref())
+    }
+}
+
+fn version_at_least(actual: Option<&str>, minimum: Option<&str>) -> bool {
+    let Some(minimum) = minimum.and_then(parse_version) else {
+        return true;
+    };
+    match actual.and_then(parse_version) {
+        Some(actual) => compare_versions(&actual, &minimum) != Ordering::Less,
+        None => false,
+    }
+}
+
+/// Extracts the first dotted number from strings like "iOS 18.0" or
+/// "Version 17.4.1 (Build 21E236)".
+pub fn parse_version(value: &str) -> Option<Vec<u32>> {
+    let start = value.find(|c: char| c.is_ascii_digit())?;
+    let rest = &value[start..];
+    let end = rest
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(rest.len());
+    let parts: Vec<u32> = rest[..end]
+        .split('.')
+        .take_while(|part| !part.is_empty())
+        .filter_map(|part| part.parse().ok())
+        .collect();
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts)
+    }
+}
+
+fn compare_versions(a: &[u32], b: &[u32]) -> Ordering {
+    let len = a.len().max(b.len());
+    for i in 0..len {
+        let ordering = a.get(i).unwrap_or(&0).cmp(b.get(i).unwrap_or(&0));
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+/// Whether `device_token` falls in a `percent` sample keyed by `seed`.
+///
+/// Each token hashes to a fixed point in [0, 100) per seed, so a seed always
+/// picks the same devices and raising the percentage only adds to them.
+pub fn in_sample(seed: &str, device_token: &str, percent: f64) -> bool {
+    let digest = Sha256::new()
+        .chain_update(seed)
+        .chain_update([0])
+        .chain_update(device_token)
+        .finalize();
+    let point = u64::from_be_bytes(digest[..8].try_into().expect("digest has 8 bytes"));
+    point as f64 / u64::MAX as f64 * 100.0 < percent
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_sample_is_deterministic_and_nested() {
+        let tokens: Vec<String> = (0..1000).map(|n| format!("{n:064x}")).collect();
+        let sample = |seed: &str, percent: f64| -> Vec<&String> {
+            tokens
+                .iter()
+                .filter(|token| in_sample(seed, token, percent))
+                .collect()
+        };
+
+        let ten = sample("launch", 10.0);
+        assert!((50..150).contains(&ten.len()), "{}", ten.len());
+        assert_eq!(ten, sample("launch", 10.0));
+        assert_ne!(ten, sample("other", 10.0));
+
+        let fifty = sample("launch", 50.0);
+        assert!(ten.iter().all(|token| fifty.contains(token)));
+        assert_eq!(sample("launch", 100.0).len(), tokens.len());
+    }
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("18.0"), Some(vec![18, 0]));
+        assert_eq!(parse_version("iOS 17.4.1"), Some(vec![17, 4, 1]));
+        assert_eq!(
+            parse_version("Version 17.4.1 (Build 21E236)"),
+            Some(vec![17, 4, 1])
+        );
+        assert_eq!(parse_version("unknown"), None);
+    }
+
+    #[test]
+    fn test_matches_min_os_version() {
+        let filter = DeviceFilter {
+            min_os_version: Some("17.0".to_string()),
+            ..Default::default()
+        };
+        assert!(filter.matches_versions(Some("Version 17.4.1 (Build 21E236)"), None));
+        assert!(filter.matches_versions(Some("iOS 17"), None));
+        assert!(!filter.matches_versions(Some("Version 16.7.2 (Build 20H115)"), None));
+        assert!(!filter.matches_versions(None, None));
+    }
+
+    #[test]
+    fn test_matches_without_version_filters() {
+        let filter = DeviceFilter::default();
+        assert!(filter.matches_versions(None, None));
+    }
+
+    #[test]
+    fn test_sql_conditions() {
+        let filter = DeviceFilter {
+            device_type: Some("iPad".to_string()),
+            device_name: Some("*Test*".to_string()),
+            min_os_version: Some("17.0".to_string()),
+            ..Default::default()
+        };
+        let (conditions, values) = filter.sql_conditions();
+        assert_eq!(
+            conditions,
+            vec!["device_type = ? COLLATE NOCASE", "device_name GLOB ?"]
+        );
+        assert_eq!(values.len(), 2);
+    }
+
+    #[test]
+    fn test_overlay() {
+        let base = DeviceFilter {
+            device_type: Some("iPhone".to_string()),
+            min_os_version: Some("16.0".to_string()),
+            ..Default::default()
+        };
+        let overrides = DeviceFilter {
+            min_os_version: Some("17.0".to_string()),
+            ..Default::default()
+        };
+        let merged = base.overlay(&overrides);
+        assert_eq!(merged.device_type.as_deref(), Some("iPhone"));
+        assert_eq!(merged.min_os_version.as_deref(), Some("17.0"));
+    }
+
+    #[test]
+    fn test_tags_from_map_or_list() {
+        let filter: DeviceFilter =
+            serde_json::from_str(r#"{"tags": {"tier": "beta", "region": "eu"}}"#).unwrap();
+        let from_query: DeviceFilter =
+            serde_urlencoded::from_str("tags=tier%3Dbeta%2C+region%3Deu").unwrap();
+        assert_eq!(filter, from_query);
+        assert_eq!(filter.sql_conditions().1.len(), 4);
+        assert!(serde_urlencoded::from_str::<DeviceFilter>("tags=tier").is_err());
+
+        let overrides = DeviceFilter {
+            tags: BTreeMap::from([("tier".to_string(), "ga".to_string())]),
+            ..Default::default()
+        };
+        assert_eq!(
+            filter.overlay(&overrides).tags,
+            BTreeMap::from([
+                ("region".to_string(), "eu".to_string()),
+                ("tier".to_string(), "ga".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_rejects_unknown_fields() {
+        let result: Result<DeviceFilter, _> = serde_json::from_str(r#"{"os_version": "17"}"#);
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,90 @@
+use crate::SendRequest;
+
+/// The `SendRequest` fields a form body or query string can set: the flat
+/// ones, since forms have no way to spell lists or objects.
+const FIELDS: &[&str] = &[
+    "title",
+    "subtitle",
+    "body",
+    "launch_image",
+    "raw",
+    "message_key",
+    "badge",
+    "sound",
+    "content_available",
+    "mutable_content",
+    "category",
+    "thread_id",
+    "interruption_level",
+    "relevance_score",
+    "target_content_id",
+    "stale_date",
+    "filter_criteria",
+    "event",
+    "dismissal_date",
+    "sample_percent",
+    "push_type",
+    "topic",
+    "priority",
+    "collapse_id",
+    "expiration",
+    "expires_in_seconds",
+    "defer_throttled",
+    "segment",
+];
+
+/// Whether a form-encoded body is fields rather than text. `curl --data
+/// 'backup done'` is form-encoded too, so a body only counts as a form when
+/// every key is a send field; anything else is the notification text.
+pub(crate) fn is_fields(body: &[u8]) -> bool {
+    body.contains(&b'=')
+        && unknown_field(body).is_none()
+        && serde_urlencoded::from_bytes::<Vec<(String, String)>>(body).is_ok()
+}
+
+/// Reads `title=...&body=...` pairs, from a form body or a query string.
+pub(crate) fn parse(input: &[u8]) -> Result<SendRequest, String> {
+    if let Some(key) = unknown_field(input) {
+        return Err(format!("Unknown field '{key}'"));
+    }
+    serde_urlencoded::from_bytes(input).map_err(|e| e.to_string())
+}
+
+fn unknown_field(input: &[u8]) -> Option<String> {
+    serde_urlencoded::from_bytes::<Vec<(String, String)>>(input)
+        .ok()?
+        .into_iter()
+        .map(|(key, _)| key)
+        .find(|key| !FIELDS.contains(&key.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_is_not_fields() {
+        assert!(is_fields(b"title=Build&body=done"));
+        assert!(is_fields(b"body=50%25%20done"));
+        assert!(!is_fields(b"backup done"));
+        assert!(!is_fields(b"status=ok"));
+        assert!(!is_fields(b"title"));
+        assert!(!is_fields(b""));
+    }
+
+    #[test]
+    fn test_parse_fields() {
+        let req = parse(b"title=Build&body=50%25+done&sound=default&priority=5&badge=2").unwrap();
+        assert_eq!(req.title.as_deref(), Some("Build"));
+        assert_eq!(req.body.as_deref(), Some("50% done"));
+        assert!(matches!(req.sound, Some(crate::SoundConfig::Simple(ref s)) if s == "default"));
+        assert_eq!(req.priority, Some(5));
+        assert_eq!(req.badge, Some(2));
+
+        assert_eq!(
+            parse(b"title=Hi&colour=red").unwrap_err(),
+            "Unknown field 'colour'"
+        );
+        assert!(parse(b"badge=lots").is_err());
+    }
+}
@@ -0,0 +1,259 @@
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use seekwel::{connection::Connection, error::Error as SeekwelError};
+use serde::Serialize;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use crate::{
+    auth::Caller,
+    provider::{Credentials, EndpointStatus, Platform},
+    AppState, Database,
+};
+
+/// Number of device deliveries accepted by `/send` but not yet attempted.
+#[derive(Debug, Clone, Default)]
+pub struct QueueDepth(Arc<AtomicUsize>);
+
+impl QueueDepth {
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Adds `count` deliveries; whatever hasn't completed is removed on drop.
+    pub fn enqueue(&self, count: usize) -> QueuedSends {
+        self.0.fetch_add(count, Ordering::Relaxed);
+        QueuedSends {
+            depth: self.0.clone(),
+            remaining: count,
+        }
+    }
+}
+
+pub struct QueuedSends {
+    depth: Arc<AtomicUsize>,
+    remaining: usize,
+}
+
+impl QueuedSends {
+    pub fn complete_one(&mut self) {
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            self.depth.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for QueuedSends {
+    fn drop(&mut self) {
+        self.depth.fetch_sub(self.remaining, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthResponse {
+    status: &'static str,
+    version: &'static str,
+    uptime_seconds: u64,
+    queue_depth: usize,
+    checks: HealthChecks,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthChecks {
+    database: Check,
+    apns: Check,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// Something that doesn't fail the check but needs attention, like APNs
+    /// repeatedly rejecting the provider token.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warning: Option<String>,
+    /// Seconds since the provider token in use was signed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_age_seconds: Option<u64>,
+    /// Sends and keepalive probes in a row that couldn't reach the provider.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    consecutive_failures: Option<u32>,
+    /// Who the provider sends as, for checking against the developer portal.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    credentials: Option<Credentials>,
+    /// When each of the provider's endpoints last accepted its token, and
+    /// whether it has been rejecting it.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    endpoints: Vec<EndpointStatus>,
+}
+
+impl Check {
+    fn from_result<E: ToString>(result: Result<(), E>) -> Self {
+        match result {
+            Ok(()) => Check {
+                ok: true,
+                error: None,
+                warning: None,
+                token_age_seconds: None,
+                consecutive_failures: None,
+                credentials: None,
+                endpoints: Vec::new(),
+            },
+            Err(e) => Check {
+                ok: false,
+                error: Some(e.to_string()),
+                warning: None,
+                token_age_seconds: None,
+                consecutive_failures: None,
+                credentials: None,
+                endpoints: Vec::new(),
+            },
+        }
+    }
+}
+
+impl HealthChecks {
+    fn ok(&self) -> bool {
+        self.database.ok && self.apns.ok
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProbeResponse {
+    status: &'static str,
+}
+
+impl Database {
+    pub(crate) fn ping() -> Result<(), SeekwelError> {
+        Connection::get()?.query_row("SELECT 1", (), |row| row.get::<_, i64>(0))?;
+        Ok(())
+    }
+}
+
+async fn run_checks(state: &AppState) -> HealthChecks {
+    let database = Check::from_result(Database::ping());
+    let provider = state.providers.get(Platform::Apns);
+    let endpoints = provider
+        .map(|provider| provider.endpoint_statuses())
+        .unwrap_or_default();
+    let warnings: Vec<String> = endpoints
+        .iter()
+        .filter_map(EndpointStatus::warning)
+        .collect();
+    let apns = Check {
+        warning: (!warnings.is_empty()).then(|| warnings.join("; ")),
+        endpoints,
+        token_age_seconds: provider
+            .and_then(|provider| provider.token_age())
+            .map(|age| age.as_secs()),
+        consecutive_failures: provider.and_then(|provider| provider.consecutive_failures()),
+        credentials: provider.and_then(|provider| provider.credentials()),
+        ..Check::from_result(match provider {
+            Some(provider) => provider.check_credentials(),
+            None => Err("APNs provider not configured".to_string()),
+        })
+    };
+    if let Some(error) = &database.error {
+        tracing::warn!(error = %error, "Database health check failed");
+    }
+    if let Some(error) = &apns.error {
+        tracing::warn!(error = %error, "APNs credential health check failed");
+    }
+    if let Some(warning) = &apns.warning {
+        tracing::warn!(warning = %warning, "APNs is rejecting the provider token");
+    }
+    HealthChecks { database, apns }
+}
+
+fn status_code(ok: bool) -> StatusCode {
+    if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+/// The server's health. Once API keys are configured, the checks, which
+/// name the APNs key and team, are only shown to keys that can read; other
+/// callers get just the status.
+pub async fn health(State(state): State<AppState>, caller: Option<Extension<Caller>>) -> Response {
+    let checks = run_checks(&state).await;
+    let ok = checks.ok();
+    let status = if ok { "ok" } else { "unavailable" };
+    let details =
+        state.api_keys.is_empty() || caller.is_some_and(|Extension(caller)| caller.role.can_read());
+    if !details {
+        return (status_code(ok), Json(ProbeResponse { status })).into_response();
+    }
+    (
+        status_code(ok),
+        Json(HealthResponse {
+            status,
+            version: env!("GIT_HASH"),
+            uptime_seconds: state.started_at.elapsed().as_secs(),
+            queue_depth: state.queue.get(),
+            checks,
+        }),
+    )
+        .into_response()
+}
+
+/// Readiness: the database answers and APNs credentials can sign a token.
+pub async fn ready(State(state): State<AppState>) -> (StatusCode, Json<ProbeResponse>) {
+    let ok = run_checks(&state).await.ok();
+    (
+        status_code(ok),
+        Json(ProbeResponse {
+            status: if ok { "ready" } else { "unavailable" },
+        }),
+    )
+}
+
+/// Liveness: the process is serving requests.
+pub async fn live() -> Json<ProbeResponse> {
+    Json(ProbeResponse { status: "ok" })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_db;
+
+    #[test]
+    fn test_queue_depth_releases_unfinished_sends() {
+        let depth = QueueDepth::default();
+        let mut sends = depth.enqueue(3);
+        assert_eq!(depth.get(), 3);
+        sends.complete_one();
+        assert_eq!(depth.get(), 2);
+        drop(sends);
+        assert_eq!(depth.get(), 0);
+    }
+
+    #[test]
+    fn test_database_ping() {
+        let _db = test_db();
+        assert!(Database::ping().is_ok());
+    }
+
+    #[test]
+    fn test_checks_fail_if_any_check_fails() {
+        let checks = HealthChecks {
+            database: Check::from_result(Ok::<(), String>(())),
+            apns: Check::from_result(Err("Cannot read APNs key")),
+        };
+        assert!(!checks.ok());
+        let json = serde_json::to_value(&checks).unwrap();
+        assert_eq!(json["database"]["ok"], true);
+        assert!(json["database"].get("error").is_none());
+        assert_eq!(json["apns"]["error"], "Cannot read APNs key");
+    }
+}
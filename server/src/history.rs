@@ -0,0 +1,298 @@
+use seekwel::{
+    connection::Connection,
+    error::Error as SeekwelError,
+    rusqlite::{params_from_iter, ToSql},
+};
+
+use crate::{
+    actions, apns_error::SendError, background, dedup, Database, DeviceTarget, SendRequest,
+};
+
+/// Rows per `INSERT`, keeping the bound parameters under SQLite's
+/// conservative default limit of 999.
+const ROWS_PER_INSERT: usize = 999 / COLUMNS;
+
+/// Pushes a send buffers before writing them, so a large fleet's history
+/// isn't held until the very end.
+const FLUSH_EVERY: usize = 1000;
+
+const COLUMNS: usize = 17;
+
+/// A push's row in `pushes`, buffered so a send writes its history in a few
+/// multi-row inserts instead of one per device.
+#[derive(Debug)]
+pub(crate) struct PendingPush {
+    device_id: i64,
+    apns_id: Option<String>,
+    title: Option<String>,
+    body: Option<String>,
+    /// The body before it was cut to fit.
+    full_body: Option<String>,
+    payload: Option<String>,
+    interruption_level: Option<String>,
+    /// As rendered for the device, so the push can be withdrawn or replaced.
+    collapse_id: Option<String>,
+    /// The `apns-topic` it was sent under, when not the server's app.
+    app: Option<String>,
+    /// The topic it was published to.
+    topic: Option<String>,
+    /// The API key that sent it.
+    api_key: Option<String>,
+    /// The APNs environment the push went to.
+    environment: String,
+    status: &'static str,
+    error: Option<String>,
+    error_code: Option<&'static str>,
+    actions: Option<String>,
+    content_hash: String,
+    /// Also counts against the device's background budget.
+    background: bool,
+}
+
+impl PendingPush {
+    fn new(device: &DeviceTarget, req: &SendRequest, payload_json: Option<&str>) -> Self {
+        PendingPush {
+            device_id: device.id,
+            apns_id: None,
+            title: req.title.clone(),
+            body: req.body.clone(),
+            full_body: req.full_body.clone(),
+            payload: payload_json.map(str::to_string),
+            interruption_level: req.interruption_level.clone(),
+            collapse_id: req.collapse_id.clone(),
+            app: req.topic.clone(),
+            topic: req.filter.as_ref().and_then(|filter| filter.topic.clone()),
+            api_key: req.api_key.clone(),
+            environment: device.environment.clone(),
+            status: "sent",
+            error: None,
+            error_code: None,
+            actions: actions::history_json(req),
+            content_hash: dedup::content_hash(req, payload_json),
+            background: background::is_throttled(req, device.platform),
+        }
+    }
+
+    /// A delivered push, which went through `environment`.
+    pub(crate) fn sent(
+        device: &DeviceTarget,
+        environment: &str,
+        apns_id: &str,
+        req: &SendRequest,
+        payload_json: Option<&str>,
+    ) -> Self {
+        PendingPush {
+            apns_id: Some(apns_id.to_string()),
+            environment: environment.to_string(),
+            ..Self::new(device, req, payload_json)
+        }
+    }
+
+    pub(crate) fn failed(
+        device: &DeviceTarget,
+        req: &SendRequest,
+        payload_json: Option<&str>,
+        error: &SendError,
+    ) -> Self {
+        PendingPush {
+            status: "failed",
+            error: Some(error.message.clone()),
+            error_code: Some(error.code.as_str()),
+            // Only delivered pushes count against the budget.
+            background: false,
+            ..Self::new(device, req, payload_json)
+        }
+    }
+}
+
+/// A send's history, written every `FLUSH_EVERY` pushes and once more when
+/// it's dropped, so pushes that went out are recorded even if the send is
+/// abandoned partway.
+#[derive(Debug, Default)]
+pub(crate) struct HistoryBuffer {
+    records: Vec<PendingPush>,
+}
+
+impl HistoryBuffer {
+    pub(crate) fn push(&mut self, record: PendingPush) {
+        self.records.push(record);
+        if self.records.len() >= FLUSH_EVERY {
+            self.flush();
+        }
+    }
+
+    /// Writes what's buffered, logging rather than failing the send that
+    /// has already gone out.
+    pub(crate) fn flush(&mut self) {
+        if let Err(e) = Database::record_pushes(&self.records) {
+            tracing::error!(count = self.records.len(), error = %e, "Failed to record pushes");
+        }
+        self.records.clear();
+    }
+}
+
+impl Drop for HistoryBuffer {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+impl Database {
+    /// Writes `records` to push history in one transaction.
+    #[tracing::instrument(name = "db.record_pushes", skip_all, fields(count = records.len()))]
+    pub(crate) fn record_pushes(records: &[PendingPush]) -> Result<(), SeekwelError> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        let conn = Connection::get()?;
+        Connection::transaction(|| {
+            for chunk in records.chunks(ROWS_PER_INSERT) {
+                let rows = vec![format!("({})", ["?"; COLUMNS].join(", ")); chunk.len()];
+                let sql = format!(
+                    r#"
+                    INSERT INTO pushes (device_id, apns_id, title, body, full_body, payload, interruption_level, environment, status, error, error_code, actions, content_hash, collapse_id, app, topic, api_key)
+                    VALUES {}
+                    "#,
+                    rows.join(", ")
+                );
+                let mut values: Vec<&dyn ToSql> = Vec::with_capacity(chunk.len() * COLUMNS);
+                for record in chunk {
+                    values.extend([
+                        &record.device_id as &dyn ToSql,
+                        &record.apns_id,
+                        &record.title,
+                        &record.body,
+                        &record.full_body,
+                        &record.payload,
+                        &record.interruption_level,
+                        &record.environment,
+                        &record.status,
+                        &record.error,
+                        &record.error_code,
+                        &record.actions,
+                        &record.content_hash,
+                        &record.collapse_id,
+                        &record.app,
+                        &record.topic,
+                        &record.api_key,
+                    ]);
+                }
+                conn.execute(&sql, params_from_iter(values))?;
+            }
+            for record in records.iter().filter(|r| r.background) {
+                Self::record_background_push(record.device_id)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{apns_error::ApnsErrorCode, provider::Platform, test_support::test_db};
+
+    fn devices(count: usize) -> Vec<DeviceTarget> {
+        let conn = Connection::get().unwrap();
+        (0..count)
+            .map(|i| {
+                let device_token = format!("token-{i}");
+                conn.execute(
+                    "INSERT INTO devices (device_token, installation_id, environment) VALUES (?1, 'install', 'sandbox')",
+                    [&device_token],
+                )
+                .unwrap();
+                DeviceTarget {
+                    id: conn
+                        .query_row("SELECT MAX(id) FROM devices", (), |row| row.get(0))
+                        .unwrap(),
+                    device_token,
+                    environment: "sandbox".to_string(),
+                    platform: Platform::Apns,
+                    device_name: None,
+                    device_type: None,
+                    os_version: None,
+                    app_version: None,
+                    locale: None,
+                    timezone: None,
+                    snoozed: false,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_record_pushes_in_batches() {
+        let _db = test_db();
+        let req = SendRequest {
+            title: Some("Hello".to_string()),
+            content_available: Some(true),
+            ..Default::default()
+        };
+        let devices = devices(ROWS_PER_INSERT + 10);
+        let error = SendError::new(ApnsErrorCode::BadDeviceToken);
+        let records: Vec<PendingPush> = devices
+            .iter()
+            .enumerate()
+            .map(|(i, device)| match i {
+                0 => PendingPush::failed(device, &req, None, &error),
+                1 => PendingPush::sent(device, "production", "apns-1", &req, None),
+                _ => PendingPush::sent(device, "sandbox", &format!("apns-{i}"), &req, None),
+            })
+            .collect();
+
+        Database::record_pushes(&records).unwrap();
+
+        let conn = Connection::get().unwrap();
+        let (sent, failed): (i64, i64) = conn
+            .query_row(
+                "SELECT SUM(status = 'sent'), SUM(status = 'failed') FROM pushes",
+                (),
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((sent, failed), (ROWS_PER_INSERT as i64 + 9, 1));
+        let error_code: String = conn
+            .query_row(
+                "SELECT error_code FROM pushes WHERE device_id = ?1",
+                [devices[0].id],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(error_code, "BadDeviceToken");
+        let environment: String = conn
+            .query_row(
+                "SELECT environment FROM pushes WHERE device_id = ?1",
+                [devices[1].id],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(environment, "production");
+        assert_eq!(
+            Database::background_pushes_this_hour(devices[0].id).unwrap(),
+            0
+        );
+        assert_eq!(
+            Database::background_pushes_this_hour(devices[1].id).unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_history_buffer_writes_when_dropped() {
+        let _db = test_db();
+        let req = SendRequest::default();
+        let devices = devices(2);
+        let mut history = HistoryBuffer::default();
+        for device in &devices {
+            history.push(PendingPush::sent(device, "sandbox", "apns-1", &req, None));
+        }
+        drop(history);
+
+        let count: i64 = Connection::get()
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM pushes", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+}
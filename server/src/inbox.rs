@@ -0,0 +1,378 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::{
+        header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::Response,
+    Json,
+};
+use seekwel::{connection::Connection, error::Error as SeekwelError, rusqlite::params};
+use serde::{Deserialize, Serialize};
+
+use crate::{cache, AppState, Database, ErrorResponse};
+
+/// `POST /pushes/read` body.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MarkReadRequest {
+    installation_id: String,
+    /// Pushes to mark read; every push in the inbox when absent.
+    ids: Option<Vec<i64>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InstallationQuery {
+    installation_id: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InboxResponse {
+    unread: i64,
+}
+
+/// Pushes in an installation's inbox: delivered and not deleted from it.
+const INBOX: &str = r#"
+    FROM pushes
+    WHERE status = 'sent'
+      AND inbox_deleted_at IS NULL
+      AND device_id IN (SELECT id FROM devices WHERE installation_id = ?1)
+"#;
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+
+/// A stored UTC time, `2026-10-16 09:30:00`, as an HTTP date:
+/// `Fri, 16 Oct 2026 09:30:00 GMT`.
+pub(crate) fn http_date(timestamp: &str) -> Option<String> {
+    let (date, time) = timestamp.split_once(' ')?;
+    let mut fields = date.splitn(3, '-').map(|field| field.parse::<i64>().ok());
+    let (year, month, day) = (fields.next()??, fields.next()??, fields.next()??);
+    if !(1..=12).contains(&month) {
+        return None;
+    }
+    // Sakamoto's day of the week.
+    let offsets = [0, 3, 2, 5, 0, 3, 5, 1, 4, 6, 2, 4];
+    let y = if month < 3 { year - 1 } else { year };
+    let weekday = (y + y / 4 - y / 100 + y / 400 + offsets[month as usize - 1] + day).rem_euclid(7);
+    Some(format!(
+        "{}, {day:02} {} {year} {time} GMT",
+        WEEKDAYS[weekday as usize],
+        MONTHS[month as usize - 1]
+    ))
+}
+
+/// An HTTP date as a stored UTC time, which compare as strings.
+fn stored_time(http_date: &str) -> Option<String> {
+    let mut fields = http_date.split_whitespace().skip(1);
+    let day: u32 = fields.next()?.parse().ok()?;
+    let month_name = fields.next()?;
+    let month = MONTHS.iter().position(|month| *month == month_name)? + 1;
+    let year: u32 = fields.next()?.parse().ok()?;
+    let time = fields.next()?;
+    let is_time = time.len() == 8
+        && time.bytes().enumerate().all(|(i, b)| {
+            if i == 2 || i == 5 {
+                b == b':'
+            } else {
+                b.is_ascii_digit()
+            }
+        });
+    (is_time && fields.next()? == "GMT").then(|| format!("{year:04}-{month:02}-{day:02} {time}"))
+}
+
+/// An installation's inbox as it stands, for answering conditional reads.
+#[derive(Debug)]
+pub(crate) struct InboxValidators {
+    /// Changes with every push that arrives, is read, deleted or pruned.
+    pub(crate) etag: HeaderValue,
+    /// When the inbox last changed, as an HTTP date, once that second is
+    /// over. Until then a later change could still share it.
+    pub(crate) last_modified: Option<String>,
+}
+
+impl InboxValidators {
+    /// Sets `ETag` and, once there is one, `Last-Modified` on `response`.
+    pub(crate) fn apply(self, response: &mut Response) {
+        let headers = response.headers_mut();
+        if let Some(value) = self
+            .last_modified
+            .and_then(|date| HeaderValue::from_str(&date).ok())
+        {
+            headers.insert(LAST_MODIFIED, value);
+        }
+        headers.insert(ETAG, self.etag);
+    }
+}
+
+/// Whether the client's copy of the inbox is current: its `If-None-Match`
+/// lists the ETag or, when it sends none, its `If-Modified-Since` is at or
+/// after the last change.
+pub(crate) fn not_modified(headers: &HeaderMap, validators: &InboxValidators) -> bool {
+    if headers.contains_key(IF_NONE_MATCH) {
+        return cache::matches_if_none_match(headers, &validators.etag);
+    }
+    validators
+        .last_modified
+        .as_deref()
+        .is_some_and(|last_modified| not_modified_since(headers, last_modified))
+}
+
+/// Whether the request's `If-Modified-Since` is at or after `last_modified`.
+fn not_modified_since(headers: &HeaderMap, last_modified: &str) -> bool {
+    let since = headers
+        .get(IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(stored_time);
+    match (since, stored_time(last_modified)) {
+        (Some(since), Some(last_modified)) => last_modified <= since,
+        _ => false,
+    }
+}
+
+impl Database {
+    /// Validators for the installation's inbox. Pushes are only ever read
+    /// or deleted once, and pruning lowers the count until a new push
+    /// raises the newest id, so the ETag never repeats for a changed inbox.
+    pub(crate) fn inbox_validators(installation_id: &str) -> Result<InboxValidators, SeekwelError> {
+        Connection::get()?.query_row(
+            r#"
+            SELECT
+                MAX(MAX(p.sent_at, COALESCE(p.read_at, ''), COALESCE(p.inbox_deleted_at, ''))),
+                COALESCE(MAX(p.id), 0),
+                COUNT(*),
+                COUNT(p.read_at),
+                COUNT(p.inbox_deleted_at),
+                CURRENT_TIMESTAMP
+            FROM pushes p
+            JOIN devices d ON p.device_id = d.id
+            WHERE d.installation_id = ?1 AND p.status = 'sent'
+            "#,
+            params![installation_id],
+            |row| {
+                let changed_at: Option<String> = row.get(0)?;
+                let now: String = row.get(5)?;
+                let etag = format!(
+                    "\"{}-{}-{}-{}\"",
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, i64>(3)?,
+                    row.get::<_, i64>(4)?
+                );
+                Ok(InboxValidators {
+                    etag: HeaderValue::from_str(&etag).expect("digits are a valid header value"),
+                    last_modified: changed_at
+                        .filter(|changed_at| *changed_at < now)
+                        .as_deref()
+                        .and_then(http_date),
+                })
+            },
+        )
+    }
+
+    pub(crate) fn unread_count(installation_id: &str) -> Result<i64, SeekwelError> {
+        Connection::get()?.query_row(
+            &format!("SELECT COUNT(*) {INBOX} AND read_at IS NULL"),
+            params![installation_id],
+            |row| row.get(0),
+        )
+    }
+
+    fn in_inbox(installation_id: &str, push_id: i64) -> Result<bool, SeekwelError> {
+        let count: i64 = Connection::get()?.query_row(
+            &format!("SELECT COUNT(*) {INBOX} AND id = ?2"),
+            params![installation_id, push_id],
+            |row| row.get(0),
+        )?;
+        Ok(count > 0)
+    }
+
+    /// Marks `ids`, or the whole inbox, read, keeping the first time for
+    /// pushes already read.
+    fn mark_read(installation_id: &str, ids: Option<&[i64]>) -> Result<(), SeekwelError> {
+        let conn = Connection::get()?;
+        let sql = format!(
+            "UPDATE pushes SET read_at = CURRENT_TIMESTAMP WHERE read_at IS NULL AND id IN (SELECT id {INBOX} AND (?2 IS NULL OR id = ?2))"
+        );
+        match ids {
+            None => {
+                conn.execute(&sql, params![installation_id, None::<i64>])?;
+            }
+            Some(ids) => Connection::transaction(|| {
+                for id in ids {
+                    conn.execute(&sql, params![installation_id, id])?;
+                }
+                Ok(())
+            })?,
+        }
+        Ok(())
+    }
+
+    /// Takes a push out of the inbox. Push history keeps it.
+    fn delete_from_inbox(push_id: i64) -> Result<(), SeekwelError> {
+        Connection::get()?.execute(
+            "UPDATE pushes SET inbox_deleted_at = CURRENT_TIMESTAMP WHERE id = ?1",
+            params![push_id],
+        )?;
+        Ok(())
+    }
+}
+
+fn database_error(e: SeekwelError) -> (StatusCode, Json<ErrorResponse>) {
+    tracing::error!(error = %e, "Database error updating inbox");
+    ErrorResponse::with_status(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Database error: {e}"),
+    )
+}
+
+/// `POST /pushes/read`: the app marks pushes in its inbox read, or all of
+/// them without `ids`, and gets the count left unread. Ids that aren't in
+/// the installation's inbox are ignored.
+pub async fn mark_read(
+    State(_state): State<AppState>,
+    Json(req): Json<MarkReadRequest>,
+) -> Result<Json<InboxResponse>, (StatusCode, Json<ErrorResponse>)> {
+    Database::mark_read(&req.installation_id, req.ids.as_deref()).map_err(database_error)?;
+    let unread = Database::unread_count(&req.installation_id).map_err(database_error)?;
+    tracing::debug!(installation_id = %req.installation_id, ids = ?req.ids, unread = unread, "Marked pushes read");
+    Ok(Json(InboxResponse { unread }))
+}
+
+/// `DELETE /pushes/:id?installation_id=...`: the app removes a push from its
+/// inbox. It stays in push history and stats.
+pub async fn delete_from_inbox(
+    State(_state): State<AppState>,
+    Path(push_id): Path<i64>,
+    Query(query): Query<InstallationQuery>,
+) -> Result<StatusCode, (StatusCode, Json<ErrorResponse>)> {
+    if !Database::in_inbox(&query.installation_id, push_id).map_err(database_error)? {
+        tracing::warn!(push_id = push_id, installation_id = %query.installation_id, "Push to delete not in inbox");
+        return Err(ErrorResponse::with_status(
+            StatusCode::NOT_FOUND,
+            "Push not found",
+        ));
+    }
+    Database::delete_from_inbox(push_id).map_err(database_error)?;
+    tracing::debug!(push_id = push_id, installation_id = %query.installation_id, "Deleted push from inbox");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_db;
+
+    fn push(device_id: i64) -> i64 {
+        let conn = Connection::get().unwrap();
+        conn.execute(
+            "INSERT INTO pushes (device_id, body) VALUES (?1, 'hi')",
+            params![device_id],
+        )
+        .unwrap();
+        conn.query_row("SELECT MAX(id) FROM pushes", (), |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_http_dates() {
+        let date = http_date("2026-10-16 09:30:05").unwrap();
+        assert_eq!(date, "Fri, 16 Oct 2026 09:30:05 GMT");
+        assert_eq!(
+            http_date("2024-02-29 00:00:00").unwrap(),
+            "Thu, 29 Feb 2024 00:00:00 GMT"
+        );
+        assert_eq!(stored_time(&date).as_deref(), Some("2026-10-16 09:30:05"));
+        assert_eq!(stored_time("Fri, 16 Oct 2026 9:30 GMT"), None);
+        assert_eq!(stored_time("yesterday"), None);
+
+        let mut headers = HeaderMap::new();
+        assert!(!not_modified_since(&headers, &date));
+        headers.insert(IF_MODIFIED_SINCE, date.parse().unwrap());
+        assert!(not_modified_since(&headers, &date));
+        let later = http_date("2026-10-16 09:30:06").unwrap();
+        assert!(!not_modified_since(&headers, &later));
+    }
+
+    #[test]
+    fn test_a_push_in_the_same_second_changes_the_etag() {
+        let _db = test_db();
+        let conn = Connection::get().unwrap();
+        conn.execute(
+            "INSERT INTO devices (device_token, installation_id, environment) VALUES ('a', 'install-1', 'sandbox')",
+            (),
+        )
+        .unwrap();
+        let device_id = Database::device_id("a").unwrap().unwrap();
+        let first = push(device_id);
+        let at = "2024-02-29 08:00:00";
+        conn.execute("UPDATE pushes SET sent_at = ?1", params![at])
+            .unwrap();
+        let seen = Database::inbox_validators("install-1").unwrap();
+        assert_eq!(seen.last_modified, http_date(at));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(IF_NONE_MATCH, seen.etag.clone());
+        headers.insert(IF_MODIFIED_SINCE, http_date(at).unwrap().parse().unwrap());
+        assert!(not_modified(&headers, &seen));
+
+        // Another push stamped with the very same second.
+        let second = push(device_id);
+        conn.execute(
+            "UPDATE pushes SET sent_at = ?1 WHERE id = ?2",
+            params![at, second],
+        )
+        .unwrap();
+        let now = Database::inbox_validators("install-1").unwrap();
+        assert_eq!(now.last_modified, seen.last_modified);
+        assert!(!not_modified(&headers, &now));
+        Database::mark_read("install-1", Some(&[first])).unwrap();
+        let read = Database::inbox_validators("install-1").unwrap();
+        assert_ne!(read.etag, now.etag);
+
+        // A change this second has no Last-Modified yet.
+        push(device_id);
+        let latest = Database::inbox_validators("install-1").unwrap();
+        assert_eq!(latest.last_modified, None);
+    }
+
+    #[test]
+    fn test_read_and_delete_stay_in_the_installation() {
+        let _db = test_db();
+        let conn = Connection::get().unwrap();
+        for (token, installation_id) in [("a", "install-1"), ("b", "install-2")] {
+            conn.execute(
+                "INSERT INTO devices (device_token, installation_id, environment) VALUES (?1, ?2, 'sandbox')",
+                params![token, installation_id],
+            )
+            .unwrap();
+        }
+        let mine = Database::device_id("a").unwrap().unwrap();
+        let theirs = Database::device_id("b").unwrap().unwrap();
+        let (first, second, third) = (push(mine), push(mine), push(mine));
+        let other = push(theirs);
+
+        Database::mark_read("install-1", Some(&[first, other])).unwrap();
+        assert_eq!(Database::unread_count("install-1").unwrap(), 2);
+        assert_eq!(Database::unread_count("install-2").unwrap(), 1);
+
+        assert!(Database::in_inbox("install-1", second).unwrap());
+        assert!(!Database::in_inbox("install-1", other).unwrap());
+        Database::delete_from_inbox(second).unwrap();
+        assert!(!Database::in_inbox("install-1", second).unwrap());
+        assert_eq!(Database::unread_count("install-1").unwrap(), 1);
+
+        Database::mark_read("install-1", None).unwrap();
+        assert_eq!(Database::unread_count("install-1").unwrap(), 0);
+        let read_at: Option<String> = conn
+            .query_row(
+                "SELECT read_at FROM pushes WHERE id = ?1",
+                params![third],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(read_at.is_some());
+    }
+}
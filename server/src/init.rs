@@ -0,0 +1,280 @@
+use aes_gcm::aead::{rand_core::RngCore, OsRng};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{apns::ApnsClients, Database};
+
+const CONFIG_FILE: &str = "server.toml";
+const ENV_FILE: &str = ".env";
+
+/// Asks questions on `output` and reads the answers from `input`.
+struct Prompt<R, W> {
+    input: R,
+    output: W,
+}
+
+impl<R: BufRead, W: Write> Prompt<R, W> {
+    /// Asks until answered, or takes `default` for an empty answer.
+    fn ask(&mut self, question: &str, default: Option<&str>) -> io::Result<String> {
+        loop {
+            match default {
+                Some(default) => write!(self.output, "{question} [{default}]: ")?,
+                None => write!(self.output, "{question}: ")?,
+            }
+            self.output.flush()?;
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Input closed before setup finished",
+                ));
+            }
+            match (line.trim(), default) {
+                ("", Some(default)) => return Ok(default.to_string()),
+                ("", None) => continue,
+                (answer, _) => return Ok(answer.to_string()),
+            }
+        }
+    }
+
+    fn confirm(&mut self, question: &str, default: bool) -> io::Result<bool> {
+        let hint = if default { "Y/n" } else { "y/N" };
+        loop {
+            let answer = self.ask(&format!("{question} ({hint})"), Some(""))?;
+            match answer.to_ascii_lowercase().as_str() {
+                "" => return Ok(default),
+                "y" | "yes" => return Ok(true),
+                "n" | "no" => return Ok(false),
+                _ => {}
+            }
+        }
+    }
+
+    fn say(&mut self, message: impl std::fmt::Display) -> io::Result<()> {
+        writeln!(self.output, "{message}")
+    }
+}
+
+/// What `init` asks for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Answers {
+    key_path: PathBuf,
+    key_id: String,
+    team_id: String,
+    topic: String,
+    database_url: String,
+}
+
+/// The key id in a key file named as Apple downloads it, `AuthKey_<id>.p8`.
+fn key_id_from_path(path: &Path) -> Option<String> {
+    let id = path.file_stem()?.to_str()?.strip_prefix("AuthKey_")?;
+    (!id.is_empty()).then(|| id.to_string())
+}
+
+/// The only `.p8` file in `dir`, the usual place for a freshly downloaded key.
+fn find_key(dir: &Path) -> Option<PathBuf> {
+    let mut keys = fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "p8"));
+    let key = keys.next()?;
+    keys.next().is_none().then_some(key)
+}
+
+/// A fresh random API key.
+pub(crate) fn generate_api_key() -> String {
+    let mut bytes = [0u8; 24];
+    OsRng.fill_bytes(&mut bytes);
+    format!("psh_{}", URL_SAFE_NO_PAD.encode(bytes))
+}
+
+/// `server.toml` with `api_key` as its one admin key.
+fn server_toml(api_key: &str) -> String {
+    format!(
+        "# Written by `server init`; the README lists every section.\n\
+         \n\
+         [[api_keys]]\n\
+         name = \"admin\"\n\
+         key = {}\n\
+         role = \"admin\"\n",
+        toml::Value::String(api_key.to_string())
+    )
+}
+
+/// Quotes a value for an environment file when the shell, systemd or
+/// Compose would otherwise split or expand it.
+fn env_value(value: &str) -> String {
+    if value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "/._-:?=+@,".contains(c))
+    {
+        value.to_string()
+    } else {
+        format!("'{}'", value.replace('\'', "'\\''"))
+    }
+}
+
+/// The environment the server reads its APNs credentials and database from.
+fn env_file(answers: &Answers) -> String {
+    [
+        ("APNS_KEY_PATH", answers.key_path.display().to_string()),
+        ("APNS_KEY_ID", answers.key_id.clone()),
+        ("APNS_TEAM_ID", answers.team_id.clone()),
+        ("APNS_TOPIC", answers.topic.clone()),
+        ("DATABASE_URL", answers.database_url.clone()),
+    ]
+    .iter()
+    .map(|(name, value)| format!("{name}={}\n", env_value(value)))
+    .collect()
+}
+
+/// `server init`: asks for the APNs key and app, checks the key against
+/// the APNs sandbox, creates the database and writes `server.toml`, with a
+/// first admin API key, and `.env` in the working directory.
+pub async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let mut prompt = Prompt {
+        input: io::stdin().lock(),
+        output: io::stdout(),
+    };
+    prompt.say("Setting up the psh server. Press enter to take the [default].\n")?;
+
+    for file in [CONFIG_FILE, ENV_FILE] {
+        if Path::new(file).exists()
+            && !prompt.confirm(&format!("{file} already exists. Overwrite it?"), false)?
+        {
+            return Err(format!("Left {file} as it was, nothing written").into());
+        }
+    }
+
+    let found = find_key(Path::new("."));
+    let answers = loop {
+        let key_path = prompt.ask(
+            "Path to your APNs key (.p8)",
+            found.as_deref().and_then(Path::to_str),
+        )?;
+        let key_path = match fs::canonicalize(&key_path) {
+            Ok(path) => path,
+            Err(e) => {
+                prompt.say(format!("Cannot read {key_path}: {e}"))?;
+                continue;
+            }
+        };
+        let key_id = prompt.ask("Key ID", key_id_from_path(&key_path).as_deref())?;
+        let team_id = prompt.ask("Team ID", None)?;
+        let topic = prompt.ask("App bundle ID (the APNs topic)", None)?;
+        let database = prompt.ask("Database file", Some("data.db"))?;
+        break Answers {
+            key_path,
+            key_id,
+            team_id,
+            topic,
+            database_url: format!("sqlite:{database}"),
+        };
+    };
+
+    prompt.say("\nSigning a provider token with the key...")?;
+    let clients = ApnsClients::with_key(
+        answers.key_path.display().to_string(),
+        answers.key_id.clone(),
+        answers.team_id.clone(),
+        answers.topic.clone(),
+    )
+    .map_err(|e| format!("The key can't sign provider tokens: {e}"))?;
+    prompt.say("Checking the credentials with the APNs sandbox...")?;
+    match clients.check_endpoint(crate::Environment::Sandbox).await {
+        Ok(()) => prompt.say("APNs accepted the key, team and topic.")?,
+        Err(e) => {
+            prompt.say(format!("APNs sandbox check failed: {e}"))?;
+            if !prompt.confirm("Write the configuration anyway?", false)? {
+                return Err("Setup cancelled, nothing written".into());
+            }
+        }
+    }
+
+    Database::initialize(&answers.database_url)?;
+    prompt.say(format!("Created the database at {}", answers.database_url))?;
+
+    let api_key = generate_api_key();
+    fs::write(CONFIG_FILE, server_toml(&api_key))?;
+    fs::write(ENV_FILE, env_file(&answers))?;
+    prompt.say(format!(
+        "\nWrote {CONFIG_FILE} and {ENV_FILE}. Your admin API key, which isn't shown again:\n\n    {api_key}\n\n\
+         Start the server with the environment from {ENV_FILE}:\n\n    \
+         set -a; . ./{ENV_FILE}; set +a; server\n\n\
+         and point the CLI at it:\n\n    \
+         psh config set server http://localhost:3000\n    \
+         psh config set api-key {api_key}"
+    ))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prompt(input: &str) -> Prompt<&[u8], Vec<u8>> {
+        Prompt {
+            input: input.as_bytes(),
+            output: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_prompt() {
+        let mut p = prompt("\n\nABC\n\nmaybe\ny\n");
+        assert_eq!(p.ask("Key ID", Some("XYZ")).unwrap(), "XYZ");
+        // Questions without a default are asked again.
+        assert_eq!(p.ask("Team ID", None).unwrap(), "ABC");
+        assert!(!p.confirm("Overwrite?", false).unwrap());
+        assert!(p.confirm("Overwrite?", false).unwrap());
+        assert!(p.ask("Topic", None).is_err());
+        let output = String::from_utf8(p.output).unwrap();
+        assert!(
+            output.starts_with("Key ID [XYZ]: Team ID: Team ID: "),
+            "{output}"
+        );
+    }
+
+    #[test]
+    fn test_key_id_from_path() {
+        assert_eq!(
+            key_id_from_path(Path::new("/keys/AuthKey_ABC123DEF4.p8")).as_deref(),
+            Some("ABC123DEF4")
+        );
+        assert_eq!(key_id_from_path(Path::new("key.p8")), None);
+        assert_eq!(key_id_from_path(Path::new("AuthKey_.p8")), None);
+    }
+
+    #[test]
+    fn test_files() {
+        let api_key = generate_api_key();
+        assert!(
+            api_key.starts_with("psh_") && api_key.len() == 36,
+            "{api_key}"
+        );
+        let config: crate::config::ServerConfig = toml::from_str(&server_toml(&api_key)).unwrap();
+        assert_eq!(config.api_keys[0].key, api_key);
+        assert_eq!(config.api_keys[0].role, crate::config::Role::Admin);
+
+        let answers = Answers {
+            key_path: PathBuf::from("/home/me/My Keys/AuthKey_ABC.p8"),
+            key_id: "ABC".to_string(),
+            team_id: "TEAM".to_string(),
+            topic: "com.example.app".to_string(),
+            database_url: "sqlite:data.db".to_string(),
+        };
+        assert_eq!(
+            env_file(&answers),
+            "APNS_KEY_PATH='/home/me/My Keys/AuthKey_ABC.p8'\n\
+             APNS_KEY_ID=ABC\n\
+             APNS_TEAM_ID=TEAM\n\
+             APNS_TOPIC=com.example.app\n\
+             DATABASE_URL=sqlite:data.db\n"
+        );
+        assert_eq!(env_value("it's"), "'it'\\''s'");
+    }
+}
@@ -0,0 +1,178 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use seekwel::{connection::Connection, error::Error as SeekwelError, rusqlite::params};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audit::{self, AuditContext},
+    AppState, Database, ErrorResponse,
+};
+
+#[derive(Debug, Default, Deserialize)]
+pub struct DeleteInstallationQuery {
+    /// Count what would be deleted without deleting it.
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeleteInstallationResponse {
+    installation_id: String,
+    dry_run: bool,
+    /// Registrations, including tokens the installation has rotated away
+    /// from.
+    devices: i64,
+    pushes: i64,
+}
+
+/// How much an installation has been pushed and when it was last active.
+#[derive(Debug, Serialize)]
+pub struct InstallationActivity {
+    /// Pushes recorded for any of its tokens, rotated ones included.
+    pushes: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_push_at: Option<String>,
+    /// When it last registered, opened a push or read its inbox.
+    last_active_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InstallationSummary {
+    pub(crate) installation_id: Option<String>,
+    /// Current devices, not counting tokens it rotated away from.
+    devices: i64,
+    #[serde(flatten)]
+    pub(crate) activity: InstallationActivity,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InstallationsResponse {
+    installations: Vec<InstallationSummary>,
+}
+
+impl Database {
+    /// Every installation, most recently active first. Devices registered
+    /// without an installation id are summarized together under `None`.
+    pub(crate) fn installations() -> Result<Vec<InstallationSummary>, SeekwelError> {
+        Connection::get()?.query_all(
+            r#"
+            SELECT
+                d.installation_id,
+                COUNT(DISTINCT CASE WHEN d.superseded_at IS NULL THEN d.id END),
+                COUNT(p.id),
+                MAX(p.sent_at),
+                MAX(MAX(d.updated_at, COALESCE(p.opened_at, ''), COALESCE(p.read_at, '')))
+            FROM devices d
+            LEFT JOIN pushes p ON p.device_id = d.id
+            GROUP BY d.installation_id
+            ORDER BY 5 DESC, d.installation_id
+            "#,
+            (),
+            |row| {
+                Ok(InstallationSummary {
+                    installation_id: row.get(0)?,
+                    devices: row.get(1)?,
+                    activity: InstallationActivity {
+                        pushes: row.get(2)?,
+                        last_push_at: row.get(3)?,
+                        last_active_at: row.get(4)?,
+                    },
+                })
+            },
+        )
+    }
+
+    /// How many registrations and pushes `installation_id` has.
+    fn installation_counts(installation_id: &str) -> Result<(i64, i64), SeekwelError> {
+        Connection::get()?.query_row(
+            r#"
+            SELECT
+                (SELECT COUNT(*) FROM devices WHERE installation_id = ?1),
+                (SELECT COUNT(*) FROM pushes p
+                 JOIN devices d ON p.device_id = d.id
+                 WHERE d.installation_id = ?1)
+            "#,
+            params![installation_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+    }
+
+    /// Deletes every registration of `installation_id`, with their push
+    /// history, tags and subscriptions, and returns how many registrations
+    /// and pushes went.
+    fn delete_installation(installation_id: &str) -> Result<(i64, i64), SeekwelError> {
+        let conn = Connection::get()?;
+        Connection::transaction(|| {
+            let counts = Self::installation_counts(installation_id)?;
+            if counts.0 > 0 {
+                conn.execute(
+                    "DELETE FROM devices WHERE installation_id = ?1",
+                    params![installation_id],
+                )?;
+            }
+            Ok(counts)
+        })
+    }
+}
+
+fn database_error(e: SeekwelError) -> (StatusCode, Json<ErrorResponse>) {
+    tracing::error!(error = %e, "Database error handling installation request");
+    ErrorResponse::with_status(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Database error: {e}"),
+    )
+}
+
+/// `GET /installations`: each app install with its device count, pushes
+/// and last activity, most recently active first.
+pub async fn list_installations(
+    State(_state): State<AppState>,
+) -> Result<Json<InstallationsResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let installations = Database::installations()
+        .map_err(database_error)?
+        .into_iter()
+        .filter(|installation| installation.installation_id.is_some())
+        .collect();
+    Ok(Json(InstallationsResponse { installations }))
+}
+
+/// `DELETE /installations/:id`: erases everything stored about an
+/// installation, for a user's deletion request. `?dry_run=true` only counts
+/// it. An unknown installation reports zero, so a retried request succeeds.
+pub async fn delete_installation(
+    State(_state): State<AppState>,
+    audit: AuditContext,
+    Path(installation_id): Path<String>,
+    Query(query): Query<DeleteInstallationQuery>,
+) -> Result<Json<DeleteInstallationResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let (devices, pushes) = if query.dry_run {
+        Database::installation_counts(&installation_id).map_err(database_error)?
+    } else {
+        let counts = Database::delete_installation(&installation_id).map_err(database_error)?;
+        tracing::info!(
+            installation_id = %installation_id,
+            devices = counts.0,
+            pushes = counts.1,
+            "Deleted installation"
+        );
+        audit::record(
+            &audit,
+            "installation.delete",
+            format!(
+                "installation_id={installation_id} devices={} pushes={}",
+                counts.0, counts.1
+            ),
+        );
+        counts
+    };
+
+    Ok(Json(DeleteInstallationResponse {
+        installation_id,
+        dry_run: query.dry_run,
+        devices,
+        pushes,
+    }))
+}
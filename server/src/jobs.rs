@@ -0,0 +1,354 @@
+use aes_gcm::aead::{rand_core::RngCore, OsRng};
+use serde::{Deserialize, Serialize};
+use std::{env, io, sync::Arc, time::Duration};
+
+use crate::{
+    apns_error::{ApnsErrorCode, SendError},
+    attempt,
+    lanes::Lane,
+    provider::{Platform, Target},
+    redis::{self, RedisUrl, Reply},
+    token, AppState, Delivered, DeviceTarget, Environment, SendRequest,
+};
+
+const DEFAULT_WORKERS: usize = 8;
+
+/// How long a send waits for the next worker to report back before giving
+/// up on the deliveries still out.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long an idle worker blocks on the queue before asking again.
+const POLL_SECONDS: &str = "5";
+
+/// How long a worker waits to reconnect after losing Redis.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Replies nobody collects, because their send gave up, expire after this.
+const REPLY_TTL_SECONDS: &str = "60";
+
+/// The list a lane's jobs wait in.
+fn lane_key(lane: Lane) -> String {
+    format!("psh:jobs:{}", lane.as_str())
+}
+
+/// Workers pop from these in order, so critical jobs go first.
+fn lane_keys() -> [String; 3] {
+    [Lane::Critical, Lane::Normal, Lane::Bulk].map(lane_key)
+}
+
+/// One delivery for a worker to make, and where to report how it went.
+#[derive(Debug, Deserialize, Serialize)]
+struct Job<R> {
+    reply_to: String,
+    index: usize,
+    device_token: String,
+    environment: String,
+    platform: String,
+    request: R,
+}
+
+/// How a job went: the apns-id, or why it failed.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct JobReply {
+    index: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    apns_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fallback_environment: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error_code: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl JobReply {
+    fn new(index: usize, attempt: Result<Delivered, SendError>) -> Self {
+        match attempt {
+            Ok(delivered) => Self {
+                index,
+                apns_id: Some(delivered.apns_id),
+                fallback_environment: delivered.fallback_environment.map(str::to_string),
+                ..Default::default()
+            },
+            Err(error) => Self {
+                index,
+                error_code: Some(error.code.as_str().to_string()),
+                error: Some(error.message),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn into_attempt(self) -> Result<Delivered, SendError> {
+        match self.apns_id {
+            Some(apns_id) => Ok(Delivered {
+                apns_id,
+                fallback_environment: self
+                    .fallback_environment
+                    .and_then(|env| Environment::try_from(env.as_str()).ok())
+                    .map(|env| env.as_str()),
+            }),
+            None => {
+                let code = ApnsErrorCode::from_reason(self.error_code.as_deref().unwrap_or(""));
+                Err(SendError {
+                    code,
+                    message: self.error.unwrap_or_else(|| code.description().to_string()),
+                })
+            }
+        }
+    }
+}
+
+/// A Redis-backed queue that spreads deliveries over the workers of every
+/// replica sharing it.
+pub struct JobQueue {
+    url: RedisUrl,
+    workers: usize,
+}
+
+impl JobQueue {
+    /// Reads `QUEUE_URL` (`redis://[user:password@]host[:port][/db]`) and
+    /// `QUEUE_WORKERS`, how many deliveries this replica works on at once
+    /// (default 8). Unset, sends are delivered in process.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let url = match env::var("QUEUE_URL") {
+            Ok(url) if !url.trim().is_empty() => url,
+            _ => return Ok(None),
+        };
+        if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+            return Err(
+                "QUEUE_URL: Postgres queues are not supported, the server stores its data in SQLite; use redis://".to_string(),
+            );
+        }
+        let workers = match env::var("QUEUE_WORKERS") {
+            Ok(value) => value
+                .trim()
+                .parse()
+                .ok()
+                .filter(|&workers| workers > 0)
+                .ok_or_else(|| format!("Invalid QUEUE_WORKERS '{value}', expected a number"))?,
+            Err(_) => DEFAULT_WORKERS,
+        };
+        Ok(Some(Self {
+            url: RedisUrl::parse(url.trim())?,
+            workers,
+        }))
+    }
+
+    pub(crate) fn workers(&self) -> usize {
+        self.workers
+    }
+
+    /// Queues one job per device in `lane` and waits for each to be
+    /// delivered, in the order given. Fails only when nothing could be
+    /// queued; deliveries no worker reports back on fail individually.
+    pub(crate) async fn dispatch(
+        &self,
+        lane: Lane,
+        jobs: &[(&DeviceTarget, &SendRequest)],
+    ) -> io::Result<Vec<Result<Delivered, SendError>>> {
+        let mut conn = redis::Connection::open(&self.url).await?;
+        let mut id = [0u8; 12];
+        OsRng.fill_bytes(&mut id);
+        let reply_to = format!(
+            "psh:replies:{}",
+            id.iter().map(|b| format!("{b:02x}")).collect::<String>()
+        );
+
+        let mut args = vec![b"LPUSH".to_vec(), lane_key(lane).into_bytes()];
+        for (index, (device, req)) in jobs.iter().enumerate() {
+            let job = Job {
+                reply_to: reply_to.clone(),
+                index,
+                device_token: device.device_token.clone(),
+                environment: device.environment.clone(),
+                platform: device.platform.as_str().to_string(),
+                request: req,
+            };
+            args.push(serde_json::to_vec(&job).map_err(io::Error::other)?);
+        }
+        conn.command(&args).await?;
+        tracing::info!(jobs = jobs.len(), lane = lane.as_str(), "Queued deliveries");
+
+        let mut attempts: Vec<Option<Result<Delivered, SendError>>> =
+            jobs.iter().map(|_| None).collect();
+        let mut remaining = jobs.len();
+        let mut lost = None;
+        let timeout = REPLY_TIMEOUT.as_secs().to_string();
+        while remaining > 0 {
+            let reply = match conn.command(&["BRPOP", &reply_to, &timeout]).await {
+                Ok(Reply::Array(Some(items))) => items.into_iter().nth(1),
+                Ok(_) => break,
+                Err(e) => {
+                    lost = Some(e);
+                    break;
+                }
+            };
+            let Some(Reply::Bulk(Some(bytes))) = reply else {
+                continue;
+            };
+            match serde_json::from_slice::<JobReply>(&bytes) {
+                Ok(reply) if attempts.get(reply.index).is_some_and(Option::is_none) => {
+                    let index = reply.index;
+                    attempts[index] = Some(reply.into_attempt());
+                    remaining -= 1;
+                }
+                Ok(_) => {}
+                Err(e) => tracing::error!(error = %e, "Ignoring malformed job reply"),
+            }
+        }
+        let _ = conn.command(&["DEL", &reply_to]).await;
+
+        let unanswered = || match &lost {
+            Some(e) => SendError {
+                code: ApnsErrorCode::ConnectionError,
+                message: format!("Lost the job queue before a worker reported back: {e}"),
+            },
+            None => SendError {
+                code: ApnsErrorCode::Timeout,
+                message: format!(
+                    "No queue worker reported back within {}s",
+                    REPLY_TIMEOUT.as_secs()
+                ),
+            },
+        };
+        if remaining > 0 {
+            tracing::warn!(
+                unanswered = remaining,
+                "Deliveries went unanswered by queue workers"
+            );
+        }
+        Ok(attempts
+            .into_iter()
+            .map(|attempt| attempt.unwrap_or_else(|| Err(unanswered())))
+            .collect())
+    }
+
+    /// Starts this replica's workers, which deliver queued jobs from any
+    /// replica until the process exits.
+    pub fn spawn_workers(self: &Arc<Self>, state: AppState) {
+        for worker in 0..self.workers {
+            let queue = self.clone();
+            let state = state.clone();
+            tokio::spawn(async move { queue.work(state, worker).await });
+        }
+    }
+
+    async fn work(&self, state: AppState, worker: usize) {
+        let keys = lane_keys();
+        let mut args: Vec<&str> = vec!["BRPOP"];
+        args.extend(keys.iter().map(String::as_str));
+        args.push(POLL_SECONDS);
+        loop {
+            let mut conn = match redis::Connection::open(&self.url).await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    tracing::warn!(worker = worker, error = %e, "Job queue unreachable, retrying");
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                    continue;
+                }
+            };
+            tracing::debug!(worker = worker, "Job queue worker connected");
+            loop {
+                let job = match conn.command(&args).await {
+                    Ok(Reply::Array(Some(items))) => items.into_iter().nth(1),
+                    Ok(_) => continue,
+                    Err(e) => {
+                        tracing::warn!(worker = worker, error = %e, "Lost the job queue, reconnecting");
+                        break;
+                    }
+                };
+                let Some(Reply::Bulk(Some(job))) = job else {
+                    continue;
+                };
+                if let Err(e) = run_job(&state, &mut conn, &job).await {
+                    tracing::warn!(worker = worker, error = %e, "Failed to report a job, reconnecting");
+                    break;
+                }
+            }
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+}
+
+async fn run_job(state: &AppState, conn: &mut redis::Connection, job: &[u8]) -> io::Result<()> {
+    let job: Job<SendRequest> = match serde_json::from_slice(job) {
+        Ok(job) => job,
+        Err(e) => {
+            tracing::error!(error = %e, "Dropping malformed job");
+            return Ok(());
+        }
+    };
+    let target = Target {
+        token: &job.device_token,
+        environment: &job.environment,
+    };
+    let span = tracing::info_span!("job", device_token = %token::logged(&job.device_token));
+    let _enter = span.enter();
+    let attempt = attempt(
+        state,
+        Platform::from_db(&job.platform),
+        target,
+        &job.request,
+    )
+    .await;
+    let reply = serde_json::to_vec(&JobReply::new(job.index, attempt)).map_err(io::Error::other)?;
+    conn.command(&[b"LPUSH".as_slice(), job.reply_to.as_bytes(), &reply])
+        .await?;
+    conn.command(&["EXPIRE", &job.reply_to, REPLY_TTL_SECONDS])
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apns;
+    use serde_json::json;
+
+    #[test]
+    fn test_requests_survive_the_queue() {
+        let req: SendRequest = serde_json::from_value(json!({
+            "title": "Deploy",
+            "body": "done",
+            "sound": {"name": "alarm.caf", "critical": true, "volume": 0.5},
+            "push_type": "liveactivity",
+            "event": "update",
+            "content_state": {"progress": 0.5},
+            "category": "MESSAGE",
+            "actions": [{"id": "ok", "title": "OK"}],
+            "data": {"order": 7},
+            "filter": {"device_type": "iPad"},
+        }))
+        .unwrap();
+        let job = Job {
+            reply_to: "psh:replies:1".to_string(),
+            index: 3,
+            device_token: "abc".to_string(),
+            environment: "sandbox".to_string(),
+            platform: "apns".to_string(),
+            request: &req,
+        };
+        let job: Job<SendRequest> =
+            serde_json::from_slice(&serde_json::to_vec(&job).unwrap()).unwrap();
+        assert_eq!(job.index, 3);
+        assert_eq!(apns::payload_json(&job.request), apns::payload_json(&req));
+        assert!(job.request.filter.is_none());
+    }
+
+    #[test]
+    fn test_replies_round_trip() {
+        let delivered = Delivered {
+            apns_id: "apns-1".to_string(),
+            fallback_environment: Some("production"),
+        };
+        let reply = JobReply::new(0, Ok(delivered.clone()));
+        let reply: JobReply = serde_json::from_slice(&serde_json::to_vec(&reply).unwrap()).unwrap();
+        assert_eq!(reply.into_attempt(), Ok(delivered));
+
+        let error = SendError::new(ApnsErrorCode::BadDeviceToken);
+        let reply = JobReply::new(1, Err(error.clone()));
+        let reply: JobReply = serde_json::from_slice(&serde_json::to_vec(&reply).unwrap()).unwrap();
+        assert_eq!(reply.into_attempt(), Err(error));
+    }
+}
@@ -0,0 +1,294 @@
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use seekwel::{connection::Connection, error::Error as SeekwelError, rusqlite::params};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audit::{self, AuditContext},
+    auth::{self, Caller},
+    config::Role,
+    init, topics, AppState, Database, ErrorResponse,
+};
+
+/// `POST /keys` body.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IssueKeyRequest {
+    /// Defaults to the topic, or the role, with a random suffix.
+    name: Option<String>,
+    role: Role,
+    /// Limits a `send` key to `POST /t/<topic>`.
+    topic: Option<String>,
+}
+
+/// An API key issued through the API rather than listed in `server.toml`.
+#[derive(Debug, Serialize)]
+pub struct IssuedKey {
+    name: String,
+    role: Role,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created_by: Option<String>,
+    created_at: String,
+}
+
+/// The one response that carries the key itself.
+#[derive(Debug, Serialize)]
+pub struct IssueKeyResponse {
+    key: String,
+    #[serde(flatten)]
+    issued: IssuedKey,
+}
+
+#[derive(Debug, Serialize)]
+pub struct KeysResponse {
+    keys: Vec<IssuedKey>,
+}
+
+/// The stored digest of `key`, as hex.
+fn stored_digest(key: &str) -> String {
+    auth::digest(key)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+fn role_from_db(value: &str) -> Role {
+    match value {
+        "read" => Role::Read,
+        _ => Role::Send,
+    }
+}
+
+impl Database {
+    pub(crate) fn create_issued_keys_table(conn: &Connection) -> Result<(), SeekwelError> {
+        conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS issued_keys (
+                name TEXT PRIMARY KEY,
+                digest TEXT NOT NULL UNIQUE,
+                role TEXT NOT NULL,
+                topic TEXT,
+                created_by TEXT,
+                created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            )
+            "#,
+            (),
+        )?;
+        Ok(())
+    }
+
+    fn issue_key(
+        name: &str,
+        key: &str,
+        role: Role,
+        topic: Option<&str>,
+        created_by: Option<&str>,
+    ) -> Result<(), SeekwelError> {
+        Connection::get()?.execute(
+            "INSERT INTO issued_keys (name, digest, role, topic, created_by) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![name, stored_digest(key), role.as_str(), topic, created_by],
+        )?;
+        Ok(())
+    }
+
+    fn issued_keys(name: Option<&str>) -> Result<Vec<IssuedKey>, SeekwelError> {
+        Connection::get()?.query_all(
+            r#"
+            SELECT name, role, topic, created_by, created_at
+            FROM issued_keys
+            WHERE ?1 IS NULL OR name = ?1
+            ORDER BY name
+            "#,
+            params![name],
+            |row| {
+                let role: String = row.get(1)?;
+                Ok(IssuedKey {
+                    name: row.get(0)?,
+                    role: role_from_db(&role),
+                    topic: row.get(2)?,
+                    created_by: row.get(3)?,
+                    created_at: row.get(4)?,
+                })
+            },
+        )
+    }
+
+    fn revoke_key(name: &str) -> Result<(), SeekwelError> {
+        Connection::get()?.execute("DELETE FROM issued_keys WHERE name = ?1", params![name])?;
+        Ok(())
+    }
+
+    /// The caller an issued key authenticates as, if `key` is one.
+    pub(crate) fn issued_caller(key: &str) -> Result<Option<Caller>, SeekwelError> {
+        Connection::get()?.query_optional(
+            "SELECT name, role, topic FROM issued_keys WHERE digest = ?1",
+            params![stored_digest(key)],
+            |row| {
+                let role: String = row.get(1)?;
+                Ok(Caller {
+                    name: row.get(0)?,
+                    role: role_from_db(&role),
+                    daily_quota: None,
+                    monthly_quota: None,
+                    topic: row.get(2)?,
+                })
+            },
+        )
+    }
+}
+
+fn database_error(e: SeekwelError) -> (StatusCode, Json<ErrorResponse>) {
+    tracing::error!(error = %e, "Database error handling API keys");
+    ErrorResponse::with_status(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Database error: {e}"),
+    )
+}
+
+fn unprocessable(message: &str) -> (StatusCode, Json<ErrorResponse>) {
+    ErrorResponse::with_status(StatusCode::UNPROCESSABLE_ENTITY, message)
+}
+
+/// Checks what a key may be issued with, filling in its name.
+fn check_request(
+    state: &AppState,
+    req: &IssueKeyRequest,
+    key: &str,
+) -> Result<String, (StatusCode, Json<ErrorResponse>)> {
+    if state.api_keys.is_empty() {
+        return Err(ErrorResponse::with_status(
+            StatusCode::CONFLICT,
+            "No [[api_keys]] are configured, so the API is open and a key would restrict nothing",
+        ));
+    }
+    match (req.role, &req.topic) {
+        (Role::Admin, _) => {
+            return Err(unprocessable(
+                "Admin keys can only be listed in server.toml; issue a send or read key",
+            ))
+        }
+        (Role::Read, Some(_)) => {
+            return Err(unprocessable("Only send keys can be limited to a topic"))
+        }
+        (_, Some(topic)) if !topics::is_valid_topic(topic) => {
+            return Err(unprocessable(
+                "Topics must be 1-64 characters of letters, digits, '-', '_' or '.'",
+            ))
+        }
+        _ => {}
+    }
+
+    let name = match &req.name {
+        Some(name) => name.clone(),
+        None => format!(
+            "{}-{}",
+            req.topic.as_deref().unwrap_or(req.role.as_str()),
+            &stored_digest(key)[..6]
+        ),
+    };
+    if !topics::is_valid_topic(&name) {
+        return Err(unprocessable(
+            "Key names must be 1-64 characters of letters, digits, '-', '_' or '.'",
+        ));
+    }
+    let taken = state.api_keys.callers().any(|caller| caller.name == name)
+        || !Database::issued_keys(Some(&name))
+            .map_err(database_error)?
+            .is_empty();
+    if taken {
+        return Err(ErrorResponse::with_status(
+            StatusCode::CONFLICT,
+            format!("An API key named '{name}' already exists"),
+        ));
+    }
+    Ok(name)
+}
+
+/// `POST /keys`: issues a `send` or `read` key, optionally limited to
+/// publishing to one topic. The key is in this response only.
+pub async fn issue_key(
+    State(state): State<AppState>,
+    audit: AuditContext,
+    body: Bytes,
+) -> Result<(StatusCode, Json<IssueKeyResponse>), (StatusCode, Json<ErrorResponse>)> {
+    let req: IssueKeyRequest = serde_json::from_slice(&body).map_err(|e| {
+        ErrorResponse::with_status(StatusCode::BAD_REQUEST, format!("Invalid JSON: {e}"))
+    })?;
+    let key = init::generate_api_key();
+    let name = check_request(&state, &req, &key)?;
+    let created_by = audit.caller.as_ref().map(|caller| caller.name.as_str());
+    Database::issue_key(&name, &key, req.role, req.topic.as_deref(), created_by)
+        .map_err(database_error)?;
+
+    let topic = req.topic.as_deref().unwrap_or("any");
+    tracing::info!(api_key = %name, role = req.role.as_str(), topic = %topic, "Issued API key");
+    audit::record(
+        &audit,
+        "api_key.issue",
+        format!("name={name} role={} topic={topic}", req.role.as_str()),
+    );
+    let issued = Database::issued_keys(Some(&name))
+        .map_err(database_error)?
+        .pop()
+        .ok_or_else(|| {
+            ErrorResponse::with_status(StatusCode::INTERNAL_SERVER_ERROR, "Key wasn't saved")
+        })?;
+    Ok((StatusCode::CREATED, Json(IssueKeyResponse { key, issued })))
+}
+
+/// `GET /keys`: the keys issued through the API, without the keys
+/// themselves.
+pub async fn list_keys(
+    State(_state): State<AppState>,
+) -> Result<Json<KeysResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let keys = Database::issued_keys(None).map_err(database_error)?;
+    Ok(Json(KeysResponse { keys }))
+}
+
+/// `DELETE /keys/:name`: revokes an issued key at once.
+pub async fn revoke_key(
+    State(_state): State<AppState>,
+    audit: AuditContext,
+    Path(name): Path<String>,
+) -> Result<StatusCode, (StatusCode, Json<ErrorResponse>)> {
+    if Database::issued_keys(Some(&name))
+        .map_err(database_error)?
+        .is_empty()
+    {
+        return Err(ErrorResponse::with_status(
+            StatusCode::NOT_FOUND,
+            format!("No issued API key named '{name}'"),
+        ));
+    }
+    Database::revoke_key(&name).map_err(database_error)?;
+    tracing::info!(api_key = %name, "Revoked API key");
+    audit::record(&audit, "api_key.revoke", format!("name={name}"));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_db;
+
+    #[test]
+    fn test_issued_keys_authenticate_until_revoked() {
+        let _db = test_db();
+        Database::issue_key("builds-1", "psh_secret", Role::Send, Some("builds"), None).unwrap();
+        let caller = Database::issued_caller("psh_secret").unwrap().unwrap();
+        assert_eq!(caller.name, "builds-1");
+        assert_eq!(caller.role, Role::Send);
+        assert_eq!(caller.topic.as_deref(), Some("builds"));
+        assert!(Database::issued_caller("psh_guess").unwrap().is_none());
+
+        Database::revoke_key("builds-1").unwrap();
+        assert!(Database::issued_caller("psh_secret").unwrap().is_none());
+        assert!(Database::issued_keys(None).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,245 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+
+use crate::{config::LanesConfig, SendRequest};
+
+/// The dispatch lanes a send's deliveries wait in, from most to least
+/// urgent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Lane {
+    /// Critical and time-sensitive alerts.
+    Critical,
+    Normal,
+    /// Sends to at least `bulk_threshold` devices.
+    Bulk,
+}
+
+impl Lane {
+    pub(crate) fn of(req: &SendRequest, devices: usize, bulk_threshold: usize) -> Self {
+        if req.is_critical() || req.interruption_level.as_deref() == Some("time-sensitive") {
+            Self::Critical
+        } else if devices >= bulk_threshold {
+            Self::Bulk
+        } else {
+            Self::Normal
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Critical => "critical",
+            Self::Normal => "normal",
+            Self::Bulk => "bulk",
+        }
+    }
+}
+
+struct LaneState {
+    permits: Arc<Semaphore>,
+    /// Deliveries accepted into the lane and not yet finished.
+    pending: AtomicUsize,
+}
+
+struct Inner {
+    critical: LaneState,
+    normal: LaneState,
+    bulk: LaneState,
+    bulk_threshold: usize,
+    /// Woken whenever the critical lane empties.
+    critical_drained: Notify,
+}
+
+impl Inner {
+    fn lane(&self, lane: Lane) -> &LaneState {
+        match lane {
+            Lane::Critical => &self.critical,
+            Lane::Normal => &self.normal,
+            Lane::Bulk => &self.bulk,
+        }
+    }
+
+    fn release(&self, lane: Lane, count: usize) {
+        if count == 0 {
+            return;
+        }
+        let before = self.lane(lane).pending.fetch_sub(count, Ordering::AcqRel);
+        if lane == Lane::Critical && before == count {
+            self.critical_drained.notify_waiters();
+        }
+    }
+}
+
+/// Limits how many deliveries each lane runs at once, and holds normal and
+/// bulk deliveries back while any critical ones are pending, so an incident
+/// alert doesn't wait behind a large broadcast.
+#[derive(Clone)]
+pub struct Lanes(Arc<Inner>);
+
+impl Lanes {
+    pub fn new(config: &LanesConfig) -> Result<Self, String> {
+        let lane = |name: &str, concurrency: usize| {
+            if concurrency == 0 {
+                return Err(format!("lanes.{name} must be at least 1"));
+            }
+            Ok(LaneState {
+                permits: Arc::new(Semaphore::new(concurrency)),
+                pending: AtomicUsize::new(0),
+            })
+        };
+        Ok(Self(Arc::new(Inner {
+            critical: lane("critical", config.critical)?,
+            normal: lane("normal", config.normal)?,
+            bulk: lane("bulk", config.bulk)?,
+            bulk_threshold: config.bulk_threshold,
+            critical_drained: Notify::new(),
+        })))
+    }
+
+    pub(crate) fn bulk_threshold(&self) -> usize {
+        self.0.bulk_threshold
+    }
+
+    /// Queues `count` deliveries in `lane`; whatever isn't delivered leaves
+    /// the lane when the entry drops.
+    pub(crate) fn enter(&self, lane: Lane, count: usize) -> LaneEntry {
+        self.0.lane(lane).pending.fetch_add(count, Ordering::AcqRel);
+        LaneEntry {
+            inner: self.0.clone(),
+            lane,
+            remaining: count,
+        }
+    }
+}
+
+impl Default for Lanes {
+    fn default() -> Self {
+        Self::new(&LanesConfig::default()).expect("default lanes are valid")
+    }
+}
+
+pub(crate) struct LaneEntry {
+    inner: Arc<Inner>,
+    lane: Lane,
+    remaining: usize,
+}
+
+impl LaneEntry {
+    /// Waits for this lane's turn to deliver one push: until the critical
+    /// lane is empty, unless this is it, and then for a free slot.
+    pub(crate) async fn acquire(&mut self) -> LanePermit {
+        if self.lane != Lane::Critical {
+            loop {
+                let drained = self.inner.critical_drained.notified();
+                tokio::pin!(drained);
+                drained.as_mut().enable();
+                if self.inner.critical.pending.load(Ordering::Acquire) == 0 {
+                    break;
+                }
+                drained.await;
+            }
+        }
+        let permit = self
+            .inner
+            .lane(self.lane)
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("lane semaphores are never closed");
+        let taken = self.remaining.min(1);
+        self.remaining -= taken;
+        LanePermit {
+            inner: self.inner.clone(),
+            lane: self.lane,
+            count: taken,
+            _permit: permit,
+        }
+    }
+}
+
+impl Drop for LaneEntry {
+    fn drop(&mut self) {
+        self.inner.release(self.lane, self.remaining);
+    }
+}
+
+/// A slot in a lane for one delivery, given back on drop.
+pub(crate) struct LanePermit {
+    inner: Arc<Inner>,
+    lane: Lane,
+    count: usize,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Drop for LanePermit {
+    fn drop(&mut self) {
+        self.inner.release(self.lane, self.count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn lanes(critical: usize, normal: usize, bulk: usize) -> Lanes {
+        Lanes::new(&LanesConfig {
+            critical,
+            normal,
+            bulk,
+            bulk_threshold: 100,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_lane_of_send() {
+        let mut req = SendRequest::default();
+        assert_eq!(Lane::of(&req, 10, 100), Lane::Normal);
+        assert_eq!(Lane::of(&req, 100, 100), Lane::Bulk);
+        req.interruption_level = Some("time-sensitive".to_string());
+        assert_eq!(Lane::of(&req, 50_000, 100), Lane::Critical);
+        req.interruption_level = Some("critical".to_string());
+        assert_eq!(Lane::of(&req, 1, 100), Lane::Critical);
+        assert!(Lanes::new(&LanesConfig {
+            bulk: 0,
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bulk_waits_for_critical_lane() {
+        let lanes = lanes(1, 1, 1);
+        let mut bulk = lanes.enter(Lane::Bulk, 2);
+        let first = bulk.acquire().await;
+        drop(first);
+
+        let mut critical = lanes.enter(Lane::Critical, 1);
+        let permit = critical.acquire().await;
+        let waiting = tokio::time::timeout(Duration::from_millis(20), bulk.acquire()).await;
+        assert!(
+            waiting.is_err(),
+            "bulk delivery ran ahead of a critical one"
+        );
+
+        drop(permit);
+        tokio::time::timeout(Duration::from_secs(1), bulk.acquire())
+            .await
+            .expect("bulk delivery resumes once the critical lane drains");
+    }
+
+    #[tokio::test]
+    async fn test_entries_release_undelivered_sends() {
+        let lanes = lanes(1, 1, 1);
+        let critical = lanes.enter(Lane::Critical, 3);
+        drop(critical);
+        let mut normal = lanes.enter(Lane::Normal, 1);
+        tokio::time::timeout(Duration::from_secs(1), normal.acquire())
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,104 @@
+use aes_gcm::aead::{rand_core::RngCore, OsRng};
+use seekwel::{connection::Connection, error::Error as SeekwelError, rusqlite::params};
+use std::{env, sync::OnceLock, time::Duration};
+
+use crate::{unix_now, Database};
+
+/// Names this process when it holds a lease: `PSH_INSTANCE_ID`, else random.
+pub(crate) fn instance_id() -> &'static str {
+    static INSTANCE_ID: OnceLock<String> = OnceLock::new();
+    INSTANCE_ID.get_or_init(|| match env::var("PSH_INSTANCE_ID") {
+        Ok(id) if !id.trim().is_empty() => id.trim().to_string(),
+        _ => {
+            let mut bytes = [0u8; 8];
+            OsRng.fill_bytes(&mut bytes);
+            bytes.iter().map(|b| format!("{b:02x}")).collect()
+        }
+    })
+}
+
+impl Database {
+    pub(crate) fn create_leases_table(conn: &Connection) -> Result<(), SeekwelError> {
+        conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS leases (
+                name TEXT PRIMARY KEY,
+                holder TEXT NOT NULL,
+                expires_at INTEGER NOT NULL
+            )
+            "#,
+            (),
+        )?;
+        Ok(())
+    }
+
+    /// Takes or renews the lease on `name` for `holder` until `ttl` from
+    /// `now`, unless another holder's lease is still running. Returns
+    /// whether `holder` has it.
+    pub(crate) fn acquire_lease(
+        name: &str,
+        holder: &str,
+        ttl: Duration,
+        now: u64,
+    ) -> Result<bool, SeekwelError> {
+        let conn = Connection::get()?;
+        Connection::transaction(|| {
+            conn.execute(
+                r#"
+                INSERT INTO leases (name, holder, expires_at) VALUES (?1, ?2, ?3)
+                ON CONFLICT(name) DO UPDATE SET
+                    holder = excluded.holder,
+                    expires_at = excluded.expires_at
+                WHERE leases.holder = excluded.holder OR leases.expires_at <= ?4
+                "#,
+                params![name, holder, (now + ttl.as_secs()) as i64, now as i64],
+            )?;
+            let current: String = conn.query_row(
+                "SELECT holder FROM leases WHERE name = ?1",
+                params![name],
+                |row| row.get(0),
+            )?;
+            Ok(current == holder)
+        })
+    }
+}
+
+/// Whether this instance should run the job `name` now. With several
+/// instances on one database only the lease holder does; it keeps the lease
+/// by renewing it each run, and another instance takes over once a holder
+/// has missed runs for `ttl`.
+pub(crate) fn is_leader(name: &str, ttl: Duration) -> bool {
+    match Database::acquire_lease(name, instance_id(), ttl, unix_now()) {
+        Ok(true) => true,
+        Ok(false) => {
+            tracing::debug!(job = name, "Another instance holds the lease, skipping");
+            false
+        }
+        Err(e) => {
+            tracing::error!(job = name, error = %e, "Failed to acquire lease, skipping");
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_db;
+
+    #[test]
+    fn test_one_holder_at_a_time() {
+        let _db = test_db();
+        let ttl = Duration::from_secs(60);
+        assert!(Database::acquire_lease("retention", "a", ttl, 1000).unwrap());
+        assert!(!Database::acquire_lease("retention", "b", ttl, 1030).unwrap());
+        // Renewing pushes the expiry out.
+        assert!(Database::acquire_lease("retention", "a", ttl, 1050).unwrap());
+        assert!(!Database::acquire_lease("retention", "b", ttl, 1100).unwrap());
+        // A holder that stops renewing loses the lease.
+        assert!(Database::acquire_lease("retention", "b", ttl, 1110).unwrap());
+        assert!(!Database::acquire_lease("retention", "a", ttl, 1120).unwrap());
+        // Leases are per job.
+        assert!(Database::acquire_lease("other", "a", ttl, 1120).unwrap());
+    }
+}
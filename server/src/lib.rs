@@ -0,0 +1,3223 @@
+use axum::{
+    body::Bytes,
+    extract::{DefaultBodyLimit, Path, Query, RawQuery, State},
+    http::{header::CONTENT_TYPE, HeaderMap, Method, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{delete, get, patch, post, put},
+    Json, Router,
+};
+use seekwel::{connection::Connection, error::Error as SeekwelError, rusqlite::params};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    env,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tower_http::{
+    cors::CorsLayer,
+    request_id::{PropagateRequestIdLayer, SetRequestIdLayer},
+    timeout::TimeoutLayer,
+    trace::TraceLayer,
+};
+use tracing::Instrument;
+
+mod actions;
+mod alerts;
+mod apns;
+pub mod apns_error;
+mod apps;
+pub mod attachments;
+mod audit;
+pub mod auth;
+mod background;
+mod breaker;
+mod cache;
+mod catalog;
+mod channels;
+pub mod config;
+pub mod cors;
+pub mod credentials;
+mod dedup;
+mod devices;
+mod duration;
+mod export;
+mod filter;
+mod form;
+mod health;
+mod history;
+mod inbox;
+pub mod init;
+mod installations;
+mod jobs;
+mod keys;
+mod lanes;
+mod leases;
+pub mod limits;
+mod listen;
+pub mod logging;
+mod markdown;
+pub mod mock;
+mod mqtt;
+mod opens;
+#[cfg(feature = "otel")]
+mod otel;
+mod pacing;
+mod pauses;
+mod preview;
+pub mod provider;
+mod quota;
+mod redis;
+mod request_id;
+mod retention;
+mod s3;
+mod segments;
+mod signing;
+mod slack;
+mod stats;
+mod systemd;
+mod tags;
+mod templates;
+mod test_device;
+mod token;
+mod token_cipher;
+mod topics;
+mod truncate;
+pub mod version;
+mod webpush;
+mod withdraw;
+
+use apns::{ApnsClients, ApnsMode, ApnsPriority, ApnsPushType};
+use apns_error::{ApnsErrorCode, SendError};
+use audit::AuditContext;
+use config::{ServerConfig, TruncationConfig};
+use filter::DeviceFilter;
+use health::QueueDepth;
+use history::{HistoryBuffer, PendingPush};
+use lanes::Lane;
+use mock::{MockBroadcaster, MockDeliveries, MockProvider};
+use provider::{Platform, ProviderRegistry, Target};
+use stats::{PlatformCount, StatsGroup, StatsQuery, StatsSeries};
+use token::TokenValidation;
+use webpush::{VapidKeys, WebPushProvider};
+
+#[derive(Clone)]
+pub struct AppState {
+    providers: Arc<ProviderRegistry>,
+    /// The app's bundle id, which is also its APNs topic.
+    bundle_id: String,
+    started_at: Instant,
+    queue: QueueDepth,
+    /// From `[lanes]` in `server.toml`; critical sends go ahead of the rest.
+    lanes: lanes::Lanes,
+    /// From `[truncation]` in `server.toml`.
+    truncation: Arc<TruncationConfig>,
+    token_validation: TokenValidation,
+    /// Set when Web Push is configured, for browsers to subscribe with.
+    vapid_public_key: Option<String>,
+    /// Set in `PSH_APNS_MODE=mock`, where APNs sends land here instead.
+    mock_deliveries: Option<MockDeliveries>,
+    /// APNs broadcast channels, when APNs is configured or mocked.
+    broadcaster: Option<Arc<dyn channels::Broadcaster>>,
+    /// Uploaded files served to devices by signed link.
+    attachments: Option<Arc<attachments::Attachments>>,
+    /// Set by `QUEUE_URL`; deliveries go through it to the workers of every
+    /// replica instead of being made in process.
+    job_queue: Option<Arc<jobs::JobQueue>>,
+    /// Recent reads of the polled list endpoints, dropped on every write.
+    response_cache: cache::ResponseCache,
+    /// Set by `PSH_DEDUP_WINDOW`; identical pushes to a device within it are
+    /// skipped.
+    dedup_window: Option<Duration>,
+    /// Set by `PSH_APNS_ENV_FALLBACK`; a token APNs rejects as bad is retried
+    /// in the other environment.
+    environment_fallback: bool,
+    /// Set by `PSH_REDACT_TOKENS`; push history shows device tokens cut to
+    /// their ends.
+    redact_tokens: bool,
+    /// From `[[api_keys]]` in `server.toml`; empty leaves the API open.
+    api_keys: auth::ApiKeys,
+    /// Set by `PSH_MAX_BODY_BYTES`, `PSH_REQUEST_TIMEOUT` and
+    /// `PSH_LONG_REQUEST_TIMEOUT`.
+    http_limits: limits::HttpLimits,
+    /// From `[cors]` in `server.toml`; unset, pages on other origins can't
+    /// read responses.
+    cors: Option<CorsLayer>,
+}
+
+impl AppState {
+    /// State with `providers` and defaults for everything configured by
+    /// environment variables.
+    pub fn new(providers: ProviderRegistry, bundle_id: impl Into<String>) -> Self {
+        Self {
+            providers: Arc::new(providers),
+            bundle_id: bundle_id.into(),
+            started_at: Instant::now(),
+            queue: QueueDepth::default(),
+            lanes: lanes::Lanes::default(),
+            truncation: Arc::default(),
+            token_validation: TokenValidation::default(),
+            vapid_public_key: None,
+            mock_deliveries: None,
+            broadcaster: None,
+            attachments: None,
+            job_queue: None,
+            response_cache: cache::ResponseCache::default(),
+            dedup_window: None,
+            environment_fallback: false,
+            redact_tokens: false,
+            api_keys: auth::ApiKeys::default(),
+            http_limits: limits::HttpLimits::default(),
+            cors: None,
+        }
+    }
+
+    /// Serves `deliveries` at `/mock/deliveries`, where broadcast channel
+    /// sends are recorded too.
+    pub fn with_mock_deliveries(mut self, deliveries: MockDeliveries) -> Self {
+        let broadcaster = MockBroadcaster::new(self.bundle_id.clone(), deliveries.clone());
+        self.broadcaster = Some(Arc::new(broadcaster));
+        self.mock_deliveries = Some(deliveries);
+        self
+    }
+
+    /// Skips pushes identical to one sent to the same device within `window`.
+    pub fn with_dedup_window(mut self, window: Duration) -> Self {
+        self.dedup_window = Some(window);
+        self
+    }
+
+    /// Retries APNs sends rejected with `BadDeviceToken` in the other
+    /// environment.
+    pub fn with_environment_fallback(mut self) -> Self {
+        self.environment_fallback = true;
+        self
+    }
+
+    /// Cuts device tokens to their ends in push history responses.
+    pub fn with_token_redaction(mut self) -> Self {
+        self.redact_tokens = true;
+        self
+    }
+
+    /// Cuts bodies too long for APNs as `config` says.
+    pub fn with_truncation(mut self, config: TruncationConfig) -> Self {
+        self.truncation = Arc::new(config);
+        self
+    }
+
+    /// Accepts uploads at `/attachments` and serves them by signed link.
+    pub fn with_attachments(mut self, attachments: attachments::Attachments) -> Self {
+        self.attachments = Some(Arc::new(attachments));
+        self
+    }
+
+    /// Requires one of `keys`, within its role, on every non-public route.
+    pub fn with_api_keys(mut self, keys: auth::ApiKeys) -> Self {
+        self.api_keys = keys;
+        self
+    }
+
+    /// Limits request bodies and how long requests may take as `limits`
+    /// says.
+    pub fn with_http_limits(mut self, limits: limits::HttpLimits) -> Self {
+        self.http_limits = limits;
+        self
+    }
+
+    /// Answers browsers on other origins as `cors` allows.
+    pub fn with_cors(mut self, cors: CorsLayer) -> Self {
+        self.cors = Some(cors);
+        self
+    }
+}
+
+pub struct Database;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum DatabaseLocation {
+    Memory,
+    File(String),
+}
+
+#[derive(Debug)]
+struct DeviceTarget {
+    id: i64,
+    device_token: String,
+    environment: String,
+    platform: Platform,
+    device_name: Option<String>,
+    device_type: Option<String>,
+    os_version: Option<String>,
+    app_version: Option<String>,
+    locale: Option<String>,
+    timezone: Option<String>,
+    /// Snoozed devices only get critical pushes.
+    snoozed: bool,
+}
+
+/// What `device_target` reads from `devices`.
+const DEVICE_TARGET_COLUMNS: &str =
+    "id, device_token, environment, platform, locale, os_version, app_version, \
+     COALESCE(snoozed_until > CURRENT_TIMESTAMP, 0), device_name, device_type, timezone";
+
+fn device_target(row: &seekwel::rusqlite::Row<'_>) -> seekwel::rusqlite::Result<DeviceTarget> {
+    let platform: String = row.get(3)?;
+    Ok(DeviceTarget {
+        id: row.get(0)?,
+        device_token: token_cipher::open(row, 1)?,
+        environment: row.get(2)?,
+        platform: Platform::from_db(&platform),
+        device_name: row.get(8)?,
+        device_type: row.get(9)?,
+        os_version: row.get(5)?,
+        app_version: row.get(6)?,
+        locale: row.get(4)?,
+        timezone: row.get(10)?,
+        snoozed: row.get(7)?,
+    })
+}
+
+/// How long a statement waits on another connection's write lock before
+/// failing with "database is locked".
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The longest `expires_in` a registration accepts: a year.
+const MAX_REGISTRATION_TTL: u64 = 365 * 24 * 60 * 60;
+
+/// Inbox page sizes for `GET /pushes` with a `limit` or `cursor`.
+const DEFAULT_INBOX_LIMIT: i64 = 50;
+const MAX_INBOX_LIMIT: i64 = 500;
+
+impl Database {
+    pub fn initialize(database_url: &str) -> Result<(), SeekwelError> {
+        let location = Self::location_from_url(database_url);
+        let in_memory = matches!(location, DatabaseLocation::Memory);
+        match location {
+            DatabaseLocation::Memory => match Connection::memory() {
+                Ok(()) | Err(SeekwelError::AlreadyInitialized) => {}
+                Err(error) => return Err(error),
+            },
+            DatabaseLocation::File(path) => match Connection::file(&path) {
+                Ok(()) | Err(SeekwelError::AlreadyInitialized) => {}
+                Err(error) => return Err(error),
+            },
+        }
+
+        let conn = Connection::get()?;
+        conn.execute("PRAGMA foreign_keys = ON", ())?;
+        // Concurrent sends and registrations wait for each other's writes
+        // instead of failing with "database is locked".
+        conn.query_row(
+            &format!("PRAGMA busy_timeout = {}", BUSY_TIMEOUT.as_millis()),
+            (),
+            |row| row.get::<_, i64>(0),
+        )?;
+        if !in_memory {
+            // WAL lets reads proceed during a write.
+            let journal_mode: String =
+                conn.query_row("PRAGMA journal_mode = WAL", (), |row| row.get(0))?;
+            if !journal_mode.eq_ignore_ascii_case("wal") {
+                tracing::warn!(journal_mode = %journal_mode, "Could not enable WAL journal mode");
+            }
+            conn.execute("PRAGMA synchronous = NORMAL", ())?;
+        }
+        Connection::transaction(|| {
+            Self::migrate_devices(&conn)?;
+            Self::migrate_pushes(&conn)?;
+            Self::create_schema(&conn)
+        })
+    }
+
+    fn location_from_url(database_url: &str) -> DatabaseLocation {
+        let mut value = database_url
+            .strip_prefix("sqlite:")
+            .unwrap_or(database_url)
+            .split('?')
+            .next()
+            .unwrap_or(database_url);
+        if let Some(path) = value.strip_prefix("//") {
+            value = path;
+        }
+
+        if value == ":memory:" {
+            DatabaseLocation::Memory
+        } else {
+            DatabaseLocation::File(value.to_string())
+        }
+    }
+
+    fn create_schema(conn: &Connection) -> Result<(), SeekwelError> {
+        Self::create_devices_table(conn)?;
+        Self::create_pushes_table(conn)?;
+        Self::create_background_pushes_table(conn)?;
+        Self::create_segments_table(conn)?;
+        Self::create_audit_table(conn)?;
+        Self::create_apps_table(conn)?;
+        Self::create_webpush_table(conn)?;
+        Self::create_messages_table(conn)?;
+        Self::create_topics_table(conn)?;
+        Self::create_api_key_usage_table(conn)?;
+        Self::create_device_tags_table(conn)?;
+        Self::create_attachments_table(conn)?;
+        Self::create_leases_table(conn)?;
+        Self::create_pauses_tables(conn)?;
+        Self::create_issued_keys_table(conn)?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_devices_installation_id ON devices(installation_id)",
+            (),
+        )?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_pushes_device_id_sent_at ON pushes(device_id, sent_at DESC)",
+            (),
+        )?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_pushes_sent_at ON pushes(sent_at)",
+            (),
+        )?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_pushes_status ON pushes(status)",
+            (),
+        )?;
+        Ok(())
+    }
+
+    fn create_devices_table(conn: &Connection) -> Result<(), SeekwelError> {
+        conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS devices (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                device_token TEXT NOT NULL UNIQUE,
+                installation_id TEXT,
+                environment TEXT NOT NULL CHECK(environment IN ('sandbox', 'production')),
+                platform TEXT NOT NULL DEFAULT 'apns',
+                device_name TEXT,
+                device_type TEXT,
+                os_version TEXT,
+                app_version TEXT,
+                locale TEXT,
+                timezone TEXT,
+                enabled INTEGER NOT NULL DEFAULT 1,
+                snoozed_until TEXT,
+                expires_at TEXT,
+                created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                superseded_at TEXT
+            )
+            "#,
+            (),
+        )?;
+        Ok(())
+    }
+
+    fn create_pushes_table(conn: &Connection) -> Result<(), SeekwelError> {
+        conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS pushes (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                device_id INTEGER NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+                apns_id TEXT,
+                title TEXT,
+                body TEXT,
+                payload TEXT,
+                interruption_level TEXT,
+                status TEXT NOT NULL DEFAULT 'sent' CHECK(status IN ('sent', 'failed')),
+                error TEXT,
+                error_code TEXT,
+                actions TEXT,
+                content_hash TEXT,
+                environment TEXT,
+                sent_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                opened_at TEXT,
+                full_body TEXT,
+                collapse_id TEXT,
+                app TEXT,
+                topic TEXT,
+                api_key TEXT,
+                read_at TEXT,
+                inbox_deleted_at TEXT
+            )
+            "#,
+            (),
+        )?;
+        Ok(())
+    }
+
+    fn migrate_devices(conn: &Connection) -> Result<(), SeekwelError> {
+        if !Self::table_exists(conn, "devices")? {
+            return Self::create_devices_table(conn);
+        }
+
+        if Self::column_exists(conn, "devices", "id")? {
+            if !Self::column_exists(conn, "devices", "superseded_at")? {
+                conn.execute("ALTER TABLE devices ADD COLUMN superseded_at TEXT", ())?;
+            }
+            if !Self::column_exists(conn, "devices", "platform")? {
+                conn.execute(
+                    "ALTER TABLE devices ADD COLUMN platform TEXT NOT NULL DEFAULT 'apns'",
+                    (),
+                )?;
+            }
+            for column in ["locale", "timezone"] {
+                if !Self::column_exists(conn, "devices", column)? {
+                    conn.execute(&format!("ALTER TABLE devices ADD COLUMN {column} TEXT"), ())?;
+                }
+            }
+            if !Self::column_exists(conn, "devices", "enabled")? {
+                conn.execute(
+                    "ALTER TABLE devices ADD COLUMN enabled INTEGER NOT NULL DEFAULT 1",
+                    (),
+                )?;
+            }
+            if !Self::column_exists(conn, "devices", "snoozed_until")? {
+                conn.execute("ALTER TABLE devices ADD COLUMN snoozed_until TEXT", ())?;
+            }
+            if !Self::column_exists(conn, "devices", "expires_at")? {
+                conn.execute("ALTER TABLE devices ADD COLUMN expires_at TEXT", ())?;
+            }
+            return Ok(());
+        }
+
+        let legacy_columns = [
+            "installation_id",
+            "device_name",
+            "device_type",
+            "os_version",
+            "app_version",
+            "created_at",
+            "updated_at",
+        ];
+        for column in legacy_columns {
+            if !Self::column_exists(conn, "devices", column)? {
+                let _ = conn.execute(&format!("ALTER TABLE devices ADD COLUMN {column} TEXT"), ());
+            }
+        }
+
+        conn.execute("DROP TABLE IF EXISTS devices_old", ())?;
+        conn.execute("ALTER TABLE devices RENAME TO devices_old", ())?;
+        Self::create_devices_table(conn)?;
+        conn.execute(
+            r#"
+            INSERT OR IGNORE INTO devices (
+                device_token,
+                installation_id,
+                environment,
+                device_name,
+                device_type,
+                os_version,
+                app_version,
+                created_at,
+                updated_at
+            )
+            SELECT
+                device_token,
+                installation_id,
+                environment,
+                device_name,
+                device_type,
+                os_version,
+                app_version,
+                COALESCE(created_at, CURRENT_TIMESTAMP),
+                COALESCE(updated_at, CURRENT_TIMESTAMP)
+            FROM devices_old
+            WHERE device_token IS NOT NULL
+              AND environment IN ('sandbox', 'production')
+            "#,
+            (),
+        )?;
+        conn.execute("DROP TABLE devices_old", ())?;
+        Ok(())
+    }
+
+    fn migrate_pushes(conn: &Connection) -> Result<(), SeekwelError> {
+        if Self::table_exists(conn, "pushes")? && !Self::column_exists(conn, "pushes", "device_id")?
+        {
+            conn.execute("DROP TABLE pushes", ())?;
+        }
+        Self::create_pushes_table(conn)?;
+
+        if !Self::column_exists(conn, "pushes", "status")? {
+            conn.execute(
+                "ALTER TABLE pushes ADD COLUMN status TEXT NOT NULL DEFAULT 'sent'",
+                (),
+            )?;
+        }
+        if !Self::column_exists(conn, "pushes", "error")? {
+            conn.execute("ALTER TABLE pushes ADD COLUMN error TEXT", ())?;
+        }
+        if !Self::column_exists(conn, "pushes", "error_code")? {
+            conn.execute("ALTER TABLE pushes ADD COLUMN error_code TEXT", ())?;
+        }
+        if !Self::column_exists(conn, "pushes", "actions")? {
+            conn.execute("ALTER TABLE pushes ADD COLUMN actions TEXT", ())?;
+        }
+        if !Self::column_exists(conn, "pushes", "content_hash")? {
+            conn.execute("ALTER TABLE pushes ADD COLUMN content_hash TEXT", ())?;
+        }
+        if !Self::column_exists(conn, "pushes", "environment")? {
+            conn.execute("ALTER TABLE pushes ADD COLUMN environment TEXT", ())?;
+        }
+        if !Self::column_exists(conn, "pushes", "opened_at")? {
+            conn.execute("ALTER TABLE pushes ADD COLUMN opened_at TEXT", ())?;
+        }
+        if !Self::column_exists(conn, "pushes", "full_body")? {
+            conn.execute("ALTER TABLE pushes ADD COLUMN full_body TEXT", ())?;
+        }
+        for column in [
+            "collapse_id",
+            "app",
+            "topic",
+            "api_key",
+            "read_at",
+            "inbox_deleted_at",
+        ] {
+            if !Self::column_exists(conn, "pushes", column)? {
+                conn.execute(&format!("ALTER TABLE pushes ADD COLUMN {column} TEXT"), ())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn table_exists(conn: &Connection, table: &str) -> Result<bool, SeekwelError> {
+        let exists: i64 = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+            params![table],
+            |row| row.get(0),
+        )?;
+        Ok(exists != 0)
+    }
+
+    fn column_exists(conn: &Connection, table: &str, column: &str) -> Result<bool, SeekwelError> {
+        let sql = format!("PRAGMA table_info({table})");
+        let columns: Vec<String> = conn.query_all(&sql, (), |row| row.get(1))?;
+        Ok(columns.iter().any(|name| name == column))
+    }
+
+    /// Registers a device and marks any other tokens from the same
+    /// installation as superseded, returning those tokens.
+    #[tracing::instrument(name = "db.upsert_device", skip_all)]
+    fn upsert_device(req: &RegisterRequest) -> Result<Vec<String>, SeekwelError> {
+        let conn = Connection::get()?;
+        Connection::transaction(|| {
+            Self::save_device(&conn, req)?;
+            Self::supersede_installation_tokens(&conn, &req.installation_id, &req.device_token)
+        })
+    }
+
+    fn save_device(conn: &Connection, req: &RegisterRequest) -> Result<(), SeekwelError> {
+        conn.execute(
+            r#"
+            INSERT INTO devices (
+                device_token,
+                installation_id,
+                environment,
+                device_name,
+                device_type,
+                os_version,
+                app_version,
+                locale,
+                timezone,
+                expires_at,
+                updated_at
+            )
+            VALUES (
+                ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9,
+                CASE WHEN ?10 IS NULL THEN NULL ELSE datetime('now', '+' || ?10 || ' seconds') END,
+                CURRENT_TIMESTAMP
+            )
+            ON CONFLICT(device_token) DO UPDATE SET
+                installation_id = excluded.installation_id,
+                environment = excluded.environment,
+                device_name = excluded.device_name,
+                device_type = excluded.device_type,
+                os_version = excluded.os_version,
+                app_version = excluded.app_version,
+                locale = excluded.locale,
+                timezone = excluded.timezone,
+                expires_at = excluded.expires_at,
+                updated_at = CURRENT_TIMESTAMP,
+                superseded_at = NULL
+            "#,
+            params![
+                token_cipher::seal(&req.device_token),
+                req.installation_id,
+                req.environment.as_str(),
+                req.device_name,
+                req.device_type,
+                req.os_version,
+                req.app_version,
+                req.locale,
+                req.timezone,
+                req.expires_in.map(|seconds| seconds as i64)
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn supersede_installation_tokens(
+        conn: &Connection,
+        installation_id: &str,
+        current_token: &str,
+    ) -> Result<Vec<String>, SeekwelError> {
+        if installation_id.is_empty() {
+            return Ok(Vec::new());
+        }
+        let current_token = &token_cipher::seal(current_token);
+        let superseded = conn.query_all(
+            r#"
+            SELECT device_token FROM devices
+            WHERE installation_id = ?1 AND device_token != ?2 AND superseded_at IS NULL
+            "#,
+            params![installation_id, current_token],
+            |row| token_cipher::open(row, 0),
+        )?;
+        if !superseded.is_empty() {
+            Self::carry_topics(conn, installation_id, current_token)?;
+            Self::carry_tags(conn, installation_id, current_token)?;
+            // A disabled or snoozed device stays so when its token rotates.
+            conn.execute(
+                r#"
+                UPDATE devices SET
+                    enabled = enabled AND NOT EXISTS (
+                        SELECT 1 FROM devices
+                        WHERE installation_id = ?1 AND device_token != ?2
+                          AND superseded_at IS NULL AND enabled = 0
+                    ),
+                    snoozed_until = COALESCE(snoozed_until, (
+                        SELECT MAX(snoozed_until) FROM devices
+                        WHERE installation_id = ?1 AND device_token != ?2
+                          AND superseded_at IS NULL
+                    ))
+                WHERE device_token = ?2
+                "#,
+                params![installation_id, current_token],
+            )?;
+            conn.execute(
+                r#"
+                UPDATE devices SET superseded_at = CURRENT_TIMESTAMP
+                WHERE installation_id = ?1 AND device_token != ?2 AND superseded_at IS NULL
+                "#,
+                params![installation_id, current_token],
+            )?;
+        }
+        Ok(superseded)
+    }
+
+    /// Moves a device to the environment APNs actually accepted its token in.
+    fn correct_device_environment(id: i64, environment: &str) -> Result<(), SeekwelError> {
+        Connection::get()?.execute(
+            "UPDATE devices SET environment = ?1, updated_at = CURRENT_TIMESTAMP WHERE id = ?2",
+            params![environment, id],
+        )?;
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "db.delivery_targets", skip_all)]
+    fn delivery_targets(filter: Option<&DeviceFilter>) -> Result<Vec<DeviceTarget>, SeekwelError> {
+        let no_filter = DeviceFilter::default();
+        let filter = filter.unwrap_or(&no_filter);
+        let (mut conditions, values) = filter.sql_conditions();
+        conditions.splice(
+            0..0,
+            [
+                "superseded_at IS NULL",
+                "enabled = 1",
+                "(expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)",
+            ],
+        );
+        let sql = format!(
+            "SELECT {DEVICE_TARGET_COLUMNS} FROM devices WHERE {} ORDER BY id",
+            conditions.join(" AND ")
+        );
+
+        let rows = Connection::get()?.query_all(&sql, values.as_slice(), device_target)?;
+
+        Ok(rows
+            .into_iter()
+            .filter(|target| {
+                filter.matches_versions(target.os_version.as_deref(), target.app_version.as_deref())
+            })
+            .collect())
+    }
+
+    /// The current registration of `device_token`, to send to it alone.
+    pub(crate) fn delivery_target(
+        device_token: &str,
+    ) -> Result<Option<DeviceTarget>, SeekwelError> {
+        Connection::get()?.query_optional(
+            &format!(
+                "SELECT {DEVICE_TARGET_COLUMNS} FROM devices \
+                 WHERE device_token = ?1 AND superseded_at IS NULL"
+            ),
+            params![token_cipher::seal(device_token)],
+            device_target,
+        )
+    }
+
+    fn stats() -> Result<StatsResponse, SeekwelError> {
+        let conn = Connection::get()?;
+        let total_devices = Self::count(
+            &conn,
+            "SELECT COUNT(*) FROM devices WHERE superseded_at IS NULL",
+        )?;
+        let sandbox_devices = Self::count(
+            &conn,
+            "SELECT COUNT(*) FROM devices WHERE environment = 'sandbox' AND superseded_at IS NULL",
+        )?;
+        let production_devices = Self::count(
+            &conn,
+            "SELECT COUNT(*) FROM devices WHERE environment = 'production' AND superseded_at IS NULL",
+        )?;
+        let total_pushes = Self::count(&conn, "SELECT COUNT(*) FROM pushes WHERE status = 'sent'")?;
+        let failed_pushes =
+            Self::count(&conn, "SELECT COUNT(*) FROM pushes WHERE status = 'failed'")?;
+        let opened_pushes = Self::count(
+            &conn,
+            "SELECT COUNT(*) FROM pushes WHERE status = 'sent' AND opened_at IS NOT NULL",
+        )?;
+        let (device_types, os_versions) = Self::device_platforms()?;
+
+        Ok(StatsResponse {
+            total_devices,
+            sandbox_devices,
+            production_devices,
+            total_pushes,
+            failed_pushes,
+            opened_pushes,
+            open_rate: stats::open_rate(opened_pushes, total_pushes),
+            device_types,
+            os_versions,
+            series: None,
+            groups: None,
+        })
+    }
+
+    fn count(conn: &Connection, sql: &str) -> Result<i64, SeekwelError> {
+        conn.query_row(sql, (), |row| row.get(0))
+    }
+
+    /// The installation's inbox, newest first: up to `limit` pushes (all
+    /// without one) newer than `after` and older than `before`.
+    fn pushes_for_installation(
+        installation_id: &str,
+        after: Option<i64>,
+        before: Option<i64>,
+        limit: Option<i64>,
+    ) -> Result<Vec<PushRecord>, SeekwelError> {
+        Connection::get()?.query_all(
+            r#"
+            SELECT
+                p.id,
+                d.device_token,
+                p.apns_id,
+                p.title,
+                p.body,
+                p.payload,
+                p.interruption_level,
+                p.sent_at,
+                p.read_at
+            FROM pushes p
+            JOIN devices d ON p.device_id = d.id
+            WHERE d.installation_id = ?1
+              AND p.status = 'sent'
+              AND p.inbox_deleted_at IS NULL
+              AND (?2 IS NULL OR p.id > ?2)
+              AND (?3 IS NULL OR p.id < ?3)
+            ORDER BY p.id DESC
+            LIMIT ?4
+            "#,
+            params![installation_id, after, before, limit.unwrap_or(-1)],
+            |row| {
+                Ok(PushRecord {
+                    id: row.get(0)?,
+                    device_token: token_cipher::open(row, 1)?,
+                    apns_id: row.get(2)?,
+                    title: row.get(3)?,
+                    body: row.get(4)?,
+                    payload: row.get(5)?,
+                    interruption_level: row.get(6)?,
+                    sent_at: row.get(7)?,
+                    read_at: row.get(8)?,
+                })
+            },
+        )
+    }
+
+    fn push_detail(push_id: i64) -> Result<Option<PushDetailRecord>, SeekwelError> {
+        Connection::get()?.query_optional(
+            r#"
+            SELECT
+                p.id,
+                p.apns_id,
+                p.title,
+                p.body,
+                p.payload,
+                p.interruption_level,
+                p.sent_at,
+                d.device_token,
+                d.device_name,
+                d.device_type,
+                COALESCE(p.environment, d.environment),
+                p.status,
+                p.error,
+                p.error_code,
+                p.actions,
+                p.opened_at,
+                p.full_body,
+                p.collapse_id
+            FROM pushes p
+            JOIN devices d ON p.device_id = d.id
+            WHERE p.id = ?1
+            "#,
+            params![push_id],
+            |row| {
+                Ok(PushDetailRecord {
+                    id: row.get(0)?,
+                    apns_id: row.get(1)?,
+                    title: row.get(2)?,
+                    body: row.get(3)?,
+                    payload: row.get(4)?,
+                    interruption_level: row.get(5)?,
+                    sent_at: row.get(6)?,
+                    device_token: token_cipher::open(row, 7)?,
+                    device_name: row.get(8)?,
+                    device_type: row.get(9)?,
+                    environment: row.get(10)?,
+                    status: row.get(11)?,
+                    error: row.get(12)?,
+                    error_code: row.get(13)?,
+                    actions: row
+                        .get::<_, Option<String>>(14)?
+                        .and_then(|actions| serde_json::from_str(&actions).ok()),
+                    opened_at: row.get(15)?,
+                    full_body: row.get(16)?,
+                    collapse_id: row.get(17)?,
+                })
+            },
+        )
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RegisterRequest {
+    device_token: String,
+    installation_id: String,
+    environment: Environment,
+    device_name: Option<String>,
+    device_type: Option<String>,
+    os_version: Option<String>,
+    app_version: Option<String>,
+    /// Picks the message catalog translation the device receives.
+    locale: Option<String>,
+    /// IANA time zone name such as `Europe/Paris`.
+    timezone: Option<String>,
+    /// Seconds until the registration lapses. Expired devices get no pushes
+    /// and are deleted by the retention task; registering again renews them.
+    expires_in: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Environment {
+    Sandbox,
+    Production,
+}
+
+impl Environment {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Environment::Sandbox => "sandbox",
+            Environment::Production => "production",
+        }
+    }
+
+    fn other(&self) -> Self {
+        match self {
+            Environment::Sandbox => Environment::Production,
+            Environment::Production => Environment::Sandbox,
+        }
+    }
+}
+
+impl TryFrom<&str> for Environment {
+    type Error = &'static str;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        match s {
+            "sandbox" => Ok(Environment::Sandbox),
+            "production" => Ok(Environment::Production),
+            _ => Err("invalid environment"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct RegisterResponse {
+    success: bool,
+    message: String,
+}
+
+/// A push as `/send` and the routes built on it take it, plus what the
+/// server works out while sending. It's serialized only to hand a delivery
+/// to a job queue worker. Tests reject unknown fields, so the CLI contract
+/// fixtures catch drift.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[cfg_attr(test, serde(deny_unknown_fields))]
+pub struct SendRequest {
+    // Alert options
+    title: Option<String>,
+    subtitle: Option<String>,
+    body: Option<String>,
+    launch_image: Option<String>,
+    /// Sends the title, subtitle and body as written instead of converting
+    /// their markdown to plain text.
+    raw: Option<bool>,
+    /// The body was cut to fit APNs' payload limit.
+    #[serde(skip)]
+    truncated: bool,
+    /// The body before it was cut, kept for push history.
+    #[serde(skip)]
+    full_body: Option<String>,
+    /// The API key that sent it, kept for per-key stats.
+    #[serde(skip)]
+    api_key: Option<String>,
+
+    // Localization
+    title_loc_key: Option<String>,
+    title_loc_args: Option<Vec<String>>,
+    loc_key: Option<String>,
+    loc_args: Option<Vec<String>>,
+    /// Catalog message whose translation replaces the title and body.
+    message_key: Option<String>,
+
+    // Badge & Sound
+    badge: Option<u32>,
+    sound: Option<SoundConfig>,
+
+    // Behavior
+    content_available: Option<bool>,
+    mutable_content: Option<bool>,
+    category: Option<String>,
+    /// Groups notifications into a thread in Notification Center.
+    thread_id: Option<String>,
+    interruption_level: Option<String>,
+    relevance_score: Option<f64>,
+    /// Window or scene identifier the app brings forward when opened.
+    target_content_id: Option<String>,
+    /// Unix time a Live Activity update is considered out of date.
+    stale_date: Option<u64>,
+    /// Focus filter criteria deciding whether the notification shows.
+    filter_criteria: Option<String>,
+    /// Live Activity `start`, `update` or `end`.
+    event: Option<String>,
+    /// The Live Activity's new dynamic state.
+    content_state: Option<serde_json::Value>,
+    /// Unix time an ended Live Activity leaves the Lock Screen.
+    dismissal_date: Option<u64>,
+    /// Buttons for the app to register under `category`, delivered in the
+    /// custom data and kept in push history.
+    actions: Option<Vec<actions::Action>>,
+    /// Send to only this percentage of the targeted devices, picked by
+    /// `collapse_id` so a canary can be widened to the same devices.
+    sample_percent: Option<f64>,
+
+    // Delivery options
+    /// The `apns-push-type`; defaults to `background` for silent pushes and
+    /// `alert` otherwise, and picks the topic suffix APNs expects.
+    push_type: Option<ApnsPushType>,
+    /// Sends under this `apns-topic` instead of the one derived from the
+    /// bundle ID and push type.
+    topic: Option<String>,
+    priority: Option<u8>,
+    collapse_id: Option<String>,
+    expiration: Option<u64>,
+    /// Relative alternative to `expiration`; 0 means deliver now or never.
+    expires_in_seconds: Option<u64>,
+    /// Hold background pushes to devices over APNs' hourly budget until the
+    /// next hour instead of sending them to be throttled.
+    defer_throttled: Option<bool>,
+
+    // Custom data
+    data: Option<HashMap<String, serde_json::Value>>,
+    /// An uploaded attachment's id; its signed link goes in the custom data.
+    attachment: Option<String>,
+
+    // Targeting, which queue workers don't need
+    #[serde(skip_serializing)]
+    filter: Option<DeviceFilter>,
+    #[serde(skip_serializing)]
+    segment: Option<String>,
+}
+
+impl SendRequest {
+    /// Critical alerts break through a device's snooze, as they do Focus.
+    fn is_critical(&self) -> bool {
+        self.interruption_level.as_deref() == Some("critical")
+            || matches!(
+                self.sound,
+                Some(SoundConfig::Critical {
+                    critical: Some(true),
+                    ..
+                })
+            )
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+enum SoundConfig {
+    Simple(String),
+    Critical {
+        name: String,
+        critical: Option<bool>,
+        volume: Option<f64>,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct SendResponse {
+    success: bool,
+    sent: usize,
+    failed: usize,
+    #[serde(skip_serializing_if = "is_zero")]
+    deferred: usize,
+    #[serde(skip_serializing_if = "is_zero")]
+    skipped: usize,
+    #[serde(skip_serializing_if = "is_zero")]
+    snoozed: usize,
+    /// The `x-request-id` this send was logged and audited under.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    /// The `apns-expiration` the pushes went out with, from the request or
+    /// the app's default TTL.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    apns_expiration: Option<u64>,
+    /// Set when a paused topic or segment held the send instead.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    paused: Option<pauses::PausedSend>,
+    results: Vec<DeviceSendResult>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct DeviceSendResult {
+    device_token: String,
+    success: bool,
+    apns_id: Option<String>,
+    error: Option<String>,
+    error_code: Option<ApnsErrorCode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warning: Option<String>,
+    /// Unix time a deferred background push goes out.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deferred_until: Option<u64>,
+    /// Not sent because the device got the same push within the dedup window.
+    #[serde(skip_serializing_if = "is_false")]
+    skipped_duplicate: bool,
+    /// Not sent because the device is snoozed and the push isn't critical.
+    #[serde(skip_serializing_if = "is_false")]
+    skipped_snoozed: bool,
+    /// The APNs environment the push went through after the device's own
+    /// rejected its token.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fallback_environment: Option<&'static str>,
+    /// The device's environment was updated to `fallback_environment`.
+    #[serde(skip_serializing_if = "is_false")]
+    environment_corrected: bool,
+    /// The body was cut to fit APNs' payload limit.
+    #[serde(skip_serializing_if = "is_false")]
+    truncated: bool,
+}
+
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorResponse {
+    success: bool,
+    error: String,
+}
+
+impl ErrorResponse {
+    fn with_status(status: StatusCode, error: impl Into<String>) -> (StatusCode, Json<Self>) {
+        (
+            status,
+            Json(Self {
+                success: false,
+                error: error.into(),
+            }),
+        )
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct StatsResponse {
+    total_devices: i64,
+    sandbox_devices: i64,
+    production_devices: i64,
+    total_pushes: i64,
+    failed_pushes: i64,
+    /// Delivered pushes the app reported as opened.
+    opened_pushes: i64,
+    /// `opened_pushes` as a share of delivered pushes, from 0 to 1.
+    open_rate: f64,
+    /// Current devices by device type, most common first.
+    device_types: Vec<PlatformCount>,
+    /// Current devices by major OS version, most common first.
+    os_versions: Vec<PlatformCount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    series: Option<StatsSeries>,
+    /// Push counts by `group_by`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    groups: Option<Vec<StatsGroup>>,
+}
+
+#[derive(Debug, Serialize)]
+struct PushRecord {
+    id: i64,
+    device_token: String,
+    apns_id: Option<String>,
+    title: Option<String>,
+    body: Option<String>,
+    payload: Option<String>,
+    interruption_level: Option<String>,
+    sent_at: String,
+    /// When the app marked it read in its inbox.
+    read_at: Option<String>,
+}
+
+/// A push as `GET /pushes?compact=true` lists it, without its custom data
+/// or device token.
+#[derive(Debug, Serialize)]
+struct CompactPushRecord {
+    id: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    apns_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interruption_level: Option<String>,
+    sent_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    read_at: Option<String>,
+}
+
+impl From<PushRecord> for CompactPushRecord {
+    fn from(push: PushRecord) -> Self {
+        CompactPushRecord {
+            id: push.id,
+            apns_id: push.apns_id,
+            title: push.title,
+            body: push.body,
+            interruption_level: push.interruption_level,
+            sent_at: push.sent_at,
+            read_at: push.read_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct PushesResponse<T = PushRecord> {
+    pushes: Vec<T>,
+    /// Pushes in the inbox not yet marked read, on every page.
+    unread: i64,
+    /// Passed as `cursor` for the next page; absent on the last.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PushesQuery {
+    installation_id: String,
+    /// Pushes per page. Without it or a cursor, every push is returned.
+    limit: Option<i64>,
+    /// `next_cursor` from the previous page.
+    cursor: Option<String>,
+    /// Only pushes newer than this id, for syncing what's new.
+    since_id: Option<i64>,
+    /// Leave out custom data and device tokens.
+    #[serde(default)]
+    compact: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct PushDetailRecord {
+    id: i64,
+    apns_id: Option<String>,
+    title: Option<String>,
+    body: Option<String>,
+    payload: Option<String>,
+    interruption_level: Option<String>,
+    sent_at: String,
+    device_token: String,
+    device_name: Option<String>,
+    device_type: Option<String>,
+    environment: Option<String>,
+    status: String,
+    error: Option<String>,
+    error_code: Option<String>,
+    /// The buttons the push advertised.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    actions: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    opened_at: Option<String>,
+    /// The body before it was cut to fit, when `store_full_body` is set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    full_body: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    collapse_id: Option<String>,
+}
+
+async fn register_device(
+    State(state): State<AppState>,
+    audit: AuditContext,
+    Json(mut req): Json<RegisterRequest>,
+) -> Result<Json<RegisterResponse>, (StatusCode, Json<ErrorResponse>)> {
+    req.device_token = token::normalize_device_token(&req.device_token, state.token_validation)
+        .map_err(|e| {
+            tracing::warn!(device_token = %token::logged(&req.device_token), error = %e, "Rejecting device token");
+            ErrorResponse::with_status(StatusCode::UNPROCESSABLE_ENTITY, e)
+        })?;
+    devices::normalize_locale_and_timezone(&req.device_token, &mut req.locale, &mut req.timezone);
+    if let Some(expires_in) = req.expires_in {
+        if expires_in == 0 || expires_in > MAX_REGISTRATION_TTL {
+            return Err(ErrorResponse::with_status(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("expires_in must be between 1 and {MAX_REGISTRATION_TTL} seconds"),
+            ));
+        }
+    }
+
+    tracing::info!(
+        device_token = %token::logged(&req.device_token),
+        installation_id = %req.installation_id,
+        environment = %req.environment.as_str(),
+        device_name = ?req.device_name,
+        "Registering device"
+    );
+
+    match Database::upsert_device(&req) {
+        Ok(superseded) => {
+            tracing::info!(device_token = %token::logged(&req.device_token), "Device registered");
+            audit::record(
+                &audit,
+                "register",
+                format!(
+                    "device_token={} installation_id={} environment={}",
+                    token::logged(&req.device_token),
+                    req.installation_id,
+                    req.environment.as_str()
+                ),
+            );
+            for old_token in superseded {
+                tracing::info!(
+                    installation_id = %req.installation_id,
+                    old_token = %token::logged(&old_token),
+                    new_token = %token::logged(&req.device_token),
+                    "Device token rotated"
+                );
+                audit::record(
+                    &audit,
+                    "device.token_rotated",
+                    format!(
+                        "installation_id={} old_token={} new_token={}",
+                        req.installation_id,
+                        token::logged(&old_token),
+                        token::logged(&req.device_token)
+                    ),
+                );
+            }
+            Ok(Json(RegisterResponse {
+                success: true,
+                message: "Device registered successfully".to_string(),
+            }))
+        }
+        Err(e) => {
+            tracing::error!(device_token = %token::logged(&req.device_token), error = %e, "Failed to register device");
+            Err(ErrorResponse::with_status(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to register device: {e}"),
+            ))
+        }
+    }
+}
+
+/// Reads a `/send` body: JSON or form fields when the content type says so,
+/// otherwise the text is the notification body and the query string
+/// (`?title=...`) sets the other fields.
+pub(crate) fn parse_send_request(
+    headers: &HeaderMap,
+    query: Option<&str>,
+    body: &[u8],
+) -> Result<SendRequest, (StatusCode, Json<ErrorResponse>)> {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let is_json = content_type.contains("application/json");
+    let is_form = content_type.starts_with("application/x-www-form-urlencoded");
+
+    let req: SendRequest = if is_json {
+        serde_json::from_slice(body).map_err(|e| {
+            tracing::warn!(error = %e, "Invalid JSON in send request");
+            ErrorResponse::with_status(StatusCode::BAD_REQUEST, format!("Invalid JSON: {e}"))
+        })?
+    } else if is_form && form::is_fields(body) {
+        form::parse(body).map_err(|e| {
+            tracing::warn!(error = %e, "Invalid form in send request");
+            ErrorResponse::with_status(StatusCode::BAD_REQUEST, format!("Invalid form: {e}"))
+        })?
+    } else {
+        let mut req = match query.filter(|q| !q.is_empty()) {
+            Some(query) => form::parse(query.as_bytes()).map_err(|e| {
+                tracing::warn!(error = %e, "Invalid query in send request");
+                ErrorResponse::with_status(StatusCode::BAD_REQUEST, format!("Invalid query: {e}"))
+            })?,
+            None => SendRequest::default(),
+        };
+        let body_text = String::from_utf8_lossy(body).trim_end().to_string();
+        if !body_text.is_empty() {
+            req.body = Some(body_text);
+        }
+        req
+    };
+
+    tracing::debug!(
+        content_type = content_type,
+        title = ?req.title,
+        body = ?req.body,
+        interruption_level = ?req.interruption_level,
+        relevance_score = ?req.relevance_score,
+        "Parsed send request"
+    );
+    Ok(req)
+}
+
+/// Rejects options APNs or the server can't honor with a 422, and resolves
+/// `expires_in_seconds` into `expiration`.
+pub(crate) fn validate_send_request(
+    req: &mut SendRequest,
+) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+    if let Some(priority) = req.priority {
+        ApnsPriority::from_u8(priority).map_err(|e| {
+            tracing::warn!(priority = priority, "Rejecting send with invalid priority");
+            ErrorResponse::with_status(StatusCode::UNPROCESSABLE_ENTITY, e)
+        })?;
+    }
+
+    if let Some(topic) = &req.topic {
+        if topic.is_empty() || topic.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            return Err(ErrorResponse::with_status(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("Invalid topic '{topic}'"),
+            ));
+        }
+    }
+
+    if let Some(event) = &req.event {
+        if !matches!(event.as_str(), "start" | "update" | "end") {
+            return Err(ErrorResponse::with_status(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("event must be start, update or end, got '{event}'"),
+            ));
+        }
+    }
+
+    resolve_expiration(req, unix_now())
+        .map_err(|e| ErrorResponse::with_status(StatusCode::UNPROCESSABLE_ENTITY, e))?;
+    actions::validate(req)
+        .map_err(|e| ErrorResponse::with_status(StatusCode::UNPROCESSABLE_ENTITY, e))?;
+    if req.raw != Some(true) {
+        for text in [&mut req.title, &mut req.subtitle, &mut req.body]
+            .into_iter()
+            .flatten()
+        {
+            *text = markdown::to_plain_text(text);
+        }
+    }
+    templates::validate(req)
+        .map_err(|e| ErrorResponse::with_status(StatusCode::UNPROCESSABLE_ENTITY, e))?;
+    attachments::inject(req)?;
+    if let Some(percent) = req.sample_percent {
+        if !(percent > 0.0 && percent <= 100.0) {
+            return Err(ErrorResponse::with_status(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("sample_percent must be greater than 0 and at most 100, got {percent}"),
+            ));
+        }
+    }
+    Ok(())
+}
+
+async fn send_notification(
+    State(state): State<AppState>,
+    audit: AuditContext,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<SendResponse>, (StatusCode, Json<ErrorResponse>)> {
+    tracing::info!(body_len = body.len(), "Received send request");
+    let req = parse_send_request(&headers, query.as_deref(), &body)?;
+    send(state, audit, req).await
+}
+
+/// `GET /send?title=...&body=...`, for systems that can only fire GET
+/// webhooks. Takes the same flat fields as a form body.
+async fn send_query(
+    State(state): State<AppState>,
+    audit: AuditContext,
+    method: Method,
+    RawQuery(query): RawQuery,
+) -> Result<Json<SendResponse>, (StatusCode, Json<ErrorResponse>)> {
+    // The router answers HEAD with the GET handler; a link checker's HEAD
+    // shouldn't notify anyone.
+    if method == Method::HEAD {
+        return Err(ErrorResponse::with_status(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "HEAD /send doesn't send, use GET",
+        ));
+    }
+    tracing::info!("Received send request by query");
+    let req = form::parse(query.unwrap_or_default().as_bytes()).map_err(|e| {
+        tracing::warn!(error = %e, "Invalid query in send request");
+        ErrorResponse::with_status(StatusCode::BAD_REQUEST, format!("Invalid query: {e}"))
+    })?;
+    send(state, audit, req).await
+}
+
+/// Sends `req` to the devices it targets. The send goes on to every one of
+/// them even if the request that asked for it is abandoned.
+pub(crate) async fn send(
+    state: AppState,
+    audit: AuditContext,
+    req: SendRequest,
+) -> Result<Json<SendResponse>, (StatusCode, Json<ErrorResponse>)> {
+    limits::run_to_completion(send_to_targets(state, audit, req)).await
+}
+
+async fn send_to_targets(
+    state: AppState,
+    audit: AuditContext,
+    mut req: SendRequest,
+) -> Result<Json<SendResponse>, (StatusCode, Json<ErrorResponse>)> {
+    // Validation resolves relative fields, so a paused send keeps the
+    // original to be validated again when it's replayed.
+    let received = req.clone();
+    validate_send_request(&mut req)?;
+
+    let message = match &req.message_key {
+        Some(key) => {
+            let message = Database::message(key).map_err(|e| {
+                tracing::error!(message_key = %key, error = %e, "Database error fetching message");
+                ErrorResponse::with_status(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Database error: {e}"),
+                )
+            })?;
+            Some(message.ok_or_else(|| catalog::message_not_found(key))?)
+        }
+        None => None,
+    };
+
+    let filter = resolve_filter(&req)?;
+    // Critical alerts break through a pause, as they do a snooze.
+    if !req.is_critical() {
+        if let Some(response) = pauses::hold(&audit, &received, filter.as_ref())? {
+            return Ok(Json(response));
+        }
+    }
+    let devices = Database::delivery_targets(filter.as_ref()).map_err(|e| {
+        tracing::error!(error = %e, "Database error fetching devices");
+        ErrorResponse::with_status(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Database error: {e}"),
+        )
+    })?;
+
+    tracing::info!(device_count = devices.len(), "Found devices to notify");
+
+    if devices.is_empty() {
+        if filter.is_some() {
+            tracing::warn!(filter = ?filter, "No devices match filter, nothing to send");
+            return Err(ErrorResponse::with_status(
+                StatusCode::NOT_FOUND,
+                "No devices match filter",
+            ));
+        }
+        tracing::warn!("No devices registered, nothing to send");
+        return Err(ErrorResponse::with_status(
+            StatusCode::NOT_FOUND,
+            "No devices registered",
+        ));
+    }
+
+    let devices = match req.sample_percent {
+        Some(percent) => {
+            let seed = req.collapse_id.clone().unwrap_or_else(random_seed);
+            let targeted = devices.len();
+            let sampled: Vec<DeviceTarget> = devices
+                .into_iter()
+                .filter(|device| filter::in_sample(&seed, &device.device_token, percent))
+                .collect();
+            tracing::info!(percent = percent, seed = %seed, targeted = targeted, selected = sampled.len(), "Sampled devices");
+            if sampled.is_empty() {
+                return Err(ErrorResponse::with_status(
+                    StatusCode::NOT_FOUND,
+                    format!("No devices in the {percent}% sample of {targeted} targeted"),
+                ));
+            }
+            sampled
+        }
+        None => devices,
+    };
+
+    // Checked against every targeted device, but only the pushes that go
+    // out are counted, once the send is done.
+    if let Some(caller) = &audit.caller {
+        quota::check(caller, devices.len())?;
+        req.api_key = Some(caller.name.clone());
+    }
+
+    let mut queued = state.queue.enqueue(devices.len());
+    let dispatch_lane = Lane::of(&req, devices.len(), state.lanes.bulk_threshold());
+    tracing::info!(lane = dispatch_lane.as_str(), "Dispatching in lane");
+    let mut lane = state.lanes.enter(dispatch_lane, devices.len());
+    actions::inject(&mut req);
+    // Via Value, whose maps sort their keys, so equal data records the same.
+    let payload_json = serde_json::to_value(&req.data)
+        .and_then(|data| serde_json::to_string(&data))
+        .ok();
+    // Templates read the custom data as sent, so keep it from encryption.
+    let templated = templates::is_templated(&req, message.as_ref());
+    let template_data = templated.then(|| req.data.clone()).flatten();
+    let device_tags = if templated {
+        Database::current_device_tags().map_err(|e| {
+            tracing::error!(error = %e, "Database error fetching device tags");
+            ErrorResponse::with_status(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Database error: {e}"),
+            )
+        })?
+    } else {
+        HashMap::new()
+    };
+
+    let app = Database::app(&state.bundle_id).map_err(|e| {
+        tracing::error!(error = %e, "Database error fetching app settings");
+        ErrorResponse::with_status(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Database error: {e}"),
+        )
+    })?;
+    if req.expiration.is_none() {
+        if let Some(ttl) = app.as_ref().and_then(|app| app.default_ttl_seconds) {
+            req.expiration = Some(if ttl == 0 { 0 } else { unix_now() + ttl });
+        }
+    }
+    if let Some(data) = req.data.as_ref().filter(|data| !data.is_empty()) {
+        if let Some(key) = app.as_ref().and_then(|app| app.encryption_key.as_deref()) {
+            tracing::debug!(bundle_id = %state.bundle_id, "Encrypting custom data");
+            let sealed = apps::encrypt_data(key, data).map_err(|e| {
+                tracing::error!(error = %e, "Failed to encrypt custom data");
+                ErrorResponse::with_status(StatusCode::INTERNAL_SERVER_ERROR, e)
+            })?;
+            req.data = Some(sealed);
+        }
+    }
+    let signing_keys = app
+        .as_ref()
+        .map(signing::SigningKeys::for_app)
+        .transpose()
+        .map_err(|e| {
+            tracing::error!(error = %e, "Invalid signing key");
+            ErrorResponse::with_status(StatusCode::INTERNAL_SERVER_ERROR, e)
+        })?
+        .flatten();
+    let signing = signing_keys.as_ref().map(|keys| (keys, unix_now()));
+    // Catalog messages are cut and signed once localized, as each
+    // translation is, and templated ones once rendered for each device.
+    if let (None, false) = (&message, templated) {
+        truncate::apply_signed(&state.truncation, &mut req, signing);
+    }
+
+    let mut results = Vec::new();
+    let mut sent = 0;
+    let mut failed = 0;
+    let mut deferred = 0;
+    let mut skipped = 0;
+    let mut snoozed = 0;
+    let mut history = HistoryBuffer::default();
+    // Localized requests, shared by devices resolving to the same translation.
+    let mut localized: HashMap<Option<String>, SendRequest> = HashMap::new();
+    // Deliveries for the job queue, handed over together once all are ready.
+    let mut jobs = Vec::new();
+
+    for device in devices {
+        queued.complete_one();
+        tracing::debug!(device_token = %token::logged(&device.device_token), environment = %device.environment, platform = %device.platform, "Sending to device");
+
+        let req = match &message {
+            Some(message) => {
+                let locale = device.locale.as_deref();
+                &*localized
+                    .entry(message.resolved_locale(locale).map(str::to_string))
+                    .or_insert_with(|| {
+                        let mut localized = message.localize(&req, locale);
+                        if !templated {
+                            truncate::apply_signed(&state.truncation, &mut localized, signing);
+                        }
+                        localized
+                    })
+            }
+            None => &req,
+        };
+        let rendered;
+        let req = if templated {
+            let vars = templates::Variables {
+                device: &device,
+                tags: device_tags.get(&device.device_token),
+                data: template_data.as_ref(),
+            };
+            let mut req = vars.render(req);
+            truncate::apply_signed(&state.truncation, &mut req, signing);
+            rendered = req;
+            &rendered
+        } else {
+            req
+        };
+
+        if device.snoozed && !req.is_critical() {
+            tracing::info!(device_token = %token::logged(&device.device_token), "Skipping push to snoozed device");
+            results.push(DeviceSendResult {
+                device_token: device.device_token,
+                success: true,
+                skipped_snoozed: true,
+                ..Default::default()
+            });
+            snoozed += 1;
+            continue;
+        }
+
+        if let Some(window) = state.dedup_window {
+            let content_hash = dedup::content_hash(req, payload_json.as_deref());
+            match Database::sent_recently(device.id, &content_hash, window) {
+                Ok(true) => {
+                    tracing::info!(device_token = %token::logged(&device.device_token), "Skipping duplicate push");
+                    results.push(DeviceSendResult {
+                        device_token: device.device_token,
+                        success: true,
+                        skipped_duplicate: true,
+                        ..Default::default()
+                    });
+                    skipped += 1;
+                    continue;
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    tracing::error!(device_token = %token::logged(&device.device_token), error = %e, "Failed to check for duplicate pushes, sending anyway");
+                }
+            }
+        }
+
+        let mut warning = None;
+        if background::is_throttled(req, device.platform) {
+            let count = Database::background_pushes_this_hour(device.id).unwrap_or_else(|e| {
+                tracing::error!(device_token = %token::logged(&device.device_token), error = %e, "Failed to count background pushes");
+                0
+            });
+            if count >= background::HOURLY_BUDGET {
+                tracing::warn!(device_token = %token::logged(&device.device_token), count = count, "Device is over its background push budget");
+                warning = Some(background::budget_warning(count));
+                if req.defer_throttled == Some(true) {
+                    let deferred_until = background::next_hour(unix_now());
+                    tracing::info!(device_token = %token::logged(&device.device_token), deferred_until = deferred_until, "Deferring background push");
+                    results.push(DeviceSendResult {
+                        device_token: device.device_token.clone(),
+                        success: true,
+                        apns_id: None,
+                        error: None,
+                        error_code: None,
+                        warning,
+                        deferred_until: Some(deferred_until),
+                        ..Default::default()
+                    });
+                    tokio::spawn(
+                        deliver_deferred(state.clone(), device, req.clone(), payload_json.clone())
+                            .in_current_span(),
+                    );
+                    deferred += 1;
+                    continue;
+                }
+            }
+        }
+
+        if state.job_queue.is_some() {
+            jobs.push((device, req.clone(), warning));
+            continue;
+        }
+
+        let span = tracing::info_span!(
+            "deliver",
+            device_token = %token::logged(&device.device_token),
+            platform = %device.platform
+        );
+        let permit = lane.acquire().await;
+        let (result, record) = deliver(&state, &device, req, payload_json.as_deref())
+            .instrument(span)
+            .await;
+        drop(permit);
+        if result.success {
+            sent += 1;
+        } else {
+            failed += 1;
+        }
+        results.push(DeviceSendResult {
+            warning,
+            truncated: req.truncated,
+            ..result
+        });
+        history.push(record);
+    }
+    if let (Some(queue), false) = (&state.job_queue, jobs.is_empty()) {
+        let batch: Vec<_> = jobs.iter().map(|(device, req, _)| (device, req)).collect();
+        let attempts = match queue.dispatch(dispatch_lane, &batch).await {
+            Ok(attempts) => attempts,
+            Err(e) => {
+                tracing::warn!(error = %e, "Job queue unavailable, delivering in process");
+                let mut attempts = Vec::with_capacity(batch.len());
+                for (device, req) in batch {
+                    let target = Target {
+                        token: &device.device_token,
+                        environment: &device.environment,
+                    };
+                    let permit = lane.acquire().await;
+                    attempts.push(attempt(&state, device.platform, target, req).await);
+                    drop(permit);
+                }
+                attempts
+            }
+        };
+        for ((device, req, warning), attempt) in jobs.into_iter().zip(attempts) {
+            let (result, record) = finish(&device, &req, payload_json.as_deref(), attempt);
+            if result.success {
+                sent += 1;
+            } else {
+                failed += 1;
+            }
+            results.push(DeviceSendResult {
+                warning,
+                truncated: req.truncated,
+                ..result
+            });
+            history.push(record);
+        }
+    }
+    history.flush();
+    if let Some(caller) = &audit.caller {
+        quota::record(caller, sent + failed + deferred);
+    }
+
+    tracing::info!(
+        sent = sent,
+        failed = failed,
+        deferred = deferred,
+        skipped = skipped,
+        snoozed = snoozed,
+        "Send complete"
+    );
+    let mut summary = send_summary(&req, sent, failed);
+    if deferred > 0 {
+        summary.push_str(&format!(", {deferred} deferred"));
+    }
+    if skipped > 0 {
+        summary.push_str(&format!(", {skipped} skipped as duplicates"));
+    }
+    if snoozed > 0 {
+        summary.push_str(&format!(", {snoozed} snoozed"));
+    }
+    audit::record(&audit, "send", summary);
+
+    Ok(Json(SendResponse {
+        success: sent + deferred + skipped + snoozed > 0,
+        sent,
+        failed,
+        deferred,
+        skipped,
+        snoozed,
+        request_id: audit.request_id.clone(),
+        apns_expiration: req.expiration,
+        paused: None,
+        results,
+    }))
+}
+
+/// A push its provider accepted.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Delivered {
+    apns_id: String,
+    /// The APNs environment it went through after the device's own rejected
+    /// its token.
+    fallback_environment: Option<&'static str>,
+}
+
+/// Sends `req` to one device, returning the outcome and its history row for
+/// the caller to write.
+async fn deliver(
+    state: &AppState,
+    device: &DeviceTarget,
+    req: &SendRequest,
+    payload_json: Option<&str>,
+) -> (DeviceSendResult, PendingPush) {
+    let target = Target {
+        token: &device.device_token,
+        environment: &device.environment,
+    };
+    let attempt = attempt(state, device.platform, target, req).await;
+    finish(device, req, payload_json, attempt)
+}
+
+/// Hands `req` to the provider for `platform`, retrying in the other APNs
+/// environment when that's on and the token was rejected.
+async fn attempt(
+    state: &AppState,
+    platform: Platform,
+    target: Target<'_>,
+    req: &SendRequest,
+) -> Result<Delivered, SendError> {
+    let mut result = state.providers.send(platform, req, target).await;
+    let mut fallback_environment = None;
+    if let Err(error) = &result {
+        if let Some(other) = fallback_environment_for(state, platform, target.environment, error) {
+            tracing::warn!(device_token = %token::logged(target.token), environment = %target.environment, "APNs rejected the device token, retrying in {other}");
+            let target = Target {
+                environment: other,
+                ..target
+            };
+            match state.providers.send(platform, req, target).await {
+                Ok(apns_id) => {
+                    result = Ok(apns_id);
+                    fallback_environment = Some(other);
+                }
+                Err(e) => {
+                    tracing::info!(device_token = %token::logged(target.token), error_code = %e.code, "The {other} environment rejected the token too");
+                }
+            }
+        }
+    }
+    result.map(|apns_id| Delivered {
+        apns_id,
+        fallback_environment,
+    })
+}
+
+/// The result and history row for a delivery to `device`, correcting its
+/// environment when the push only went through in the other one.
+fn finish(
+    device: &DeviceTarget,
+    req: &SendRequest,
+    payload_json: Option<&str>,
+    attempt: Result<Delivered, SendError>,
+) -> (DeviceSendResult, PendingPush) {
+    match attempt {
+        Ok(Delivered {
+            apns_id,
+            fallback_environment,
+        }) => {
+            tracing::info!(device_token = %token::logged(&device.device_token), apns_id = %apns_id, "Push sent");
+            let environment = fallback_environment.unwrap_or(&device.environment);
+            let record = PendingPush::sent(device, environment, &apns_id, req, payload_json);
+            let environment_corrected = match fallback_environment {
+                Some(environment) => {
+                    match Database::correct_device_environment(device.id, environment) {
+                        Ok(()) => {
+                            tracing::info!(device_token = %token::logged(&device.device_token), from = %device.environment, to = environment, "Corrected device environment");
+                            true
+                        }
+                        Err(e) => {
+                            tracing::error!(device_token = %token::logged(&device.device_token), error = %e, "Failed to correct device environment");
+                            false
+                        }
+                    }
+                }
+                None => false,
+            };
+            let result = DeviceSendResult {
+                device_token: device.device_token.clone(),
+                success: true,
+                apns_id: Some(apns_id),
+                error: None,
+                error_code: None,
+                fallback_environment,
+                environment_corrected,
+                ..Default::default()
+            };
+            (result, record)
+        }
+        Err(error) => {
+            tracing::error!(device_token = %token::logged(&device.device_token), error_code = %error.code, error = %error.message, "Push failed");
+            let record = PendingPush::failed(device, req, payload_json, &error);
+            let result = DeviceSendResult {
+                device_token: device.device_token.clone(),
+                success: false,
+                apns_id: None,
+                error: Some(error.message),
+                error_code: Some(error.code),
+                ..Default::default()
+            };
+            (result, record)
+        }
+    }
+}
+
+/// The environment to retry an APNs send in when fallback is on and APNs
+/// said the token doesn't belong to the device's registered one.
+fn fallback_environment_for(
+    state: &AppState,
+    platform: Platform,
+    environment: &str,
+    error: &SendError,
+) -> Option<&'static str> {
+    if !state.environment_fallback
+        || platform != Platform::Apns
+        || error.code != ApnsErrorCode::BadDeviceToken
+    {
+        return None;
+    }
+    Environment::try_from(environment)
+        .ok()
+        .map(|environment| environment.other().as_str())
+}
+
+/// Sends a deferred background push once its device is back under budget,
+/// checking at the top of each hour. Deferred pushes live in memory only.
+async fn deliver_deferred(
+    state: AppState,
+    device: DeviceTarget,
+    req: SendRequest,
+    payload_json: Option<String>,
+) {
+    loop {
+        tokio::time::sleep(background::delay_until_next_hour(unix_now())).await;
+        match Database::background_pushes_this_hour(device.id) {
+            Ok(count) if count >= background::HOURLY_BUDGET => {
+                tracing::info!(device_token = %token::logged(&device.device_token), count = count, "Device still over its background push budget, deferring again");
+            }
+            Ok(_) => break,
+            Err(e) => {
+                tracing::error!(device_token = %token::logged(&device.device_token), error = %e, "Failed to count background pushes, sending anyway");
+                break;
+            }
+        }
+    }
+
+    tracing::info!(device_token = %token::logged(&device.device_token), "Sending deferred background push");
+    let (_, record) = deliver(&state, &device, &req, payload_json.as_deref()).await;
+    let mut history = HistoryBuffer::default();
+    history.push(record);
+    history.flush();
+    state.response_cache.invalidate();
+}
+
+/// One-line description of a send for the audit log.
+fn send_summary(req: &SendRequest, sent: usize, failed: usize) -> String {
+    let mut summary = format!("{sent} sent, {failed} failed");
+    if let Some(segment) = &req.segment {
+        summary.push_str(&format!(", segment={segment}"));
+    }
+    if req.filter.is_some() {
+        summary.push_str(", filtered");
+    }
+    if let Some(percent) = req.sample_percent {
+        summary.push_str(&format!(", sample={percent}%"));
+    }
+    if let Some(key) = &req.message_key {
+        summary.push_str(&format!(", message={key}"));
+    }
+    if let Some(title) = &req.title {
+        summary.push_str(&format!(", title={title:?}"));
+    }
+    if let Some(body) = &req.body {
+        let body: String = body.chars().take(80).collect();
+        summary.push_str(&format!(", body={body:?}"));
+    }
+    summary
+}
+
+/// A sampling seed for sends without a `collapse_id`.
+fn random_seed() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    format!("{nanos:x}")
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Furthest ahead a push may expire. APNs only stores undelivered pushes
+/// for a limited time, so anything later is almost always a mistake.
+pub(crate) const MAX_EXPIRATION_SECONDS: u64 = 30 * 24 * 60 * 60;
+
+/// Turns `expires_in_seconds` into the absolute `expiration` APNs expects,
+/// and rejects an `expiration` that has passed or is implausibly far off,
+/// which APNs would otherwise take as "never store" or quietly clamp.
+fn resolve_expiration(req: &mut SendRequest, now: u64) -> Result<(), String> {
+    if let Some(expires_in) = req.expires_in_seconds {
+        if req.expiration.is_some() {
+            return Err("Pass either expiration or expires_in_seconds, not both".to_string());
+        }
+        if expires_in > MAX_EXPIRATION_SECONDS {
+            return Err(format!(
+                "expires_in_seconds must be at most {MAX_EXPIRATION_SECONDS} (30 days), got {expires_in}"
+            ));
+        }
+        req.expiration = Some(if expires_in == 0 {
+            0
+        } else {
+            now.saturating_add(expires_in)
+        });
+        return Ok(());
+    }
+    match req.expiration {
+        None | Some(0) => Ok(()),
+        Some(expiration) if expiration < now => Err(format!(
+            "expiration {expiration} is {}s in the past; use 0 to deliver now or never",
+            now - expiration
+        )),
+        // Unix time in milliseconds, as JavaScript's Date.now() gives; in
+        // seconds this would be tens of thousands of years away.
+        Some(expiration) if expiration >= 1_000_000_000_000 => Err(format!(
+            "expiration {expiration} looks like milliseconds; it's Unix time in seconds"
+        )),
+        Some(expiration) if expiration - now > MAX_EXPIRATION_SECONDS => Err(format!(
+            "expiration {expiration} is more than 30 days ahead; APNs doesn't keep pushes that long"
+        )),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Combines the request's named segment (if any) with its inline filter,
+/// letting inline fields override the segment's.
+fn resolve_filter(
+    req: &SendRequest,
+) -> Result<Option<DeviceFilter>, (StatusCode, Json<ErrorResponse>)> {
+    let Some(name) = &req.segment else {
+        return Ok(req.filter.clone());
+    };
+
+    let segment = Database::segment(name).map_err(|e| {
+        tracing::error!(segment = %name, error = %e, "Database error fetching segment");
+        ErrorResponse::with_status(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Database error: {e}"),
+        )
+    })?;
+
+    match segment {
+        Some(segment) => Ok(Some(match &req.filter {
+            Some(overrides) => segment.filter.overlay(overrides),
+            None => segment.filter,
+        })),
+        None => {
+            tracing::warn!(segment = %name, "Segment not found");
+            Err(ErrorResponse::with_status(
+                StatusCode::NOT_FOUND,
+                format!("Segment not found: {name}"),
+            ))
+        }
+    }
+}
+
+async fn get_stats(
+    State(state): State<AppState>,
+    Query(query): Query<StatsQuery>,
+) -> Result<Json<StatsResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let window = query
+        .window()
+        .map_err(|e| ErrorResponse::with_status(StatusCode::BAD_REQUEST, e))?;
+    let database_error = |e: SeekwelError| {
+        ErrorResponse::with_status(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Database error: {e}"),
+        )
+    };
+
+    let mut stats = Database::stats().map_err(database_error)?;
+    if let Some(group_by) = query.group_by {
+        let groups = Database::push_groups(
+            group_by,
+            &state.bundle_id,
+            window.as_ref().map(|window| window.window),
+        )
+        .map_err(database_error)?;
+        stats.groups = Some(groups);
+    }
+    if let Some(window) = window {
+        stats.series = Some(Database::push_series(&window).map_err(database_error)?);
+    }
+    Ok(Json(stats))
+}
+
+/// `GET /pushes?installation_id=...`: an installation's inbox. Answers
+/// `If-None-Match` or `If-Modified-Since` with a 304 when nothing in the
+/// inbox has changed.
+async fn get_pushes(
+    State(state): State<AppState>,
+    Query(query): Query<PushesQuery>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    tracing::debug!(installation_id = %query.installation_id, "Fetching pushes");
+
+    let before =
+        match query.cursor.as_deref() {
+            Some(cursor) => Some(cursor.parse::<i64>().map_err(|_| {
+                ErrorResponse::with_status(StatusCode::BAD_REQUEST, "Invalid cursor")
+            })?),
+            None => None,
+        };
+    let limit = match (query.limit, before) {
+        (None, None) => None,
+        (limit, _) => Some(
+            limit
+                .unwrap_or(DEFAULT_INBOX_LIMIT)
+                .clamp(1, MAX_INBOX_LIMIT),
+        ),
+    };
+    let database_error = |e: SeekwelError| {
+        tracing::error!(error = %e, "Database error fetching pushes");
+        ErrorResponse::with_status(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Database error: {e}"),
+        )
+    };
+    let validators = Database::inbox_validators(&query.installation_id).map_err(database_error)?;
+    if inbox::not_modified(&headers, &validators) {
+        tracing::debug!(installation_id = %query.installation_id, "Pushes not modified");
+        let mut response = StatusCode::NOT_MODIFIED.into_response();
+        validators.apply(&mut response);
+        return Ok(response);
+    }
+    // One past the page says whether there's another.
+    let mut pushes = Database::pushes_for_installation(
+        &query.installation_id,
+        query.since_id,
+        before,
+        limit.map(|limit| limit + 1),
+    )
+    .map_err(database_error)?;
+    let next_cursor = match limit {
+        Some(limit) if pushes.len() as i64 > limit => {
+            pushes.truncate(limit as usize);
+            pushes.last().map(|push| push.id.to_string())
+        }
+        _ => None,
+    };
+    let unread = Database::unread_count(&query.installation_id).map_err(database_error)?;
+
+    tracing::debug!(count = pushes.len(), "Returning pushes");
+    if state.redact_tokens {
+        for push in &mut pushes {
+            push.device_token = token::redact(&push.device_token);
+        }
+    }
+
+    let mut response = if query.compact {
+        Json(PushesResponse {
+            pushes: pushes.into_iter().map(CompactPushRecord::from).collect(),
+            unread,
+            next_cursor,
+        })
+        .into_response()
+    } else {
+        Json(PushesResponse {
+            pushes,
+            unread,
+            next_cursor,
+        })
+        .into_response()
+    };
+    validators.apply(&mut response);
+    Ok(response)
+}
+
+async fn get_push_detail(
+    State(state): State<AppState>,
+    Path(push_id): Path<i64>,
+) -> Result<Json<PushDetailRecord>, (StatusCode, Json<ErrorResponse>)> {
+    tracing::debug!(push_id = push_id, "Fetching push detail");
+
+    let push = Database::push_detail(push_id).map_err(|e| {
+        tracing::error!(push_id = push_id, error = %e, "Database error fetching push detail");
+        ErrorResponse::with_status(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Database error: {e}"),
+        )
+    })?;
+
+    match push {
+        Some(mut p) => {
+            if state.redact_tokens {
+                p.device_token = token::redact(&p.device_token);
+            }
+            Ok(Json(p))
+        }
+        None => {
+            tracing::warn!(push_id = push_id, "Push not found");
+            Err(ErrorResponse::with_status(
+                StatusCode::NOT_FOUND,
+                "Push not found",
+            ))
+        }
+    }
+}
+
+/// Builds the app with `build_app` and serves it on `PSH_LISTEN` (port 3000
+/// by default) until shutdown.
+pub async fn run(config: ServerConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let listen = listen::Listen::from_env()?;
+    let app = build_app(config).await?;
+    if let Some(interval) = systemd::watchdog_interval() {
+        tracing::info!(
+            interval_seconds = interval.as_secs(),
+            "Pinging the systemd watchdog"
+        );
+        systemd::spawn_watchdog(interval);
+    }
+
+    listen::serve(&listen, app).await?;
+
+    tracing::info!("Server stopped");
+    Ok(())
+}
+
+/// The push endpoints as `run` serves them, configured by `config` and the
+/// environment, for another axum app to mount under its own router
+/// (`Router::new().nest("/push", app)`). Connects the database and starts
+/// the background tasks, but leaves listening to the caller.
+pub async fn build_app(config: ServerConfig) -> Result<Router, Box<dyn std::error::Error>> {
+    Ok(router(build_state(config).await?))
+}
+
+/// The state behind `build_app`'s router, for callers that build the router
+/// themselves with `router`.
+pub async fn build_state(config: ServerConfig) -> Result<AppState, Box<dyn std::error::Error>> {
+    let database_url = env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite:data.db".to_string());
+    tracing::info!(database_url = %database_url, "Connecting to database");
+
+    Database::initialize(&database_url)?;
+    tracing::info!("Database initialized");
+
+    let token_cipher = config
+        .token_encryption
+        .as_ref()
+        .map(token_cipher::TokenCipher::from_config)
+        .transpose()?;
+    let encrypting = token_cipher.is_some();
+    token_cipher::set_cipher(token_cipher);
+    let sealed = Database::seal_device_tokens()?;
+    if encrypting {
+        tracing::info!(sealed = sealed, "Encrypting device tokens at rest");
+    }
+
+    let token_validation = TokenValidation::from_env()?;
+    tracing::info!(mode = token_validation.as_str(), "Device token validation");
+
+    let redact_tokens = token::redaction_from_env()?;
+    token::set_log_redaction(redact_tokens);
+    if redact_tokens {
+        tracing::info!("Redacting device tokens in logs and push history");
+    }
+
+    let dedup_window = dedup::window_from_env()?;
+    if let Some(window) = dedup_window {
+        tracing::info!(
+            window_seconds = window.as_secs(),
+            "Skipping duplicate pushes"
+        );
+    }
+
+    let anonymize_after = retention::anonymize_after_from_env()?;
+    match retention::interval_from_env()? {
+        Some(interval) => {
+            tracing::info!(
+                interval_seconds = interval.as_secs(),
+                instance_id = leases::instance_id(),
+                "Purging expired registrations"
+            );
+            if let Some(age) = anonymize_after {
+                tracing::info!(after_seconds = age.as_secs(), "Anonymizing old pushes");
+            }
+            retention::spawn(interval, anonymize_after);
+        }
+        None if anonymize_after.is_some() => {
+            return Err(
+                "PSH_ANONYMIZE_AFTER needs the retention task, but PSH_RETENTION_INTERVAL is 0"
+                    .into(),
+            );
+        }
+        None => tracing::info!("Retention task disabled"),
+    }
+
+    let mut providers = ProviderRegistry::default();
+    let mut mock_deliveries = None;
+    let broadcaster: Arc<dyn channels::Broadcaster>;
+    let bundle_id = match ApnsMode::from_env()? {
+        ApnsMode::Live => {
+            let apns_timeout = apns::request_timeout_from_env()?;
+            let apns_clients = Arc::new(
+                ApnsClients::new()?
+                    .with_request_timeout(apns_timeout)?
+                    .with_pacing(&config.apns_pacing)
+                    .with_circuit_breaker(&config.apns_circuit)?,
+            );
+            tracing::info!(
+                request_timeout_seconds = apns_timeout.as_secs(),
+                "APNs clients initialized"
+            );
+            tracing::info!(
+                sandbox = config.apns_pacing.sandbox,
+                production = config.apns_pacing.production,
+                burst = config.apns_pacing.burst,
+                "APNs requests a second"
+            );
+            let topic = apns_clients.topic().to_string();
+            match apns::keepalive_from_env()? {
+                Some(interval) => {
+                    tracing::info!(
+                        interval_seconds = interval.as_secs(),
+                        "Probing idle APNs connections"
+                    );
+                    apns_clients.clone().spawn_keepalive(interval);
+                }
+                None => tracing::info!("APNs keepalive disabled"),
+            }
+            providers.register(Platform::Apns, apns_clients);
+            broadcaster = Arc::new(channels::ApnsBroadcaster::from_env()?);
+            topic
+        }
+        ApnsMode::Mock => {
+            let topic = env::var("APNS_TOPIC").unwrap_or_else(|_| "com.example.psh".to_string());
+            let mock = MockProvider::new(topic.clone());
+            mock_deliveries = Some(mock.deliveries());
+            broadcaster = Arc::new(MockBroadcaster::new(topic.clone(), mock.deliveries()));
+            providers.register(Platform::Apns, mock);
+            tracing::warn!(topic = %topic, "APNs mock mode: pushes are logged at /mock/deliveries, not sent");
+            topic
+        }
+    };
+
+    let mut vapid_public_key = None;
+    match VapidKeys::from_env()? {
+        Some(vapid) => {
+            let webpush = WebPushProvider::new(vapid)?;
+            vapid_public_key = Some(webpush.public_key());
+            providers.register(Platform::WebPush, webpush);
+            tracing::info!("Web Push enabled");
+        }
+        None => tracing::info!("Web Push disabled, VAPID_PRIVATE_KEY not set"),
+    }
+
+    let environment_fallback = apns::environment_fallback_from_env()?;
+    if environment_fallback {
+        tracing::info!("Retrying rejected device tokens in the other APNs environment");
+    }
+
+    let api_keys = auth::ApiKeys::from_config(&config.api_keys)?;
+    if api_keys.is_empty() {
+        tracing::warn!("No API keys configured, the API is open to anyone who can reach it");
+    } else {
+        tracing::info!(keys = config.api_keys.len(), "Requiring API keys");
+    }
+
+    let lanes = lanes::Lanes::new(&config.lanes)?;
+    tracing::info!(
+        critical = config.lanes.critical,
+        normal = config.lanes.normal,
+        bulk = config.lanes.bulk,
+        bulk_threshold = config.lanes.bulk_threshold,
+        "Dispatch lane concurrency"
+    );
+
+    let attachments = attachments::Attachments::from_env()?;
+
+    let http_limits = limits::HttpLimits::from_env()?;
+    tracing::info!(
+        max_body_bytes = http_limits.max_body_bytes,
+        request_timeout_seconds = http_limits.request_timeout.map(|t| t.as_secs()),
+        long_request_timeout_seconds = http_limits.long_request_timeout.map(|t| t.as_secs()),
+        "HTTP request limits"
+    );
+
+    let cors = config.cors.as_ref().map(cors::layer).transpose()?;
+    if let Some(cors) = &config.cors {
+        tracing::info!(origins = ?cors.allowed_origins, "Allowing cross-origin requests");
+    }
+
+    let mqtt = config
+        .mqtt
+        .as_ref()
+        .map(mqtt::MqttBridge::new)
+        .transpose()?;
+    let failure_alerts = config
+        .failure_alerts
+        .as_ref()
+        .map(alerts::FailureAlerts::new)
+        .transpose()?;
+
+    let job_queue = jobs::JobQueue::from_env()?.map(Arc::new);
+    match &job_queue {
+        Some(queue) => tracing::info!(
+            workers = queue.workers(),
+            "Sharing deliveries through the job queue"
+        ),
+        None => tracing::info!("Job queue disabled, delivering in process"),
+    }
+
+    let state = AppState {
+        lanes,
+        truncation: Arc::new(config.truncation),
+        token_validation,
+        vapid_public_key,
+        mock_deliveries,
+        broadcaster: Some(broadcaster),
+        attachments: Some(Arc::new(attachments)),
+        job_queue,
+        dedup_window,
+        environment_fallback,
+        redact_tokens,
+        api_keys,
+        http_limits,
+        cors,
+        ..AppState::new(providers, bundle_id)
+    };
+    if let Some(queue) = &state.job_queue {
+        queue.spawn_workers(state.clone());
+    }
+    match mqtt {
+        Some(bridge) => {
+            tracing::info!(topics = bridge.topics(), "Bridging MQTT messages to pushes");
+            bridge.spawn(state.clone());
+        }
+        None => tracing::info!("MQTT bridge disabled"),
+    }
+    if let Some(alerts) = failure_alerts {
+        tracing::info!(
+            threshold = alerts.threshold(),
+            window_seconds = alerts.window().as_secs(),
+            "Alerting on push failure rate"
+        );
+        alerts.spawn(state.clone());
+    }
+    Ok(state)
+}
+
+/// Resolves on Ctrl-C or SIGTERM, letting in-flight sends finish and write
+/// their history before the server exits.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!(error = %e, "Failed to listen for Ctrl-C");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+    tracing::info!("Shutting down, finishing in-flight requests");
+    systemd::notify("STOPPING=1");
+}
+
+/// Applies `timeout`, if any, to every route in `router`.
+fn with_timeout(router: Router<AppState>, timeout: Option<Duration>) -> Router<AppState> {
+    match timeout {
+        Some(timeout) => router.route_layer(TimeoutLayer::new(timeout)),
+        None => router,
+    }
+}
+
+/// Every endpoint, bound to `state`.
+pub fn router(state: AppState) -> Router {
+    let limits = state.http_limits;
+    // Routes that deliver pushes or move a lot of data, which get longer.
+    let long_running = Router::new()
+        .route("/pushes/export", get(export::export_pushes))
+        .route("/pushes/:id/withdraw", post(withdraw::withdraw_push))
+        .route("/devices/export", get(export::export_devices))
+        .route("/devices/:token/test", post(test_device::test_device))
+        .route("/topics/:topic/resume", post(pauses::resume_topic))
+        .route("/t/:topic", post(topics::publish))
+        .route("/send", post(send_notification).get(send_query))
+        .route("/channels/:id/send", post(channels::send_to_channel))
+        .route("/webhook/slack", post(slack::webhook))
+        .route(
+            "/attachments",
+            post(attachments::upload)
+                .layer(DefaultBodyLimit::max(attachments::MAX_ATTACHMENT_BYTES)),
+        )
+        .route("/segments/:name/resume", post(pauses::resume_segment));
+    let api = Router::new()
+        .route("/", get(health::live))
+        .route("/health", get(health::health))
+        .route("/health/ready", get(health::ready))
+        .route("/health/live", get(health::live))
+        .route("/version", get(version::version))
+        .route("/stats", get(get_stats))
+        .route("/pushes", get(get_pushes))
+        .route("/pushes/read", post(inbox::mark_read))
+        .route(
+            "/pushes/:id",
+            get(get_push_detail).delete(inbox::delete_from_inbox),
+        )
+        .route("/pushes/:id/opened", post(opens::push_opened))
+        .route("/devices", get(devices::list_devices))
+        .route("/devices/:token", patch(devices::update_device))
+        .route("/devices/:token/pushes", get(devices::get_device_pushes))
+        .route(
+            "/devices/:token/tags",
+            get(tags::get_device_tags).post(tags::update_device_tags),
+        )
+        .route("/devices/:token/topics", get(topics::get_device_topics))
+        .route(
+            "/devices/:token/topics/:topic",
+            put(topics::subscribe).delete(topics::unsubscribe),
+        )
+        .route("/installations", get(installations::list_installations))
+        .route(
+            "/installations/:id",
+            delete(installations::delete_installation),
+        )
+        .route("/topics", get(topics::list_topics))
+        .route("/topics/:topic/pause", post(pauses::pause_topic))
+        .route("/pauses", get(pauses::list_pauses))
+        .route("/register", post(register_device))
+        .route("/audit", get(audit::get_audit))
+        .route("/usage", get(quota::get_usage))
+        .route("/keys", get(keys::list_keys).post(keys::issue_key))
+        .route("/keys/:name", delete(keys::revoke_key))
+        .route("/apps", get(apps::list_apps))
+        .route("/apps/:bundle_id", get(apps::get_app).put(apps::update_app))
+        .route(
+            "/channels",
+            get(channels::list_channels).post(channels::create_channel),
+        )
+        .route(
+            "/channels/:id",
+            get(channels::get_channel).delete(channels::delete_channel),
+        )
+        .route("/attachments/:id", get(attachments::download))
+        .route(
+            "/preview",
+            get(preview::preview_query).post(preview::preview_body),
+        )
+        .route("/messages", get(catalog::list_messages))
+        .route(
+            "/messages/:key",
+            get(catalog::get_message)
+                .put(catalog::update_message)
+                .delete(catalog::delete_message),
+        )
+        .route(
+            "/mock/deliveries",
+            get(mock::list_deliveries).delete(mock::clear_deliveries),
+        )
+        .route("/webpush/vapid-public-key", get(webpush::vapid_public_key))
+        .route(
+            "/webpush/subscriptions",
+            post(webpush::subscribe).delete(webpush::unsubscribe),
+        )
+        .route(
+            "/segments",
+            get(segments::list_segments).post(segments::create_segment),
+        )
+        .route(
+            "/segments/:name",
+            get(segments::get_segment)
+                .put(segments::update_segment)
+                .delete(segments::delete_segment),
+        )
+        .route("/segments/:name/pause", post(pauses::pause_segment));
+
+    let router = with_timeout(api, limits.request_timeout)
+        .merge(with_timeout(long_running, limits.long_request_timeout))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            cache::conditional_get,
+        ))
+        // After routing, so the check sees the matched route, and around the
+        // cache, so cached responses are only served to callers allowed them.
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            auth::require,
+        ))
+        .layer(DefaultBodyLimit::max(limits.max_body_bytes))
+        .layer(axum::middleware::from_fn(limits::json_errors))
+        .layer(axum::middleware::from_fn(request_id::tag_errors));
+    // Outside authentication, which preflight requests don't carry.
+    let router = match state.cors.clone() {
+        Some(cors) => router.layer(cors),
+        None => router,
+    };
+    router
+        .layer(TraceLayer::new_for_http().make_span_with(logging::request_span))
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(
+            request_id::MakeHexRequestId,
+        ))
+        .with_state(state)
+}
+
+#[cfg(test)]
+pub(crate) mod test_support {
+    use super::*;
+    use std::sync::{Mutex, MutexGuard};
+
+    static DB_LOCK: Mutex<()> = Mutex::new(());
+
+    /// Serializes tests that share the global in-memory database.
+    pub fn lock_db() -> MutexGuard<'static, ()> {
+        DB_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Locks the shared database and gives the test a freshly created schema.
+    pub fn test_db() -> MutexGuard<'static, ()> {
+        let guard = lock_db();
+        Database::initialize("sqlite::memory:").unwrap();
+        let conn = Connection::get().unwrap();
+        for table in [
+            "leases",
+            "pauses",
+            "paused_sends",
+            "issued_keys",
+            "attachments",
+            "device_tags",
+            "api_key_usage",
+            "topic_subscriptions",
+            "background_pushes",
+            "pushes",
+            "webpush_subscriptions",
+            "devices",
+            "segments",
+            "audit_log",
+            "apps",
+            "messages",
+        ] {
+            conn.execute(&format!("DROP TABLE IF EXISTS {table}"), ())
+                .unwrap();
+        }
+        Database::create_schema(&conn).unwrap();
+        guard
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::{lock_db, test_db};
+
+    fn register(token: &str, device_type: &str, os_version: &str) {
+        Database::upsert_device(&RegisterRequest {
+            device_token: token.to_string(),
+            installation_id: format!("install-{token}"),
+            environment: Environment::Sandbox,
+            device_name: Some(format!("{device_type} {token}")),
+            device_type: Some(device_type.to_string()),
+            os_version: Some(os_version.to_string()),
+            app_version: Some("1.0".to_string()),
+            locale: None,
+            timezone: None,
+            expires_in: None,
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_database_location_from_url() {
+        assert_eq!(
+            Database::location_from_url("sqlite:data.db"),
+            DatabaseLocation::File("data.db".to_string())
+        );
+        assert_eq!(
+            Database::location_from_url("sqlite:/app/data/data.db?mode=rwc"),
+            DatabaseLocation::File("/app/data/data.db".to_string())
+        );
+        assert_eq!(
+            Database::location_from_url("sqlite:///app/data/data.db?mode=rwc"),
+            DatabaseLocation::File("/app/data/data.db".to_string())
+        );
+        assert_eq!(
+            Database::location_from_url("sqlite::memory:"),
+            DatabaseLocation::Memory
+        );
+        assert_eq!(
+            Database::location_from_url("/tmp/psh.db"),
+            DatabaseLocation::File("/tmp/psh.db".to_string())
+        );
+    }
+
+    #[test]
+    fn test_migrates_legacy_devices_and_recreates_pushes() -> Result<(), SeekwelError> {
+        let _db = lock_db();
+        match Connection::memory() {
+            Ok(()) | Err(SeekwelError::AlreadyInitialized) => {}
+            Err(error) => return Err(error),
+        }
+
+        let conn = Connection::get()?;
+        conn.execute("DROP TABLE IF EXISTS pushes", ())?;
+        conn.execute("DROP TABLE IF EXISTS devices", ())?;
+        conn.execute("DROP TABLE IF EXISTS devices_old", ())?;
+        conn.execute(
+            r#"
+            CREATE TABLE devices (
+                device_token TEXT PRIMARY KEY,
+                installation_id TEXT,
+                environment TEXT NOT NULL CHECK(environment IN ('sandbox', 'production')),
+                device_name TEXT,
+                device_type TEXT,
+                os_version TEXT,
+                app_version TEXT,
+                created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            )
+            "#,
+            (),
+        )?;
+        conn.execute(
+            r#"
+            INSERT INTO devices (
+                device_token,
+                installation_id,
+                environment,
+                device_name,
+                device_type,
+                os_version,
+                app_version
+            ) VALUES ('token-1', 'install-1', 'sandbox', 'Phone', 'iPhone', 'iOS', '1.0')
+            "#,
+            (),
+        )?;
+        conn.execute(
+            r#"
+            CREATE TABLE pushes (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                device_token TEXT NOT NULL,
+                apns_id TEXT,
+                title TEXT,
+                body TEXT,
+                payload TEXT,
+                sent_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            )
+            "#,
+            (),
+        )?;
+        conn.execute(
+            "INSERT INTO pushes (device_token, title) VALUES ('token-1', 'old push')",
+            (),
+        )?;
+
+        Database::migrate_devices(&conn)?;
+        Database::migrate_pushes(&conn)?;
+        Database::create_schema(&conn)?;
+
+        assert!(Database::column_exists(&conn, "devices", "id")?);
+        assert!(Database::column_exists(&conn, "pushes", "device_id")?);
+        assert!(Database::column_exists(&conn, "pushes", "status")?);
+
+        let token: String = conn.query_row(
+            "SELECT device_token FROM devices WHERE installation_id = 'install-1'",
+            (),
+            |row| row.get(0),
+        )?;
+        assert_eq!(token, "token-1");
+
+        let push_count: i64 =
+            conn.query_row("SELECT COUNT(*) FROM pushes", (), |row| row.get(0))?;
+        assert_eq!(push_count, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delivery_targets_with_filter() {
+        let _db = test_db();
+        register("a", "iPhone", "Version 17.4.1 (Build 21E236)");
+        register("b", "iPhone", "Version 16.7 (Build 20H19)");
+        register("c", "iPad", "Version 18.0 (Build 22A3354)");
+
+        let all = Database::delivery_targets(None).unwrap();
+        assert_eq!(all.len(), 3);
+
+        let filter = DeviceFilter {
+            device_type: Some("iphone".to_string()),
+            min_os_version: Some("17.0".to_string()),
+            ..Default::default()
+        };
+        let targets = Database::delivery_targets(Some(&filter)).unwrap();
+        let tokens: Vec<_> = targets.iter().map(|t| t.device_token.as_str()).collect();
+        assert_eq!(tokens, vec!["a"]);
+
+        let filter = DeviceFilter {
+            device_name: Some("iPad *".to_string()),
+            ..Default::default()
+        };
+        let targets = Database::delivery_targets(Some(&filter)).unwrap();
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].device_token, "c");
+    }
+
+    #[test]
+    fn test_reregistering_installation_supersedes_old_token() {
+        let _db = test_db();
+        let request = |token: &str| RegisterRequest {
+            device_token: token.to_string(),
+            installation_id: "install-1".to_string(),
+            environment: Environment::Sandbox,
+            device_name: None,
+            device_type: None,
+            os_version: None,
+            app_version: None,
+            locale: None,
+            timezone: None,
+            expires_in: None,
+        };
+        register("other", "iPhone", "17.0");
+
+        assert!(Database::upsert_device(&request("old")).unwrap().is_empty());
+        assert_eq!(
+            Database::upsert_device(&request("new")).unwrap(),
+            vec!["old".to_string()]
+        );
+
+        let tokens: Vec<_> = Database::delivery_targets(None)
+            .unwrap()
+            .into_iter()
+            .map(|t| t.device_token)
+            .collect();
+        assert_eq!(tokens, vec!["other", "new"]);
+        assert_eq!(Database::stats().unwrap().total_devices, 2);
+
+        // A restored token becomes current again.
+        assert_eq!(
+            Database::upsert_device(&request("old")).unwrap(),
+            vec!["new".to_string()]
+        );
+        let tokens: Vec<_> = Database::delivery_targets(None)
+            .unwrap()
+            .into_iter()
+            .map(|t| t.device_token)
+            .collect();
+        assert_eq!(tokens, vec!["other", "old"]);
+    }
+
+    #[test]
+    fn test_resolve_filter_from_segment() {
+        let _db = test_db();
+        let conn = Connection::get().unwrap();
+        conn.execute(
+            r#"INSERT INTO segments (name, filter) VALUES ('ipads', '{"device_type":"iPad","min_os_version":"16.0"}')"#,
+            (),
+        )
+        .unwrap();
+
+        let req: SendRequest = serde_json::from_str(
+            r#"{"body": "hi", "segment": "ipads", "filter": {"min_os_version": "17.0"}}"#,
+        )
+        .unwrap();
+        let filter = resolve_filter(&req).unwrap().unwrap();
+        assert_eq!(filter.device_type.as_deref(), Some("iPad"));
+        assert_eq!(filter.min_os_version.as_deref(), Some("17.0"));
+
+        let req: SendRequest =
+            serde_json::from_str(r#"{"body": "hi", "segment": "missing"}"#).unwrap();
+        let (status, _) = resolve_filter(&req).unwrap_err();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_environment_from_str() {
+        assert_eq!(
+            Environment::try_from("sandbox").unwrap(),
+            Environment::Sandbox
+        );
+        assert_eq!(
+            Environment::try_from("production").unwrap(),
+            Environment::Production
+        );
+        assert!(Environment::try_from("invalid").is_err());
+    }
+
+    #[test]
+    fn test_environment_as_str() {
+        assert_eq!(Environment::Sandbox.as_str(), "sandbox");
+        assert_eq!(Environment::Production.as_str(), "production");
+    }
+
+    #[test]
+    fn test_deserialize_register_request() {
+        let json = r#"{
+            "device_token": "abc123",
+            "installation_id": "uuid-install-1",
+            "environment": "sandbox",
+            "device_name": "John's iPhone"
+        }"#;
+        let req: RegisterRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(req.device_token, "abc123");
+        assert_eq!(req.installation_id, "uuid-install-1");
+        assert_eq!(req.environment, Environment::Sandbox);
+        assert_eq!(req.device_name, Some("John's iPhone".to_string()));
+    }
+
+    #[test]
+    fn test_deserialize_send_request_simple() {
+        let json = r#"{
+            "title": "Hello",
+            "body": "World"
+        }"#;
+        let req: SendRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(req.title, Some("Hello".to_string()));
+        assert_eq!(req.body, Some("World".to_string()));
+    }
+
+    #[test]
+    fn test_deserialize_send_request_with_sound() {
+        let json = r#"{
+            "sound": "default"
+        }"#;
+        let req: SendRequest = serde_json::from_str(json).unwrap();
+        assert!(matches!(req.sound, Some(SoundConfig::Simple(s)) if s == "default"));
+
+        let json = r#"{
+            "sound": {"name": "alert.caf", "critical": true, "volume": 0.8}
+        }"#;
+        let req: SendRequest = serde_json::from_str(json).unwrap();
+        assert!(matches!(
+            req.sound,
+            Some(SoundConfig::Critical { name, critical: Some(true), volume: Some(v) })
+            if name == "alert.caf" && (v - 0.8).abs() < f64::EPSILON
+        ));
+    }
+
+    #[test]
+    fn test_deserialize_send_request_with_interruption_level() {
+        let json = r#"{
+            "title": "Hello",
+            "interruption_level": "time-sensitive",
+            "relevance_score": 0.75
+        }"#;
+        let req: SendRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(req.interruption_level, Some("time-sensitive".to_string()));
+        assert!((req.relevance_score.unwrap() - 0.75).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_deserialize_send_request_with_data() {
+        let json = r#"{
+            "data": {"key": "value", "number": 42}
+        }"#;
+        let req: SendRequest = serde_json::from_str(json).unwrap();
+        let data = req.data.unwrap();
+        assert_eq!(data.get("key").unwrap(), "value");
+        assert_eq!(data.get("number").unwrap(), 42);
+    }
+
+    #[test]
+    fn test_send_request_contract() {
+        let cases: Vec<serde_json::Value> =
+            serde_json::from_str(include_str!("../../contract/send_requests.json")).unwrap();
+        for case in cases {
+            let name = case["name"].as_str().unwrap_or_default();
+            if let Err(e) = serde_json::from_value::<SendRequest>(case["request"].clone()) {
+                panic!("{name}: server rejected CLI request: {e}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_serialize_pushes_response() {
+        let pushes = vec![
+            PushRecord {
+                id: 1,
+                device_token: "abc123".to_string(),
+                apns_id: Some("uuid-1".to_string()),
+                title: Some("Test Title".to_string()),
+                body: Some("Test Body".to_string()),
+                payload: None,
+                interruption_level: None,
+                sent_at: "2024-01-01 12:00:00".to_string(),
+                read_at: None,
+            },
+            PushRecord {
+                id: 2,
+                device_token: "def456".to_string(),
+                apns_id: None,
+                title: None,
+                body: Some("Body only".to_string()),
+                payload: Some(r#"{"key":"value"}"#.to_string()),
+                interruption_level: Some("time-sensitive".to_string()),
+                sent_at: "2024-01-02 12:00:00".to_string(),
+                read_at: Some("2024-01-02 13:00:00".to_string()),
+            },
+        ];
+        let response = PushesResponse {
+            pushes,
+            unread: 1,
+            next_cursor: None,
+        };
+        let json = serde_json::to_string(&response).unwrap();
+
+        assert!(json.contains("\"id\":1"));
+        assert!(json.contains("\"device_token\":\"abc123\""));
+        assert!(json.contains("\"apns_id\":\"uuid-1\""));
+        assert!(json.contains("\"title\":\"Test Title\""));
+        assert!(json.contains("\"body\":\"Test Body\""));
+        assert!(json.contains("\"sent_at\":\"2024-01-01 12:00:00\""));
+        assert!(json.contains("\"id\":2"));
+        assert!(json.contains("\"apns_id\":null"));
+        assert!(json.contains("\"title\":null"));
+        assert!(json.contains("\"read_at\":\"2024-01-02 13:00:00\""));
+        assert!(json.contains("\"unread\":1"));
+        assert!(!json.contains("next_cursor"));
+    }
+
+    #[test]
+    fn test_deserialize_pushes_query() {
+        let json = r#"{"installation_id": "uuid-install-1"}"#;
+        let parsed: PushesQuery = serde_json::from_str(json).unwrap();
+        assert_eq!(parsed.installation_id, "uuid-install-1");
+    }
+
+    #[test]
+    fn test_serialize_push_detail_record() {
+        let detail = PushDetailRecord {
+            id: 1,
+            apns_id: Some("apns-uuid-1".to_string()),
+            title: Some("Test Title".to_string()),
+            body: Some("Test Body".to_string()),
+            payload: Some(r#"{"key":"value"}"#.to_string()),
+            interruption_level: Some("time-sensitive".to_string()),
+            sent_at: "2024-01-01 12:00:00".to_string(),
+            device_token: "abc123".to_string(),
+            device_name: Some("John's iPhone".to_string()),
+            device_type: Some("iPhone".to_string()),
+            environment: Some("sandbox".to_string()),
+            status: "sent".to_string(),
+            error: None,
+            error_code: None,
+            actions: None,
+            opened_at: None,
+            full_body: None,
+            collapse_id: None,
+        };
+        let json = serde_json::to_string(&detail).unwrap();
+
+        assert!(json.contains("\"id\":1"));
+        assert!(json.contains("\"apns_id\":\"apns-uuid-1\""));
+        assert!(json.contains("\"device_name\":\"John's iPhone\""));
+        assert!(json.contains("\"device_type\":\"iPhone\""));
+        assert!(json.contains("\"environment\":\"sandbox\""));
+    }
+
+    #[test]
+    fn test_send_summary() {
+        let req = SendRequest {
+            title: Some("Deploy".to_string()),
+            segment: Some("beta".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            send_summary(&req, 3, 1),
+            "3 sent, 1 failed, segment=beta, title=\"Deploy\""
+        );
+    }
+
+    #[test]
+    fn test_resolve_expiration() {
+        let mut req = SendRequest {
+            expires_in_seconds: Some(7200),
+            ..Default::default()
+        };
+        resolve_expiration(&mut req, 1_700_000_000).unwrap();
+        assert_eq!(req.expiration, Some(1_700_007_200));
+
+        let mut req = SendRequest {
+            expires_in_seconds: Some(0),
+            ..Default::default()
+        };
+        resolve_expiration(&mut req, 1_700_000_000).unwrap();
+        assert_eq!(req.expiration, Some(0));
+
+        let mut req = SendRequest {
+            expiration: Some(1),
+            expires_in_seconds: Some(60),
+            ..Default::default()
+        };
+        assert!(resolve_expiration(&mut req, 1_700_000_000).is_err());
+
+        let check = |expiration: u64| {
+            let mut req = SendRequest {
+                expiration: Some(expiration),
+                ..Default::default()
+            };
+            resolve_expiration(&mut req, 1_700_000_000)
+        };
+        assert!(check(0).is_ok());
+        assert!(check(1_700_003_600).is_ok());
+        assert!(check(1_699_999_000)
+            .unwrap_err()
+            .contains("1000s in the past"));
+        assert!(check(1_700_000_000_000)
+            .unwrap_err()
+            .contains("milliseconds"));
+        assert!(check(1_800_000_000).unwrap_err().contains("30 days"));
+        let mut req = SendRequest {
+            expires_in_seconds: Some(MAX_EXPIRATION_SECONDS + 1),
+            ..Default::default()
+        };
+        assert!(resolve_expiration(&mut req, 1_700_000_000).is_err());
+    }
+
+    #[test]
+    fn test_serialize_device_send_result_error_code() {
+        let error = SendError::new(ApnsErrorCode::BadDeviceToken);
+        let result = DeviceSendResult {
+            device_token: "abc123".to_string(),
+            success: false,
+            apns_id: None,
+            error: Some(error.message),
+            error_code: Some(error.code),
+            ..Default::default()
+        };
+        let json = serde_json::to_value(&result).unwrap();
+
+        assert_eq!(json["error_code"], "BadDeviceToken");
+        assert!(json.get("warning").is_none());
+        assert!(json["error"].as_str().unwrap().contains("device token"));
+    }
+}
@@ -0,0 +1,160 @@
+use axum::{
+    extract::Request,
+    http::{header::CONTENT_TYPE, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{env, future::Future, time::Duration};
+use tracing::Instrument;
+
+use crate::{duration, ErrorResponse};
+
+const DEFAULT_MAX_BODY_BYTES: usize = 1024 * 1024;
+
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What the HTTP API accepts, and how long it works on a request before
+/// answering 408.
+#[derive(Debug, Clone, Copy)]
+pub struct HttpLimits {
+    /// Larger request bodies get a 413. Attachment uploads have their own,
+    /// larger limit.
+    pub max_body_bytes: usize,
+    /// For most routes; `None` lets a request take as long as it takes.
+    pub request_timeout: Option<Duration>,
+    /// For routes that send pushes, upload attachments or export, which
+    /// can take minutes for a large broadcast. A send still going when it
+    /// runs out finishes in the background, with its history recorded, but
+    /// the caller gets a 408 instead of the per-device results.
+    pub long_request_timeout: Option<Duration>,
+}
+
+impl Default for HttpLimits {
+    fn default() -> Self {
+        Self {
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+            request_timeout: Some(DEFAULT_REQUEST_TIMEOUT),
+            long_request_timeout: None,
+        }
+    }
+}
+
+impl HttpLimits {
+    /// Reads `PSH_MAX_BODY_BYTES`, `PSH_REQUEST_TIMEOUT` (e.g. `30s`) and
+    /// `PSH_LONG_REQUEST_TIMEOUT`. A timeout of `0` turns it off.
+    pub fn from_env() -> Result<Self, String> {
+        let defaults = Self::default();
+        Ok(Self {
+            max_body_bytes: match env::var("PSH_MAX_BODY_BYTES") {
+                Ok(value) => parse_max_body_bytes(&value)?,
+                Err(_) => defaults.max_body_bytes,
+            },
+            request_timeout: match env::var("PSH_REQUEST_TIMEOUT") {
+                Ok(value) => {
+                    parse_timeout("PSH_REQUEST_TIMEOUT", &value, defaults.request_timeout)?
+                }
+                Err(_) => defaults.request_timeout,
+            },
+            long_request_timeout: match env::var("PSH_LONG_REQUEST_TIMEOUT") {
+                Ok(value) => parse_timeout(
+                    "PSH_LONG_REQUEST_TIMEOUT",
+                    &value,
+                    defaults.long_request_timeout,
+                )?,
+                Err(_) => defaults.long_request_timeout,
+            },
+        })
+    }
+}
+
+fn parse_max_body_bytes(value: &str) -> Result<usize, String> {
+    if value.trim().is_empty() {
+        return Ok(DEFAULT_MAX_BODY_BYTES);
+    }
+    value
+        .trim()
+        .parse()
+        .ok()
+        .filter(|bytes| *bytes > 0)
+        .ok_or_else(|| {
+            format!(
+                "Invalid PSH_MAX_BODY_BYTES '{value}', expected a number of bytes such as 1048576"
+            )
+        })
+}
+
+fn parse_timeout(
+    name: &str,
+    value: &str,
+    default: Option<Duration>,
+) -> Result<Option<Duration>, String> {
+    if value.trim().is_empty() {
+        return Ok(default);
+    }
+    match duration::parse_duration(value) {
+        Some(timeout) if timeout.is_zero() => Ok(None),
+        Some(timeout) => Ok(Some(timeout)),
+        None => Err(format!(
+            "Invalid {name} '{value}', expected a duration such as 30s"
+        )),
+    }
+}
+
+/// Runs `work` in its own task and waits for it. Abandoning the wait, when
+/// a timeout runs out or the client goes away, leaves `work` to finish
+/// instead of stopping it partway.
+pub(crate) async fn run_to_completion<F>(work: F) -> F::Output
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    match tokio::spawn(work.in_current_span()).await {
+        Ok(output) => output,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+/// Gives the bare 408 and 413 the timeout and body limit answer with a
+/// JSON error, like every other error.
+pub(crate) async fn json_errors(request: Request, next: Next) -> Response {
+    let response = next.run(request).await;
+    let message = match response.status() {
+        StatusCode::REQUEST_TIMEOUT => "The request took too long and was abandoned",
+        StatusCode::PAYLOAD_TOO_LARGE => "The request body is too large",
+        _ => return response,
+    };
+    let is_json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(b"application/json"));
+    if is_json {
+        return response;
+    }
+    ErrorResponse::with_status(response.status(), message).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_limits() {
+        assert_eq!(parse_max_body_bytes("65536"), Ok(65536));
+        assert_eq!(parse_max_body_bytes(""), Ok(DEFAULT_MAX_BODY_BYTES));
+        assert!(parse_max_body_bytes("0").is_err());
+        assert!(parse_max_body_bytes("1MB").is_err());
+
+        let default = Some(DEFAULT_REQUEST_TIMEOUT);
+        assert_eq!(
+            parse_timeout("PSH_REQUEST_TIMEOUT", "2m", default),
+            Ok(Some(Duration::from_secs(120)))
+        );
+        assert_eq!(parse_timeout("PSH_REQUEST_TIMEOUT", "0", default), Ok(None));
+        assert_eq!(
+            parse_timeout("PSH_REQUEST_TIMEOUT", " ", default),
+            Ok(default)
+        );
+        let error = parse_timeout("PSH_REQUEST_TIMEOUT", "soon", default).unwrap_err();
+        assert!(error.contains("PSH_REQUEST_TIMEOUT"), "{error}");
+    }
+}
@@ -0,0 +1,215 @@
+use axum::Router;
+use std::{env, io, path::PathBuf};
+
+use crate::{shutdown_signal, systemd};
+
+const DEFAULT_ADDRESS: &str = "0.0.0.0:3000";
+
+/// Where the server takes connections: the socket systemd passed, else
+/// `PSH_LISTEN`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Listen {
+    /// A TCP `host:port`.
+    Tcp(String),
+    /// A Unix domain socket, for scripts on the same host, given as
+    /// `unix:/run/psh.sock` or `unix:///run/psh.sock`.
+    Unix(PathBuf),
+    /// A TCP or Unix socket opened by a systemd `.socket` unit, by its
+    /// descriptor.
+    Systemd(i32),
+}
+
+impl Listen {
+    pub(crate) fn from_env() -> Result<Self, String> {
+        if let Some(fd) = systemd::listen_fd()? {
+            return Ok(Self::Systemd(fd));
+        }
+        match env::var("PSH_LISTEN") {
+            Ok(value) if !value.trim().is_empty() => Self::parse(value.trim()),
+            _ => Ok(Self::Tcp(DEFAULT_ADDRESS.to_string())),
+        }
+    }
+
+    fn parse(value: &str) -> Result<Self, String> {
+        let Some(path) = value.strip_prefix("unix:") else {
+            return Ok(Self::Tcp(value.to_string()));
+        };
+        let path = path.strip_prefix("//").unwrap_or(path);
+        if path.is_empty() {
+            return Err(format!(
+                "Invalid PSH_LISTEN '{value}', expected unix:/path/to/psh.sock"
+            ));
+        }
+        if cfg!(not(unix)) {
+            return Err("PSH_LISTEN: Unix sockets aren't supported on this platform".to_string());
+        }
+        Ok(Self::Unix(PathBuf::from(path)))
+    }
+}
+
+/// Serves `router` until Ctrl-C or SIGTERM, letting in-flight requests
+/// finish first. systemd hears the server is ready once it's listening.
+pub(crate) async fn serve(listen: &Listen, router: Router) -> io::Result<()> {
+    match listen {
+        Listen::Tcp(address) => {
+            serve_tcp(tokio::net::TcpListener::bind(address).await?, router).await
+        }
+        #[cfg(unix)]
+        Listen::Unix(path) => {
+            let served = serve_unix(bind_unix(path)?, router, shutdown_signal()).await;
+            let _ = std::fs::remove_file(path);
+            served
+        }
+        #[cfg(unix)]
+        Listen::Systemd(fd) => {
+            tracing::info!(fd = fd, "Using the socket passed by systemd");
+            match systemd::inherit(*fd)? {
+                systemd::Inherited::Tcp(listener) => {
+                    serve_tcp(tokio::net::TcpListener::from_std(listener)?, router).await
+                }
+                systemd::Inherited::Unix(listener) => {
+                    let listener = tokio::net::UnixListener::from_std(listener)?;
+                    serve_unix(listener, router, shutdown_signal()).await
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        Listen::Unix(_) | Listen::Systemd(_) => unreachable!("rejected by Listen::from_env"),
+    }
+}
+
+async fn serve_tcp(listener: tokio::net::TcpListener, router: Router) -> io::Result<()> {
+    tracing::info!("Server listening on {}", listener.local_addr()?);
+    systemd::notify("READY=1");
+    axum::serve(
+        listener,
+        router.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &std::path::Path) -> io::Result<tokio::net::UnixListener> {
+    // A socket left behind by an unclean exit would make the bind fail.
+    match std::fs::remove_file(path) {
+        Ok(()) => tracing::debug!(path = %path.display(), "Removed stale socket"),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    tokio::net::UnixListener::bind(path)
+}
+
+/// `axum::serve` only takes TCP listeners, so Unix socket connections are
+/// handed to hyper directly. There is no peer address, so audit entries
+/// record none.
+#[cfg(unix)]
+async fn serve_unix(
+    listener: tokio::net::UnixListener,
+    router: Router,
+    shutdown: impl std::future::Future<Output = ()>,
+) -> io::Result<()> {
+    use hyper_util::{
+        rt::{TokioExecutor, TokioIo},
+        server::{conn::auto, graceful::GracefulShutdown},
+        service::TowerToHyperService,
+    };
+
+    match listener.local_addr()?.as_pathname() {
+        Some(path) => tracing::info!("Server listening on unix:{}", path.display()),
+        None => tracing::info!("Server listening on an unnamed Unix socket"),
+    }
+    systemd::notify("READY=1");
+
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let stream = match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        tracing::warn!(error = %e, "Failed to accept a connection");
+                        continue;
+                    }
+                };
+                let service = TowerToHyperService::new(router.clone());
+                let conn = builder
+                    .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                    .into_owned();
+                let conn = graceful.watch(conn);
+                tokio::spawn(async move {
+                    if let Err(e) = conn.await {
+                        tracing::debug!(error = %e, "Connection closed with an error");
+                    }
+                });
+            }
+            _ = &mut shutdown => break,
+        }
+    }
+    drop(listener);
+    graceful.shutdown().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Listen::parse("127.0.0.1:8080").unwrap(),
+            Listen::Tcp("127.0.0.1:8080".to_string())
+        );
+        assert_eq!(
+            Listen::parse("unix:///run/psh.sock").unwrap(),
+            Listen::Unix(PathBuf::from("/run/psh.sock"))
+        );
+        assert_eq!(
+            Listen::parse("unix:psh.sock").unwrap(),
+            Listen::Unix(PathBuf::from("psh.sock"))
+        );
+        assert!(Listen::parse("unix:").is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_serves_over_unix_socket() {
+        use axum::routing::get;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let dir = std::env::temp_dir().join(format!("psh-listen-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("psh.sock");
+        // A leftover file where the socket goes doesn't stop the server.
+        std::fs::write(&path, "stale").unwrap();
+
+        let router = Router::new().route("/", get(|| async { "ok" }));
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let listener = bind_unix(&path).unwrap();
+        let server = tokio::spawn(serve_unix(listener, router, async {
+            let _ = stopped.await;
+        }));
+
+        let mut stream = loop {
+            match tokio::net::UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        };
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: psh\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.ends_with("ok"), "{response}");
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
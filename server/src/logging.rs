@@ -0,0 +1,117 @@
+use axum::{body::Body, http::Request};
+use tracing::Span;
+use tracing_appender::{non_blocking::WorkerGuard, rolling};
+use tracing_subscriber::{
+    fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer, Registry,
+};
+
+#[cfg(feature = "otel")]
+use crate::otel;
+use crate::{
+    config::{LogConfig, LogFormat, LogRotation},
+    request_id,
+};
+
+/// Keeps the file writer and span exporter flushing; what's logged after
+/// it drops is lost, so hold it for the life of the process.
+pub struct LogGuard {
+    _file: Option<WorkerGuard>,
+    #[cfg(feature = "otel")]
+    _otel: Option<otel::OtelGuard>,
+}
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+fn env_filter() -> EnvFilter {
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into())
+}
+
+/// Logs to stdout, filtered by `RUST_LOG`, to rotating files when
+/// `server.toml` has a `[log]` section, and, in builds with the `otel`
+/// feature, exports spans when `OTEL_EXPORTER_OTLP_ENDPOINT` is set.
+pub fn init(config: Option<&LogConfig>) -> Result<LogGuard, Box<dyn std::error::Error>> {
+    let mut layers: Vec<BoxedLayer> = vec![fmt::layer().with_filter(env_filter()).boxed()];
+
+    let file = match config {
+        Some(config) => {
+            let (layer, guard) = file_layer(config)?;
+            layers.push(layer);
+            Some(guard)
+        }
+        None => None,
+    };
+
+    #[cfg(feature = "otel")]
+    let otel = match otel::layer()? {
+        Some((layer, guard)) => {
+            layers.push(layer.with_filter(env_filter()).boxed());
+            Some(guard)
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry().with(layers).try_init()?;
+
+    #[cfg(not(feature = "otel"))]
+    if std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_some() {
+        tracing::warn!("OTEL_EXPORTER_OTLP_ENDPOINT is set, but this server was built without the otel feature");
+    }
+
+    Ok(LogGuard {
+        _file: file,
+        #[cfg(feature = "otel")]
+        _otel: otel,
+    })
+}
+
+fn file_layer(config: &LogConfig) -> Result<(BoxedLayer, WorkerGuard), String> {
+    let rotation = match config.rotation {
+        LogRotation::Minutely => rolling::Rotation::MINUTELY,
+        LogRotation::Hourly => rolling::Rotation::HOURLY,
+        LogRotation::Daily => rolling::Rotation::DAILY,
+        LogRotation::Never => rolling::Rotation::NEVER,
+    };
+    let mut appender = rolling::RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(&config.file_name);
+    if let Some(max_files) = config.max_files {
+        appender = appender.max_log_files(max_files);
+    }
+    let appender = appender
+        .build(&config.directory)
+        .map_err(|e| format!("Cannot write logs to {}: {e}", config.directory.display()))?;
+    let (writer, guard) = tracing_appender::non_blocking(appender);
+
+    let filter = match &config.level {
+        Some(level) => {
+            EnvFilter::try_new(level).map_err(|e| format!("Invalid log level '{level}': {e}"))?
+        }
+        None => env_filter(),
+    };
+    let layer = match config.format {
+        LogFormat::Json => fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .with_writer(writer)
+            .with_filter(filter)
+            .boxed(),
+        LogFormat::Text => fmt::layer()
+            .with_ansi(false)
+            .with_writer(writer)
+            .with_filter(filter)
+            .boxed(),
+    };
+    Ok((layer, guard))
+}
+
+/// The span each request is handled in, so its events, including those from
+/// the APNs sends it makes, carry its `request_id`.
+pub(crate) fn request_span(request: &Request<Body>) -> Span {
+    tracing::info_span!(
+        "request",
+        request_id = %request_id::from_extensions(request.extensions()).unwrap_or_default(),
+        method = %request.method(),
+        path = %request.uri().path(),
+    )
+}
@@ -0,0 +1,260 @@
+/// Converts the markdown in `text` to plain text, since iOS shows alert
+/// text as written: emphasis and code spans keep their text, links keep
+/// their URL, and headings, quotes and list bullets lose their markers.
+pub(crate) fn to_plain_text(text: &str) -> String {
+    let mut lines = Vec::new();
+    let mut in_fence = false;
+    for line in text.lines() {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_fence = !in_fence;
+            continue;
+        }
+        if in_fence {
+            lines.push(line.to_string());
+        } else if !is_rule(trimmed) {
+            lines.push(convert_line(line));
+        }
+    }
+    lines.join("\n")
+}
+
+/// `---`, `***` or `___`, possibly spaced out, on a line of its own.
+fn is_rule(line: &str) -> bool {
+    let marks: Vec<char> = line.chars().filter(|c| !c.is_whitespace()).collect();
+    marks.len() >= 3 && matches!(marks[0], '-' | '*' | '_') && marks.iter().all(|&c| c == marks[0])
+}
+
+fn convert_line(line: &str) -> String {
+    let indent = &line[..line.len() - line.trim_start().len()];
+    let mut rest = line.trim_start();
+    while let Some(quoted) = rest.strip_prefix('>') {
+        rest = quoted.strip_prefix(' ').unwrap_or(quoted);
+    }
+    let hashes = rest.len() - rest.trim_start_matches('#').len();
+    if (1..=6).contains(&hashes) && (rest.len() == hashes || rest[hashes..].starts_with(' ')) {
+        rest = rest[hashes..].trim();
+    }
+    for bullet in ["- ", "* ", "+ "] {
+        if let Some(item) = rest.strip_prefix(bullet) {
+            return format!("{indent}• {}", inline(item));
+        }
+    }
+    format!("{indent}{}", inline(rest))
+}
+
+fn inline(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '\\' if chars.get(i + 1).is_some_and(|c| c.is_ascii_punctuation()) => {
+                out.push(chars[i + 1]);
+                i += 2;
+            }
+            '`' => {
+                let run = run_length(&chars, i);
+                match find_run(&chars, i + run, '`', run) {
+                    Some(end) => {
+                        let code: String = chars[i + run..end].iter().collect();
+                        out.push_str(code.trim());
+                        i = end + run;
+                    }
+                    None => {
+                        out.extend(&chars[i..i + run]);
+                        i += run;
+                    }
+                }
+            }
+            '!' if chars.get(i + 1) == Some(&'[') => match link(&chars, i + 1) {
+                Some((alt, _, end)) => {
+                    out.push_str(&inline(&alt));
+                    i = end;
+                }
+                None => {
+                    out.push(c);
+                    i += 1;
+                }
+            },
+            '[' => match link(&chars, i) {
+                Some((label, url, end)) => {
+                    let label = inline(&label);
+                    if label == url || url.is_empty() {
+                        out.push_str(&label);
+                    } else {
+                        out.push_str(&format!("{label} ({url})"));
+                    }
+                    i = end;
+                }
+                None => {
+                    out.push(c);
+                    i += 1;
+                }
+            },
+            '<' => match autolink(&chars, i) {
+                Some((url, end)) => {
+                    out.push_str(&url);
+                    i = end;
+                }
+                None => {
+                    out.push(c);
+                    i += 1;
+                }
+            },
+            '*' | '_' | '~' => {
+                let run = run_length(&chars, i).min(3);
+                let closing = (c != '~' || run == 2)
+                    .then(|| emphasis_end(&chars, i, run))
+                    .flatten();
+                match closing {
+                    Some(end) => {
+                        let inner: String = chars[i + run..end].iter().collect();
+                        out.push_str(&inline(&inner));
+                        i = end + run;
+                    }
+                    None => {
+                        out.extend(&chars[i..i + run]);
+                        i += run;
+                    }
+                }
+            }
+            _ => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+fn run_length(chars: &[char], start: usize) -> usize {
+    chars[start..]
+        .iter()
+        .take_while(|&&c| c == chars[start])
+        .count()
+}
+
+/// The start of the next run of exactly `len` `mark`s at or after `from`.
+fn find_run(chars: &[char], from: usize, mark: char, len: usize) -> Option<usize> {
+    let mut i = from;
+    while i < chars.len() {
+        if chars[i] == mark {
+            let run = run_length(chars, i);
+            if run == len {
+                return Some(i);
+            }
+            i += run;
+        } else {
+            i += 1;
+        }
+    }
+    None
+}
+
+/// Where the emphasis opened by the `len` delimiters at `start` closes.
+/// Delimiters only open before text and close after it, and `_` only
+/// outside words, so `2 * 3 * 4` and `snake_case_name` stay as they are.
+fn emphasis_end(chars: &[char], start: usize, len: usize) -> Option<usize> {
+    let mark = chars[start];
+    let is_word = |i: Option<usize>| {
+        i.and_then(|i| chars.get(i))
+            .is_some_and(|c| c.is_alphanumeric())
+    };
+    let after = chars.get(start + len)?;
+    if after.is_whitespace() || (mark == '_' && is_word(start.checked_sub(1))) {
+        return None;
+    }
+    let mut from = start + len;
+    while let Some(end) = find_run(chars, from, mark, len) {
+        let closes = !chars[end - 1].is_whitespace()
+            && end > start + len
+            && !(mark == '_' && is_word(Some(end + len)));
+        if closes {
+            return Some(end);
+        }
+        from = end + len;
+    }
+    None
+}
+
+/// `[label](url)` starting at `start`: the label, the URL and where the
+/// link ends.
+fn link(chars: &[char], start: usize) -> Option<(String, String, usize)> {
+    let close = start + chars[start..].iter().position(|&c| c == ']')?;
+    if chars.get(close + 1) != Some(&'(') {
+        return None;
+    }
+    let end = close + 2 + chars[close + 2..].iter().position(|&c| c == ')')?;
+    let label = chars[start + 1..close].iter().collect();
+    let url: String = chars[close + 2..end].iter().collect();
+    // Drop a title: [label](url "title")
+    let url = url
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    Some((label, url, end + 1))
+}
+
+/// `<https://…>` or `<mailto:…>` starting at `start`: the URL and where
+/// it ends.
+fn autolink(chars: &[char], start: usize) -> Option<(String, usize)> {
+    let end = start + chars[start..].iter().position(|&c| c == '>')?;
+    let url: String = chars[start + 1..end].iter().collect();
+    let is_url = ["http://", "https://", "mailto:"]
+        .iter()
+        .any(|scheme| url.starts_with(scheme))
+        && !url.contains(char::is_whitespace);
+    is_url.then_some((url, end + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inline_markdown() {
+        for (markdown, plain) in [
+            ("**Deploy** of `main` *finished*", "Deploy of main finished"),
+            (
+                "__bold__ _italic_ ~~gone~~ ***both***",
+                "bold italic gone both",
+            ),
+            ("``a `tick` b``", "a `tick` b"),
+            ("`a*b*c`", "a*b*c"),
+            (
+                "see [runbook](https://x.io/rb \"Runbook\")",
+                "see runbook (https://x.io/rb)",
+            ),
+            ("[https://x.io](https://x.io)", "https://x.io"),
+            (
+                "![chart](https://x.io/c.png) <https://x.io>",
+                "chart https://x.io",
+            ),
+            ("\\*not emphasis\\*", "*not emphasis*"),
+            ("2 * 3 * 4", "2 * 3 * 4"),
+            (
+                "snake_case_name and {{device_name}}",
+                "snake_case_name and {{device_name}}",
+            ),
+            ("disk at 95%, load 3.2", "disk at 95%, load 3.2"),
+            ("**unclosed", "**unclosed"),
+            ("a < b > c", "a < b > c"),
+        ] {
+            assert_eq!(to_plain_text(markdown), plain, "{markdown}");
+        }
+    }
+
+    #[test]
+    fn test_block_markdown() {
+        let markdown =
+            "## [FIRING] HighCPU\n> **host**: web-1\n\n---\n- one\n  * two\n```\nlet *x* = 1;\n```";
+        assert_eq!(
+            to_plain_text(markdown),
+            "[FIRING] HighCPU\nhost: web-1\n\n• one\n  • two\nlet *x* = 1;"
+        );
+        assert_eq!(to_plain_text("#hashtag"), "#hashtag");
+    }
+}
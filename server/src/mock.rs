@@ -0,0 +1,336 @@
+use async_trait::async_trait;
+use axum::{extract::State, http::StatusCode, Json};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use crate::{
+    apns::{self, ApnsPriority, ApnsPushType},
+    apns_error::{ApnsErrorCode, SendError},
+    channels::{Broadcaster, ChannelError, MessageStoragePolicy},
+    provider::{Credentials, DeliveryResult, Provider, Target},
+    token, AppState, Environment, ErrorResponse, SendRequest,
+};
+
+/// Oldest deliveries are dropped past this many.
+const MAX_DELIVERIES: usize = 1000;
+
+/// A push the mock provider accepted instead of sending to Apple.
+#[derive(Debug, Clone, Serialize)]
+pub struct MockDelivery {
+    apns_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_token: Option<String>,
+    /// Set for a broadcast, which goes to a channel instead of a device.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    channel_id: Option<String>,
+    environment: String,
+    topic: String,
+    push_type: &'static str,
+    /// The APNs priority level (1, 5 or 10) the request maps to, if any.
+    priority: Option<u8>,
+    /// The `apns-collapse-id` header, if set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    collapse_id: Option<String>,
+    /// The `apns-expiration` header, if set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expiration: Option<u64>,
+    payload: Value,
+}
+
+#[derive(Debug, Default)]
+struct Log {
+    sent: u64,
+    deliveries: Vec<MockDelivery>,
+}
+
+/// The in-memory delivery log behind `GET /mock/deliveries`.
+#[derive(Debug, Clone, Default)]
+pub struct MockDeliveries(Arc<Mutex<Log>>);
+
+impl MockDeliveries {
+    fn push(&self, mut delivery: MockDelivery) -> String {
+        let mut log = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        log.sent += 1;
+        delivery.apns_id = format!("00000000-0000-0000-0000-{:012x}", log.sent);
+        let apns_id = delivery.apns_id.clone();
+        if log.deliveries.len() == MAX_DELIVERIES {
+            log.deliveries.remove(0);
+        }
+        log.deliveries.push(delivery);
+        apns_id
+    }
+
+    pub fn list(&self) -> Vec<MockDelivery> {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .deliveries
+            .clone()
+    }
+
+    pub fn clear(&self) {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .deliveries
+            .clear();
+    }
+}
+
+/// Stands in for APNs under `PSH_APNS_MODE=mock`, accepting every push.
+pub struct MockProvider {
+    topic: String,
+    deliveries: MockDeliveries,
+}
+
+impl MockProvider {
+    pub fn new(topic: String) -> Self {
+        Self {
+            topic,
+            deliveries: MockDeliveries::default(),
+        }
+    }
+
+    pub fn deliveries(&self) -> MockDeliveries {
+        self.deliveries.clone()
+    }
+}
+
+#[async_trait]
+impl Provider for MockProvider {
+    async fn send(&self, req: &SendRequest, target: Target<'_>) -> DeliveryResult {
+        let environment = Environment::try_from(target.environment)
+            .map_err(|_| SendError::new(ApnsErrorCode::InvalidEnvironment))?;
+        let apns_id = self.deliveries.push(MockDelivery {
+            apns_id: String::new(),
+            device_token: Some(target.token.to_string()),
+            channel_id: None,
+            environment: environment.as_str().to_string(),
+            topic: apns::topic_for(&self.topic, req).into_owned(),
+            push_type: ApnsPushType::of(req).as_str(),
+            // What live APNs sends, which has no 1.
+            priority: req
+                .priority
+                .and_then(|p| ApnsPriority::from_u8(p).ok())
+                .map(|p| p.sent_to_devices().as_u8()),
+            collapse_id: req.collapse_id.clone(),
+            expiration: req.expiration,
+            payload: apns::payload_json(req),
+        });
+        tracing::info!(device_token = %token::logged(target.token), apns_id = %apns_id, "Mock APNs delivery");
+        Ok(apns_id)
+    }
+    fn credentials(&self) -> Option<Credentials> {
+        Some(Credentials {
+            mock: true,
+            key_id: None,
+            team_id: None,
+            topic: self.topic.clone(),
+        })
+    }
+}
+
+/// Stands in for APNs broadcast channels under `PSH_APNS_MODE=mock`,
+/// recording each channel send alongside the device deliveries.
+pub struct MockBroadcaster {
+    topic: String,
+    deliveries: MockDeliveries,
+    /// Channel id to its environment and storage policy.
+    channels: Mutex<BTreeMap<String, (Environment, MessageStoragePolicy)>>,
+}
+
+impl MockBroadcaster {
+    pub fn new(topic: String, deliveries: MockDeliveries) -> Self {
+        Self {
+            topic,
+            deliveries,
+            channels: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn channel(
+        &self,
+        environment: Environment,
+        channel_id: &str,
+    ) -> Result<MessageStoragePolicy, ChannelError> {
+        match self
+            .channels
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(channel_id)
+        {
+            Some((env, policy)) if *env == environment => Ok(*policy),
+            _ => Err(ChannelError::not_found()),
+        }
+    }
+}
+
+#[async_trait]
+impl Broadcaster for MockBroadcaster {
+    async fn create_channel(
+        &self,
+        environment: Environment,
+        policy: MessageStoragePolicy,
+    ) -> Result<String, ChannelError> {
+        let mut channels = self.channels.lock().unwrap_or_else(|e| e.into_inner());
+        let channel_id = format!("mock-channel-{}", channels.len() + 1);
+        channels.insert(channel_id.clone(), (environment, policy));
+        Ok(channel_id)
+    }
+
+    async fn list_channels(&self, environment: Environment) -> Result<Vec<String>, ChannelError> {
+        Ok(self
+            .channels
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .filter(|(_, (env, _))| *env == environment)
+            .map(|(id, _)| id.clone())
+            .collect())
+    }
+
+    async fn channel_policy(
+        &self,
+        environment: Environment,
+        channel_id: &str,
+    ) -> Result<MessageStoragePolicy, ChannelError> {
+        self.channel(environment, channel_id)
+    }
+
+    async fn delete_channel(
+        &self,
+        environment: Environment,
+        channel_id: &str,
+    ) -> Result<(), ChannelError> {
+        self.channel(environment, channel_id)?;
+        self.channels
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(channel_id);
+        Ok(())
+    }
+
+    async fn publish(
+        &self,
+        environment: Environment,
+        channel_id: &str,
+        req: &SendRequest,
+    ) -> Result<String, ChannelError> {
+        self.channel(environment, channel_id)?;
+        let apns_id = self.deliveries.push(MockDelivery {
+            apns_id: String::new(),
+            device_token: None,
+            channel_id: Some(channel_id.to_string()),
+            environment: environment.as_str().to_string(),
+            topic: self.topic.clone(),
+            push_type: ApnsPushType::LiveActivity.as_str(),
+            priority: req
+                .priority
+                .and_then(|p| ApnsPriority::from_u8(p).ok())
+                .map(|p| p.as_u8()),
+            collapse_id: req.collapse_id.clone(),
+            expiration: req.expiration,
+            payload: apns::payload_json(req),
+        });
+        tracing::info!(channel_id = %channel_id, apns_id = %apns_id, "Mock APNs broadcast");
+        Ok(apns_id)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct MockDeliveriesResponse {
+    deliveries: Vec<MockDelivery>,
+}
+
+fn mock_deliveries(state: &AppState) -> Result<&MockDeliveries, (StatusCode, Json<ErrorResponse>)> {
+    state.mock_deliveries.as_ref().ok_or_else(|| {
+        ErrorResponse::with_status(
+            StatusCode::NOT_FOUND,
+            "Mock deliveries are only recorded with PSH_APNS_MODE=mock",
+        )
+    })
+}
+
+pub(crate) async fn list_deliveries(
+    State(state): State<AppState>,
+) -> Result<Json<MockDeliveriesResponse>, (StatusCode, Json<ErrorResponse>)> {
+    Ok(Json(MockDeliveriesResponse {
+        deliveries: mock_deliveries(&state)?.list(),
+    }))
+}
+
+pub(crate) async fn clear_deliveries(
+    State(state): State<AppState>,
+) -> Result<StatusCode, (StatusCode, Json<ErrorResponse>)> {
+    mock_deliveries(&state)?.clear();
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_mock_provider_records_payloads() {
+        let provider = MockProvider::new("com.example.app".to_string());
+        let req = SendRequest {
+            title: Some("Hello".to_string()),
+            ..Default::default()
+        };
+        let target = Target {
+            token: "abc",
+            environment: "sandbox",
+        };
+
+        let first = provider.send(&req, target).await.unwrap();
+        let second = provider.send(&req, target).await.unwrap();
+        assert_ne!(first, second);
+
+        let deliveries = provider.deliveries().list();
+        assert_eq!(deliveries.len(), 2);
+        assert_eq!(deliveries[0].apns_id, first);
+        assert_eq!(deliveries[0].topic, "com.example.app");
+        assert_eq!(deliveries[0].payload["aps"]["alert"]["title"], "Hello");
+
+        provider.deliveries().clear();
+        assert!(provider.deliveries().list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_mock_provider_rejects_unknown_environment() {
+        let provider = MockProvider::new("com.example.app".to_string());
+        let target = Target {
+            token: "abc",
+            environment: "staging",
+        };
+        let error = provider
+            .send(&SendRequest::default(), target)
+            .await
+            .unwrap_err();
+        assert_eq!(error.code, ApnsErrorCode::InvalidEnvironment);
+    }
+
+    #[test]
+    fn test_log_keeps_most_recent_deliveries() {
+        let log = MockDeliveries::default();
+        for i in 0..MAX_DELIVERIES + 5 {
+            log.push(MockDelivery {
+                apns_id: String::new(),
+                device_token: Some(i.to_string()),
+                channel_id: None,
+                environment: "sandbox".to_string(),
+                topic: String::new(),
+                push_type: "alert",
+                priority: None,
+                collapse_id: None,
+                expiration: None,
+                payload: Value::Null,
+            });
+        }
+        let deliveries = log.list();
+        assert_eq!(deliveries.len(), MAX_DELIVERIES);
+        assert_eq!(deliveries[0].device_token.as_deref(), Some("5"));
+    }
+}
@@ -0,0 +1,130 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use seekwel::{connection::Connection, error::Error as SeekwelError, rusqlite::params};
+use serde::Serialize;
+
+use crate::{AppState, Database, ErrorResponse};
+
+#[derive(Debug, Serialize)]
+pub struct OpenedResponse {
+    id: i64,
+    opened_at: String,
+}
+
+impl Database {
+    /// A delivered push by its psh id or, as the app knows it, its APNs id.
+    pub(crate) fn delivered_push_id(id: &str) -> Result<Option<i64>, SeekwelError> {
+        let conn = Connection::get()?;
+        match id.parse::<i64>() {
+            Ok(id) => conn.query_optional(
+                "SELECT id FROM pushes WHERE id = ?1 AND status = 'sent'",
+                params![id],
+                |row| row.get(0),
+            ),
+            Err(_) => Self::delivered_push_by_apns_id(id),
+        }
+    }
+
+    /// A delivered push by the APNs id the app sees as the notification's
+    /// identifier.
+    pub(crate) fn delivered_push_by_apns_id(apns_id: &str) -> Result<Option<i64>, SeekwelError> {
+        Connection::get()?.query_optional(
+            r#"
+            SELECT id FROM pushes
+            WHERE apns_id = ?1 COLLATE NOCASE AND status = 'sent'
+            ORDER BY id DESC
+            LIMIT 1
+            "#,
+            params![apns_id],
+            |row| row.get(0),
+        )
+    }
+
+    /// Records the first time a push was opened and returns when that was.
+    fn mark_opened(push_id: i64) -> Result<String, SeekwelError> {
+        let conn = Connection::get()?;
+        conn.execute(
+            "UPDATE pushes SET opened_at = COALESCE(opened_at, CURRENT_TIMESTAMP) WHERE id = ?1",
+            params![push_id],
+        )?;
+        conn.query_row(
+            "SELECT opened_at FROM pushes WHERE id = ?1",
+            params![push_id],
+            |row| row.get(0),
+        )
+    }
+}
+
+fn database_error(e: SeekwelError) -> (StatusCode, Json<ErrorResponse>) {
+    tracing::error!(error = %e, "Database error recording opened push");
+    ErrorResponse::with_status(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Database error: {e}"),
+    )
+}
+
+/// `POST /pushes/:id/opened`: the app reports the user tapped a push, by
+/// the APNs id it sees as the notification's identifier. Reporting the same
+/// push again keeps the first time. The route needs no key, so it takes
+/// only APNs ids: psh ids count up, and could be walked to inflate stats.
+pub async fn push_opened(
+    State(_state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<OpenedResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let push_id = Database::delivered_push_by_apns_id(&id)
+        .map_err(database_error)?
+        .ok_or_else(|| {
+            tracing::warn!(push = %id, "Opened push not found");
+            ErrorResponse::with_status(StatusCode::NOT_FOUND, "Push not found")
+        })?;
+    let opened_at = Database::mark_opened(push_id).map_err(database_error)?;
+    tracing::info!(push_id = push_id, "Push opened");
+    Ok(Json(OpenedResponse {
+        id: push_id,
+        opened_at,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_db;
+
+    #[test]
+    fn test_mark_opened_by_id_or_apns_id() {
+        let _db = test_db();
+        let conn = Connection::get().unwrap();
+        conn.execute(
+            "INSERT INTO devices (device_token, installation_id, environment) VALUES ('a', 'i', 'sandbox')",
+            (),
+        )
+        .unwrap();
+        conn.execute(
+            r#"
+            INSERT INTO pushes (device_id, apns_id, status, sent_at) VALUES
+                (1, 'B7E1C2D4-0000-4000-8000-000000000001', 'sent', '2024-01-01 14:00:00'),
+                (1, NULL, 'failed', '2024-01-01 14:01:00')
+            "#,
+            (),
+        )
+        .unwrap();
+
+        let id = Database::delivered_push_id("b7e1c2d4-0000-4000-8000-000000000001")
+            .unwrap()
+            .unwrap();
+        assert_eq!(Database::delivered_push_id("1").unwrap(), Some(id));
+        assert!(Database::delivered_push_id("2").unwrap().is_none());
+        assert!(Database::delivered_push_id("unknown").unwrap().is_none());
+        assert!(Database::delivered_push_by_apns_id("1").unwrap().is_none());
+
+        conn.execute(
+            "UPDATE pushes SET opened_at = '2024-01-01 14:05:00' WHERE id = 1",
+            (),
+        )
+        .unwrap();
+        assert_eq!(Database::mark_opened(id).unwrap(), "2024-01-01 14:05:00");
+    }
+}
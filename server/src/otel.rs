@@ -0,0 +1,44 @@
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+use std::env;
+use tracing_subscriber::{registry::LookupSpan, Layer};
+
+const DEFAULT_SERVICE_NAME: &str = "psh-server";
+
+/// Flushes buffered spans to the collector when dropped.
+pub(crate) struct OtelGuard(SdkTracerProvider);
+
+impl Drop for OtelGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.shutdown() {
+            eprintln!("Failed to flush OpenTelemetry spans: {e}");
+        }
+    }
+}
+
+/// A layer exporting spans over OTLP/HTTP to `OTEL_EXPORTER_OTLP_ENDPOINT`,
+/// or `None` when it isn't set. The exporter reads the other standard
+/// `OTEL_EXPORTER_OTLP_*` variables itself.
+pub(crate) fn layer<S>() -> Result<Option<(impl Layer<S>, OtelGuard)>, String>
+where
+    S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+{
+    if env::var("OTEL_EXPORTER_OTLP_ENDPOINT").map_or(true, |v| v.trim().is_empty()) {
+        return Ok(None);
+    }
+
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .build()
+        .map_err(|e| format!("Cannot create OTLP exporter: {e}"))?;
+    let service_name =
+        env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| DEFAULT_SERVICE_NAME.to_string());
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(service_name).build())
+        .build();
+
+    let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("psh-server"));
+    Ok(Some((layer, OtelGuard(provider))))
+}
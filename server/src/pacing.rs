@@ -0,0 +1,185 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// How much of its configured rate a slowed endpoint regains each second.
+const RECOVERY_PER_SECOND: f64 = 0.05;
+
+/// `TooManyRequests` never slows an endpoint below this many requests a
+/// second.
+const MIN_RATE: f64 = 1.0;
+
+/// `TooManyRequests` answers this soon after a slow-down are for requests
+/// already in flight, so they don't slow the endpoint again.
+const SLOW_DOWN_GRACE: Duration = Duration::from_secs(1);
+
+struct Bucket {
+    /// The configured requests a second.
+    limit: f64,
+    /// The current requests a second, below `limit` after a slow-down.
+    rate: f64,
+    burst: f64,
+    /// Requests that may go now; negative when requests are waiting.
+    tokens: f64,
+    updated: Instant,
+    slowed_at: Option<Instant>,
+}
+
+impl Bucket {
+    fn new(limit: u32, burst: u32, now: Instant) -> Self {
+        Self {
+            limit: limit as f64,
+            rate: limit as f64,
+            burst: burst.max(1) as f64,
+            tokens: burst.max(1) as f64,
+            updated: now,
+            slowed_at: None,
+        }
+    }
+
+    fn advance(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.updated = self.updated.max(now);
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.rate = (self.rate + elapsed * self.limit * RECOVERY_PER_SECOND).min(self.limit);
+    }
+
+    /// Takes the next request's turn, returning how long to wait for it.
+    fn take(&mut self, now: Instant) -> Duration {
+        self.advance(now);
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+
+    /// Halves the rate and drops any burst, returning the new rate unless
+    /// the endpoint was only just slowed.
+    fn slow_down(&mut self, now: Instant) -> Option<f64> {
+        self.advance(now);
+        if self
+            .slowed_at
+            .is_some_and(|at| now.saturating_duration_since(at) < SLOW_DOWN_GRACE)
+        {
+            return None;
+        }
+        self.slowed_at = Some(now);
+        self.rate = (self.rate / 2.0).max(MIN_RATE).min(self.limit);
+        self.tokens = self.tokens.min(0.0);
+        Some(self.rate)
+    }
+
+    fn slowed_to(&mut self, now: Instant) -> Option<u32> {
+        self.advance(now);
+        (self.rate < self.limit).then_some(self.rate as u32)
+    }
+}
+
+/// Paces requests to one APNs endpoint with a token bucket, so a bulk send
+/// goes out at a rate APNs accepts rather than all at once. When APNs
+/// answers `TooManyRequests`, the rate halves and then climbs back to the
+/// configured one over the following seconds.
+pub(crate) struct Pacer {
+    environment: &'static str,
+    /// `None` when the endpoint isn't paced.
+    bucket: Option<Mutex<Bucket>>,
+}
+
+impl Pacer {
+    /// A pacer allowing `rate` requests a second, up to `burst` at once after
+    /// a quiet spell. A rate of 0 doesn't pace at all.
+    pub(crate) fn new(environment: &'static str, rate: u32, burst: u32) -> Self {
+        Self {
+            environment,
+            bucket: (rate > 0).then(|| Mutex::new(Bucket::new(rate, burst, Instant::now()))),
+        }
+    }
+
+    fn with_bucket<T>(&self, f: impl FnOnce(&mut Bucket) -> T) -> Option<T> {
+        self.bucket
+            .as_ref()
+            .map(|bucket| f(&mut bucket.lock().unwrap_or_else(|e| e.into_inner())))
+    }
+
+    /// Waits for the next request's turn.
+    pub(crate) async fn wait(&self) {
+        let delay = self
+            .with_bucket(|bucket| bucket.take(Instant::now()))
+            .unwrap_or_default();
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Slows down after APNs answered `TooManyRequests`.
+    pub(crate) fn slow_down(&self) {
+        if let Some(Some(rate)) = self.with_bucket(|bucket| bucket.slow_down(Instant::now())) {
+            tracing::warn!(
+                environment = self.environment,
+                requests_per_second = rate as u32,
+                "APNs answered TooManyRequests, slowing down"
+            );
+        }
+    }
+
+    /// The requests a second the endpoint is slowed to, while it's below
+    /// its configured rate.
+    pub(crate) fn slowed_to(&self) -> Option<u32> {
+        self.with_bucket(|bucket| bucket.slowed_to(Instant::now()))
+            .flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_paces_past_its_burst() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(10, 2, start);
+        assert_eq!(bucket.take(start), Duration::ZERO);
+        assert_eq!(bucket.take(start), Duration::ZERO);
+        assert_eq!(bucket.take(start), Duration::from_millis(100));
+        assert_eq!(bucket.take(start), Duration::from_millis(200));
+
+        // A quiet spell refills the burst, and no more.
+        let later = start + Duration::from_secs(10);
+        assert_eq!(bucket.take(later), Duration::ZERO);
+        assert_eq!(bucket.take(later), Duration::ZERO);
+        assert!(bucket.take(later) > Duration::ZERO);
+    }
+
+    #[test]
+    fn test_too_many_requests_halves_the_rate_until_it_recovers() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(100, 10, start);
+        assert_eq!(bucket.slowed_to(start), None);
+
+        assert_eq!(bucket.slow_down(start), Some(50.0));
+        assert_eq!(bucket.take(start), Duration::from_millis(20));
+        // Answers to requests already in flight don't slow it further.
+        let soon = start + Duration::from_millis(500);
+        assert_eq!(bucket.slow_down(soon), None);
+        let second = start + Duration::from_secs(2);
+        assert!(bucket.slow_down(second).unwrap() < 50.0);
+        assert!(bucket.slowed_to(second).is_some());
+
+        assert_eq!(bucket.slowed_to(second + Duration::from_secs(20)), None);
+    }
+
+    #[test]
+    fn test_slow_down_keeps_a_minimum_rate() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(2, 1, start);
+        bucket.slow_down(start);
+        assert_eq!(
+            bucket.slow_down(start + Duration::from_secs(2)),
+            Some(MIN_RATE)
+        );
+        assert!(Pacer::new("sandbox", 0, 10).slowed_to().is_none());
+    }
+}
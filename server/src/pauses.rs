@@ -0,0 +1,633 @@
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use seekwel::{connection::Connection, error::Error as SeekwelError, rusqlite::params};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audit::{self, AuditContext},
+    auth::Caller,
+    config::Role,
+    filter::DeviceFilter,
+    limits, request_id, topics, AppState, Database, ErrorResponse, SendRequest, SendResponse,
+};
+
+/// Sends queued behind one pause past this many are dropped, so a
+/// forgotten pause can't grow the database without bound.
+const MAX_QUEUED_SENDS: i64 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PauseKind {
+    Topic,
+    Segment,
+}
+
+impl PauseKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            PauseKind::Topic => "topic",
+            PauseKind::Segment => "segment",
+        }
+    }
+
+    fn from_db(value: &str) -> Self {
+        match value {
+            "segment" => PauseKind::Segment,
+            _ => PauseKind::Topic,
+        }
+    }
+}
+
+/// What happens to sends while paused.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PausePolicy {
+    /// Kept, and sent in order on resume.
+    #[default]
+    Queue,
+    Drop,
+}
+
+impl PausePolicy {
+    fn as_str(self) -> &'static str {
+        match self {
+            PausePolicy::Queue => "queue",
+            PausePolicy::Drop => "drop",
+        }
+    }
+
+    fn from_db(value: &str) -> Self {
+        match value {
+            "drop" => PausePolicy::Drop,
+            _ => PausePolicy::Queue,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PauseRequest {
+    #[serde(default)]
+    policy: PausePolicy,
+    /// Why, for whoever finds the pause later.
+    reason: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Pause {
+    kind: PauseKind,
+    name: String,
+    policy: PausePolicy,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    paused_at: String,
+    /// Sends waiting for the resume.
+    queued: i64,
+    /// Sends dropped while paused, by the policy or a full queue.
+    dropped: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PausesResponse {
+    pauses: Vec<Pause>,
+}
+
+/// Tells a sender its push was held by a pause instead of sent.
+#[derive(Debug, Serialize)]
+pub(crate) struct PausedSend {
+    kind: PauseKind,
+    name: String,
+    /// `queued` or `dropped`.
+    action: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ResumeResponse {
+    kind: PauseKind,
+    name: String,
+    /// Queued sends replayed.
+    replayed: usize,
+    sent: usize,
+    failed: usize,
+    /// Queued sends the server refused on replay, e.g. because they had
+    /// expired or no devices match any more.
+    rejected: usize,
+    /// Sends dropped while paused.
+    dropped: i64,
+}
+
+impl Database {
+    pub(crate) fn create_pauses_tables(conn: &Connection) -> Result<(), SeekwelError> {
+        conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS pauses (
+                kind TEXT NOT NULL,
+                name TEXT NOT NULL,
+                policy TEXT NOT NULL,
+                reason TEXT,
+                dropped INTEGER NOT NULL DEFAULT 0,
+                paused_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                PRIMARY KEY (kind, name)
+            )
+            "#,
+            (),
+        )?;
+        conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS paused_sends (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                kind TEXT NOT NULL,
+                name TEXT NOT NULL,
+                request TEXT NOT NULL,
+                api_key TEXT,
+                created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            )
+            "#,
+            (),
+        )?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_paused_sends_pause ON paused_sends(kind, name)",
+            (),
+        )?;
+        Ok(())
+    }
+
+    /// Pauses `name`, or changes the policy and reason of its pause.
+    fn pause(
+        kind: PauseKind,
+        name: &str,
+        policy: PausePolicy,
+        reason: Option<&str>,
+    ) -> Result<(), SeekwelError> {
+        Connection::get()?.execute(
+            r#"
+            INSERT INTO pauses (kind, name, policy, reason)
+            VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT(kind, name) DO UPDATE SET
+                policy = excluded.policy,
+                reason = excluded.reason
+            "#,
+            params![kind.as_str(), name, policy.as_str(), reason],
+        )?;
+        Ok(())
+    }
+
+    fn pauses() -> Result<Vec<Pause>, SeekwelError> {
+        Connection::get()?.query_all(
+            r#"
+            SELECT p.kind, p.name, p.policy, p.reason, p.paused_at, p.dropped,
+                   (SELECT COUNT(*) FROM paused_sends s WHERE s.kind = p.kind AND s.name = p.name)
+            FROM pauses p
+            ORDER BY p.kind, p.name
+            "#,
+            (),
+            |row| {
+                let kind: String = row.get(0)?;
+                let policy: String = row.get(2)?;
+                Ok(Pause {
+                    kind: PauseKind::from_db(&kind),
+                    name: row.get(1)?,
+                    policy: PausePolicy::from_db(&policy),
+                    reason: row.get(3)?,
+                    paused_at: row.get(4)?,
+                    dropped: row.get(5)?,
+                    queued: row.get(6)?,
+                })
+            },
+        )
+    }
+
+    /// The pause holding sends to `segment` or `topic`, the segment's first.
+    fn active_pause(
+        segment: Option<&str>,
+        topic: Option<&str>,
+    ) -> Result<Option<(PauseKind, String, PausePolicy)>, SeekwelError> {
+        if segment.is_none() && topic.is_none() {
+            return Ok(None);
+        }
+        Connection::get()?.query_optional(
+            r#"
+            SELECT kind, name, policy
+            FROM pauses
+            WHERE (kind = 'segment' AND name = ?1) OR (kind = 'topic' AND name = ?2)
+            ORDER BY kind
+            LIMIT 1
+            "#,
+            params![segment, topic],
+            |row| {
+                let kind: String = row.get(0)?;
+                let policy: String = row.get(2)?;
+                Ok((
+                    PauseKind::from_db(&kind),
+                    row.get(1)?,
+                    PausePolicy::from_db(&policy),
+                ))
+            },
+        )
+    }
+
+    /// Queues `request` behind the pause, unless its queue is full. Returns
+    /// whether it was queued.
+    fn queue_paused_send(
+        kind: PauseKind,
+        name: &str,
+        request: &str,
+        api_key: Option<&str>,
+    ) -> Result<bool, SeekwelError> {
+        let conn = Connection::get()?;
+        Connection::transaction(|| {
+            let queued: i64 = conn.query_row(
+                "SELECT COUNT(*) FROM paused_sends WHERE kind = ?1 AND name = ?2",
+                params![kind.as_str(), name],
+                |row| row.get(0),
+            )?;
+            if queued >= MAX_QUEUED_SENDS {
+                return Ok(false);
+            }
+            conn.execute(
+                "INSERT INTO paused_sends (kind, name, request, api_key) VALUES (?1, ?2, ?3, ?4)",
+                params![kind.as_str(), name, request, api_key],
+            )?;
+            Ok(true)
+        })
+    }
+
+    fn count_dropped_send(kind: PauseKind, name: &str) -> Result<(), SeekwelError> {
+        Connection::get()?.execute(
+            "UPDATE pauses SET dropped = dropped + 1 WHERE kind = ?1 AND name = ?2",
+            params![kind.as_str(), name],
+        )?;
+        Ok(())
+    }
+
+    /// Lifts the pause, returning how many sends it dropped and the sends it
+    /// queued, oldest first, with the API key of each. `None` if `name`
+    /// wasn't paused.
+    #[allow(clippy::type_complexity)]
+    fn resume(
+        kind: PauseKind,
+        name: &str,
+    ) -> Result<Option<(i64, Vec<(String, Option<String>)>)>, SeekwelError> {
+        let conn = Connection::get()?;
+        Connection::transaction(|| {
+            let dropped: Option<i64> = conn.query_optional(
+                "SELECT dropped FROM pauses WHERE kind = ?1 AND name = ?2",
+                params![kind.as_str(), name],
+                |row| row.get(0),
+            )?;
+            let Some(dropped) = dropped else {
+                return Ok(None);
+            };
+            let queued = conn.query_all(
+                "SELECT request, api_key FROM paused_sends WHERE kind = ?1 AND name = ?2 ORDER BY id",
+                params![kind.as_str(), name],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?;
+            conn.execute(
+                "DELETE FROM paused_sends WHERE kind = ?1 AND name = ?2",
+                params![kind.as_str(), name],
+            )?;
+            conn.execute(
+                "DELETE FROM pauses WHERE kind = ?1 AND name = ?2",
+                params![kind.as_str(), name],
+            )?;
+            Ok(Some((dropped, queued)))
+        })
+    }
+}
+
+fn database_error(e: SeekwelError) -> (StatusCode, Json<ErrorResponse>) {
+    tracing::error!(error = %e, "Database error handling pause");
+    ErrorResponse::with_status(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Database error: {e}"),
+    )
+}
+
+/// The request as received, with the targeting that serializing a
+/// `SendRequest` leaves out, so a replay goes to the same devices.
+fn stored_request(req: &SendRequest) -> Result<String, serde_json::Error> {
+    let mut value = serde_json::to_value(req)?;
+    if let Some(object) = value.as_object_mut() {
+        object.insert("filter".to_string(), serde_json::to_value(&req.filter)?);
+        object.insert("segment".to_string(), serde_json::to_value(&req.segment)?);
+    }
+    serde_json::to_string(&value)
+}
+
+/// Holds `received`, the send as it arrived, if its segment or the topic
+/// its resolved `filter` targets is paused: queues or drops it per the
+/// pause's policy, and returns the response to give instead of sending.
+pub(crate) fn hold(
+    audit: &AuditContext,
+    received: &SendRequest,
+    filter: Option<&DeviceFilter>,
+) -> Result<Option<SendResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let topic = filter.and_then(|filter| filter.topic.as_deref());
+    let Some((kind, name, policy)) =
+        Database::active_pause(received.segment.as_deref(), topic).map_err(database_error)?
+    else {
+        return Ok(None);
+    };
+
+    let api_key = audit.caller.as_ref().map(|caller| caller.name.as_str());
+    let queued = policy == PausePolicy::Queue
+        && {
+            let request = stored_request(received).map_err(|e| {
+                ErrorResponse::with_status(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+            })?;
+            let queued = Database::queue_paused_send(kind, &name, &request, api_key)
+                .map_err(database_error)?;
+            if !queued {
+                tracing::warn!(kind = kind.as_str(), name = %name, limit = MAX_QUEUED_SENDS, "Pause queue is full, dropping send");
+            }
+            queued
+        };
+    if !queued {
+        Database::count_dropped_send(kind, &name).map_err(database_error)?;
+    }
+    let action = if queued { "queued" } else { "dropped" };
+    tracing::info!(kind = kind.as_str(), name = %name, action = action, "Send held by pause");
+    audit::record(
+        audit,
+        "send",
+        format!("{action} while {} {name} is paused", kind.as_str()),
+    );
+
+    Ok(Some(SendResponse {
+        success: true,
+        sent: 0,
+        failed: 0,
+        deferred: 0,
+        skipped: 0,
+        snoozed: 0,
+        request_id: audit.request_id.clone(),
+        apns_expiration: None,
+        paused: Some(PausedSend { kind, name, action }),
+        results: Vec::new(),
+    }))
+}
+
+fn parse_pause_request(body: &Bytes) -> Result<PauseRequest, (StatusCode, Json<ErrorResponse>)> {
+    if body.is_empty() {
+        return Ok(PauseRequest::default());
+    }
+    serde_json::from_slice(body).map_err(|e| {
+        ErrorResponse::with_status(StatusCode::BAD_REQUEST, format!("Invalid JSON: {e}"))
+    })
+}
+
+/// Checks `name` names something that can be paused.
+fn check_target(kind: PauseKind, name: &str) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+    match kind {
+        PauseKind::Topic if !topics::is_valid_topic(name) => Err(ErrorResponse::with_status(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Topics must be 1-64 characters of letters, digits, '-', '_' or '.'",
+        )),
+        PauseKind::Segment if Database::segment(name).map_err(database_error)?.is_none() => Err(
+            ErrorResponse::with_status(StatusCode::NOT_FOUND, format!("Segment not found: {name}")),
+        ),
+        _ => Ok(()),
+    }
+}
+
+async fn pause(
+    audit: AuditContext,
+    kind: PauseKind,
+    name: String,
+    body: Bytes,
+) -> Result<Json<Pause>, (StatusCode, Json<ErrorResponse>)> {
+    let req = parse_pause_request(&body)?;
+    check_target(kind, &name)?;
+    Database::pause(kind, &name, req.policy, req.reason.as_deref()).map_err(database_error)?;
+    tracing::info!(kind = kind.as_str(), name = %name, policy = req.policy.as_str(), "Paused sends");
+    audit::record(
+        &audit,
+        &format!("{}.pause", kind.as_str()),
+        format!("{}={name} policy={}", kind.as_str(), req.policy.as_str()),
+    );
+    Database::pauses()
+        .map_err(database_error)?
+        .into_iter()
+        .find(|pause| pause.kind == kind && pause.name == name)
+        .map(Json)
+        .ok_or_else(|| {
+            ErrorResponse::with_status(StatusCode::INTERNAL_SERVER_ERROR, "Pause wasn't saved")
+        })
+}
+
+/// The key a queued send came in with, so its replay counts against that
+/// key's quotas as they are now. Keys issued through `/keys` have none.
+fn queued_caller(state: &AppState, api_key: Option<String>) -> Option<Caller> {
+    let name = api_key?;
+    let configured = state
+        .api_keys
+        .callers()
+        .find(|caller| caller.name == name)
+        .cloned();
+    Some(configured.unwrap_or(Caller {
+        name,
+        role: Role::Send,
+        daily_quota: None,
+        monthly_quota: None,
+        topic: None,
+    }))
+}
+
+/// Lifts the pause, then sends what it queued in the order it arrived.
+async fn resume(
+    state: AppState,
+    audit: AuditContext,
+    kind: PauseKind,
+    name: String,
+) -> Result<Json<ResumeResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let (dropped, queued) = Database::resume(kind, &name)
+        .map_err(database_error)?
+        .ok_or_else(|| {
+            ErrorResponse::with_status(
+                StatusCode::NOT_FOUND,
+                format!("{} {name} isn't paused", kind.as_str()),
+            )
+        })?;
+    tracing::info!(kind = kind.as_str(), name = %name, queued = queued.len(), dropped = dropped, "Resuming sends");
+    // The queue is already emptied, so its sends are all made even if the
+    // request is abandoned.
+    let response =
+        limits::run_to_completion(replay(state, audit, kind, name, dropped, queued)).await;
+    Ok(Json(response))
+}
+
+/// Sends what a pause queued, in the order it arrived.
+async fn replay(
+    state: AppState,
+    audit: AuditContext,
+    kind: PauseKind,
+    name: String,
+    dropped: i64,
+    queued: Vec<(String, Option<String>)>,
+) -> ResumeResponse {
+    let mut response = ResumeResponse {
+        kind,
+        name,
+        replayed: 0,
+        sent: 0,
+        failed: 0,
+        rejected: 0,
+        dropped,
+    };
+    for (request, api_key) in queued {
+        let req: SendRequest = match serde_json::from_str(&request) {
+            Ok(req) => req,
+            Err(e) => {
+                tracing::error!(error = %e, "Unreadable queued send");
+                response.rejected += 1;
+                continue;
+            }
+        };
+        let replay = AuditContext {
+            endpoint: format!("resume {} {}", kind.as_str(), response.name),
+            remote_addr: audit.remote_addr.clone(),
+            user_agent: audit.user_agent.clone(),
+            request_id: Some(request_id::new_request_id()),
+            caller: queued_caller(&state, api_key),
+        };
+        response.replayed += 1;
+        match crate::send(state.clone(), replay, req).await {
+            Ok(Json(sent)) => {
+                response.sent += sent.sent;
+                response.failed += sent.failed;
+            }
+            Err((status, Json(e))) => {
+                tracing::warn!(status = %status, error = %e.error, "Queued send rejected on resume");
+                response.rejected += 1;
+            }
+        }
+    }
+    audit::record(
+        &audit,
+        &format!("{}.resume", kind.as_str()),
+        format!(
+            "{}={} replayed={} sent={} failed={} dropped={}",
+            kind.as_str(),
+            response.name,
+            response.replayed,
+            response.sent,
+            response.failed,
+            response.dropped
+        ),
+    );
+    response
+}
+
+pub async fn list_pauses(
+    State(_state): State<AppState>,
+) -> Result<Json<PausesResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let pauses = Database::pauses().map_err(database_error)?;
+    Ok(Json(PausesResponse { pauses }))
+}
+
+/// `POST /topics/:topic/pause`: holds sends to the topic, queuing them or
+/// dropping them per `policy`, until it's resumed.
+pub async fn pause_topic(
+    State(_state): State<AppState>,
+    audit: AuditContext,
+    Path(topic): Path<String>,
+    body: Bytes,
+) -> Result<Json<Pause>, (StatusCode, Json<ErrorResponse>)> {
+    pause(audit, PauseKind::Topic, topic, body).await
+}
+
+/// `POST /topics/:topic/resume`: lifts the pause and sends what it queued.
+pub async fn resume_topic(
+    State(state): State<AppState>,
+    audit: AuditContext,
+    Path(topic): Path<String>,
+) -> Result<Json<ResumeResponse>, (StatusCode, Json<ErrorResponse>)> {
+    resume(state, audit, PauseKind::Topic, topic).await
+}
+
+/// `POST /segments/:name/pause`: holds sends naming the segment.
+pub async fn pause_segment(
+    State(_state): State<AppState>,
+    audit: AuditContext,
+    Path(name): Path<String>,
+    body: Bytes,
+) -> Result<Json<Pause>, (StatusCode, Json<ErrorResponse>)> {
+    pause(audit, PauseKind::Segment, name, body).await
+}
+
+pub async fn resume_segment(
+    State(state): State<AppState>,
+    audit: AuditContext,
+    Path(name): Path<String>,
+) -> Result<Json<ResumeResponse>, (StatusCode, Json<ErrorResponse>)> {
+    resume(state, audit, PauseKind::Segment, name).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_db;
+
+    #[test]
+    fn test_pause_queue_and_resume() {
+        let _db = test_db();
+        Database::pause(
+            PauseKind::Topic,
+            "deploys",
+            PausePolicy::Queue,
+            Some("postmortem"),
+        )
+        .unwrap();
+        Database::pause(PauseKind::Segment, "ipads", PausePolicy::Drop, None).unwrap();
+
+        assert_eq!(
+            Database::active_pause(None, Some("deploys")).unwrap(),
+            Some((PauseKind::Topic, "deploys".to_string(), PausePolicy::Queue))
+        );
+        assert_eq!(
+            Database::active_pause(Some("ipads"), Some("deploys"))
+                .unwrap()
+                .map(|pause| pause.0),
+            Some(PauseKind::Segment)
+        );
+        assert_eq!(
+            Database::active_pause(Some("beta"), Some("builds")).unwrap(),
+            None
+        );
+
+        assert!(
+            Database::queue_paused_send(PauseKind::Topic, "deploys", "{}", Some("ci")).unwrap()
+        );
+        Database::count_dropped_send(PauseKind::Segment, "ipads").unwrap();
+        let pauses = Database::pauses().unwrap();
+        assert_eq!(pauses.len(), 2);
+        assert_eq!((pauses[0].name.as_str(), pauses[0].dropped), ("ipads", 1));
+        assert_eq!((pauses[1].name.as_str(), pauses[1].queued), ("deploys", 1));
+        assert_eq!(pauses[1].reason.as_deref(), Some("postmortem"));
+
+        let (dropped, queued) = Database::resume(PauseKind::Topic, "deploys")
+            .unwrap()
+            .unwrap();
+        assert_eq!(dropped, 0);
+        assert_eq!(queued, [("{}".to_string(), Some("ci".to_string()))]);
+        assert_eq!(Database::resume(PauseKind::Topic, "deploys").unwrap(), None);
+        assert_eq!(Database::active_pause(None, Some("deploys")).unwrap(), None);
+    }
+
+    #[test]
+    fn test_stored_request_keeps_targeting() {
+        let req: SendRequest = serde_json::from_str(
+            r#"{"body": "hi", "segment": "ipads", "filter": {"topic": "deploys"}}"#,
+        )
+        .unwrap();
+        let stored: SendRequest = serde_json::from_str(&stored_request(&req).unwrap()).unwrap();
+        assert_eq!(stored.body.as_deref(), Some("hi"));
+        assert_eq!(stored.segment.as_deref(), Some("ipads"));
+        assert_eq!(stored.filter.unwrap().topic.as_deref(), Some("deploys"));
+    }
+}
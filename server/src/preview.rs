@@ -0,0 +1,267 @@
+use axum::{
+    body::Bytes,
+    extract::{Query, RawQuery, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use crate::{
+    actions,
+    apns::{self, ApnsPriority},
+    parse_send_request, truncate, validate_send_request, AppState, ErrorResponse, SendRequest,
+    SoundConfig,
+};
+
+/// APNs rejects alert and background payloads larger than this.
+pub(crate) const MAX_PAYLOAD_BYTES: usize = 4096;
+
+/// What a send would deliver, without sending it.
+#[derive(Debug, Serialize)]
+pub struct PreviewResponse {
+    /// One line describing what the device would show.
+    summary: String,
+    /// The alert text on the lock screen, absent for silent pushes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lock_screen: Option<LockScreen>,
+    /// The APNs headers the server would set.
+    headers: BTreeMap<&'static str, String>,
+    payload: Value,
+    size_bytes: usize,
+    max_size_bytes: usize,
+    warnings: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct LockScreen {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subtitle: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body: Option<String>,
+}
+
+fn has_alert(req: &SendRequest) -> bool {
+    req.title.is_some()
+        || req.subtitle.is_some()
+        || req.body.is_some()
+        || req.title_loc_key.is_some()
+        || req.loc_key.is_some()
+}
+
+/// Renders `req` as the send handler would for one APNs device.
+fn render(req: &SendRequest, topic: &str) -> PreviewResponse {
+    let mut req = req.clone();
+    actions::inject(&mut req);
+
+    let payload = apns::payload_json(&req);
+    let size_bytes = serde_json::to_vec(&payload).map(|v| v.len()).unwrap_or(0);
+    let background = req.content_available == Some(true);
+
+    let mut headers = BTreeMap::from([
+        ("apns-topic", topic.to_string()),
+        (
+            "apns-push-type",
+            if background { "background" } else { "alert" }.to_string(),
+        ),
+    ]);
+    let priority = req.priority.and_then(|p| ApnsPriority::from_u8(p).ok());
+    if let Some(priority) = priority {
+        let sent = priority.sent_to_devices().as_u8();
+        headers.insert("apns-priority", sent.to_string());
+    }
+    if let Some(collapse_id) = &req.collapse_id {
+        headers.insert("apns-collapse-id", collapse_id.clone());
+    }
+    if let Some(expiration) = req.expiration {
+        headers.insert("apns-expiration", expiration.to_string());
+    }
+
+    let mut warnings = Vec::new();
+    if let Some(body) = req.body.as_ref().filter(|_| req.truncated) {
+        warnings.push(format!(
+            "body is cut to {} bytes to fit APNs' {MAX_PAYLOAD_BYTES}-byte limit",
+            body.len()
+        ));
+    }
+    if size_bytes > MAX_PAYLOAD_BYTES {
+        warnings.push(format!(
+            "payload is {size_bytes} bytes, over APNs' {MAX_PAYLOAD_BYTES}-byte limit"
+        ));
+    }
+    if background {
+        if has_alert(&req) || req.sound.is_some() || req.badge.is_some() {
+            warnings.push(
+                "background push with alert fields: it is sent as apns-push-type background, so the alert, sound and badge may not be shown".to_string(),
+            );
+        }
+        if priority == Some(ApnsPriority::High) {
+            warnings.push("background pushes must use priority 5; APNs rejects 10".to_string());
+        }
+    } else if !has_alert(&req) && req.sound.is_none() && req.badge.is_none() {
+        warnings.push(
+            "nothing to show: no alert, sound or badge, and not a background push".to_string(),
+        );
+    }
+    if priority == Some(ApnsPriority::Low) {
+        warnings.push("priority 1-4 is sent as 5 by the APNs client".to_string());
+    }
+    if req.mutable_content == Some(true) && !has_alert(&req) {
+        warnings.push("mutable_content has no effect without an alert".to_string());
+    }
+    if let Some(SoundConfig::Critical {
+        critical: Some(true),
+        ..
+    }) = &req.sound
+    {
+        warnings.push("critical sounds need the app's critical alerts entitlement".to_string());
+    }
+    if req
+        .relevance_score
+        .is_some_and(|score| !(0.0..=1.0).contains(&score))
+    {
+        warnings.push("relevance_score should be between 0 and 1".to_string());
+    }
+    if req.message_key.is_some() {
+        warnings.push(
+            "message_key is resolved per device locale; this shows the request before localization"
+                .to_string(),
+        );
+    }
+
+    let lock_screen = has_alert(&req).then(|| LockScreen {
+        title: req.title.clone().or_else(|| req.title_loc_key.clone()),
+        subtitle: req.subtitle.clone(),
+        body: req.body.clone().or_else(|| req.loc_key.clone()),
+    });
+
+    PreviewResponse {
+        summary: summarize(&req, lock_screen.as_ref(), background),
+        lock_screen,
+        headers,
+        payload,
+        size_bytes,
+        max_size_bytes: MAX_PAYLOAD_BYTES,
+        warnings,
+    }
+}
+
+fn summarize(req: &SendRequest, lock_screen: Option<&LockScreen>, background: bool) -> String {
+    let mut summary = match lock_screen {
+        Some(lock_screen) => {
+            let text: Vec<&str> = [&lock_screen.title, &lock_screen.subtitle, &lock_screen.body]
+                .into_iter()
+                .filter_map(|part| part.as_deref())
+                .collect();
+            format!("Alert: {}", text.join(" / "))
+        }
+        None if background => "Silent background push".to_string(),
+        None => "No visible alert".to_string(),
+    };
+
+    let mut extras = Vec::new();
+    match &req.sound {
+        Some(SoundConfig::Simple(name)) | Some(SoundConfig::Critical { name, .. }) => {
+            extras.push(format!("sound {name}"))
+        }
+        None => {}
+    }
+    if let Some(badge) = req.badge {
+        extras.push(format!("badge {badge}"));
+    }
+    if let Some(level) = &req.interruption_level {
+        extras.push(level.clone());
+    }
+    if let Some(actions) = req.actions.as_ref().filter(|a| !a.is_empty()) {
+        let titles: Vec<&str> = actions.iter().map(|a| a.title.as_str()).collect();
+        extras.push(format!("buttons {}", titles.join(", ")));
+    }
+    if !extras.is_empty() {
+        summary.push_str(&format!(" ({})", extras.join(", ")));
+    }
+    summary
+}
+
+/// `GET /preview?title=...&body=...` for the simple fields.
+pub async fn preview_query(
+    State(state): State<AppState>,
+    Query(mut req): Query<SendRequest>,
+) -> Result<Json<PreviewResponse>, (StatusCode, Json<ErrorResponse>)> {
+    validate_send_request(&mut req)?;
+    truncate::apply(&state.truncation, &mut req);
+    Ok(Json(render(&req, &state.bundle_id)))
+}
+
+/// `POST /preview` with the same body `/send` takes.
+pub async fn preview_body(
+    State(state): State<AppState>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<PreviewResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let mut req = parse_send_request(&headers, query.as_deref(), &body)?;
+    validate_send_request(&mut req)?;
+    truncate::apply(&state.truncation, &mut req);
+    Ok(Json(render(&req, &state.bundle_id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_alert() {
+        let req = SendRequest {
+            title: Some("Hello".to_string()),
+            body: Some("World".to_string()),
+            sound: Some(SoundConfig::Simple("default".to_string())),
+            badge: Some(2),
+            collapse_id: Some("greeting".to_string()),
+            priority: Some(10),
+            ..Default::default()
+        };
+        let preview = render(&req, "com.example.psh");
+
+        assert_eq!(
+            preview.summary,
+            "Alert: Hello / World (sound default, badge 2)"
+        );
+        assert_eq!(preview.headers["apns-push-type"], "alert");
+        assert_eq!(preview.headers["apns-priority"], "10");
+        assert_eq!(preview.headers["apns-collapse-id"], "greeting");
+        assert_eq!(preview.payload["aps"]["alert"]["title"], "Hello");
+        assert_eq!(
+            preview.size_bytes,
+            serde_json::to_vec(&preview.payload).unwrap().len()
+        );
+        assert!(preview.warnings.is_empty(), "{:?}", preview.warnings);
+    }
+
+    #[test]
+    fn test_render_warns_about_background_alerts_and_size() {
+        let req = SendRequest {
+            body: Some("x".repeat(MAX_PAYLOAD_BYTES)),
+            content_available: Some(true),
+            priority: Some(10),
+            ..Default::default()
+        };
+        let preview = render(&req, "com.example.psh");
+
+        assert_eq!(preview.headers["apns-push-type"], "background");
+        assert_eq!(preview.warnings.len(), 3, "{:?}", preview.warnings);
+        assert!(preview.warnings[0].contains("over APNs'"));
+        assert!(preview.warnings[1].starts_with("background push with alert fields"));
+
+        let silent = SendRequest {
+            content_available: Some(true),
+            ..Default::default()
+        };
+        let preview = render(&silent, "com.example.psh");
+        assert_eq!(preview.summary, "Silent background push");
+        assert!(preview.lock_screen.is_none());
+        assert!(preview.warnings.is_empty());
+    }
+}
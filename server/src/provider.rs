@@ -0,0 +1,276 @@
+use async_trait::async_trait;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{
+    apns_error::{ApnsErrorCode, SendError},
+    SendRequest,
+};
+
+/// The delivery channel a registered target belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Platform {
+    Apns,
+    WebPush,
+}
+
+impl Platform {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Platform::Apns => "apns",
+            Platform::WebPush => "webpush",
+        }
+    }
+
+    /// Reads the `devices.platform` column; rows predating it are APNs.
+    pub fn from_db(value: &str) -> Self {
+        match value {
+            "webpush" => Platform::WebPush,
+            _ => Platform::Apns,
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// One recipient of a send, as stored for its platform.
+#[derive(Debug, Clone, Copy)]
+pub struct Target<'a> {
+    pub token: &'a str,
+    pub environment: &'a str,
+}
+
+/// What a provider authenticates as, for `/health`. Never includes the key.
+#[derive(Debug, Clone, Serialize)]
+pub struct Credentials {
+    /// Pushes are recorded, not delivered (`PSH_APNS_MODE=mock`).
+    #[serde(skip_serializing_if = "crate::is_false")]
+    pub mock: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub team_id: Option<String>,
+    pub topic: String,
+}
+
+/// Provider token rejections in a row before an endpoint is reported as
+/// refusing the key. A single one is refreshed and retried, so a run of them
+/// means the key itself is revoked or wrong.
+pub const TOKEN_REJECTIONS_BEFORE_WARNING: u32 = 3;
+
+/// How one of the provider's endpoints has been answering its credentials,
+/// for `/health`.
+#[derive(Debug, Clone, Serialize)]
+pub struct EndpointStatus {
+    pub environment: &'static str,
+    /// Unix time the endpoint last accepted the provider token.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_success: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_rejection: Option<ApnsErrorCode>,
+    /// Unix time of `last_rejection`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_rejected_at: Option<u64>,
+    /// Rejections of the provider token since it was last accepted.
+    pub consecutive_rejections: u32,
+    /// Requests a second sends are slowed to after `TooManyRequests`,
+    /// while below the configured pace.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slowed_to: Option<u32>,
+    /// Whether sends to the endpoint are failing fast after it kept failing.
+    pub circuit_open: bool,
+}
+
+impl EndpointStatus {
+    pub fn new(environment: &'static str) -> Self {
+        Self {
+            environment,
+            last_success: None,
+            last_rejection: None,
+            last_rejected_at: None,
+            consecutive_rejections: 0,
+            slowed_to: None,
+            circuit_open: false,
+        }
+    }
+
+    pub fn is_rejecting(&self) -> bool {
+        self.consecutive_rejections >= TOKEN_REJECTIONS_BEFORE_WARNING
+    }
+
+    /// What to tell an admin when the endpoint keeps refusing the key, or
+    /// its circuit is open.
+    pub fn warning(&self) -> Option<String> {
+        if !self.is_rejecting() {
+            return self.circuit_open.then(|| {
+                format!(
+                    "APNs {} circuit open: requests keep failing, so sends fail fast until a probe gets through",
+                    self.environment
+                )
+            });
+        }
+        let reason = self
+            .last_rejection
+            .map(|code| format!(" ({code})"))
+            .unwrap_or_default();
+        Some(format!(
+            "APNs {} rejected the provider token {} times in a row{reason}. Check the key hasn't been revoked and APNS_KEY_ID and APNS_TEAM_ID match it",
+            self.environment, self.consecutive_rejections
+        ))
+    }
+}
+
+/// The provider's id for an accepted notification, or why it was rejected.
+pub type DeliveryResult = Result<String, SendError>;
+
+#[async_trait]
+pub trait Provider: Send + Sync {
+    async fn send(&self, req: &SendRequest, target: Target<'_>) -> DeliveryResult;
+
+    /// Whether the provider's credentials are usable, for `/health`.
+    fn check_credentials(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Age of the signed token the provider authenticates with, if any.
+    fn token_age(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Sends in a row that couldn't reach the provider, if it tracks them.
+    fn consecutive_failures(&self) -> Option<u32> {
+        None
+    }
+    /// The identity the provider sends as, if it has one.
+    fn credentials(&self) -> Option<Credentials> {
+        None
+    }
+
+    /// How each endpoint has been answering the credentials, if tracked.
+    fn endpoint_statuses(&self) -> Vec<EndpointStatus> {
+        Vec::new()
+    }
+}
+
+/// Lets a provider that runs background tasks be shared with them.
+#[async_trait]
+impl<P: Provider + ?Sized> Provider for Arc<P> {
+    async fn send(&self, req: &SendRequest, target: Target<'_>) -> DeliveryResult {
+        (**self).send(req, target).await
+    }
+
+    fn check_credentials(&self) -> Result<(), String> {
+        (**self).check_credentials()
+    }
+
+    fn token_age(&self) -> Option<Duration> {
+        (**self).token_age()
+    }
+
+    fn consecutive_failures(&self) -> Option<u32> {
+        (**self).consecutive_failures()
+    }
+    fn credentials(&self) -> Option<Credentials> {
+        (**self).credentials()
+    }
+
+    fn endpoint_statuses(&self) -> Vec<EndpointStatus> {
+        (**self).endpoint_statuses()
+    }
+}
+
+/// Providers keyed by the platform they deliver to.
+#[derive(Default)]
+pub struct ProviderRegistry {
+    providers: HashMap<Platform, Box<dyn Provider>>,
+}
+
+impl ProviderRegistry {
+    pub fn register(&mut self, platform: Platform, provider: impl Provider + 'static) {
+        self.providers.insert(platform, Box::new(provider));
+    }
+
+    pub fn get(&self, platform: Platform) -> Option<&dyn Provider> {
+        self.providers
+            .get(&platform)
+            .map(|provider| provider.as_ref())
+    }
+
+    pub async fn send(
+        &self,
+        platform: Platform,
+        req: &SendRequest,
+        target: Target<'_>,
+    ) -> DeliveryResult {
+        match self.get(platform) {
+            Some(provider) => provider.send(req, target).await,
+            None => Err(SendError {
+                code: ApnsErrorCode::ProviderUnavailable,
+                message: format!("No provider configured for {platform}"),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct EchoProvider;
+
+    #[async_trait]
+    impl Provider for EchoProvider {
+        async fn send(&self, _req: &SendRequest, target: Target<'_>) -> DeliveryResult {
+            Ok(format!("echo-{}", target.token))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_registry_dispatches_by_platform() {
+        let target = Target {
+            token: "abc",
+            environment: "sandbox",
+        };
+        let req = SendRequest::default();
+
+        let registry = ProviderRegistry::default();
+        let error = registry
+            .send(Platform::Apns, &req, target)
+            .await
+            .unwrap_err();
+        assert_eq!(error.code, ApnsErrorCode::ProviderUnavailable);
+
+        let mut registry = ProviderRegistry::default();
+        registry.register(Platform::Apns, EchoProvider);
+        assert_eq!(
+            registry.send(Platform::Apns, &req, target).await.unwrap(),
+            "echo-abc"
+        );
+        assert!(registry
+            .get(Platform::Apns)
+            .unwrap()
+            .check_credentials()
+            .is_ok());
+    }
+
+    #[test]
+    fn test_endpoint_warns_after_repeated_rejections() {
+        let mut status = EndpointStatus::new("production");
+        status.last_rejection = Some(ApnsErrorCode::InvalidProviderToken);
+        status.consecutive_rejections = TOKEN_REJECTIONS_BEFORE_WARNING - 1;
+        assert!(status.warning().is_none());
+
+        status.consecutive_rejections += 1;
+        let warning = status.warning().unwrap();
+        assert!(warning.starts_with(
+            "APNs production rejected the provider token 3 times in a row (InvalidProviderToken)"
+        ));
+    }
+}
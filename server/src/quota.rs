@@ -0,0 +1,291 @@
+use axum::{
+    extract::State,
+    http::{header::RETRY_AFTER, HeaderName, HeaderValue, StatusCode},
+    response::Response,
+    Extension, Json,
+};
+use seekwel::{connection::Connection, error::Error as SeekwelError, rusqlite::params};
+use serde::Serialize;
+
+use crate::{auth::Caller, config::Role, AppState, Database, ErrorResponse};
+
+const DAILY_REMAINING: HeaderName = HeaderName::from_static("x-quota-daily-remaining");
+const MONTHLY_REMAINING: HeaderName = HeaderName::from_static("x-quota-monthly-remaining");
+
+/// Pushes a key has sent in the current UTC day and month.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Usage {
+    today: u64,
+    this_month: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct KeyUsage {
+    name: String,
+    role: Role,
+    today: u64,
+    this_month: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    daily_quota: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    daily_remaining: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    monthly_quota: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    monthly_remaining: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UsageResponse {
+    keys: Vec<KeyUsage>,
+}
+
+impl Database {
+    pub(crate) fn create_api_key_usage_table(conn: &Connection) -> Result<(), SeekwelError> {
+        conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS api_key_usage (
+                api_key TEXT NOT NULL,
+                day TEXT NOT NULL,
+                pushes INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (api_key, day)
+            )
+            "#,
+            (),
+        )?;
+        Ok(())
+    }
+
+    fn api_key_usage(api_key: &str) -> Result<Usage, SeekwelError> {
+        Connection::get()?.query_row(
+            r#"
+            SELECT
+                COALESCE(SUM(CASE WHEN day = date('now') THEN pushes END), 0),
+                COALESCE(SUM(pushes), 0)
+            FROM api_key_usage
+            WHERE api_key = ?1 AND day >= date('now', 'start of month')
+            "#,
+            params![api_key],
+            |row| {
+                Ok(Usage {
+                    today: row.get::<_, i64>(0)? as u64,
+                    this_month: row.get::<_, i64>(1)? as u64,
+                })
+            },
+        )
+    }
+
+    fn add_api_key_usage(api_key: &str, pushes: u64) -> Result<(), SeekwelError> {
+        Connection::get()?.execute(
+            r#"
+            INSERT INTO api_key_usage (api_key, day, pushes) VALUES (?1, date('now'), ?2)
+            ON CONFLICT(api_key, day) DO UPDATE SET pushes = pushes + excluded.pushes
+            "#,
+            params![api_key, pushes as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Seconds until the next UTC day and month begin.
+    fn seconds_until_resets() -> Result<(u64, u64), SeekwelError> {
+        Connection::get()?.query_row(
+            r#"
+            SELECT
+                strftime('%s', date('now', '+1 day')) - strftime('%s', 'now'),
+                strftime('%s', date('now', 'start of month', '+1 month')) - strftime('%s', 'now')
+            "#,
+            (),
+            |row| Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64)),
+        )
+    }
+}
+
+fn remaining(quota: Option<u64>, used: u64) -> Option<u64> {
+    quota.map(|quota| quota.saturating_sub(used))
+}
+
+/// The quota a send of `pushes` more would exceed, as an error message.
+fn exceeded(caller: &Caller, usage: Usage, pushes: u64) -> Option<String> {
+    [
+        ("daily", caller.daily_quota, usage.today),
+        ("monthly", caller.monthly_quota, usage.this_month),
+    ]
+    .into_iter()
+    .find_map(|(window, quota, used)| {
+        let quota = quota?;
+        (used + pushes > quota).then(|| {
+            format!(
+                "API key '{}' has used {used} of its {window} quota of {quota} pushes; this send targets {pushes}",
+                caller.name
+            )
+        })
+    })
+}
+
+fn database_error(e: SeekwelError) -> (StatusCode, Json<ErrorResponse>) {
+    tracing::error!(error = %e, "Database error handling API key usage");
+    ErrorResponse::with_status(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Database error: {e}"),
+    )
+}
+
+/// Refuses a send of `pushes` with a 429 when it would take the caller over
+/// a quota. Nothing is counted until `record`.
+pub(crate) fn check(
+    caller: &Caller,
+    pushes: usize,
+) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+    if !caller.has_quota() {
+        return Ok(());
+    }
+    let pushes = pushes as u64;
+    let usage = Database::api_key_usage(&caller.name).map_err(database_error)?;
+    match exceeded(caller, usage, pushes) {
+        Some(message) => {
+            tracing::warn!(api_key = %caller.name, pushes = pushes, "Refusing send over quota");
+            Err(ErrorResponse::with_status(
+                StatusCode::TOO_MANY_REQUESTS,
+                message,
+            ))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Counts `pushes` that went out against the caller's usage, logging rather
+/// than failing a send that has already happened.
+pub(crate) fn record(caller: &Caller, pushes: usize) {
+    if pushes == 0 {
+        return;
+    }
+    if let Err(e) = Database::add_api_key_usage(&caller.name, pushes as u64) {
+        tracing::error!(api_key = %caller.name, pushes = pushes, error = %e, "Failed to record API key usage");
+    }
+}
+
+/// Adds the caller's remaining quotas to a response and, when it was refused
+/// for being over quota, `Retry-After` for when the tighter quota resets.
+pub(crate) fn annotate(caller: &Caller, response: &mut Response) {
+    let usage = match Database::api_key_usage(&caller.name) {
+        Ok(usage) => usage,
+        Err(e) => {
+            tracing::error!(api_key = %caller.name, error = %e, "Failed to read API key usage");
+            return;
+        }
+    };
+    let daily = remaining(caller.daily_quota, usage.today);
+    let monthly = remaining(caller.monthly_quota, usage.this_month);
+    let headers = response.headers_mut();
+    for (name, remaining) in [(DAILY_REMAINING, daily), (MONTHLY_REMAINING, monthly)] {
+        if let Some(remaining) = remaining {
+            headers.insert(name, HeaderValue::from(remaining));
+        }
+    }
+
+    if response.status() == StatusCode::TOO_MANY_REQUESTS {
+        match Database::seconds_until_resets() {
+            Ok((day, month)) => {
+                let monthly_binds = monthly.is_some_and(|m| daily.is_none_or(|d| m <= d));
+                let retry_after = if monthly_binds { month } else { day };
+                response
+                    .headers_mut()
+                    .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+            }
+            Err(e) => tracing::error!(error = %e, "Failed to compute quota reset"),
+        }
+    }
+}
+
+/// `GET /usage`: pushes sent today and this month per API key, with what's
+/// left of their quotas. A `send` key sees only itself.
+pub async fn get_usage(
+    State(state): State<AppState>,
+    caller: Option<Extension<Caller>>,
+) -> Result<Json<UsageResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let callers: Vec<&Caller> = state
+        .api_keys
+        .callers()
+        .filter(|key| match &caller {
+            Some(Extension(caller)) if caller.role == Role::Send => key.name == caller.name,
+            _ => true,
+        })
+        .collect();
+
+    let mut keys = Vec::with_capacity(callers.len());
+    for key in callers {
+        let usage = Database::api_key_usage(&key.name).map_err(database_error)?;
+        keys.push(KeyUsage {
+            name: key.name.clone(),
+            role: key.role,
+            today: usage.today,
+            this_month: usage.this_month,
+            daily_quota: key.daily_quota,
+            daily_remaining: remaining(key.daily_quota, usage.today),
+            monthly_quota: key.monthly_quota,
+            monthly_remaining: remaining(key.monthly_quota, usage.this_month),
+        });
+    }
+    Ok(Json(UsageResponse { keys }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_db;
+
+    fn caller(daily: Option<u64>, monthly: Option<u64>) -> Caller {
+        Caller {
+            name: "ci".to_string(),
+            role: Role::Send,
+            daily_quota: daily,
+            monthly_quota: monthly,
+            topic: None,
+        }
+    }
+
+    #[test]
+    fn test_exceeded() {
+        let usage = Usage {
+            today: 8,
+            this_month: 95,
+        };
+        assert!(exceeded(&caller(None, None), usage, 1000).is_none());
+        assert!(exceeded(&caller(Some(10), None), usage, 2).is_none());
+        assert_eq!(
+            exceeded(&caller(Some(10), None), usage, 3).unwrap(),
+            "API key 'ci' has used 8 of its daily quota of 10 pushes; this send targets 3"
+        );
+        assert!(exceeded(&caller(Some(10), Some(100)), usage, 6)
+            .unwrap()
+            .contains("daily"));
+        assert!(exceeded(&caller(Some(50), Some(100)), usage, 6)
+            .unwrap()
+            .contains("monthly quota of 100"));
+    }
+
+    #[test]
+    fn test_check_and_record() {
+        let _db = test_db();
+        let ci = caller(Some(5), None);
+        check(&ci, 5).unwrap();
+        record(&ci, 3);
+        check(&ci, 2).unwrap();
+        record(&ci, 2);
+        let (status, _) = check(&ci, 1).unwrap_err();
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            Database::api_key_usage("ci").unwrap(),
+            Usage {
+                today: 5,
+                this_month: 5
+            }
+        );
+
+        // Keys without a quota are counted but never refused.
+        check(&caller(None, None), 100).unwrap();
+        record(&caller(None, None), 100);
+        let (day, month) = Database::seconds_until_resets().unwrap();
+        assert!(day > 0 && day <= 86_400 && month >= day);
+    }
+}
@@ -0,0 +1,237 @@
+use std::io;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+/// Where a Redis server is: `redis://[user[:password]@]host[:port][/db]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RedisUrl {
+    host: String,
+    port: u16,
+    username: Option<String>,
+    password: Option<String>,
+    db: u32,
+}
+
+impl RedisUrl {
+    pub(crate) fn parse(url: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid Redis URL '{url}', expected redis://host:port/db");
+        if url.starts_with("rediss://") {
+            return Err("TLS Redis URLs (rediss://) are not supported".to_string());
+        }
+        let rest = url.strip_prefix("redis://").ok_or_else(invalid)?;
+        let (authority, db) = match rest.split_once('/') {
+            Some((authority, "")) => (authority, 0),
+            Some((authority, db)) => (authority, db.parse().map_err(|_| invalid())?),
+            None => (rest, 0),
+        };
+        let (credentials, address) = match authority.rsplit_once('@') {
+            Some((credentials, address)) => (Some(credentials), address),
+            None => (None, authority),
+        };
+        let (username, password) = match credentials.map(|c| c.split_once(':')) {
+            Some(Some((user, password))) => (
+                (!user.is_empty()).then(|| user.to_string()),
+                Some(password.to_string()),
+            ),
+            Some(None) => (None, credentials.map(str::to_string)),
+            None => (None, None),
+        };
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+            None => (address, 6379),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            username,
+            password,
+            db,
+        })
+    }
+}
+
+/// A Redis reply.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Reply {
+    Status(String),
+    Integer(i64),
+    /// `None` for a nil bulk string.
+    Bulk(Option<Vec<u8>>),
+    /// `None` for a nil array, which is how a blocking pop says it timed out.
+    Array(Option<Vec<Reply>>),
+}
+
+/// One connection speaking just enough RESP for the job queue.
+pub(crate) struct Connection {
+    stream: BufReader<TcpStream>,
+}
+
+impl Connection {
+    /// Connects, authenticates and selects the database `url` names.
+    pub(crate) async fn open(url: &RedisUrl) -> io::Result<Self> {
+        let stream = TcpStream::connect((url.host.as_str(), url.port)).await?;
+        let mut conn = Self {
+            stream: BufReader::new(stream),
+        };
+        if let Some(password) = &url.password {
+            match &url.username {
+                Some(user) => conn.command(&["AUTH", user, password]).await?,
+                None => conn.command(&["AUTH", password]).await?,
+            };
+        }
+        if url.db != 0 {
+            conn.command(&["SELECT", &url.db.to_string()]).await?;
+        }
+        Ok(conn)
+    }
+
+    /// Sends one command and reads its reply; an error reply is an `Err`.
+    pub(crate) async fn command<A: AsRef<[u8]>>(&mut self, args: &[A]) -> io::Result<Reply> {
+        self.stream.get_mut().write_all(&encode(args)).await?;
+        read_reply(&mut self.stream).await
+    }
+}
+
+fn encode<A: AsRef<[u8]>>(args: &[A]) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        let arg = arg.as_ref();
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg);
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+async fn read_line<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> io::Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Redis closed the connection",
+        ));
+    }
+    Ok(line.trim_end_matches("\r\n").to_string())
+}
+
+fn parse_length(value: &str) -> io::Result<i64> {
+    value.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Bad Redis length '{value}'"),
+        )
+    })
+}
+
+async fn read_reply<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> io::Result<Reply> {
+    // Arrays nest, so read them with a stack of (items, expected) instead
+    // of recursing in an async fn.
+    let mut stack: Vec<(Vec<Reply>, usize)> = Vec::new();
+    loop {
+        let line = read_line(reader).await?;
+        let (kind, value) = line.split_at(line.len().min(1));
+        let mut reply = match kind {
+            "+" => Reply::Status(value.to_string()),
+            "-" => return Err(io::Error::other(format!("Redis error: {value}"))),
+            ":" => Reply::Integer(parse_length(value)?),
+            "$" => match parse_length(value)? {
+                len if len < 0 => Reply::Bulk(None),
+                len => {
+                    let mut bytes = vec![0; len as usize + 2];
+                    reader.read_exact(&mut bytes).await?;
+                    bytes.truncate(len as usize);
+                    Reply::Bulk(Some(bytes))
+                }
+            },
+            "*" => match parse_length(value)? {
+                len if len < 0 => Reply::Array(None),
+                0 => Reply::Array(Some(Vec::new())),
+                len => {
+                    stack.push((Vec::with_capacity(len as usize), len as usize));
+                    continue;
+                }
+            },
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unexpected Redis reply '{line}'"),
+                ))
+            }
+        };
+        loop {
+            let Some((items, expected)) = stack.last_mut() else {
+                return Ok(reply);
+            };
+            items.push(reply);
+            if items.len() < *expected {
+                break;
+            }
+            let (items, _) = stack.pop().expect("checked above");
+            reply = Reply::Array(Some(items));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn parse(bytes: &[u8]) -> io::Result<Reply> {
+        read_reply(&mut BufReader::new(bytes)).await
+    }
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            RedisUrl::parse("redis://:secret@cache:6380/2").unwrap(),
+            RedisUrl {
+                host: "cache".to_string(),
+                port: 6380,
+                username: None,
+                password: Some("secret".to_string()),
+                db: 2,
+            }
+        );
+        let url = RedisUrl::parse("redis://psh:pw@localhost").unwrap();
+        assert_eq!((url.port, url.db), (6379, 0));
+        assert_eq!(url.username.as_deref(), Some("psh"));
+        for invalid in ["localhost:6379", "redis://", "redis://h:port", "rediss://h"] {
+            assert!(RedisUrl::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(
+            encode(&["LPUSH", "jobs", "a b"]),
+            b"*3\r\n$5\r\nLPUSH\r\n$4\r\njobs\r\n$3\r\na b\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_reply() {
+        assert_eq!(parse(b"+OK\r\n").await.unwrap(), Reply::Status("OK".into()));
+        assert_eq!(parse(b":3\r\n").await.unwrap(), Reply::Integer(3));
+        assert_eq!(parse(b"$-1\r\n").await.unwrap(), Reply::Bulk(None));
+        assert_eq!(parse(b"*-1\r\n").await.unwrap(), Reply::Array(None));
+        assert_eq!(
+            parse(b"*2\r\n$4\r\njobs\r\n*2\r\n$4\r\na\r\nb\r\n:1\r\n")
+                .await
+                .unwrap(),
+            Reply::Array(Some(vec![
+                Reply::Bulk(Some(b"jobs".to_vec())),
+                Reply::Array(Some(vec![
+                    Reply::Bulk(Some(b"a\r\nb".to_vec())),
+                    Reply::Integer(1)
+                ])),
+            ]))
+        );
+        assert!(parse(b"-ERR wrong\r\n").await.is_err());
+        assert!(parse(b"*2\r\n:1\r\n").await.is_err());
+    }
+}
@@ -0,0 +1,86 @@
+use aes_gcm::aead::{rand_core::RngCore, OsRng};
+use axum::{
+    body::{to_bytes, Body},
+    extract::Request,
+    http::{
+        header::{CONTENT_LENGTH, CONTENT_TYPE},
+        Extensions, HeaderValue,
+    },
+    middleware::Next,
+    response::Response,
+};
+use serde_json::Value;
+use tower_http::request_id::{MakeRequestId, RequestId};
+
+/// Gives requests that arrive without an `x-request-id` a random one.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct MakeHexRequestId;
+
+impl MakeRequestId for MakeHexRequestId {
+    fn make_request_id<B>(&mut self, _request: &Request<B>) -> Option<RequestId> {
+        HeaderValue::from_str(&new_request_id())
+            .ok()
+            .map(RequestId::new)
+    }
+}
+
+pub(crate) fn new_request_id() -> String {
+    let mut bytes = [0u8; 8];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// The request's id, as set by `SetRequestIdLayer`.
+pub(crate) fn from_extensions(extensions: &Extensions) -> Option<String> {
+    extensions
+        .get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .map(str::to_string)
+}
+
+/// Adds `request_id` to JSON error bodies, so an error a client reports can
+/// be found in the server's logs.
+pub(crate) async fn tag_errors(request: Request, next: Next) -> Response {
+    let request_id = from_extensions(request.extensions());
+    let response = next.run(request).await;
+
+    let status = response.status();
+    let is_json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(b"application/json"));
+    let Some(request_id) =
+        request_id.filter(|_| is_json && (status.is_client_error() || status.is_server_error()))
+    else {
+        return response;
+    };
+
+    let (mut parts, body) = response.into_parts();
+    parts.headers.remove(CONTENT_LENGTH);
+    let Ok(bytes) = to_bytes(body, usize::MAX).await else {
+        return Response::from_parts(parts, Body::empty());
+    };
+    let body = match serde_json::from_slice::<Value>(&bytes) {
+        Ok(Value::Object(mut error)) => {
+            error
+                .entry("request_id")
+                .or_insert(Value::String(request_id));
+            Body::from(Value::Object(error).to_string())
+        }
+        _ => Body::from(bytes),
+    };
+    Response::from_parts(parts, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_ids_are_distinct_hex() {
+        let id = new_request_id();
+        assert_eq!(id.len(), 16);
+        assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(id, new_request_id());
+    }
+}
@@ -0,0 +1,232 @@
+use seekwel::{connection::Connection, error::Error as SeekwelError, rusqlite::params};
+use std::{env, time::Duration};
+
+use crate::{duration, leases, Database};
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Reads `PSH_RETENTION_INTERVAL` (e.g. `1h`), how often expired
+/// registrations are purged. `0` turns the task off.
+pub fn interval_from_env() -> Result<Option<Duration>, String> {
+    match env::var("PSH_RETENTION_INTERVAL") {
+        Ok(value) => parse_interval(&value),
+        Err(_) => Ok(Some(DEFAULT_INTERVAL)),
+    }
+}
+
+fn parse_interval(value: &str) -> Result<Option<Duration>, String> {
+    if value.trim().is_empty() {
+        return Ok(Some(DEFAULT_INTERVAL));
+    }
+    match duration::parse_duration(value) {
+        Some(interval) if interval.is_zero() => Ok(None),
+        Some(interval) => Ok(Some(interval)),
+        None => Err(format!(
+            "Invalid PSH_RETENTION_INTERVAL '{value}', expected a duration such as 1h"
+        )),
+    }
+}
+
+/// Reads `PSH_ANONYMIZE_AFTER` (e.g. `90d`), the age at which pushes lose
+/// their content. Unset or `0` keeps it for as long as the push is kept.
+pub fn anonymize_after_from_env() -> Result<Option<Duration>, String> {
+    match env::var("PSH_ANONYMIZE_AFTER") {
+        Ok(value) => parse_anonymize_after(&value),
+        Err(_) => Ok(None),
+    }
+}
+
+fn parse_anonymize_after(value: &str) -> Result<Option<Duration>, String> {
+    if value.trim().is_empty() {
+        return Ok(None);
+    }
+    match duration::parse_duration(value) {
+        Some(age) if age.is_zero() => Ok(None),
+        Some(age) => Ok(Some(age)),
+        None => Err(format!(
+            "Invalid PSH_ANONYMIZE_AFTER '{value}', expected a duration such as 90d"
+        )),
+    }
+}
+
+impl Database {
+    /// Clears the title, body, payload and actions of pushes sent more than
+    /// `age` ago, and returns how many there were. The rows stay, so stats
+    /// and open rates still count them.
+    pub(crate) fn anonymize_pushes(age: Duration) -> Result<i64, SeekwelError> {
+        const OLD_WITH_CONTENT: &str = "sent_at <= datetime('now', '-' || ?1 || ' seconds') \
+            AND (title IS NOT NULL OR body IS NOT NULL OR payload IS NOT NULL \
+            OR full_body IS NOT NULL OR actions IS NOT NULL OR content_hash IS NOT NULL \
+            OR collapse_id IS NOT NULL)";
+        let seconds = age.as_secs() as i64;
+        let conn = Connection::get()?;
+        Connection::transaction(|| {
+            let old: i64 = conn.query_row(
+                &format!("SELECT COUNT(*) FROM pushes WHERE {OLD_WITH_CONTENT}"),
+                params![seconds],
+                |row| row.get(0),
+            )?;
+            if old > 0 {
+                conn.execute(
+                    &format!(
+                        "UPDATE pushes SET title = NULL, body = NULL, payload = NULL, \
+                         full_body = NULL, actions = NULL, content_hash = NULL, \
+                         collapse_id = NULL \
+                         WHERE {OLD_WITH_CONTENT}"
+                    ),
+                    params![seconds],
+                )?;
+            }
+            Ok(old)
+        })
+    }
+
+    /// Deletes registrations whose `expires_in` has run out, along with
+    /// their history, and returns how many there were.
+    pub(crate) fn purge_expired_devices() -> Result<i64, SeekwelError> {
+        let conn = Connection::get()?;
+        Connection::transaction(|| {
+            let expired: i64 = conn.query_row(
+                "SELECT COUNT(*) FROM devices WHERE expires_at <= CURRENT_TIMESTAMP",
+                (),
+                |row| row.get(0),
+            )?;
+            if expired > 0 {
+                conn.execute(
+                    "DELETE FROM devices WHERE expires_at <= CURRENT_TIMESTAMP",
+                    (),
+                )?;
+            }
+            Ok(expired)
+        })
+    }
+}
+
+fn purge(anonymize_after: Option<Duration>) {
+    match Database::purge_expired_devices() {
+        Ok(0) => tracing::debug!("No expired registrations to purge"),
+        Ok(purged) => tracing::info!(purged = purged, "Purged expired registrations"),
+        Err(e) => tracing::error!(error = %e, "Failed to purge expired registrations"),
+    }
+    let Some(age) = anonymize_after else {
+        return;
+    };
+    match Database::anonymize_pushes(age) {
+        Ok(0) => tracing::debug!("No old pushes to anonymize"),
+        Ok(anonymized) => tracing::info!(anonymized = anonymized, "Anonymized old pushes"),
+        Err(e) => tracing::error!(error = %e, "Failed to anonymize old pushes"),
+    }
+}
+
+/// Purges expired data every `interval`, starting now, on whichever
+/// instance holds the retention lease, and clears the content of pushes
+/// older than `anonymize_after`.
+pub fn spawn(interval: Duration, anonymize_after: Option<Duration>) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            // Held across two runs, so it changes hands only when its
+            // holder stops.
+            if leases::is_leader("retention", interval * 2) {
+                purge(anonymize_after);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_db;
+
+    #[test]
+    fn test_parse_interval() {
+        assert_eq!(parse_interval("").unwrap(), Some(DEFAULT_INTERVAL));
+        assert_eq!(
+            parse_interval("10m").unwrap(),
+            Some(Duration::from_secs(600))
+        );
+        assert_eq!(parse_interval("0").unwrap(), None);
+        assert!(parse_interval("soon").is_err());
+        assert_eq!(
+            parse_anonymize_after("90d").unwrap(),
+            Some(Duration::from_secs(90 * 24 * 60 * 60))
+        );
+        assert_eq!(parse_anonymize_after("0").unwrap(), None);
+        assert!(parse_anonymize_after("a while").is_err());
+    }
+
+    #[test]
+    fn test_anonymize_pushes() {
+        let _db = test_db();
+        let conn = Connection::get().unwrap();
+        conn.execute(
+            "INSERT INTO devices (device_token, installation_id, environment) VALUES ('a', 'i', 'sandbox')",
+            (),
+        )
+        .unwrap();
+        conn.execute(
+            r#"
+            INSERT INTO pushes (device_id, title, body, payload, status, opened_at, sent_at) VALUES
+                (1, 'Old', 'secret', '{}', 'sent', datetime('now', '-9 days'), datetime('now', '-10 days')),
+                (1, 'New', 'recent', '{}', 'sent', NULL, datetime('now', '-1 day'))
+            "#,
+            (),
+        )
+        .unwrap();
+
+        let week = Duration::from_secs(7 * 24 * 60 * 60);
+        assert_eq!(Database::anonymize_pushes(week).unwrap(), 1);
+        assert_eq!(Database::anonymize_pushes(week).unwrap(), 0);
+        let rows: Vec<(Option<String>, Option<String>, bool)> = conn
+            .query_all(
+                "SELECT title, body, opened_at IS NOT NULL FROM pushes ORDER BY id",
+                (),
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(
+            rows,
+            [
+                (None, None, true),
+                (Some("New".to_string()), Some("recent".to_string()), false)
+            ]
+        );
+        assert_eq!(Database::stats().unwrap().total_pushes, 2);
+    }
+
+    #[test]
+    fn test_purge_expired_devices() {
+        let _db = test_db();
+        let conn = Connection::get().unwrap();
+        conn.execute(
+            r#"
+            INSERT INTO devices (device_token, installation_id, environment, expires_at) VALUES
+                ('expired', 'i1', 'sandbox', datetime('now', '-1 minute')),
+                ('later', 'i2', 'sandbox', datetime('now', '+1 hour')),
+                ('forever', 'i3', 'sandbox', NULL)
+            "#,
+            (),
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO pushes (device_id, status) VALUES (1, 'sent'), (2, 'sent')",
+            (),
+        )
+        .unwrap();
+
+        let targets = Database::delivery_targets(None).unwrap();
+        assert_eq!(targets.len(), 2);
+        assert!(targets.iter().all(|t| t.device_token != "expired"));
+
+        assert_eq!(Database::purge_expired_devices().unwrap(), 1);
+        assert!(Database::device_id("expired").unwrap().is_none());
+        let pushes: i64 = conn
+            .query_row("SELECT COUNT(*) FROM pushes", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(pushes, 1);
+        assert_eq!(Database::purge_expired_devices().unwrap(), 0);
+    }
+}
@@ -0,0 +1,260 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use seekwel::{
+    connection::Connection,
+    error::Error as SeekwelError,
+    rusqlite::{self, params, types::Type},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audit::{self, AuditContext},
+    filter::DeviceFilter,
+    AppState, Database, ErrorResponse,
+};
+
+/// A named, reusable device filter.
+#[derive(Debug, Serialize)]
+pub struct Segment {
+    pub name: String,
+    pub filter: DeviceFilter,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SegmentsResponse {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateSegmentRequest {
+    name: String,
+    filter: DeviceFilter,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateSegmentRequest {
+    filter: DeviceFilter,
+}
+
+impl Database {
+    pub(crate) fn create_segments_table(conn: &Connection) -> Result<(), SeekwelError> {
+        conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS segments (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                name TEXT NOT NULL UNIQUE,
+                filter TEXT NOT NULL,
+                created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            )
+            "#,
+            (),
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn segments() -> Result<Vec<Segment>, SeekwelError> {
+        Connection::get()?.query_all(
+            "SELECT name, filter, created_at, updated_at FROM segments ORDER BY name",
+            (),
+            segment_from_row,
+        )
+    }
+
+    pub(crate) fn segment(name: &str) -> Result<Option<Segment>, SeekwelError> {
+        Connection::get()?.query_optional(
+            "SELECT name, filter, created_at, updated_at FROM segments WHERE name = ?1",
+            params![name],
+            segment_from_row,
+        )
+    }
+
+    fn upsert_segment(name: &str, filter: &DeviceFilter) -> Result<(), SeekwelError> {
+        let filter_json = serde_json::to_string(filter).unwrap_or_else(|_| "{}".to_string());
+        Connection::get()?.execute(
+            r#"
+            INSERT INTO segments (name, filter, updated_at)
+            VALUES (?1, ?2, CURRENT_TIMESTAMP)
+            ON CONFLICT(name) DO UPDATE SET
+                filter = excluded.filter,
+                updated_at = CURRENT_TIMESTAMP
+            "#,
+            params![name, filter_json],
+        )?;
+        Ok(())
+    }
+
+    fn delete_segment(name: &str) -> Result<(), SeekwelError> {
+        Connection::get()?.execute("DELETE FROM segments WHERE name = ?1", params![name])?;
+        Ok(())
+    }
+}
+
+fn segment_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Segment> {
+    let filter_json: String = row.get(1)?;
+    let filter = serde_json::from_str(&filter_json)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(1, Type::Text, Box::new(e)))?;
+    Ok(Segment {
+        name: row.get(0)?,
+        filter,
+        created_at: row.get(2)?,
+        updated_at: row.get(3)?,
+    })
+}
+
+fn is_valid_segment_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+fn database_error(e: SeekwelError) -> (StatusCode, Json<ErrorResponse>) {
+    tracing::error!(error = %e, "Database error handling segment");
+    ErrorResponse::with_status(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Database error: {e}"),
+    )
+}
+
+fn segment_not_found(name: &str) -> (StatusCode, Json<ErrorResponse>) {
+    ErrorResponse::with_status(StatusCode::NOT_FOUND, format!("Segment not found: {name}"))
+}
+
+pub async fn list_segments(
+    State(_state): State<AppState>,
+) -> Result<Json<SegmentsResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let segments = Database::segments().map_err(database_error)?;
+    Ok(Json(SegmentsResponse { segments }))
+}
+
+pub async fn get_segment(
+    State(_state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<Segment>, (StatusCode, Json<ErrorResponse>)> {
+    match Database::segment(&name).map_err(database_error)? {
+        Some(segment) => Ok(Json(segment)),
+        None => Err(segment_not_found(&name)),
+    }
+}
+
+pub async fn create_segment(
+    State(_state): State<AppState>,
+    audit: AuditContext,
+    Json(req): Json<CreateSegmentRequest>,
+) -> Result<(StatusCode, Json<Segment>), (StatusCode, Json<ErrorResponse>)> {
+    if !is_valid_segment_name(&req.name) {
+        return Err(ErrorResponse::with_status(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Segment names must be 1-64 characters of letters, digits, '-', '_' or '.'",
+        ));
+    }
+    if Database::segment(&req.name)
+        .map_err(database_error)?
+        .is_some()
+    {
+        return Err(ErrorResponse::with_status(
+            StatusCode::CONFLICT,
+            format!("Segment already exists: {}", req.name),
+        ));
+    }
+
+    tracing::info!(segment = %req.name, filter = ?req.filter, "Creating segment");
+    Database::upsert_segment(&req.name, &req.filter).map_err(database_error)?;
+    audit::record(&audit, "segment.create", format!("segment={}", req.name));
+    let segment = Database::segment(&req.name)
+        .map_err(database_error)?
+        .ok_or_else(|| segment_not_found(&req.name))?;
+    Ok((StatusCode::CREATED, Json(segment)))
+}
+
+pub async fn update_segment(
+    State(_state): State<AppState>,
+    audit: AuditContext,
+    Path(name): Path<String>,
+    Json(req): Json<UpdateSegmentRequest>,
+) -> Result<Json<Segment>, (StatusCode, Json<ErrorResponse>)> {
+    if !is_valid_segment_name(&name) {
+        return Err(ErrorResponse::with_status(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Segment names must be 1-64 characters of letters, digits, '-', '_' or '.'",
+        ));
+    }
+
+    tracing::info!(segment = %name, filter = ?req.filter, "Saving segment");
+    Database::upsert_segment(&name, &req.filter).map_err(database_error)?;
+    audit::record(&audit, "segment.update", format!("segment={name}"));
+    let segment = Database::segment(&name)
+        .map_err(database_error)?
+        .ok_or_else(|| segment_not_found(&name))?;
+    Ok(Json(segment))
+}
+
+pub async fn delete_segment(
+    State(_state): State<AppState>,
+    audit: AuditContext,
+    Path(name): Path<String>,
+) -> Result<StatusCode, (StatusCode, Json<ErrorResponse>)> {
+    if Database::segment(&name).map_err(database_error)?.is_none() {
+        return Err(segment_not_found(&name));
+    }
+
+    tracing::info!(segment = %name, "Deleting segment");
+    Database::delete_segment(&name).map_err(database_error)?;
+    audit::record(&audit, "segment.delete", format!("segment={name}"));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_db;
+
+    #[test]
+    fn test_segment_round_trip() {
+        let _db = test_db();
+        let filter = DeviceFilter {
+            device_type: Some("iPad".to_string()),
+            ..Default::default()
+        };
+        Database::upsert_segment("ipads", &filter).unwrap();
+
+        let segment = Database::segment("ipads").unwrap().unwrap();
+        assert_eq!(segment.filter, filter);
+
+        let updated = DeviceFilter {
+            min_os_version: Some("17.0".to_string()),
+            ..Default::default()
+        };
+        Database::upsert_segment("ipads", &updated).unwrap();
+        let segments = Database::segments().unwrap();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].filter, updated);
+
+        Database::delete_segment("ipads").unwrap();
+        assert!(Database::segment("ipads").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_segment_name_validation() {
+        assert!(is_valid_segment_name("beta-testers"));
+        assert!(is_valid_segment_name("ios_17.plus"));
+        assert!(!is_valid_segment_name(""));
+        assert!(!is_valid_segment_name("has space"));
+        assert!(!is_valid_segment_name("slash/name"));
+    }
+
+    #[test]
+    fn test_deserialize_create_segment_request() {
+        let json = r#"{"name": "ipads", "filter": {"device_type": "iPad"}}"#;
+        let req: CreateSegmentRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(req.name, "ipads");
+        assert_eq!(req.filter.device_type.as_deref(), Some("iPad"));
+    }
+}
@@ -0,0 +1,172 @@
+use aes_gcm::aead::{rand_core::RngCore, OsRng};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use sha2::Sha256;
+use std::collections::HashMap;
+
+use crate::{apps::App, SendRequest};
+
+/// Custom data key that carries the signature when signing is on.
+pub const SIGNATURE_KEY: &str = "psh_signature";
+
+/// Shortest signing key accepted, in bytes.
+const MIN_KEY_LEN: usize = 32;
+
+/// What a signature covers: the visible alert, the custom data as
+/// delivered and when it was signed. Sent verbatim as `signed`, so the app
+/// checks the HMAC over those exact bytes instead of re-encoding JSON.
+#[derive(Serialize)]
+struct Signed<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subtitle: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<&'a HashMap<String, Value>>,
+    ts: u64,
+}
+
+/// An app's signing keys: the current one, and during a rotation the
+/// previous one, so builds that only know the old key keep verifying.
+pub(crate) struct SigningKeys {
+    keys: Vec<(i64, Vec<u8>)>,
+}
+
+impl SigningKeys {
+    /// The app's keys, or `None` when signing is off.
+    pub(crate) fn for_app(app: &App) -> Result<Option<Self>, String> {
+        let Some(current) = &app.signing_key else {
+            return Ok(None);
+        };
+        let mut keys = vec![(app.signing_key_id, decode_key(current)?)];
+        if let Some(previous) = &app.previous_signing_key {
+            keys.push((app.signing_key_id - 1, decode_key(previous)?));
+        }
+        Ok(Some(Self { keys }))
+    }
+
+    /// Adds `psh_signature` to the custom data:
+    /// `{"v": 1, "signed": "<json>", "sigs": {"<key id>": "<base64 HMAC-SHA256>"}}`.
+    pub(crate) fn sign(&self, req: &mut SendRequest, now: u64) {
+        let data = req.data.as_ref().filter(|data| !data.is_empty());
+        let signed = serde_json::to_string(&Signed {
+            title: req.title.as_deref(),
+            subtitle: req.subtitle.as_deref(),
+            body: req.body.as_deref(),
+            data,
+            ts: now,
+        })
+        .expect("signed content is valid JSON");
+
+        let sigs: Map<String, Value> = self
+            .keys
+            .iter()
+            .map(|(id, key)| (id.to_string(), Value::String(hmac(key, &signed))))
+            .collect();
+        req.data.get_or_insert_with(HashMap::new).insert(
+            SIGNATURE_KEY.to_string(),
+            json!({ "v": 1, "signed": signed, "sigs": sigs }),
+        );
+    }
+}
+
+fn hmac(key: &[u8], message: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(message.as_bytes());
+    BASE64.encode(mac.finalize().into_bytes())
+}
+
+pub(crate) fn decode_key(encoded: &str) -> Result<Vec<u8>, String> {
+    let bytes = BASE64
+        .decode(encoded.trim())
+        .map_err(|e| format!("signing_key is not valid base64: {e}"))?;
+    if bytes.len() < MIN_KEY_LEN {
+        return Err(format!(
+            "signing_key must be at least {MIN_KEY_LEN} bytes, got {}",
+            bytes.len()
+        ));
+    }
+    Ok(bytes)
+}
+
+pub(crate) fn generate_key() -> String {
+    let mut key = [0u8; MIN_KEY_LEN];
+    OsRng.fill_bytes(&mut key);
+    BASE64.encode(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app(current: Option<&str>, id: i64, previous: Option<&str>) -> App {
+        App {
+            bundle_id: "com.example.app".to_string(),
+            encryption_key: None,
+            signing_key: current.map(str::to_string),
+            signing_key_id: id,
+            previous_signing_key: previous.map(str::to_string),
+            created_at: String::new(),
+            updated_at: String::new(),
+            default_ttl_seconds: None,
+        }
+    }
+
+    #[test]
+    fn test_sign_covers_alert_and_data() {
+        let key = generate_key();
+        let keys = SigningKeys::for_app(&app(Some(&key), 1, None))
+            .unwrap()
+            .unwrap();
+        let mut req = SendRequest {
+            title: Some("Login".to_string()),
+            body: Some("Approve?".to_string()),
+            data: Some(HashMap::from([("otp".to_string(), json!("123456"))])),
+            ..Default::default()
+        };
+        keys.sign(&mut req, 1_700_000_000);
+
+        let signature = &req.data.as_ref().unwrap()[SIGNATURE_KEY];
+        let signed = signature["signed"].as_str().unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(signed).unwrap(),
+            json!({"title": "Login", "body": "Approve?", "data": {"otp": "123456"}, "ts": 1_700_000_000})
+        );
+        let expected = hmac(&BASE64.decode(&key).unwrap(), signed);
+        assert_eq!(signature["sigs"], json!({ "1": expected }));
+    }
+
+    #[test]
+    fn test_rotation_signs_with_both_keys() {
+        let (old, new) = (generate_key(), generate_key());
+        let keys = SigningKeys::for_app(&app(Some(&new), 2, Some(&old)))
+            .unwrap()
+            .unwrap();
+        let mut req = SendRequest::default();
+        keys.sign(&mut req, 0);
+
+        let signature = &req.data.as_ref().unwrap()[SIGNATURE_KEY];
+        let signed = signature["signed"].as_str().unwrap();
+        assert_eq!(signed, r#"{"ts":0}"#);
+        assert_eq!(
+            signature["sigs"]["1"],
+            hmac(&BASE64.decode(&old).unwrap(), signed)
+        );
+        assert_eq!(
+            signature["sigs"]["2"],
+            hmac(&BASE64.decode(&new).unwrap(), signed)
+        );
+        assert!(SigningKeys::for_app(&app(None, 0, None)).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_decode_key_rejects_short_keys() {
+        assert!(decode_key(&BASE64.encode([0u8; 16])).is_err());
+        assert!(decode_key("not base64!").is_err());
+        assert!(decode_key(&BASE64.encode([0u8; 64])).is_ok());
+    }
+}
@@ -0,0 +1,219 @@
+use axum::{
+    body::Bytes,
+    extract::{RawQuery, State},
+    http::{header::CONTENT_TYPE, HeaderMap, StatusCode},
+    Json,
+};
+use serde::Deserialize;
+
+use crate::{audit::AuditContext, form, AppState, ErrorResponse, SendRequest, SendResponse};
+
+/// An incoming-webhook message as Slack and Mattermost take it. Fields psh
+/// has no use for (`channel`, `icon_emoji`, `blocks`, ...) are ignored.
+#[derive(Debug, Default, Deserialize)]
+struct SlackMessage {
+    text: Option<String>,
+    username: Option<String>,
+    #[serde(default)]
+    attachments: Vec<Attachment>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Attachment {
+    fallback: Option<String>,
+    pretext: Option<String>,
+    title: Option<String>,
+    text: Option<String>,
+    #[serde(default)]
+    fields: Vec<AttachmentField>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AttachmentField {
+    title: Option<String>,
+    value: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct LegacyForm {
+    payload: String,
+}
+
+fn non_empty(s: &Option<String>) -> Option<&str> {
+    s.as_deref().map(str::trim).filter(|s| !s.is_empty())
+}
+
+impl SlackMessage {
+    /// The first attachment title, or failing that the bot's username.
+    fn title(&self) -> Option<String> {
+        self.attachments
+            .iter()
+            .find_map(|a| non_empty(&a.title))
+            .or_else(|| non_empty(&self.username))
+            .map(unescape)
+    }
+
+    /// The message text followed by each attachment's pretext, text and
+    /// fields, one per line. An attachment with none of those contributes
+    /// its fallback.
+    fn body(&self) -> Option<String> {
+        let mut lines: Vec<String> = non_empty(&self.text).map(unescape).into_iter().collect();
+        for attachment in &self.attachments {
+            let before = lines.len();
+            lines.extend(non_empty(&attachment.pretext).map(unescape));
+            lines.extend(non_empty(&attachment.text).map(unescape));
+            for field in &attachment.fields {
+                match (non_empty(&field.title), non_empty(&field.value)) {
+                    (Some(title), Some(value)) => {
+                        lines.push(format!("{}: {}", unescape(title), unescape(value)))
+                    }
+                    (None, Some(value)) => lines.push(unescape(value)),
+                    _ => {}
+                }
+            }
+            if lines.len() == before {
+                lines.extend(non_empty(&attachment.fallback).map(unescape));
+            }
+        }
+        (!lines.is_empty()).then(|| lines.join("\n"))
+    }
+}
+
+/// Turns Slack's markup into plain text: `<url|label>` becomes the label,
+/// `<url>` the URL, `<!here>` `@here`, and `&amp;`, `&lt;` and `&gt;` their
+/// characters.
+fn unescape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        let Some(end) = rest[start..].find('>') else {
+            break;
+        };
+        out.push_str(&rest[..start]);
+        let inner = &rest[start + 1..start + end];
+        let (target, label) = match inner.split_once('|') {
+            Some((target, label)) => (target, Some(label)),
+            None => (inner, None),
+        };
+        match (label, target.strip_prefix('!')) {
+            (Some(label), _) => out.push_str(label),
+            (None, Some(special)) => {
+                out.push('@');
+                out.push_str(special);
+            }
+            (None, None) => out.push_str(target),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    out.push_str(rest);
+    out.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+fn invalid(e: impl std::fmt::Display) -> (StatusCode, Json<ErrorResponse>) {
+    tracing::warn!(error = %e, "Invalid Slack webhook payload");
+    ErrorResponse::with_status(StatusCode::BAD_REQUEST, format!("Invalid payload: {e}"))
+}
+
+/// Reads the JSON body, or the `payload=` form field older Slack clients
+/// post.
+fn parse_message(headers: &HeaderMap, body: &[u8]) -> Result<SlackMessage, String> {
+    let is_form = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/x-www-form-urlencoded"));
+    if is_form {
+        let form: LegacyForm = serde_urlencoded::from_bytes(body).map_err(|e| e.to_string())?;
+        serde_json::from_str(&form.payload).map_err(|e| e.to_string())
+    } else {
+        serde_json::from_slice(body).map_err(|e| e.to_string())
+    }
+}
+
+/// `POST /webhook/slack`: takes a Slack or Mattermost incoming-webhook
+/// message and sends it as a push, so alerting that already posts to Slack
+/// can post here instead. The query string sets the other send fields, as
+/// for a plain-text `/send`.
+pub async fn webhook(
+    State(state): State<AppState>,
+    audit: AuditContext,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<SendResponse>, (StatusCode, Json<ErrorResponse>)> {
+    tracing::info!(body_len = body.len(), "Received Slack webhook");
+    let message = parse_message(&headers, &body).map_err(invalid)?;
+    let mut req: SendRequest = match query.filter(|q| !q.is_empty()) {
+        Some(query) => form::parse(query.as_bytes()).map_err(|e| {
+            tracing::warn!(error = %e, "Invalid query in Slack webhook");
+            ErrorResponse::with_status(StatusCode::BAD_REQUEST, format!("Invalid query: {e}"))
+        })?,
+        None => SendRequest::default(),
+    };
+    let Some(body) = message.body() else {
+        return Err(ErrorResponse::with_status(
+            StatusCode::BAD_REQUEST,
+            "Message has no text or attachments",
+        ));
+    };
+    req.body = Some(body);
+    if req.title.is_none() {
+        req.title = message.title();
+    }
+    crate::send(state, audit, req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(json: &str) -> SlackMessage {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(
+            unescape("Deploy <https://ci.example.com/1|#1> done &amp; <!here>"),
+            "Deploy #1 done & @here"
+        );
+        assert_eq!(
+            unescape("see <https://example.com>"),
+            "see https://example.com"
+        );
+        assert_eq!(unescape("&lt;tag&gt; and a < b"), "<tag> and a < b");
+    }
+
+    #[test]
+    fn test_text_message() {
+        let msg = message(r##"{"text": "Backup done", "username": "cron", "channel": "#ops"}"##);
+        assert_eq!(msg.title().as_deref(), Some("cron"));
+        assert_eq!(msg.body().as_deref(), Some("Backup done"));
+    }
+
+    #[test]
+    fn test_attachments() {
+        let msg = message(
+            r#"{
+                "attachments": [
+                    {
+                        "fallback": "[FIRING] DiskFull",
+                        "title": "[FIRING] DiskFull",
+                        "text": "/var is 95% full",
+                        "fields": [{"title": "host", "value": "db1"}, {"title": "empty"}]
+                    },
+                    {"fallback": "second alert", "color": "danger"}
+                ]
+            }"#,
+        );
+        assert_eq!(msg.title().as_deref(), Some("[FIRING] DiskFull"));
+        assert_eq!(
+            msg.body().as_deref(),
+            Some("/var is 95% full\nhost: db1\nsecond alert")
+        );
+        assert!(message(r#"{"text": "  ", "attachments": []}"#)
+            .body()
+            .is_none());
+    }
+}
@@ -0,0 +1,525 @@
+use seekwel::{connection::Connection, error::Error as SeekwelError, rusqlite::params};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, time::Duration};
+
+use crate::{duration::parse_duration, Database};
+
+const HOUR: u64 = 60 * 60;
+const DAY: u64 = 24 * HOUR;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct StatsQuery {
+    /// Window to report on, e.g. "24h" or "7d".
+    pub since: Option<String>,
+    /// "hour" or "day"; defaults to hourly for windows up to two days.
+    pub bucket: Option<Bucket>,
+    /// Breaks push counts down by this, over `since` when given.
+    pub group_by: Option<GroupBy>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupBy {
+    /// The `apns-topic` pushes went under.
+    App,
+    /// The topic pushes were published to.
+    Topic,
+    /// The API key that sent them.
+    ApiKey,
+}
+
+impl GroupBy {
+    /// The group a push falls in; `?1` is the server's app.
+    fn column(self) -> &'static str {
+        match self {
+            GroupBy::App => "COALESCE(app, ?1)",
+            GroupBy::Topic => "topic",
+            GroupBy::ApiKey => "api_key",
+        }
+    }
+}
+
+/// Push counts for one app, topic or API key. A `null` key collects pushes
+/// without one, such as sends that weren't to a topic.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct StatsGroup {
+    pub key: Option<String>,
+    /// Current devices the group reaches: the app's registrations, or a
+    /// topic's subscribers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub devices: Option<i64>,
+    pub sent: i64,
+    pub failed: i64,
+    pub failure_rate: f64,
+    pub opened: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Bucket {
+    Hour,
+    Day,
+}
+
+impl Bucket {
+    fn format(self) -> &'static str {
+        match self {
+            Bucket::Hour => "%Y-%m-%d %H:00:00",
+            Bucket::Day => "%Y-%m-%d 00:00:00",
+        }
+    }
+
+    fn step(self) -> &'static str {
+        match self {
+            Bucket::Hour => "+1 hour",
+            Bucket::Day => "+1 day",
+        }
+    }
+
+    fn max_window(self) -> Duration {
+        match self {
+            Bucket::Hour => Duration::from_secs(31 * DAY),
+            Bucket::Day => Duration::from_secs(366 * DAY),
+        }
+    }
+}
+
+/// How many current devices share a device type or OS version.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct PlatformCount {
+    pub value: String,
+    pub devices: i64,
+    /// `devices` as a share of all current devices, from 0 to 1.
+    pub share: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatsSeries {
+    pub since: String,
+    pub bucket: Bucket,
+    pub points: Vec<StatsPoint>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatsPoint {
+    pub bucket: String,
+    pub sent: i64,
+    pub failed: i64,
+    pub failure_rate: f64,
+    pub sandbox: i64,
+    pub production: i64,
+}
+
+/// A validated series request.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SeriesWindow {
+    pub since: String,
+    pub window: Duration,
+    pub bucket: Bucket,
+}
+
+impl StatsQuery {
+    /// Returns `Ok(None)` when no series was requested.
+    pub fn window(&self) -> Result<Option<SeriesWindow>, String> {
+        let Some(since) = &self.since else {
+            return Ok(None);
+        };
+        let window = parse_duration(since)
+            .filter(|d| !d.is_zero())
+            .ok_or_else(|| format!("Invalid since '{since}', expected e.g. 24h or 7d"))?;
+        let bucket = self.bucket.unwrap_or(if window.as_secs() <= 2 * DAY {
+            Bucket::Hour
+        } else {
+            Bucket::Day
+        });
+        if window > bucket.max_window() {
+            return Err(format!(
+                "since '{since}' is too long for {} buckets",
+                match bucket {
+                    Bucket::Hour => "hourly",
+                    Bucket::Day => "daily",
+                }
+            ));
+        }
+        Ok(Some(SeriesWindow {
+            since: since.clone(),
+            window,
+            bucket,
+        }))
+    }
+}
+
+impl Database {
+    /// Current devices by `device_type` and by major OS version (`17.4.1`
+    /// counts as `17`), most common first. Devices that didn't report one
+    /// count as `unknown`.
+    pub(crate) fn device_platforms(
+    ) -> Result<(Vec<PlatformCount>, Vec<PlatformCount>), SeekwelError> {
+        let device_types = Self::device_distribution("device_type")?;
+        let os_versions = Self::device_distribution(
+            "CASE WHEN instr(os_version, '.') > 0 \
+             THEN substr(os_version, 1, instr(os_version, '.') - 1) ELSE os_version END",
+        )?;
+        Ok((device_types, os_versions))
+    }
+
+    fn device_distribution(value: &str) -> Result<Vec<PlatformCount>, SeekwelError> {
+        let counts: Vec<(String, i64)> = Connection::get()?.query_all(
+            &format!(
+                r#"
+                SELECT COALESCE(NULLIF({value}, ''), 'unknown') AS value, COUNT(*) AS devices
+                FROM devices
+                WHERE superseded_at IS NULL
+                GROUP BY value
+                ORDER BY devices DESC, value
+                "#
+            ),
+            (),
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        let total: i64 = counts.iter().map(|(_, devices)| devices).sum();
+        Ok(counts
+            .into_iter()
+            .map(|(value, devices)| PlatformCount {
+                value,
+                devices,
+                share: devices as f64 / total as f64,
+            })
+            .collect())
+    }
+
+    /// Push counts per time bucket, including empty buckets, oldest first.
+    pub(crate) fn push_series(window: &SeriesWindow) -> Result<StatsSeries, SeekwelError> {
+        let format = window.bucket.format();
+        let offset = format!("-{} seconds", window.window.as_secs());
+        let points = Connection::get()?.query_all(
+            r#"
+            WITH RECURSIVE ticks(t) AS (
+                SELECT strftime(?1, datetime('now', ?2))
+                UNION ALL
+                SELECT strftime(?1, datetime(t, ?3)) FROM ticks
+                WHERE datetime(t, ?3) <= datetime('now')
+            ),
+            counts AS (
+                SELECT
+                    strftime(?1, p.sent_at) AS bucket,
+                    SUM(p.status = 'sent') AS sent,
+                    SUM(p.status = 'failed') AS failed,
+                    SUM(d.environment = 'sandbox') AS sandbox,
+                    SUM(d.environment = 'production') AS production
+                FROM pushes p
+                JOIN devices d ON p.device_id = d.id
+                WHERE p.sent_at >= strftime(?1, datetime('now', ?2))
+                GROUP BY bucket
+            )
+            SELECT
+                ticks.t,
+                COALESCE(counts.sent, 0),
+                COALESCE(counts.failed, 0),
+                COALESCE(counts.sandbox, 0),
+                COALESCE(counts.production, 0)
+            FROM ticks
+            LEFT JOIN counts ON counts.bucket = ticks.t
+            ORDER BY ticks.t
+            "#,
+            params![format, offset, window.bucket.step()],
+            |row| {
+                let sent: i64 = row.get(1)?;
+                let failed: i64 = row.get(2)?;
+                Ok(StatsPoint {
+                    bucket: row.get(0)?,
+                    sent,
+                    failed,
+                    failure_rate: failure_rate(sent, failed),
+                    sandbox: row.get(3)?,
+                    production: row.get(4)?,
+                })
+            },
+        )?;
+
+        Ok(StatsSeries {
+            since: window.since.clone(),
+            bucket: window.bucket,
+            points,
+        })
+    }
+
+    /// Push counts per group, over the last `window` when given, with the
+    /// groups pushing the most first.
+    pub(crate) fn push_groups(
+        group_by: GroupBy,
+        bundle_id: &str,
+        window: Option<Duration>,
+    ) -> Result<Vec<StatsGroup>, SeekwelError> {
+        let conn = Connection::get()?;
+        let offset = window.map(|window| format!("-{} seconds", window.as_secs()));
+        let counts: Vec<(Option<String>, i64, i64, i64)> = conn.query_all(
+            &format!(
+                r#"
+                SELECT
+                    {} AS key,
+                    SUM(status = 'sent'),
+                    SUM(status = 'failed'),
+                    SUM(status = 'sent' AND opened_at IS NOT NULL)
+                FROM pushes
+                WHERE ?2 IS NULL OR sent_at >= datetime('now', ?2)
+                GROUP BY key
+                "#,
+                group_by.column()
+            ),
+            params![bundle_id, offset],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )?;
+
+        let mut devices: BTreeMap<Option<String>, i64> = match group_by {
+            GroupBy::App => BTreeMap::from([(
+                Some(bundle_id.to_string()),
+                conn.query_row(
+                    "SELECT COUNT(*) FROM devices WHERE superseded_at IS NULL",
+                    (),
+                    |row| row.get(0),
+                )?,
+            )]),
+            GroupBy::Topic => conn
+                .query_all(
+                    r#"
+                    SELECT t.topic, COUNT(*)
+                    FROM topic_subscriptions t
+                    JOIN devices d ON d.id = t.device_id
+                    WHERE d.superseded_at IS NULL
+                    GROUP BY t.topic
+                    "#,
+                    (),
+                    |row| Ok((Some(row.get(0)?), row.get(1)?)),
+                )?
+                .into_iter()
+                .collect(),
+            GroupBy::ApiKey => BTreeMap::new(),
+        };
+
+        let mut groups: Vec<StatsGroup> = counts
+            .into_iter()
+            .map(|(key, sent, failed, opened)| StatsGroup {
+                devices: devices.remove(&key),
+                key,
+                sent,
+                failed,
+                failure_rate: failure_rate(sent, failed),
+                opened,
+            })
+            .collect();
+        // Groups with devices but nothing sent yet.
+        groups.extend(devices.into_iter().map(|(key, devices)| StatsGroup {
+            key,
+            devices: Some(devices),
+            sent: 0,
+            failed: 0,
+            failure_rate: 0.0,
+            opened: 0,
+        }));
+        groups.sort_by(|a, b| {
+            (b.sent + b.failed)
+                .cmp(&(a.sent + a.failed))
+                .then_with(|| a.key.cmp(&b.key))
+        });
+        Ok(groups)
+    }
+}
+
+fn failure_rate(sent: i64, failed: i64) -> f64 {
+    let total = sent + failed;
+    if total == 0 {
+        0.0
+    } else {
+        failed as f64 / total as f64
+    }
+}
+
+pub(crate) fn open_rate(opened: i64, sent: i64) -> f64 {
+    if sent == 0 {
+        0.0
+    } else {
+        opened as f64 / sent as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_db;
+
+    #[test]
+    fn test_window_defaults_bucket_by_length() {
+        let query = StatsQuery {
+            since: Some("24h".to_string()),
+            bucket: None,
+            group_by: None,
+        };
+        assert_eq!(query.window().unwrap().unwrap().bucket, Bucket::Hour);
+
+        let query = StatsQuery {
+            since: Some("7d".to_string()),
+            bucket: None,
+            group_by: None,
+        };
+        assert_eq!(query.window().unwrap().unwrap().bucket, Bucket::Day);
+
+        assert!(StatsQuery::default().window().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_window_rejects_invalid_since() {
+        let query = StatsQuery {
+            since: Some("soon".to_string()),
+            bucket: None,
+            group_by: None,
+        };
+        assert!(query.window().is_err());
+
+        let query = StatsQuery {
+            since: Some("90d".to_string()),
+            bucket: Some(Bucket::Hour),
+            group_by: None,
+        };
+        assert!(query.window().is_err());
+    }
+
+    #[test]
+    fn test_failure_rate() {
+        assert_eq!(failure_rate(0, 0), 0.0);
+        assert_eq!(failure_rate(3, 1), 0.25);
+    }
+
+    #[test]
+    fn test_push_series_fills_empty_buckets() {
+        let _db = test_db();
+        let conn = Connection::get().unwrap();
+        conn.execute(
+            "INSERT INTO devices (device_token, installation_id, environment) VALUES ('t', 'i', 'sandbox')",
+            (),
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO pushes (device_id, status, sent_at) VALUES (1, 'sent', datetime('now')), (1, 'failed', datetime('now')), (1, 'sent', datetime('now', '-2 days'))",
+            (),
+        )
+        .unwrap();
+
+        let window = StatsQuery {
+            since: Some("3d".to_string()),
+            bucket: Some(Bucket::Day),
+            group_by: None,
+        }
+        .window()
+        .unwrap()
+        .unwrap();
+        let series = Database::push_series(&window).unwrap();
+
+        assert_eq!(series.points.len(), 4);
+        let today = series.points.last().unwrap();
+        assert_eq!((today.sent, today.failed), (1, 1));
+        assert_eq!(today.failure_rate, 0.5);
+        assert_eq!(today.sandbox, 2);
+        assert_eq!(series.points[1].sent, 1);
+        assert_eq!(series.points[2].sent, 0);
+    }
+
+    #[test]
+    fn test_push_groups() {
+        let _db = test_db();
+        let conn = Connection::get().unwrap();
+        conn.execute(
+            "INSERT INTO devices (device_token, installation_id, environment) VALUES ('a', 'i', 'sandbox'), ('b', 'j', 'sandbox')",
+            (),
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO topic_subscriptions (topic, device_id) VALUES ('backups', 1), ('backups', 2), ('deploys', 1)",
+            (),
+        )
+        .unwrap();
+        conn.execute(
+            r#"
+            INSERT INTO pushes (device_id, status, app, topic, api_key, opened_at, sent_at) VALUES
+                (1, 'sent', NULL, 'backups', 'ci', datetime('now'), datetime('now')),
+                (2, 'failed', NULL, 'backups', 'ci', NULL, datetime('now')),
+                (1, 'sent', 'com.example.app.voip', NULL, 'ops', NULL, datetime('now', '-3 days'))
+            "#,
+            (),
+        )
+        .unwrap();
+
+        let topics = Database::push_groups(GroupBy::Topic, "com.example.app", None).unwrap();
+        let summary: Vec<_> = topics
+            .iter()
+            .map(|g| (g.key.as_deref(), g.devices, g.sent, g.failed))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (Some("backups"), Some(2), 1, 1),
+                (None, None, 1, 0),
+                (Some("deploys"), Some(1), 0, 0)
+            ]
+        );
+        assert_eq!((topics[0].opened, topics[0].failure_rate), (1, 0.5));
+
+        let apps = Database::push_groups(
+            GroupBy::App,
+            "com.example.app",
+            Some(Duration::from_secs(DAY)),
+        )
+        .unwrap();
+        assert_eq!(apps.len(), 1);
+        assert_eq!(apps[0].key.as_deref(), Some("com.example.app"));
+        assert_eq!((apps[0].devices, apps[0].sent), (Some(2), 1));
+
+        let keys = Database::push_groups(GroupBy::ApiKey, "com.example.app", None).unwrap();
+        let keys: Vec<_> = keys.iter().map(|g| (g.key.as_deref(), g.devices)).collect();
+        assert_eq!(keys, [(Some("ci"), None), (Some("ops"), None)]);
+    }
+
+    #[test]
+    fn test_device_platforms() {
+        let _db = test_db();
+        Connection::get()
+            .unwrap()
+            .execute(
+                r#"
+                INSERT INTO devices (device_token, installation_id, environment, device_type, os_version, superseded_at) VALUES
+                    ('a', 'i', 'sandbox', 'iPhone', '18.1', NULL),
+                    ('b', 'j', 'sandbox', 'iPhone', '17.4.1', NULL),
+                    ('c', 'k', 'sandbox', 'iPad', '18', NULL),
+                    ('d', 'l', 'sandbox', NULL, NULL, NULL),
+                    ('e', 'l', 'sandbox', 'iPad', '16.0', datetime('now'))
+                "#,
+                (),
+            )
+            .unwrap();
+
+        let (device_types, os_versions) = Database::device_platforms().unwrap();
+        let counts = |counts: &[PlatformCount]| -> Vec<(String, i64)> {
+            counts
+                .iter()
+                .map(|c| (c.value.clone(), c.devices))
+                .collect()
+        };
+        assert_eq!(
+            counts(&device_types),
+            [
+                ("iPhone".to_string(), 2),
+                ("iPad".to_string(), 1),
+                ("unknown".to_string(), 1)
+            ]
+        );
+        assert_eq!(device_types[0].share, 0.5);
+        assert_eq!(
+            counts(&os_versions),
+            [
+                ("18".to_string(), 2),
+                ("17".to_string(), 1),
+                ("unknown".to_string(), 1)
+            ]
+        );
+    }
+}
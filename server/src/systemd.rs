@@ -0,0 +1,204 @@
+use std::{env, time::Duration};
+
+use crate::Database;
+
+/// The first descriptor of sockets passed by socket activation.
+const LISTEN_FDS_START: i32 = 3;
+
+/// Whether a `LISTEN_PID` or `WATCHDOG_PID` names this process, rather than
+/// one that handed its environment down.
+fn is_for_us(pid: Option<&str>, own_pid: u32) -> bool {
+    pid.and_then(|pid| pid.parse().ok()) == Some(own_pid)
+}
+
+fn parse_listen_fds(
+    fds: Option<&str>,
+    pid: Option<&str>,
+    own_pid: u32,
+) -> Result<Option<i32>, String> {
+    let Some(fds) = fds else {
+        return Ok(None);
+    };
+    if !is_for_us(pid, own_pid) {
+        return Ok(None);
+    }
+    match fds.parse::<i32>() {
+        Ok(0) => Ok(None),
+        Ok(1) => Ok(Some(LISTEN_FDS_START)),
+        Ok(count) if count > 1 => {
+            tracing::warn!(
+                count = count,
+                "systemd passed several sockets, serving on the first"
+            );
+            Ok(Some(LISTEN_FDS_START))
+        }
+        _ => Err(format!("Invalid LISTEN_FDS '{fds}'")),
+    }
+}
+
+/// The socket systemd opened for this process when started by a `.socket`
+/// unit, from `LISTEN_FDS` and `LISTEN_PID`.
+pub(crate) fn listen_fd() -> Result<Option<i32>, String> {
+    if cfg!(not(unix)) {
+        return Ok(None);
+    }
+    parse_listen_fds(
+        env::var("LISTEN_FDS").ok().as_deref(),
+        env::var("LISTEN_PID").ok().as_deref(),
+        std::process::id(),
+    )
+}
+
+/// A listening socket inherited from systemd.
+#[cfg(unix)]
+pub(crate) enum Inherited {
+    Tcp(std::net::TcpListener),
+    Unix(std::os::unix::net::UnixListener),
+}
+
+/// Takes ownership of the listening socket `fd`, whichever family it is.
+#[cfg(unix)]
+pub(crate) fn inherit(fd: i32) -> std::io::Result<Inherited> {
+    use std::os::{
+        fd::{FromRawFd, IntoRawFd},
+        unix::net::UnixListener,
+    };
+
+    // SAFETY: systemd passes the descriptor for this process to own, and it
+    // is only taken once, when the server starts.
+    let tcp = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    // Each family's `local_addr` refuses the other's sockets.
+    let inherited = if tcp.local_addr().is_ok() {
+        tcp.set_nonblocking(true)?;
+        Inherited::Tcp(tcp)
+    } else {
+        // SAFETY: the descriptor was just released by `into_raw_fd`.
+        let unix = unsafe { UnixListener::from_raw_fd(tcp.into_raw_fd()) };
+        unix.local_addr()?;
+        unix.set_nonblocking(true)?;
+        Inherited::Unix(unix)
+    };
+    Ok(inherited)
+}
+
+/// Tells systemd how the service is doing (`READY=1`, `WATCHDOG=1`,
+/// `STOPPING=1`) when it started the server with `NOTIFY_SOCKET`, as for
+/// `Type=notify`. Does nothing otherwise.
+pub(crate) fn notify(state: &str) {
+    #[cfg(unix)]
+    if let Ok(path) = env::var("NOTIFY_SOCKET") {
+        if let Err(e) = send_notify(&path, state) {
+            tracing::warn!(state = state, error = %e, "Failed to notify systemd");
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = state;
+}
+
+#[cfg(unix)]
+fn send_notify(path: &str, state: &str) -> std::io::Result<()> {
+    let socket = std::os::unix::net::UnixDatagram::unbound()?;
+    match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+    Ok(())
+}
+
+fn parse_watchdog(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    // Unlike `LISTEN_PID`, `WATCHDOG_PID` may be left out.
+    if pid.is_some() && !is_for_us(pid, own_pid) {
+        return None;
+    }
+    let usec: u64 = usec?.parse().ok().filter(|&usec| usec > 0)?;
+    Some(Duration::from_micros(usec))
+}
+
+/// How often systemd expects to hear from the server (`WatchdogSec=`), from
+/// `WATCHDOG_USEC` and `WATCHDOG_PID`.
+pub(crate) fn watchdog_interval() -> Option<Duration> {
+    parse_watchdog(
+        env::var("WATCHDOG_USEC").ok().as_deref(),
+        env::var("WATCHDOG_PID").ok().as_deref(),
+        std::process::id(),
+    )
+}
+
+/// Pings the watchdog at half its interval while the database answers, so
+/// systemd restarts a server that has hung or lost its database.
+pub(crate) fn spawn_watchdog(interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval / 2);
+        loop {
+            ticker.tick().await;
+            match Database::ping() {
+                Ok(()) => notify("WATCHDOG=1"),
+                Err(e) => {
+                    tracing::error!(error = %e, "Database unreachable, withholding the watchdog ping")
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listen_fds() {
+        assert_eq!(parse_listen_fds(None, None, 7).unwrap(), None);
+        assert_eq!(parse_listen_fds(Some("1"), Some("7"), 7).unwrap(), Some(3));
+        assert_eq!(parse_listen_fds(Some("2"), Some("7"), 7).unwrap(), Some(3));
+        assert_eq!(parse_listen_fds(Some("0"), Some("7"), 7).unwrap(), None);
+        // Passed to another process, which this one inherited them from.
+        assert_eq!(parse_listen_fds(Some("1"), Some("6"), 7).unwrap(), None);
+        assert_eq!(parse_listen_fds(Some("1"), None, 7).unwrap(), None);
+        assert!(parse_listen_fds(Some("x"), Some("7"), 7).is_err());
+    }
+
+    #[test]
+    fn test_watchdog() {
+        let interval = Some(Duration::from_secs(30));
+        assert_eq!(parse_watchdog(Some("30000000"), None, 7), interval);
+        assert_eq!(parse_watchdog(Some("30000000"), Some("7"), 7), interval);
+        assert_eq!(parse_watchdog(Some("30000000"), Some("6"), 7), None);
+        assert_eq!(parse_watchdog(Some("0"), None, 7), None);
+        assert_eq!(parse_watchdog(None, None, 7), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_inherit_and_notify() {
+        use std::os::fd::IntoRawFd;
+
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        match inherit(tcp.into_raw_fd()).unwrap() {
+            Inherited::Tcp(listener) => assert_eq!(listener.local_addr().unwrap(), addr),
+            Inherited::Unix(_) => panic!("expected a TCP listener"),
+        }
+
+        let dir = std::env::temp_dir().join(format!("psh-systemd-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("psh.sock");
+        let unix = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let inherited = inherit(unix.into_raw_fd()).unwrap();
+        assert!(matches!(inherited, Inherited::Unix(_)));
+
+        let notify_path = dir.join("notify.sock");
+        let notify = std::os::unix::net::UnixDatagram::bind(&notify_path).unwrap();
+        send_notify(notify_path.to_str().unwrap(), "READY=1").unwrap();
+        let mut buf = [0; 16];
+        let len = notify.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
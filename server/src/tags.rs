@@ -0,0 +1,274 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use seekwel::{connection::Connection, error::Error as SeekwelError, rusqlite::params};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    audit::{self, AuditContext},
+    devices::device_not_found,
+    token, token_cipher, AppState, Database, ErrorResponse,
+};
+
+const MAX_TAGS: usize = 32;
+const MAX_VALUE_LEN: usize = 128;
+
+#[derive(Debug, Serialize)]
+pub struct DeviceTagsResponse {
+    device_token: String,
+    tags: BTreeMap<String, String>,
+}
+
+impl Database {
+    pub(crate) fn create_device_tags_table(conn: &Connection) -> Result<(), SeekwelError> {
+        conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS device_tags (
+                device_id INTEGER NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+                key TEXT NOT NULL,
+                value TEXT NOT NULL,
+                PRIMARY KEY (device_id, key)
+            )
+            "#,
+            (),
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn device_tags(device_id: i64) -> Result<BTreeMap<String, String>, SeekwelError> {
+        let tags = Connection::get()?.query_all(
+            "SELECT key, value FROM device_tags WHERE device_id = ?1",
+            params![device_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        Ok(tags.into_iter().collect())
+    }
+
+    /// Tags of every current device, by token.
+    pub(crate) fn current_device_tags(
+    ) -> Result<HashMap<String, BTreeMap<String, String>>, SeekwelError> {
+        let rows: Vec<(String, String, String)> = Connection::get()?.query_all(
+            r#"
+            SELECT d.device_token, t.key, t.value
+            FROM device_tags t
+            JOIN devices d ON d.id = t.device_id
+            WHERE d.superseded_at IS NULL
+            "#,
+            (),
+            |row| Ok((token_cipher::open(row, 0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        let mut tags: HashMap<String, BTreeMap<String, String>> = HashMap::new();
+        for (device_token, key, value) in rows {
+            tags.entry(device_token).or_default().insert(key, value);
+        }
+        Ok(tags)
+    }
+
+    /// Sets each tag with a value and removes each one without.
+    fn update_device_tags(
+        device_id: i64,
+        changes: &BTreeMap<String, Option<String>>,
+    ) -> Result<(), SeekwelError> {
+        let conn = Connection::get()?;
+        Connection::transaction(|| {
+            for (key, value) in changes {
+                match value {
+                    Some(value) => conn.execute(
+                        r#"
+                        INSERT INTO device_tags (device_id, key, value) VALUES (?1, ?2, ?3)
+                        ON CONFLICT(device_id, key) DO UPDATE SET value = excluded.value
+                        "#,
+                        params![device_id, key, value],
+                    )?,
+                    None => conn.execute(
+                        "DELETE FROM device_tags WHERE device_id = ?1 AND key = ?2",
+                        params![device_id, key],
+                    )?,
+                };
+            }
+            Ok(())
+        })
+    }
+
+    /// Copies the tags of an installation's other current tokens to
+    /// `current_token`, without overwriting tags it already has. The token is
+    /// as stored, sealed when token encryption is on.
+    pub(crate) fn carry_tags(
+        conn: &Connection,
+        installation_id: &str,
+        current_token: &str,
+    ) -> Result<(), SeekwelError> {
+        conn.execute(
+            r#"
+            INSERT OR IGNORE INTO device_tags (device_id, key, value)
+            SELECT (SELECT id FROM devices WHERE device_token = ?2), t.key, t.value
+            FROM device_tags t
+            JOIN devices d ON d.id = t.device_id
+            WHERE d.installation_id = ?1 AND d.device_token != ?2 AND d.superseded_at IS NULL
+            "#,
+            params![installation_id, current_token],
+        )?;
+        Ok(())
+    }
+}
+
+fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= 64
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// Values can't hold `,`, which separates tags in a filter query string.
+fn is_valid_value(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= MAX_VALUE_LEN
+        && !value.contains(',')
+        && !value.chars().any(char::is_control)
+}
+
+fn check_changes(changes: &BTreeMap<String, Option<String>>) -> Result<(), String> {
+    if changes.len() > MAX_TAGS {
+        return Err(format!("At most {MAX_TAGS} tags can be set at once"));
+    }
+    for (key, value) in changes {
+        if !is_valid_key(key) {
+            return Err(format!(
+                "Invalid tag '{key}': keys must be 1-64 characters of letters, digits, '-', '_' or '.'"
+            ));
+        }
+        if value.as_deref().is_some_and(|value| !is_valid_value(value)) {
+            return Err(format!(
+                "Invalid value for tag '{key}': values must be 1-{MAX_VALUE_LEN} characters without commas"
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn database_error(e: SeekwelError) -> (StatusCode, Json<ErrorResponse>) {
+    tracing::error!(error = %e, "Database error handling device tags");
+    ErrorResponse::with_status(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Database error: {e}"),
+    )
+}
+
+fn device_id(device_token: &str) -> Result<i64, (StatusCode, Json<ErrorResponse>)> {
+    Database::device_id(device_token)
+        .map_err(database_error)?
+        .ok_or_else(device_not_found)
+}
+
+pub async fn get_device_tags(
+    State(_state): State<AppState>,
+    Path(device_token): Path<String>,
+) -> Result<Json<DeviceTagsResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let tags = Database::device_tags(device_id(&device_token)?).map_err(database_error)?;
+    Ok(Json(DeviceTagsResponse { device_token, tags }))
+}
+
+/// `POST /devices/:token/tags`: `{"tier": "beta", "cohort": null}` sets
+/// `tier` and removes `cohort`, leaving the device's other tags as they are.
+pub async fn update_device_tags(
+    State(_state): State<AppState>,
+    audit: AuditContext,
+    Path(device_token): Path<String>,
+    Json(changes): Json<BTreeMap<String, Option<String>>>,
+) -> Result<Json<DeviceTagsResponse>, (StatusCode, Json<ErrorResponse>)> {
+    check_changes(&changes)
+        .map_err(|e| ErrorResponse::with_status(StatusCode::UNPROCESSABLE_ENTITY, e))?;
+    let device_id = device_id(&device_token)?;
+    tracing::info!(device_token = %token::logged(&device_token), changes = changes.len(), "Updating device tags");
+    Database::update_device_tags(device_id, &changes).map_err(database_error)?;
+
+    let summary: Vec<String> = changes
+        .iter()
+        .map(|(key, value)| format!("{key}={}", value.as_deref().unwrap_or("")))
+        .collect();
+    audit::record(
+        &audit,
+        "device.tags",
+        format!(
+            "device_token={} {}",
+            token::logged(&device_token),
+            summary.join(" ")
+        ),
+    );
+    let tags = Database::device_tags(device_id).map_err(database_error)?;
+    Ok(Json(DeviceTagsResponse { device_token, tags }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{filter::DeviceFilter, test_support::test_db};
+
+    fn device(token: &str, installation_id: &str) -> i64 {
+        Connection::get()
+            .unwrap()
+            .execute(
+                "INSERT INTO devices (device_token, installation_id, environment) VALUES (?1, ?2, 'sandbox')",
+                params![token, installation_id],
+            )
+            .unwrap();
+        Database::device_id(token).unwrap().unwrap()
+    }
+
+    fn changes(pairs: &[(&str, Option<&str>)]) -> BTreeMap<String, Option<String>> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.map(str::to_string)))
+            .collect()
+    }
+
+    #[test]
+    fn test_tags_filter_targets_and_follow_rotation() {
+        let _db = test_db();
+        let a = device("a", "install-1");
+        let b = device("b", "install-2");
+        Database::update_device_tags(
+            a,
+            &changes(&[("tier", Some("beta")), ("team", Some("ios"))]),
+        )
+        .unwrap();
+        Database::update_device_tags(b, &changes(&[("tier", Some("ga"))])).unwrap();
+        Database::update_device_tags(a, &changes(&[("team", None)])).unwrap();
+        assert_eq!(
+            Database::device_tags(a).unwrap(),
+            BTreeMap::from([("tier".to_string(), "beta".to_string())])
+        );
+
+        let beta = DeviceFilter {
+            tags: BTreeMap::from([("tier".to_string(), "beta".to_string())]),
+            ..Default::default()
+        };
+        let targets = Database::delivery_targets(Some(&beta)).unwrap();
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].device_token, "a");
+
+        let rotated = device("a2", "install-1");
+        let conn = Connection::get().unwrap();
+        Database::carry_tags(&conn, "install-1", "a2").unwrap();
+        assert_eq!(
+            Database::device_tags(rotated).unwrap(),
+            Database::device_tags(a).unwrap()
+        );
+        assert_eq!(Database::current_device_tags().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_check_changes() {
+        assert!(check_changes(&changes(&[("tier", Some("beta")), ("old", None)])).is_ok());
+        assert!(check_changes(&changes(&[("a b", Some("x"))])).is_err());
+        // Either would read as another tag in a filter query string.
+        assert!(check_changes(&changes(&[("a,b", Some("x"))])).is_err());
+        assert!(check_changes(&changes(&[("a=b", Some("x"))])).is_err());
+        assert!(check_changes(&changes(&[("tier", Some("beta,ga"))])).is_err());
+        assert!(check_changes(&changes(&[("tier", Some(""))])).is_err());
+    }
+}
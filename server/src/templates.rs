@@ -0,0 +1,386 @@
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{catalog::Message, DeviceTarget, SendRequest};
+
+/// Templates nest `{{#if}}` blocks at most this deep.
+const MAX_DEPTH: usize = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Text(String),
+    Variable(String),
+    If {
+        name: String,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+}
+
+/// A parsed title, subtitle or body: text with `{{name}}` variables and
+/// `{{#if name}}…{{else}}…{{/if}}` blocks.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Template(Vec<Node>);
+
+/// Where a `{{#if}}` block is in the parse: its name, the nodes of the
+/// branch already closed by `{{else}}`, if any, and the enclosing nodes.
+struct Frame {
+    name: String,
+    then: Option<Vec<Node>>,
+    outer: Vec<Node>,
+}
+
+impl Template {
+    pub(crate) fn parse(text: &str) -> Result<Self, String> {
+        let mut nodes = Vec::new();
+        let mut frames: Vec<Frame> = Vec::new();
+        let mut rest = text;
+
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                nodes.push(Node::Text(rest[..start].to_string()));
+            }
+            let after = &rest[start + 2..];
+            let end = after
+                .find("}}")
+                .ok_or_else(|| "Unclosed '{{' in template".to_string())?;
+            let tag = after[..end].trim();
+            rest = &after[end + 2..];
+
+            if let Some(name) = tag.strip_prefix("#if ") {
+                if frames.len() == MAX_DEPTH {
+                    return Err(format!(
+                        "Templates nest at most {MAX_DEPTH} {{{{#if}}}} blocks"
+                    ));
+                }
+                frames.push(Frame {
+                    name: variable_name(name)?,
+                    then: None,
+                    outer: std::mem::take(&mut nodes),
+                });
+            } else if tag == "else" {
+                match frames.last_mut() {
+                    Some(frame) if frame.then.is_none() => {
+                        frame.then = Some(std::mem::take(&mut nodes));
+                    }
+                    _ => return Err("'{{else}}' outside an '{{#if}}' block".to_string()),
+                }
+            } else if tag == "/if" {
+                let frame = frames
+                    .pop()
+                    .ok_or_else(|| "'{{/if}}' without an '{{#if}}'".to_string())?;
+                let last = std::mem::replace(&mut nodes, frame.outer);
+                let (then, otherwise) = match frame.then {
+                    Some(then) => (then, last),
+                    None => (last, Vec::new()),
+                };
+                nodes.push(Node::If {
+                    name: frame.name,
+                    then,
+                    otherwise,
+                });
+            } else if tag.starts_with('#') || tag.starts_with('/') {
+                return Err(format!("Unsupported template block '{{{{{tag}}}}}'"));
+            } else {
+                nodes.push(Node::Variable(variable_name(tag)?));
+            }
+        }
+        if let Some(frame) = frames.last() {
+            return Err(format!("'{{{{#if {}}}}}' is never closed", frame.name));
+        }
+        if !rest.is_empty() {
+            nodes.push(Node::Text(rest.to_string()));
+        }
+        Ok(Self(nodes))
+    }
+
+    pub(crate) fn render(&self, lookup: &impl Fn(&str) -> Option<String>) -> String {
+        let mut out = String::new();
+        render_nodes(&self.0, lookup, &mut out);
+        out
+    }
+}
+
+fn render_nodes(nodes: &[Node], lookup: &impl Fn(&str) -> Option<String>, out: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Variable(name) => out.push_str(&lookup(name).unwrap_or_default()),
+            Node::If {
+                name,
+                then,
+                otherwise,
+            } => {
+                let branch = if lookup(name).is_some_and(|value| is_truthy(&value)) {
+                    then
+                } else {
+                    otherwise
+                };
+                render_nodes(branch, lookup, out);
+            }
+        }
+    }
+}
+
+fn variable_name(name: &str) -> Result<String, String> {
+    let name = name.trim();
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+    if valid {
+        Ok(name.to_string())
+    } else {
+        Err(format!("Invalid template variable '{name}'"))
+    }
+}
+
+/// Missing values, empty strings, `false` and `0` take the `{{else}}` branch.
+fn is_truthy(value: &str) -> bool {
+    !matches!(value, "" | "false" | "0")
+}
+
+const MAX_COLLAPSE_ID_BYTES: usize = 64;
+
+fn is_template(text: Option<&str>) -> bool {
+    text.is_some_and(|text| text.contains("{{"))
+}
+
+/// Whether any recipient's title, subtitle, body or collapse id needs
+/// rendering.
+pub(crate) fn is_templated(req: &SendRequest, message: Option<&Message>) -> bool {
+    [&req.title, &req.subtitle, &req.body, &req.collapse_id]
+        .into_iter()
+        .any(|text| is_template(text.as_deref()))
+        || message.is_some_and(|message| {
+            message
+                .locales
+                .values()
+                .any(|t| is_template(t.title.as_deref()) || is_template(t.body.as_deref()))
+        })
+}
+
+/// Checks a send's templates up front, so a typo fails the request rather
+/// than every delivery.
+pub(crate) fn validate(req: &SendRequest) -> Result<(), String> {
+    for (field, text) in [
+        ("title", &req.title),
+        ("subtitle", &req.subtitle),
+        ("body", &req.body),
+        ("collapse_id", &req.collapse_id),
+    ] {
+        if let Some(text) = text.as_deref().filter(|text| is_template(Some(text))) {
+            Template::parse(text).map_err(|e| format!("Invalid {field} template: {e}"))?;
+        }
+    }
+    Ok(())
+}
+
+/// APNs rejects a collapse id over 64 bytes, so a rendered one is cut to
+/// fit rather than failing the delivery.
+fn cut_collapse_id(mut id: String) -> String {
+    let mut len = id.len().min(MAX_COLLAPSE_ID_BYTES);
+    while !id.is_char_boundary(len) {
+        len -= 1;
+    }
+    id.truncate(len);
+    id
+}
+
+/// What a template can refer to for one recipient: the device's own
+/// fields, its tags as `tags.<key>`, and the send's custom data as
+/// `data.<path>`. A bare name tries the device, then its tags, then data.
+pub(crate) struct Variables<'a> {
+    pub(crate) device: &'a DeviceTarget,
+    pub(crate) tags: Option<&'a BTreeMap<String, String>>,
+    pub(crate) data: Option<&'a HashMap<String, Value>>,
+}
+
+impl Variables<'_> {
+    fn lookup(&self, name: &str) -> Option<String> {
+        if let Some(key) = name.strip_prefix("tags.") {
+            return self.tag(key);
+        }
+        if let Some(path) = name.strip_prefix("data.") {
+            return self.data(path);
+        }
+        self.device_field(name)
+            .or_else(|| self.tag(name))
+            .or_else(|| self.data(name))
+    }
+
+    fn device_field(&self, name: &str) -> Option<String> {
+        let device = self.device;
+        match name {
+            "device_name" => device.device_name.clone(),
+            "device_type" => device.device_type.clone(),
+            "os_version" => device.os_version.clone(),
+            "app_version" => device.app_version.clone(),
+            "locale" => device.locale.clone(),
+            "timezone" => device.timezone.clone(),
+            "environment" => Some(device.environment.clone()),
+            "platform" => Some(device.platform.as_str().to_string()),
+            _ => None,
+        }
+    }
+
+    fn tag(&self, key: &str) -> Option<String> {
+        self.tags?.get(key).cloned()
+    }
+
+    fn data(&self, path: &str) -> Option<String> {
+        let mut keys = path.split('.');
+        let mut value = self.data?.get(keys.next()?)?;
+        for key in keys {
+            value = value.get(key)?;
+        }
+        match value {
+            Value::Null => None,
+            Value::String(s) => Some(s.clone()),
+            other => Some(other.to_string()),
+        }
+    }
+
+    /// The request this recipient gets, with its title, subtitle, body and
+    /// collapse id rendered.
+    pub(crate) fn render(&self, req: &SendRequest) -> SendRequest {
+        let lookup = |name: &str| self.lookup(name);
+        let render = |text: &Option<String>| {
+            text.as_deref().map(|text| match Template::parse(text) {
+                Ok(template) => template.render(&lookup),
+                // Catalog translations aren't validated with the request;
+                // one that doesn't parse goes out as written.
+                Err(_) => text.to_string(),
+            })
+        };
+        let mut rendered = req.clone();
+        rendered.title = render(&req.title);
+        rendered.subtitle = render(&req.subtitle);
+        rendered.body = render(&req.body);
+        rendered.collapse_id = render(&req.collapse_id).map(cut_collapse_id);
+        rendered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::Platform;
+    use serde_json::json;
+
+    fn device() -> DeviceTarget {
+        DeviceTarget {
+            id: 1,
+            device_token: "token".to_string(),
+            environment: "sandbox".to_string(),
+            platform: Platform::Apns,
+            device_name: Some("Ada's iPhone".to_string()),
+            device_type: Some("iPhone".to_string()),
+            os_version: None,
+            app_version: None,
+            locale: Some("en".to_string()),
+            timezone: None,
+            snoozed: false,
+        }
+    }
+
+    #[test]
+    fn test_parse_and_render() {
+        let template = Template::parse(
+            "Hi {{ device_name }}{{#if overdue}}, {{days}} days late{{else}}!{{/if}}",
+        )
+        .unwrap();
+        let vars = HashMap::from([("device_name", "Ada"), ("overdue", "true"), ("days", "3")]);
+        let lookup = |name: &str| vars.get(name).map(|v| v.to_string());
+        assert_eq!(template.render(&lookup), "Hi Ada, 3 days late");
+
+        let vars = HashMap::from([("overdue", "false")]);
+        let lookup = |name: &str| vars.get(name).map(|v| v.to_string());
+        assert_eq!(template.render(&lookup), "Hi !");
+
+        let nested = Template::parse("{{#if a}}a{{#if b}}b{{/if}}{{else}}-{{/if}}").unwrap();
+        let lookup = |name: &str| (name == "a").then(|| "1".to_string());
+        assert_eq!(nested.render(&lookup), "a");
+
+        for invalid in [
+            "{{name",
+            "{{#if a}}open",
+            "{{/if}}",
+            "{{else}}",
+            "{{#if a}}{{else}}{{else}}{{/if}}",
+            "{{#each items}}{{/each}}",
+            "{{}}",
+            "{{first name}}",
+        ] {
+            assert!(Template::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_render_for_device() {
+        let device = device();
+        let tags = BTreeMap::from([("plan".to_string(), "pro".to_string())]);
+        let data = HashMap::from([
+            ("overdue".to_string(), json!(true)),
+            ("order".to_string(), json!({"id": 42})),
+            ("plan".to_string(), json!("ignored")),
+        ]);
+        let vars = Variables {
+            device: &device,
+            tags: Some(&tags),
+            data: Some(&data),
+        };
+        let req = SendRequest {
+            title: Some("{{plan}} / {{data.plan}}".to_string()),
+            body: Some(
+                "Hi {{device_name}}, {{#if overdue}}order {{data.order.id}} is overdue{{/if}}{{#if timezone}} ({{timezone}}){{/if}}"
+                    .to_string(),
+            ),
+            ..Default::default()
+        };
+        let rendered = vars.render(&req);
+        assert_eq!(rendered.title.as_deref(), Some("pro / ignored"));
+        assert_eq!(
+            rendered.body.as_deref(),
+            Some("Hi Ada's iPhone, order 42 is overdue")
+        );
+
+        let plain = SendRequest {
+            body: Some("no {variables}".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(vars.render(&plain).body, plain.body);
+    }
+
+    #[test]
+    fn test_render_collapse_id() {
+        let device = device();
+        let data = HashMap::from([("host".to_string(), json!("db-1"))]);
+        let vars = Variables {
+            device: &device,
+            tags: None,
+            data: Some(&data),
+        };
+        let req = SendRequest {
+            collapse_id: Some("alert-{{host}}".to_string()),
+            ..Default::default()
+        };
+        assert!(is_templated(&req, None));
+        assert_eq!(vars.render(&req).collapse_id.as_deref(), Some("alert-db-1"));
+
+        let data = HashMap::from([("host".to_string(), json!("é".repeat(40)))]);
+        let vars = Variables {
+            data: Some(&data),
+            ..vars
+        };
+        let cut = vars.render(&req).collapse_id.unwrap();
+        assert_eq!(cut.len(), 64);
+        assert!(cut.starts_with("alert-é"));
+
+        let invalid = SendRequest {
+            collapse_id: Some("alert-{{host".to_string()),
+            ..Default::default()
+        };
+        assert!(validate(&invalid).unwrap_err().contains("collapse_id"));
+    }
+}
@@ -0,0 +1,113 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use serde::Serialize;
+
+use crate::{
+    apns_error::ApnsErrorCode,
+    audit::{self, AuditContext},
+    provider::{Platform, Target},
+    token, AppState, Environment, ErrorResponse, SendRequest,
+};
+
+/// How one APNs environment answered the test push.
+#[derive(Debug, Serialize)]
+pub struct EnvironmentResult {
+    environment: &'static str,
+    success: bool,
+    apns_id: Option<String>,
+    error: Option<String>,
+    error_code: Option<ApnsErrorCode>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TestDeviceResponse {
+    device_token: String,
+    /// The environment that accepted the token, if either did.
+    environment: Option<&'static str>,
+    results: Vec<EnvironmentResult>,
+}
+
+/// A push that says what it is, so nobody mistakes it for a real one.
+fn test_request() -> SendRequest {
+    SendRequest {
+        title: Some("psh test notification".to_string()),
+        body: Some(
+            "Sent by `psh test-device` to check this device's token. You can ignore it."
+                .to_string(),
+        ),
+        ..SendRequest::default()
+    }
+}
+
+/// Sends a test push to `token` through both APNs environments, whether or
+/// not it's registered, and reports which accepted it. Nothing is written
+/// to push history.
+pub async fn test_device(
+    State(state): State<AppState>,
+    audit: AuditContext,
+    Path(token): Path<String>,
+) -> Result<Json<TestDeviceResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let token = token::normalize_device_token(&token, state.token_validation)
+        .map_err(|e| ErrorResponse::with_status(StatusCode::UNPROCESSABLE_ENTITY, e))?;
+    let Some(provider) = state.providers.get(Platform::Apns) else {
+        return Err(ErrorResponse::with_status(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "APNs provider not configured",
+        ));
+    };
+
+    let req = test_request();
+    let send = |environment: Environment| {
+        let target = Target {
+            token: &token,
+            environment: environment.as_str(),
+        };
+        let req = &req;
+        async move { (environment, provider.send(req, target).await) }
+    };
+    let (sandbox, production) =
+        tokio::join!(send(Environment::Sandbox), send(Environment::Production));
+
+    let results: Vec<EnvironmentResult> = [sandbox, production]
+        .into_iter()
+        .map(|(environment, result)| match result {
+            Ok(apns_id) => EnvironmentResult {
+                environment: environment.as_str(),
+                success: true,
+                apns_id: Some(apns_id),
+                error: None,
+                error_code: None,
+            },
+            Err(e) => EnvironmentResult {
+                environment: environment.as_str(),
+                success: false,
+                apns_id: None,
+                error: Some(e.message),
+                error_code: Some(e.code),
+            },
+        })
+        .collect();
+    let environment = results
+        .iter()
+        .find(|result| result.success)
+        .map(|result| result.environment);
+    tracing::info!(device_token = %token::logged(&token), environment = ?environment, "Sent test push");
+    audit::record(
+        &audit,
+        "test_device",
+        format!(
+            "device_token={} accepted_by={}",
+            token::logged(&token),
+            environment.unwrap_or("none")
+        ),
+    );
+
+    Ok(Json(TestDeviceResponse {
+        device_token: token,
+        environment,
+        results,
+    }))
+}
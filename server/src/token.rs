@@ -0,0 +1,206 @@
+use std::{
+    env, fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// APNs device tokens are currently 32 bytes, sent as 64 hex characters.
+const APNS_TOKEN_HEX_LEN: usize = 64;
+/// Upper bound for lenient mode, in case Apple lengthens tokens.
+const MAX_TOKEN_HEX_LEN: usize = 200;
+
+/// How strictly `/register` checks device tokens, set with
+/// `PSH_TOKEN_VALIDATION`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TokenValidation {
+    /// Exactly 64 hex characters.
+    #[default]
+    Strict,
+    /// Any even-length hex string up to 200 characters.
+    Lenient,
+    /// Store tokens as given, like older servers did.
+    Off,
+}
+
+impl TokenValidation {
+    pub fn from_env() -> Result<Self, String> {
+        match env::var("PSH_TOKEN_VALIDATION") {
+            Ok(value) => Self::parse(&value),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    fn parse(value: &str) -> Result<Self, String> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "strict" | "" => Ok(Self::Strict),
+            "lenient" => Ok(Self::Lenient),
+            "off" => Ok(Self::Off),
+            other => Err(format!(
+                "Invalid PSH_TOKEN_VALIDATION '{other}', expected strict, lenient or off"
+            )),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Strict => "strict",
+            Self::Lenient => "lenient",
+            Self::Off => "off",
+        }
+    }
+}
+
+/// Reads `PSH_REDACT_TOKENS`: whether logs, the audit log and push history
+/// responses show device tokens cut to their ends. Off by default.
+pub fn redaction_from_env() -> Result<bool, String> {
+    match env::var("PSH_REDACT_TOKENS") {
+        Ok(value) => parse_redaction(&value),
+        Err(_) => Ok(false),
+    }
+}
+
+fn parse_redaction(value: &str) -> Result<bool, String> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "" | "0" | "false" | "off" | "no" => Ok(false),
+        "1" | "true" | "on" | "yes" => Ok(true),
+        _ => Err(format!(
+            "Invalid PSH_REDACT_TOKENS '{value}', expected true or false"
+        )),
+    }
+}
+
+/// Set once at startup; tracing output and audit entries read it.
+static REDACT_LOGS: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn set_log_redaction(redact: bool) {
+    REDACT_LOGS.store(redact, Ordering::Relaxed);
+}
+
+/// `token` cut to its first and last 8 characters, enough to tell devices
+/// apart without being able to send to them.
+pub(crate) fn redact(token: &str) -> String {
+    match (token.get(..8), token.get(token.len().saturating_sub(8)..)) {
+        (Some(start), Some(end)) if token.len() > 16 => format!("{start}...{end}"),
+        _ => token.to_string(),
+    }
+}
+
+/// A device token as logs show it: redacted under `PSH_REDACT_TOKENS`.
+pub(crate) struct Logged<'a>(&'a str);
+
+pub(crate) fn logged(token: &str) -> Logged<'_> {
+    Logged(token)
+}
+
+impl fmt::Display for Logged<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if REDACT_LOGS.load(Ordering::Relaxed) {
+            f.write_str(&redact(self.0))
+        } else {
+            f.write_str(self.0)
+        }
+    }
+}
+
+/// Lowercases a device token and strips the spaces and angle brackets of
+/// `NSData` descriptions, then checks it against `mode`.
+pub fn normalize_device_token(raw: &str, mode: TokenValidation) -> Result<String, String> {
+    if mode == TokenValidation::Off {
+        return Ok(raw.to_string());
+    }
+
+    let token: String = raw
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '<' && *c != '>')
+        .collect::<String>()
+        .to_ascii_lowercase();
+
+    if token.is_empty() {
+        return Err("device_token is empty".to_string());
+    }
+    if !token.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err("device_token must be hexadecimal".to_string());
+    }
+    let valid_length = match mode {
+        TokenValidation::Strict => token.len() == APNS_TOKEN_HEX_LEN,
+        _ => token.len().is_multiple_of(2) && token.len() <= MAX_TOKEN_HEX_LEN,
+    };
+    if !valid_length {
+        return Err(match mode {
+            TokenValidation::Strict => format!(
+                "device_token must be {APNS_TOKEN_HEX_LEN} hex characters, got {}",
+                token.len()
+            ),
+            _ => format!(
+                "device_token must be an even number of hex characters, at most {MAX_TOKEN_HEX_LEN}"
+            ),
+        });
+    }
+    Ok(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "a1b2c3d4e5f60718293a4b5c6d7e8f90a1b2c3d4e5f60718293a4b5c6d7e8f90";
+
+    #[test]
+    fn test_normalizes_case_and_nsdata_format() {
+        let upper = TOKEN.to_uppercase();
+        assert_eq!(
+            normalize_device_token(&upper, TokenValidation::Strict).unwrap(),
+            TOKEN
+        );
+
+        let described = format!("<{} {}>", &TOKEN[..8], &TOKEN[8..]);
+        assert_eq!(
+            normalize_device_token(&described, TokenValidation::Strict).unwrap(),
+            TOKEN
+        );
+    }
+
+    #[test]
+    fn test_strict_rejects_garbage_and_wrong_length() {
+        assert!(normalize_device_token("", TokenValidation::Strict).is_err());
+        assert!(normalize_device_token("not-a-token", TokenValidation::Strict).is_err());
+        assert!(normalize_device_token(&TOKEN[..62], TokenValidation::Strict).is_err());
+    }
+
+    #[test]
+    fn test_lenient_allows_other_even_lengths() {
+        let longer = format!("{TOKEN}abcd");
+        assert_eq!(
+            normalize_device_token(&longer, TokenValidation::Lenient).unwrap(),
+            longer
+        );
+        assert!(normalize_device_token("abc", TokenValidation::Lenient).is_err());
+        assert!(normalize_device_token("zz", TokenValidation::Lenient).is_err());
+    }
+
+    #[test]
+    fn test_off_keeps_token_as_given() {
+        assert_eq!(
+            normalize_device_token("Test Token", TokenValidation::Off).unwrap(),
+            "Test Token"
+        );
+    }
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(redact(TOKEN), "a1b2c3d4...6d7e8f90");
+        assert_eq!(redact("short"), "short");
+        assert_eq!(logged(TOKEN).to_string(), TOKEN);
+        assert_eq!(parse_redaction("on"), Ok(true));
+        assert!(parse_redaction("maybe").is_err());
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(
+            TokenValidation::parse("Lenient"),
+            Ok(TokenValidation::Lenient)
+        );
+        assert_eq!(TokenValidation::parse("off"), Ok(TokenValidation::Off));
+        assert!(TokenValidation::parse("sometimes").is_err());
+    }
+}
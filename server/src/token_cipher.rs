@@ -0,0 +1,256 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes256Gcm, Key, Nonce,
+};
+use base64::{
+    engine::general_purpose::{STANDARD as BASE64, URL_SAFE_NO_PAD},
+    Engine,
+};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use seekwel::{
+    connection::Connection,
+    error::Error as SeekwelError,
+    rusqlite::{self, params, types::Type},
+};
+use sha2::Sha256;
+use std::{process::Command, sync::RwLock};
+
+use crate::{config::TokenEncryptionConfig, Database};
+
+/// Marks a stored token as sealed, so plaintext rows written before
+/// encryption was turned on still read back.
+const PREFIX: &str = "enc1:";
+
+/// Seals device tokens for the `devices` table with AES-256-GCM.
+///
+/// The nonce is derived from the token, so a token always seals to the same
+/// text and the store can still look devices up by token and keep them
+/// unique. That shows which rows share a token, which the column's
+/// uniqueness already does.
+pub(crate) struct TokenCipher {
+    cipher: Aes256Gcm,
+    nonce_key: [u8; 32],
+}
+
+impl TokenCipher {
+    /// `key` is base64 of 32 bytes. The encryption and nonce keys are both
+    /// derived from it.
+    pub(crate) fn new(key: &str) -> Result<Self, String> {
+        let bytes = BASE64
+            .decode(key.trim())
+            .map_err(|e| format!("token_encryption key is not valid base64: {e}"))?;
+        if bytes.len() != 32 {
+            return Err(format!(
+                "token_encryption key must be 32 bytes (AES-256), got {}",
+                bytes.len()
+            ));
+        }
+        let hkdf = Hkdf::<Sha256>::new(None, &bytes);
+        let mut encryption_key = [0u8; 32];
+        let mut nonce_key = [0u8; 32];
+        hkdf.expand(b"psh device token encryption", &mut encryption_key)
+            .and_then(|()| hkdf.expand(b"psh device token nonce", &mut nonce_key))
+            .map_err(|e| format!("Cannot derive token keys: {e}"))?;
+        Ok(Self {
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&encryption_key)),
+            nonce_key,
+        })
+    }
+
+    /// Reads the key from `[token_encryption]`: given as `key`, or printed
+    /// by `key_command`, for a key kept in a KMS or secret manager.
+    pub(crate) fn from_config(config: &TokenEncryptionConfig) -> Result<Self, String> {
+        match (&config.key, &config.key_command) {
+            (Some(key), None) => Self::new(key),
+            (None, Some(command)) => {
+                let output = Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .output()
+                    .map_err(|e| format!("Cannot run token_encryption key_command: {e}"))?;
+                if !output.status.success() {
+                    return Err(format!(
+                        "token_encryption key_command failed ({}): {}",
+                        output.status,
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    ));
+                }
+                Self::new(&String::from_utf8_lossy(&output.stdout))
+            }
+            _ => Err("[token_encryption] needs exactly one of key or key_command".to_string()),
+        }
+    }
+
+    fn seal(&self, token: &str) -> String {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.nonce_key)
+            .expect("HMAC takes keys of any length");
+        mac.update(token.as_bytes());
+        let digest = mac.finalize().into_bytes();
+        let nonce = Nonce::from_slice(&digest[..12]);
+        let ciphertext = self
+            .cipher
+            .encrypt(nonce, token.as_bytes())
+            .expect("AES-GCM encrypts any token");
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        format!("{PREFIX}{}", URL_SAFE_NO_PAD.encode(sealed))
+    }
+
+    fn open(&self, sealed: &str) -> Result<String, String> {
+        let bytes = URL_SAFE_NO_PAD
+            .decode(sealed)
+            .map_err(|e| format!("Sealed device token is not valid base64: {e}"))?;
+        if bytes.len() < 12 {
+            return Err("Sealed device token is too short".to_string());
+        }
+        let (nonce, ciphertext) = bytes.split_at(12);
+        let token = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| "Cannot decrypt device token, is this the key it was sealed with?")?;
+        String::from_utf8(token).map_err(|e| e.to_string())
+    }
+}
+
+/// Set once at startup, before the first query.
+static CIPHER: RwLock<Option<TokenCipher>> = RwLock::new(None);
+
+pub(crate) fn set_cipher(cipher: Option<TokenCipher>) {
+    *CIPHER.write().unwrap_or_else(|e| e.into_inner()) = cipher;
+}
+
+/// `token` as the `devices` table stores it: sealed when encryption is on.
+pub(crate) fn seal(token: &str) -> String {
+    match &*CIPHER.read().unwrap_or_else(|e| e.into_inner()) {
+        Some(cipher) => cipher.seal(token),
+        None => token.to_string(),
+    }
+}
+
+/// The device token in column `index` of `row`, opened if it was sealed.
+pub(crate) fn open(row: &rusqlite::Row<'_>, index: usize) -> rusqlite::Result<String> {
+    let stored: String = row.get(index)?;
+    open_stored(stored)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(index, Type::Text, e.into()))
+}
+
+fn open_stored(stored: String) -> Result<String, String> {
+    let Some(sealed) = stored.strip_prefix(PREFIX) else {
+        return Ok(stored);
+    };
+    match &*CIPHER.read().unwrap_or_else(|e| e.into_inner()) {
+        Some(cipher) => cipher.open(sealed),
+        None => Err("Device token is encrypted but [token_encryption] isn't set".to_string()),
+    }
+}
+
+impl Database {
+    /// Brings stored tokens in line with the cipher at startup: seals the
+    /// plaintext ones when encryption is on, returning how many, and checks
+    /// that sealed ones open with the key. Sealed tokens without a key are an
+    /// error, since nothing could be sent to them.
+    pub(crate) fn seal_device_tokens() -> Result<usize, String> {
+        let conn = Connection::get().map_err(|e| e.to_string())?;
+        let sealed: Option<String> = conn
+            .query_optional(
+                "SELECT device_token FROM devices WHERE device_token LIKE ?1 LIMIT 1",
+                params![format!("{PREFIX}%")],
+                |row| row.get(0),
+            )
+            .map_err(|e| e.to_string())?;
+        if let Some(sealed) = sealed {
+            open_stored(sealed)?;
+        }
+        if CIPHER.read().unwrap_or_else(|e| e.into_inner()).is_none() {
+            return Ok(0);
+        }
+
+        let plaintext: Vec<(i64, String)> = conn
+            .query_all(
+                "SELECT id, device_token FROM devices WHERE device_token NOT LIKE ?1",
+                params![format!("{PREFIX}%")],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .map_err(|e| e.to_string())?;
+        Connection::transaction(|| {
+            for (id, token) in &plaintext {
+                conn.execute(
+                    "UPDATE devices SET device_token = ?1 WHERE id = ?2",
+                    params![seal(token), id],
+                )?;
+            }
+            Ok::<_, SeekwelError>(())
+        })
+        .map_err(|e| e.to_string())?;
+        Ok(plaintext.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_db;
+
+    const KEY: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+
+    #[test]
+    fn test_seal_is_deterministic_and_opens() {
+        let cipher = TokenCipher::new(KEY).unwrap();
+        let sealed = cipher.seal("abc123");
+        assert!(sealed.starts_with(PREFIX), "{sealed}");
+        assert_eq!(cipher.seal("abc123"), sealed);
+        assert_ne!(cipher.seal("abc124"), sealed);
+        assert_eq!(cipher.open(&sealed[PREFIX.len()..]).unwrap(), "abc123");
+
+        let other = TokenCipher::new(&BASE64.encode([7u8; 32])).unwrap();
+        assert!(other.open(&sealed[PREFIX.len()..]).is_err());
+        assert!(TokenCipher::new("c2hvcnQ=").is_err());
+    }
+
+    #[test]
+    fn test_key_from_config() {
+        let config = |key: Option<&str>, key_command: Option<&str>| TokenEncryptionConfig {
+            key: key.map(str::to_string),
+            key_command: key_command.map(str::to_string),
+        };
+        let sealed = TokenCipher::from_config(&config(Some(KEY), None))
+            .unwrap()
+            .seal("abc");
+        let from_command =
+            TokenCipher::from_config(&config(None, Some(&format!("echo {KEY}")))).unwrap();
+        assert_eq!(from_command.seal("abc"), sealed);
+        assert!(TokenCipher::from_config(&config(None, Some("exit 1"))).is_err());
+        assert!(TokenCipher::from_config(&config(None, None)).is_err());
+        assert!(TokenCipher::from_config(&config(Some(KEY), Some("true"))).is_err());
+    }
+
+    #[test]
+    fn test_store_seals_tokens() {
+        let _db = test_db();
+        let conn = Connection::get().unwrap();
+        conn.execute(
+            "INSERT INTO devices (device_token, installation_id, environment) VALUES ('a', 'i', 'sandbox')",
+            (),
+        )
+        .unwrap();
+        let stored = || -> String {
+            conn.query_row("SELECT device_token FROM devices", (), |row| row.get(0))
+                .unwrap()
+        };
+
+        set_cipher(Some(TokenCipher::new(KEY).unwrap()));
+        assert_eq!(Database::seal_device_tokens(), Ok(1));
+        assert!(stored().starts_with(PREFIX));
+        assert_eq!(Database::seal_device_tokens(), Ok(0));
+        // Lookups and reads go through the cipher.
+        let id = Database::device_id("a").unwrap().unwrap();
+        let targets = Database::delivery_targets(None).unwrap();
+        assert_eq!((targets[0].id, targets[0].device_token.as_str()), (id, "a"));
+
+        set_cipher(None);
+        assert!(Database::seal_device_tokens().is_err());
+        assert!(Database::delivery_targets(None).is_err());
+    }
+}
@@ -0,0 +1,254 @@
+use axum::{
+    body::Bytes,
+    extract::{Path, RawQuery, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use seekwel::{connection::Connection, error::Error as SeekwelError, rusqlite::params};
+use serde::Serialize;
+
+use crate::{
+    audit::AuditContext, devices::device_not_found, filter::DeviceFilter, parse_send_request,
+    token, AppState, Database, ErrorResponse, SendResponse,
+};
+
+#[derive(Debug, Serialize)]
+pub struct Topic {
+    topic: String,
+    subscribers: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TopicsResponse {
+    topics: Vec<Topic>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceTopicsResponse {
+    device_token: String,
+    topics: Vec<String>,
+}
+
+impl Database {
+    pub(crate) fn create_topics_table(conn: &Connection) -> Result<(), SeekwelError> {
+        conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS topic_subscriptions (
+                topic TEXT NOT NULL,
+                device_id INTEGER NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+                created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                PRIMARY KEY (topic, device_id)
+            )
+            "#,
+            (),
+        )?;
+        Ok(())
+    }
+
+    fn subscribe(device_id: i64, topic: &str) -> Result<(), SeekwelError> {
+        Connection::get()?.execute(
+            "INSERT OR IGNORE INTO topic_subscriptions (topic, device_id) VALUES (?1, ?2)",
+            params![topic, device_id],
+        )?;
+        Ok(())
+    }
+
+    fn unsubscribe(device_id: i64, topic: &str) -> Result<(), SeekwelError> {
+        Connection::get()?.execute(
+            "DELETE FROM topic_subscriptions WHERE topic = ?1 AND device_id = ?2",
+            params![topic, device_id],
+        )?;
+        Ok(())
+    }
+
+    fn device_topics(device_id: i64) -> Result<Vec<String>, SeekwelError> {
+        Connection::get()?.query_all(
+            "SELECT topic FROM topic_subscriptions WHERE device_id = ?1 ORDER BY topic",
+            params![device_id],
+            |row| row.get(0),
+        )
+    }
+
+    /// Topics with the number of current devices subscribed to each.
+    fn topics() -> Result<Vec<Topic>, SeekwelError> {
+        Connection::get()?.query_all(
+            r#"
+            SELECT t.topic, COUNT(*)
+            FROM topic_subscriptions t
+            JOIN devices d ON d.id = t.device_id
+            WHERE d.superseded_at IS NULL
+            GROUP BY t.topic
+            ORDER BY t.topic
+            "#,
+            (),
+            |row| {
+                Ok(Topic {
+                    topic: row.get(0)?,
+                    subscribers: row.get(1)?,
+                })
+            },
+        )
+    }
+
+    /// Copies the subscriptions of an installation's other current tokens to
+    /// `current_token`, so a rotated token keeps its topics. The token is as
+    /// stored, sealed when token encryption is on.
+    pub(crate) fn carry_topics(
+        conn: &Connection,
+        installation_id: &str,
+        current_token: &str,
+    ) -> Result<(), SeekwelError> {
+        conn.execute(
+            r#"
+            INSERT OR IGNORE INTO topic_subscriptions (topic, device_id)
+            SELECT t.topic, (SELECT id FROM devices WHERE device_token = ?2)
+            FROM topic_subscriptions t
+            JOIN devices d ON d.id = t.device_id
+            WHERE d.installation_id = ?1 AND d.device_token != ?2 AND d.superseded_at IS NULL
+            "#,
+            params![installation_id, current_token],
+        )?;
+        Ok(())
+    }
+}
+
+pub(crate) fn is_valid_topic(topic: &str) -> bool {
+    !topic.is_empty()
+        && topic.len() <= 64
+        && topic
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+fn check_topic(topic: &str) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+    if is_valid_topic(topic) {
+        Ok(())
+    } else {
+        Err(ErrorResponse::with_status(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Topics must be 1-64 characters of letters, digits, '-', '_' or '.'",
+        ))
+    }
+}
+
+fn database_error(e: SeekwelError) -> (StatusCode, Json<ErrorResponse>) {
+    tracing::error!(error = %e, "Database error handling topic");
+    ErrorResponse::with_status(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Database error: {e}"),
+    )
+}
+
+fn device_id(device_token: &str) -> Result<i64, (StatusCode, Json<ErrorResponse>)> {
+    Database::device_id(device_token)
+        .map_err(database_error)?
+        .ok_or_else(device_not_found)
+}
+
+pub async fn list_topics(
+    State(_state): State<AppState>,
+) -> Result<Json<TopicsResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let topics = Database::topics().map_err(database_error)?;
+    Ok(Json(TopicsResponse { topics }))
+}
+
+pub async fn get_device_topics(
+    State(_state): State<AppState>,
+    Path(device_token): Path<String>,
+) -> Result<Json<DeviceTopicsResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let topics = Database::device_topics(device_id(&device_token)?).map_err(database_error)?;
+    Ok(Json(DeviceTopicsResponse {
+        device_token,
+        topics,
+    }))
+}
+
+pub async fn subscribe(
+    State(_state): State<AppState>,
+    Path((device_token, topic)): Path<(String, String)>,
+) -> Result<StatusCode, (StatusCode, Json<ErrorResponse>)> {
+    check_topic(&topic)?;
+    let device_id = device_id(&device_token)?;
+    tracing::info!(device_token = %token::logged(&device_token), topic = %topic, "Subscribing device to topic");
+    Database::subscribe(device_id, &topic).map_err(database_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn unsubscribe(
+    State(_state): State<AppState>,
+    Path((device_token, topic)): Path<(String, String)>,
+) -> Result<StatusCode, (StatusCode, Json<ErrorResponse>)> {
+    let device_id = device_id(&device_token)?;
+    tracing::info!(device_token = %token::logged(&device_token), topic = %topic, "Unsubscribing device from topic");
+    Database::unsubscribe(device_id, &topic).map_err(database_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `POST /t/:topic`: sends the body, read as for `/send`, to the topic's
+/// subscribers, so `curl -d "backup done" $PSH/t/backups` just works.
+pub async fn publish(
+    State(state): State<AppState>,
+    audit: AuditContext,
+    Path(topic): Path<String>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<SendResponse>, (StatusCode, Json<ErrorResponse>)> {
+    check_topic(&topic)?;
+    tracing::info!(topic = %topic, body_len = body.len(), "Received topic publish");
+    let mut req = parse_send_request(&headers, query.as_deref(), &body)?;
+    req.filter = Some(DeviceFilter {
+        topic: Some(topic),
+        ..req.filter.unwrap_or_default()
+    });
+    crate::send(state, audit, req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_db;
+
+    fn device(token: &str, installation_id: &str) -> i64 {
+        let conn = Connection::get().unwrap();
+        conn.execute(
+            "INSERT INTO devices (device_token, installation_id, environment) VALUES (?1, ?2, 'sandbox')",
+            params![token, installation_id],
+        )
+        .unwrap();
+        Database::device_id(token).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_subscriptions_follow_rotated_tokens() {
+        let _db = test_db();
+        let old = device("old", "install-1");
+        Database::subscribe(old, "backups").unwrap();
+        Database::subscribe(old, "backups").unwrap();
+        Database::subscribe(old, "deploys").unwrap();
+        assert_eq!(
+            Database::device_topics(old).unwrap(),
+            ["backups", "deploys"]
+        );
+
+        let new = device("new", "install-1");
+        let conn = Connection::get().unwrap();
+        Database::carry_topics(&conn, "install-1", "new").unwrap();
+        assert_eq!(
+            Database::device_topics(new).unwrap(),
+            ["backups", "deploys"]
+        );
+
+        Database::unsubscribe(new, "deploys").unwrap();
+        assert_eq!(Database::device_topics(new).unwrap(), ["backups"]);
+    }
+
+    #[test]
+    fn test_topic_names() {
+        assert!(is_valid_topic("backups"));
+        assert!(is_valid_topic("ci.main-builds_2"));
+        assert!(!is_valid_topic(""));
+        assert!(!is_valid_topic("a b"));
+        assert!(!is_valid_topic(&"x".repeat(65)));
+    }
+}
@@ -0,0 +1,181 @@
+use crate::{
+    apns, config::TruncationConfig, preview::MAX_PAYLOAD_BYTES, signing::SigningKeys, SendRequest,
+};
+
+/// A cut looks back this far for a space to end on instead of mid-word.
+const WORD_BREAK_WINDOW: usize = 40;
+
+fn payload_size(req: &SendRequest) -> usize {
+    serde_json::to_vec(&apns::payload_json(req)).map_or(0, |v| v.len())
+}
+
+/// Shortens the body so the APNs payload fits, and to `max_body_bytes`
+/// when set, leaving the title and subtitle as they are. With
+/// `store_full_body` the uncut body is kept on the request for push
+/// history.
+pub(crate) fn apply(config: &TruncationConfig, req: &mut SendRequest) {
+    apply_signed(config, req, None);
+}
+
+/// Like `apply`, then signs with `signing`'s keys and time. The signature
+/// repeats the body, so the body is cut to fit with it attached.
+pub(crate) fn apply_signed(
+    config: &TruncationConfig,
+    req: &mut SendRequest,
+    signing: Option<(&SigningKeys, u64)>,
+) {
+    let sign = |req: &mut SendRequest| {
+        if let Some((keys, signed_at)) = signing {
+            keys.sign(req, signed_at);
+        }
+    };
+    let Some(body) = req.body.clone() else {
+        sign(req);
+        return;
+    };
+    let data = req.data.clone();
+    let mut budget = config.max_body_bytes.unwrap_or(body.len()).min(body.len());
+    loop {
+        if budget < body.len() {
+            req.body = Some(cut(&body, budget, &config.ellipsis));
+        }
+        if signing.is_some() {
+            req.data.clone_from(&data);
+            sign(req);
+        }
+        let size = payload_size(req);
+        if size <= MAX_PAYLOAD_BYTES || budget == 0 {
+            break;
+        }
+        // Escaping can make the body take more of the payload than its
+        // length, so this may take a few rounds.
+        budget = budget
+            .min(body.len())
+            .saturating_sub(size - MAX_PAYLOAD_BYTES);
+    }
+    req.truncated = req.body.as_deref() != Some(body.as_str());
+    if req.truncated {
+        tracing::info!(
+            bytes = body.len(),
+            kept = req.body.as_ref().map_or(0, String::len),
+            "Truncated body"
+        );
+        if config.store_full_body {
+            req.full_body = Some(body);
+        }
+    }
+}
+
+/// `text` cut to at most `max` bytes including `ellipsis`, at a space
+/// near the end when there is one.
+fn cut(text: &str, max: usize, ellipsis: &str) -> String {
+    let mut end = max.saturating_sub(ellipsis.len()).min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    let kept = &text[..end];
+    let at_break = text[end..].starts_with(char::is_whitespace);
+    let kept = match kept.rfind(char::is_whitespace) {
+        Some(space) if !at_break && end - space <= WORD_BREAK_WINDOW => &kept[..space],
+        _ => kept,
+    };
+    format!("{}{ellipsis}", kept.trim_end())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cut() {
+        assert_eq!(cut("hello wonderful world", 14, "…"), "hello…");
+        assert_eq!(cut("abcdefghij", 6, "..."), "abc...");
+        // Never splits a character.
+        assert_eq!(cut("ééé", 4, ""), "éé");
+        let long = "x".repeat(100);
+        assert_eq!(
+            cut(&format!("a {long}"), 60, "…"),
+            format!("a {}…", &long[..55])
+        );
+    }
+
+    #[test]
+    fn test_apply() {
+        let config = TruncationConfig {
+            store_full_body: true,
+            ..Default::default()
+        };
+        let body = "word ".repeat(1000);
+        let mut req = SendRequest {
+            title: Some("Kept".to_string()),
+            body: Some(body.clone()),
+            ..Default::default()
+        };
+        apply(&config, &mut req);
+        assert!(req.truncated);
+        assert!(payload_size(&req) <= MAX_PAYLOAD_BYTES);
+        assert!(payload_size(&req) > MAX_PAYLOAD_BYTES - 100);
+        assert!(req.body.as_deref().unwrap().ends_with("word…"));
+        assert_eq!(req.title.as_deref(), Some("Kept"));
+        assert_eq!(req.full_body, Some(body));
+
+        // Quotes take two bytes each once escaped.
+        let mut quoted = SendRequest {
+            body: Some("\"".repeat(3000)),
+            ..Default::default()
+        };
+        apply(&TruncationConfig::default(), &mut quoted);
+        assert!(quoted.truncated);
+        assert!(payload_size(&quoted) <= MAX_PAYLOAD_BYTES);
+        assert_eq!(quoted.full_body, None);
+
+        let config = TruncationConfig {
+            max_body_bytes: Some(10),
+            ellipsis: "...".to_string(),
+            ..Default::default()
+        };
+        let mut short = SendRequest {
+            body: Some("disk at 95% on web-1".to_string()),
+            ..Default::default()
+        };
+        apply(&config, &mut short);
+        assert_eq!(short.body.as_deref(), Some("disk at..."));
+
+        let mut fits = SendRequest {
+            body: Some("fits".to_string()),
+            ..Default::default()
+        };
+        apply(&config, &mut fits);
+        assert!(!fits.truncated);
+        assert_eq!(fits.body.as_deref(), Some("fits"));
+    }
+
+    #[test]
+    fn test_apply_signed_leaves_room_for_the_signature() {
+        let app = crate::apps::App {
+            bundle_id: "com.example.app".to_string(),
+            encryption_key: None,
+            signing_key: Some(crate::signing::generate_key()),
+            signing_key_id: 1,
+            previous_signing_key: None,
+            created_at: String::new(),
+            updated_at: String::new(),
+            default_ttl_seconds: None,
+        };
+        let keys = SigningKeys::for_app(&app).unwrap().unwrap();
+        let mut req = SendRequest {
+            title: Some("Kept".to_string()),
+            body: Some("word \"quoted\" ".repeat(1000)),
+            ..Default::default()
+        };
+        apply_signed(&TruncationConfig::default(), &mut req, Some((&keys, 0)));
+        assert!(req.truncated);
+        assert!(payload_size(&req) <= MAX_PAYLOAD_BYTES);
+        // Signed once, over the body that went out.
+        let signature = &req.data.as_ref().unwrap()[crate::signing::SIGNATURE_KEY];
+        let signed: serde_json::Value =
+            serde_json::from_str(signature["signed"].as_str().unwrap()).unwrap();
+        assert_eq!(signed["body"].as_str(), req.body.as_deref());
+        assert_eq!(req.data.as_ref().unwrap().len(), 1);
+    }
+}
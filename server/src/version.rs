@@ -0,0 +1,21 @@
+use axum::Json;
+use serde::Serialize;
+
+/// The request and response format this server speaks. Bumped when fields
+/// are added, so a newer `psh` can tell an older server would drop them.
+pub const API_VERSION: u32 = 2;
+
+#[derive(Debug, Serialize)]
+pub struct VersionResponse {
+    version: &'static str,
+    git_hash: &'static str,
+    api_version: u32,
+}
+
+pub async fn version() -> Json<VersionResponse> {
+    Json(VersionResponse {
+        version: env!("CARGO_PKG_VERSION"),
+        git_hash: env!("GIT_HASH"),
+        api_version: API_VERSION,
+    })
+}
@@ -0,0 +1,771 @@
+use aes_gcm::{
+    aead::{rand_core::RngCore, Aead, KeyInit, OsRng},
+    Aes128Gcm, Nonce,
+};
+use async_trait::async_trait;
+use axum::{extract::State, http::StatusCode, Json};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hkdf::Hkdf;
+use p256::{
+    ecdsa::{signature::Signer, Signature, SigningKey},
+    elliptic_curve::sec1::ToEncodedPoint,
+    PublicKey, SecretKey,
+};
+use reqwest::header::{AUTHORIZATION, CONTENT_ENCODING, CONTENT_TYPE, LOCATION};
+use seekwel::{connection::Connection, error::Error as SeekwelError, rusqlite::params};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    env,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    apns_error::{ApnsErrorCode, SendError},
+    audit::{self, AuditContext},
+    devices,
+    provider::{DeliveryResult, Provider, Target},
+    token_cipher, AppState, Database, ErrorResponse, RegisterResponse, SendRequest,
+};
+
+/// Record size advertised in the aes128gcm header; one record per message.
+const RECORD_SIZE: u32 = 4096;
+/// Push services cap the encrypted body at 4096 bytes. That leaves room for
+/// the 86-byte header, the 16-byte tag and the padding delimiter.
+const MAX_PLAINTEXT_LEN: usize = RECORD_SIZE as usize - 86 - 16 - 1;
+/// How long push services keep undelivered messages when `expiration` is unset.
+const DEFAULT_TTL_SECS: u64 = 4 * 7 * 24 * 60 * 60;
+/// VAPID tokens may be valid for at most 24 hours.
+const VAPID_TOKEN_TTL_SECS: u64 = 12 * 60 * 60;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The application server's VAPID identity (RFC 8292).
+pub struct VapidKeys {
+    signing_key: SigningKey,
+    subject: String,
+}
+
+impl VapidKeys {
+    /// Reads `VAPID_PRIVATE_KEY` (base64url, 32 bytes) and `VAPID_SUBJECT`.
+    /// Returns `None` when Web Push isn't configured.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let Ok(private_key) = env::var("VAPID_PRIVATE_KEY") else {
+            return Ok(None);
+        };
+        let subject = env::var("VAPID_SUBJECT")
+            .map_err(|_| "VAPID_SUBJECT must be set when VAPID_PRIVATE_KEY is".to_string())?;
+        Self::new(&private_key, &subject).map(Some)
+    }
+
+    fn new(private_key: &str, subject: &str) -> Result<Self, String> {
+        if !subject.starts_with("mailto:") && !subject.starts_with("https:") {
+            return Err(format!(
+                "VAPID_SUBJECT must be a mailto: or https: URL, got '{subject}'"
+            ));
+        }
+        let bytes = decode_base64url(private_key)
+            .map_err(|e| format!("VAPID_PRIVATE_KEY is not valid base64url: {e}"))?;
+        let signing_key = SigningKey::from_slice(&bytes)
+            .map_err(|_| "VAPID_PRIVATE_KEY must be a 32-byte P-256 private key".to_string())?;
+        Ok(Self {
+            signing_key,
+            subject: subject.to_string(),
+        })
+    }
+
+    /// The uncompressed public key, base64url encoded, as browsers expect
+    /// for `applicationServerKey`.
+    pub fn public_key(&self) -> String {
+        URL_SAFE_NO_PAD.encode(
+            self.signing_key
+                .verifying_key()
+                .to_encoded_point(false)
+                .as_bytes(),
+        )
+    }
+
+    /// Builds the `Authorization: vapid t=..., k=...` header for `endpoint`.
+    fn authorization(&self, endpoint: &str, now: u64) -> Result<String, String> {
+        let url = reqwest::Url::parse(endpoint).map_err(|e| format!("Invalid endpoint: {e}"))?;
+        let header = URL_SAFE_NO_PAD.encode(r#"{"typ":"JWT","alg":"ES256"}"#);
+        let claims = URL_SAFE_NO_PAD.encode(
+            json!({
+                "aud": url.origin().ascii_serialization(),
+                "exp": now + VAPID_TOKEN_TTL_SECS,
+                "sub": self.subject,
+            })
+            .to_string(),
+        );
+        let signing_input = format!("{header}.{claims}");
+        let signature: Signature = self.signing_key.sign(signing_input.as_bytes());
+        Ok(format!(
+            "vapid t={signing_input}.{}, k={}",
+            URL_SAFE_NO_PAD.encode(signature.to_bytes()),
+            self.public_key()
+        ))
+    }
+}
+
+/// The browser's `PushSubscription.keys`, base64url encoded.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SubscriptionKeys {
+    p256dh: String,
+    auth: String,
+}
+
+/// `PushSubscription.toJSON()`, plus the same device details `/register` takes.
+#[derive(Debug, Deserialize)]
+pub struct SubscribeRequest {
+    endpoint: String,
+    keys: SubscriptionKeys,
+    installation_id: Option<String>,
+    device_name: Option<String>,
+    app_version: Option<String>,
+    locale: Option<String>,
+    timezone: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnsubscribeRequest {
+    endpoint: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VapidKeyResponse {
+    public_key: String,
+}
+
+/// What the service worker's `push` event receives as JSON.
+#[derive(Debug, Serialize)]
+struct WebPayload<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subtitle: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    badge: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<&'a HashMap<String, serde_json::Value>>,
+}
+
+impl<'a> From<&'a SendRequest> for WebPayload<'a> {
+    fn from(req: &'a SendRequest) -> Self {
+        WebPayload {
+            title: req.title.as_deref(),
+            subtitle: req.subtitle.as_deref(),
+            body: req.body.as_deref(),
+            badge: req.badge,
+            tag: req.collapse_id.as_deref(),
+            data: req.data.as_ref(),
+        }
+    }
+}
+
+impl Database {
+    pub(crate) fn create_webpush_table(conn: &Connection) -> Result<(), SeekwelError> {
+        conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS webpush_subscriptions (
+                device_id INTEGER PRIMARY KEY REFERENCES devices(id) ON DELETE CASCADE,
+                p256dh TEXT NOT NULL,
+                auth TEXT NOT NULL,
+                created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            )
+            "#,
+            (),
+        )?;
+        Ok(())
+    }
+
+    /// Stores a browser subscription as a `webpush` device whose token is the
+    /// endpoint URL, returning endpoints it superseded for the installation.
+    pub(crate) fn save_webpush_subscription(
+        req: &SubscribeRequest,
+    ) -> Result<Vec<String>, SeekwelError> {
+        let conn = Connection::get()?;
+        Connection::transaction(|| {
+            conn.execute(
+                r#"
+                INSERT INTO devices (
+                    device_token,
+                    installation_id,
+                    environment,
+                    platform,
+                    device_name,
+                    device_type,
+                    app_version,
+                    locale,
+                    timezone,
+                    updated_at
+                )
+                VALUES (?1, ?2, 'production', 'webpush', ?3, 'Web', ?4, ?5, ?6, CURRENT_TIMESTAMP)
+                ON CONFLICT(device_token) DO UPDATE SET
+                    installation_id = excluded.installation_id,
+                    platform = 'webpush',
+                    device_name = excluded.device_name,
+                    app_version = excluded.app_version,
+                    locale = excluded.locale,
+                    timezone = excluded.timezone,
+                    updated_at = CURRENT_TIMESTAMP,
+                    superseded_at = NULL
+                "#,
+                params![
+                    token_cipher::seal(&req.endpoint),
+                    req.installation_id,
+                    req.device_name,
+                    req.app_version,
+                    req.locale,
+                    req.timezone
+                ],
+            )?;
+            let device_id: i64 = conn.query_row(
+                "SELECT id FROM devices WHERE device_token = ?1",
+                params![token_cipher::seal(&req.endpoint)],
+                |row| row.get(0),
+            )?;
+            conn.execute(
+                r#"
+                INSERT INTO webpush_subscriptions (device_id, p256dh, auth)
+                VALUES (?1, ?2, ?3)
+                ON CONFLICT(device_id) DO UPDATE SET
+                    p256dh = excluded.p256dh,
+                    auth = excluded.auth,
+                    updated_at = CURRENT_TIMESTAMP
+                "#,
+                params![device_id, req.keys.p256dh, req.keys.auth],
+            )?;
+            Self::supersede_installation_tokens(
+                &conn,
+                req.installation_id.as_deref().unwrap_or_default(),
+                &req.endpoint,
+            )
+        })
+    }
+
+    fn webpush_keys(endpoint: &str) -> Result<Option<SubscriptionKeys>, SeekwelError> {
+        Connection::get()?.query_optional(
+            r#"
+            SELECT s.p256dh, s.auth
+            FROM webpush_subscriptions s
+            JOIN devices d ON s.device_id = d.id
+            WHERE d.device_token = ?1
+            "#,
+            params![token_cipher::seal(endpoint)],
+            |row| {
+                Ok(SubscriptionKeys {
+                    p256dh: row.get(0)?,
+                    auth: row.get(1)?,
+                })
+            },
+        )
+    }
+
+    /// Stops delivering to an endpoint, keeping its push history. Returns
+    /// whether an active subscription was found.
+    fn expire_webpush_subscription(endpoint: &str) -> Result<bool, SeekwelError> {
+        let conn = Connection::get()?;
+        Connection::transaction(|| {
+            let device_id: Option<i64> = conn.query_optional(
+                r#"
+                SELECT id FROM devices
+                WHERE device_token = ?1 AND platform = 'webpush' AND superseded_at IS NULL
+                "#,
+                params![token_cipher::seal(endpoint)],
+                |row| row.get(0),
+            )?;
+            if let Some(device_id) = device_id {
+                conn.execute(
+                    "UPDATE devices SET superseded_at = CURRENT_TIMESTAMP WHERE id = ?1",
+                    params![device_id],
+                )?;
+            }
+            Ok(device_id.is_some())
+        })
+    }
+}
+
+fn decode_base64url(value: &str) -> Result<Vec<u8>, base64::DecodeError> {
+    URL_SAFE_NO_PAD.decode(value.trim().trim_end_matches('='))
+}
+
+fn validate_subscription(req: &SubscribeRequest) -> Result<(), String> {
+    let url = reqwest::Url::parse(&req.endpoint).map_err(|e| format!("Invalid endpoint: {e}"))?;
+    if url.scheme() != "https" {
+        return Err("endpoint must be an https URL".to_string());
+    }
+    let p256dh = decode_base64url(&req.keys.p256dh)
+        .map_err(|e| format!("keys.p256dh is not valid base64url: {e}"))?;
+    PublicKey::from_sec1_bytes(&p256dh)
+        .map_err(|_| "keys.p256dh is not a P-256 public key".to_string())?;
+    let auth = decode_base64url(&req.keys.auth)
+        .map_err(|e| format!("keys.auth is not valid base64url: {e}"))?;
+    if auth.len() != 16 {
+        return Err(format!("keys.auth must be 16 bytes, got {}", auth.len()));
+    }
+    Ok(())
+}
+
+/// Encrypts `plaintext` for a subscription with RFC 8291's aes128gcm scheme.
+fn encrypt(keys: &SubscriptionKeys, plaintext: &[u8]) -> Result<Vec<u8>, String> {
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    encrypt_with(&SecretKey::random(&mut OsRng), salt, keys, plaintext)
+}
+
+fn encrypt_with(
+    as_secret: &SecretKey,
+    salt: [u8; 16],
+    keys: &SubscriptionKeys,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, String> {
+    let ua_public_bytes = decode_base64url(&keys.p256dh).map_err(|e| e.to_string())?;
+    let ua_public = PublicKey::from_sec1_bytes(&ua_public_bytes)
+        .map_err(|_| "Subscription p256dh is not a P-256 public key".to_string())?;
+    let auth_secret = decode_base64url(&keys.auth).map_err(|e| e.to_string())?;
+    let as_public = as_secret.public_key().to_encoded_point(false);
+
+    let shared = p256::ecdh::diffie_hellman(as_secret.to_nonzero_scalar(), ua_public.as_affine());
+    let mut key_info = b"WebPush: info\0".to_vec();
+    key_info.extend_from_slice(&ua_public_bytes);
+    key_info.extend_from_slice(as_public.as_bytes());
+    let mut ikm = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&auth_secret), shared.raw_secret_bytes())
+        .expand(&key_info, &mut ikm)
+        .map_err(|e| e.to_string())?;
+
+    let hkdf = Hkdf::<Sha256>::new(Some(&salt), &ikm);
+    let mut cek = [0u8; 16];
+    let mut nonce = [0u8; 12];
+    hkdf.expand(b"Content-Encoding: aes128gcm\0", &mut cek)
+        .map_err(|e| e.to_string())?;
+    hkdf.expand(b"Content-Encoding: nonce\0", &mut nonce)
+        .map_err(|e| e.to_string())?;
+
+    let mut record = plaintext.to_vec();
+    record.push(0x02);
+    let ciphertext = Aes128Gcm::new_from_slice(&cek)
+        .map_err(|e| e.to_string())?
+        .encrypt(Nonce::from_slice(&nonce), record.as_slice())
+        .map_err(|e| format!("Encryption failed: {e}"))?;
+
+    let mut body = salt.to_vec();
+    body.extend_from_slice(&RECORD_SIZE.to_be_bytes());
+    body.push(as_public.as_bytes().len() as u8);
+    body.extend_from_slice(as_public.as_bytes());
+    body.extend_from_slice(&ciphertext);
+    Ok(body)
+}
+
+/// Seconds the push service should hold the message, from the APNs-style
+/// `expiration` timestamp.
+fn ttl(expiration: Option<u64>, now: u64) -> u64 {
+    match expiration {
+        Some(expiration) => expiration.saturating_sub(now),
+        None => DEFAULT_TTL_SECS,
+    }
+}
+
+/// Maps APNs priorities (10 immediate, 5 power-considerate, 1 low) to the
+/// Web Push `Urgency` header.
+fn urgency(priority: Option<u8>) -> Option<&'static str> {
+    priority.map(|priority| match priority {
+        10.. => "high",
+        5..=9 => "normal",
+        _ => "low",
+    })
+}
+
+/// `Topic` replaces earlier undelivered messages, like `apns-collapse-id`,
+/// but must be at most 32 base64url characters.
+fn topic(collapse_id: Option<&str>) -> Option<&str> {
+    collapse_id.filter(|id| {
+        !id.is_empty()
+            && id.len() <= 32
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    })
+}
+
+fn error_for_status(status: reqwest::StatusCode, body: &str) -> SendError {
+    let code = match status.as_u16() {
+        404 | 410 => ApnsErrorCode::Unregistered,
+        413 => ApnsErrorCode::PayloadTooLarge,
+        429 => ApnsErrorCode::TooManyRequests,
+        400 => ApnsErrorCode::InvalidRequest,
+        401 | 403 => ApnsErrorCode::InvalidProviderToken,
+        500..=599 => ApnsErrorCode::ServiceUnavailable,
+        _ => ApnsErrorCode::Unknown,
+    };
+    let body = body.trim();
+    SendError {
+        code,
+        message: if body.is_empty() {
+            format!("Push service returned {status}")
+        } else {
+            format!("Push service returned {status}: {body}")
+        },
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+pub struct WebPushProvider {
+    client: reqwest::Client,
+    vapid: VapidKeys,
+}
+
+impl WebPushProvider {
+    pub fn new(vapid: VapidKeys) -> Result<Self, reqwest::Error> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+        Ok(Self { client, vapid })
+    }
+
+    pub fn public_key(&self) -> String {
+        self.vapid.public_key()
+    }
+}
+
+#[async_trait]
+impl Provider for WebPushProvider {
+    async fn send(&self, req: &SendRequest, target: Target<'_>) -> DeliveryResult {
+        let endpoint = target.token;
+        let invalid = |message: String| SendError {
+            code: ApnsErrorCode::InvalidRequest,
+            message,
+        };
+
+        let keys = Database::webpush_keys(endpoint)
+            .map_err(|e| SendError {
+                code: ApnsErrorCode::Unknown,
+                message: format!("Database error: {e}"),
+            })?
+            .ok_or_else(|| SendError {
+                code: ApnsErrorCode::Unregistered,
+                message: "No Web Push subscription for this endpoint".to_string(),
+            })?;
+
+        let payload =
+            serde_json::to_vec(&WebPayload::from(req)).map_err(|e| invalid(e.to_string()))?;
+        if payload.len() > MAX_PLAINTEXT_LEN {
+            return Err(SendError {
+                code: ApnsErrorCode::PayloadTooLarge,
+                message: format!(
+                    "Web Push payload is {} bytes, limit is {MAX_PLAINTEXT_LEN}",
+                    payload.len()
+                ),
+            });
+        }
+        let body = encrypt(&keys, &payload).map_err(invalid)?;
+
+        let now = now();
+        let authorization = self.vapid.authorization(endpoint, now).map_err(invalid)?;
+        let mut request = self
+            .client
+            .post(endpoint)
+            .header(AUTHORIZATION, authorization)
+            .header(CONTENT_ENCODING, "aes128gcm")
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .header("TTL", ttl(req.expiration, now))
+            .body(body);
+        if let Some(urgency) = urgency(req.priority) {
+            request = request.header("Urgency", urgency);
+        }
+        if let Some(topic) = topic(req.collapse_id.as_deref()) {
+            request = request.header("Topic", topic);
+        }
+
+        let response = request.send().await.map_err(|e| SendError {
+            code: if e.is_timeout() {
+                ApnsErrorCode::Timeout
+            } else {
+                ApnsErrorCode::ConnectionError
+            },
+            message: format!("Web Push request failed: {e}"),
+        })?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response
+                .headers()
+                .get(LOCATION)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .to_string());
+        }
+
+        let error = error_for_status(status, &response.text().await.unwrap_or_default());
+        if error.code == ApnsErrorCode::Unregistered {
+            tracing::info!(endpoint = %endpoint, "Push service dropped subscription, expiring it");
+            if let Err(e) = Database::expire_webpush_subscription(endpoint) {
+                tracing::error!(endpoint = %endpoint, error = %e, "Failed to expire subscription");
+            }
+        }
+        Err(error)
+    }
+}
+
+fn database_error(e: SeekwelError) -> (StatusCode, Json<ErrorResponse>) {
+    tracing::error!(error = %e, "Database error handling Web Push subscription");
+    ErrorResponse::with_status(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Database error: {e}"),
+    )
+}
+
+pub async fn vapid_public_key(
+    State(state): State<AppState>,
+) -> Result<Json<VapidKeyResponse>, (StatusCode, Json<ErrorResponse>)> {
+    match state.vapid_public_key {
+        Some(public_key) => Ok(Json(VapidKeyResponse { public_key })),
+        None => Err(ErrorResponse::with_status(
+            StatusCode::NOT_FOUND,
+            "Web Push is not configured",
+        )),
+    }
+}
+
+pub async fn subscribe(
+    State(_state): State<AppState>,
+    audit: AuditContext,
+    Json(mut req): Json<SubscribeRequest>,
+) -> Result<Json<RegisterResponse>, (StatusCode, Json<ErrorResponse>)> {
+    validate_subscription(&req).map_err(|e| {
+        tracing::warn!(endpoint = %req.endpoint, error = %e, "Rejecting Web Push subscription");
+        ErrorResponse::with_status(StatusCode::UNPROCESSABLE_ENTITY, e)
+    })?;
+    devices::normalize_locale_and_timezone(&req.endpoint, &mut req.locale, &mut req.timezone);
+
+    tracing::info!(
+        endpoint = %req.endpoint,
+        installation_id = ?req.installation_id,
+        device_name = ?req.device_name,
+        "Registering Web Push subscription"
+    );
+    let superseded = Database::save_webpush_subscription(&req).map_err(database_error)?;
+
+    audit::record(
+        &audit,
+        "webpush.subscribe",
+        format!(
+            "endpoint={} installation_id={}",
+            req.endpoint,
+            req.installation_id.as_deref().unwrap_or("-")
+        ),
+    );
+    for old_endpoint in superseded {
+        audit::record(
+            &audit,
+            "device.token_rotated",
+            format!(
+                "installation_id={} old_token={} new_token={}",
+                req.installation_id.as_deref().unwrap_or("-"),
+                old_endpoint,
+                req.endpoint
+            ),
+        );
+    }
+
+    Ok(Json(RegisterResponse {
+        success: true,
+        message: "Subscription registered successfully".to_string(),
+    }))
+}
+
+pub async fn unsubscribe(
+    State(_state): State<AppState>,
+    audit: AuditContext,
+    Json(req): Json<UnsubscribeRequest>,
+) -> Result<Json<RegisterResponse>, (StatusCode, Json<ErrorResponse>)> {
+    if !Database::expire_webpush_subscription(&req.endpoint).map_err(database_error)? {
+        return Err(ErrorResponse::with_status(
+            StatusCode::NOT_FOUND,
+            "Subscription not found",
+        ));
+    }
+    tracing::info!(endpoint = %req.endpoint, "Web Push subscription removed");
+    audit::record(
+        &audit,
+        "webpush.unsubscribe",
+        format!("endpoint={}", req.endpoint),
+    );
+    Ok(Json(RegisterResponse {
+        success: true,
+        message: "Subscription removed successfully".to_string(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_db;
+    use p256::ecdsa::{signature::Verifier, VerifyingKey};
+
+    // RFC 8291, Appendix A.
+    const AS_PRIVATE: &str = "yfWPiYE-n46HLnH0KqZOF1fJJU3MYrct3AELtAQ-oRw";
+    const UA_PUBLIC: &str =
+        "BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4";
+    const AUTH_SECRET: &str = "BTBZMqHH6r4Tts7J_aSIgg";
+    const SALT: &str = "DGv6ra1nlYgDCS1FRnbzlw";
+    const ENCRYPTED: &str = "DGv6ra1nlYgDCS1FRnbzlwAAEABBBP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A_yl95bQpu6cVPTpK4Mqgkf1CXztLVBSt2Ks3oZwbuwXPXLWyouBWLVWGNWQexSgSxsj_Qulcy4a-fN";
+
+    fn keys() -> SubscriptionKeys {
+        SubscriptionKeys {
+            p256dh: UA_PUBLIC.to_string(),
+            auth: AUTH_SECRET.to_string(),
+        }
+    }
+
+    fn subscription(endpoint: &str, installation_id: &str) -> SubscribeRequest {
+        SubscribeRequest {
+            endpoint: endpoint.to_string(),
+            keys: keys(),
+            installation_id: Some(installation_id.to_string()),
+            device_name: Some("Firefox".to_string()),
+            app_version: None,
+            locale: None,
+            timezone: None,
+        }
+    }
+
+    #[test]
+    fn test_encrypt_matches_rfc_8291_example() {
+        let as_secret = SecretKey::from_slice(&decode_base64url(AS_PRIVATE).unwrap()).unwrap();
+        let salt: [u8; 16] = decode_base64url(SALT).unwrap().try_into().unwrap();
+        let body = encrypt_with(
+            &as_secret,
+            salt,
+            &keys(),
+            b"When I grow up, I want to be a watermelon",
+        )
+        .unwrap();
+        assert_eq!(URL_SAFE_NO_PAD.encode(body), ENCRYPTED);
+    }
+
+    #[test]
+    fn test_vapid_authorization_is_signed_for_endpoint_origin() {
+        let vapid = VapidKeys::new(AS_PRIVATE, "mailto:ops@example.com").unwrap();
+        let header = vapid
+            .authorization("https://push.example.net/send/abc123", 1_700_000_000)
+            .unwrap();
+
+        let (token, key) = header
+            .strip_prefix("vapid t=")
+            .unwrap()
+            .split_once(", k=")
+            .unwrap();
+        assert_eq!(key, vapid.public_key());
+
+        let (signing_input, signature) = token.rsplit_once('.').unwrap();
+        let verifying_key = VerifyingKey::from_sec1_bytes(&decode_base64url(key).unwrap()).unwrap();
+        let signature = Signature::from_slice(&decode_base64url(signature).unwrap()).unwrap();
+        assert!(verifying_key
+            .verify(signing_input.as_bytes(), &signature)
+            .is_ok());
+
+        let claims = signing_input.split('.').nth(1).unwrap();
+        let claims: serde_json::Value =
+            serde_json::from_slice(&decode_base64url(claims).unwrap()).unwrap();
+        assert_eq!(claims["aud"], "https://push.example.net");
+        assert_eq!(claims["sub"], "mailto:ops@example.com");
+        assert_eq!(claims["exp"], 1_700_000_000 + VAPID_TOKEN_TTL_SECS);
+    }
+
+    #[test]
+    fn test_vapid_keys_reject_bad_config() {
+        assert!(VapidKeys::new(AS_PRIVATE, "ops@example.com").is_err());
+        assert!(VapidKeys::new("c2hvcnQ", "mailto:ops@example.com").is_err());
+    }
+
+    #[test]
+    fn test_validate_subscription() {
+        assert!(validate_subscription(&subscription("https://push.example.net/abc", "a")).is_ok());
+        assert!(validate_subscription(&subscription("http://push.example.net/abc", "a")).is_err());
+
+        let mut req = subscription("https://push.example.net/abc", "a");
+        req.keys.auth = URL_SAFE_NO_PAD.encode([0u8; 8]);
+        assert!(validate_subscription(&req).is_err());
+        req.keys = keys();
+        req.keys.p256dh = URL_SAFE_NO_PAD.encode([4u8; 65]);
+        assert!(validate_subscription(&req).is_err());
+    }
+
+    #[test]
+    fn test_delivery_headers() {
+        assert_eq!(ttl(None, 100), DEFAULT_TTL_SECS);
+        assert_eq!(ttl(Some(160), 100), 60);
+        assert_eq!(ttl(Some(0), 100), 0);
+
+        assert_eq!(urgency(None), None);
+        assert_eq!(urgency(Some(10)), Some("high"));
+        assert_eq!(urgency(Some(5)), Some("normal"));
+        assert_eq!(urgency(Some(1)), Some("low"));
+
+        assert_eq!(topic(Some("score-update")), Some("score-update"));
+        assert_eq!(topic(Some("has spaces")), None);
+        assert_eq!(topic(Some(&"x".repeat(33))), None);
+    }
+
+    #[test]
+    fn test_error_for_status() {
+        let error = error_for_status(reqwest::StatusCode::GONE, "");
+        assert_eq!(error.code, ApnsErrorCode::Unregistered);
+        assert_eq!(error.message, "Push service returned 410 Gone");
+        assert_eq!(
+            error_for_status(reqwest::StatusCode::PAYLOAD_TOO_LARGE, "").code,
+            ApnsErrorCode::PayloadTooLarge
+        );
+        assert_eq!(
+            error_for_status(reqwest::StatusCode::FORBIDDEN, "bad jwt").code,
+            ApnsErrorCode::InvalidProviderToken
+        );
+        assert_eq!(
+            error_for_status(reqwest::StatusCode::BAD_GATEWAY, "").code,
+            ApnsErrorCode::ServiceUnavailable
+        );
+    }
+
+    #[test]
+    fn test_subscriptions_are_delivery_targets() {
+        let _db = test_db();
+        let endpoint = "https://push.example.net/abc";
+        assert!(
+            Database::save_webpush_subscription(&subscription(endpoint, "browser-1"))
+                .unwrap()
+                .is_empty()
+        );
+
+        let targets = Database::delivery_targets(None).unwrap();
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].device_token, endpoint);
+        assert_eq!(targets[0].platform, crate::provider::Platform::WebPush);
+        assert_eq!(
+            Database::webpush_keys(endpoint).unwrap().unwrap().auth,
+            AUTH_SECRET
+        );
+
+        let renewed = "https://push.example.net/def";
+        assert_eq!(
+            Database::save_webpush_subscription(&subscription(renewed, "browser-1")).unwrap(),
+            vec![endpoint.to_string()]
+        );
+
+        assert!(Database::expire_webpush_subscription(renewed).unwrap());
+        assert!(!Database::expire_webpush_subscription(renewed).unwrap());
+        assert!(Database::delivery_targets(None).unwrap().is_empty());
+    }
+}
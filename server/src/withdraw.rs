@@ -0,0 +1,192 @@
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use seekwel::{connection::Connection, error::Error as SeekwelError, rusqlite::params};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+
+use crate::{
+    audit::{self, AuditContext},
+    deliver,
+    provider::Platform,
+    token, token_cipher, truncate, AppState, Database, DeviceSendResult, DeviceTarget,
+    ErrorResponse, SendRequest,
+};
+
+/// The custom data key of a withdrawal, for the app to remove the
+/// notification it names.
+const WITHDRAW_KEY: &str = "psh_withdraw";
+
+/// With a title or body, the push is replaced with them; without, it's
+/// withdrawn.
+#[derive(Debug, Default, Deserialize)]
+pub struct WithdrawRequest {
+    title: Option<String>,
+    body: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WithdrawResponse {
+    push_id: i64,
+    /// `withdraw` or `update`.
+    action: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    collapse_id: Option<String>,
+    result: DeviceSendResult,
+}
+
+/// A delivered push, with the device it went to.
+struct Original {
+    device: DeviceTarget,
+    apns_id: Option<String>,
+    collapse_id: Option<String>,
+}
+
+impl Database {
+    fn original_push(push_id: i64) -> Result<Option<Original>, SeekwelError> {
+        Connection::get()?.query_optional(
+            r#"
+            SELECT d.id, d.device_token, COALESCE(p.environment, d.environment), d.platform,
+                   d.device_name, d.device_type, d.os_version, d.app_version, d.locale,
+                   d.timezone, p.apns_id, p.collapse_id
+            FROM pushes p
+            JOIN devices d ON p.device_id = d.id
+            WHERE p.id = ?1
+            "#,
+            params![push_id],
+            |row| {
+                let platform: String = row.get(3)?;
+                Ok(Original {
+                    device: DeviceTarget {
+                        id: row.get(0)?,
+                        device_token: token_cipher::open(row, 1)?,
+                        environment: row.get(2)?,
+                        platform: Platform::from_db(&platform),
+                        device_name: row.get(4)?,
+                        device_type: row.get(5)?,
+                        os_version: row.get(6)?,
+                        app_version: row.get(7)?,
+                        locale: row.get(8)?,
+                        timezone: row.get(9)?,
+                        snoozed: false,
+                    },
+                    apns_id: row.get(10)?,
+                    collapse_id: row.get(11)?,
+                })
+            },
+        )
+    }
+}
+
+fn database_error(e: SeekwelError) -> (StatusCode, Json<ErrorResponse>) {
+    tracing::error!(error = %e, "Database error withdrawing push");
+    ErrorResponse::with_status(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Database error: {e}"),
+    )
+}
+
+/// A silent push carrying what to withdraw, under the original's collapse
+/// id. Removing the notification is up to the app.
+fn withdrawal(push_id: i64, original: &Original) -> SendRequest {
+    SendRequest {
+        content_available: Some(true),
+        priority: Some(5),
+        collapse_id: original.collapse_id.clone(),
+        data: Some(HashMap::from([(
+            WITHDRAW_KEY.to_string(),
+            json!({
+                "push_id": push_id,
+                "apns_id": original.apns_id,
+                "collapse_id": original.collapse_id,
+            }),
+        )])),
+        ..Default::default()
+    }
+}
+
+/// `POST /pushes/:id/withdraw`: takes back a delivered push, by psh id or
+/// APNs id. With a `title` or `body` it sends a replacement under the
+/// push's `collapse_id`, which APNs shows in place of the original; without,
+/// a background push under the same `collapse_id` tells the app to remove
+/// it. Either is recorded in push history like any other push.
+pub async fn withdraw_push(
+    State(state): State<AppState>,
+    audit: AuditContext,
+    Path(id): Path<String>,
+    body: Bytes,
+) -> Result<Json<WithdrawResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let update: WithdrawRequest = if body.is_empty() {
+        WithdrawRequest::default()
+    } else {
+        serde_json::from_slice(&body).map_err(|e| {
+            ErrorResponse::with_status(StatusCode::BAD_REQUEST, format!("Invalid JSON: {e}"))
+        })?
+    };
+    let not_found = || {
+        tracing::warn!(push = %id, "Push to withdraw not found");
+        ErrorResponse::with_status(StatusCode::NOT_FOUND, "Push not found")
+    };
+    let push_id = Database::delivered_push_id(&id)
+        .map_err(database_error)?
+        .ok_or_else(not_found)?;
+    let original = Database::original_push(push_id)
+        .map_err(database_error)?
+        .ok_or_else(not_found)?;
+
+    let (action, mut req) = if update.title.is_some() || update.body.is_some() {
+        if original.collapse_id.is_none() {
+            return Err(ErrorResponse::with_status(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "The push was sent without a collapse_id, so it can't be replaced; withdraw it without a title or body instead",
+            ));
+        }
+        let req = SendRequest {
+            title: update.title,
+            body: update.body,
+            collapse_id: original.collapse_id.clone(),
+            ..Default::default()
+        };
+        ("update", req)
+    } else {
+        ("withdraw", withdrawal(push_id, &original))
+    };
+    truncate::apply(&state.truncation, &mut req);
+    let payload_json = req
+        .data
+        .as_ref()
+        .and_then(|data| serde_json::to_string(data).ok());
+
+    let device = &original.device;
+    let (result, record) = deliver(&state, device, &req, payload_json.as_deref()).await;
+    if let Err(e) = Database::record_pushes(&[record]) {
+        tracing::error!(error = %e, "Failed to record withdrawal");
+    }
+    tracing::info!(
+        push_id = push_id,
+        action = action,
+        device_token = %token::logged(&device.device_token),
+        success = result.success,
+        "Withdrew push"
+    );
+    audit::record(
+        &audit,
+        "push.withdraw",
+        format!(
+            "push_id={push_id} action={action} device_token={} success={}",
+            token::logged(&device.device_token),
+            result.success
+        ),
+    );
+
+    Ok(Json(WithdrawResponse {
+        push_id,
+        action,
+        collapse_id: original.collapse_id,
+        result,
+    }))
+}
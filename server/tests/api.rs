@@ -0,0 +1,2398 @@
+//! End-to-end tests of the HTTP API against the in-process router, an
+//! in-memory database and mock providers.
+
+use async_trait::async_trait;
+use axum::{
+    body::{to_bytes, Body},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH},
+        Request, StatusCode,
+    },
+    Router,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use seekwel::connection::Connection;
+use serde_json::{json, Value};
+use server::{
+    apns_error::{ApnsErrorCode, SendError},
+    attachments::{Attachments, DiskStore},
+    auth::ApiKeys,
+    config::{ApiKeyConfig, CorsConfig, Role, TruncationConfig},
+    cors,
+    limits::HttpLimits,
+    mock::MockProvider,
+    provider::{DeliveryResult, Platform, Provider, ProviderRegistry, Target},
+    AppState, Database, SendRequest,
+};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::{Mutex, MutexGuard};
+use tower::ServiceExt;
+
+/// The in-memory database is process-wide, so tests take turns.
+static DB_LOCK: Mutex<()> = Mutex::const_new(());
+
+const TABLES: [&str; 16] = [
+    "leases",
+    "paused_sends",
+    "pauses",
+    "issued_keys",
+    "attachments",
+    "device_tags",
+    "api_key_usage",
+    "topic_subscriptions",
+    "background_pushes",
+    "pushes",
+    "webpush_subscriptions",
+    "devices",
+    "segments",
+    "audit_log",
+    "apps",
+    "messages",
+];
+
+struct TestApp {
+    router: Router,
+    _db: MutexGuard<'static, ()>,
+}
+
+/// Rejects every push, as APNs does for a dead token.
+struct RejectingProvider;
+
+#[async_trait]
+impl Provider for RejectingProvider {
+    async fn send(&self, _req: &SendRequest, _target: Target<'_>) -> DeliveryResult {
+        Err(SendError::new(ApnsErrorCode::BadDeviceToken))
+    }
+}
+
+/// Accepts tokens only in production, like a TestFlight build registered as
+/// sandbox.
+struct ProductionOnlyProvider;
+
+#[async_trait]
+impl Provider for ProductionOnlyProvider {
+    async fn send(&self, _req: &SendRequest, target: Target<'_>) -> DeliveryResult {
+        match target.environment {
+            "production" => Ok("apns-production".to_string()),
+            _ => Err(SendError::new(ApnsErrorCode::BadDeviceToken)),
+        }
+    }
+}
+
+/// Accepts every push after a pause, like APNs on a bad day.
+struct SlowProvider;
+
+#[async_trait]
+impl Provider for SlowProvider {
+    async fn send(&self, _req: &SendRequest, _target: Target<'_>) -> DeliveryResult {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        Ok("apns-slow".to_string())
+    }
+}
+
+async fn reset_db() -> MutexGuard<'static, ()> {
+    let guard = DB_LOCK.lock().await;
+    Database::initialize("sqlite::memory:").unwrap();
+    let conn = Connection::get().unwrap();
+    for table in TABLES {
+        conn.execute(&format!("DELETE FROM {table}"), ()).unwrap();
+    }
+    guard
+}
+
+/// An app whose APNs provider is the same mock `PSH_APNS_MODE=mock` uses.
+async fn mock_app() -> TestApp {
+    mock_app_with(|state| state).await
+}
+
+/// `mock_app` with further configuration of its state.
+async fn mock_app_with(configure: impl FnOnce(AppState) -> AppState) -> TestApp {
+    let db = reset_db().await;
+    let mock = MockProvider::new("com.example.psh".to_string());
+    let deliveries = mock.deliveries();
+    let mut providers = ProviderRegistry::default();
+    providers.register(Platform::Apns, mock);
+    TestApp {
+        router: server::router(configure(
+            AppState::new(providers, "com.example.psh").with_mock_deliveries(deliveries),
+        )),
+        _db: db,
+    }
+}
+
+async fn app_with(provider: impl Provider + 'static) -> TestApp {
+    app_with_state(provider, |state| state).await
+}
+
+/// `app_with` with further configuration of its state.
+async fn app_with_state(
+    provider: impl Provider + 'static,
+    configure: impl FnOnce(AppState) -> AppState,
+) -> TestApp {
+    let db = reset_db().await;
+    let mut providers = ProviderRegistry::default();
+    providers.register(Platform::Apns, provider);
+    TestApp {
+        router: server::router(configure(AppState::new(providers, "com.example.psh"))),
+        _db: db,
+    }
+}
+
+fn token(n: u8) -> String {
+    format!("{n:064x}")
+}
+
+impl TestApp {
+    async fn request(&self, method: &str, uri: &str, body: Option<Value>) -> (StatusCode, Value) {
+        let request = Request::builder().method(method).uri(uri);
+        let request = match body {
+            Some(body) => request
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string())),
+            None => request.body(Body::empty()),
+        }
+        .unwrap();
+        self.respond(request).await
+    }
+
+    /// Posts `body` as is, for the non-JSON content types.
+    async fn post_raw(&self, uri: &str, content_type: &str, body: &str) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        self.respond(request).await
+    }
+
+    async fn respond(&self, request: Request<Body>) -> (StatusCode, Value) {
+        let response = self.router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = serde_json::from_slice(&bytes)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&bytes).into_owned()));
+        (status, body)
+    }
+
+    async fn get(&self, uri: &str) -> (StatusCode, Value) {
+        self.request("GET", uri, None).await
+    }
+
+    async fn post(&self, uri: &str, body: Value) -> (StatusCode, Value) {
+        self.request("POST", uri, Some(body)).await
+    }
+
+    async fn register(&self, token: &str, installation_id: &str, device_type: &str) {
+        let (status, body) = self
+            .post(
+                "/register",
+                json!({
+                    "device_token": token,
+                    "installation_id": installation_id,
+                    "environment": "sandbox",
+                    "device_name": format!("Test {device_type}"),
+                    "device_type": device_type,
+                    "os_version": "18.0",
+                    "app_version": "1.0",
+                }),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+    }
+}
+
+#[tokio::test]
+async fn test_register_send_and_read_history() {
+    let app = mock_app().await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    app.register(&token(2), "install-2", "iPad").await;
+
+    let (status, body) = app
+        .post(
+            "/send",
+            json!({"title": "Hello", "body": "World", "data": {"order": 7}}),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body["sent"], 2);
+    assert_eq!(body["failed"], 0);
+
+    let (_, mock) = app.get("/mock/deliveries").await;
+    let deliveries = mock["deliveries"].as_array().unwrap();
+    assert_eq!(deliveries.len(), 2);
+    assert_eq!(deliveries[0]["device_token"], token(1));
+    assert_eq!(deliveries[0]["topic"], "com.example.psh");
+    assert_eq!(deliveries[0]["payload"]["aps"]["alert"]["title"], "Hello");
+    assert_eq!(deliveries[0]["payload"]["order"], 7);
+
+    let (status, history) = app.get("/pushes?installation_id=install-1").await;
+    assert_eq!(status, StatusCode::OK);
+    let pushes = history["pushes"].as_array().unwrap();
+    assert_eq!(pushes.len(), 1);
+    assert_eq!(pushes[0]["device_token"], token(1));
+    assert_eq!(pushes[0]["apns_id"], deliveries[0]["apns_id"]);
+
+    let (_, stats) = app.get("/stats").await;
+    assert_eq!(stats["total_devices"], 2);
+    assert_eq!(stats["total_pushes"], 2);
+    assert_eq!(
+        stats["device_types"],
+        json!([
+            {"value": "iPad", "devices": 1, "share": 0.5},
+            {"value": "iPhone", "devices": 1, "share": 0.5}
+        ])
+    );
+    assert_eq!(
+        stats["os_versions"],
+        json!([{"value": "18", "devices": 2, "share": 1.0}])
+    );
+}
+
+#[tokio::test]
+async fn test_inbox_pages_reads_and_deletes() {
+    let app = mock_app().await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    for body in ["one", "two", "three"] {
+        let (status, _) = app.post("/send", json!({"body": body})).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    let (_, page) = app.get("/pushes?installation_id=install-1&limit=2").await;
+    let bodies = |page: &Value| -> Vec<Value> {
+        page["pushes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|push| push["body"].clone())
+            .collect()
+    };
+    assert_eq!(bodies(&page), [json!("three"), json!("two")]);
+    assert_eq!(page["unread"], 3);
+    let cursor = page["next_cursor"].as_str().unwrap();
+    let (_, last) = app
+        .get(&format!(
+            "/pushes?installation_id=install-1&limit=2&cursor={cursor}"
+        ))
+        .await;
+    assert_eq!(bodies(&last), [json!("one")]);
+    assert!(last.get("next_cursor").is_none());
+
+    let newest = page["pushes"][0]["id"].as_i64().unwrap();
+    let (status, body) = app
+        .post(
+            "/pushes/read",
+            json!({"installation_id": "install-1", "ids": [newest]}),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body["unread"], 2);
+
+    let uri = format!("/pushes/{newest}?installation_id=install-2");
+    let (status, _) = app.request("DELETE", &uri, None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let uri = format!("/pushes/{newest}?installation_id=install-1");
+    let (status, _) = app.request("DELETE", &uri, None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    let (_, inbox) = app.get("/pushes?installation_id=install-1").await;
+    assert_eq!(bodies(&inbox), [json!("two"), json!("one")]);
+    assert_eq!(inbox["pushes"][0]["read_at"], Value::Null);
+    let (status, _) = app.get(&format!("/pushes/{newest}")).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_inbox_delta_sync() {
+    let app = mock_app().await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    for body in ["one", "two"] {
+        let (status, _) = app
+            .post("/send", json!({"body": body, "data": {"order": 7}}))
+            .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    let response = app
+        .router
+        .clone()
+        .oneshot(
+            Request::get("/pushes?installation_id=install-1")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let etag = response.headers()[ETAG].clone();
+    let unchanged = || {
+        Request::get("/pushes?installation_id=install-1")
+            .header(IF_NONE_MATCH, etag.clone())
+            .body(Body::empty())
+            .unwrap()
+    };
+    let (status, _) = app.respond(unchanged()).await;
+    assert_eq!(status, StatusCode::NOT_MODIFIED);
+
+    let (_, all) = app.get("/pushes?installation_id=install-1").await;
+    let oldest = all["pushes"][1]["id"].as_i64().unwrap();
+    let uri = format!("/pushes?installation_id=install-1&since_id={oldest}&compact=true");
+    let (_, new) = app.get(&uri).await;
+    let pushes = new["pushes"].as_array().unwrap();
+    assert_eq!(pushes.len(), 1);
+    assert_eq!(pushes[0]["body"], "two");
+    assert!(pushes[0].get("payload").is_none());
+    assert!(pushes[0].get("device_token").is_none());
+    assert_eq!(new["unread"], 2);
+
+    let (status, _) = app
+        .post("/pushes/read", json!({"installation_id": "install-1"}))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, body) = app.respond(unchanged()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["unread"], 0);
+    // Any earlier date gets the inbox again.
+    let (status, body) = app
+        .respond(
+            Request::get("/pushes?installation_id=install-1")
+                .header(IF_MODIFIED_SINCE, "Thu, 01 Jan 1970 00:00:00 GMT")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["unread"], 0);
+}
+
+#[tokio::test]
+async fn test_opened_pushes_feed_open_rate() {
+    let app = mock_app().await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    app.register(&token(2), "install-2", "iPad").await;
+    let (_, body) = app.post("/send", json!({"body": "hi"})).await;
+    assert_eq!(body["sent"], 2);
+    let apns_id = body["results"][0]["apns_id"].as_str().unwrap().to_string();
+
+    // The app reports by APNs id; a second report keeps the first time.
+    let (status, opened) = app
+        .post(&format!("/pushes/{apns_id}/opened"), json!({}))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{opened}");
+    let (_, again) = app
+        .post(&format!("/pushes/{apns_id}/opened"), json!({}))
+        .await;
+    assert_eq!(again["opened_at"], opened["opened_at"]);
+    // psh ids count up, so anyone could walk them; only APNs ids are taken.
+    let (status, _) = app
+        .post(&format!("/pushes/{}/opened", opened["id"]), json!({}))
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (_, detail) = app.get(&format!("/pushes/{}", opened["id"])).await;
+    assert_eq!(detail["opened_at"], opened["opened_at"]);
+    let (_, stats) = app.get("/stats").await;
+    assert_eq!(stats["opened_pushes"], 1);
+    assert_eq!(stats["open_rate"], 0.5);
+
+    let (status, _) = app.post("/pushes/999/opened", json!({})).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_send_targets_filters_and_segments() {
+    let app = mock_app().await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    app.register(&token(2), "install-2", "iPad").await;
+
+    let (status, body) = app
+        .post(
+            "/send",
+            json!({"body": "tablets", "filter": {"device_type": "ipad"}}),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body["sent"], 1);
+    assert_eq!(body["results"][0]["device_token"], token(2));
+
+    let (status, _) = app
+        .post(
+            "/segments",
+            json!({"name": "phones", "filter": {"device_type": "iPhone"}}),
+        )
+        .await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (_, body) = app
+        .post("/send", json!({"body": "phones", "segment": "phones"}))
+        .await;
+    assert_eq!(body["sent"], 1);
+    assert_eq!(body["results"][0]["device_token"], token(1));
+
+    let (status, body) = app
+        .post(
+            "/send",
+            json!({"body": "x", "filter": {"device_type": "Watch"}}),
+        )
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["error"], "No devices match filter");
+}
+
+#[tokio::test]
+async fn test_message_key_sends_each_device_its_locale() {
+    let app = mock_app().await;
+    for (n, locale) in [(1, Some("fr_CA")), (2, Some("en-US")), (3, None)] {
+        let (status, _) = app
+            .post(
+                "/register",
+                json!({
+                    "device_token": token(n),
+                    "installation_id": format!("install-{n}"),
+                    "environment": "sandbox",
+                    "locale": locale,
+                }),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    let (status, body) = app
+        .request(
+            "PUT",
+            "/messages/order.shipped",
+            Some(json!({"locales": {
+                "en": {"title": "Shipped", "body": "Your order is on its way"},
+                "fr": {"title": "Expédiée", "body": "Votre commande est en route"},
+            }})),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+
+    let (status, body) = app
+        .post("/send", json!({"message_key": "order.shipped"}))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body["sent"], 3);
+
+    let (_, mock) = app.get("/mock/deliveries").await;
+    let alerts: Vec<_> = mock["deliveries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|delivery| delivery["payload"]["aps"]["alert"].clone())
+        .collect();
+    assert_eq!(alerts[0]["title"], "Expédiée");
+    assert_eq!(alerts[1]["body"], "Your order is on its way");
+    assert_eq!(alerts[2]["loc-key"], "order.shipped");
+    assert_eq!(alerts[2]["title-loc-key"], "order.shipped.title");
+
+    let (_, history) = app.get(&format!("/devices/{}/pushes", token(1))).await;
+    assert_eq!(history["pushes"][0]["title"], "Expédiée");
+
+    let (status, body) = app.post("/send", json!({"message_key": "missing"})).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["error"], "Message not found: missing");
+}
+
+#[tokio::test]
+async fn test_devices_carry_locale_and_timezone() {
+    let app = mock_app().await;
+    for (n, timezone) in [(1, "America/New_York"), (2, "Asia/Tokyo")] {
+        let (status, _) = app
+            .post(
+                "/register",
+                json!({
+                    "device_token": token(n),
+                    "installation_id": format!("install-{n}"),
+                    "environment": "sandbox",
+                    "locale": "en_US",
+                    "timezone": timezone,
+                }),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    let (status, body) = app.get("/devices?timezone=Asia/*").await;
+    assert_eq!(status, StatusCode::OK);
+    let devices = body["devices"].as_array().unwrap();
+    assert_eq!(devices.len(), 1);
+    assert_eq!(devices[0]["device_token"], token(2));
+    assert_eq!(devices[0]["locale"], "en-us");
+    assert_eq!(devices[0]["timezone"], "Asia/Tokyo");
+
+    let (_, body) = app
+        .post(
+            "/send",
+            json!({"body": "x", "filter": {"locale": "en", "timezone": "America/*"}}),
+        )
+        .await;
+    assert_eq!(body["sent"], 1);
+    assert_eq!(body["results"][0]["device_token"], token(1));
+}
+
+#[tokio::test]
+async fn test_polled_reads_honor_if_none_match() {
+    let app = mock_app().await;
+    app.register(&token(1), "install-1", "iPhone").await;
+
+    let conditional_get = |etag: Option<String>| {
+        let mut request = Request::builder().uri("/devices");
+        if let Some(etag) = etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        app.router
+            .clone()
+            .oneshot(request.body(Body::empty()).unwrap())
+    };
+    let etag_of = |response: &axum::response::Response| {
+        response.headers()[ETAG].to_str().unwrap().to_string()
+    };
+
+    let first = conditional_get(None).await.unwrap();
+    assert_eq!(first.status(), StatusCode::OK);
+    let etag = etag_of(&first);
+
+    let unchanged = conditional_get(Some(etag.clone())).await.unwrap();
+    assert_eq!(unchanged.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(etag_of(&unchanged), etag);
+    let body = to_bytes(unchanged.into_body(), usize::MAX).await.unwrap();
+    assert!(body.is_empty());
+
+    app.register(&token(2), "install-2", "iPad").await;
+    let changed = conditional_get(Some(etag.clone())).await.unwrap();
+    assert_eq!(changed.status(), StatusCode::OK);
+    assert_ne!(etag_of(&changed), etag);
+    let body = to_bytes(changed.into_body(), usize::MAX).await.unwrap();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["devices"].as_array().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn test_background_pushes_over_budget_warn_or_defer() {
+    let app = mock_app().await;
+    app.register(&token(1), "install-1", "iPhone").await;
+
+    for _ in 0..3 {
+        let (status, body) = app.post("/send", json!({"content_available": true})).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert!(body["results"][0].get("warning").is_none(), "{body}");
+    }
+
+    let (status, body) = app.post("/send", json!({"content_available": true})).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body["sent"], 1);
+    let warning = body["results"][0]["warning"].as_str().unwrap();
+    assert!(warning.contains("3 background pushes"), "{warning}");
+
+    let (status, body) = app
+        .post("/send", json!({"title": "Hi", "body": "there"}))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert!(body["results"][0].get("warning").is_none(), "{body}");
+
+    let (status, body) = app
+        .post(
+            "/send",
+            json!({"content_available": true, "defer_throttled": true}),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body["sent"], 0);
+    assert_eq!(body["deferred"], 1);
+    assert!(body["results"][0]["deferred_until"].as_u64().unwrap() % 3600 == 0);
+    assert!(body["results"][0]["apns_id"].is_null());
+}
+
+#[tokio::test]
+async fn test_actions_are_delivered_and_kept_in_history() {
+    let app = mock_app().await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    let actions = json!([
+        {"id": "reply", "title": "Reply", "text_input": {"button_title": "Send"}},
+        {"id": "delete", "title": "Delete", "destructive": true}
+    ]);
+
+    let (status, body) = app
+        .post("/send", json!({"title": "Hi", "actions": actions}))
+        .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{body}");
+
+    let (status, body) = app
+        .post(
+            "/send",
+            json!({"title": "Hi", "category": "MESSAGE", "actions": actions, "data": {"thread": 7}}),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+
+    let (_, deliveries) = app.get("/mock/deliveries").await;
+    let payload = &deliveries["deliveries"][0]["payload"];
+    assert_eq!(payload["psh_actions"], actions, "{deliveries}");
+    assert_eq!(payload["thread"], 7);
+
+    let (_, pushes) = app.get("/pushes?installation_id=install-1").await;
+    let id = pushes["pushes"][0]["id"].as_i64().unwrap();
+    let (status, detail) = app.get(&format!("/pushes/{id}")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(detail["actions"], actions);
+}
+
+#[tokio::test]
+async fn test_sample_percent_picks_a_stable_subset() {
+    let app = mock_app().await;
+    for n in 1..=20 {
+        app.register(&token(n), &format!("install-{n}"), "iPhone")
+            .await;
+    }
+
+    let (status, _) = app
+        .post("/send", json!({"body": "hi", "sample_percent": 0}))
+        .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+    let canary = json!({"body": "hi", "collapse_id": "launch", "sample_percent": 30});
+    let sent_to = |body: Value| -> Vec<Value> {
+        body["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|result| result["device_token"].clone())
+            .collect()
+    };
+    let (status, first) = app.post("/send", canary.clone()).await;
+    assert_eq!(status, StatusCode::OK, "{first}");
+    let first = sent_to(first);
+    assert!(!first.is_empty() && first.len() < 20, "{first:?}");
+    let (_, second) = app.post("/send", canary).await;
+    assert_eq!(sent_to(second), first);
+
+    let (_, all) = app
+        .post(
+            "/send",
+            json!({"body": "hi", "collapse_id": "launch", "sample_percent": 100}),
+        )
+        .await;
+    assert_eq!(all["sent"], 20);
+}
+
+#[tokio::test]
+async fn test_duplicate_pushes_within_window_are_skipped() {
+    let app = mock_app_with(|state| state.with_dedup_window(Duration::from_secs(600))).await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    let push = json!({"title": "Deploy", "body": "done", "data": {"sha": "abc", "n": 1}});
+
+    let (status, body) = app.post("/send", push.clone()).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body["sent"], 1);
+
+    let (status, body) = app.post("/send", push).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body["sent"], 0);
+    assert_eq!(body["skipped"], 1);
+    assert_eq!(body["results"][0]["skipped_duplicate"], true);
+
+    app.register(&token(2), "install-2", "iPad").await;
+    let (_, body) = app
+        .post(
+            "/send",
+            json!({"title": "Deploy", "body": "done", "data": {"n": 1, "sha": "abc"}}),
+        )
+        .await;
+    assert_eq!(body["sent"], 1, "{body}");
+    assert_eq!(body["skipped"], 1);
+
+    let (_, body) = app
+        .post("/send", json!({"title": "Deploy", "body": "done again"}))
+        .await;
+    assert_eq!(body["sent"], 2, "{body}");
+    assert!(body.get("skipped").is_none());
+}
+
+#[tokio::test]
+async fn test_preview_renders_without_sending() {
+    let app = mock_app().await;
+    app.register(&token(1), "install-1", "iPhone").await;
+
+    let (status, preview) = app
+        .post(
+            "/preview",
+            json!({"title": "Hi", "body": "there", "content_available": true}),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{preview}");
+    assert_eq!(preview["payload"]["aps"]["alert"]["body"], "there");
+    assert_eq!(preview["headers"]["apns-push-type"], "background");
+    assert_eq!(preview["lock_screen"]["title"], "Hi");
+    assert!(preview["warnings"][0]
+        .as_str()
+        .unwrap()
+        .starts_with("background push with alert fields"));
+
+    let (status, preview) = app.get("/preview?title=Hi&badge=3").await;
+    assert_eq!(status, StatusCode::OK, "{preview}");
+    assert_eq!(preview["summary"], "Alert: Hi (badge 3)");
+
+    let (status, _) = app.post("/preview", json!({"priority": 11})).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+    let (_, mock) = app.get("/mock/deliveries").await;
+    assert!(mock["deliveries"].as_array().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_request_ids_reach_responses_errors_and_audit_log() {
+    let app = mock_app().await;
+    app.register(&token(1), "install-1", "iPhone").await;
+
+    let send = |request_id: Option<&str>, body: Value| {
+        let mut request = Request::builder()
+            .method("POST")
+            .uri("/send")
+            .header(CONTENT_TYPE, "application/json");
+        if let Some(request_id) = request_id {
+            request = request.header("x-request-id", request_id);
+        }
+        app.router
+            .clone()
+            .oneshot(request.body(Body::from(body.to_string())).unwrap())
+    };
+    let json_body = |response: axum::response::Response| async {
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice::<Value>(&bytes).unwrap()
+    };
+
+    let response = send(Some("deploy-42"), json!({"title": "Hi"}))
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["x-request-id"], "deploy-42");
+    let body = json_body(response).await;
+    assert_eq!(body["request_id"], "deploy-42");
+
+    let (_, audit) = app.get("/audit?action=send").await;
+    assert_eq!(audit["entries"][0]["request_id"], "deploy-42");
+
+    let response = send(None, json!({"body": "x", "segment": "missing"}))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let generated = response.headers()["x-request-id"]
+        .to_str()
+        .unwrap()
+        .to_string();
+    assert_eq!(generated.len(), 16);
+    let body = json_body(response).await;
+    assert_eq!(body["error"], "Segment not found: missing");
+    assert_eq!(body["request_id"], generated);
+}
+
+#[tokio::test]
+async fn test_rotated_tokens_stop_receiving_sends() {
+    let app = mock_app().await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    app.register(&token(9), "install-1", "iPhone").await;
+
+    let (_, body) = app.post("/send", json!({"body": "hi"})).await;
+    assert_eq!(body["sent"], 1);
+    assert_eq!(body["results"][0]["device_token"], token(9));
+}
+
+#[tokio::test]
+async fn test_disabled_devices_are_muted() {
+    let app = mock_app().await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    app.register(&token(2), "install-2", "iPad").await;
+
+    let uri = format!("/devices/{}", token(1));
+    let (status, device) = app
+        .request("PATCH", &uri, Some(json!({"enabled": false})))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{device}");
+    assert_eq!(device["enabled"], false);
+
+    let (_, body) = app.post("/send", json!({"body": "hi"})).await;
+    assert_eq!(body["sent"], 1);
+    assert_eq!(body["results"][0]["device_token"], token(2));
+
+    // Still listed, with its history intact.
+    let (_, list) = app.get("/devices").await;
+    assert_eq!(list["devices"][0]["enabled"], false);
+    let (status, _) = app.get(&format!("{uri}/pushes")).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (_, device) = app
+        .request("PATCH", &uri, Some(json!({"enabled": true})))
+        .await;
+    assert_eq!(device["enabled"], true);
+    let (_, body) = app.post("/send", json!({"body": "hi"})).await;
+    assert_eq!(body["sent"], 2);
+
+    let (status, _) = app
+        .request("PATCH", "/devices/missing", Some(json!({"enabled": false})))
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (_, audit) = app.get("/audit").await;
+    let actions: Vec<_> = audit["entries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["action"].as_str().unwrap())
+        .collect();
+    assert!(actions.contains(&"device.disable") && actions.contains(&"device.enable"));
+}
+
+#[tokio::test]
+async fn test_registration_ttl() {
+    let app = mock_app().await;
+    let registration = |expires_in: serde_json::Value| {
+        json!({
+            "device_token": token(1),
+            "installation_id": "install-1",
+            "environment": "sandbox",
+            "expires_in": expires_in,
+        })
+    };
+
+    for invalid in [json!(0), json!(40_000_000)] {
+        let (status, _) = app.post("/register", registration(invalid)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    let (status, body) = app.post("/register", registration(json!(3600))).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let (_, list) = app.get("/devices").await;
+    assert!(list["devices"][0]["expires_at"].is_string());
+    let (_, body) = app.post("/send", json!({"body": "hi"})).await;
+    assert_eq!(body["sent"], 1);
+
+    // Registering again without a TTL keeps the device indefinitely.
+    app.post("/register", registration(json!(null))).await;
+    let (_, list) = app.get("/devices").await;
+    assert!(list["devices"][0].get("expires_at").is_none());
+}
+
+#[tokio::test]
+async fn test_push_type_and_topic_override() {
+    let app = mock_app().await;
+    app.register(&token(1), "install-1", "iPhone").await;
+
+    let sends = [
+        json!({"body": "hi"}),
+        json!({"content_available": true}),
+        json!({"push_type": "liveactivity", "data": {"content-state": {"score": 2}}}),
+        json!({"body": "hi", "topic": "com.example.psh.widgets"}),
+    ];
+    for send in sends {
+        let (status, body) = app.post("/send", send).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+    }
+    let (_, mock) = app.get("/mock/deliveries").await;
+    let sent: Vec<_> = mock["deliveries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|d| {
+            (
+                d["push_type"].as_str().unwrap(),
+                d["topic"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        sent,
+        [
+            ("alert", "com.example.psh"),
+            ("background", "com.example.psh"),
+            ("liveactivity", "com.example.psh.push-type.liveactivity"),
+            ("alert", "com.example.psh.widgets"),
+        ]
+    );
+
+    let (status, _) = app
+        .post("/send", json!({"body": "hi", "push_type": "banner"}))
+        .await;
+    assert!(status.is_client_error());
+    let (status, _) = app
+        .post("/send", json!({"body": "hi", "topic": "com.example psh"}))
+        .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn test_broadcast_channels() {
+    let app = mock_app().await;
+
+    let (status, channel) = app
+        .post(
+            "/channels",
+            json!({"environment": "sandbox", "message_storage_policy": "most_recent"}),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{channel}");
+    let id = channel["channel_id"].as_str().unwrap().to_string();
+    assert_eq!(channel["message_storage_policy"], "most_recent");
+
+    let (_, list) = app.get("/channels?environment=sandbox").await;
+    assert_eq!(list["channels"], json!([id]));
+    let (_, list) = app.get("/channels").await;
+    assert_eq!(list["environment"], "production");
+    assert_eq!(list["channels"], json!([]));
+    let (status, _) = app.get(&format!("/channels/{id}")).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (status, body) = app
+        .post(
+            &format!("/channels/{id}/send?environment=sandbox"),
+            json!({"event": "update", "content_state": {"score": "2-1"}}),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let (_, mock) = app.get("/mock/deliveries").await;
+    let delivery = &mock["deliveries"][0];
+    assert_eq!(delivery["channel_id"], id);
+    assert_eq!(delivery["apns_id"], body["apns_request_id"]);
+    assert_eq!(delivery["push_type"], "liveactivity");
+    assert_eq!(delivery["payload"]["aps"]["content-state"]["score"], "2-1");
+    assert!(delivery["payload"]["aps"]["timestamp"].is_u64());
+
+    let (status, _) = app
+        .post(
+            &format!("/channels/{id}/send?environment=sandbox"),
+            json!({"event": "update", "filter": {"device_type": "iPad"}}),
+        )
+        .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+    let uri = format!("/channels/{id}?environment=sandbox");
+    let (status, _) = app.request("DELETE", &uri, None).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = app.get(&uri).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_snoozed_devices_only_get_critical_pushes() {
+    let app = mock_app().await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    app.register(&token(2), "install-2", "iPad").await;
+
+    let uri = format!("/devices/{}", token(1));
+    let (status, device) = app
+        .request("PATCH", &uri, Some(json!({"snooze_seconds": 7200})))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{device}");
+    assert!(device["snoozed_until"].is_string());
+
+    let (_, body) = app.post("/send", json!({"body": "deploy done"})).await;
+    assert_eq!(body["sent"], 1);
+    assert_eq!(body["snoozed"], 1);
+    assert_eq!(body["results"][0]["device_token"], token(1));
+    assert_eq!(body["results"][0]["skipped_snoozed"], true);
+
+    let (_, body) = app
+        .post(
+            "/send",
+            json!({"body": "site down", "interruption_level": "critical"}),
+        )
+        .await;
+    assert_eq!(body["sent"], 2);
+    assert!(body.get("snoozed").is_none());
+
+    let (_, device) = app
+        .request("PATCH", &uri, Some(json!({"snooze_seconds": 0})))
+        .await;
+    assert!(device.get("snoozed_until").is_none());
+    let (_, body) = app.post("/send", json!({"body": "hi"})).await;
+    assert_eq!(body["sent"], 2);
+
+    let (status, _) = app
+        .request("PATCH", &uri, Some(json!({"snooze_seconds": 40_000_000})))
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_tags_target_sends() {
+    let app = mock_app().await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    app.register(&token(2), "install-2", "iPad").await;
+
+    let uri = format!("/devices/{}/tags", token(1));
+    let (status, body) = app.post(&uri, json!({"tier": "beta", "team": "ios"})).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let (_, body) = app.post(&uri, json!({"team": null})).await;
+    assert_eq!(body["tags"], json!({"tier": "beta"}));
+    let (status, _) = app.post(&uri, json!({"tier": "a,b"})).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+    let (_, body) = app
+        .post(
+            "/send",
+            json!({"body": "beta build", "filter": {"tags": {"tier": "beta"}}}),
+        )
+        .await;
+    assert_eq!(body["sent"], 1);
+    assert_eq!(body["results"][0]["device_token"], token(1));
+
+    let (_, list) = app.get("/devices?tags=tier%3Dbeta").await;
+    let devices = list["devices"].as_array().unwrap();
+    assert_eq!(devices.len(), 1);
+    assert_eq!(devices[0]["tags"], json!({"tier": "beta"}));
+}
+
+#[tokio::test]
+async fn test_templates_render_per_device() {
+    let app = mock_app().await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    app.register(&token(2), "install-2", "iPad").await;
+    let uri = format!("/devices/{}/tags", token(2));
+    app.post(&uri, json!({"overdue": "true"})).await;
+
+    let body = "Hi {{device_name}}{{#if overdue}}, order {{data.order}} is late{{else}}!{{/if}}";
+    let (status, response) = app
+        .post("/send", json!({"body": body, "data": {"order": 7}}))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{response}");
+
+    let (_, mock) = app.get("/mock/deliveries").await;
+    let bodies: Vec<_> = mock["deliveries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|delivery| delivery["payload"]["aps"]["alert"]["body"].clone())
+        .collect();
+    assert_eq!(
+        bodies,
+        [
+            json!("Hi Test iPhone!"),
+            json!("Hi Test iPad, order 7 is late")
+        ]
+    );
+
+    let (status, response) = app
+        .post("/send", json!({"body": "{{#if overdue}}late"}))
+        .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(response["error"]
+        .as_str()
+        .unwrap()
+        .contains("body template"));
+}
+
+#[tokio::test]
+async fn test_collapse_id_template_renders_from_data() {
+    let app = mock_app().await;
+    app.register(&token(1), "install-1", "iPhone").await;
+
+    for host in ["db-1", "db-2"] {
+        let (status, response) = app
+            .post(
+                "/send",
+                json!({"body": "Disk full", "collapse_id": "alert-{{host}}", "data": {"host": host}}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{response}");
+    }
+
+    let (_, mock) = app.get("/mock/deliveries").await;
+    let ids: Vec<_> = mock["deliveries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|delivery| delivery["collapse_id"].clone())
+        .collect();
+    assert_eq!(ids, [json!("alert-db-1"), json!("alert-db-2")]);
+}
+
+#[tokio::test]
+async fn test_withdraw_push_replaces_it_under_its_collapse_id() {
+    let app = mock_app().await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    app.post(
+        "/send",
+        json!({"body": "Disk full", "collapse_id": "alert-db-1"}),
+    )
+    .await;
+    app.post("/send", json!({"body": "No collapse id"})).await;
+    let (_, history) = app.get("/pushes?installation_id=install-1").await;
+    let id_of = |body: &str| {
+        history["pushes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|push| push["body"] == body)
+            .unwrap()["id"]
+            .clone()
+    };
+    let (collapsed, plain) = (id_of("Disk full"), id_of("No collapse id"));
+
+    let (status, response) = app
+        .request("POST", &format!("/pushes/{collapsed}/withdraw"), None)
+        .await;
+    assert_eq!(status, StatusCode::OK, "{response}");
+    assert_eq!(response["action"], "withdraw");
+    assert_eq!(response["result"]["success"], true);
+    let (_, mock) = app.get("/mock/deliveries").await;
+    let last = mock["deliveries"]
+        .as_array()
+        .unwrap()
+        .last()
+        .unwrap()
+        .clone();
+    assert_eq!(last["collapse_id"], "alert-db-1");
+    assert_eq!(last["push_type"], "background");
+    assert_eq!(last["payload"]["psh_withdraw"]["push_id"], collapsed);
+
+    let uri = format!("/pushes/{collapsed}/withdraw");
+    let (status, response) = app.post(&uri, json!({"body": "Disk ok"})).await;
+    assert_eq!(status, StatusCode::OK, "{response}");
+    assert_eq!(response["action"], "update");
+    let (_, mock) = app.get("/mock/deliveries").await;
+    let last = mock["deliveries"]
+        .as_array()
+        .unwrap()
+        .last()
+        .unwrap()
+        .clone();
+    assert_eq!(last["collapse_id"], "alert-db-1");
+    assert_eq!(last["payload"]["aps"]["alert"]["body"], "Disk ok");
+
+    let uri = format!("/pushes/{plain}/withdraw");
+    let (status, _) = app.post(&uri, json!({"body": "Disk ok"})).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let (status, _) = app.request("POST", "/pushes/999/withdraw", None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_long_bodies_are_truncated() {
+    let app = mock_app_with(|state| {
+        state.with_truncation(TruncationConfig {
+            store_full_body: true,
+            ..Default::default()
+        })
+    })
+    .await;
+    app.register(&token(1), "install-1", "iPhone").await;
+
+    let body = "log line ".repeat(600);
+    let (status, response) = app
+        .post("/send", json!({"title": "Nightly", "body": body}))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{response}");
+    assert_eq!(response["sent"], 1);
+    assert_eq!(response["results"][0]["truncated"], true);
+
+    let (_, mock) = app.get("/mock/deliveries").await;
+    let alert = &mock["deliveries"][0]["payload"]["aps"]["alert"];
+    assert_eq!(alert["title"], "Nightly");
+    let sent = alert["body"].as_str().unwrap();
+    assert!(sent.len() < body.len());
+    assert!(sent.ends_with("log…") || sent.ends_with("line…"), "{sent}");
+
+    let (_, pushes) = app.get("/pushes?installation_id=install-1").await;
+    let id = pushes["pushes"][0]["id"].as_i64().unwrap();
+    let (_, detail) = app.get(&format!("/pushes/{id}")).await;
+    assert_eq!(detail["full_body"], body);
+}
+
+#[tokio::test]
+async fn test_attachments_are_linked_from_pushes() {
+    let dir = std::env::temp_dir().join(format!("psh-attachments-{}", std::process::id()));
+    let app = mock_app_with(|state| {
+        state.with_attachments(
+            Attachments::new(Arc::new(DiskStore::new(&dir)), "secret")
+                .with_public_url("https://psh.example/"),
+        )
+    })
+    .await;
+    app.register(&token(1), "install-1", "iPhone").await;
+
+    let (status, upload) = app
+        .post_raw("/attachments", "text/plain", "disk full on web-1")
+        .await;
+    assert_eq!(status, StatusCode::OK, "{upload}");
+    assert_eq!(upload["content_type"], "text/plain");
+    assert_eq!(upload["size_bytes"], 18);
+    let id = upload["id"].as_str().unwrap();
+    let url = upload["url"].as_str().unwrap();
+    assert!(id.ends_with(".txt"));
+
+    let (status, response) = app
+        .post("/send", json!({"body": "See log", "attachment": id}))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{response}");
+    let (_, mock) = app.get("/mock/deliveries").await;
+    let payload = &mock["deliveries"][0]["payload"];
+    assert_eq!(payload["aps"]["mutable-content"], 1);
+    assert_eq!(payload["psh_attachment"]["url"], url);
+    assert_eq!(payload["psh_attachment"]["content_type"], "text/plain");
+
+    let path = url.strip_prefix("https://psh.example").unwrap();
+    assert_eq!(
+        app.get(path).await,
+        (StatusCode::OK, json!("disk full on web-1"))
+    );
+    let tampered = path.replace("expires=", "expires=1");
+    assert_eq!(app.get(&tampered).await.0, StatusCode::FORBIDDEN);
+    let unsigned = format!("/attachments/{id}");
+    assert_eq!(app.get(&unsigned).await.0, StatusCode::FORBIDDEN);
+
+    // Downloads are never sniffed, and only media is shown inline.
+    let download = |path: String| {
+        app.router
+            .clone()
+            .oneshot(Request::get(path).body(Body::empty()).unwrap())
+    };
+    let response = download(path.to_string()).await.unwrap();
+    assert_eq!(response.headers()["x-content-type-options"], "nosniff");
+    assert_eq!(response.headers()["content-disposition"], "attachment");
+
+    // Types that could run as script on this origin are stored as downloads.
+    let (_, html) = app
+        .post_raw("/attachments", "text/html", "<script>alert(1)</script>")
+        .await;
+    assert_eq!(html["content_type"], "application/octet-stream");
+    assert!(html["id"].as_str().unwrap().ends_with(".bin"));
+    let (_, image) = app.post_raw("/attachments", "image/png", "png").await;
+    let image_url = image["url"].as_str().unwrap();
+    let response = download(image_url.replace("https://psh.example", ""))
+        .await
+        .unwrap();
+    assert_eq!(response.headers()[CONTENT_TYPE], "image/png");
+    assert!(!response.headers().contains_key("content-disposition"));
+
+    let (status, _) = app
+        .post("/send", json!({"body": "x", "attachment": "missing.png"}))
+        .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn test_priority_maps_to_apns_levels() {
+    let app = mock_app().await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    for priority in [1, 7, 10] {
+        let (status, _) = app
+            .post("/send", json!({"body": "x", "priority": priority}))
+            .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    let (_, mock) = app.get("/mock/deliveries").await;
+    let priorities: Vec<_> = mock["deliveries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|delivery| delivery["priority"].as_u64().unwrap())
+        .collect();
+    // APNs device sends have no 1, so low power goes out at 5.
+    assert_eq!(priorities, [5, 5, 10]);
+}
+
+#[tokio::test]
+async fn test_device_history_respects_limit() {
+    let app = mock_app().await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    for title in ["one", "two", "three"] {
+        let (status, _) = app.post("/send", json!({"title": title})).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    let (status, body) = app
+        .get(&format!("/devices/{}/pushes?limit=2", token(1)))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let titles: Vec<_> = body["pushes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|push| push["title"].as_str().unwrap())
+        .collect();
+    assert_eq!(titles, ["three", "two"]);
+
+    let (status, _) = app.get(&format!("/devices/{}/pushes", token(2))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_provider_failures_are_reported_and_recorded() {
+    let app = app_with(RejectingProvider).await;
+    app.register(&token(1), "install-1", "iPhone").await;
+
+    let (status, body) = app.post("/send", json!({"title": "Hi"})).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["success"], false);
+    assert_eq!(body["failed"], 1);
+    assert_eq!(body["results"][0]["error_code"], "BadDeviceToken");
+
+    let (_, history) = app.get(&format!("/devices/{}/pushes", token(1))).await;
+    assert_eq!(history["pushes"][0]["status"], "failed");
+    assert_eq!(history["pushes"][0]["error_code"], "BadDeviceToken");
+
+    let (status, _) = app.get("/mock/deliveries").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_bad_tokens_fall_back_to_the_other_environment() {
+    let app = app_with(ProductionOnlyProvider).await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    let (_, body) = app.post("/send", json!({"title": "Hi"})).await;
+    assert_eq!(body["failed"], 1, "fallback is off by default");
+    drop(app);
+
+    let app = app_with_state(ProductionOnlyProvider, |state| {
+        state.with_environment_fallback()
+    })
+    .await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    let (status, body) = app.post("/send", json!({"title": "Hi"})).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["sent"], 1);
+    assert_eq!(body["results"][0]["apns_id"], "apns-production");
+    assert_eq!(body["results"][0]["fallback_environment"], "production");
+    assert_eq!(body["results"][0]["environment_corrected"], true);
+
+    let (_, history) = app.get(&format!("/devices/{}/pushes", token(1))).await;
+    let id = history["pushes"][0]["id"].as_i64().unwrap();
+    let (_, detail) = app.get(&format!("/pushes/{id}")).await;
+    assert_eq!(detail["environment"], "production");
+
+    // The device now sends straight to production.
+    let (_, devices) = app.get("/devices").await;
+    assert_eq!(devices["devices"][0]["environment"], "production");
+    let (_, body) = app.post("/send", json!({"title": "Again"})).await;
+    assert_eq!(body["sent"], 1);
+    assert!(body["results"][0].get("fallback_environment").is_none());
+}
+
+#[tokio::test]
+async fn test_test_device_reports_which_environment_accepts() {
+    let app = app_with(ProductionOnlyProvider).await;
+    let (status, body) = app
+        .post(&format!("/devices/{}/test", token(1)), json!({}))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body["environment"], "production");
+    assert_eq!(body["results"][0]["environment"], "sandbox");
+    assert_eq!(body["results"][0]["error_code"], "BadDeviceToken");
+    assert_eq!(body["results"][1]["apns_id"], "apns-production");
+
+    // The token is tested without being registered.
+    let (_, devices) = app.get("/devices").await;
+    assert!(devices["devices"].as_array().unwrap().is_empty());
+
+    let (status, _) = app.post("/devices/not-hex/test", json!({})).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn test_delete_installation_erases_devices_and_history() {
+    let app = mock_app().await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    app.register(&token(2), "install-1", "iPhone").await;
+    app.register(&token(3), "install-2", "iPad").await;
+    app.post("/send", json!({"title": "Hi"})).await;
+
+    let dry_run = app
+        .request("DELETE", "/installations/install-1?dry_run=true", None)
+        .await;
+    assert_eq!(dry_run.0, StatusCode::OK, "{}", dry_run.1);
+    assert_eq!(dry_run.1["devices"], 2);
+    assert_eq!(dry_run.1["pushes"], 1);
+    let (_, pushes) = app.get("/pushes?installation_id=install-1").await;
+    assert_eq!(pushes["pushes"].as_array().unwrap().len(), 1);
+
+    let (status, body) = app
+        .request("DELETE", "/installations/install-1", None)
+        .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body["dry_run"], false);
+    assert_eq!(
+        (body["devices"].as_i64(), body["pushes"].as_i64()),
+        (Some(2), Some(1))
+    );
+    let (_, pushes) = app.get("/pushes?installation_id=install-1").await;
+    assert!(pushes["pushes"].as_array().unwrap().is_empty());
+    let (_, devices) = app.get("/devices").await;
+    assert_eq!(devices["devices"].as_array().unwrap().len(), 1);
+
+    let (_, audit) = app.get("/audit?action=installation.delete").await;
+    assert_eq!(
+        audit["entries"][0]["summary"],
+        "installation_id=install-1 devices=2 pushes=1"
+    );
+    // Deleting again finds nothing, rather than failing.
+    let (status, body) = app
+        .request("DELETE", "/installations/install-1", None)
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["devices"], 0);
+}
+
+#[tokio::test]
+async fn test_devices_group_by_installation() {
+    let app = mock_app().await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    app.register(&token(3), "install-2", "iPad").await;
+    app.post("/send", json!({"title": "Hi"})).await;
+    // A rotated token counts towards the installation's pushes, not its
+    // devices.
+    app.register(&token(2), "install-1", "iPhone").await;
+
+    let (status, body) = app.get("/installations").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let installations = body["installations"].as_array().unwrap();
+    assert_eq!(installations.len(), 2);
+    assert_eq!(installations[0]["installation_id"], "install-1");
+    assert_eq!(installations[0]["devices"], 1);
+    assert_eq!(installations[0]["pushes"], 1);
+    assert!(installations[0]["last_push_at"].is_string());
+    assert!(installations[0]["last_active_at"].is_string());
+    assert_eq!(installations[1]["installation_id"], "install-2");
+
+    let (status, body) = app.get("/devices?group_by=installation").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let group = &body["installations"][0];
+    assert_eq!(group["installation_id"], "install-1");
+    assert_eq!(group["pushes"], 1);
+    assert_eq!(group["devices"].as_array().unwrap().len(), 1);
+    assert_eq!(group["devices"][0]["device_token"], token(2).as_str());
+
+    // Filters pick the devices, and with them the installations.
+    let (_, body) = app
+        .get("/devices?device_type=iPad&group_by=installation")
+        .await;
+    let installations = body["installations"].as_array().unwrap();
+    assert_eq!(installations.len(), 1);
+    assert_eq!(installations[0]["installation_id"], "install-2");
+
+    let (status, _) = app.get("/devices?group_by=locale").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = app.get("/devices?colour=blue").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_body_limit_and_timeouts() {
+    let limits = HttpLimits {
+        max_body_bytes: 1024,
+        request_timeout: Some(Duration::from_millis(50)),
+        long_request_timeout: None,
+    };
+    let app = app_with_state(SlowProvider, |state| state.with_http_limits(limits)).await;
+    app.register(&token(1), "install-1", "iPhone").await;
+
+    let (status, body) = app
+        .post("/send", json!({"title": "Hi", "body": "x".repeat(2048)}))
+        .await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(body["success"], false);
+    assert!(body["request_id"].is_string(), "{body}");
+
+    // Sends take the long timeout, which is off.
+    let (status, body) = app.post("/send", json!({"title": "Hi"})).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body["sent"], 1);
+    drop(app);
+
+    let limits = HttpLimits {
+        long_request_timeout: Some(Duration::from_millis(50)),
+        ..limits
+    };
+    let app = app_with_state(SlowProvider, |state| state.with_http_limits(limits)).await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    let (status, body) = app.post("/send", json!({"title": "Hi"})).await;
+    assert_eq!(status, StatusCode::REQUEST_TIMEOUT);
+    assert_eq!(body["error"], "The request took too long and was abandoned");
+
+    // The send carries on without the caller.
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    let (_, history) = app.get(&format!("/devices/{}/pushes", token(1))).await;
+    assert_eq!(history["pushes"][0]["apns_id"], "apns-slow");
+}
+
+#[tokio::test]
+async fn test_cors_lets_allowed_origins_read() {
+    let keys = api_keys(&[("dashboard", Role::Read)]);
+    let cors = cors::layer(&CorsConfig {
+        allowed_origins: vec!["https://dashboard.example.com".to_string()],
+        allowed_methods: vec!["GET".to_string()],
+        allowed_headers: vec!["authorization".to_string()],
+        max_age: "10m".to_string(),
+    })
+    .unwrap();
+    let app = mock_app_with(|state| state.with_api_keys(keys).with_cors(cors)).await;
+
+    // Preflights carry no API key, and are answered before authentication.
+    let preflight = Request::builder()
+        .method("OPTIONS")
+        .uri("/devices")
+        .header("origin", "https://dashboard.example.com")
+        .header("access-control-request-method", "GET")
+        .header("access-control-request-headers", "authorization")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.router.clone().oneshot(preflight).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let headers = response.headers();
+    assert_eq!(
+        headers["access-control-allow-origin"],
+        "https://dashboard.example.com"
+    );
+    assert_eq!(headers["access-control-allow-methods"], "GET");
+    assert_eq!(headers["access-control-allow-headers"], "authorization");
+    assert_eq!(headers["access-control-max-age"], "600");
+
+    let get = |origin: &'static str, key: Option<&'static str>| {
+        let mut request = Request::get("/devices").header("origin", origin);
+        if let Some(key) = key {
+            request = request.header(AUTHORIZATION, key);
+        }
+        app.router
+            .clone()
+            .oneshot(request.body(Body::empty()).unwrap())
+    };
+    // Errors are readable too, so the page can tell the key is wrong.
+    let response = get("https://dashboard.example.com", None).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert!(response
+        .headers()
+        .contains_key("access-control-allow-origin"));
+
+    let response = get(
+        "https://dashboard.example.com",
+        Some("Bearer dashboard-secret"),
+    )
+    .await
+    .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()["access-control-allow-origin"],
+        "https://dashboard.example.com"
+    );
+    assert!(response.headers()["access-control-expose-headers"]
+        .to_str()
+        .unwrap()
+        .contains("x-request-id"));
+
+    let response = get("https://evil.example.com", Some("Bearer dashboard-secret"))
+        .await
+        .unwrap();
+    assert!(!response
+        .headers()
+        .contains_key("access-control-allow-origin"));
+}
+
+#[tokio::test]
+async fn test_token_redaction_cuts_tokens_in_push_history() {
+    let app = mock_app_with(|state| state.with_token_redaction()).await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    app.post("/send", json!({"title": "Hi"})).await;
+    let redacted = format!("{}...{}", &token(1)[..8], &token(1)[56..]);
+
+    let (_, history) = app.get(&format!("/devices/{}/pushes", token(1))).await;
+    assert_eq!(history["device_token"], redacted.as_str());
+    let id = history["pushes"][0]["id"].as_i64().unwrap();
+    let (_, detail) = app.get(&format!("/pushes/{id}")).await;
+    assert_eq!(detail["device_token"], redacted.as_str());
+    let (_, pushes) = app.get("/pushes?installation_id=install-1").await;
+    assert_eq!(pushes["pushes"][0]["device_token"], redacted.as_str());
+    let (_, export) = app.get("/pushes/export?format=csv").await;
+    let export = export.as_str().unwrap();
+    assert!(
+        export.contains(&redacted) && !export.contains(&token(1)),
+        "{export}"
+    );
+
+    // Device listings still show whole tokens, to act on.
+    let (_, devices) = app.get("/devices").await;
+    assert_eq!(devices["devices"][0]["device_token"], token(1).as_str());
+}
+
+#[tokio::test]
+async fn test_health_reports_apns_credentials() {
+    let app = mock_app().await;
+    let (status, health) = app.get("/health").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(health["checks"]["apns"]["ok"], true);
+    let credentials = &health["checks"]["apns"]["credentials"];
+    assert_eq!(credentials["mock"], true);
+    assert_eq!(credentials["topic"], "com.example.psh");
+    assert!(credentials.get("key_id").is_none());
+}
+
+#[tokio::test]
+async fn test_health_details_need_a_read_key() {
+    let keys = api_keys(&[("ci", Role::Send), ("grafana", Role::Read)]);
+    let app = mock_app_with(|state| state.with_api_keys(keys)).await;
+    let health = |key: Option<&'static str>| {
+        let mut request = Request::get("/health");
+        if let Some(key) = key {
+            request = request.header(AUTHORIZATION, key);
+        }
+        app.respond(request.body(Body::empty()).unwrap())
+    };
+
+    for key in [None, Some("Bearer ci-secret")] {
+        let (status, body) = health(key).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({"status": "ok"}), "{key:?}");
+    }
+    let (status, body) = health(Some("Bearer grafana-secret")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["checks"]["apns"]["credentials"]["mock"], true);
+}
+
+#[tokio::test]
+async fn test_version_reports_api_version() {
+    let app = mock_app().await;
+    let (status, body) = app.get("/version").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(body["api_version"], server::version::API_VERSION);
+    assert!(body["git_hash"].is_string());
+}
+
+#[tokio::test]
+async fn test_form_and_text_sends() {
+    let app = mock_app().await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    let form = "application/x-www-form-urlencoded";
+
+    let (status, body) = app
+        .post_raw("/send", form, "title=Build&body=50%25+done&sound=default")
+        .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    // What `curl --data` sends: form-encoded, but not fields.
+    let (status, _) = app.post_raw("/send", form, "backup done").await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = app
+        .post_raw("/send?title=Sensor", "text/plain", "Temperature high\n")
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (_, mock) = app.get("/mock/deliveries").await;
+    let alerts: Vec<_> = mock["deliveries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|delivery| delivery["payload"]["aps"]["alert"].clone())
+        .collect();
+    assert_eq!(alerts[0], json!({"title": "Build", "body": "50% done"}));
+    assert_eq!(mock["deliveries"][0]["payload"]["aps"]["sound"], "default");
+    assert_eq!(alerts[1]["body"], "backup done");
+    assert_eq!(
+        alerts[2],
+        json!({"title": "Sensor", "body": "Temperature high"})
+    );
+
+    let (status, body) = app.post_raw("/send", form, "title=Hi&badge=lots").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body["error"].as_str().unwrap().starts_with("Invalid form"));
+    let (status, body) = app.post_raw("/send?colour=red", "text/plain", "Hi").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"], "Invalid query: Unknown field 'colour'");
+}
+
+#[tokio::test]
+async fn test_get_send_maps_query_fields() {
+    let app = mock_app().await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    let (_, stats) = app.get("/stats").await;
+    assert_eq!(stats["total_pushes"], 0);
+
+    let (status, body) = app.get("/send?title=Build&body=done&priority=10").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body["sent"], 1);
+    let (_, mock) = app.get("/mock/deliveries").await;
+    let delivery = &mock["deliveries"][0];
+    assert_eq!(
+        delivery["payload"]["aps"]["alert"],
+        json!({"title": "Build", "body": "done"})
+    );
+    assert_eq!(delivery["priority"], 10);
+    // A GET that sends must not leave cached reads behind.
+    let (_, stats) = app.get("/stats").await;
+    assert_eq!(stats["total_pushes"], 1);
+
+    let (status, body) = app.get("/send?title=Hi&colour=red").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"], "Invalid query: Unknown field 'colour'");
+    let (status, _) = app.request("HEAD", "/send?title=Hi", None).await;
+    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    let (_, mock) = app.get("/mock/deliveries").await;
+    assert_eq!(mock["deliveries"].as_array().unwrap().len(), 1);
+}
+
+/// Makes `method uri` with `Authorization` set to `authorization`, if any.
+async fn authorized(
+    app: &TestApp,
+    method: &str,
+    uri: &str,
+    authorization: Option<&str>,
+) -> StatusCode {
+    let mut request = Request::builder().method(method).uri(uri);
+    if let Some(authorization) = authorization {
+        request = request.header(AUTHORIZATION, authorization);
+    }
+    let request = match method {
+        "POST" => request
+            .header(CONTENT_TYPE, "text/plain")
+            .body(Body::from("hi")),
+        _ => request.body(Body::empty()),
+    };
+    app.respond(request.unwrap()).await.0
+}
+
+/// Keys named `name` whose key is `<name>-secret`.
+fn api_keys(keys: &[(&str, Role)]) -> ApiKeys {
+    let configs: Vec<ApiKeyConfig> = keys
+        .iter()
+        .map(|&(name, role)| ApiKeyConfig {
+            name: name.to_string(),
+            key: format!("{name}-secret"),
+            role,
+            daily_quota: (role == Role::Send).then_some(3),
+            monthly_quota: None,
+        })
+        .collect();
+    ApiKeys::from_config(&configs).unwrap()
+}
+
+#[tokio::test]
+async fn test_api_key_roles() {
+    let keys = api_keys(&[
+        ("ci", Role::Send),
+        ("grafana", Role::Read),
+        ("ops", Role::Admin),
+    ]);
+    let app = mock_app_with(|state| state.with_api_keys(keys)).await;
+    app.register(&token(1), "install-1", "iPhone").await;
+
+    let (ci, grafana, ops) = (
+        Some("Bearer ci-secret"),
+        Some("Bearer grafana-secret"),
+        Some("Bearer ops-secret"),
+    );
+    let cases = [
+        ("GET", "/health", None, StatusCode::OK),
+        ("POST", "/send", None, StatusCode::UNAUTHORIZED),
+        (
+            "POST",
+            "/send",
+            Some("Bearer guess"),
+            StatusCode::UNAUTHORIZED,
+        ),
+        ("POST", "/send", ci, StatusCode::OK),
+        ("GET", "/devices", ci, StatusCode::FORBIDDEN),
+        ("GET", "/stats", ci, StatusCode::FORBIDDEN),
+        ("GET", "/devices", grafana, StatusCode::OK),
+        ("POST", "/send", grafana, StatusCode::FORBIDDEN),
+        ("DELETE", "/segments/beta", grafana, StatusCode::FORBIDDEN),
+        ("POST", "/send", ops, StatusCode::OK),
+        ("GET", "/stats", ops, StatusCode::OK),
+        // The app reads its own inbox without a key.
+        (
+            "GET",
+            "/pushes?installation_id=install-1",
+            None,
+            StatusCode::OK,
+        ),
+    ];
+    for (method, uri, authorization, expected) in cases {
+        let status = authorized(&app, method, uri, authorization).await;
+        assert_eq!(status, expected, "{method} {uri} with {authorization:?}");
+    }
+
+    // Basic auth, for webhook URLs like https://:ci-secret@psh.example.com/send
+    let basic = format!("Basic {}", BASE64.encode(":ci-secret"));
+    let status = authorized(&app, "POST", "/send", Some(&basic)).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_cached_reads_still_need_a_key() {
+    let keys = api_keys(&[("ci", Role::Send), ("grafana", Role::Read)]);
+    let app = mock_app_with(|state| state.with_api_keys(keys)).await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    let grafana = Some("Bearer grafana-secret");
+
+    for uri in ["/devices", "/stats"] {
+        assert_eq!(authorized(&app, "GET", uri, grafana).await, StatusCode::OK);
+        // The response is cached now, but is only served past the key check.
+        assert_eq!(
+            authorized(&app, "GET", uri, None).await,
+            StatusCode::UNAUTHORIZED,
+            "{uri}"
+        );
+        assert_eq!(
+            authorized(&app, "GET", uri, Some("Bearer ci-secret")).await,
+            StatusCode::FORBIDDEN,
+            "{uri}"
+        );
+        assert_eq!(authorized(&app, "GET", uri, grafana).await, StatusCode::OK);
+    }
+}
+
+#[tokio::test]
+async fn test_api_key_quotas() {
+    let keys = api_keys(&[("ci", Role::Send), ("ops", Role::Admin)]);
+    let app = mock_app_with(|state| state.with_api_keys(keys)).await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    app.register(&token(2), "install-2", "iPad").await;
+    let send = || {
+        Request::post("/send")
+            .header(AUTHORIZATION, "Bearer ci-secret")
+            .header(CONTENT_TYPE, "text/plain")
+            .body(Body::from("hi"))
+            .unwrap()
+    };
+
+    let response = app.router.clone().oneshot(send()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["x-quota-daily-remaining"], "1");
+    let response = app.router.clone().oneshot(send()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()["x-quota-daily-remaining"], "1");
+    assert!(response.headers().contains_key("retry-after"));
+
+    // Admin keys have no quota here, so aren't held back by ci's.
+    let status = authorized(&app, "POST", "/send", Some("Bearer ops-secret")).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let usage = |authorization: &'static str| {
+        Request::get("/usage")
+            .header(AUTHORIZATION, authorization)
+            .body(Body::empty())
+            .unwrap()
+    };
+    let (_, body) = app.respond(usage("Bearer ci-secret")).await;
+    assert_eq!(
+        body["keys"],
+        json!([{"name": "ci", "role": "send", "today": 2, "this_month": 2, "daily_quota": 3, "daily_remaining": 1}])
+    );
+    let (_, body) = app.respond(usage("Bearer ops-secret")).await;
+    assert_eq!(body["keys"].as_array().unwrap().len(), 2);
+    assert_eq!(body["keys"][1]["today"], 2);
+}
+
+#[tokio::test]
+async fn test_snoozed_devices_dont_use_quota() {
+    let keys = api_keys(&[("ci", Role::Send), ("ops", Role::Admin)]);
+    let app = mock_app_with(|state| state.with_api_keys(keys)).await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    app.register(&token(2), "install-2", "iPad").await;
+    let snooze = Request::patch(format!("/devices/{}", token(1)))
+        .header(AUTHORIZATION, "Bearer ops-secret")
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(json!({"snooze_seconds": 7200}).to_string()))
+        .unwrap();
+    let (status, _) = app.respond(snooze).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let send = Request::post("/send")
+        .header(AUTHORIZATION, "Bearer ci-secret")
+        .header(CONTENT_TYPE, "text/plain")
+        .body(Body::from("hi"))
+        .unwrap();
+    let response = app.router.clone().oneshot(send).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    // Only the push to the device that isn't snoozed counts.
+    assert_eq!(response.headers()["x-quota-daily-remaining"], "2");
+}
+
+#[tokio::test]
+async fn test_issued_topic_key() {
+    let keys = api_keys(&[("ci", Role::Send), ("ops", Role::Admin)]);
+    let app = mock_app_with(|state| state.with_api_keys(keys)).await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    authorized(
+        &app,
+        "PUT",
+        &format!("/devices/{}/topics/builds", token(1)),
+        None,
+    )
+    .await;
+    let issue = |authorization: &'static str, body: Value| {
+        Request::post("/keys")
+            .header(AUTHORIZATION, authorization)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+
+    let (status, _) = app
+        .respond(issue("Bearer ci-secret", json!({"role": "send"})))
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = app
+        .respond(issue("Bearer ops-secret", json!({"role": "admin"})))
+        .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let (status, _) = app
+        .respond(issue(
+            "Bearer ops-secret",
+            json!({"name": "ci", "role": "send"}),
+        ))
+        .await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    let (status, body) = app
+        .respond(issue(
+            "Bearer ops-secret",
+            json!({"role": "send", "topic": "builds"}),
+        ))
+        .await;
+    assert_eq!(status, StatusCode::CREATED, "{body}");
+    assert_eq!(body["role"], "send");
+    assert_eq!(body["topic"], "builds");
+    assert_eq!(body["created_by"], "ops");
+    let name = body["name"].as_str().unwrap().to_string();
+    assert!(name.starts_with("builds-"));
+    let bearer = format!("Bearer {}", body["key"].as_str().unwrap());
+
+    let cases = [
+        ("POST", "/t/builds", StatusCode::OK),
+        ("POST", "/t/deploys", StatusCode::FORBIDDEN),
+        ("POST", "/send", StatusCode::FORBIDDEN),
+        ("GET", "/usage", StatusCode::OK),
+    ];
+    for (method, uri, expected) in cases {
+        let status = authorized(&app, method, uri, Some(&bearer)).await;
+        assert_eq!(status, expected, "{method} {uri}");
+    }
+
+    let list = Request::get("/keys")
+        .header(AUTHORIZATION, "Bearer ops-secret")
+        .body(Body::empty())
+        .unwrap();
+    let (_, body) = app.respond(list).await;
+    assert_eq!(body["keys"][0]["name"], name.as_str());
+    assert!(body["keys"][0].get("key").is_none());
+
+    let revoke = format!("/keys/{name}");
+    let status = authorized(&app, "DELETE", &revoke, Some("Bearer ops-secret")).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let status = authorized(&app, "POST", "/t/builds", Some(&bearer)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_stats_group_by_api_key_and_topic() {
+    let keys = api_keys(&[("ci", Role::Send), ("ops", Role::Admin)]);
+    let app = mock_app_with(|state| state.with_api_keys(keys)).await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    app.register(&token(2), "install-2", "iPad").await;
+    let subscribe = format!("/devices/{}/topics/backups", token(1));
+    authorized(&app, "PUT", &subscribe, None).await;
+    let status = authorized(&app, "POST", "/send", Some("Bearer ci-secret")).await;
+    assert_eq!(status, StatusCode::OK);
+    let status = authorized(&app, "POST", "/t/backups", Some("Bearer ops-secret")).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let stats = |uri: &'static str| {
+        Request::get(uri)
+            .header(AUTHORIZATION, "Bearer ops-secret")
+            .body(Body::empty())
+            .unwrap()
+    };
+    let (status, body) = app.respond(stats("/stats?group_by=api_key")).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let groups: Vec<_> = body["groups"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|group| (group["key"].clone(), group["sent"].clone()))
+        .collect();
+    assert_eq!(groups, [(json!("ci"), json!(2)), (json!("ops"), json!(1))]);
+
+    let (_, body) = app.respond(stats("/stats?group_by=topic&since=1h")).await;
+    assert_eq!(body["groups"][0]["key"], Value::Null);
+    assert_eq!(body["groups"][1]["key"], "backups");
+    assert_eq!(body["groups"][1]["devices"], 1);
+    assert_eq!(body["groups"][1]["sent"], 1);
+    assert!(body["series"].is_object());
+
+    let (status, _) = app.respond(stats("/stats?group_by=planet")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+/// Sends a push and returns the ids of the keys its signature was made with.
+async fn signing_key_ids(app: &TestApp) -> Vec<String> {
+    let (status, _) = app.post("/send", json!({"body": "Approve login?"})).await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, mock) = app.get("/mock/deliveries").await;
+    let delivery = mock["deliveries"].as_array().unwrap().last().unwrap();
+    match delivery["payload"]["psh_signature"]["sigs"].as_object() {
+        Some(sigs) => sigs.keys().cloned().collect(),
+        None => Vec::new(),
+    }
+}
+
+#[tokio::test]
+async fn test_signed_pushes_follow_key_rotation() {
+    let app = mock_app().await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    assert!(signing_key_ids(&app).await.is_empty());
+
+    let (status, body) = app
+        .request(
+            "PUT",
+            "/apps/com.example.psh",
+            Some(json!({"rotate_signing_key": true})),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body["signing_enabled"], true);
+    assert_eq!(body["signing_key_id"], 1);
+    assert!(body["signing_key"].is_string());
+    assert_eq!(signing_key_ids(&app).await, ["1"]);
+
+    let (_, body) = app
+        .request(
+            "PUT",
+            "/apps/com.example.psh",
+            Some(json!({"rotate_signing_key": true})),
+        )
+        .await;
+    assert_eq!(body["previous_signing_key_id"], 1);
+    assert_eq!(signing_key_ids(&app).await, ["1", "2"]);
+
+    let (_, body) = app
+        .request(
+            "PUT",
+            "/apps/com.example.psh",
+            Some(json!({"retire_previous_signing_key": true})),
+        )
+        .await;
+    assert!(body.get("previous_signing_key_id").is_none());
+    assert!(body.get("signing_key").is_none());
+    assert_eq!(signing_key_ids(&app).await, ["2"]);
+
+    let (status, _) = app
+        .request(
+            "PUT",
+            "/apps/com.example.psh",
+            Some(json!({"signing_key": "dG9vIHNob3J0"})),
+        )
+        .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn test_app_default_ttl_sets_expiration() {
+    let app = mock_app().await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    let (_, body) = app.post("/send", json!({"body": "hi"})).await;
+    assert!(body.get("apns_expiration").is_none(), "{body}");
+
+    let (status, body) = app
+        .request(
+            "PUT",
+            "/apps/com.example.psh",
+            Some(json!({"default_ttl_seconds": 3600})),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body["default_ttl_seconds"], 3600);
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let (_, body) = app.post("/send", json!({"body": "hi"})).await;
+    let expiration = body["apns_expiration"].as_u64().unwrap();
+    assert!((now + 3600..now + 3610).contains(&expiration), "{body}");
+    let (_, mock) = app.get("/mock/deliveries").await;
+    assert_eq!(mock["deliveries"][1]["expiration"], expiration);
+
+    // The request's own expiration wins over the default.
+    let (_, body) = app
+        .post("/send", json!({"body": "hi", "expires_in_seconds": 0}))
+        .await;
+    assert_eq!(body["apns_expiration"], 0);
+
+    let (status, body) = app
+        .post("/send", json!({"body": "hi", "expiration": now - 60}))
+        .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(
+        body["error"].as_str().unwrap().contains("in the past"),
+        "{body}"
+    );
+    let (status, _) = app
+        .request(
+            "PUT",
+            "/apps/com.example.psh",
+            Some(json!({"default_ttl_seconds": 90 * 24 * 60 * 60})),
+        )
+        .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn test_slack_webhook() {
+    let app = mock_app().await;
+    app.register(&token(1), "install-1", "iPhone").await;
+
+    let (status, body) = app
+        .post(
+            "/webhook/slack?priority=10",
+            json!({
+                "text": "Deploy <https://ci.example.com/1|#1> finished",
+                "username": "ci",
+                "channel": "#deploys",
+                "icon_emoji": ":rocket:"
+            }),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body["sent"], 1);
+    // Older Slack clients post the message as a `payload` form field.
+    let payload = json!({"attachments": [{"title": "DiskFull", "text": "/var at 95%"}]});
+    let (status, _) = app
+        .post_raw(
+            "/webhook/slack",
+            "application/x-www-form-urlencoded",
+            &serde_urlencoded::to_string([("payload", payload.to_string())]).unwrap(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (_, mock) = app.get("/mock/deliveries").await;
+    assert_eq!(
+        mock["deliveries"][0]["payload"]["aps"]["alert"],
+        json!({"title": "ci", "body": "Deploy #1 finished"})
+    );
+    assert_eq!(mock["deliveries"][0]["priority"], 10);
+    assert_eq!(
+        mock["deliveries"][1]["payload"]["aps"]["alert"],
+        json!({"title": "DiskFull", "body": "/var at 95%"})
+    );
+
+    let (status, body) = app.post("/webhook/slack", json!({"channel": "#ops"})).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"], "Message has no text or attachments");
+    let (status, _) = app
+        .post_raw("/webhook/slack", "application/json", "not json")
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_topic_publish_reaches_subscribers() {
+    let app = mock_app().await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    app.register(&token(2), "install-2", "iPad").await;
+    let (status, _) = app
+        .request(
+            "PUT",
+            &format!("/devices/{}/topics/backups", token(1)),
+            None,
+        )
+        .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = app
+        .request(
+            "PUT",
+            &format!("/devices/{}/topics/no%20spaces", token(1)),
+            None,
+        )
+        .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let (status, _) = app
+        .request(
+            "PUT",
+            &format!("/devices/{}/topics/backups", token(9)),
+            None,
+        )
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (status, body) = app
+        .post_raw(
+            "/t/backups",
+            "application/x-www-form-urlencoded",
+            "backup done",
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body["sent"], 1);
+    assert_eq!(body["results"][0]["device_token"], token(1));
+    let (_, mock) = app.get("/mock/deliveries").await;
+    assert_eq!(
+        mock["deliveries"][0]["payload"]["aps"]["alert"]["body"],
+        "backup done"
+    );
+
+    let (_, topics) = app.get("/topics").await;
+    assert_eq!(
+        topics["topics"],
+        json!([{"topic": "backups", "subscribers": 1}])
+    );
+    let (_, devices) = app.get("/devices?topic=backups").await;
+    assert_eq!(devices["devices"].as_array().unwrap().len(), 1);
+
+    let (status, _) = app
+        .request(
+            "DELETE",
+            &format!("/devices/{}/topics/backups", token(1)),
+            None,
+        )
+        .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (_, device_topics) = app.get(&format!("/devices/{}/topics", token(1))).await;
+    assert_eq!(device_topics["topics"], json!([]));
+    let (status, _) = app.post_raw("/t/backups", "text/plain", "again").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_paused_topic_queues_until_resumed() {
+    let app = mock_app().await;
+    app.register(&token(1), "install-1", "iPhone").await;
+    app.request(
+        "PUT",
+        &format!("/devices/{}/topics/deploys", token(1)),
+        None,
+    )
+    .await;
+
+    let (status, pause) = app
+        .post(
+            "/topics/deploys/pause",
+            json!({"policy": "queue", "reason": "postmortem"}),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{pause}");
+    assert_eq!(pause["policy"], "queue");
+
+    let (status, body) = app.post_raw("/t/deploys", "text/plain", "shipped").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body["sent"], 0);
+    assert_eq!(body["paused"]["action"], "queued");
+    let (status, body) = app
+        .post(
+            "/t/deploys",
+            json!({"body": "paging", "interruption_level": "critical"}),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body["sent"], 1);
+    let (_, pauses) = app.get("/pauses").await;
+    assert_eq!(pauses["pauses"][0]["name"], "deploys");
+    assert_eq!(pauses["pauses"][0]["queued"], 1);
+
+    let (status, resumed) = app.request("POST", "/topics/deploys/resume", None).await;
+    assert_eq!(status, StatusCode::OK, "{resumed}");
+    assert_eq!(
+        (resumed["replayed"].clone(), resumed["sent"].clone()),
+        (json!(1), json!(1))
+    );
+    let (_, mock) = app.get("/mock/deliveries").await;
+    let bodies: Vec<_> = mock["deliveries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|delivery| delivery["payload"]["aps"]["alert"]["body"].clone())
+        .collect();
+    assert_eq!(bodies, [json!("paging"), json!("shipped")]);
+
+    let (status, _) = app.request("POST", "/topics/deploys/resume", None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = app
+        .post("/segments/missing/pause", json!({"policy": "drop"}))
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_queued_sends_count_against_quotas_on_resume() {
+    let keys = api_keys(&[("ci", Role::Send), ("ops", Role::Admin)]);
+    let app = mock_app_with(|state| state.with_api_keys(keys)).await;
+    for n in [1, 2] {
+        app.register(&token(n), &format!("install-{n}"), "iPhone")
+            .await;
+        app.request(
+            "PUT",
+            &format!("/devices/{}/topics/deploys", token(n)),
+            None,
+        )
+        .await;
+    }
+    let as_key = |method: &str, uri: &str, key: &str, body: &'static str| {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(AUTHORIZATION, format!("Bearer {key}"))
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap();
+        app.respond(request)
+    };
+
+    let (status, _) = as_key(
+        "POST",
+        "/topics/deploys/pause",
+        "ops-secret",
+        r#"{"policy": "queue"}"#,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    // ci's quota is 3 a day: two sends to two devices go over it.
+    for _ in 0..2 {
+        let (status, body) = as_key("POST", "/t/deploys", "ci-secret", r#"{"body": "x"}"#).await;
+        assert_eq!(body["paused"]["action"], "queued", "{status} {body}");
+    }
+
+    let (status, resumed) = as_key("POST", "/topics/deploys/resume", "ops-secret", "").await;
+    assert_eq!(status, StatusCode::OK, "{resumed}");
+    assert_eq!(resumed["replayed"], 2);
+    assert_eq!(resumed["sent"], 2);
+    assert_eq!(resumed["rejected"], 1);
+    let (_, usage) = as_key("GET", "/usage", "ci-secret", "").await;
+    assert_eq!(usage["keys"][0]["today"], 2, "{usage}");
+}
+
+#[tokio::test]
+async fn test_error_responses() {
+    let app = mock_app().await;
+
+    let (status, body) = app.post("/send", json!({"body": "nobody"})).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["error"], "No devices registered");
+
+    let (status, body) = app
+        .post(
+            "/register",
+            json!({"device_token": "nope", "installation_id": "i", "environment": "sandbox"}),
+        )
+        .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body["success"], false);
+
+    let (status, _) = app
+        .post("/register", json!({"device_token": token(1)}))
+        .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+    app.register(&token(1), "install-1", "iPhone").await;
+    let (status, body) = app
+        .post("/send", json!({"body": "x", "segment": "missing"}))
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["error"], "Segment not found: missing");
+
+    let (status, body) = app.post("/send", json!({"body": "x", "priority": 0})).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body["error"], "priority must be between 1 and 10, got 0");
+
+    let (status, _) = app.post("/send", json!({"title": 42})).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, body) = app.get("/pushes/999").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["error"], "Push not found");
+}
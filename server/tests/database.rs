@@ -0,0 +1,41 @@
+//! Opens a database file, which the other tests can't: the connection is
+//! process-wide and they share an in-memory one.
+
+use seekwel::connection::Connection;
+use server::Database;
+use std::{env, fs, process};
+
+#[test]
+fn test_file_database_uses_wal_and_waits_when_busy() {
+    let dir = env::temp_dir().join(format!("psh-database-test-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("data.db");
+    Database::initialize(&format!("sqlite:{}?mode=rwc", path.display())).unwrap();
+
+    let conn = Connection::get().unwrap();
+    let journal_mode: String = conn
+        .query_row("PRAGMA journal_mode", (), |row| row.get(0))
+        .unwrap();
+    assert_eq!(journal_mode, "wal");
+    let busy_timeout: i64 = conn
+        .query_row("PRAGMA busy_timeout", (), |row| row.get(0))
+        .unwrap();
+    assert_eq!(busy_timeout, 5000);
+
+    let indexes: Vec<String> = conn
+        .query_all(
+            "SELECT name FROM sqlite_master WHERE type = 'index' AND name LIKE 'idx_%' ORDER BY name",
+            (),
+            |row| row.get(0),
+        )
+        .unwrap();
+    for index in [
+        "idx_devices_installation_id",
+        "idx_pushes_device_id_sent_at",
+        "idx_pushes_status",
+    ] {
+        assert!(indexes.iter().any(|i| i == index), "{index} in {indexes:?}");
+    }
+
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,50 @@
+//! Builds the app the way a host service embedding psh would, which sets up
+//! the process-wide database from the environment on its own.
+
+use axum::{
+    body::{to_bytes, Body},
+    http::{Request, StatusCode},
+    routing::get,
+    Router,
+};
+use serde_json::{json, Value};
+use server::config::ServerConfig;
+use std::env;
+use tower::ServiceExt;
+
+async fn get_json(app: &Router, uri: &str) -> (StatusCode, Value) {
+    let request = Request::get(uri).body(Body::empty()).unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+#[tokio::test]
+async fn test_mounts_under_a_host_router() {
+    env::set_var("DATABASE_URL", "sqlite::memory:");
+    env::set_var("PSH_APNS_MODE", "mock");
+    env::set_var("APNS_TOPIC", "com.example.host");
+    env::set_var("PSH_RETENTION_INTERVAL", "0");
+
+    let psh = server::build_app(ServerConfig::default()).await.unwrap();
+    let app = Router::new()
+        .route(
+            "/status",
+            get(|| async { axum::Json(json!({"host": true})) }),
+        )
+        .nest("/push", psh);
+
+    let (status, body) = get_json(&app, "/status").await;
+    assert_eq!((status, body), (StatusCode::OK, json!({"host": true})));
+
+    let (status, body) = get_json(&app, "/push/devices").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["devices"], json!([]));
+
+    let (status, body) = get_json(&app, "/push/mock/deliveries").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+
+    let (status, _) = get_json(&app, "/devices").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}